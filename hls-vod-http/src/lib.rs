@@ -0,0 +1,277 @@
+//! HTTP error responses
+//!
+//! The errors of `hls-vod-lib` as HTTP responses, shared by `hls-vod-server`
+//! and `jellyfin-transmux-proxy`, so that both map an `HlsError` to the same
+//! status code.
+//!
+//! Error bodies are `application/problem+json` (RFC 9457), with an extra
+//! `retryable` member: a player, or a proxy in front of the server, can tell
+//! a failure that may go away (the source could not be read, the file is
+//! still being indexed) from one that will not. Retryable errors are sent
+//! as `503 Service Unavailable` with a `Retry-After` header.
+//!
+//! The `detail` of an `HlsError` is a generic message per status: the error
+//! itself can contain paths and FFmpeg output, so it is only logged.
+
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Json, Response};
+use hls_vod_lib::{ErrorClass, HlsError, ScanStatus};
+use serde::Serialize;
+
+/// `Retry-After` of a retryable error, in seconds.
+pub const RETRY_AFTER_SECS: u64 = 2;
+
+/// Content type of the error bodies.
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// An error of a request, as an HTTP response.
+#[derive(Debug)]
+pub enum HttpError {
+    StreamNotFound(String),
+    SegmentNotFound(String),
+    InvalidFormat(String),
+    Forbidden(String),
+    /// The source file changed, see `HlsError::SourceChanged`
+    Gone(String),
+    /// The source has no track the request can be served from
+    Unsupported(String),
+    /// A failure that may go away, see `is_retryable()`
+    Unavailable(String),
+    /// Not an error: the file is still being indexed, retry later. The
+    /// `Retry-After` is the estimated time left, at most `max_retry_secs`.
+    Indexing {
+        status: ScanStatus,
+        max_retry_secs: u64,
+    },
+    InternalError(String),
+}
+
+impl HttpError {
+    /// The status code of the response.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            HttpError::StreamNotFound(_) | HttpError::SegmentNotFound(_) => StatusCode::NOT_FOUND,
+            HttpError::InvalidFormat(_) => StatusCode::BAD_REQUEST,
+            HttpError::Forbidden(_) => StatusCode::FORBIDDEN,
+            HttpError::Gone(_) => StatusCode::GONE,
+            HttpError::Unsupported(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            HttpError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            HttpError::Indexing { .. } => StatusCode::ACCEPTED,
+            HttpError::InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Whether the same request may succeed later.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, HttpError::Unavailable(_) | HttpError::Indexing { .. })
+    }
+
+    /// The problem+json body of the error. `None` for `Indexing`, which
+    /// has the scan progress as its body.
+    pub fn problem(&self) -> Option<Problem> {
+        let detail = match self {
+            HttpError::StreamNotFound(m)
+            | HttpError::SegmentNotFound(m)
+            | HttpError::InvalidFormat(m)
+            | HttpError::Forbidden(m)
+            | HttpError::Gone(m)
+            | HttpError::Unsupported(m)
+            | HttpError::Unavailable(m)
+            | HttpError::InternalError(m) => m,
+            HttpError::Indexing { .. } => return None,
+        };
+        Some(Problem::new(self.status(), detail, self.is_retryable()))
+    }
+}
+
+impl From<HlsError> for HttpError {
+    fn from(err: HlsError) -> Self {
+        let http_err = if is_retryable(&err) {
+            HttpError::Unavailable("Temporarily unavailable, try again later".into())
+        } else {
+            match &err {
+                HlsError::StreamNotFound(_) | HlsError::NoTextSubtitle => {
+                    HttpError::StreamNotFound("Not found".into())
+                }
+                HlsError::SegmentNotFound { .. } => {
+                    HttpError::SegmentNotFound("Segment not found".into())
+                }
+                HlsError::SourceChanged(_) => {
+                    HttpError::Gone("The source file changed, open it again".into())
+                }
+                HlsError::Http(_) => HttpError::InvalidFormat("Invalid request".into()),
+                HlsError::NoVideoStream
+                | HlsError::NoSupportedAudio
+                | HlsError::InvalidCodec(_) => {
+                    HttpError::Unsupported("No track can be served for this request".into())
+                }
+                HlsError::Io(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    HttpError::StreamNotFound("Not found".into())
+                }
+                HlsError::Io(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                    HttpError::Forbidden("Access denied".into())
+                }
+                _ => HttpError::InternalError("Internal server error".into()),
+            }
+        };
+        if http_err.status().is_server_error() {
+            tracing::warn!("{}: {}", http_err.status(), err);
+        } else {
+            tracing::debug!("{}: {}", http_err.status(), err);
+        }
+        http_err
+    }
+}
+
+impl IntoResponse for HttpError {
+    fn into_response(self) -> Response {
+        if let HttpError::Indexing {
+            status,
+            max_retry_secs,
+        } = &self
+        {
+            // While opening, there is no estimate yet.
+            let retry_after = status.remaining_secs().min(*max_retry_secs).max(1);
+            let retry_after = HeaderValue::from(retry_after);
+            return (
+                StatusCode::ACCEPTED,
+                [(header::RETRY_AFTER, retry_after)],
+                Json(*status),
+            )
+                .into_response();
+        }
+        match self.problem() {
+            Some(problem) => problem.into_response(),
+            None => self.status().into_response(),
+        }
+    }
+}
+
+/// Whether a request that failed with `err` may succeed if it is retried.
+///
+/// Reading the source failed (`ErrorClass::Transient`), the file could not
+/// be indexed in time, or the server ran out of memory. A muxing error is
+/// not: the segment comes out the same the next time.
+pub fn is_retryable(err: &HlsError) -> bool {
+    matches!(err, HlsError::IndexTimeout(_) | HlsError::MemoryLimit)
+        || err.class() == ErrorClass::Transient
+}
+
+/// An `application/problem+json` error body.
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    /// Whether the same request may succeed later
+    pub retryable: bool,
+}
+
+impl Problem {
+    /// A problem without a specific type: the status code tells what went wrong.
+    pub fn new(status: StatusCode, detail: impl Into<String>, retryable: bool) -> Self {
+        Self {
+            kind: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail: detail.into(),
+            retryable,
+        }
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut response = (
+            status,
+            [(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON))],
+            serde_json::to_string(&self).unwrap_or_default(),
+        )
+            .into_response();
+        if self.retryable {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_hls_error() {
+        let err = HttpError::from(HlsError::SourceChanged("a.mkv".into()));
+        assert_eq!(err.status(), StatusCode::GONE);
+        assert!(!err.is_retryable());
+
+        let err = HttpError::from(HlsError::Io(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "read timed out",
+        )));
+        assert_eq!(err.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(err.is_retryable());
+
+        let err = HttpError::from(HlsError::Muxing("bad packet".into()));
+        assert_eq!(err.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!err.is_retryable());
+
+        let err = HttpError::from(HlsError::Config("no AAC encoder".into()));
+        assert_eq!(err.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_detail_hides_error() {
+        let errors = [
+            HlsError::StreamNotFound("/srv/media/secret.mkv".into()),
+            HlsError::Muxing("/srv/media/secret.mkv: [mp4] bad packet".into()),
+            HlsError::Io(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "/srv/media/secret.mkv",
+            )),
+        ];
+        for err in errors {
+            let problem = HttpError::from(err).problem().unwrap();
+            assert!(!problem.detail.contains("secret"), "{}", problem.detail);
+        }
+    }
+
+    #[test]
+    fn test_problem_response() {
+        let response = HttpError::Unavailable("source busy".into()).into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], PROBLEM_JSON);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+
+        let problem = HttpError::Forbidden("outside of media root".into())
+            .problem()
+            .unwrap();
+        let json = serde_json::to_value(&problem).unwrap();
+        assert_eq!(json["type"], "about:blank");
+        assert_eq!(json["title"], "Forbidden");
+        assert_eq!(json["status"], 403);
+        assert_eq!(json["retryable"], false);
+    }
+
+    #[test]
+    fn test_indexing_response() {
+        let status = ScanStatus {
+            phase: hls_vod_lib::ScanPhase::Opening,
+            percent: 0,
+            elapsed_secs: 5.0,
+        };
+        assert_eq!(status.remaining_secs(), 495);
+        let response = HttpError::Indexing {
+            status,
+            max_retry_secs: 5,
+        }
+        .into_response();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "5");
+    }
+}
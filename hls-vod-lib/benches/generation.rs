@@ -0,0 +1,110 @@
+//! Benchmarks for scanning and segment generation.
+//!
+//! Run with `cargo bench -p hls-vod-lib --features bench`.
+//!
+//! The media is a 30 minute fixture (H.264, AAC and AC-3 audio, two SubRip
+//! tracks) synthesized with the FFmpeg encoders on the first run, and kept
+//! in `$TMPDIR/hls-vod-fixtures/`. The segment cache is not initialized, so
+//! every iteration generates the segment.
+
+use std::path::{Path, PathBuf};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use hls_vod_lib::bench::{generate, scan_file_with_options, FixtureSpec, IndexOptions};
+use hls_vod_lib::{HlsParams, HlsVideo};
+
+/// Sequence number of the segments that are generated, halfway the file.
+const SEGMENT: usize = 200;
+
+struct Media {
+    path: PathBuf,
+    /// `<video url>/<stream id>`
+    base: String,
+    video_track: usize,
+    ac3_track: usize,
+    subtitle_track: usize,
+}
+
+fn fixture() -> Media {
+    hls_vod_lib::ffmpeg_init().unwrap();
+    let spec = FixtureSpec {
+        name: "bench_30min",
+        duration_secs: 1800,
+        ..FixtureSpec::multi_language()
+    };
+    let path = generate(&spec).expect("cannot generate the benchmark fixture");
+
+    let video_url = path.to_str().unwrap().trim_start_matches('/').to_string();
+    let params = HlsParams::parse(&format!("{}.as.m3u8", video_url)).unwrap();
+    let index = match HlsVideo::open(&path, params).unwrap() {
+        HlsVideo::MainPlaylist(p) => p.index,
+        HlsVideo::PlaylistOrSegment(_) => unreachable!(),
+    };
+    Media {
+        base: format!("{}/{}", video_url, index.stream_id()),
+        video_track: index.video_streams[0].stream_index,
+        ac3_track: index
+            .audio_streams
+            .iter()
+            .find(|a| a.codec_id == ffmpeg_next::codec::Id::AC3)
+            .unwrap()
+            .stream_index,
+        subtitle_track: index.subtitle_streams[0].stream_index,
+        path,
+    }
+}
+
+fn fetch(path: &Path, url: &str) -> Vec<u8> {
+    let params = HlsParams::parse(url).unwrap_or_else(|| panic!("cannot parse {}", url));
+    HlsVideo::open(path, params).unwrap().generate().unwrap()
+}
+
+// The URL of segment `n` in a subtitle playlist; the cue ranges are only
+// known by the playlist.
+#[cfg(feature = "subtitles")]
+fn subtitle_segment(media: &Media, n: usize) -> String {
+    let playlist = format!("{}/t.{}.m3u8", media.base, media.subtitle_track);
+    let text = String::from_utf8(fetch(&media.path, &playlist)).unwrap();
+    let uri = text
+        .lines()
+        .filter(|l| !l.starts_with('#') && !l.is_empty())
+        .nth(n)
+        .unwrap();
+    format!("{}/{}", media.base, uri)
+}
+
+fn benchmarks(c: &mut Criterion) {
+    let media = fixture();
+
+    c.bench_function("scan_file", |b| {
+        b.iter(|| scan_file_with_options(&media.path, &IndexOptions::default()).unwrap())
+    });
+
+    let url = format!("{}/v/{}.{}.m4s", media.base, media.video_track, SEGMENT);
+    c.bench_function("video_segment_passthrough", |b| {
+        b.iter(|| fetch(&media.path, &url))
+    });
+
+    #[cfg(feature = "transcode")]
+    {
+        let url = format!("{}/a/{}-aac.{}.m4s", media.base, media.ac3_track, SEGMENT);
+        c.bench_function("audio_segment_transcode", |b| {
+            b.iter(|| fetch(&media.path, &url))
+        });
+    }
+
+    #[cfg(feature = "subtitles")]
+    {
+        let url = subtitle_segment(&media, SEGMENT);
+        c.bench_function("subtitle_segment", |b| b.iter(|| fetch(&media.path, &url)));
+    }
+}
+
+criterion_group! {
+    name = benches;
+    // Segments take milliseconds, keep the total run time reasonable.
+    config = Criterion::default().sample_size(20);
+    targets = benchmarks
+}
+criterion_main!(benches);
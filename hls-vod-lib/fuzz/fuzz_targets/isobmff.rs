@@ -0,0 +1,24 @@
+//! Feed arbitrary bytes to the ISOBMFF box walker and the segment patchers.
+//!
+//! Run with `cargo +nightly fuzz run isobmff` from `hls-vod-lib`.
+#![no_main]
+
+use hls_vod_lib::isobmff;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = data.to_vec();
+    isobmff::walk_boxes_mut(
+        &mut buf,
+        &[b"moov", b"trak", b"mvex", b"moof", b"traf"],
+        &mut |_, _| {},
+    );
+
+    isobmff::fix_trex_durations(&mut data.to_vec(), 1024);
+    isobmff::fix_trex_durations_per_track(&mut data.to_vec(), 1, 3600, 2, 1024);
+    isobmff::patch_tfdts(&mut data.to_vec(), 90_000, 1);
+    isobmff::patch_tfdts_per_track(&mut data.to_vec(), 1, 1, 2, 90_000, 48_000);
+
+    let (video, audio) = data.split_at(data.len() / 2);
+    let _ = isobmff::merge_track_fragments(video, audio, 2);
+});
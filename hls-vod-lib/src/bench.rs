@@ -0,0 +1,8 @@
+//! Internals for the benchmarks in `benches/` (the `bench` feature).
+//!
+//! Synthesized media, see `fixtures`, and the scanner without the rest of
+//! `HlsVideo::open()`. The server crate uses the fixtures in its tests.
+//! Not a stable API.
+
+pub use crate::fixtures::{generate, AudioSpec, FixtureSpec};
+pub use crate::index::scanner::{scan_file_with_options, IndexOptions};
@@ -2,11 +2,11 @@
 //!
 //! We keep two persistent caches:
 //! - all currently open streams
-//! - a stream segment cache (optional).
+//! - a stream segment cache (optional, requires the `cache` feature).
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, OnceLock};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use bytes::Bytes;
 use dashmap::DashMap;
@@ -16,10 +16,28 @@ use crate::media::StreamIndex;
 
 static CACHE: OnceLock<SegmentCache> = OnceLock::new();
 
+/// Idle timeouts of open streams, see `SegmentCacheConfig`.
+static STREAM_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(600);
+static PAUSED_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(3600);
+/// Derive stream ids from the file instead of generating random ones.
+static STABLE_STREAM_IDS: AtomicBool = AtomicBool::new(false);
+
 /// Initialize the global segment cache.
 /// This function should be called once at application startup.
+///
+/// Without the `cache` feature this is a no-op and segments are always generated.
 pub fn init_segment_cache(config: SegmentCacheConfig) {
+    crate::memory::set_memory_budget(config.memory_budget_mb * 1024 * 1024);
+    STREAM_TIMEOUT_SECS.store(config.stream_timeout_secs, Ordering::Relaxed);
+    PAUSED_TIMEOUT_SECS.store(config.paused_timeout_secs, Ordering::Relaxed);
+    STABLE_STREAM_IDS.store(config.stable_stream_ids, Ordering::Relaxed);
+    #[cfg(feature = "cache")]
     let _ = CACHE.set(SegmentCache::new(config));
+    #[cfg(not(feature = "cache"))]
+    {
+        let _ = config;
+        tracing::info!("segment cache not compiled in (cache feature disabled)");
+    }
 }
 
 /// Retrieve the global cache stats
@@ -32,6 +50,9 @@ pub fn segment_cache_stats() -> SegmentCacheStats {
             total_size_bytes: 0,
             memory_limit_bytes: 0,
             oldest_entry_age_secs: 0,
+            eviction_policy: EvictionPolicy::default(),
+            saved_generation_secs: 0.0,
+            evicted_generation_secs: 0.0,
         }
     }
 }
@@ -56,6 +77,53 @@ pub struct SegmentCacheConfig {
     /// Number of segments to pre-generate ahead (0 = disabled)
     #[serde(default)]
     pub lookahead: usize,
+
+    /// Memory budget in megabytes for all subsystems together: segment
+    /// cache, stream indexes, input contexts and in-flight segments
+    /// (0 = unlimited)
+    #[serde(default)]
+    pub memory_budget_mb: usize,
+
+    /// Drop an open stream after it has not been accessed for this many seconds
+    #[serde(default = "default_stream_timeout_secs")]
+    pub stream_timeout_secs: u64,
+
+    /// Keep a stream for this many seconds after the last keep-alive
+    /// (heartbeat or playlist reload), so that paused players keep it
+    #[serde(default = "default_paused_timeout_secs")]
+    pub paused_timeout_secs: u64,
+
+    /// Derive the stream id from the file path, modification time and
+    /// indexing options instead of generating a random one, so that the
+    /// same file always gets the same id
+    #[serde(default)]
+    pub stable_stream_ids: bool,
+
+    /// Which entries to evict when the cache is full
+    #[serde(default)]
+    pub eviction_policy: EvictionPolicy,
+}
+
+/// How the segment cache picks the entries to evict.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EvictionPolicy {
+    /// Least recently used first
+    #[default]
+    Lru,
+    /// Greedy-Dual-Size-Frequency: lowest generation time per byte, times
+    /// the number of accesses, first. Cheap remuxed segments then make room
+    /// before transcoded ones, while entries that are not accessed anymore
+    /// still age out.
+    Gdsf,
+}
+
+fn default_stream_timeout_secs() -> u64 {
+    600
+}
+
+fn default_paused_timeout_secs() -> u64 {
+    3600
 }
 
 impl Default for SegmentCacheConfig {
@@ -65,6 +133,11 @@ impl Default for SegmentCacheConfig {
             max_segments: 100, // ~400 seconds of content at 4s/segment
             ttl_secs: 300,     // 5 minutes
             lookahead: 2,      // 2 segments by default
+            memory_budget_mb: 0,
+            stream_timeout_secs: default_stream_timeout_secs(),
+            paused_timeout_secs: default_paused_timeout_secs(),
+            stable_stream_ids: false,
+            eviction_policy: EvictionPolicy::default(),
         }
     }
 }
@@ -83,6 +156,10 @@ pub(crate) struct CacheEntry {
     pub created_at: SystemTime,
     pub last_accessed: SystemTime,
     pub access_count: usize,
+    /// Time it took to generate
+    pub cost: Duration,
+    /// Cache inflation value at the last access, see `gdsf_priority()`
+    pub inflation: f64,
 }
 
 impl CacheEntry {
@@ -93,6 +170,8 @@ impl CacheEntry {
             created_at: now,
             last_accessed: now,
             access_count: 1,
+            cost: Duration::ZERO,
+            inflation: 0.0,
         }
     }
 
@@ -108,9 +187,17 @@ impl CacheEntry {
     pub fn is_expired(&self, ttl_secs: u64) -> bool {
         self.age_secs() > ttl_secs
     }
+
+    /// GDSF priority: the inflation value at the last access, plus the
+    /// generation time in milliseconds per byte times the accesses.
+    /// Entries generated in less than a millisecond count as one.
+    pub fn gdsf_priority(&self) -> f64 {
+        let cost_ms = (self.cost.as_secs_f64() * 1000.0).max(1.0);
+        self.inflation + self.access_count as f64 * cost_ms / self.data.len().max(1) as f64
+    }
 }
 
-/// LRU cache for HLS segments
+/// LRU or GDSF cache for HLS segments
 pub struct SegmentCache {
     /// Cache entries (key -> entry)
     entries: DashMap<String, CacheEntry>,
@@ -118,6 +205,12 @@ pub struct SegmentCache {
     generation_locks: DashMap<String, Arc<Mutex<()>>>,
     /// Current memory usage in bytes
     memory_bytes: AtomicUsize,
+    /// GDSF inflation value (`f64` bits): the priority of the last evicted entry
+    inflation: AtomicU64,
+    /// Generation time of the cache hits, in microseconds
+    saved_micros: AtomicU64,
+    /// Generation time of the evicted entries, in microseconds
+    evicted_micros: AtomicU64,
     /// Cache configuration
     config: SegmentCacheConfig,
 }
@@ -129,6 +222,9 @@ impl SegmentCache {
             entries: DashMap::new(),
             generation_locks: DashMap::new(),
             memory_bytes: AtomicUsize::new(0),
+            inflation: AtomicU64::new(0),
+            saved_micros: AtomicU64::new(0),
+            evicted_micros: AtomicU64::new(0),
             config,
         }
     }
@@ -144,13 +240,22 @@ impl SegmentCache {
 
         if let Some(mut entry) = self.entries.get_mut(&key) {
             entry.touch();
+            entry.inflation = self.inflation();
+            self.saved_micros
+                .fetch_add(entry.cost.as_micros() as u64, Ordering::Relaxed);
             Some(entry.data.clone())
         } else {
             None
         }
     }
 
-    #[allow(dead_code)]
+    /// The size of a cached segment, without counting it as an access.
+    pub fn cached_size(&self, stream_id: &str, segment_key: &str) -> Option<usize> {
+        let key = Self::make_key(stream_id, segment_key);
+        self.entries.get(&key).map(|e| e.data.len())
+    }
+
+    /// Whether a segment is cached, without counting it as an access.
     pub fn contains(&self, stream_id: &str, segment_key: &str) -> bool {
         let key = Self::make_key(stream_id, segment_key);
         self.entries.contains_key(&key)
@@ -158,6 +263,43 @@ impl SegmentCache {
 
     /// Cache a segment
     pub fn insert(&self, stream_id: &str, segment_key: &str, data: Bytes) {
+        self.insert_with_cost(stream_id, segment_key, data, Duration::ZERO);
+    }
+
+    /// Cache a segment that took `cost` to generate.
+    ///
+    /// With the GDSF eviction policy, costly segments stay cached longer.
+    pub fn insert_with_cost(
+        &self,
+        stream_id: &str,
+        segment_key: &str,
+        data: Bytes,
+        cost: Duration,
+    ) {
+        self.insert_entry(stream_id, segment_key, data, cost, 1);
+    }
+
+    /// Cache a segment that no player asked for (yet), such as a segment
+    /// of the warmer. It doesn't count as an access, so it adds nothing to
+    /// the popularity of the stream, see `stream_accesses()`.
+    pub(crate) fn insert_unrequested(
+        &self,
+        stream_id: &str,
+        segment_key: &str,
+        data: Bytes,
+        cost: Duration,
+    ) {
+        self.insert_entry(stream_id, segment_key, data, cost, 0);
+    }
+
+    fn insert_entry(
+        &self,
+        stream_id: &str,
+        segment_key: &str,
+        data: Bytes,
+        cost: Duration,
+        access_count: usize,
+    ) {
         let key = Self::make_key(stream_id, segment_key);
         let size = data.len();
 
@@ -173,8 +315,16 @@ impl SegmentCache {
             self.evict_if_needed(size);
         }
 
-        self.entries.insert(key, CacheEntry::new(data));
+        let entry = CacheEntry {
+            cost,
+            access_count,
+            inflation: self.inflation(),
+            ..CacheEntry::new(data)
+        };
+        self.entries.insert(key, entry);
         self.memory_bytes.fetch_add(size, Ordering::Relaxed);
+
+        crate::memory::enforce_budget();
     }
 
     /// Evict entries if needed to make room for new data.
@@ -189,35 +339,92 @@ impl SegmentCache {
         let true_usage: usize = self.entries.iter().map(|e| e.value().data.len()).sum();
         self.memory_bytes.store(true_usage, Ordering::Relaxed);
 
-        // Phase 2: LRU eviction if still over budget
+        // Phase 2: evict by policy if still over budget
         if true_usage + needed_size > self.config.max_memory_bytes() {
-            let mut candidates: Vec<(SystemTime, String, usize)> = self
-                .entries
-                .iter()
-                .map(|e| {
-                    (
-                        e.value().last_accessed,
-                        e.key().clone(),
-                        e.value().data.len(),
-                    )
-                })
-                .collect();
-
-            candidates.sort_unstable_by_key(|(t, _, _)| *t);
-
-            let mut freed = 0usize;
-            for (_, key, size) in candidates {
-                if freed >= target {
-                    break;
-                }
-                if self.entries.remove(&key).is_some() {
-                    freed += size;
-                }
-            }
+            self.evict(target);
+        }
+    }
 
-            let after: usize = self.entries.iter().map(|e| e.value().data.len()).sum();
-            self.memory_bytes.store(after, Ordering::Relaxed);
+    /// Evict entries by the configured policy until at least `target` bytes
+    /// are freed.
+    ///
+    /// Returns the number of bytes freed.
+    pub(crate) fn evict(&self, target: usize) -> usize {
+        match self.config.eviction_policy {
+            EvictionPolicy::Lru => self.evict_lru(target),
+            EvictionPolicy::Gdsf => self.evict_gdsf(target),
+        }
+    }
+
+    /// Evict least recently used entries until at least `target` bytes are freed.
+    ///
+    /// Returns the number of bytes freed.
+    pub(crate) fn evict_lru(&self, target: usize) -> usize {
+        let mut candidates: Vec<(SystemTime, String)> = self
+            .entries
+            .iter()
+            .map(|e| (e.value().last_accessed, e.key().clone()))
+            .collect();
+
+        candidates.sort_unstable_by_key(|(t, _)| *t);
+
+        let (freed, _) = self.remove_until(candidates.iter().map(|(_, key)| key), target);
+        freed
+    }
+
+    /// Evict the entries with the lowest GDSF priority until at least
+    /// `target` bytes are freed. The inflation value becomes the priority
+    /// of the last entry evicted, so that entries accessed from now on
+    /// outrank the ones that were not.
+    ///
+    /// Returns the number of bytes freed.
+    pub(crate) fn evict_gdsf(&self, target: usize) -> usize {
+        let mut candidates: Vec<(f64, String)> = self
+            .entries
+            .iter()
+            .map(|e| (e.value().gdsf_priority(), e.key().clone()))
+            .collect();
+
+        candidates.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+
+        let (freed, removed) = self.remove_until(candidates.iter().map(|(_, key)| key), target);
+        if removed > 0 {
+            // Priorities are never negative, so they order like their bits.
+            let priority = candidates[removed - 1].0;
+            self.inflation
+                .fetch_max(priority.to_bits(), Ordering::Relaxed);
+        }
+        freed
+    }
+
+    // Remove the entries of `keys` in order until at least `target` bytes are
+    // freed. Returns the bytes freed and the number of keys used.
+    fn remove_until<'a>(
+        &self,
+        keys: impl Iterator<Item = &'a String>,
+        target: usize,
+    ) -> (usize, usize) {
+        let (mut freed, mut used) = (0usize, 0usize);
+        for key in keys {
+            if freed >= target {
+                break;
+            }
+            used += 1;
+            if let Some((_, entry)) = self.entries.remove(key) {
+                freed += entry.data.len();
+                self.evicted_micros
+                    .fetch_add(entry.cost.as_micros() as u64, Ordering::Relaxed);
+            }
         }
+
+        let after: usize = self.entries.iter().map(|e| e.value().data.len()).sum();
+        self.memory_bytes.store(after, Ordering::Relaxed);
+        (freed, used)
+    }
+
+    // The current GDSF inflation value.
+    fn inflation(&self) -> f64 {
+        f64::from_bits(self.inflation.load(Ordering::Relaxed))
     }
 
     /// Clear stream cache
@@ -262,6 +469,9 @@ impl SegmentCache {
             total_size_bytes: total_size,
             memory_limit_bytes: self.config.max_memory_bytes(),
             oldest_entry_age_secs: oldest_age,
+            eviction_policy: self.config.eviction_policy,
+            saved_generation_secs: self.saved_micros.load(Ordering::Relaxed) as f64 / 1e6,
+            evicted_generation_secs: self.evicted_micros.load(Ordering::Relaxed) as f64 / 1e6,
         }
     }
 
@@ -288,6 +498,31 @@ impl SegmentCache {
     pub fn lookahead(&self) -> usize {
         self.config.lookahead
     }
+
+    /// Accesses of the unexpired entries of every stream, most accessed first.
+    pub(crate) fn stream_accesses(&self) -> Vec<(String, usize)> {
+        let mut accesses: std::collections::HashMap<String, usize> = Default::default();
+        for entry in self.entries.iter() {
+            if entry.value().is_expired(self.config.ttl_secs) {
+                continue;
+            }
+            if let Some((stream_id, _)) = entry.key().split_once(':') {
+                *accesses.entry(stream_id.to_string()).or_default() += entry.value().access_count;
+            }
+        }
+        let mut accesses: Vec<_> = accesses.into_iter().collect();
+        accesses.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        accesses
+    }
+
+    /// The segment keys of the cached entries of a stream.
+    pub(crate) fn stream_keys(&self, stream_id: &str) -> Vec<String> {
+        let prefix = Self::make_key(stream_id, "");
+        self.entries
+            .iter()
+            .filter_map(|e| e.key().strip_prefix(&prefix).map(|k| k.to_string()))
+            .collect()
+    }
 }
 
 /// Cache statistics
@@ -297,6 +532,12 @@ pub struct SegmentCacheStats {
     pub total_size_bytes: usize,
     pub memory_limit_bytes: usize,
     pub oldest_entry_age_secs: u64,
+    pub eviction_policy: EvictionPolicy,
+    /// Generation time of the segments served from the cache, that is, the
+    /// recomputation the cache saved
+    pub saved_generation_secs: f64,
+    /// Generation time of the evicted segments
+    pub evicted_generation_secs: f64,
 }
 
 impl Default for SegmentCache {
@@ -309,6 +550,11 @@ pub(crate) static STREAMS_BY_ID: std::sync::OnceLock<
     dashmap::DashMap<String, std::sync::Arc<StreamIndex>>,
 > = std::sync::OnceLock::new();
 
+/// Whether stream ids are derived from the file, see `SegmentCacheConfig`.
+pub(crate) fn stable_stream_ids() -> bool {
+    STABLE_STREAM_IDS.load(Ordering::Relaxed)
+}
+
 /// Retrieve a tracked media stream by its generated stream ID
 pub(crate) fn get_stream_by_id(stream_id: &str) -> Option<std::sync::Arc<StreamIndex>> {
     STREAMS_BY_ID
@@ -333,6 +579,59 @@ pub fn remove_stream_by_id(stream_id: &str) -> bool {
     false
 }
 
+/// Remove all tracked media streams of a file.
+///
+/// Used when something changed that requires the file to be indexed again.
+/// Returns the number of streams removed.
+pub fn remove_streams_by_path(path: &std::path::Path) -> usize {
+    let canonical = path.canonicalize().ok();
+    let same_file = |p: &std::path::Path| {
+        p == path || (canonical.is_some() && p.canonicalize().ok() == canonical)
+    };
+    let stream_ids: Vec<String> = STREAMS_BY_ID
+        .get_or_init(dashmap::DashMap::new)
+        .iter()
+        .filter(|r| same_file(&r.value().source_path))
+        .map(|r| r.key().clone())
+        .collect();
+    for stream_id in &stream_ids {
+        remove_stream_by_id(stream_id);
+    }
+    stream_ids.len()
+}
+
+/// Keep a tracked media stream alive while the player is paused.
+///
+/// Returns false if the stream is not (or no longer) open.
+pub fn keepalive_stream(stream_id: &str) -> bool {
+    match get_stream_by_id(stream_id) {
+        Some(media) => {
+            media.keepalive();
+            true
+        }
+        None => false,
+    }
+}
+
+/// The file of a tracked media stream, `None` if the stream is not open.
+pub fn stream_source_path(stream_id: &str) -> Option<std::path::PathBuf> {
+    get_stream_by_id(stream_id).map(|media| media.source_path.clone())
+}
+
+/// A font attached to the file of a tracked media stream.
+///
+/// Returns `None` if the stream is not open, or has no such attachment.
+pub fn stream_attachment(stream_id: &str, filename: &str) -> Option<crate::media::Attachment> {
+    get_stream_by_id(stream_id).and_then(|media| media.attachment(filename).cloned())
+}
+
+/// The cover art in stream `stream_index` of a tracked media stream.
+///
+/// Returns `None` if the stream is not open, or has no such picture.
+pub fn stream_artwork(stream_id: &str, stream_index: usize) -> Option<crate::media::Artwork> {
+    get_stream_by_id(stream_id).and_then(|media| media.artwork(stream_index).cloned())
+}
+
 /// Active stream metadata
 #[derive(serde::Serialize, Clone, Debug)]
 pub struct ActiveStreamInfo {
@@ -354,14 +653,39 @@ pub fn active_streams() -> Vec<ActiveStreamInfo> {
         .collect()
 }
 
+/// The recent errors of a stream, see `StreamIndex::recent_errors()`.
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct StreamErrors {
+    pub stream_id: String,
+    pub path: String,
+    /// Oldest first
+    pub errors: Vec<crate::media::GenerationError>,
+}
+
+/// The recent errors of the active streams that have any.
+pub fn stream_errors() -> Vec<StreamErrors> {
+    STREAMS_BY_ID
+        .get_or_init(dashmap::DashMap::new)
+        .iter()
+        .map(|r| StreamErrors {
+            stream_id: r.value().stream_id.clone(),
+            path: r.value().source_path.to_string_lossy().to_string(),
+            errors: r.value().recent_errors(),
+        })
+        .filter(|s| !s.errors.is_empty())
+        .collect()
+}
+
 /// Remove expired streams from tracking and cache
 pub fn cleanup_expired_streams() -> usize {
-    const STREAM_TIMEOUT_SECS: u64 = 600; // 10 minutes
+    let timeout = STREAM_TIMEOUT_SECS.load(Ordering::Relaxed);
+    let paused_timeout = PAUSED_TIMEOUT_SECS.load(Ordering::Relaxed);
 
     let mut streams_to_remove = Vec::new();
 
     for entry in STREAMS_BY_ID.get_or_init(dashmap::DashMap::new).iter() {
-        if entry.value().time_since_last_access() > STREAM_TIMEOUT_SECS {
+        let timeout = entry.value().config.stream_timeout_secs.unwrap_or(timeout);
+        if entry.value().is_expired(timeout, paused_timeout) {
             streams_to_remove.push(entry.key().clone());
         }
     }
@@ -438,6 +762,35 @@ mod tests {
         assert!(cache.contains("stream2", "video:0"));
     }
 
+    #[test]
+    fn test_stream_accesses() {
+        let cache = SegmentCache::new(SegmentCacheConfig::default());
+
+        cache.insert("stream1", "v/0.0.m4s", Bytes::from("v0"));
+        cache.insert("stream2", "v/0.0.m4s", Bytes::from("v0"));
+        cache.insert("stream2", "v/0.1.m4s", Bytes::from("v1"));
+        cache.get("stream1", "v/0.0.m4s");
+        cache.get("stream1", "v/0.0.m4s");
+        // Neither are existence checks.
+        assert!(cache.contains("stream2", "v/0.0.m4s"));
+        // Warmed segments are not accesses.
+        let cost = Duration::ZERO;
+        cache.insert_unrequested("stream2", "v/0.2.m4s", Bytes::from("v2"), cost);
+        cache.insert_unrequested("stream3", "v/0.0.m4s", Bytes::from("v0"), cost);
+
+        assert_eq!(
+            cache.stream_accesses(),
+            vec![
+                ("stream1".to_string(), 3),
+                ("stream2".to_string(), 2),
+                ("stream3".to_string(), 0)
+            ]
+        );
+        let mut keys = cache.stream_keys("stream2");
+        keys.sort();
+        assert_eq!(keys, ["v/0.0.m4s", "v/0.1.m4s", "v/0.2.m4s"]);
+    }
+
     #[test]
     fn test_cache_stats() {
         let cache = SegmentCache::new(SegmentCacheConfig::default());
@@ -449,6 +802,58 @@ mod tests {
         assert!(stats.total_size_bytes > 0);
     }
 
+    #[test]
+    fn test_cache_evict_lru() {
+        let cache = SegmentCache::new(SegmentCacheConfig::default());
+
+        cache.insert("s1", "v:0", Bytes::from(vec![0u8; 100]));
+        std::thread::sleep(Duration::from_millis(10));
+        cache.insert("s1", "v:1", Bytes::from(vec![0u8; 100]));
+        std::thread::sleep(Duration::from_millis(10));
+        cache.insert("s1", "v:2", Bytes::from(vec![0u8; 100]));
+        std::thread::sleep(Duration::from_millis(10));
+        cache.get("s1", "v:0");
+
+        assert_eq!(cache.evict_lru(150), 200);
+        assert!(cache.contains("s1", "v:0"));
+        assert!(!cache.contains("s1", "v:1"));
+        assert!(!cache.contains("s1", "v:2"));
+        assert_eq!(cache.memory_usage(), 100);
+    }
+
+    #[test]
+    fn test_cache_evict_gdsf() {
+        let cache = SegmentCache::new(SegmentCacheConfig {
+            eviction_policy: EvictionPolicy::Gdsf,
+            ..Default::default()
+        });
+        let ms = Duration::from_millis;
+
+        // A transcoded segment, a remuxed one, and one that is accessed often.
+        cache.insert_with_cost("s1", "v:0", Bytes::from(vec![0u8; 100]), ms(400));
+        cache.insert_with_cost("s1", "v:1", Bytes::from(vec![0u8; 100]), ms(10));
+        cache.insert_with_cost("s1", "v:2", Bytes::from(vec![0u8; 100]), ms(10));
+        for _ in 0..60 {
+            cache.get("s1", "v:2");
+        }
+
+        assert_eq!(cache.evict(150), 200);
+        assert!(!cache.contains("s1", "v:0"));
+        assert!(!cache.contains("s1", "v:1"));
+        assert!(cache.contains("s1", "v:2"));
+
+        // New entries compete with the priority of the evicted ones.
+        assert_eq!(cache.inflation(), 4.0);
+        cache.insert_with_cost("s1", "v:3", Bytes::from(vec![0u8; 100]), ms(10));
+        assert_eq!(cache.evict(50), 100);
+        assert!(!cache.contains("s1", "v:3"));
+
+        let stats = cache.stats();
+        assert_eq!(stats.eviction_policy, EvictionPolicy::Gdsf);
+        assert!((stats.saved_generation_secs - 0.6).abs() < 1e-9);
+        assert!((stats.evicted_generation_secs - 0.42).abs() < 1e-9);
+    }
+
     #[test]
     fn test_cache_make_key() {
         let key = SegmentCache::make_key("abc123", "video:5");
@@ -465,4 +870,61 @@ mod tests {
         assert!(!cache.is_empty());
         assert_eq!(cache.len(), 1);
     }
+
+    #[test]
+    fn test_stream_expiry() {
+        let index = StreamIndex::new(std::path::PathBuf::from("/test/video.mp4"));
+        let now = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        index.touch();
+        assert!(!index.is_expired(600, 3600));
+
+        index.last_accessed.store(now - 700, Ordering::Relaxed);
+        assert!(index.is_expired(600, 3600));
+
+        // Paused: the last keep-alive was less than the paused timeout ago.
+        index.last_keepalive.store(now - 700, Ordering::Relaxed);
+        assert!(!index.is_expired(600, 3600));
+        assert!(index.is_expired(600, 600));
+
+        index.keepalive();
+        assert!(!index.is_expired(600, 0));
+    }
+
+    #[test]
+    fn test_stable_stream_id() {
+        use crate::index::scanner::IndexOptions;
+        use std::io::Write;
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"not really a video").unwrap();
+        let options = IndexOptions::default();
+
+        let id = crate::media::stable_stream_id(file.path(), &options).unwrap();
+        assert_eq!(
+            id,
+            crate::media::stable_stream_id(file.path(), &options).unwrap()
+        );
+
+        // Different indexing options give a different id.
+        let options2 = IndexOptions {
+            segment_duration_secs: 6.0,
+            ..Default::default()
+        };
+        assert_ne!(
+            id,
+            crate::media::stable_stream_id(file.path(), &options2).unwrap()
+        );
+
+        // So does a changed file.
+        file.write_all(b" at all").unwrap();
+        file.flush().unwrap();
+        assert_ne!(
+            id,
+            crate::media::stable_stream_id(file.path(), &options).unwrap()
+        );
+    }
 }
@@ -0,0 +1,402 @@
+//! Tunables of the library.
+//!
+//! Values that used to be constants (the segment duration, the AAC bitrate
+//! per channel count, the sample rate of transcoded audio, the `mfhd`
+//! sequence number multiplier and the stream timeout) are collected in
+//! `LibConfig`, together with how transcoded audio is mixed down to stereo
+//! and the volumes of a mix of two audio tracks, whether new sessions
+//! start before their segment index is complete, and the number of segments
+//! above which the segments of a file are made longer. A stream takes its config
+//! when it is opened, from `HlsVideo::open_with_config()`, or else the one
+//! installed with `set_lib_config()`; the defaults are the former constants.
+//!
+//! ```ignore
+//! let config = LibConfig::default()
+//!     .segment_duration(6.0)
+//!     .sample_rate(44100);
+//! hls_vod_lib::set_lib_config(config);
+//! ```
+
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+/// Library configuration, see the module documentation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LibConfig {
+    /// Target duration of video segments in seconds. Segments are cut at
+    /// keyframes, so they are only about this long.
+    pub segment_duration_secs: f64,
+    /// Bitrate of transcoded AAC audio per channel count, unless one is set
+    /// with `set_aac_encoder()`
+    pub aac_bitrates: AacBitrates,
+    /// Sample rate of transcoded AAC audio, in Hz
+    pub sample_rate: u32,
+    /// Stereo downmix of transcoded multichannel audio
+    pub downmix: DownmixConfig,
+    /// Volumes of the tracks of an audio mix
+    pub audio_mix: AudioMixConfig,
+    /// The `mfhd` sequence number of the first fragment of segment N is
+    /// `N * fragment_sequence_multiplier + 1`. Raise it for segments with
+    /// more than one fragment, so sequence numbers keep increasing. At most
+    /// `MAX_FRAGMENT_SEQUENCE_MULTIPLIER`.
+    pub fragment_sequence_multiplier: u32,
+    /// Seconds after which an unused stream is closed. `None` uses
+    /// `SegmentCacheConfig::stream_timeout_secs`.
+    pub stream_timeout_secs: Option<u64>,
+    /// Serve the main playlist of a new session before the segment index is
+    /// built, see `StreamIndex::open_first_phase()`
+    pub fast_start: bool,
+    /// Files that would have more segments than this get longer segments
+    /// (at least 6 seconds), see `StreamIndex::segment_escalation`. 0 is no
+    /// limit.
+    pub max_segments: usize,
+}
+
+impl Default for LibConfig {
+    fn default() -> Self {
+        Self {
+            segment_duration_secs: 4.0,
+            aac_bitrates: AacBitrates::default(),
+            sample_rate: 48000,
+            downmix: DownmixConfig::default(),
+            audio_mix: AudioMixConfig::default(),
+            fragment_sequence_multiplier: 1,
+            stream_timeout_secs: None,
+            fast_start: false,
+            max_segments: crate::index::scanner::DEFAULT_MAX_SEGMENTS,
+        }
+    }
+}
+
+impl LibConfig {
+    /// Highest `fragment_sequence_multiplier`. The `mfhd` sequence numbers
+    /// of titles of more than 4 million segments would overflow beyond it.
+    pub const MAX_FRAGMENT_SEQUENCE_MULTIPLIER: u32 = 1000;
+
+    /// The same config, with target segment duration `secs`.
+    pub fn segment_duration(mut self, secs: f64) -> Self {
+        self.segment_duration_secs = secs;
+        self
+    }
+
+    /// The same config, with AAC bitrates `bitrates`.
+    pub fn aac_bitrates(mut self, bitrates: AacBitrates) -> Self {
+        self.aac_bitrates = bitrates;
+        self
+    }
+
+    /// The same config, with transcoded audio at `sample_rate` Hz.
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// The same config, with stereo downmix `downmix`.
+    pub fn downmix(mut self, downmix: DownmixConfig) -> Self {
+        self.downmix = downmix;
+        self
+    }
+
+    /// The same config, with audio mix volumes `audio_mix`.
+    pub fn audio_mix(mut self, audio_mix: AudioMixConfig) -> Self {
+        self.audio_mix = audio_mix;
+        self
+    }
+
+    /// The same config, with `mfhd` sequence multiplier `multiplier`.
+    pub fn fragment_sequence_multiplier(mut self, multiplier: u32) -> Self {
+        self.fragment_sequence_multiplier = multiplier;
+        self
+    }
+
+    /// The same config, with streams closed after `secs` seconds unused.
+    pub fn stream_timeout(mut self, secs: u64) -> Self {
+        self.stream_timeout_secs = Some(secs);
+        self
+    }
+
+    /// The same config, with the main playlist served before the segment
+    /// index is complete if `enabled`.
+    pub fn fast_start(mut self, enabled: bool) -> Self {
+        self.fast_start = enabled;
+        self
+    }
+
+    /// The same config, with longer segments for files with more than
+    /// `max` segments. 0 is no limit.
+    pub fn max_segments(mut self, max: usize) -> Self {
+        self.max_segments = max;
+        self
+    }
+
+    /// Check the values, `Err` names the first invalid one.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if !(self.segment_duration_secs >= 1.0 && self.segment_duration_secs <= 60.0) {
+            return Err(format!(
+                "segment_duration_secs must be between 1 and 60, not {}",
+                self.segment_duration_secs
+            ));
+        }
+        if !(8000..=96000).contains(&self.sample_rate) {
+            return Err(format!(
+                "sample_rate must be between 8000 and 96000, not {}",
+                self.sample_rate
+            ));
+        }
+        if !(1..=Self::MAX_FRAGMENT_SEQUENCE_MULTIPLIER)
+            .contains(&self.fragment_sequence_multiplier)
+        {
+            return Err(format!(
+                "fragment_sequence_multiplier must be between 1 and {}, not {}",
+                Self::MAX_FRAGMENT_SEQUENCE_MULTIPLIER,
+                self.fragment_sequence_multiplier
+            ));
+        }
+        self.downmix.validate()?;
+        self.audio_mix.validate()
+    }
+}
+
+/// AAC bitrates in bits per second, per channel count of the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AacBitrates {
+    pub mono: u64,
+    pub stereo: u64,
+    /// 5.1
+    pub surround: u64,
+    /// 7.1
+    pub surround_7_1: u64,
+    /// Any other channel count
+    pub other: u64,
+}
+
+impl Default for AacBitrates {
+    fn default() -> Self {
+        Self {
+            mono: 64_000,
+            stereo: 128_000,
+            surround: 384_000,
+            surround_7_1: 512_000,
+            other: 128_000,
+        }
+    }
+}
+
+impl AacBitrates {
+    /// The bitrate for `channels` channels.
+    pub fn for_channels(&self, channels: u16) -> u64 {
+        match channels {
+            1 => self.mono,
+            2 => self.stereo,
+            6 => self.surround,
+            8 => self.surround_7_1,
+            _ => self.other,
+        }
+    }
+}
+
+/// Stereo downmix matrix, see `DownmixConfig`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DownmixMode {
+    /// The ITU-R BS.775 matrix: the center and surrounds are added to the
+    /// front channels at their mix levels.
+    #[default]
+    Itu,
+    /// Dolby Pro Logic II matrix encoding: the surrounds are added with a
+    /// phase shift, so a Pro Logic II receiver can extract them again.
+    Dplii,
+}
+
+impl DownmixMode {
+    /// The FFmpeg `matrix_encoding` option of the mode.
+    pub fn matrix_encoding(&self) -> &'static str {
+        match self {
+            DownmixMode::Itu => "none",
+            DownmixMode::Dplii => "dplii",
+        }
+    }
+}
+
+/// How audio with more than two channels is mixed down to stereo when it is
+/// transcoded to AAC.
+///
+/// Mix levels are linear gains. With the default matrix, dialogue in the
+/// center channel often ends up too quiet next to music and effects; a higher
+/// `center_mix_level` brings it forward.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DownmixConfig {
+    /// Downmix matrix
+    pub mode: DownmixMode,
+    /// Gain of the center channel, default 0.707 (-3 dB)
+    pub center_mix_level: f64,
+    /// Gain of the surround channels, default 0.707 (-3 dB)
+    pub surround_mix_level: f64,
+    /// Gain of the LFE channel, default 0 (left out)
+    pub lfe_mix_level: f64,
+}
+
+impl Default for DownmixConfig {
+    fn default() -> Self {
+        Self {
+            mode: DownmixMode::Itu,
+            center_mix_level: std::f64::consts::FRAC_1_SQRT_2,
+            surround_mix_level: std::f64::consts::FRAC_1_SQRT_2,
+            lfe_mix_level: 0.0,
+        }
+    }
+}
+
+impl DownmixConfig {
+    /// Highest mix level, +12 dB.
+    pub const MAX_MIX_LEVEL: f64 = 4.0;
+
+    /// Check the mix levels, `Err` names the first invalid one.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        for (name, level) in [
+            ("center_mix_level", self.center_mix_level),
+            ("surround_mix_level", self.surround_mix_level),
+            ("lfe_mix_level", self.lfe_mix_level),
+        ] {
+            if !(0.0..=Self::MAX_MIX_LEVEL).contains(&level) {
+                return Err(format!(
+                    "downmix {} must be between 0 and {}, not {}",
+                    name,
+                    Self::MAX_MIX_LEVEL,
+                    level
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Volumes of the two tracks of an audio mix, see `MainPlaylist::audio_mix()`.
+///
+/// Volumes are linear gains. By default the main track is at half volume,
+/// so that the commentary mixed over it can be understood.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioMixConfig {
+    /// Gain of the main track, default 0.5 (-6 dB)
+    pub main_volume: f64,
+    /// Gain of the track mixed in, default 1.0
+    pub secondary_volume: f64,
+}
+
+impl Default for AudioMixConfig {
+    fn default() -> Self {
+        Self {
+            main_volume: 0.5,
+            secondary_volume: 1.0,
+        }
+    }
+}
+
+impl AudioMixConfig {
+    /// Check the volumes, `Err` names the first invalid one.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        for (name, volume) in [
+            ("main_volume", self.main_volume),
+            ("secondary_volume", self.secondary_volume),
+        ] {
+            if !(0.0..=DownmixConfig::MAX_MIX_LEVEL).contains(&volume) {
+                return Err(format!(
+                    "audio_mix {} must be between 0 and {}, not {}",
+                    name,
+                    DownmixConfig::MAX_MIX_LEVEL,
+                    volume
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+static LIB_CONFIG: RwLock<Option<Arc<LibConfig>>> = RwLock::new(None);
+
+/// Install the config of streams opened with `HlsVideo::open()`.
+///
+/// Streams that are open already keep the config they were opened with.
+pub fn set_lib_config(config: LibConfig) -> crate::Result<()> {
+    config.validate().map_err(crate::HlsError::Config)?;
+    *LIB_CONFIG.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(config));
+    Ok(())
+}
+
+/// The config installed with `set_lib_config()`, or the defaults.
+pub fn lib_config() -> Arc<LibConfig> {
+    LIB_CONFIG
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lib_config_defaults() {
+        let config = LibConfig::default();
+        assert_eq!(config.segment_duration_secs, 4.0);
+        assert_eq!(config.sample_rate, 48000);
+        assert_eq!(config.aac_bitrates.for_channels(1), 64_000);
+        assert_eq!(config.aac_bitrates.for_channels(6), 384_000);
+        assert_eq!(config.aac_bitrates.for_channels(4), 128_000);
+        assert!(config.validate().is_ok());
+
+        let config: LibConfig =
+            toml::from_str("sample_rate = 44100\n[aac_bitrates]\nstereo = 96000").unwrap();
+        assert_eq!(config.sample_rate, 44100);
+        assert_eq!(config.aac_bitrates.for_channels(2), 96_000);
+        assert_eq!(config.aac_bitrates.for_channels(1), 64_000);
+        assert_eq!(config.segment_duration_secs, 4.0);
+
+        let config: LibConfig =
+            toml::from_str("[downmix]\nmode = \"dplii\"\ncenter_mix_level = 1.0").unwrap();
+        assert_eq!(config.downmix.mode, DownmixMode::Dplii);
+        assert_eq!(config.downmix.center_mix_level, 1.0);
+        assert_eq!(config.downmix.lfe_mix_level, 0.0);
+    }
+
+    #[test]
+    fn test_lib_config_validate() {
+        assert!(LibConfig::default()
+            .segment_duration(0.5)
+            .validate()
+            .is_err());
+        assert!(LibConfig::default().sample_rate(0).validate().is_err());
+        assert!(LibConfig::default()
+            .fragment_sequence_multiplier(0)
+            .validate()
+            .is_err());
+        assert!(LibConfig::default()
+            .fragment_sequence_multiplier(LibConfig::MAX_FRAGMENT_SEQUENCE_MULTIPLIER + 1)
+            .validate()
+            .is_err());
+        let downmix = DownmixConfig {
+            center_mix_level: -1.0,
+            ..Default::default()
+        };
+        assert!(LibConfig::default().downmix(downmix).validate().is_err());
+        let audio_mix = AudioMixConfig {
+            secondary_volume: 5.0,
+            ..Default::default()
+        };
+        assert!(LibConfig::default()
+            .audio_mix(audio_mix)
+            .validate()
+            .is_err());
+        assert!(LibConfig::default()
+            .segment_duration(6.0)
+            .stream_timeout(60)
+            .validate()
+            .is_ok());
+    }
+}
@@ -1,3 +1,6 @@
+use std::fmt;
+use std::path::PathBuf;
+
 use thiserror::Error;
 
 /// Main error type for the HLS server
@@ -7,6 +10,13 @@ pub enum HlsError {
     #[error("FFmpeg error: {0}")]
     Ffmpeg(#[from] FfmpegError),
 
+    /// An FFmpeg error, with the stream, track and segment it occurred in
+    #[error("FFmpeg error: {source} ({context})")]
+    FfmpegContext {
+        source: FfmpegError,
+        context: Box<ErrorContext>,
+    },
+
     /// A standard I/O error
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
@@ -39,6 +49,11 @@ pub enum HlsError {
     #[error("No supported audio codec found")]
     NoSupportedAudio,
 
+    /// The source file changed since the stream was indexed, and the
+    /// segment is not in the cache
+    #[error("Source changed: {0}")]
+    SourceChanged(String),
+
     /// A requested text subtitle stream could not be found
     #[error("No text subtitle stream found")]
     NoTextSubtitle,
@@ -78,6 +93,64 @@ pub enum HlsError {
     /// A process or task exceeded the allowed memory limit
     #[error("Memory limit exceeded")]
     MemoryLimit,
+
+    /// The requested functionality was compiled out (cargo feature not enabled)
+    #[error("Feature not enabled: {0}")]
+    FeatureDisabled(&'static str),
+}
+
+impl HlsError {
+    /// Attach `context` to an FFmpeg error. Other errors are returned as-is.
+    pub fn with_context(self, context: ErrorContext) -> HlsError {
+        match self {
+            HlsError::Ffmpeg(source) => HlsError::FfmpegContext {
+                source,
+                context: Box::new(context),
+            },
+            other => other,
+        }
+    }
+
+    /// How a retry of the request that failed would fare.
+    pub fn class(&self) -> crate::segment::retry::ErrorClass {
+        crate::segment::retry::classify(self)
+    }
+
+    /// The FFmpeg error, with or without context.
+    pub fn ffmpeg_error(&self) -> Option<&FfmpegError> {
+        match self {
+            HlsError::Ffmpeg(e) | HlsError::FfmpegContext { source: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Where an FFmpeg error occurred.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorContext {
+    pub stream_id: String,
+    pub path: PathBuf,
+    pub track: Option<usize>,
+    /// Segment sequence number
+    pub sequence: Option<usize>,
+    /// The last FFmpeg log messages of the thread that failed
+    pub ffmpeg_log: Vec<String>,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "stream {}, file {}", self.stream_id, self.path.display())?;
+        if let Some(track) = self.track {
+            write!(f, ", track {}", track)?;
+        }
+        if let Some(sequence) = self.sequence {
+            write!(f, ", segment {}", sequence)?;
+        }
+        if !self.ffmpeg_log.is_empty() {
+            write!(f, "; FFmpeg log: {}", self.ffmpeg_log.join(" | "))?;
+        }
+        Ok(())
+    }
 }
 
 /// FFmpeg-specific errors
@@ -166,3 +239,33 @@ pub enum FfmpegError {
 
 /// Result type alias for convenience
 pub type Result<T> = std::result::Result<T, HlsError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_context() {
+        let context = ErrorContext {
+            stream_id: "abc".to_string(),
+            path: PathBuf::from("/media/a.mkv"),
+            track: Some(1),
+            sequence: Some(12),
+            ffmpeg_log: vec!["[aac] Invalid data".to_string()],
+        };
+        let err = HlsError::from(FfmpegError::ReadFrame("EOF".to_string())).with_context(context);
+        assert!(matches!(
+            err.ffmpeg_error(),
+            Some(FfmpegError::ReadFrame(_))
+        ));
+        assert_eq!(
+            err.to_string(),
+            "FFmpeg error: Failed to read frame: EOF (stream abc, file /media/a.mkv, \
+             track 1, segment 12; FFmpeg log: [aac] Invalid data)"
+        );
+
+        // Other errors are left alone.
+        let err = HlsError::NoVideoStream.with_context(ErrorContext::default());
+        assert!(matches!(err, HlsError::NoVideoStream));
+    }
+}
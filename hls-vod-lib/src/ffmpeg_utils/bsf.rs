@@ -0,0 +1,244 @@
+//! Bitstream filters for passthrough audio.
+//!
+//! Passthrough packets are copied into the fMP4 output as-is, but some
+//! sources carry a bitstream that is not valid in MP4. The typical case is
+//! AAC from MPEG-TS (or some MKV files), which is stored with ADTS headers;
+//! players refuse such a track. A bitstream filter (`aac_adtstoasc`) strips
+//! the headers and produces the AudioSpecificConfig the `esds` box needs.
+//!
+//! Filters are configured per codec with [`set_audio_bitstream_filters`].
+//! By default only AAC is filtered. AC-3 and E-AC-3 are copied bit-exact,
+//! which preserves their dialnorm and dynamic range metadata.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::sync::RwLock;
+
+use ffmpeg_next as ffmpeg;
+
+use crate::error::{FfmpegError, Result};
+
+/// Filter chain per codec name, `None` until configured.
+static FILTERS: RwLock<Option<HashMap<String, String>>> = RwLock::new(None);
+
+/// The filters applied when nothing was configured.
+pub fn default_audio_bitstream_filters() -> HashMap<String, String> {
+    [("aac".to_string(), "aac_adtstoasc".to_string())].into()
+}
+
+/// Set the bitstream filters for passthrough audio.
+///
+/// Maps an FFmpeg codec name (`aac`, `ac3`, `eac3`, ...) to a filter chain
+/// in FFmpeg syntax (`filter1=opt=value,filter2`). An empty chain disables
+/// filtering of that codec. Replaces the defaults.
+pub fn set_audio_bitstream_filters(filters: HashMap<String, String>) -> Result<()> {
+    for (codec, chain) in &filters {
+        if chain.is_empty() {
+            continue;
+        }
+        // Check the syntax and that all filters exist.
+        BitstreamFilter::parse(chain).map_err(|e| {
+            crate::error::HlsError::Config(format!(
+                "invalid bitstream filter for {}: {}: {}",
+                codec, chain, e
+            ))
+        })?;
+    }
+    *FILTERS.write().unwrap_or_else(|e| e.into_inner()) = Some(filters);
+    Ok(())
+}
+
+/// The filter chain configured for `codec_id`, if any.
+fn audio_filter_chain(codec_id: ffmpeg::codec::Id) -> Option<String> {
+    let name = codec_id.name();
+    let filters = FILTERS.read().unwrap_or_else(|e| e.into_inner());
+    let chain = match filters.as_ref() {
+        Some(filters) => filters.get(name).cloned(),
+        None => default_audio_bitstream_filters().remove(name),
+    };
+    chain.filter(|c| !c.is_empty())
+}
+
+/// A (chain of) FFmpeg bitstream filter(s).
+pub struct BitstreamFilter {
+    ctx: *mut ffmpeg::ffi::AVBSFContext,
+}
+
+// SAFETY: the context is owned by this struct and only used through `&mut self`.
+unsafe impl Send for BitstreamFilter {}
+
+impl BitstreamFilter {
+    /// The bitstream filter configured for a passthrough audio stream, if any.
+    ///
+    /// `time_base` is the time base of the packets that will be sent.
+    pub fn for_audio(
+        params: &ffmpeg::codec::Parameters,
+        time_base: ffmpeg::Rational,
+    ) -> Result<Option<Self>> {
+        match audio_filter_chain(params.id()) {
+            Some(chain) => Self::new(&chain, params, time_base).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Create and initialize the filter chain `chain` for a stream with
+    /// codec parameters `params`.
+    pub fn new(
+        chain: &str,
+        params: &ffmpeg::codec::Parameters,
+        time_base: ffmpeg::Rational,
+    ) -> Result<Self> {
+        let filter = Self::parse(chain)?;
+        // SAFETY: `filter.ctx` was allocated by `av_bsf_list_parse_str` and is
+        // not initialized yet, so `par_in` and `time_base_in` may be set.
+        // `params.as_ptr()` is valid for the lifetime of `params`.
+        unsafe {
+            let ret = ffmpeg::ffi::avcodec_parameters_copy((*filter.ctx).par_in, params.as_ptr());
+            if ret < 0 {
+                return Err(bsf_error(chain, ret));
+            }
+            (*filter.ctx).time_base_in = time_base.into();
+            let ret = ffmpeg::ffi::av_bsf_init(filter.ctx);
+            if ret < 0 {
+                return Err(bsf_error(chain, ret));
+            }
+        }
+        Ok(filter)
+    }
+
+    fn parse(chain: &str) -> Result<Self> {
+        let c_chain = CString::new(chain)
+            .map_err(|_| FfmpegError::StreamConfig(format!("invalid filter chain {:?}", chain)))?;
+        let mut ctx = std::ptr::null_mut();
+        // SAFETY: `c_chain` is a valid C string, `ctx` receives a newly
+        // allocated context on success.
+        let ret = unsafe { ffmpeg::ffi::av_bsf_list_parse_str(c_chain.as_ptr(), &mut ctx) };
+        if ret < 0 {
+            return Err(bsf_error(chain, ret));
+        }
+        Ok(BitstreamFilter { ctx })
+    }
+
+    /// Codec parameters of the filtered stream.
+    ///
+    /// Some filters only know these after the first packet (e.g. the
+    /// AudioSpecificConfig that `aac_adtstoasc` takes from the ADTS header).
+    pub fn output_parameters(&self) -> ffmpeg::codec::Parameters {
+        let mut params = ffmpeg::codec::Parameters::new();
+        // SAFETY: `par_out` is valid after `av_bsf_init`, `params` owns a
+        // freshly allocated `AVCodecParameters`.
+        unsafe {
+            ffmpeg::ffi::avcodec_parameters_copy(params.as_mut_ptr(), (*self.ctx).par_out);
+        }
+        params
+    }
+
+    /// Filter one packet. Returns the packets that came out, usually one.
+    pub fn filter(&mut self, packet: &mut ffmpeg::Packet) -> Result<Vec<ffmpeg::Packet>> {
+        // SAFETY: `av_bsf_send_packet` takes the packet's references and
+        // resets it, `packet` stays a valid (empty) packet.
+        let ret = unsafe { ffmpeg::ffi::av_bsf_send_packet(self.ctx, packet.as_mut_ptr()) };
+        if ret < 0 {
+            return Err(bsf_error("send", ret));
+        }
+        self.receive()
+    }
+
+    /// Signal the end of the stream and return the packets still buffered.
+    pub fn flush(&mut self) -> Result<Vec<ffmpeg::Packet>> {
+        // SAFETY: a null packet signals EOF.
+        let ret = unsafe { ffmpeg::ffi::av_bsf_send_packet(self.ctx, std::ptr::null_mut()) };
+        if ret < 0 {
+            return Err(bsf_error("flush", ret));
+        }
+        self.receive()
+    }
+
+    fn receive(&mut self) -> Result<Vec<ffmpeg::Packet>> {
+        let mut packets = Vec::new();
+        loop {
+            let mut out = ffmpeg::Packet::empty();
+            // SAFETY: `out` is a valid, empty packet that receives the output.
+            let ret = unsafe { ffmpeg::ffi::av_bsf_receive_packet(self.ctx, out.as_mut_ptr()) };
+            if ret >= 0 {
+                packets.push(out);
+                continue;
+            }
+            match ffmpeg::Error::from(ret) {
+                ffmpeg::Error::Other { errno } if errno == ffmpeg::error::EAGAIN => break,
+                ffmpeg::Error::Eof => break,
+                _ => return Err(bsf_error("receive", ret)),
+            }
+        }
+        Ok(packets)
+    }
+}
+
+impl Drop for BitstreamFilter {
+    fn drop(&mut self) {
+        // SAFETY: `ctx` was allocated by `av_bsf_list_parse_str`;
+        // `av_bsf_free` sets it to null.
+        unsafe { ffmpeg::ffi::av_bsf_free(&mut self.ctx) };
+    }
+}
+
+fn bsf_error(what: &str, err: i32) -> crate::error::HlsError {
+    FfmpegError::StreamConfig(format!(
+        "bitstream filter {}: {}",
+        what,
+        ffmpeg::Error::from(err)
+    ))
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filter_chain() {
+        assert!(BitstreamFilter::parse("aac_adtstoasc").is_ok());
+        assert!(BitstreamFilter::parse("null,aac_adtstoasc").is_ok());
+        assert!(BitstreamFilter::parse("no_such_filter").is_err());
+    }
+
+    #[test]
+    fn test_default_filters() {
+        assert_eq!(
+            default_audio_bitstream_filters()
+                .get("aac")
+                .map(String::as_str),
+            Some("aac_adtstoasc")
+        );
+        assert!(!default_audio_bitstream_filters().contains_key("ac3"));
+    }
+
+    #[test]
+    fn test_adts_to_asc() {
+        let mut params = ffmpeg::codec::Parameters::new();
+        crate::ffmpeg_utils::helpers::codec_params_set_for_test(
+            &mut params,
+            ffmpeg::ffi::AVCodecID::AV_CODEC_ID_AAC,
+            1024,
+            128_000,
+        );
+        let mut bsf =
+            BitstreamFilter::new("aac_adtstoasc", &params, ffmpeg::Rational::new(1, 48000))
+                .unwrap();
+
+        // ADTS header: AAC-LC, 48 kHz, stereo, frame length 7 + 2, followed
+        // by a 2-byte payload.
+        let frame = [0xff, 0xf1, 0x4c, 0x80, 0x01, 0x3f, 0xfc, 0x21, 0x00];
+        let mut packet = ffmpeg::Packet::copy(&frame);
+        packet.set_pts(Some(1024));
+        let out = bsf.filter(&mut packet).unwrap();
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].data(), Some(&frame[7..]));
+        assert_eq!(out[0].pts(), Some(1024));
+
+        // The AudioSpecificConfig: AAC-LC, 48 kHz, stereo.
+        let extradata =
+            crate::ffmpeg_utils::helpers::codec_params_extradata(&bsf.output_parameters());
+        assert_eq!(extradata, vec![0x11, 0x90]);
+    }
+}
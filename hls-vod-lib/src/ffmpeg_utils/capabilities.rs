@@ -0,0 +1,169 @@
+//! Capabilities of the linked FFmpeg build
+//!
+//! FFmpeg builds differ a lot: distribution packages often lack
+//! `libfdk_aac`, minimal builds lack decoders for E-AC-3 or TrueHD. The
+//! components that matter to the pipeline are probed once, reported at
+//! `init()`, and consulted by playlist generation, so that a track is not
+//! advertised as AAC if this build cannot decode its source codec.
+//!
+//! Passthrough needs no codecs at all, only the demuxers and the mp4 muxer.
+
+use std::collections::BTreeSet;
+use std::ffi::CString;
+use std::sync::OnceLock;
+
+use ffmpeg_next as ffmpeg;
+use ffmpeg_next::codec::Id;
+use serde::Serialize;
+
+/// Input formats of the source files.
+const DEMUXERS: &[&str] = &["mp4", "matroska", "webm", "mpegts"];
+
+/// Output formats of the segments.
+const MUXERS: &[&str] = &["mp4", "webvtt"];
+
+/// Source codecs that may have to be decoded for transcoding.
+const DECODERS: &[Id] = &[
+    Id::H264,
+    Id::HEVC,
+    Id::AV1,
+    Id::VP9,
+    Id::AAC,
+    Id::AC3,
+    Id::EAC3,
+    Id::DTS,
+    Id::TRUEHD,
+    Id::FLAC,
+    Id::MP3,
+    Id::OPUS,
+    Id::VORBIS,
+    Id::WEBVTT,
+];
+
+/// Encoders, by name.
+const ENCODERS: &[&str] = &["aac", "libfdk_aac", "webvtt"];
+
+/// The pipeline-relevant components present in the linked FFmpeg.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FfmpegCapabilities {
+    pub demuxers: BTreeSet<String>,
+    pub muxers: BTreeSet<String>,
+    /// Codec names, e.g. `eac3`
+    pub decoders: BTreeSet<String>,
+    /// Encoder names, e.g. `libfdk_aac`
+    pub encoders: BTreeSet<String>,
+    /// Components that were probed for but are not present, as `<kind> <name>`
+    pub missing: Vec<String>,
+}
+
+impl FfmpegCapabilities {
+    /// Probe the linked FFmpeg.
+    pub fn probe() -> FfmpegCapabilities {
+        let mut caps = FfmpegCapabilities::default();
+
+        for name in DEMUXERS {
+            let cname = CString::new(*name).unwrap();
+            // SAFETY: looks up a static format by name, the result is only
+            // checked for null.
+            let found = unsafe { !ffmpeg::ffi::av_find_input_format(cname.as_ptr()).is_null() };
+            caps.add(found, "demuxer", name, |c| &mut c.demuxers);
+        }
+        for name in MUXERS {
+            let cname = CString::new(*name).unwrap();
+            // SAFETY: as above.
+            let found = unsafe {
+                !ffmpeg::ffi::av_guess_format(cname.as_ptr(), std::ptr::null(), std::ptr::null())
+                    .is_null()
+            };
+            caps.add(found, "muxer", name, |c| &mut c.muxers);
+        }
+        for id in DECODERS {
+            let found = super::helpers::decoder_exists(*id);
+            caps.add(found, "decoder", id.name(), |c| &mut c.decoders);
+        }
+        for name in ENCODERS {
+            let found = ffmpeg::codec::encoder::find_by_name(name).is_some();
+            caps.add(found, "encoder", name, |c| &mut c.encoders);
+        }
+        caps
+    }
+
+    fn add(
+        &mut self,
+        found: bool,
+        kind: &str,
+        name: &str,
+        set: impl Fn(&mut Self) -> &mut BTreeSet<String>,
+    ) {
+        if found {
+            set(self).insert(name.to_string());
+        } else {
+            self.missing.push(format!("{} {}", kind, name));
+        }
+    }
+
+    /// Whether `codec` can be decoded.
+    ///
+    /// Codecs that are not in the probed set are looked up directly.
+    pub fn can_decode(&self, codec: Id) -> bool {
+        if DECODERS.contains(&codec) {
+            self.decoders.contains(codec.name())
+        } else {
+            super::helpers::decoder_exists(codec)
+        }
+    }
+
+    /// Whether there is an AAC encoder, native or `libfdk_aac`.
+    pub fn has_aac_encoder(&self) -> bool {
+        self.encoders.contains("aac") || self.encoders.contains("libfdk_aac")
+    }
+
+    /// Whether audio in `codec` can be transcoded to AAC.
+    pub fn can_transcode_audio(&self, codec: Id) -> bool {
+        self.can_decode(codec) && self.has_aac_encoder()
+    }
+
+    /// Log the capabilities, and what is missing as a warning.
+    pub fn log(&self) {
+        tracing::info!(
+            "FFmpeg capabilities: demuxers {:?}, muxers {:?}, decoders {:?}, encoders {:?}",
+            self.demuxers,
+            self.muxers,
+            self.decoders,
+            self.encoders
+        );
+        if !self.missing.is_empty() {
+            tracing::warn!("FFmpeg build lacks: {}", self.missing.join(", "));
+        }
+    }
+}
+
+static CAPABILITIES: OnceLock<FfmpegCapabilities> = OnceLock::new();
+
+/// The capabilities of the linked FFmpeg, probed on first use.
+pub fn capabilities() -> &'static FfmpegCapabilities {
+    CAPABILITIES.get_or_init(FfmpegCapabilities::probe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe() {
+        crate::ffmpeg_utils::init().unwrap();
+        let caps = capabilities();
+
+        // Needed by every test that generates segments.
+        assert!(caps.demuxers.contains("mp4"));
+        assert!(caps.muxers.contains("mp4"));
+        assert!(caps.can_decode(Id::AAC));
+        assert!(caps.has_aac_encoder());
+
+        // Everything probed is either present or missing.
+        let probed = DEMUXERS.len() + MUXERS.len() + DECODERS.len() + ENCODERS.len();
+        let found =
+            caps.demuxers.len() + caps.muxers.len() + caps.decoders.len() + caps.encoders.len();
+        assert_eq!(found + caps.missing.len(), probed);
+    }
+}
@@ -49,6 +49,38 @@ pub fn codec_params_bit_rate(params: &ffmpeg::codec::parameters::Parameters) ->
     unsafe { (*params.as_ptr()).bit_rate as u64 }
 }
 
+/// Read `video_delay` from an `AVCodecParameters` struct: the number of
+/// frames the decoder holds back to reorder them, non-zero with B-frames.
+pub fn codec_params_video_delay(params: &ffmpeg::codec::parameters::Parameters) -> u32 {
+    unsafe { (*params.as_ptr()).video_delay.max(0) as u32 }
+}
+
+/// Copy `extradata` out of an `AVCodecParameters` struct.
+pub fn codec_params_extradata(params: &ffmpeg::codec::parameters::Parameters) -> Vec<u8> {
+    unsafe {
+        let p = params.as_ptr();
+        if (*p).extradata.is_null() || (*p).extradata_size <= 0 {
+            return Vec::new();
+        }
+        std::slice::from_raw_parts((*p).extradata, (*p).extradata_size as usize).to_vec()
+    }
+}
+
+/// Copy the picture out of an attached picture (cover art) stream.
+///
+/// The demuxer reads it into `AVStream.attached_pic` when the file is opened.
+pub fn stream_attached_pic(stream: &ffmpeg::Stream) -> Vec<u8> {
+    // SAFETY: `stream.as_ptr()` is valid for the lifetime of `stream`, and
+    // `attached_pic` is owned by the stream; the data is copied out.
+    unsafe {
+        let pkt = &(*stream.as_ptr()).attached_pic;
+        if pkt.data.is_null() || pkt.size <= 0 {
+            return Vec::new();
+        }
+        std::slice::from_raw_parts(pkt.data, pkt.size as usize).to_vec()
+    }
+}
+
 /// Zero out `codec_tag` on the `AVCodecParameters` attached to an output
 /// stream, so the muxer picks the correct tag for the target container.
 ///
@@ -63,6 +95,36 @@ pub fn stream_reset_codec_tag(out_stream: &mut ffmpeg::format::stream::StreamMut
     }
 }
 
+/// Set the codec of an output stream whose packets are already in the
+/// format of that codec.
+///
+/// Must be called after `out_stream.set_parameters(...)` and before
+/// `write_header`.
+pub fn stream_set_codec_id(
+    out_stream: &mut ffmpeg::format::stream::StreamMut,
+    codec_id: ffmpeg::codec::Id,
+) {
+    // SAFETY: `out_stream.as_mut_ptr()` is valid for the lifetime of the
+    // stream.  `codecpar` is set by `set_parameters` and is non-null;
+    // `codec_id` is a plain enum field.
+    unsafe {
+        (*(*out_stream.as_mut_ptr()).codecpar).codec_id = codec_id.into();
+    }
+}
+
+/// Mark an output stream as an attached picture (cover art), so the muxer
+/// writes its one packet as the cover of the file.
+///
+/// Must be called before `write_header`.
+pub fn stream_set_attached_pic(out_stream: &mut ffmpeg::format::stream::StreamMut) {
+    // SAFETY: `out_stream.as_mut_ptr()` is valid for the lifetime of the
+    // stream; `disposition` is a plain int field.
+    unsafe {
+        (*out_stream.as_mut_ptr()).disposition |=
+            ffmpeg::format::stream::Disposition::ATTACHED_PIC.bits();
+    }
+}
+
 /// Allocate a fresh `AVCodecParameters`, copy the encoder context into it,
 /// and return it as a safe `ffmpeg::codec::Parameters`.
 ///
@@ -105,6 +167,21 @@ pub fn detach_avio(output: &mut ffmpeg::format::context::Output) {
     }
 }
 
+/// Flush the `AVIOContext` buffer of an `Output` into its write callback.
+///
+/// With a custom IO context, data written by the muxer sits in the AVIO
+/// buffer until it fills up; this pushes it through to the `MemoryWriter`.
+pub fn flush_avio(output: &mut ffmpeg::format::context::Output) {
+    // SAFETY: `output.as_mut_ptr()` is valid for the lifetime of `output`, and
+    // `pb` is either null or the AVIO context created by `create_memory_io`.
+    unsafe {
+        let ctx = output.as_mut_ptr();
+        if !ctx.is_null() && !(*ctx).pb.is_null() {
+            ffmpeg::ffi::avio_flush((*ctx).pb);
+        }
+    }
+}
+
 // ── Subtitle codec lookup ────────────────────────────────────────────────────
 
 /// Returns `true` if a decoder is registered for `codec_id`.
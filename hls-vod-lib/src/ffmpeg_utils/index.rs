@@ -70,6 +70,23 @@ pub fn read_index_entries(stream: &ffmpeg::Stream) -> Vec<IndexEntry> {
     }
 }
 
+/// Approximate heap size of an open input context.
+///
+/// Dominated by the per-stream index tables (one `AVIndexEntry` per sample)
+/// plus the AVIO read buffer. Used for memory accounting only.
+pub fn input_memory(input: &ffmpeg::format::context::Input) -> usize {
+    // AVIO buffer and the format/stream structs themselves.
+    const CONTEXT_OVERHEAD: usize = 64 * 1024;
+    let entries: usize = input
+        .streams()
+        .map(|stream| unsafe {
+            let stream_ptr = stream.as_ptr() as *mut ffmpeg::ffi::AVStream;
+            ffmpeg::ffi::avformat_index_get_entries_count(stream_ptr).max(0) as usize
+        })
+        .sum();
+    CONTEXT_OVERHEAD + entries * std::mem::size_of::<ffmpeg::ffi::AVIndexEntry>()
+}
+
 /// Seek to an exact byte offset in the file using `AVSEEK_FLAG_BYTE`.
 ///
 /// This is the most precise seek available: it positions the AVIO read pointer
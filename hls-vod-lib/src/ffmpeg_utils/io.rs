@@ -1,7 +1,8 @@
-//! Custom AVIOContext for in-memory writing
+//! Custom AVIOContext for in-memory writing and reading
 //!
 //! This module provides a custom IO context that writes to a `Vec<u8>`
-//! instead of a file, enabling completely in-memory muxing.
+//! instead of a file, enabling completely in-memory muxing, and one that
+//! reads a media file from memory, see `open_memory_input()`.
 //!
 //! # Thread safety
 //! `MemoryWriter` is intentionally NOT thread-safe. Each muxer instance is
@@ -13,14 +14,20 @@
 
 use ffmpeg_next as ffmpeg;
 use std::ffi::c_void;
-use std::io::{Seek, SeekFrom, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
 use std::ptr;
+use std::sync::Arc;
 
 /// Custom IO context that writes to an in-memory buffer.
 /// Single-threaded use only — one instance per muxer, never shared across threads.
 pub struct MemoryWriter {
     buffer: Vec<u8>,
     position: u64,
+    /// Offset up to which data has been handed out by `take_complete_boxes`.
+    taken: usize,
+    /// Set once the first media box (`styp`/`moof`) has been seen.
+    seen_media: bool,
 }
 
 impl MemoryWriter {
@@ -29,6 +36,8 @@ impl MemoryWriter {
         Self {
             buffer: Vec::with_capacity(4096),
             position: 0,
+            taken: 0,
+            seen_media: false,
         }
     }
 
@@ -37,6 +46,11 @@ impl MemoryWriter {
         self.buffer.clone()
     }
 
+    /// Number of bytes written so far
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
     /// Check if the buffer is empty
     #[allow(dead_code)] // we need this for testing and development
     pub fn is_empty(&self) -> bool {
@@ -47,6 +61,58 @@ impl MemoryWriter {
     pub fn clear(&mut self) {
         self.buffer.clear();
         self.position = 0;
+        self.taken = 0;
+        self.seen_media = false;
+    }
+
+    /// Return a copy of the top-level boxes that have been written completely
+    /// since the last call, starting at the first `styp` or `moof` box.
+    ///
+    /// This allows a consumer to forward finished fragments (for example to a
+    /// channel that feeds an HTTP response) while the muxer is still running.
+    /// Header boxes (`ftyp`, `moov`) are skipped, so the concatenation of all
+    /// returned chunks equals the media part of the final buffer.
+    pub fn take_complete_boxes(&mut self) -> Option<Vec<u8>> {
+        let mut chunk_start = None;
+        let mut pos = self.taken;
+        while pos + 8 <= self.buffer.len() {
+            // size 0 ("to end of file") is never complete here.
+            if self.buffer[pos..pos + 4] == [0; 4] {
+                break;
+            }
+            let Some((_, size, box_type)) = crate::segment::isobmff::box_header(&self.buffer, pos)
+            else {
+                break;
+            };
+            if !self.seen_media && (&box_type == b"moof" || &box_type == b"styp") {
+                self.seen_media = true;
+            }
+            if self.seen_media && chunk_start.is_none() {
+                chunk_start = Some(pos);
+            }
+            pos += size;
+        }
+        self.taken = pos;
+        chunk_start.map(|s| self.buffer[s..pos].to_vec())
+    }
+
+    /// The offset just past the first complete `mdat` box from offset `from`
+    /// on, which must be the start of a box. That is the end of the next
+    /// fragment that has been written completely, if there is one.
+    pub fn fragment_end(&self, from: usize) -> Option<usize> {
+        let mut pos = from;
+        while pos + 8 <= self.buffer.len() {
+            // size 0 ("to end of file") is never complete here.
+            if self.buffer[pos..pos + 4] == [0; 4] {
+                return None;
+            }
+            let (_, size, box_type) = crate::segment::isobmff::box_header(&self.buffer, pos)?;
+            pos += size;
+            if &box_type == b"mdat" {
+                return Some(pos);
+            }
+        }
+        None
     }
 }
 
@@ -203,6 +269,205 @@ pub fn create_memory_io(
     }
 }
 
+/// Reads a media file that is held in memory.
+pub struct MemoryReader {
+    data: Arc<[u8]>,
+    position: u64,
+}
+
+impl MemoryReader {
+    /// Create a reader positioned at the start of `data`.
+    pub fn new(data: Arc<[u8]>) -> Self {
+        Self { data, position: 0 }
+    }
+}
+
+impl Read for MemoryReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = (self.position as usize).min(self.data.len());
+        let n = buf.len().min(self.data.len() - start);
+        buf[..n].copy_from_slice(&self.data[start..start + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for MemoryReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(p) => p as i64,
+            SeekFrom::Current(p) => self.position as i64 + p,
+            SeekFrom::End(p) => self.data.len() as i64 + p,
+        };
+        if new_pos < 0 {
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        }
+        self.position = new_pos as u64;
+        Ok(self.position)
+    }
+}
+
+unsafe extern "C" fn read_packet(opaque: *mut c_void, buf: *mut u8, buf_size: i32) -> i32 {
+    let reader = &mut *(opaque as *mut MemoryReader);
+    let slice = std::slice::from_raw_parts_mut(buf, buf_size.max(0) as usize);
+    match reader.read(slice) {
+        Ok(0) | Err(_) => ffmpeg::ffi::AVERROR_EOF,
+        Ok(n) => n as i32,
+    }
+}
+
+unsafe extern "C" fn seek_reader(opaque: *mut c_void, offset: i64, whence: i32) -> i64 {
+    let reader = &mut *(opaque as *mut MemoryReader);
+
+    // AVSEEK_SIZE: return total size
+    if whence & 0x10000 != 0 {
+        return reader.data.len() as i64;
+    }
+
+    // Without AVSEEK_FORCE (0x20000), which makes no difference in memory.
+    let seek_from = match whence & !0x20000 {
+        0 => SeekFrom::Start(offset.max(0) as u64),
+        1 => SeekFrom::Current(offset),
+        2 => SeekFrom::End(offset),
+        _ => return -1,
+    };
+    match reader.seek(seek_from) {
+        Ok(pos) => pos as i64,
+        Err(_) => -1,
+    }
+}
+
+/// The custom IO context of an input, and the reader behind it.
+struct ReaderIo {
+    avio: *mut ffmpeg::ffi::AVIOContext,
+    reader: *mut MemoryReader,
+}
+
+// Owned by one `InputContext`, which is used by one thread at a time.
+unsafe impl Send for ReaderIo {}
+
+impl Drop for ReaderIo {
+    fn drop(&mut self) {
+        unsafe {
+            // FFmpeg may have replaced the buffer; free the current one.
+            ffmpeg::ffi::av_freep(&mut (*self.avio).buffer as *mut *mut u8 as *mut c_void);
+            ffmpeg::ffi::avio_context_free(&mut self.avio);
+            let _ = Box::from_raw(self.reader);
+        }
+    }
+}
+
+/// An FFmpeg input context, with the in-memory reader it reads from, if any.
+///
+/// Derefs to the `Input`, so it can be used wherever one is expected.
+pub struct InputContext {
+    // Dropped first: closing an input with custom IO leaves the
+    // AVIOContext alone, `reader` frees it afterwards.
+    input: ffmpeg::format::context::Input,
+    reader: Option<ReaderIo>,
+}
+
+impl From<ffmpeg::format::context::Input> for InputContext {
+    fn from(input: ffmpeg::format::context::Input) -> Self {
+        Self {
+            input,
+            reader: None,
+        }
+    }
+}
+
+impl Deref for InputContext {
+    type Target = ffmpeg::format::context::Input;
+
+    fn deref(&self) -> &Self::Target {
+        &self.input
+    }
+}
+
+impl DerefMut for InputContext {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.input
+    }
+}
+
+/// Open a media file that is held in memory.
+///
+/// `name` is only used to guess the format from the extension, and in the
+/// FFmpeg log.
+pub fn open_memory_input(
+    data: Arc<[u8]>,
+    name: &str,
+) -> Result<InputContext, crate::error::FfmpegError> {
+    use crate::error::FfmpegError;
+    let url = std::ffi::CString::new(name)
+        .map_err(|_| FfmpegError::OpenInput(format!("invalid name {:?}", name)))?;
+    unsafe {
+        let reader = Box::into_raw(Box::new(MemoryReader::new(data)));
+
+        // Allocate internal buffer for AVIO
+        let buffer_size = 32768;
+        let buffer = ffmpeg::ffi::av_malloc(buffer_size as usize) as *mut u8;
+        if buffer.is_null() {
+            let _ = Box::from_raw(reader);
+            return Err(FfmpegError::InitFailed(
+                "Failed to allocate AVIO buffer".to_string(),
+            ));
+        }
+
+        let avio = ffmpeg::ffi::avio_alloc_context(
+            buffer,
+            buffer_size,
+            0,
+            reader as *mut c_void,
+            Some(read_packet),
+            None,
+            Some(seek_reader),
+        );
+        if avio.is_null() {
+            ffmpeg::ffi::av_free(buffer as *mut c_void);
+            let _ = Box::from_raw(reader);
+            return Err(FfmpegError::InitFailed(
+                "Failed to allocate AVIO context".to_string(),
+            ));
+        }
+        // From here on, dropping `io` cleans up.
+        let io = ReaderIo { avio, reader };
+
+        let mut ctx = ffmpeg::ffi::avformat_alloc_context();
+        if ctx.is_null() {
+            return Err(FfmpegError::InitFailed(
+                "Failed to allocate format context".to_string(),
+            ));
+        }
+        (*ctx).pb = avio;
+
+        // On failure, FFmpeg frees the context, but not the custom IO.
+        let ret =
+            ffmpeg::ffi::avformat_open_input(&mut ctx, url.as_ptr(), ptr::null(), ptr::null_mut());
+        if ret < 0 {
+            return Err(FfmpegError::OpenInput(format!(
+                "Failed to open {}: {}",
+                name,
+                ffmpeg::Error::from(ret)
+            )));
+        }
+        let ret = ffmpeg::ffi::avformat_find_stream_info(ctx, ptr::null_mut());
+        if ret < 0 {
+            ffmpeg::ffi::avformat_close_input(&mut ctx);
+            return Err(FfmpegError::OpenInput(format!(
+                "Failed to open {}: {}",
+                name,
+                ffmpeg::Error::from(ret)
+            )));
+        }
+
+        Ok(InputContext {
+            input: ffmpeg::format::context::Input::wrap(ctx),
+            reader: Some(io),
+        })
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -213,4 +478,86 @@ mod tests {
         writer.write_all(b"test").unwrap();
         assert_eq!(writer.data(), b"test");
     }
+
+    #[test]
+    fn test_take_complete_boxes() {
+        fn mp4box(t: &[u8; 4], payload: usize) -> Vec<u8> {
+            let mut b = ((payload + 8) as u32).to_be_bytes().to_vec();
+            b.extend_from_slice(t);
+            b.resize(payload + 8, 0);
+            b
+        }
+        let mut writer = MemoryWriter::new();
+        writer.write_all(&mp4box(b"ftyp", 8)).unwrap();
+        writer.write_all(&mp4box(b"moov", 16)).unwrap();
+        writer.write_all(&mp4box(b"moof", 8)).unwrap();
+        let mdat = mp4box(b"mdat", 32);
+        writer.write_all(&mdat[..20]).unwrap();
+
+        // Only the moof is complete; the header boxes are skipped.
+        let chunk = writer.take_complete_boxes().unwrap();
+        assert_eq!(&chunk[4..8], b"moof");
+        assert_eq!(chunk.len(), 16);
+        assert!(writer.take_complete_boxes().is_none());
+
+        writer.write_all(&mdat[20..]).unwrap();
+        let chunk = writer.take_complete_boxes().unwrap();
+        assert_eq!(&chunk[4..8], b"mdat");
+        assert_eq!(chunk.len(), 40);
+
+        // A 64-bit largesize mdat is complete once all of it is written.
+        let mut mdat = vec![0, 0, 0, 1, b'm', b'd', b'a', b't'];
+        mdat.extend_from_slice(&24u64.to_be_bytes());
+        mdat.resize(24, 0);
+        writer.write_all(&mdat[..12]).unwrap();
+        assert!(writer.take_complete_boxes().is_none());
+        writer.write_all(&mdat[12..]).unwrap();
+        assert_eq!(writer.take_complete_boxes().unwrap(), mdat);
+    }
+
+    #[test]
+    fn test_fragment_end() {
+        fn mp4box(t: &[u8; 4], payload: usize) -> Vec<u8> {
+            let mut b = ((payload + 8) as u32).to_be_bytes().to_vec();
+            b.extend_from_slice(t);
+            b.resize(payload + 8, 0);
+            b
+        }
+        let mut writer = MemoryWriter::new();
+        writer.write_all(&mp4box(b"moov", 16)).unwrap();
+        writer.write_all(&mp4box(b"moof", 8)).unwrap();
+        let mdat = mp4box(b"mdat", 32);
+        writer.write_all(&mdat[..20]).unwrap();
+        assert_eq!(writer.fragment_end(0), None);
+
+        writer.write_all(&mdat[20..]).unwrap();
+        assert_eq!(writer.fragment_end(0), Some(80));
+        assert_eq!(writer.fragment_end(80), None);
+        writer.write_all(&mp4box(b"moof", 8)).unwrap();
+        writer.write_all(&mp4box(b"mdat", 8)).unwrap();
+        assert_eq!(writer.fragment_end(80), Some(112));
+    }
+
+    #[test]
+    fn test_memory_reader() {
+        let mut reader = MemoryReader::new(Arc::from(&b"0123456789"[..]));
+        let mut buf = [0u8; 4];
+        assert_eq!(reader.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"0123");
+        assert_eq!(reader.seek(SeekFrom::End(-2)).unwrap(), 8);
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"89");
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert!(reader.seek(SeekFrom::Current(-20)).is_err());
+
+        // Past the end reads nothing, like a file.
+        reader.seek(SeekFrom::Start(100)).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+        let opaque = &mut reader as *mut MemoryReader as *mut c_void;
+        assert_eq!(unsafe { seek_reader(opaque, 0, 0x10000) }, 10);
+        assert_eq!(unsafe { seek_reader(opaque, 3, 0x20000) }, 3);
+        assert_eq!(unsafe { read_packet(opaque, buf.as_mut_ptr(), 4) }, 4);
+        assert_eq!(&buf, b"3456");
+    }
 }
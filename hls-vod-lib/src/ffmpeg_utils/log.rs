@@ -0,0 +1,306 @@
+//! FFmpeg log capture
+//!
+//! FFmpeg messages are routed into `tracing` events with target `ffmpeg`,
+//! instead of being written to stderr. The FFmpeg level maps to the
+//! `tracing` level, the component that logged the message (e.g. `mov`,
+//! `aac`) is the `module` field.
+//!
+//! Messages that are expected side-effects of the muxer configuration are
+//! dropped, and every component is rate limited, so a corrupt file cannot
+//! flood the log. Both are configurable at runtime with
+//! `set_ffmpeg_log_config()`.
+//!
+//! The last few messages of every thread are kept, so that an error can
+//! be reported together with what FFmpeg said just before it.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use ffmpeg_next::ffi;
+use serde::{Deserialize, Serialize};
+
+/// Messages that are expected side-effects of our muxer design.
+const DEFAULT_SUPPRESSED: &[&str] = &[
+    "No meaningful edit list will be written when using empty_moov without delay_moov",
+    "starts with a nonzero dts",
+    "Set the delay_moov flag to handle this case",
+    "Could not update timestamps for skipped samples",
+    "Could not update timestamps for discarded samples",
+    "Error parsing Opus packet header",
+];
+
+/// FFmpeg log capture configuration.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FfmpegLogConfig {
+    /// Messages containing one of these strings are dropped (default: the
+    /// warnings caused by the fragmented mp4 muxer options)
+    pub suppressed: Vec<String>,
+    /// Maximum number of messages per second per component, 0 is
+    /// unlimited (default 20)
+    pub rate_limit: u32,
+}
+
+impl Default for FfmpegLogConfig {
+    fn default() -> Self {
+        FfmpegLogConfig {
+            suppressed: DEFAULT_SUPPRESSED.iter().map(|s| s.to_string()).collect(),
+            rate_limit: 20,
+        }
+    }
+}
+
+static LOG_CONFIG: RwLock<Option<FfmpegLogConfig>> = RwLock::new(None);
+
+/// Set the FFmpeg log configuration. Can be called at any time.
+pub fn set_ffmpeg_log_config(config: FfmpegLogConfig) {
+    *LOG_CONFIG.write().unwrap_or_else(|e| e.into_inner()) = Some(config);
+}
+
+/// The FFmpeg log configuration.
+pub fn ffmpeg_log_config() -> FfmpegLogConfig {
+    LOG_CONFIG
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .unwrap_or_default()
+}
+
+fn is_suppressed(msg: &str) -> bool {
+    let config = LOG_CONFIG.read().unwrap_or_else(|e| e.into_inner());
+    match config.as_ref() {
+        Some(c) => c.suppressed.iter().any(|s| msg.contains(s.as_str())),
+        None => DEFAULT_SUPPRESSED.iter().any(|s| msg.contains(s)),
+    }
+}
+
+fn rate_limit() -> u32 {
+    let config = LOG_CONFIG.read().unwrap_or_else(|e| e.into_inner());
+    config.as_ref().map(|c| c.rate_limit).unwrap_or(20)
+}
+
+/// Number of messages kept per thread.
+const RECENT_LINES: usize = 8;
+
+thread_local! {
+    static RECENT: RefCell<VecDeque<String>> = const { RefCell::new(VecDeque::new()) };
+}
+
+fn remember(module: &str, msg: &str) {
+    RECENT.with(|recent| {
+        let mut recent = recent.borrow_mut();
+        if recent.len() == RECENT_LINES {
+            recent.pop_front();
+        }
+        recent.push_back(format!("[{}] {}", module, msg));
+    });
+}
+
+/// Forget the messages logged by this thread so far.
+pub fn clear_recent_lines() {
+    RECENT.with(|recent| recent.borrow_mut().clear());
+}
+
+/// The last messages logged by this thread, oldest first.
+pub fn take_recent_lines() -> Vec<String> {
+    RECENT.with(|recent| recent.borrow_mut().drain(..).collect())
+}
+
+// Messages of one component in the current one-second window.
+struct Window {
+    start: Instant,
+    count: u32,
+    dropped: u32,
+}
+
+static WINDOWS: Mutex<Option<HashMap<String, Window>>> = Mutex::new(None);
+
+// Whether a message of `module` is within the rate limit. The number of
+// messages dropped in a window is logged with the first message after it.
+fn within_rate_limit(module: &str, limit: u32, now: Instant) -> bool {
+    if limit == 0 {
+        return true;
+    }
+    let mut windows = WINDOWS.lock().unwrap_or_else(|e| e.into_inner());
+    let window = windows
+        .get_or_insert_with(HashMap::new)
+        .entry(module.to_string())
+        .or_insert(Window {
+            start: now,
+            count: 0,
+            dropped: 0,
+        });
+    if now.duration_since(window.start) >= Duration::from_secs(1) {
+        if window.dropped > 0 {
+            tracing::warn!(
+                target: "ffmpeg",
+                module,
+                "{} messages dropped by the rate limit",
+                window.dropped
+            );
+        }
+        *window = Window {
+            start: now,
+            count: 0,
+            dropped: 0,
+        };
+    }
+    window.count += 1;
+    if window.count > limit {
+        window.dropped += 1;
+        return false;
+    }
+    true
+}
+
+// The name of the component that logs, e.g. `mov` or `aac`.
+//
+// SAFETY: `avcl` is null or points to a struct whose first member is an
+// `AVClass` pointer, as documented for `av_log`.
+unsafe fn module_name(avcl: *mut c_void) -> String {
+    if avcl.is_null() {
+        return "ffmpeg".to_string();
+    }
+    let class = *(avcl as *const *const ffi::AVClass);
+    if class.is_null() {
+        return "ffmpeg".to_string();
+    }
+    let name = match (*class).item_name {
+        Some(item_name) => item_name(avcl),
+        None => (*class).class_name,
+    };
+    if name.is_null() {
+        return "ffmpeg".to_string();
+    }
+    // Formats have names like "mov,mp4,m4a,3gp,3g2,mj2".
+    let name = CStr::from_ptr(name).to_string_lossy();
+    name.split(',').next().unwrap_or_default().to_string()
+}
+
+fn emit(level: c_int, module: &str, msg: &str) {
+    match level {
+        l if l <= ffi::AV_LOG_ERROR => tracing::error!(target: "ffmpeg", module, "{}", msg),
+        l if l <= ffi::AV_LOG_WARNING => tracing::warn!(target: "ffmpeg", module, "{}", msg),
+        l if l <= ffi::AV_LOG_INFO => tracing::info!(target: "ffmpeg", module, "{}", msg),
+        l if l <= ffi::AV_LOG_VERBOSE => tracing::debug!(target: "ffmpeg", module, "{}", msg),
+        _ => tracing::trace!(target: "ffmpeg", module, "{}", msg),
+    }
+}
+
+/// Install a FFmpeg log callback that routes messages into `tracing`.
+///
+/// When muxing HLS streams on the fly (especially using `empty_moov` without `delay_moov`
+/// to reduce latency), FFmpeg emits many warnings that are expected side-effects of this
+/// deliberate muxer configuration. These are filtered out, see `FfmpegLogConfig`.
+///
+/// **Safety & Ordering:** Must be called after `init()` and before any threading begins,
+/// because altering the global log callback is not thread-safe.
+pub fn install_log_filter() {
+    // SAFETY: both functions modify global FFmpeg state and are safe to call
+    // after `ffmpeg::init()`.  They are called exactly once at startup before
+    // any threads begin generating segments.
+    unsafe {
+        ffi::av_log_set_level(ffi::AV_LOG_WARNING);
+
+        #[cfg(all(feature = "compat-ffmpeg7", target_os = "linux"))]
+        {
+            // On Linux/FFmpeg 7, the va_list type decays to a pointer in C but is an array in Rust.
+            // We use transmute to bridge the gap between our *mut c_void and the expected *mut __va_list_tag.
+            let callback: unsafe extern "C" fn(*mut c_void, c_int, *const c_char, *mut c_void) =
+                ffmpeg_log_callback;
+            ffi::av_log_set_callback(Some(std::mem::transmute(callback)));
+        }
+
+        #[cfg(any(not(feature = "compat-ffmpeg7"), not(target_os = "linux")))]
+        ffi::av_log_set_callback(Some(ffmpeg_log_callback));
+    }
+}
+
+unsafe extern "C" fn ffmpeg_log_callback(
+    avcl: *mut c_void,
+    level: c_int,
+    fmt: *const c_char,
+    #[cfg(all(feature = "compat-ffmpeg7", target_os = "linux"))] vl: *mut c_void,
+    #[cfg(any(not(feature = "compat-ffmpeg7"), not(target_os = "linux")))] vl: ffi::va_list,
+) {
+    // Respect the configured log level
+    if level > unsafe { ffi::av_log_get_level() } {
+        return;
+    }
+
+    // Format the message using FFmpeg's own vsnprintf helper, without the
+    // "[mov @ 0x...]" prefix; the component is logged as a field.
+    let mut buf = [0 as c_char; 1024];
+    let mut print_prefix: c_int = 0;
+    ffi::av_log_format_line(
+        avcl,
+        level,
+        fmt,
+        vl as _,
+        buf.as_mut_ptr(),
+        buf.len() as c_int,
+        &mut print_prefix,
+    );
+
+    let msg = CStr::from_ptr(buf.as_ptr()).to_string_lossy();
+    let msg = msg.trim_end();
+    if msg.is_empty() || is_suppressed(msg) {
+        return;
+    }
+
+    let module = module_name(avcl);
+    remember(&module, msg);
+    if within_rate_limit(&module, rate_limit(), Instant::now()) {
+        emit(level, &module, msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let start = Instant::now();
+        let module = "test_rate_limit";
+        for _ in 0..3 {
+            assert!(within_rate_limit(module, 3, start));
+        }
+        assert!(!within_rate_limit(module, 3, start));
+        assert!(!within_rate_limit(
+            module,
+            3,
+            start + Duration::from_millis(900)
+        ));
+
+        // A new window.
+        assert!(within_rate_limit(module, 3, start + Duration::from_secs(1)));
+
+        // Other components have their own window, 0 is unlimited.
+        assert!(within_rate_limit("test_rate_limit_other", 3, start));
+        assert!((0..100).all(|_| within_rate_limit(module, 0, start)));
+    }
+
+    #[test]
+    fn test_recent_lines() {
+        clear_recent_lines();
+        for i in 0..RECENT_LINES + 2 {
+            remember("mov", &format!("message {}", i));
+        }
+        let lines = take_recent_lines();
+        assert_eq!(lines.len(), RECENT_LINES);
+        assert_eq!(lines[0], "[mov] message 2");
+        assert!(take_recent_lines().is_empty());
+    }
+
+    #[test]
+    fn test_default_suppressed() {
+        assert!(is_suppressed(
+            "Set the delay_moov flag to handle this case."
+        ));
+        assert!(!is_suppressed("Invalid NAL unit size"));
+    }
+}
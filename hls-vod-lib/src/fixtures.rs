@@ -0,0 +1,657 @@
+//! Synthesized test media
+//!
+//! Generates deterministic media files with the FFmpeg encoders, so tests
+//! don't depend on checked-in videos: color bars with a moving bar, a sine
+//! tone per audio track (a different pitch per track), SubRip subtitles
+//! (timed text in MP4), and optionally a variable frame rate, B-frames or a
+//! cover picture.
+//!
+//! Files are written once to `$TMPDIR/hls-vod-fixtures/` and reused by
+//! later tests and test runs, as long as neither the spec nor
+//! `FIXTURE_VERSION` changes. A fixture whose encoder is not available in
+//! this FFmpeg build (e.g. no libx265) is not generated, and `generate`
+//! returns `None` so the test can be skipped.
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use ffmpeg_next as ffmpeg;
+use ffmpeg_next::codec;
+use ffmpeg_next::format::Pixel;
+use ffmpeg_next::util::channel_layout::ChannelLayout;
+use ffmpeg_next::util::format::sample::{Sample, Type as SampleType};
+
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 240;
+const FPS: i32 = 24;
+const SAMPLE_RATE: i32 = 48000;
+
+/// Part of the file name of every fixture. Raise it when the generated
+/// media changes without a change of `FixtureSpec`, so stale files are
+/// not reused.
+const FIXTURE_VERSION: u32 = 1;
+
+/// An audio track.
+#[derive(Debug, Clone)]
+pub struct AudioSpec {
+    pub codec: codec::Id,
+    pub channels: i32,
+    pub language: &'static str,
+}
+
+/// A synthesized media file.
+#[derive(Debug, Clone)]
+pub struct FixtureSpec {
+    pub name: &'static str,
+    /// FFmpeg muxer name, `mp4` or `matroska`
+    pub container: &'static str,
+    pub duration_secs: u32,
+    pub video: Option<codec::Id>,
+    /// Alternate between 24 and 30 fps frame durations
+    pub vfr: bool,
+    /// Encode with B-frames, so the decode timestamps start below zero
+    pub b_frames: bool,
+    pub audio: Vec<AudioSpec>,
+    /// Languages of the SubRip subtitle tracks
+    pub subtitles: Vec<&'static str>,
+    /// Add a PNG cover picture (an attached picture stream)
+    pub cover_art: bool,
+}
+
+fn audio(codec: codec::Id, channels: i32, language: &'static str) -> AudioSpec {
+    AudioSpec {
+        codec,
+        channels,
+        language,
+    }
+}
+
+impl FixtureSpec {
+    fn new(name: &'static str, container: &'static str) -> Self {
+        FixtureSpec {
+            name,
+            container,
+            duration_secs: 20,
+            video: Some(codec::Id::H264),
+            vfr: false,
+            b_frames: false,
+            audio: Vec::new(),
+            subtitles: Vec::new(),
+            cover_art: false,
+        }
+    }
+
+    /// H.264 + AAC in MP4
+    pub fn h264_aac() -> Self {
+        FixtureSpec {
+            audio: vec![audio(codec::Id::AAC, 2, "eng")],
+            ..Self::new("h264_aac", "mp4")
+        }
+    }
+
+    /// HEVC + AAC in Matroska
+    pub fn hevc_mkv() -> Self {
+        FixtureSpec {
+            video: Some(codec::Id::HEVC),
+            audio: vec![audio(codec::Id::AAC, 2, "eng")],
+            ..Self::new("hevc_mkv", "matroska")
+        }
+    }
+
+    /// H.264 + AC-3 5.1
+    pub fn ac3_surround() -> Self {
+        FixtureSpec {
+            audio: vec![audio(codec::Id::AC3, 6, "eng")],
+            ..Self::new("ac3_surround", "matroska")
+        }
+    }
+
+    /// H.264 + Opus
+    pub fn opus() -> Self {
+        FixtureSpec {
+            audio: vec![audio(codec::Id::OPUS, 2, "eng")],
+            ..Self::new("opus", "matroska")
+        }
+    }
+
+    /// Variable frame rate H.264 + AAC in MP4
+    pub fn vfr() -> Self {
+        FixtureSpec {
+            vfr: true,
+            audio: vec![audio(codec::Id::AAC, 2, "eng")],
+            ..Self::new("vfr", "mp4")
+        }
+    }
+
+    /// H.264 with B-frames + AAC + subtitles in MP4: the first decode
+    /// timestamp is negative. Not in `all()`.
+    pub fn b_frames() -> Self {
+        FixtureSpec {
+            b_frames: true,
+            audio: vec![audio(codec::Id::AAC, 2, "eng")],
+            subtitles: vec!["eng"],
+            ..Self::new("b_frames", "mp4")
+        }
+    }
+
+    /// Two audio languages and two subtitle languages
+    pub fn multi_language() -> Self {
+        FixtureSpec {
+            audio: vec![
+                audio(codec::Id::AAC, 2, "eng"),
+                audio(codec::Id::AC3, 2, "spa"),
+            ],
+            subtitles: vec!["eng", "spa"],
+            ..Self::new("multi_language", "matroska")
+        }
+    }
+
+    /// MP3 with cover art and no video. Not in `all()`, which are videos.
+    pub fn mp3_cover() -> Self {
+        FixtureSpec {
+            video: None,
+            audio: vec![audio(codec::Id::MP3, 2, "eng")],
+            cover_art: true,
+            ..Self::new("mp3_cover", "mp3")
+        }
+    }
+
+    /// All fixtures.
+    pub fn all() -> Vec<FixtureSpec> {
+        vec![
+            Self::h264_aac(),
+            Self::hevc_mkv(),
+            Self::ac3_surround(),
+            Self::opus(),
+            Self::vfr(),
+            Self::multi_language(),
+        ]
+    }
+
+    // File name stem: the name, and a hash of the version and the whole
+    // spec, so that a changed spec gets a new file.
+    fn file_stem(&self) -> String {
+        use std::hash::{DefaultHasher, Hasher};
+
+        let mut hasher = DefaultHasher::new();
+        hasher.write(format!("{}{:?}", FIXTURE_VERSION, self).as_bytes());
+        format!("{}-{:016x}", self.name, hasher.finish())
+    }
+
+    fn extension(&self) -> &'static str {
+        match self.container {
+            "matroska" => "mkv",
+            other => other,
+        }
+    }
+}
+
+// Serializes generation, so parallel tests don't write the same file.
+static GENERATE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Generate the media file for `spec`, or return the one generated earlier.
+///
+/// Returns `None` if an encoder is not available.
+pub fn generate(spec: &FixtureSpec) -> Option<PathBuf> {
+    let _guard = GENERATE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    crate::ffmpeg_utils::init().unwrap();
+
+    let dir = std::env::temp_dir().join("hls-vod-fixtures");
+    let path = dir.join(format!("{}.{}", spec.file_stem(), spec.extension()));
+    if path.exists() {
+        return Some(path);
+    }
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // Write to a temporary file first, so an interrupted run leaves nothing behind.
+    let tmp = dir.join(format!("{}.tmp.{}", spec.file_stem(), spec.extension()));
+    match write_fixture(spec, &tmp) {
+        Ok(()) => {
+            std::fs::rename(&tmp, &path).unwrap();
+            Some(path)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp);
+            println!("Not generating fixture {}: {}", spec.name, e);
+            None
+        }
+    }
+}
+
+// A stream of the output file, and its packets in `time_base`.
+struct Track {
+    index: usize,
+    time_base: ffmpeg::Rational,
+    packets: Vec<ffmpeg::Packet>,
+}
+
+fn write_fixture(spec: &FixtureSpec, path: &std::path::Path) -> Result<(), String> {
+    let mut output = ffmpeg::format::output_as(path, spec.container).map_err(|e| e.to_string())?;
+    let global_header = output
+        .format()
+        .flags()
+        .contains(ffmpeg::format::Flags::GLOBAL_HEADER);
+
+    let mut tracks = Vec::new();
+    if let Some(video) = spec.video {
+        tracks.push(encode_video(&mut output, spec, video, global_header)?);
+    }
+    for (i, a) in spec.audio.iter().enumerate() {
+        tracks.push(encode_audio(&mut output, spec, a, i, global_header)?);
+    }
+    if spec.cover_art {
+        tracks.push(encode_cover(&mut output)?);
+    }
+    let dir = path.parent().unwrap();
+    for (i, language) in spec.subtitles.iter().enumerate() {
+        tracks.push(copy_subtitles(&mut output, spec, language, i, dir)?);
+    }
+
+    output.write_header().map_err(|e| e.to_string())?;
+
+    // Write the packets of all tracks in time order.
+    let mut packets = Vec::new();
+    for track in &mut tracks {
+        let out_tb = output.stream(track.index).unwrap().time_base();
+        for mut packet in track.packets.drain(..) {
+            let secs =
+                packet.dts().or(packet.pts()).unwrap_or(0) as f64 * f64::from(track.time_base);
+            packet.rescale_ts(track.time_base, out_tb);
+            packet.set_stream(track.index);
+            packets.push((secs, track.index, packet));
+        }
+    }
+    packets.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+    for (_, _, packet) in packets {
+        packet
+            .write_interleaved(&mut output)
+            .map_err(|e| e.to_string())?;
+    }
+    output.write_trailer().map_err(|e| e.to_string())
+}
+
+fn set_language(output: &mut ffmpeg::format::context::Output, index: usize, language: &str) {
+    let mut metadata = ffmpeg::Dictionary::new();
+    metadata.set("language", language);
+    output.stream_mut(index).unwrap().set_metadata(metadata);
+}
+
+// Color bars, with a white bar moving one bar width per second, so that
+// every frame is different and the encoder produces P-frames.
+fn fill_video_frame(frame: &mut ffmpeg::frame::Video, n: i64) {
+    const BARS: [(u8, u8, u8); 8] = [
+        (235, 128, 128),
+        (210, 16, 146),
+        (170, 166, 16),
+        (145, 54, 34),
+        (106, 202, 222),
+        (81, 90, 240),
+        (41, 240, 110),
+        (16, 128, 128),
+    ];
+    let bar_width = WIDTH as usize / BARS.len();
+    let moving = (n / FPS as i64) as usize % BARS.len();
+    for plane in 0..3 {
+        let (w, h) = if plane == 0 {
+            (WIDTH as usize, HEIGHT as usize)
+        } else {
+            (WIDTH as usize / 2, HEIGHT as usize / 2)
+        };
+        let stride = frame.stride(plane);
+        let data = frame.data_mut(plane);
+        for y in 0..h {
+            for x in 0..w {
+                let bar = (x * if plane == 0 { 1 } else { 2 }) / bar_width;
+                let (yv, u, v) = if bar == moving && y < h / 4 {
+                    (235, 128, 128)
+                } else {
+                    BARS[bar.min(BARS.len() - 1)]
+                };
+                data[y * stride + x] = match plane {
+                    0 => yv,
+                    1 => u,
+                    _ => v,
+                };
+            }
+        }
+    }
+}
+
+fn receive_packets<F>(mut receive: F, packets: &mut Vec<ffmpeg::Packet>) -> Result<(), String>
+where
+    F: FnMut(&mut ffmpeg::Packet) -> Result<(), ffmpeg::Error>,
+{
+    loop {
+        let mut packet = ffmpeg::Packet::empty();
+        match receive(&mut packet) {
+            Ok(()) => packets.push(packet),
+            Err(ffmpeg::Error::Other { errno }) if errno == ffmpeg::error::EAGAIN => return Ok(()),
+            Err(ffmpeg::Error::Eof) => return Ok(()),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+fn encode_video(
+    output: &mut ffmpeg::format::context::Output,
+    spec: &FixtureSpec,
+    codec_id: codec::Id,
+    global_header: bool,
+) -> Result<Track, String> {
+    let codec =
+        ffmpeg::encoder::find(codec_id).ok_or_else(|| format!("no {:?} encoder", codec_id))?;
+    // VFR content needs a time base that can express both frame durations.
+    let time_base = if spec.vfr {
+        ffmpeg::Rational::new(1, 1000)
+    } else {
+        ffmpeg::Rational::new(1, FPS)
+    };
+
+    let mut context = codec::Context::new_with_codec(codec);
+    context.set_time_base(time_base);
+    if !spec.vfr {
+        context.set_frame_rate(Some(ffmpeg::Rational::new(FPS, 1)));
+    }
+    if global_header {
+        context.set_flags(codec::Flags::GLOBAL_HEADER);
+    }
+    let mut video = context.encoder().video().map_err(|e| e.to_string())?;
+    video.set_width(WIDTH);
+    video.set_height(HEIGHT);
+    video.set_format(Pixel::YUV420P);
+    // A keyframe every 2 seconds, the usual segment boundary.
+    video.set_gop(2 * FPS as u32);
+    video.set_bit_rate(500_000);
+
+    let mut options = ffmpeg::Dictionary::new();
+    // Deterministic output: one thread, no scene cut detection.
+    options.set("threads", "1");
+    options.set("preset", "veryfast");
+    if spec.b_frames {
+        options.set("x264-params", "scenecut=0:bframes=2:b-pyramid=none");
+    } else {
+        options.set("x264-params", "scenecut=0");
+    }
+    options.set("x265-params", "scenecut=0:log-level=error");
+    let mut encoder = video
+        .open_as_with(codec, options)
+        .map_err(|e| format!("cannot open {:?} encoder: {}", codec_id, e))?;
+
+    let mut stream = output.add_stream(codec).map_err(|e| e.to_string())?;
+    stream.set_parameters(ffmpeg::codec::Parameters::from(&encoder));
+    stream.set_time_base(time_base);
+    let index = stream.index();
+
+    let mut packets = Vec::new();
+    let mut frame = ffmpeg::frame::Video::new(Pixel::YUV420P, WIDTH, HEIGHT);
+    let frames = spec.duration_secs as i64 * FPS as i64;
+    let mut pts = 0;
+    for n in 0..frames {
+        fill_video_frame(&mut frame, n);
+        frame.set_pts(Some(pts));
+        pts += if !spec.vfr {
+            1
+        } else if n % 2 == 0 {
+            42
+        } else {
+            33
+        };
+        encoder.send_frame(&frame).map_err(|e| e.to_string())?;
+        receive_packets(|p| encoder.receive_packet(p), &mut packets)?;
+    }
+    encoder.send_eof().map_err(|e| e.to_string())?;
+    receive_packets(|p| encoder.receive_packet(p), &mut packets)?;
+
+    Ok(Track {
+        index,
+        time_base,
+        packets,
+    })
+}
+
+// A single PNG picture, in a stream marked as an attached picture.
+fn encode_cover(output: &mut ffmpeg::format::context::Output) -> Result<Track, String> {
+    const SIZE: u32 = 64;
+    let codec = ffmpeg::encoder::find(codec::Id::PNG).ok_or("no PNG encoder")?;
+    let time_base = ffmpeg::Rational::new(1, 90000);
+
+    let mut context = codec::Context::new_with_codec(codec);
+    context.set_time_base(time_base);
+    let mut video = context.encoder().video().map_err(|e| e.to_string())?;
+    video.set_width(SIZE);
+    video.set_height(SIZE);
+    video.set_format(Pixel::RGB24);
+    let mut encoder = video
+        .open_as(codec)
+        .map_err(|e| format!("cannot open PNG encoder: {}", e))?;
+
+    let mut stream = output.add_stream(codec).map_err(|e| e.to_string())?;
+    stream.set_parameters(ffmpeg::codec::Parameters::from(&encoder));
+    stream.set_time_base(time_base);
+    crate::ffmpeg_utils::helpers::stream_set_attached_pic(&mut stream);
+    let index = stream.index();
+
+    let mut frame = ffmpeg::frame::Video::new(Pixel::RGB24, SIZE, SIZE);
+    frame.data_mut(0).fill(0x80);
+    frame.set_pts(Some(0));
+    let mut packets = Vec::new();
+    encoder.send_frame(&frame).map_err(|e| e.to_string())?;
+    encoder.send_eof().map_err(|e| e.to_string())?;
+    receive_packets(|p| encoder.receive_packet(p), &mut packets)?;
+    packets.truncate(1);
+
+    Ok(Track {
+        index,
+        time_base,
+        packets,
+    })
+}
+
+fn write_sample(data: &mut [u8], format: Sample, channels: usize, ch: usize, i: usize, v: f32) {
+    let (size, pos) = match format {
+        Sample::F32(SampleType::Planar) | Sample::I32(SampleType::Planar) => (4, i),
+        Sample::I16(SampleType::Planar) => (2, i),
+        Sample::F32(SampleType::Packed) | Sample::I32(SampleType::Packed) => (4, i * channels + ch),
+        Sample::I16(SampleType::Packed) => (2, i * channels + ch),
+        _ => return,
+    };
+    let bytes = match format {
+        Sample::F32(_) => v.to_ne_bytes().to_vec(),
+        Sample::I32(_) => ((v * i32::MAX as f32) as i32).to_ne_bytes().to_vec(),
+        _ => ((v * i16::MAX as f32) as i16).to_ne_bytes().to_vec(),
+    };
+    data[pos * size..(pos + 1) * size].copy_from_slice(&bytes);
+}
+
+fn encode_audio(
+    output: &mut ffmpeg::format::context::Output,
+    spec: &FixtureSpec,
+    track: &AudioSpec,
+    track_no: usize,
+    global_header: bool,
+) -> Result<Track, String> {
+    let codec = ffmpeg::encoder::find(track.codec)
+        .ok_or_else(|| format!("no {:?} encoder", track.codec))?;
+    let format = codec
+        .audio()
+        .map_err(|e| e.to_string())?
+        .formats()
+        .and_then(|mut f| f.find(|f| matches!(f, Sample::F32(_) | Sample::I32(_) | Sample::I16(_))))
+        .ok_or_else(|| format!("no usable sample format for {:?}", track.codec))?;
+    let layout = ChannelLayout::default(track.channels);
+    let time_base = ffmpeg::Rational::new(1, SAMPLE_RATE);
+
+    let mut context = codec::Context::new_with_codec(codec);
+    context.set_time_base(time_base);
+    if global_header {
+        context.set_flags(codec::Flags::GLOBAL_HEADER);
+    }
+    let mut audio = context.encoder().audio().map_err(|e| e.to_string())?;
+    audio.set_rate(SAMPLE_RATE);
+    audio.set_format(format);
+    audio.set_channel_layout(layout);
+    audio.set_bit_rate(64_000 * track.channels as usize);
+
+    let mut options = ffmpeg::Dictionary::new();
+    // The native Opus encoder is experimental.
+    options.set("strict", "experimental");
+    let mut encoder = audio
+        .open_as_with(codec, options)
+        .map_err(|e| format!("cannot open {:?} encoder: {}", track.codec, e))?;
+
+    let mut stream = output.add_stream(codec).map_err(|e| e.to_string())?;
+    stream.set_parameters(ffmpeg::codec::Parameters::from(&encoder));
+    stream.set_time_base(time_base);
+    let index = stream.index();
+    set_language(output, index, track.language);
+
+    let frame_size = match encoder.frame_size() {
+        0 => 1024,
+        n => n as usize,
+    };
+    let channels = track.channels as usize;
+    let pitch = 440.0 * (track_no + 1) as f32;
+    let total = spec.duration_secs as usize * SAMPLE_RATE as usize;
+
+    let mut packets = Vec::new();
+    let mut pos = 0;
+    while pos < total {
+        let mut frame = ffmpeg::frame::Audio::new(format, frame_size, layout);
+        frame.set_rate(SAMPLE_RATE as u32);
+        frame.set_pts(Some(pos as i64));
+        let planes = if format.is_planar() { channels } else { 1 };
+        for plane in 0..planes {
+            let data = crate::ffmpeg_utils::helpers::audio_plane_data_mut(&mut frame, plane);
+            for i in 0..frame_size {
+                let t = (pos + i) as f32 / SAMPLE_RATE as f32;
+                let v = 0.25 * (2.0 * std::f32::consts::PI * pitch * t).sin();
+                if format.is_planar() {
+                    write_sample(data, format, channels, plane, i, v);
+                } else {
+                    for ch in 0..channels {
+                        write_sample(data, format, channels, ch, i, v);
+                    }
+                }
+            }
+        }
+        encoder.send_frame(&frame).map_err(|e| e.to_string())?;
+        receive_packets(|p| encoder.receive_packet(p), &mut packets)?;
+        pos += frame_size;
+    }
+    encoder.send_eof().map_err(|e| e.to_string())?;
+    receive_packets(|p| encoder.receive_packet(p), &mut packets)?;
+
+    Ok(Track {
+        index,
+        time_base,
+        packets,
+    })
+}
+
+// Subtitles are written as SRT and copied, there is no need for a
+// subtitle encoder. MP4 has no SubRip: a timed text (`tx3g`) sample is the
+// same text after a 16-bit length.
+fn copy_subtitles(
+    output: &mut ffmpeg::format::context::Output,
+    spec: &FixtureSpec,
+    language: &str,
+    track_no: usize,
+    dir: &std::path::Path,
+) -> Result<Track, String> {
+    let mut srt = String::new();
+    for (n, start) in (0..spec.duration_secs).step_by(2).enumerate() {
+        srt.push_str(&format!(
+            "{}\n00:{:02}:{:02},000 --> 00:{:02}:{:02},500\n[{}] Subtitle {}\n\n",
+            n + 1,
+            start / 60,
+            start % 60,
+            (start + 1) / 60,
+            (start + 1) % 60,
+            language,
+            n + 1
+        ));
+    }
+    // Not a tempfile, this module is also built for the benchmarks.
+    let srt_path = dir.join(format!("{}.{}.srt", spec.name, track_no));
+    std::fs::write(&srt_path, srt).map_err(|e| e.to_string())?;
+    let input = ffmpeg::format::input(&srt_path);
+    let _ = std::fs::remove_file(&srt_path);
+    let mut input = input.map_err(|e| e.to_string())?;
+    let (in_index, time_base, parameters) = {
+        let stream = input
+            .streams()
+            .best(ffmpeg::media::Type::Subtitle)
+            .ok_or("no subtitle stream in SRT")?;
+        (stream.index(), stream.time_base(), stream.parameters())
+    };
+
+    let mut stream = output
+        .add_stream(ffmpeg::encoder::find(codec::Id::None))
+        .map_err(|e| e.to_string())?;
+    stream.set_parameters(parameters);
+    crate::ffmpeg_utils::helpers::stream_reset_codec_tag(&mut stream);
+    let mov_text = spec.container == "mp4";
+    if mov_text {
+        crate::ffmpeg_utils::helpers::stream_set_codec_id(&mut stream, codec::Id::MOV_TEXT);
+    }
+    stream.set_time_base(time_base);
+    let index = stream.index();
+    set_language(output, index, language);
+
+    let packets = input
+        .packets()
+        .filter(|(s, _)| s.index() == in_index)
+        .map(|(_, p)| {
+            if !mov_text {
+                return p;
+            }
+            let text = p.data().unwrap_or_default();
+            let mut sample = (text.len() as u16).to_be_bytes().to_vec();
+            sample.extend_from_slice(text);
+            let mut packet = ffmpeg::Packet::copy(&sample);
+            packet.set_pts(p.pts());
+            packet.set_dts(p.dts());
+            packet.set_duration(p.duration());
+            packet
+        })
+        .collect();
+
+    Ok(Track {
+        index,
+        time_base,
+        packets,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::StreamIndex;
+
+    #[test]
+    fn test_generate_fixtures() {
+        for spec in FixtureSpec::all() {
+            let Some(path) = generate(&spec) else {
+                continue;
+            };
+            let index = StreamIndex::open(&path, None).unwrap();
+            assert_eq!(index.video_streams.len(), 1, "{}", spec.name);
+            assert_eq!(index.audio_streams.len(), spec.audio.len(), "{}", spec.name);
+            #[cfg(feature = "subtitles")]
+            assert_eq!(
+                index.subtitle_streams.len(),
+                spec.subtitles.len(),
+                "{}",
+                spec.name
+            );
+            let expected = spec.duration_secs as f64;
+            assert!(
+                (index.duration_secs - expected).abs() < 1.0,
+                "{}: duration {}",
+                spec.name,
+                index.duration_secs
+            );
+            assert!(!index.segments.is_empty(), "{}", spec.name);
+        }
+    }
+}
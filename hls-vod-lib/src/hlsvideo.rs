@@ -5,7 +5,11 @@ use std::path::Path;
 use std::sync::Arc;
 
 use crate::media::StreamIndex;
-use crate::params::{HlsParams, UrlType};
+use crate::params::{AudioSegment, HlsParams, SubtitleTimestamps, UrlType, VideoSegment};
+use crate::segment::compare::SegmentComparison;
+use crate::segment::consistency::ConsistencyCheck;
+use crate::segment::drift::DriftReport;
+use crate::segment::muxer::MuxOptions;
 
 /// Playlist or segment generation.
 ///
@@ -38,10 +42,58 @@ pub enum HlsVideo {
 
 impl HlsVideo {
     /// Create a HlsVideo from a video file and a url.
+    ///
+    /// A stream that is not open yet is opened with the config installed
+    /// with `set_lib_config()`.
     pub fn open(video: &Path, hls_params: HlsParams) -> crate::error::Result<HlsVideo> {
-        let index = StreamIndex::open(video, hls_params.session_id.clone())?;
+        Self::open_with_config(video, hls_params, crate::config::lib_config())
+    }
+
+    /// Create a HlsVideo from a video file and a url, opening the stream
+    /// with library config `config` if it is not open yet. An open stream
+    /// keeps the config it was opened with. Fails with `HlsError::Config`
+    /// if `config` is not valid, see `LibConfig::validate()`.
+    pub fn open_with_config(
+        video: &Path,
+        hls_params: HlsParams,
+        config: Arc<crate::config::LibConfig>,
+    ) -> crate::error::Result<HlsVideo> {
+        config.validate().map_err(crate::HlsError::Config)?;
+        // Fast start: the main playlist of a new session only needs the tracks.
+        let first_phase = config.fast_start
+            && hls_params.session_id.is_none()
+            && matches!(hls_params.url_type, UrlType::MainPlaylist);
+        let index = if first_phase {
+            StreamIndex::open_first_phase(video, config)?
+        } else {
+            StreamIndex::open_with_config(video, hls_params.session_id.clone(), config)?
+        };
+        Self::from_index(index, hls_params)
+    }
+
+    /// Create a HlsVideo from the index of a stream that was opened with
+    /// `StreamIndex::open_async()`.
+    pub fn from_index(
+        index: Arc<StreamIndex>,
+        mut hls_params: HlsParams,
+    ) -> crate::error::Result<HlsVideo> {
+        if !hls_params.resolve_start_time(&index) {
+            return Err(crate::error::HlsError::StreamNotFound(format!(
+                "no segments in {}",
+                index.source_path.display()
+            )));
+        }
+        // A player that reloads playlists is alive, even when paused.
+        if matches!(
+            hls_params.url_type,
+            UrlType::MainPlaylist | UrlType::AudioMainPlaylist(_) | UrlType::Playlist(_)
+        ) {
+            index.keepalive();
+        }
         Ok(match &hls_params.url_type {
-            UrlType::MainPlaylist => HlsVideo::MainPlaylist(MainPlaylist::new(hls_params, index)),
+            UrlType::MainPlaylist | UrlType::AudioMainPlaylist(_) => {
+                HlsVideo::MainPlaylist(MainPlaylist::new(hls_params, index))
+            }
             _ => HlsVideo::PlaylistOrSegment(PlaylistOrSegment { hls_params, index }),
         })
     }
@@ -54,6 +106,62 @@ impl HlsVideo {
         }
     }
 
+    /// Generate playlist or segment, reporting media segment progress to `observer`.
+    ///
+    /// The observer is only called for media segments that are actually
+    /// generated, not for playlists, init segments or cache hits.
+    pub fn generate_with_progress(
+        self,
+        observer: &dyn ProgressObserver,
+    ) -> crate::error::Result<Vec<u8>> {
+        match self {
+            HlsVideo::MainPlaylist(p) => p.generate(),
+            HlsVideo::PlaylistOrSegment(p) => p.generate_with_progress(observer),
+        }
+    }
+
+    /// Generate playlist or segment, and report how it was produced.
+    pub fn generate_with_stats(self) -> crate::error::Result<(Vec<u8>, GenerationStats)> {
+        match self {
+            HlsVideo::MainPlaylist(p) => p.generate_cached(),
+            HlsVideo::PlaylistOrSegment(p) => p.generate_inner(None),
+        }
+    }
+
+    /// Generate playlist or segment, with the metadata needed to serve it.
+    pub fn generate_response(self) -> crate::error::Result<GeneratedResponse> {
+        let content_type = self.mime_type();
+        let cache_control = self.cache_control();
+        let (body, stats, duration) = match self {
+            HlsVideo::MainPlaylist(p) => {
+                let (body, stats) = p.generate_cached()?;
+                (body, stats, None)
+            }
+            HlsVideo::PlaylistOrSegment(p) => {
+                let (body, stats) = p.generate_inner(None)?;
+                (body, stats, p.duration())
+            }
+        };
+        Ok(GeneratedResponse {
+            etag: etag(&body),
+            body,
+            content_type,
+            cache_control,
+            duration,
+            stats,
+        })
+    }
+
+    /// Approximate size in bytes of the playlist or segment, without
+    /// generating it: to set up quotas, or to decide whether to generate it
+    /// at all. See `PlaylistOrSegment::estimate_size()`.
+    pub fn estimate_size(&self) -> Option<usize> {
+        match self {
+            HlsVideo::MainPlaylist(p) => p.estimate_size(),
+            HlsVideo::PlaylistOrSegment(p) => p.estimate_size(),
+        }
+    }
+
     pub fn mime_type(&self) -> &'static str {
         match self {
             HlsVideo::MainPlaylist(p) => p.hls_params.mime_type(),
@@ -68,6 +176,56 @@ impl HlsVideo {
     }
 }
 
+/// A generated playlist or segment, and the metadata needed to serve it.
+#[derive(Debug, Clone)]
+pub struct GeneratedResponse {
+    /// The playlist or segment.
+    pub body: Vec<u8>,
+    /// MIME type, see `HlsParams::mime_type`.
+    pub content_type: &'static str,
+    /// Cache-Control header value, see `HlsParams::cache_control`.
+    pub cache_control: &'static str,
+    /// Entity tag (including the quotes), derived from the body.
+    pub etag: String,
+    /// Play time of a media segment in seconds, `None` for playlists and
+    /// init segments.
+    pub duration: Option<f64>,
+    /// How the body was produced.
+    pub stats: GenerationStats,
+}
+
+/// How a playlist or segment was produced, for the embedder's own metrics.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GenerationStats {
+    /// Whether it came from the segment cache.
+    pub from_cache: bool,
+    /// Time spent generating it (demuxing, transcoding and muxing for
+    /// segments). Zero for cache hits.
+    pub generation_time: std::time::Duration,
+    /// Size in bytes.
+    pub bytes: usize,
+}
+
+impl GenerationStats {
+    fn cached(bytes: usize) -> GenerationStats {
+        GenerationStats {
+            from_cache: true,
+            generation_time: std::time::Duration::ZERO,
+            bytes,
+        }
+    }
+}
+
+// Entity tag of `body`. DefaultHasher is the same in every process running
+// the same build, so the server and its workers agree.
+fn etag(body: &[u8]) -> String {
+    use std::hash::{DefaultHasher, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    hasher.write(body);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
 /// HlsVideo main playlist variant.
 ///
 /// Here you can enable/disable tracks, filter on codecs, set audio/video
@@ -78,7 +236,18 @@ pub struct MainPlaylist {
     pub tracks: HashSet<usize>,
     pub codecs: Vec<String>,
     pub transcode: HashMap<usize, String>,
+    pub aac_alternates: bool,
     pub interleave: bool,
+    pub interleaved_fallback: bool,
+    pub combined_init: bool,
+    pub hdcp_level: Option<String>,
+    pub subtitle_timestamps: Option<SubtitleTimestamps>,
+    pub max_height: Option<u32>,
+    pub max_bitrate: Option<u64>,
+    pub audio_bitrate: Option<u64>,
+    pub audio_mix: Option<(usize, usize)>,
+    pub start_offset: Option<f64>,
+    pub languages: Vec<String>,
 }
 
 /// HlsVideo audio/video/subtitle playlist or segment variant.
@@ -90,6 +259,50 @@ pub struct PlaylistOrSegment {
     pub(crate) index: Arc<StreamIndex>,
 }
 
+/// Progress of a media segment being generated.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SegmentProgress {
+    /// Number of fragments flushed by the muxer so far.
+    pub fragments: usize,
+    /// Number of packets written to the muxer so far.
+    pub packets_written: usize,
+    /// Number of bytes of media data produced so far (init header excluded).
+    pub bytes_written: usize,
+}
+
+/// Observer for media segment generation progress.
+///
+/// Implemented for any `Fn(&SegmentProgress) + Send + Sync` closure.
+///
+/// An observer that returns `true` from `wants_data` also receives the
+/// segment bytes as they are produced, so they can be streamed to a client
+/// before the segment is complete. The chunks passed to `on_data`, in order,
+/// add up to the complete segment. If `on_data` was never called (cache hit,
+/// playlist, init or subtitle segment), the complete output is only available
+/// as the return value of `generate_with_progress`. A streamed segment is
+/// cached as the chunks joined together.
+pub trait ProgressObserver: Send + Sync {
+    /// Called every time the muxer has flushed a fragment to its output buffer.
+    fn on_fragment(&self, progress: &SegmentProgress);
+
+    /// Whether this observer wants to receive segment data via `on_data`.
+    fn wants_data(&self) -> bool {
+        false
+    }
+
+    /// Called with the next chunk of media segment data.
+    fn on_data(&self, _chunk: bytes::Bytes) {}
+}
+
+impl<F> ProgressObserver for F
+where
+    F: Fn(&SegmentProgress) + Send + Sync,
+{
+    fn on_fragment(&self, progress: &SegmentProgress) {
+        self(progress)
+    }
+}
+
 impl PlaylistOrSegment {
     /// Construct directly from an already-opened stream index.
     /// Used in tests where we have an in-memory fixture without a real file path.
@@ -120,38 +333,211 @@ impl MainPlaylist {
             tracks,
             codecs: Vec::new(),
             transcode: HashMap::default(),
+            aac_alternates: false,
             interleave: false,
+            interleaved_fallback: false,
+            combined_init: false,
+            hdcp_level: None,
+            subtitle_timestamps: None,
+            max_height: None,
+            max_bitrate: None,
+            audio_bitrate: None,
+            audio_mix: None,
+            start_offset: None,
+            languages: Vec::new(),
         }
     }
 
     /// Generate the main playlist.
+    ///
+    /// Main playlists only depend on the index and the options set on this
+    /// `MainPlaylist`, so they are cached (see `cache_key`). Players that
+    /// poll the main playlist then don't regenerate it every time. Not if
+    /// the URL codec signs the URLs, see `UrlCodec::is_stable`.
     // TODO: returns Bytes instead of Vec<u8>
     pub fn generate(&self) -> crate::error::Result<Vec<u8>> {
+        self.generate_cached().map(|(playlist, _)| playlist)
+    }
+
+    // The main playlist, and how it was produced.
+    fn generate_cached(&self) -> crate::error::Result<(Vec<u8>, GenerationStats)> {
+        let cache_key = self.cache_key();
+        let cache =
+            crate::cache::segment_cache().filter(|_| crate::params::url_codec().is_stable());
+        if let Some(c) = cache {
+            if let Some(b) = c.get(&self.index.stream_id, &cache_key) {
+                return Ok((b.to_vec(), GenerationStats::cached(b.len())));
+            }
+        }
+
+        let started = std::time::Instant::now();
+        let playlist = self.generate_playlist()?;
+        let stats = GenerationStats {
+            from_cache: false,
+            generation_time: started.elapsed(),
+            bytes: playlist.len(),
+        };
+
+        if let Some(c) = cache {
+            c.insert_with_cost(
+                &self.index.stream_id,
+                &cache_key,
+                bytes::Bytes::from(playlist.clone()),
+                stats.generation_time,
+            );
+        }
+        Ok((playlist, stats))
+    }
+
+    /// Size in bytes of the main playlist. Main playlists are generated from
+    /// the index, without FFmpeg, so this is exact.
+    pub fn estimate_size(&self) -> Option<usize> {
+        if let Some(c) = crate::cache::segment_cache() {
+            if let Some(size) = c.cached_size(&self.index.stream_id, &self.cache_key()) {
+                return Some(size);
+            }
+        }
+        self.generate_playlist().ok().map(|p| p.len())
+    }
+
+    /// The key of the generated playlist in the segment cache.
+    ///
+    /// This is the URL plus a fingerprint of the options and of the index.
+    /// A re-indexed file keeps its stream id with stable stream ids, but
+    /// gets a new `indexed_at`, so it never gets a stale playlist.
+    fn cache_key(&self) -> String {
+        use std::hash::{DefaultHasher, Hash, Hasher};
+
+        let mut hasher = DefaultHasher::new();
+        self.index.indexed_at.hash(&mut hasher);
+        self.index.segments.len().hash(&mut hasher);
+        self.hls_params.video_url.hash(&mut hasher);
+        let mut tracks: Vec<_> = self.tracks.iter().collect();
+        tracks.sort();
+        tracks.hash(&mut hasher);
+        self.codecs.hash(&mut hasher);
+        let mut transcode: Vec<_> = self.transcode.iter().collect();
+        transcode.sort();
+        transcode.hash(&mut hasher);
+        self.aac_alternates.hash(&mut hasher);
+        self.interleave.hash(&mut hasher);
+        self.interleaved_fallback.hash(&mut hasher);
+        self.combined_init.hash(&mut hasher);
+        self.hdcp_level.hash(&mut hasher);
+        self.subtitle_timestamps.map(|t| t.as_str()).hash(&mut hasher);
+        self.max_height.hash(&mut hasher);
+        self.max_bitrate.hash(&mut hasher);
+        self.audio_bitrate.hash(&mut hasher);
+        self.audio_mix.hash(&mut hasher);
+        self.start_offset.map(f64::to_bits).hash(&mut hasher);
+        self.languages.hash(&mut hasher);
+        format!("{}?{:016x}", self.hls_params, hasher.finish())
+    }
+
+    fn generate_playlist(&self) -> crate::error::Result<Vec<u8>> {
         match &self.hls_params.url_type {
+            // An audio file with cover art: the main playlist of its audio.
+            UrlType::MainPlaylist if self.index.primary_video().is_none() => {
+                let audio = self
+                    .index
+                    .audio_streams
+                    .first()
+                    .ok_or(crate::error::HlsError::NoSupportedAudio)?;
+                let playlist = crate::playlist::generate_audio_master_playlist(
+                    &self.index,
+                    &self.hls_params.video_url,
+                    Some(&self.index.stream_id),
+                    audio.stream_index,
+                    &self.codecs,
+                    &self.transcode,
+                    self.combined_init,
+                    self.audio_bitrate,
+                )?;
+                Ok(playlist.into_bytes())
+            }
             UrlType::MainPlaylist => {
+                let tracks = crate::playlist::master::cap_video_tracks(
+                    &self.index,
+                    &self.tracks,
+                    self.max_height,
+                    self.max_bitrate,
+                )?;
                 let playlist = crate::playlist::generate_master_playlist(
                     &self.index,
                     &self.hls_params.video_url,
                     Some(&self.index.stream_id),
                     &self.codecs,
-                    &self.tracks,
+                    &tracks,
                     &self.transcode,
-                    self.interleave,
+                    &crate::playlist::MasterOptions {
+                        aac_alternates: self.aac_alternates,
+                        interleaved: self.interleave,
+                        interleaved_fallback: self.interleaved_fallback,
+                        combined_init: self.combined_init,
+                        hdcp_level: self.hdcp_level.as_deref(),
+                        subtitle_timestamps: self.subtitle_timestamps,
+                        languages: &self.languages,
+                        aac_bitrate: self.audio_bitrate,
+                        audio_mix: self.audio_mix,
+                        start_offset: self.start_offset,
+                    },
                 );
                 Ok(playlist.into_bytes())
             }
+            UrlType::AudioMainPlaylist(a) => {
+                let playlist = crate::playlist::generate_audio_master_playlist(
+                    &self.index,
+                    &self.hls_params.video_url,
+                    Some(&self.index.stream_id),
+                    a.track_id,
+                    &self.codecs,
+                    &self.transcode,
+                    self.combined_init,
+                    self.audio_bitrate,
+                )?;
+                Ok(playlist.into_bytes())
+            }
             _ => panic!("impossible condition"),
         }
     }
 
     /// Enable audio/video interleaving.
     ///
-    /// This will cause audio and video to be interleaved in one
-    /// track, but only if the playlist has _one_ audio track and _one_ video track.
+    /// This will cause audio and video to be interleaved: the main playlist
+    /// gets one muxed variant for every audio track, paired with the video track.
     pub fn interleave(&mut self) {
         self.interleave = true;
     }
 
+    /// Keep the separate audio renditions, but also list the muxed variants
+    /// of `interleave()` after them, for players that can't play the former.
+    pub fn interleaved_fallback(&mut self) {
+        self.interleaved_fallback = true;
+    }
+
+    /// Also advertise every audio track that is not AAC transcoded to AAC.
+    ///
+    /// The alternates are in the `audio-aac` group, next to the group of the
+    /// original codec, so an ABR player can switch to the lighter audio.
+    pub fn aac_alternates(&mut self) {
+        self.aac_alternates = true;
+    }
+
+    /// Serve the init segment as a byterange of the first media segment.
+    ///
+    /// The variant playlists then have no separate init segment URL: the
+    /// first media segment is served with the init segment prepended, and
+    /// `EXT-X-MAP` points to its first bytes.
+    pub fn combined_init(&mut self) {
+        self.combined_init = true;
+    }
+
+    /// Select how the subtitle segments are timed, instead of the
+    /// configured default (see `params::set_subtitle_timestamps`).
+    pub fn subtitle_timestamps(&mut self, mode: SubtitleTimestamps) {
+        self.subtitle_timestamps = Some(mode);
+    }
+
     /// Only leave tracks enabled that match the codecs.
     ///
     /// For now, we only look at audio and subtitles.
@@ -159,16 +545,343 @@ impl MainPlaylist {
         self.codecs = codecs.iter().map(|c| c.as_ref().into()).collect();
     }
 
+    /// Set the HDCP-LEVEL attribute of the video variants.
+    ///
+    /// Valid levels are in `HDCP_LEVELS`: `TYPE-0`, `TYPE-1` and `NONE`.
+    pub fn hdcp_level(&mut self, level: &str) -> crate::error::Result<()> {
+        if !crate::playlist::codec::HDCP_LEVELS.contains(&level) {
+            return Err(crate::error::HlsError::Config(format!(
+                "invalid HDCP-LEVEL: {}",
+                level
+            )));
+        }
+        self.hdcp_level = Some(level.to_string());
+        Ok(())
+    }
+
+    /// Leave out the video tracks taller than `height` lines.
+    ///
+    /// For players on constrained links. There is no video transcoding, so
+    /// if no video track is small enough, generating the playlist fails.
+    pub fn max_height(&mut self, height: u32) {
+        self.max_height = Some(height);
+    }
+
+    /// Leave out the video tracks with a higher bitrate than `bitrate`
+    /// bits per second, like `max_height()`.
+    pub fn max_bitrate(&mut self, bitrate: u64) {
+        self.max_bitrate = Some(bitrate);
+    }
+
+    /// Transcode audio to AAC at `kbps` kbit/s instead of the configured
+    /// bitrate for the channel count, e.g. 96 for clients on slow links.
+    ///
+    /// Applies to the audio tracks that are transcoded, the bitrate is part
+    /// of their playlist and segment URLs (`aac96`) and of the `BANDWIDTH`
+    /// of their variants. Valid bitrates are in `params::AAC_BITRATE_RANGE_KBPS`.
+    pub fn audio_bitrate(&mut self, kbps: u64) -> crate::error::Result<()> {
+        if !crate::params::AAC_BITRATE_RANGE_KBPS.contains(&kbps) {
+            return Err(crate::error::HlsError::Config(format!(
+                "invalid audio bitrate: {} kbit/s",
+                kbps
+            )));
+        }
+        self.audio_bitrate = Some(kbps * 1000);
+        Ok(())
+    }
+
+    /// Also list audio track `main` with audio track `secondary` mixed in,
+    /// e.g. a director's commentary over the film, as one more AAC track.
+    ///
+    /// The volumes of both tracks are set in `LibConfig::audio_mix`. The mix
+    /// is an audio rendition, it is not part of muxed variants.
+    pub fn audio_mix(&mut self, main: usize, secondary: usize) -> crate::error::Result<()> {
+        let is_audio = |idx| self.index.get_audio_stream(idx).is_ok();
+        if main == secondary || !is_audio(main) || !is_audio(secondary) {
+            return Err(crate::error::HlsError::Config(format!(
+                "invalid audio mix: {}+{}",
+                main, secondary
+            )));
+        }
+        self.audio_mix = Some((main, secondary));
+        Ok(())
+    }
+
+    /// Start playback at `secs` seconds into the media instead of at the
+    /// start, e.g. to resume where the viewer left off (`EXT-X-START`).
+    pub fn start_offset(&mut self, secs: f64) -> crate::error::Result<()> {
+        if !(0.0..self.index.duration_secs).contains(&secs) {
+            return Err(crate::error::HlsError::Config(format!(
+                "invalid start offset: {}s",
+                secs
+            )));
+        }
+        self.start_offset = Some(secs);
+        Ok(())
+    }
+
+    /// List the audio and subtitle tracks in these languages first, and
+    /// make them the default. Most preferred first, e.g. `["nl", "en"]`.
+    pub fn preferred_languages(&mut self, languages: &[impl AsRef<str>]) {
+        self.languages = languages.iter().map(|l| l.as_ref().into()).collect();
+    }
+
     /// Enable only the specified tracks.
     pub fn enable_tracks(&mut self, tracks: &[usize]) {
         self.tracks = tracks.iter().cloned().collect();
     }
+
+    /// Check the init segment of every variant and audio playlist against
+    /// media segment `sequence` of that playlist (the last one if the
+    /// playlist is shorter).
+    ///
+    /// For debugging. Subtitle playlists have no init segment and are skipped.
+    pub fn check_consistency(
+        &self,
+        sequence: usize,
+    ) -> crate::error::Result<Vec<ConsistencyCheck>> {
+        let master_path = format!("{}.as.m3u8", self.hls_params.video_url);
+        let master = String::from_utf8_lossy(&self.generate()?).into_owned();
+
+        let mut uris = Vec::new();
+        for line in master.lines() {
+            if line.starts_with("#EXT-X-MEDIA:TYPE=AUDIO") {
+                uris.extend(attribute(line, "URI").map(String::from));
+            } else if !line.starts_with('#') && !line.is_empty() {
+                uris.push(line.to_string());
+            }
+        }
+        let mut seen = HashSet::new();
+        uris.retain(|u| seen.insert(u.clone()));
+
+        // Shifted like the tfdt, see `NegativeTsPolicy`.
+        let shift = crate::segment::profile::negative_ts_policy().shift(self.index.first_dts);
+        let first_start = self
+            .index
+            .segments
+            .first()
+            .map(|s| (s.start_pts + shift) as f64 * f64::from(self.index.video_timebase))
+            .unwrap_or(0.0);
+
+        let mut checks = Vec::new();
+        for uri in uris {
+            let playlist_path = join_uri(&master_path, &uri);
+            let playlist = String::from_utf8_lossy(&self.fetch(&playlist_path)?).into_owned();
+            let mut map = None;
+            let mut segments = Vec::new();
+            let mut extinf = 0.0;
+            for line in playlist.lines() {
+                if line.starts_with("#EXT-X-MAP:") {
+                    map = Some(line.to_string());
+                } else if let Some(v) = line.strip_prefix("#EXTINF:") {
+                    extinf = v.trim_end_matches(',').parse().unwrap_or(0.0);
+                } else if !line.starts_with('#') && !line.is_empty() {
+                    segments.push((extinf, line.to_string()));
+                }
+            }
+            let (Some(map), false) = (map, segments.is_empty()) else {
+                continue;
+            };
+            let init_uri = attribute(&map, "URI").unwrap_or_default().to_string();
+            let mut init = self.fetch(&join_uri(&playlist_path, &init_uri))?;
+            // Combined init: the init segment is the first bytes of a media segment.
+            if let Some((len, offset)) =
+                attribute(&map, "BYTERANGE").and_then(|r| r.split_once('@'))
+            {
+                let len: usize = len.parse().unwrap_or(0);
+                let offset: usize = offset.parse().unwrap_or(0);
+                init = init.get(offset..offset + len).unwrap_or_default().to_vec();
+            }
+
+            let n = sequence.min(segments.len() - 1);
+            let expected_start = first_start + segments[..n].iter().map(|s| s.0).sum::<f64>();
+            let (expected_duration, segment) = segments.swap_remove(n);
+            let data = self.fetch(&join_uri(&playlist_path, &segment))?;
+            checks.push(ConsistencyCheck {
+                problems: crate::segment::consistency::check_segment(
+                    &init,
+                    &data,
+                    Some((expected_start, expected_duration)),
+                ),
+                tracks: crate::segment::consistency::parse_init(&init),
+                playlist: uri,
+                init: init_uri,
+                segment,
+                expected_start,
+                expected_duration,
+            });
+        }
+        Ok(checks)
+    }
+
+    /// Generate every video segment and every segment of audio track
+    /// `audio_track` (the first one if `None`), and report where the audio
+    /// drifts more than `threshold_secs` from the video.
+    ///
+    /// For debugging: it takes as long as generating the whole title.
+    /// Setting `cancel` stops it early, see `DriftReport::cancelled`.
+    pub fn check_drift(
+        &self,
+        audio_track: Option<usize>,
+        threshold_secs: f64,
+        cancel: &std::sync::atomic::AtomicBool,
+    ) -> crate::error::Result<DriftReport> {
+        let video = self
+            .index
+            .primary_video()
+            .ok_or(crate::error::HlsError::NoVideoStream)?;
+        let audio = match audio_track {
+            Some(track) => self.index.get_audio_stream(track)?,
+            None => self
+                .index
+                .audio_streams
+                .first()
+                .ok_or(crate::error::HlsError::NoSupportedAudio)?,
+        };
+        crate::segment::drift::check_drift(
+            &self.index,
+            video.stream_index,
+            audio.stream_index,
+            threshold_secs,
+            cancel,
+        )
+    }
+
+    // Generate the playlist or segment at `request_path`.
+    fn fetch(&self, request_path: &str) -> crate::error::Result<Vec<u8>> {
+        let hls_params = HlsParams::parse(request_path).ok_or_else(|| {
+            crate::error::HlsError::Playlist(format!("cannot parse {}", request_path))
+        })?;
+        PlaylistOrSegment {
+            hls_params,
+            index: self.index.clone(),
+        }
+        .generate()
+    }
+}
+
+// `uri` relative to the playlist at `base`.
+fn join_uri(base: &str, uri: &str) -> String {
+    match base.rfind('/') {
+        Some(pos) => format!("{}/{}", &base[..pos], uri),
+        None => uri.to_string(),
+    }
+}
+
+// The value of quoted attribute `name` of a playlist tag.
+fn attribute<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let start = line.find(&format!("{}=\"", name))? + name.len() + 2;
+    let len = line[start..].find('"')?;
+    Some(&line[start..start + len])
 }
 
 impl PlaylistOrSegment {
     /// Generate the playlist or segment.
     // TODO: returns Bytes instead of Vec<u8>
     pub fn generate(&self) -> crate::error::Result<Vec<u8>> {
+        self.generate_inner(None).map(|(data, _)| data)
+    }
+
+    /// Like `generate`, but reports media segment progress to `observer`.
+    pub fn generate_with_progress(
+        &self,
+        observer: &dyn ProgressObserver,
+    ) -> crate::error::Result<Vec<u8>> {
+        self.generate_inner(Some(observer)).map(|(data, _)| data)
+    }
+
+    /// Play time of this media segment in seconds, `None` for playlists
+    /// and init segments.
+    pub fn duration(&self) -> Option<f64> {
+        let (start, end) = match &self.hls_params.url_type {
+            UrlType::VideoSegment(v) => (v.segment_id?, v.end_segment_id.or(v.segment_id)?),
+            UrlType::AudioSegment(a) => (a.segment_id?, a.end_segment_id.or(a.segment_id)?),
+            UrlType::VttSegment(s) => (s.start_cue, s.end_cue),
+            _ => return None,
+        };
+        let segments = self.index.segments.get(start..=end)?;
+        Some(segments.iter().map(|s| s.duration_secs).sum())
+    }
+
+    /// Approximate size in bytes, without generating it.
+    ///
+    /// Exact if it is in the segment cache. Media segments are estimated
+    /// from the bitrates of their tracks, or from the byte offsets of the
+    /// segments in the source if the video bitrate is not known, plus the
+    /// fMP4 overhead per sample. `None` for WebVTT segments, whose size
+    /// depends on the cues.
+    pub fn estimate_size(&self) -> Option<usize> {
+        if let Some(c) = crate::cache::segment_cache() {
+            let segment_key = self.hls_params.to_string();
+            if let Some(size) = c.cached_size(&self.index.stream_id, &segment_key) {
+                return Some(size);
+            }
+        }
+        let index = &self.index;
+        let (size, with_init) = match &self.hls_params.url_type {
+            UrlType::Playlist(_) => {
+                let size = PLAYLIST_HEADER_BYTES + index.segments.len() * PLAYLIST_SEGMENT_BYTES;
+                return match crate::playlist::variant::playlist_byte_budget() {
+                    0 => Some(size),
+                    budget => Some(size.min(budget)),
+                };
+            }
+            UrlType::EmptyVtt => {
+                return Some(crate::segment::generator::EMPTY_SUBTITLE_SEGMENT.len());
+            }
+            UrlType::VideoSegment(v) if v.segment_id.is_none() => return Some(INIT_SEGMENT_BYTES),
+            UrlType::AudioSegment(a) if a.segment_id.is_none() => return Some(INIT_SEGMENT_BYTES),
+            UrlType::VideoSegment(v) => {
+                let duration = self.duration()?;
+                let video = index
+                    .video_streams
+                    .iter()
+                    .find(|s| s.stream_index == v.track_id)?;
+                let mut size = match video.bitrate {
+                    0 => {
+                        let start = v.segment_id?;
+                        self.source_byte_range(start, v.end_segment_id.unwrap_or(start))?
+                    }
+                    bitrate => bitrate_bytes(bitrate, duration),
+                };
+                let fps = video.framerate.numerator() as f64
+                    / video.framerate.denominator().max(1) as f64;
+                size += (fps * duration) as usize * VIDEO_SAMPLE_BYTES;
+                if let Some(track_id) = v.audio_track_id {
+                    let audio = index.get_audio_stream(track_id).ok()?;
+                    size += audio_bytes(index, audio, v.audio_transcode_to.as_deref(), duration);
+                }
+                (size, v.with_init)
+            }
+            UrlType::AudioSegment(a) => {
+                let duration = self.duration()?;
+                let audio = index.get_audio_stream(a.track_id).ok()?;
+                let size = audio_bytes(index, audio, a.transcode_to.as_deref(), duration);
+                (size, a.with_init)
+            }
+            UrlType::VttSegment(_) | UrlType::MainPlaylist | UrlType::AudioMainPlaylist(_) => {
+                return None;
+            }
+        };
+        let init = if with_init { INIT_SEGMENT_BYTES } else { 0 };
+        Some(init + FRAGMENT_BYTES + size)
+    }
+
+    // Bytes from the start of segment `sequence` to the start of the one
+    // after `end_sequence` in the source, all tracks together.
+    fn source_byte_range(&self, sequence: usize, end_sequence: usize) -> Option<usize> {
+        let start = self.index.segments.get(sequence)?.video_byte_offset;
+        let end = self.index.segments.get(end_sequence + 1)?.video_byte_offset;
+        end.checked_sub(start)
+            .filter(|&n| n > 0)
+            .map(|n| n as usize)
+    }
+
+    // The playlist or segment, and how it was produced.
+    fn generate_inner(
+        &self,
+        progress: Option<&dyn ProgressObserver>,
+    ) -> crate::error::Result<(Vec<u8>, GenerationStats)> {
         let segment_key = self.hls_params.to_string();
 
         // Fast path: check cache without locking.
@@ -179,11 +892,15 @@ impl PlaylistOrSegment {
                 if self.is_media_segment() {
                     self.spawn_lookahead();
                 }
-                return Ok(b.to_vec());
+                return Ok((b.to_vec(), GenerationStats::cached(b.len())));
             }
         }
 
         let is_media_segment = self.is_media_segment();
+        #[cfg(feature = "cache")]
+        if is_media_segment {
+            crate::warmer::note_request();
+        }
 
         // Eager Look-ahead: We spawn the background lookahead generation *before*
         // we block on generating the current segment. This hides the generation
@@ -202,27 +919,141 @@ impl PlaylistOrSegment {
                 // Re-check cache — another thread may have completed while we waited.
                 if let Some(b) = c.get(&self.index.stream_id, &segment_key) {
                     c.cleanup_generation_lock(&self.index.stream_id, &segment_key);
-                    return Ok(b.to_vec());
+                    return Ok((b.to_vec(), GenerationStats::cached(b.len())));
                 }
             }
         }
 
         // Generate the actual content.
-        let (data, cache_it) = self.do_generate()?;
+        crate::ffmpeg_utils::log::clear_recent_lines();
+        let started = std::time::Instant::now();
+        let (data, cache_it) = match self.do_generate(progress) {
+            Ok(generated) => generated,
+            Err(e) => {
+                let context = self.error_context();
+                let sequence = context.sequence.filter(|_| is_media_segment);
+                let e = e.with_context(context);
+                self.index.record_error(&segment_key, sequence, &e);
+                return Err(e);
+            }
+        };
+        let stats = GenerationStats {
+            from_cache: false,
+            generation_time: started.elapsed(),
+            bytes: data.len(),
+        };
 
-        // Insert into cache.
+        // Insert into cache. A streamed segment is cached too: `data` is all
+        // of its fragments joined, which is a complete segment, only cut into
+        // shorter fragments.
         if cache_it {
             if let Some(c) = crate::cache::segment_cache() {
-                c.insert(
+                c.insert_with_cost(
                     &self.index.stream_id,
                     &segment_key,
                     bytes::Bytes::from(data.clone()),
+                    stats.generation_time,
                 );
                 c.cleanup_generation_lock(&self.index.stream_id, &segment_key);
             }
         }
 
-        Ok(data)
+        Ok((data, stats))
+    }
+
+    /// Stream, track and segment of this request, for FFmpeg errors.
+    fn error_context(&self) -> crate::error::ErrorContext {
+        let (track, sequence) = match &self.hls_params.url_type {
+            UrlType::Playlist(p) => (Some(p.track_id), None),
+            UrlType::VideoSegment(v) => (Some(v.track_id), v.segment_id),
+            UrlType::AudioSegment(a) => (Some(a.track_id), a.segment_id),
+            UrlType::VttSegment(s) => (Some(s.track_id), Some(s.start_cue)),
+            UrlType::MainPlaylist | UrlType::AudioMainPlaylist(_) | UrlType::EmptyVtt => {
+                (None, None)
+            }
+        };
+        crate::error::ErrorContext {
+            stream_id: self.index.stream_id.clone(),
+            path: self.index.source_path.clone(),
+            track,
+            sequence,
+            ffmpeg_log: crate::ffmpeg_utils::log::take_recent_lines(),
+        }
+    }
+
+    /// Generate this media segment with muxer options `a` and `b`, and
+    /// compare the box trees and timing values of the results.
+    ///
+    /// For debugging muxer changes. Bypasses the cache.
+    pub fn compare_muxers(
+        &self,
+        a: MuxOptions,
+        b: MuxOptions,
+    ) -> crate::error::Result<SegmentComparison> {
+        let wants_aac = |requested: Option<&str>, track: usize| {
+            requested
+                .and_then(crate::params::parse_transcode_target)
+                .is_some_and(|(codec, _)| codec == "aac")
+                || self
+                    .index
+                    .get_audio_stream(track)
+                    .map(|t| t.transcode_to == Some(crate::media::Id::AAC))
+                    .unwrap_or(false)
+        };
+        let compare = crate::segment::compare::compare_segment;
+        match &self.hls_params.url_type {
+            UrlType::VideoSegment(
+                v @ VideoSegment {
+                    segment_id: Some(seq),
+                    ..
+                },
+            ) => {
+                let segment = self.index.get_segment("video", *seq)?;
+                match v.audio_track_id {
+                    Some(audio_idx) => compare(
+                        &self.index,
+                        segment,
+                        "av",
+                        Some(v.track_id),
+                        Some(audio_idx),
+                        wants_aac(v.audio_transcode_to.as_deref(), audio_idx),
+                        a,
+                        b,
+                    ),
+                    None => compare(
+                        &self.index,
+                        segment,
+                        "video",
+                        Some(v.track_id),
+                        None,
+                        false,
+                        a,
+                        b,
+                    ),
+                }
+            }
+            UrlType::AudioSegment(
+                s @ AudioSegment {
+                    segment_id: Some(seq),
+                    ..
+                },
+            ) => {
+                let segment = self.index.get_segment("audio", *seq)?;
+                compare(
+                    &self.index,
+                    segment,
+                    "audio",
+                    None,
+                    Some(s.track_id),
+                    wants_aac(s.transcode_to.as_deref(), s.track_id),
+                    a,
+                    b,
+                )
+            }
+            _ => Err(crate::error::HlsError::Muxing(
+                "only fMP4 media segments can be compared".to_string(),
+            )),
+        }
     }
 
     /// Whether this request is for a media segment (not init segment or playlist).
@@ -240,19 +1071,56 @@ impl PlaylistOrSegment {
     }
 
     /// Perform the actual generation (separated from caching/dedup logic).
-    pub(crate) fn do_generate(&self) -> crate::error::Result<(Vec<u8>, bool)> {
+    pub(crate) fn do_generate(
+        &self,
+        progress: Option<&dyn ProgressObserver>,
+    ) -> crate::error::Result<(Vec<u8>, bool)> {
         let mut cache_it = false;
 
+        // Sessions of an older version of the file only get what is cached.
+        if matches!(
+            self.hls_params.url_type,
+            UrlType::VideoSegment(_) | UrlType::AudioSegment(_) | UrlType::VttSegment(_)
+        ) && self.index.source_changed()
+        {
+            return Err(crate::error::HlsError::SourceChanged(format!(
+                "{} changed since stream {} was opened",
+                self.index.source_path.display(),
+                self.index.stream_id
+            )));
+        }
+
         let data = match &self.hls_params.url_type {
-            UrlType::MainPlaylist => panic!("impossible condition"),
+            UrlType::MainPlaylist | UrlType::AudioMainPlaylist(_) => {
+                panic!("impossible condition")
+            }
             UrlType::Playlist(p) => {
+                let video_url = &self.hls_params.video_url;
+                let session_id = self.hls_params.session_id.as_deref();
+                #[cfg(not(feature = "subtitles"))]
+                if self
+                    .index
+                    .subtitle_streams
+                    .iter()
+                    .any(|s| s.stream_index == p.track_id)
+                {
+                    return Err(crate::error::HlsError::FeatureDisabled("subtitles"));
+                }
+                let init_len = if p.combined_init {
+                    self.combined_init_len(p)?
+                } else {
+                    None
+                };
                 let playlist = if let Some(audio_idx) = p.audio_track_id {
                     // Audio / Video interleaved playlist
                     crate::playlist::variant::generate_interleaved_playlist(
                         &self.index,
+                        video_url,
+                        session_id,
                         p.track_id,
                         audio_idx,
                         p.audio_transcode_to.as_deref(),
+                        init_len,
                     )
                 } else if self
                     .index
@@ -263,8 +1131,12 @@ impl PlaylistOrSegment {
                     // Audio only playlist
                     crate::playlist::variant::generate_audio_playlist(
                         &self.index,
+                        video_url,
+                        session_id,
                         p.track_id,
                         p.audio_transcode_to.as_deref(),
+                        init_len,
+                        p.long_segments,
                     )
                 } else if self
                     .index
@@ -273,17 +1145,49 @@ impl PlaylistOrSegment {
                     .iter()
                     .any(|s| s.stream_index == p.track_id)
                 {
-                    crate::playlist::variant::generate_subtitle_playlist(&self.index, p.track_id)
+                    crate::playlist::variant::generate_subtitle_playlist(
+                        &self.index,
+                        video_url,
+                        session_id,
+                        p.track_id,
+                        p.subtitle_timestamps,
+                    )
                 } else {
                     // Main video playlist.
-                    crate::playlist::variant::generate_video_playlist(&self.index)
+                    crate::playlist::variant::generate_video_playlist(
+                        &self.index,
+                        video_url,
+                        session_id,
+                        init_len,
+                    )
+                };
+                let playlist = if crate::playlist::delta::delta_updates() {
+                    crate::playlist::delta::advertise(playlist)
+                } else {
+                    playlist
                 };
                 Ok(playlist.into_bytes())
             }
             UrlType::VideoSegment(v) => {
                 if let Some(audio_idx) = v.audio_track_id {
                     if let Some(seq) = v.segment_id {
-                        let segment = self.index.get_segment("video", seq)?;
+                        let segment = &crate::segment::generator::segment_range(
+                            &self.index,
+                            "video",
+                            seq,
+                            v.end_segment_id.unwrap_or(seq),
+                        )?;
+                        let init = if v.with_init {
+                            crate::segment::generator::generate_interleaved_init_segment(
+                                &self.index,
+                                v.track_id,
+                                audio_idx,
+                                v.audio_transcode_to.as_deref(),
+                            )?
+                            .to_vec()
+                        } else {
+                            Vec::new()
+                        };
                         let buf = crate::segment::generator::generate_interleaved_segment(
                             &self.index,
                             v.track_id,
@@ -291,10 +1195,10 @@ impl PlaylistOrSegment {
                             segment,
                             &self.index.source_path,
                             v.audio_transcode_to.as_deref(),
-                        )
-                        .map(|b| b.to_vec())?;
+                            segment_progress(progress, v.with_init),
+                        )?;
                         cache_it = true;
-                        Ok(buf)
+                        Ok([init.as_slice(), &buf[..]].concat())
                     } else {
                         crate::segment::generator::generate_interleaved_init_segment(
                             &self.index,
@@ -305,15 +1209,22 @@ impl PlaylistOrSegment {
                         .map(|b| b.to_vec())
                     }
                 } else if let Some(seq) = v.segment_id {
-                    let buf = crate::segment::generator::generate_video_segment(
+                    let init = if v.with_init {
+                        crate::segment::generator::generate_video_init_segment(&self.index)?
+                            .to_vec()
+                    } else {
+                        Vec::new()
+                    };
+                    let buf = crate::segment::generator::generate_video_segment_range(
                         &self.index,
                         v.track_id,
                         seq,
+                        v.end_segment_id.unwrap_or(seq),
                         &self.index.source_path,
-                    )
-                    .map(|b| b.to_vec())?;
+                        segment_progress(progress, v.with_init),
+                    )?;
                     cache_it = true;
-                    Ok(buf)
+                    Ok([init.as_slice(), &buf[..]].concat())
                 } else {
                     crate::segment::generator::generate_video_init_segment(&self.index)
                         .map(|b| b.to_vec())
@@ -321,16 +1232,27 @@ impl PlaylistOrSegment {
             }
             UrlType::AudioSegment(a) => {
                 if let Some(seq) = a.segment_id {
-                    let buf = crate::segment::generator::generate_audio_segment(
+                    let init = if a.with_init {
+                        crate::segment::generator::generate_audio_init_segment(
+                            &self.index,
+                            a.track_id,
+                            a.transcode_to.as_deref(),
+                        )?
+                        .to_vec()
+                    } else {
+                        Vec::new()
+                    };
+                    let buf = crate::segment::generator::generate_audio_segment_range(
                         &self.index,
                         a.track_id,
                         seq,
+                        a.end_segment_id.unwrap_or(seq),
                         &self.index.source_path,
                         a.transcode_to.as_deref(),
-                    )
-                    .map(|b| b.to_vec())?;
+                        segment_progress(progress, a.with_init),
+                    )?;
                     cache_it = true;
-                    Ok(buf)
+                    Ok([init.as_slice(), &buf[..]].concat())
                 } else {
                     crate::segment::generator::generate_audio_init_segment(
                         &self.index,
@@ -341,22 +1263,144 @@ impl PlaylistOrSegment {
                 }
             }
             UrlType::VttSegment(s) => {
-                let buf = crate::segment::generator::generate_subtitle_segment(
-                    &self.index,
-                    s.track_id,
-                    s.start_cue,
-                    s.end_cue,
-                    &self.index.source_path,
-                )
-                .map(|b| b.to_vec())?;
+                let buf = self.generate_vtt(s).map(|b| b.to_vec())?;
                 cache_it = true;
                 Ok(buf)
             }
+            UrlType::EmptyVtt => Ok(crate::segment::generator::EMPTY_SUBTITLE_SEGMENT.to_vec()),
         }?;
 
         Ok((data, cache_it))
     }
 
+    /// Generate WebVTT segment `s`. With batch extraction, the same segment
+    /// of the other text subtitle tracks is generated in the same pass over
+    /// the file, and cached.
+    fn generate_vtt(&self, s: &crate::params::VttSegment) -> crate::error::Result<bytes::Bytes> {
+        let timestamps = s
+            .timestamps
+            .unwrap_or_else(crate::params::subtitle_timestamps);
+        #[cfg(feature = "subtitles")]
+        if let Some(c) = crate::cache::segment_cache() {
+            let others = self.vtt_batch(s);
+            if !others.is_empty() {
+                let mut tracks = vec![s.track_id];
+                tracks.extend(others.iter().map(|(track_id, _)| *track_id));
+                let started = std::time::Instant::now();
+                let mut segments = crate::segment::generator::generate_subtitle_segments(
+                    &self.index,
+                    &tracks,
+                    s.start_cue,
+                    s.end_cue,
+                    timestamps,
+                )?;
+                // One pass for all of them, so they share the cost.
+                let cost = started.elapsed() / tracks.len() as u32;
+                for ((_, key), data) in others.iter().zip(segments.drain(1..)) {
+                    c.insert_with_cost(&self.index.stream_id, key, data, cost);
+                }
+                return Ok(segments.remove(0));
+            }
+        }
+        crate::segment::generator::generate_subtitle_segment(
+            &self.index,
+            s.track_id,
+            s.start_cue,
+            s.end_cue,
+            &self.index.source_path,
+            timestamps,
+        )
+    }
+
+    /// The other text subtitle tracks to extract together with WebVTT
+    /// segment `s`, with the cache keys of their segments: the ones that
+    /// have cues in it, and are not cached yet.
+    #[cfg(feature = "subtitles")]
+    fn vtt_batch(&self, s: &crate::params::VttSegment) -> Vec<(usize, String)> {
+        let Some(c) = crate::cache::segment_cache() else {
+            return Vec::new();
+        };
+        if !crate::subtitle::extractor::subtitle_batch_extraction() {
+            return Vec::new();
+        }
+        self.index
+            .subtitle_streams
+            .iter()
+            .filter(|sub| {
+                sub.stream_index != s.track_id
+                    && !crate::subtitle::decoder::is_bitmap_subtitle_codec(sub.codec_id)
+                    && sub.non_empty_sequences.binary_search(&s.start_cue).is_ok()
+            })
+            .filter_map(|sub| {
+                let params = HlsParams {
+                    url_type: UrlType::VttSegment(crate::params::VttSegment {
+                        track_id: sub.stream_index,
+                        ..s.clone()
+                    }),
+                    session_id: self.hls_params.session_id.clone(),
+                    video_url: self.hls_params.video_url.clone(),
+                };
+                let key = params.to_string();
+                (!c.contains(&self.index.stream_id, &key)).then_some((sub.stream_index, key))
+            })
+            .collect()
+    }
+
+    /// Length of the init segment of the (non-subtitle) variant playlist `p`.
+    ///
+    /// Generated the same way as for the first media segment of a combined
+    /// init playlist, so that the `EXT-X-MAP` byterange matches. Generated
+    /// once, the length is kept in `StreamIndex::init_lengths`.
+    fn combined_init_len(
+        &self,
+        p: &crate::params::Playlist,
+    ) -> crate::error::Result<Option<usize>> {
+        use crate::segment::generator;
+        let index = &self.index;
+        let key = (p.track_id, p.audio_track_id, p.audio_transcode_to.clone());
+        if let Ok(lengths) = index.init_lengths.lock() {
+            if let Some(&len) = lengths.get(&key) {
+                return Ok(Some(len));
+            }
+        }
+        let init = if let Some(audio_idx) = p.audio_track_id {
+            let transcode_to = crate::playlist::variant::audio_transcode_to(
+                index,
+                audio_idx,
+                p.audio_transcode_to.as_deref(),
+            );
+            generator::generate_interleaved_init_segment(
+                index,
+                p.track_id,
+                audio_idx,
+                transcode_to.as_deref(),
+            )?
+        } else if index
+            .audio_streams
+            .iter()
+            .any(|a| a.stream_index == p.track_id)
+        {
+            let transcode_to = crate::playlist::variant::audio_transcode_to(
+                index,
+                p.track_id,
+                p.audio_transcode_to.as_deref(),
+            );
+            generator::generate_audio_init_segment(index, p.track_id, transcode_to.as_deref())?
+        } else if index
+            .video_streams
+            .iter()
+            .any(|v| v.stream_index == p.track_id)
+        {
+            generator::generate_video_init_segment(index)?
+        } else {
+            return Ok(None);
+        };
+        if let Ok(mut lengths) = index.init_lengths.lock() {
+            lengths.insert(key, init.len());
+        }
+        Ok(Some(init.len()))
+    }
+
     /// Add coming segments into the global threadpool's work queue.
     ///
     /// The global threadpool ensures look-ahead generation happens concurrently
@@ -374,13 +1418,14 @@ impl PlaylistOrSegment {
         let total_segments = self.index.segment_count();
 
         // Check if we are generating a media segment and track the latest sequence.
+        // A long segment covers segments `n` up to and including `end`.
         let requested_seg_id = match &self.hls_params.url_type {
-            UrlType::VideoSegment(v) => v.segment_id,
-            UrlType::AudioSegment(a) => a.segment_id,
+            UrlType::VideoSegment(v) => v.segment_id.map(|n| (n, v.end_segment_id.unwrap_or(n))),
+            UrlType::AudioSegment(a) => a.segment_id.map(|n| (n, a.end_segment_id.unwrap_or(n))),
             _ => None,
         };
 
-        if let Some(n) = requested_seg_id {
+        if let Some((n, end)) = requested_seg_id {
             // Seek detection: we evaluate and update this INSIDE the lookahead_queue lock!
             // This forces concurrent requests for N, N+1, N+2 (resulting from a seek)
             // to be evaluated sequentially. The first one through (e.g. N) will detect the seek
@@ -393,7 +1438,7 @@ impl PlaylistOrSegment {
                     .load(std::sync::atomic::Ordering::Relaxed);
 
                 // If this request is discontinuous with the last request, it's a seek.
-                let is_seek = last != -1 && (n as i64 != last + 1) && (n as i64 != last);
+                let is_seek = last != -1 && (n as i64 != last + 1) && (end as i64 != last);
 
                 if is_seek {
                     tracing::info!(stream_id = %self.index.stream_id, "seek detected: requested segment {}, last was {}", n, last);
@@ -402,10 +1447,10 @@ impl PlaylistOrSegment {
 
                 // Update the last requested segment if we are moving forward chronologically
                 // (This avoids back-to-back concurrent requests for N and N+1 from resetting it backward if N+1 hits the lock first)
-                if n as i64 > last {
+                if end as i64 > last {
                     self.index
                         .last_requested_segment
-                        .store(n as i64, std::sync::atomic::Ordering::Relaxed);
+                        .store(end as i64, std::sync::atomic::Ordering::Relaxed);
                 }
             }
         }
@@ -413,7 +1458,7 @@ impl PlaylistOrSegment {
         // Collect new tasks
         let mut new_tasks = Vec::new();
         for offset in 1..=lookahead {
-            let Some(next_params) = self.hls_params.with_segment_offset(offset) else {
+            let Some(next_params) = self.next_segment_params(offset) else {
                 break;
             };
 
@@ -433,7 +1478,7 @@ impl PlaylistOrSegment {
 
             // Fast cache check
             if let Some(c) = crate::cache::segment_cache() {
-                if c.get(&self.index.stream_id, &next_key).is_some() {
+                if c.contains(&self.index.stream_id, &next_key) {
                     continue;
                 }
             }
@@ -453,7 +1498,254 @@ impl PlaylistOrSegment {
             }
 
             // Notify the global queue
+            #[cfg(feature = "cache")]
             crate::lookahead::notify_lookahead(self.index.clone());
         }
     }
+
+    /// The parameters of the segment `offset` segments after this one.
+    ///
+    /// Long audio segments follow the grouping of the audio playlist.
+    fn next_segment_params(&self, offset: usize) -> Option<HlsParams> {
+        let target_secs = crate::playlist::variant::audio_segment_duration();
+        let a = match &self.hls_params.url_type {
+            UrlType::AudioSegment(a) if a.end_segment_id.is_some() => a,
+            _ => return self.hls_params.with_segment_offset(offset),
+        };
+        let groups = crate::playlist::variant::segment_groups(&self.index, target_secs);
+        let pos = groups.iter().position(|g| Some(g.start) == a.segment_id)?;
+        // Not this grouping: the playlist was over its byte budget.
+        if groups[pos].end_segment_id() != a.end_segment_id {
+            return None;
+        }
+        let group = groups.get(pos + offset)?;
+        Some(HlsParams {
+            url_type: UrlType::AudioSegment(crate::params::AudioSegment {
+                track_id: a.track_id,
+                transcode_to: a.transcode_to.clone(),
+                segment_id: Some(group.start),
+                start_ms: None,
+                end_segment_id: group.end_segment_id(),
+                with_init: false,
+            }),
+            session_id: self.hls_params.session_id.clone(),
+            video_url: self.hls_params.video_url.clone(),
+        })
+    }
+}
+
+/// Sizes that `PlaylistOrSegment::estimate_size()` does not derive from
+/// bitrates: an init segment, the boxes around the samples of a media
+/// segment, the `trun` entry of a video and of an audio sample, and the
+/// header and the lines per segment of a variant playlist.
+const INIT_SEGMENT_BYTES: usize = 1024;
+const FRAGMENT_BYTES: usize = 512;
+const VIDEO_SAMPLE_BYTES: usize = 16;
+const AUDIO_SAMPLE_BYTES: usize = 8;
+const PLAYLIST_HEADER_BYTES: usize = 256;
+const PLAYLIST_SEGMENT_BYTES: usize = 40;
+
+// Bytes of `duration` seconds at `bitrate`.
+fn bitrate_bytes(bitrate: u64, duration: f64) -> usize {
+    (bitrate as f64 * duration / 8.0) as usize
+}
+
+// Bytes of `duration` seconds of `audio`, as AAC if it is transcoded to
+// `transcode_to` (with the requested bitrate, if any).
+fn audio_bytes(
+    index: &StreamIndex,
+    audio: &crate::media::AudioStreamInfo,
+    transcode_to: Option<&str>,
+    duration: f64,
+) -> usize {
+    #[cfg(feature = "transcode")]
+    let bitrate = match transcode_to.and_then(crate::params::parse_transcode_target) {
+        Some((_, Some(bitrate))) => bitrate,
+        Some(_) => {
+            crate::transcode::encoder::aac_bitrate(audio.channels, &index.config.aac_bitrates)
+        }
+        None => audio.bitrate,
+    };
+    #[cfg(not(feature = "transcode"))]
+    let bitrate = {
+        let _ = (index, transcode_to);
+        audio.bitrate
+    };
+    // AAC, AC-3 and most other codecs have at least 1024 samples per frame.
+    let frames = (duration * audio.sample_rate as f64 / 1024.0) as usize;
+    bitrate_bytes(bitrate, duration) + frames * AUDIO_SAMPLE_BYTES
+}
+
+/// The observer to pass on to the segment generator.
+///
+/// Streamed data has to start with the init segment if it is prepended, so
+/// in that case the observer only gets the fragment progress.
+fn segment_progress(
+    progress: Option<&dyn ProgressObserver>,
+    with_init: bool,
+) -> Option<&dyn ProgressObserver> {
+    progress.filter(|p| !with_init || !p.wants_data())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::{AudioStreamInfo, SegmentInfo, VideoStreamInfo};
+    use ffmpeg_next as ffmpeg;
+
+    fn test_index(video_bitrate: u64) -> Arc<StreamIndex> {
+        let mut index = StreamIndex::new("/test/video.mp4".into());
+        index.video_streams.push(VideoStreamInfo {
+            stream_index: 0,
+            codec_id: ffmpeg::codec::Id::H264,
+            width: 1920,
+            height: 1080,
+            bitrate: video_bitrate,
+            framerate: ffmpeg::Rational::new(25, 1),
+            language: None,
+            profile: None,
+            level: None,
+            codec_string: None,
+            nal_format: Default::default(),
+            stats: None,
+        });
+        index.audio_streams.push(AudioStreamInfo {
+            stream_index: 1,
+            codec_id: ffmpeg::codec::Id::AAC,
+            sample_rate: 48000,
+            channels: 2,
+            bitrate: 128000,
+            language: None,
+            transcode_to: None,
+            mix_with: None,
+            codec_string: None,
+            encoder_delay: 0,
+        });
+        for sequence in 0..3 {
+            index.segments.push(SegmentInfo {
+                sequence,
+                start_pts: sequence as i64 * 4000,
+                end_pts: (sequence as i64 + 1) * 4000,
+                duration_secs: 4.0,
+                is_keyframe: true,
+                video_byte_offset: sequence as u64 * 600_000,
+            });
+        }
+        Arc::new(index)
+    }
+
+    fn estimate(index: &Arc<StreamIndex>, path: &str) -> Option<usize> {
+        let hls_params = HlsParams::parse(&format!("video.mp4/{}/{}", index.stream_id, path))?;
+        PlaylistOrSegment {
+            hls_params,
+            index: index.clone(),
+        }
+        .estimate_size()
+    }
+
+    #[test]
+    fn test_estimate_size() {
+        let index = test_index(1_000_000);
+
+        // 4 seconds at 1 Mbit/s, and 100 frames.
+        let video = 512 + 500_000 + 100 * 16;
+        assert_eq!(estimate(&index, "v/0.1.m4s"), Some(video));
+        assert_eq!(estimate(&index, "v/0.1.hdr.m4s"), Some(1024 + video));
+        assert_eq!(estimate(&index, "v/0.init.mp4"), Some(1024));
+
+        // 4 seconds at 128 kbit/s, and 187 frames of 1024 samples.
+        let audio = 64_000 + 187 * 8;
+        assert_eq!(estimate(&index, "v/0+1.1.m4s"), Some(video + audio));
+        assert_eq!(estimate(&index, "a/1.1.m4s"), Some(512 + audio));
+
+        assert_eq!(estimate(&index, "t.0.m3u8"), Some(256 + 3 * 40));
+        assert_eq!(estimate(&index, "v/7.1.m4s"), None);
+        assert_eq!(estimate(&index, "v/0.9.m4s"), None);
+    }
+
+    #[cfg(feature = "subtitles")]
+    #[test]
+    fn test_subtitle_segments_in_one_pass() {
+        use crate::params::SubtitleTimestamps;
+        use crate::segment::generator::{generate_subtitle_segment, generate_subtitle_segments};
+        use crate::tests::fixtures::generate::{generate, FixtureSpec};
+
+        let Some(media) = generate(&FixtureSpec::multi_language()) else {
+            return;
+        };
+        let main = HlsParams::main_playlist(media.to_string_lossy());
+        let HlsVideo::MainPlaylist(p) = HlsVideo::open(&media, main).unwrap() else {
+            panic!("not a main playlist");
+        };
+        let index = &p.index;
+        let tracks: Vec<usize> = index
+            .subtitle_streams
+            .iter()
+            .map(|s| s.stream_index)
+            .collect();
+        assert_eq!(tracks.len(), 2);
+
+        // The same segments as one track at a time.
+        for sequence in 0..index.segments.len() {
+            let timestamps = SubtitleTimestamps::Zero;
+            let batch =
+                generate_subtitle_segments(index, &tracks, sequence, sequence, timestamps).unwrap();
+            for (&track, data) in tracks.iter().zip(batch) {
+                let single = generate_subtitle_segment(
+                    index,
+                    track,
+                    sequence,
+                    sequence,
+                    &index.source_path,
+                    timestamps,
+                )
+                .unwrap();
+                assert_eq!(data, single, "track {} segment {}", track, sequence);
+            }
+        }
+    }
+
+    #[test]
+    fn test_audio_only_cover_art() {
+        use crate::tests::fixtures::generate::{generate, FixtureSpec};
+
+        let Some(media) = generate(&FixtureSpec::mp3_cover()) else {
+            return;
+        };
+        let main = HlsParams::main_playlist(media.to_string_lossy());
+        let HlsVideo::MainPlaylist(p) = HlsVideo::open(&media, main.clone()).unwrap() else {
+            panic!("not a main playlist");
+        };
+        let index = p.index.clone();
+        assert!(index.video_streams.is_empty());
+        assert_eq!(index.artwork.len(), 1);
+        assert_eq!(index.artwork[0].mime_type, "image/png");
+        assert!(!index.segments.is_empty());
+
+        // The main playlist is that of the audio track.
+        let playlist = String::from_utf8(p.generate().unwrap()).unwrap();
+        assert!(playlist.contains("#EXT-X-MEDIA:TYPE=AUDIO"), "{}", playlist);
+        assert!(!playlist.contains("RESOLUTION="), "{}", playlist);
+
+        let session = main.session(index.stream_id());
+        let track = index.audio_streams[0].stream_index;
+        for params in [
+            session.audio_init(track, None),
+            session.audio_segment(track, 0, None),
+        ] {
+            let data = HlsVideo::open(&media, params).unwrap().generate().unwrap();
+            assert!(!data.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_estimate_size_from_byte_offsets() {
+        let index = test_index(0);
+        assert_eq!(
+            estimate(&index, "v/0.1.m4s"),
+            Some(512 + 600_000 + 100 * 16)
+        );
+        // The last segment has no next byte offset.
+        assert_eq!(estimate(&index, "v/0.2.m4s"), None);
+    }
 }
@@ -0,0 +1,161 @@
+//! AAC AudioSpecificConfig parsing
+//!
+//! The RFC 6381 codec string of an AAC stream is `mp4a.40.<audioObjectType>`.
+//! HE-AAC (SBR) and HE-AACv2 (SBR + PS) streams are usually stored with an
+//! AAC-LC core, so the object type has to be taken from the SBR/PS extension
+//! signalling in the AudioSpecificConfig (ISO/IEC 14496-3 1.6.2.1) rather
+//! than from its first five bits.
+
+/// Audio object type of AAC-LC.
+pub const AOT_AAC_LC: u8 = 2;
+/// Audio object type of HE-AAC (AAC-LC + SBR).
+pub const AOT_SBR: u8 = 5;
+/// Audio object type of HE-AACv2 (AAC-LC + SBR + PS).
+pub const AOT_PS: u8 = 29;
+
+const SYNC_EXTENSION_SBR: u32 = 0x2b7;
+const SYNC_EXTENSION_PS: u32 = 0x548;
+
+/// FFmpeg `FF_PROFILE_AAC_HE` / `FF_PROFILE_AAC_HE_V2`.
+const FF_PROFILE_AAC_HE: i32 = 4;
+const FF_PROFILE_AAC_HE_V2: i32 = 28;
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, pos: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        (self.data.len() * 8).saturating_sub(self.pos)
+    }
+
+    fn read(&mut self, n: usize) -> Option<u32> {
+        if n > self.remaining() {
+            return None;
+        }
+        let mut v = 0u32;
+        for _ in 0..n {
+            let bit = (self.data[self.pos / 8] >> (7 - self.pos % 8)) & 1;
+            v = (v << 1) | bit as u32;
+            self.pos += 1;
+        }
+        Some(v)
+    }
+
+    fn object_type(&mut self) -> Option<u8> {
+        let aot = self.read(5)?;
+        let aot = if aot == 31 { 32 + self.read(6)? } else { aot };
+        Some(aot as u8)
+    }
+
+    fn sampling_frequency(&mut self) -> Option<()> {
+        if self.read(4)? == 0xf {
+            self.read(24)?;
+        }
+        Some(())
+    }
+}
+
+/// Determine the effective audio object type from an AudioSpecificConfig.
+///
+/// Returns [`AOT_SBR`] or [`AOT_PS`] for HE-AAC(v2), whether it is signalled
+/// explicitly (hierarchical) or through a backward compatible sync extension,
+/// and the plain object type otherwise.
+pub fn object_type(asc: &[u8]) -> Option<u8> {
+    let mut r = BitReader::new(asc);
+    let aot = r.object_type()?;
+    if aot == 0 {
+        return None;
+    }
+    if aot == AOT_SBR || aot == AOT_PS {
+        return Some(aot);
+    }
+    r.sampling_frequency()?;
+    let channel_config = r.read(4)?;
+
+    // Only an AAC-LC core can carry backward compatible SBR signalling, and
+    // only if we can skip over its GASpecificConfig.
+    if aot != AOT_AAC_LC || channel_config == 0 {
+        return Some(aot);
+    }
+    // frameLengthFlag, dependsOnCoreCoder, extensionFlag.
+    let Some(flags) = r.read(3) else {
+        return Some(aot);
+    };
+    if flags & 0b010 != 0 && r.read(14).is_none() {
+        return Some(aot);
+    }
+
+    if r.remaining() < 16 || r.read(11) != Some(SYNC_EXTENSION_SBR) {
+        return Some(aot);
+    }
+    if r.object_type() != Some(AOT_SBR) || r.read(1) != Some(1) {
+        return Some(aot);
+    }
+    if r.sampling_frequency().is_none() {
+        return Some(AOT_SBR);
+    }
+    if r.remaining() >= 12 && r.read(11) == Some(SYNC_EXTENSION_PS) && r.read(1) == Some(1) {
+        return Some(AOT_PS);
+    }
+    Some(AOT_SBR)
+}
+
+/// The `mp4a.40.<aot>` codec string of an AAC stream.
+///
+/// `extradata` is parsed first; if it is missing or unusable the FFmpeg
+/// `profile` of the stream is used to recognize HE-AAC(v2).
+pub fn codec_string(extradata: &[u8], profile: i32) -> Option<String> {
+    let aot = object_type(extradata).or(match profile {
+        FF_PROFILE_AAC_HE => Some(AOT_SBR),
+        FF_PROFILE_AAC_HE_V2 => Some(AOT_PS),
+        _ => None,
+    })?;
+    Some(format!("mp4a.40.{}", aot))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aac_lc() {
+        // AAC-LC, 48 kHz, stereo.
+        assert_eq!(object_type(&[0x11, 0x90]), Some(AOT_AAC_LC));
+        assert_eq!(codec_string(&[0x11, 0x90], -99).unwrap(), "mp4a.40.2");
+    }
+
+    #[test]
+    fn test_explicit_signalling() {
+        // HE-AAC, 24 kHz core, stereo, 48 kHz extension, AAC-LC core.
+        assert_eq!(object_type(&[0x2b, 0x11, 0x88, 0x00]), Some(AOT_SBR));
+        // HE-AACv2, 24 kHz core, mono, 48 kHz extension.
+        assert_eq!(object_type(&[0xeb, 0x09, 0x88, 0x00]), Some(AOT_PS));
+    }
+
+    #[test]
+    fn test_backward_compatible_signalling() {
+        // AAC-LC 24 kHz stereo + sync extension 0x2b7, SBR present, 48 kHz.
+        let sbr = [0x13, 0x10, 0x56, 0xe5, 0x98];
+        assert_eq!(object_type(&sbr), Some(AOT_SBR));
+        // ... followed by sync extension 0x548 with PS present.
+        let ps = [0x13, 0x10, 0x56, 0xe5, 0x9d, 0x48, 0x80];
+        assert_eq!(object_type(&ps), Some(AOT_PS));
+        assert_eq!(codec_string(&ps, -99).unwrap(), "mp4a.40.29");
+    }
+
+    #[test]
+    fn test_profile_fallback() {
+        assert_eq!(codec_string(&[], FF_PROFILE_AAC_HE).unwrap(), "mp4a.40.5");
+        assert_eq!(
+            codec_string(&[], FF_PROFILE_AAC_HE_V2).unwrap(),
+            "mp4a.40.29"
+        );
+        assert!(codec_string(&[], -99).is_none());
+    }
+}
@@ -0,0 +1,61 @@
+//! Cover art detection
+//!
+//! Music files (MP3, M4A) and MKV files often carry a cover image. FFmpeg
+//! presents it as a video stream with the `ATTACHED_PIC` disposition, holding
+//! a single picture. It is not a video track; serving it as one gives a
+//! broken video variant. The picture is kept as artwork instead.
+
+use bytes::Bytes;
+use ffmpeg_next as ffmpeg;
+
+use crate::media::Artwork;
+
+/// Whether a video stream is an attached picture (cover art).
+pub fn is_attached_pic(stream: &ffmpeg::Stream) -> bool {
+    stream
+        .disposition()
+        .contains(ffmpeg::format::stream::Disposition::ATTACHED_PIC)
+}
+
+/// Analyze an attached picture stream. Returns `None` if the image format
+/// is unknown or the picture is empty.
+pub fn analyze_artwork_stream(stream: &ffmpeg::Stream, index: usize) -> Option<Artwork> {
+    let mime_type = image_mime_type(stream.parameters().id())?;
+    let data = crate::ffmpeg_utils::helpers::stream_attached_pic(stream);
+    if data.is_empty() {
+        return None;
+    }
+    Some(Artwork {
+        stream_index: index,
+        mime_type,
+        data: Bytes::from(data),
+    })
+}
+
+/// The MIME type of an image codec. `None` if it is not an image format
+/// that browsers can show.
+fn image_mime_type(codec_id: ffmpeg::codec::Id) -> Option<&'static str> {
+    match codec_id {
+        ffmpeg::codec::Id::MJPEG => Some("image/jpeg"),
+        ffmpeg::codec::Id::PNG => Some("image/png"),
+        ffmpeg::codec::Id::GIF => Some("image/gif"),
+        ffmpeg::codec::Id::WEBP => Some("image/webp"),
+        ffmpeg::codec::Id::BMP => Some("image/bmp"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_mime_type() {
+        assert_eq!(
+            image_mime_type(ffmpeg::codec::Id::MJPEG),
+            Some("image/jpeg")
+        );
+        assert_eq!(image_mime_type(ffmpeg::codec::Id::PNG), Some("image/png"));
+        assert_eq!(image_mime_type(ffmpeg::codec::Id::H264), None);
+    }
+}
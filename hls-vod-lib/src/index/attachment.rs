@@ -0,0 +1,82 @@
+//! Attachment stream analysis
+//!
+//! MKV files with ASS subtitles usually carry the fonts the subtitles are
+//! styled with as attachments. We can't use them for WebVTT, but clients
+//! that render the ASS themselves (libass, JavascriptSubtitlesOctopus) can.
+//! The matroska demuxer loads attachments into the stream's extradata when
+//! the file is opened, so they are copied from there at scan time.
+
+use bytes::Bytes;
+use ffmpeg_next as ffmpeg;
+
+use crate::media::Attachment;
+
+/// Analyze an attachment stream. Returns `None` if it is not a font.
+pub fn analyze_attachment_stream(stream: &ffmpeg::Stream, index: usize) -> Option<Attachment> {
+    let metadata = stream.metadata();
+    let filename = metadata.get("filename")?.to_string();
+    let mime_type = match metadata.get("mimetype") {
+        Some(m) if is_font_mime_type(m) => m.to_string(),
+        _ => font_mime_type(&filename)?.to_string(),
+    };
+    let data = crate::ffmpeg_utils::helpers::codec_params_extradata(&stream.parameters());
+    if data.is_empty() {
+        return None;
+    }
+    Some(Attachment {
+        stream_index: index,
+        filename,
+        mime_type,
+        data: Bytes::from(data),
+    })
+}
+
+/// Whether `mime_type` is one of the (official and legacy) font MIME types.
+fn is_font_mime_type(mime_type: &str) -> bool {
+    let mime_type = mime_type.to_ascii_lowercase();
+    mime_type.starts_with("font/")
+        || matches!(
+            mime_type.as_str(),
+            "application/x-truetype-font"
+                | "application/x-font-ttf"
+                | "application/x-font-otf"
+                | "application/x-font"
+                | "application/font-sfnt"
+                | "application/vnd.ms-opentype"
+                | "application/font-woff"
+        )
+}
+
+/// The MIME type of a font file, by extension. `None` if it is not a font.
+fn font_mime_type(filename: &str) -> Option<&'static str> {
+    let ext = filename.rsplit_once('.')?.1.to_ascii_lowercase();
+    match ext.as_str() {
+        "ttf" => Some("font/ttf"),
+        "otf" => Some("font/otf"),
+        "ttc" => Some("font/collection"),
+        "woff" => Some("font/woff"),
+        "woff2" => Some("font/woff2"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_font_mime_type() {
+        assert_eq!(font_mime_type("Arial.TTF"), Some("font/ttf"));
+        assert_eq!(font_mime_type("NotoSansCJK.ttc"), Some("font/collection"));
+        assert_eq!(font_mime_type("cover.jpg"), None);
+        assert_eq!(font_mime_type("README"), None);
+    }
+
+    #[test]
+    fn test_is_font_mime_type() {
+        assert!(is_font_mime_type("application/x-truetype-font"));
+        assert!(is_font_mime_type("font/otf"));
+        assert!(is_font_mime_type("application/vnd.ms-opentype"));
+        assert!(!is_font_mime_type("image/jpeg"));
+    }
+}
@@ -1,8 +1,61 @@
 //! Audio stream analysis
 
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use crate::error::Result;
 use crate::media::AudioStreamInfo;
 use ffmpeg_next as ffmpeg;
+use serde::{Deserialize, Serialize};
+
+/// What to do with audio that HLS players can't play from fMP4 (Dolby
+/// TrueHD, DTS and DTS-HD).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HdAudio {
+    /// Transcode to AAC; only the AAC rendition is advertised
+    #[default]
+    Aac,
+    /// Leave the track out
+    Exclude,
+}
+
+static HD_AUDIO_EXCLUDE: AtomicBool = AtomicBool::new(false);
+
+/// Set what happens to TrueHD and DTS tracks of files opened after this
+/// call. The default is to transcode them to AAC. Without the `transcode`
+/// feature they are always left out.
+pub fn set_hd_audio(policy: HdAudio) {
+    HD_AUDIO_EXCLUDE.store(policy == HdAudio::Exclude, Ordering::Relaxed);
+}
+
+pub(crate) fn hd_audio() -> HdAudio {
+    if HD_AUDIO_EXCLUDE.load(Ordering::Relaxed) {
+        HdAudio::Exclude
+    } else {
+        HdAudio::Aac
+    }
+}
+
+/// Whether `codec_id` is TrueHD or DTS, which fMP4 HLS can't carry.
+pub fn is_hd_audio(codec_id: ffmpeg::codec::Id) -> bool {
+    matches!(
+        codec_id,
+        ffmpeg::codec::Id::TRUEHD | ffmpeg::codec::Id::MLP | ffmpeg::codec::Id::DTS
+    )
+}
+
+/// Why an audio track can't be served, or `None` if it can.
+pub fn unsupported_reason(codec_id: ffmpeg::codec::Id) -> Option<&'static str> {
+    if !is_hd_audio(codec_id) {
+        None
+    } else if !cfg!(feature = "transcode") {
+        Some("TrueHD/DTS can't be carried in fMP4, and transcoding is not compiled in")
+    } else if hd_audio() == HdAudio::Exclude {
+        Some("TrueHD/DTS can't be carried in fMP4")
+    } else {
+        None
+    }
+}
 
 /// Analyze an audio stream and extract metadata
 pub fn analyze_audio_stream(stream: &ffmpeg::Stream, index: usize) -> Result<AudioStreamInfo> {
@@ -13,6 +66,16 @@ pub fn analyze_audio_stream(stream: &ffmpeg::Stream, index: usize) -> Result<Aud
     let sample_rate = crate::ffmpeg_utils::helpers::codec_params_sample_rate(&params);
     let channels = crate::ffmpeg_utils::helpers::codec_params_channels(&params);
 
+    // HE-AAC is usually stored with an AAC-LC core; the AudioSpecificConfig
+    // tells us whether SBR/PS is present.
+    let codec_string = if codec_id == ffmpeg::codec::Id::AAC {
+        let extradata = crate::ffmpeg_utils::helpers::codec_params_extradata(&params);
+        let profile = crate::ffmpeg_utils::helpers::codec_params_profile(&params);
+        super::aac::codec_string(&extradata, profile)
+    } else {
+        None
+    };
+
     Ok(AudioStreamInfo {
         stream_index: index,
         codec_id,
@@ -21,11 +84,92 @@ pub fn analyze_audio_stream(stream: &ffmpeg::Stream, index: usize) -> Result<Aud
         bitrate: 0,
         language: get_stream_language(stream),
         encoder_delay: 0,
-        transcode_to: None,
+        // The only rendition of TrueHD/DTS tracks is the AAC one.
+        transcode_to: is_hd_audio(codec_id).then_some(ffmpeg::codec::Id::AAC),
+        mix_with: None,
+        codec_string,
     })
 }
 
+/// Encoder delay (priming) of an audio stream, in samples at `sample_rate`.
+///
+/// `container_delay` is what the container signals: the negative DTS of the
+/// first packet, in samples. That covers AAC, where the priming is only in
+/// the edit list or `CodecDelay`. Opus carries its pre-skip in the `OpusHead`
+/// header, which is authoritative, and is always counted at 48 kHz. Vorbis
+/// has no priming of its own: the decoder emits nothing for the first packet,
+/// so only the container delay applies.
+pub(crate) fn encoder_delay(
+    codec_id: ffmpeg::codec::Id,
+    extradata: &[u8],
+    sample_rate: u32,
+    container_delay: i64,
+) -> i64 {
+    match codec_id {
+        ffmpeg::codec::Id::OPUS => match opus_pre_skip(extradata) {
+            Some(pre_skip) if sample_rate > 0 => pre_skip * sample_rate as i64 / 48000,
+            _ => container_delay,
+        },
+        _ => container_delay,
+    }
+}
+
+/// The pre-skip of an `OpusHead` header, in samples at 48 kHz (RFC 7845).
+fn opus_pre_skip(extradata: &[u8]) -> Option<i64> {
+    if extradata.len() < 19 || !extradata.starts_with(b"OpusHead") {
+        return None;
+    }
+    Some(u16::from_le_bytes([extradata[10], extradata[11]]) as i64)
+}
+
 /// Extract language from stream metadata
 fn get_stream_language(stream: &ffmpeg::Stream) -> Option<String> {
     stream.metadata().get("language").map(|s| s.to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ffmpeg::codec::Id;
+
+    fn opus_head(pre_skip: u16) -> Vec<u8> {
+        let mut head = b"OpusHead".to_vec();
+        head.extend_from_slice(&[1, 2]);
+        head.extend_from_slice(&pre_skip.to_le_bytes());
+        head.extend_from_slice(&48000u32.to_le_bytes());
+        head.extend_from_slice(&[0, 0, 0]);
+        head
+    }
+
+    #[test]
+    fn test_encoder_delay() {
+        // Opus: pre-skip from the header, whatever the container says.
+        assert_eq!(encoder_delay(Id::OPUS, &opus_head(312), 48000, 0), 312);
+        assert_eq!(encoder_delay(Id::OPUS, &opus_head(312), 48000, 1024), 312);
+        // Without a header, fall back to the container.
+        assert_eq!(encoder_delay(Id::OPUS, &[], 48000, 120), 120);
+
+        // AAC and Vorbis: the container delay.
+        assert_eq!(encoder_delay(Id::AAC, &[0x11, 0x90], 48000, 1024), 1024);
+        assert_eq!(encoder_delay(Id::VORBIS, &[2, 30, 60], 44100, 0), 0);
+    }
+
+    #[test]
+    fn test_unsupported_reason() {
+        assert!(is_hd_audio(Id::TRUEHD));
+        assert!(is_hd_audio(Id::DTS));
+        assert!(!is_hd_audio(Id::EAC3));
+        assert_eq!(unsupported_reason(Id::AC3), None);
+        assert_eq!(
+            unsupported_reason(Id::TRUEHD).is_none(),
+            cfg!(feature = "transcode")
+        );
+    }
+
+    #[test]
+    fn test_opus_pre_skip() {
+        assert_eq!(opus_pre_skip(&opus_head(3840)), Some(3840));
+        assert_eq!(opus_pre_skip(&opus_head(3840)[..12]), None);
+        assert_eq!(opus_pre_skip(b"OpusTags\0\0\0\0\0\0\0\0\0\0\0"), None);
+    }
+}
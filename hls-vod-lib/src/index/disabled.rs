@@ -0,0 +1,174 @@
+//! Tracks disabled by the operator.
+//!
+//! Some tracks break playback, for example a corrupt DTS track. They can be
+//! disabled per file; the list is kept in a JSON sidecar file next to the
+//! media file (`movie.mkv.disabled-tracks.json`), so the choice survives
+//! restarts. The scanner leaves disabled tracks out of the index, they are
+//! listed in `StreamIndex::excluded_tracks` instead.
+
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+
+/// Reason in `ExcludedTrack` for disabled tracks.
+pub(crate) const DISABLED_REASON: &str = "disabled by operator";
+
+/// A disabled track.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DisabledTrack {
+    /// Zero-based index of the stream in the source file
+    pub track: usize,
+    /// Why it was disabled, for the operator
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Sidecar {
+    #[serde(default)]
+    disabled: Vec<DisabledTrack>,
+}
+
+/// Path of the sidecar file of a media file.
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".disabled-tracks.json");
+    PathBuf::from(name)
+}
+
+/// Version of the sidecar file of a media file: its modification time and
+/// size, or None if there is none.
+pub(crate) fn sidecar_version(path: &Path) -> Option<String> {
+    let meta = std::fs::metadata(sidecar_path(path)).ok()?;
+    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!(
+        "{}.{:09}.{}",
+        mtime.as_secs(),
+        mtime.subsec_nanos(),
+        meta.len()
+    ))
+}
+
+fn read_sidecar(path: &Path) -> Result<Sidecar> {
+    let sidecar = sidecar_path(path);
+    match std::fs::read(&sidecar) {
+        Ok(data) => serde_json::from_slice(&data)
+            .map_err(|e| crate::error::HlsError::Config(format!("{}: {}", sidecar.display(), e))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Sidecar::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_sidecar(path: &Path, sidecar: &Sidecar) -> Result<()> {
+    let file = sidecar_path(path);
+    if sidecar.disabled.is_empty() {
+        return match std::fs::remove_file(&file) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        };
+    }
+    // Write to a temporary file and rename, so a crash never leaves half a file.
+    let mut tmp = file.clone().into_os_string();
+    tmp.push(".tmp");
+    let data = serde_json::to_vec_pretty(sidecar)
+        .map_err(|e| crate::error::HlsError::Config(e.to_string()))?;
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, &file)?;
+    Ok(())
+}
+
+/// The disabled tracks of a media file.
+///
+/// An unreadable sidecar file is logged and ignored.
+pub fn disabled_tracks(path: &Path) -> Vec<DisabledTrack> {
+    match read_sidecar(path) {
+        Ok(sidecar) => sidecar.disabled,
+        Err(e) => {
+            tracing::warn!("ignoring disabled tracks: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Disable a track of a media file.
+///
+/// Open streams of the file are closed, so the next request indexes the
+/// file again without the track.
+pub fn disable_track(path: &Path, track: usize, reason: &str) -> Result<()> {
+    let mut sidecar = read_sidecar(path)?;
+    sidecar.disabled.retain(|t| t.track != track);
+    sidecar.disabled.push(DisabledTrack {
+        track,
+        reason: reason.to_string(),
+    });
+    sidecar.disabled.sort_by_key(|t| t.track);
+    write_sidecar(path, &sidecar)?;
+    crate::cache::remove_streams_by_path(path);
+    Ok(())
+}
+
+/// Enable a previously disabled track of a media file.
+///
+/// Returns false if the track was not disabled.
+pub fn enable_track(path: &Path, track: usize) -> Result<bool> {
+    let mut sidecar = read_sidecar(path)?;
+    let len = sidecar.disabled.len();
+    sidecar.disabled.retain(|t| t.track != track);
+    if sidecar.disabled.len() == len {
+        return Ok(false);
+    }
+    write_sidecar(path, &sidecar)?;
+    crate::cache::remove_streams_by_path(path);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disable_enable_track() {
+        let dir = tempfile::tempdir().unwrap();
+        let media = dir.path().join("movie.mkv");
+        std::fs::write(&media, b"").unwrap();
+
+        assert!(disabled_tracks(&media).is_empty());
+
+        disable_track(&media, 2, "corrupt DTS").unwrap();
+        disable_track(&media, 1, "").unwrap();
+        disable_track(&media, 2, "still corrupt").unwrap();
+        assert_eq!(
+            disabled_tracks(&media),
+            vec![
+                DisabledTrack {
+                    track: 1,
+                    reason: String::new()
+                },
+                DisabledTrack {
+                    track: 2,
+                    reason: "still corrupt".to_string()
+                },
+            ]
+        );
+
+        assert!(enable_track(&media, 1).unwrap());
+        assert!(!enable_track(&media, 1).unwrap());
+        assert_eq!(disabled_tracks(&media).len(), 1);
+
+        assert!(enable_track(&media, 2).unwrap());
+        assert!(!sidecar_path(&media).exists());
+    }
+
+    #[test]
+    fn test_invalid_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let media = dir.path().join("movie.mp4");
+        std::fs::write(sidecar_path(&media), b"not json").unwrap();
+
+        assert!(disabled_tracks(&media).is_empty());
+        assert!(disable_track(&media, 1, "").is_err());
+    }
+}
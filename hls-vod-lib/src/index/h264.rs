@@ -0,0 +1,130 @@
+//! H.264 sequence parameter set parsing
+//!
+//! Only the first three bytes of the SPS payload are needed for the RFC 6381
+//! `avc1.PPCCLL` codec string: `profile_idc`, the constraint flags byte, and
+//! `level_idc`. They sit at fixed offsets, so no exp-Golomb decoding is needed.
+
+/// Profile, constraint flags and level from an H.264 SPS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpsInfo {
+    pub profile_idc: u8,
+    pub constraint_flags: u8,
+    pub level_idc: u8,
+}
+
+impl SpsInfo {
+    /// The `avc1.PPCCLL` codec string.
+    pub fn codec_string(&self) -> String {
+        format!(
+            "avc1.{:02x}{:02x}{:02x}",
+            self.profile_idc, self.constraint_flags, self.level_idc
+        )
+    }
+}
+
+/// Find the first SPS in codec extradata and parse it.
+///
+/// Handles both `avcC` (ISO/IEC 14496-15, MP4/MKV) and Annex B (start code
+/// prefixed, MPEG-TS and raw streams) extradata.
+pub fn parse_extradata(extradata: &[u8]) -> Option<SpsInfo> {
+    if extradata.first() == Some(&1) {
+        parse_avcc(extradata)
+    } else {
+        parse_annexb(extradata)
+    }
+}
+
+/// avcC: version(1) profile(1) compat(1) level(1) lengthSize(1) numSps(1)
+/// followed by 16-bit length-prefixed SPS NAL units.
+fn parse_avcc(data: &[u8]) -> Option<SpsInfo> {
+    if data.len() < 8 || data[5] & 0x1f == 0 {
+        return None;
+    }
+    let len = u16::from_be_bytes([data[6], data[7]]) as usize;
+    let nal = data.get(8..8 + len)?;
+    parse_sps_nal(nal)
+}
+
+fn parse_annexb(data: &[u8]) -> Option<SpsInfo> {
+    let mut i = 0;
+    while i + 3 < data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            let nal = &data[i + 3..];
+            if nal[0] & 0x1f == 7 {
+                return parse_sps_nal(nal);
+            }
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+/// Parse an SPS NAL unit, including its one-byte NAL header.
+fn parse_sps_nal(nal: &[u8]) -> Option<SpsInfo> {
+    if nal.first()? & 0x1f != 7 {
+        return None;
+    }
+    // Strip emulation prevention bytes (00 00 03) from the part we need.
+    let mut rbsp = Vec::with_capacity(3);
+    let mut zeros = 0;
+    for &b in &nal[1..] {
+        if zeros >= 2 && b == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        rbsp.push(b);
+        if rbsp.len() == 3 {
+            break;
+        }
+    }
+    if rbsp.len() < 3 || rbsp[0] == 0 {
+        return None;
+    }
+    Some(SpsInfo {
+        profile_idc: rbsp[0],
+        constraint_flags: rbsp[1],
+        level_idc: rbsp[2],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SPS of a 1080p High profile level 4.0 stream (truncated after level_idc).
+    const SPS: [u8; 4] = [0x67, 0x64, 0x00, 0x28];
+
+    #[test]
+    fn test_parse_avcc() {
+        let mut avcc = vec![1, 0x64, 0x00, 0x28, 0xff, 0xe1, 0x00, SPS.len() as u8];
+        avcc.extend_from_slice(&SPS);
+        let sps = parse_extradata(&avcc).unwrap();
+        assert_eq!(sps.codec_string(), "avc1.640028");
+    }
+
+    #[test]
+    fn test_parse_annexb() {
+        // Constrained baseline, level 3.0.
+        let data = [0, 0, 0, 1, 0x67, 0x42, 0xc0, 0x1e, 0, 0, 1, 0x68, 0xce];
+        let sps = parse_extradata(&data).unwrap();
+        assert_eq!(
+            sps,
+            SpsInfo {
+                profile_idc: 0x42,
+                constraint_flags: 0xc0,
+                level_idc: 0x1e,
+            }
+        );
+        assert_eq!(sps.codec_string(), "avc1.42c01e");
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse_extradata(&[]).is_none());
+        assert!(parse_extradata(&[1, 0x64, 0, 0x28, 0xff, 0xe0]).is_none());
+        assert!(parse_extradata(&[0, 0, 1, 0x68, 0xce]).is_none());
+    }
+}
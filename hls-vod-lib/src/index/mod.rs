@@ -4,13 +4,25 @@
 //! - Video stream detection (codec, resolution, keyframes)
 //! - Audio stream detection (codec, sample rate, channels, language)
 //! - Subtitle stream detection (codec, language, format)
+//! - Font attachments (for client-side ASS rendering)
+//! - Cover art (attached pictures, which FFmpeg presents as video streams)
+//! - Tracks disabled by the operator (sidecar file)
 //! - Segment boundary calculation (keyframe-based)
+//! - Scan progress, and opening files in the background
 
+pub mod aac;
+pub mod artwork;
+pub mod attachment;
 pub mod audio;
+pub mod disabled;
+pub mod h264;
+pub mod progress;
 pub mod scanner;
 pub mod subtitle;
 pub mod video;
 
+pub use artwork::analyze_artwork_stream;
+pub use attachment::analyze_attachment_stream;
 pub use audio::analyze_audio_stream;
 pub use subtitle::analyze_subtitle_stream;
 pub use video::analyze_video_stream;
@@ -0,0 +1,281 @@
+//! Scan progress and background opens
+//!
+//! Indexing a large remux can take many seconds, mostly in opening the file
+//! (parsing `moov` or the MKV cues). `StreamIndex::open_async()` scans in a
+//! background thread and returns an `OpenHandle` right away, so that a
+//! server can answer `202 Accepted` with the progress instead of blocking
+//! the request.
+//!
+//! Opens of the same file share one scan: a player that retries the request
+//! gets the handle of the scan that is already running. Scans are keyed by
+//! the stream id, or by the path of the file without one.
+//!
+//! With `LibConfig::fast_start`, phase 2 of `StreamIndex::open_first_phase()`
+//! (the segment index) is such a scan; the requests of the session that need
+//! the segments wait for it with `pending()`.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::error::{HlsError, Result};
+use crate::media::StreamIndex;
+
+/// How long the result of a background open is kept for a request that
+/// retries, see `OpenHandle::wait()`.
+const RESULT_TTL: Duration = Duration::from_secs(60);
+
+/// The phases of a scan, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanPhase {
+    /// Opening the file and reading the container index
+    Opening,
+    /// Analyzing the tracks
+    Tracks,
+    /// Finding the keyframes
+    Keyframes,
+    /// Reading the first packets (encoder delay, NAL format)
+    FirstPackets,
+    /// Estimating the bitrates of the tracks
+    Bitrates,
+    /// Computing the segment boundaries and subtitle samples
+    Segments,
+    /// The index is complete, or the scan failed
+    Done,
+}
+
+impl ScanPhase {
+    const ALL: [ScanPhase; 7] = [
+        ScanPhase::Opening,
+        ScanPhase::Tracks,
+        ScanPhase::Keyframes,
+        ScanPhase::FirstPackets,
+        ScanPhase::Bitrates,
+        ScanPhase::Segments,
+        ScanPhase::Done,
+    ];
+
+    /// Rough percentage of the scan that is done when this phase starts.
+    pub fn percent(self) -> u8 {
+        match self {
+            ScanPhase::Opening => 0,
+            ScanPhase::Tracks => 50,
+            ScanPhase::Keyframes => 60,
+            ScanPhase::FirstPackets => 70,
+            ScanPhase::Bitrates => 75,
+            ScanPhase::Segments => 90,
+            ScanPhase::Done => 100,
+        }
+    }
+}
+
+/// Progress of a scan, updated by the scanner.
+#[derive(Debug)]
+pub struct ScanProgress {
+    phase: AtomicU8,
+    started: Instant,
+}
+
+impl Default for ScanProgress {
+    fn default() -> Self {
+        Self {
+            phase: AtomicU8::new(0),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl ScanProgress {
+    /// The current phase.
+    pub fn phase(&self) -> ScanPhase {
+        ScanPhase::ALL[self.phase.load(Ordering::Relaxed) as usize]
+    }
+
+    pub(crate) fn set_phase(&self, phase: ScanPhase) {
+        let n = ScanPhase::ALL.iter().position(|p| *p == phase).unwrap_or(0);
+        self.phase.store(n as u8, Ordering::Relaxed);
+    }
+
+    /// Snapshot of the progress.
+    pub fn status(&self) -> ScanStatus {
+        let phase = self.phase();
+        ScanStatus {
+            phase,
+            percent: phase.percent(),
+            elapsed_secs: self.started.elapsed().as_secs_f64(),
+        }
+    }
+}
+
+/// Snapshot of the progress of a scan.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ScanStatus {
+    pub phase: ScanPhase,
+    pub percent: u8,
+    pub elapsed_secs: f64,
+}
+
+impl ScanStatus {
+    /// Estimated seconds until the scan is done, from the time it took so
+    /// far. At least 1. Way off while `Opening`, which is 0% done until it
+    /// is over: cap it before sending it as a `Retry-After`.
+    pub fn remaining_secs(&self) -> u64 {
+        let percent = self.percent.max(1) as f64;
+        let remaining = self.elapsed_secs * (100.0 - percent) / percent;
+        (remaining.ceil() as u64).max(1)
+    }
+}
+
+/// The state of a background open, shared by all handles.
+struct OpenState {
+    progress: ScanProgress,
+    result: Mutex<Option<(Result<Arc<StreamIndex>>, Instant)>>,
+    done: Condvar,
+}
+
+/// Handle of a file being indexed in the background.
+#[derive(Clone)]
+pub struct OpenHandle {
+    key: String,
+    state: Arc<OpenState>,
+}
+
+impl OpenHandle {
+    /// Progress of the scan.
+    pub fn status(&self) -> ScanStatus {
+        self.state.progress.status()
+    }
+
+    /// Wait at most `timeout` for the index. Returns `None` if the file is
+    /// still being scanned; call again, or check `status()`.
+    ///
+    /// A failed scan is reported once, after that this returns `None` too.
+    /// The next open of the file scans it again.
+    pub fn wait(&self, timeout: Duration) -> Option<Result<Arc<StreamIndex>>> {
+        let result = self.state.result.lock().unwrap();
+        let (mut result, _) = self
+            .state
+            .done
+            .wait_timeout_while(result, timeout, |r| r.is_none())
+            .unwrap();
+        if let Some((Ok(index), _)) = result.as_ref() {
+            return Some(Ok(Arc::clone(index)));
+        }
+        let (err, _) = result.take()?;
+        drop(result);
+        opening().remove_if(&self.key, |_, s| Arc::ptr_eq(s, &self.state));
+        Some(err)
+    }
+}
+
+/// The background opens, by stream id or path.
+fn opening() -> &'static DashMap<String, Arc<OpenState>> {
+    static OPENING: OnceLock<DashMap<String, Arc<OpenState>>> = OnceLock::new();
+    OPENING.get_or_init(DashMap::new)
+}
+
+/// The scan with key `key` that is running, or that finished recently.
+pub(crate) fn pending(key: &str) -> Option<OpenHandle> {
+    let state = opening().get(key)?.clone();
+    Some(OpenHandle {
+        key: key.to_string(),
+        state,
+    })
+}
+
+/// Start a scan with key `key` in the background with `open`, or join the
+/// scan that is already running. See `StreamIndex::open_async()`.
+pub(crate) fn open_in_background(
+    key: &str,
+    open: impl FnOnce(&ScanProgress) -> Result<Arc<StreamIndex>> + Send + 'static,
+) -> OpenHandle {
+    let opening = opening();
+    // Results that no request came back for.
+    opening.retain(|_, s| match &*s.result.lock().unwrap() {
+        Some((_, finished)) => finished.elapsed() < RESULT_TTL,
+        None => true,
+    });
+
+    let mut started = None;
+    let state = opening
+        .entry(key.to_string())
+        .or_insert_with(|| {
+            let state = Arc::new(OpenState {
+                progress: ScanProgress::default(),
+                result: Mutex::new(None),
+                done: Condvar::new(),
+            });
+            started = Some(Arc::clone(&state));
+            state
+        })
+        .clone();
+
+    if let Some(state) = started {
+        let key = key.to_string();
+        let scan = Arc::clone(&state);
+        // Logged in the span of the request that started the scan.
+        let span = tracing::Span::current();
+        let spawned = std::thread::Builder::new()
+            .name("hls-open".to_string())
+            .spawn(move || {
+                let _span = span.enter();
+                let result = open(&scan.progress);
+                if let Err(e) = &result {
+                    tracing::warn!("Background open of {} failed: {}", key, e);
+                }
+                scan.progress.set_phase(ScanPhase::Done);
+                *scan.result.lock().unwrap() = Some((result, Instant::now()));
+                scan.done.notify_all();
+            });
+        if let Err(e) = spawned {
+            state.progress.set_phase(ScanPhase::Done);
+            *state.result.lock().unwrap() = Some((Err(HlsError::Io(e)), Instant::now()));
+        }
+    }
+
+    OpenHandle {
+        key: key.to_string(),
+        state,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remaining_secs() {
+        let status = ScanStatus {
+            phase: ScanPhase::Tracks,
+            percent: ScanPhase::Tracks.percent(),
+            elapsed_secs: 4.0,
+        };
+        assert_eq!(status.remaining_secs(), 4);
+
+        let progress = ScanProgress::default();
+        assert_eq!(progress.phase(), ScanPhase::Opening);
+        assert!(progress.status().remaining_secs() >= 1);
+        progress.set_phase(ScanPhase::Segments);
+        assert_eq!(progress.status().percent, 90);
+    }
+
+    #[test]
+    fn test_failed_open() {
+        let key = "/nonexistent/progress-test.mkv";
+        let handle = open_in_background(key, |progress| {
+            progress.set_phase(ScanPhase::Tracks);
+            Err(HlsError::NoVideoStream)
+        });
+        // Reported once, then forgotten.
+        assert!(matches!(
+            handle.wait(Duration::from_secs(10)),
+            Some(Err(HlsError::NoVideoStream))
+        ));
+        assert!(handle.wait(Duration::from_millis(10)).is_none());
+        assert!(pending(key).is_none());
+    }
+}
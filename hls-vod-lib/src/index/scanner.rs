@@ -4,17 +4,30 @@
 //! the demuxer's in-memory index tables (built from `moov` for MP4, `Cues` for
 //! MKV, etc.) without reading any media data.  Files that do not have a
 //! complete index are rejected with `HlsError::NoIndex`.
+//!
+//! An index that marks every frame as a keyframe is checked by reading the
+//! NAL unit headers of the video packets. One with no usable keyframes gives
+//! fixed-duration segments that are not independent.
 
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::SystemTime;
 
 use ffmpeg_next as ffmpeg;
 
 use crate::error::{FfmpegError, HlsError, Result};
 use crate::ffmpeg_utils::index::read_index_entries;
-use crate::media::{SegmentInfo, StreamIndex, SubtitleSampleRef};
+use crate::media::{
+    ExcludedTrack, NalFormat, SegmentEscalation, SegmentInfo, StreamIndex, SubtitleSampleRef,
+};
 
-use super::{analyze_audio_stream, analyze_subtitle_stream, analyze_video_stream};
+use super::artwork::is_attached_pic;
+use super::progress::{ScanPhase, ScanProgress};
+use super::video::{compute_video_stats, gop_aligned_duration};
+use super::{
+    analyze_artwork_stream, analyze_attachment_stream, analyze_audio_stream,
+    analyze_subtitle_stream, analyze_video_stream,
+};
 
 /// Indexing options
 #[derive(Debug, Clone)]
@@ -23,17 +36,53 @@ pub struct IndexOptions {
     pub segment_duration_secs: f64,
     /// Whether to read demuxer indexes and calculate segment boundaries
     pub index_segments: bool,
+    /// Seconds of packets to read per track to estimate the bitrate of
+    /// tracks whose container doesn't declare one (common for MKV). 0 disables.
+    pub bitrate_probe_secs: f64,
+    /// Compute keyframe and bitrate statistics of the video tracks, and cut
+    /// segments at a whole number of GOPs if the keyframe interval is regular
+    pub video_stats: bool,
+    /// Above this number of segments, the segments are made longer. 0 is no
+    /// limit.
+    pub max_segments: usize,
 }
 
+/// Default of `IndexOptions::max_segments`: a bit over 11 hours of 4 second
+/// segments.
+pub const DEFAULT_MAX_SEGMENTS: usize = 10_000;
+
+/// Shortest segment duration of a file with too many segments.
+const MIN_ESCALATED_SEGMENT_SECS: f64 = 6.0;
+
 impl Default for IndexOptions {
     fn default() -> Self {
         Self {
             segment_duration_secs: 4.0,
             index_segments: true,
+            bitrate_probe_secs: 10.0,
+            video_stats: false,
+            max_segments: DEFAULT_MAX_SEGMENTS,
         }
     }
 }
 
+static VIDEO_STATS: AtomicBool = AtomicBool::new(false);
+
+/// Compute keyframe interval and bitrate statistics of video tracks when a
+/// file is opened, see `VideoStreamInfo::stats`.
+///
+/// With a regular keyframe interval, the segment duration is rounded to a
+/// whole number of GOPs, so that segments are not a GOP longer than the
+/// target. This changes the segment boundaries (and the stream ids) of
+/// files that are opened after the change. The default is off.
+pub fn set_video_stats(enabled: bool) {
+    VIDEO_STATS.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn video_stats() -> bool {
+    VIDEO_STATS.load(Ordering::Relaxed)
+}
+
 /// Scan a media file with custom options.
 ///
 /// Opens the file (which causes the demuxer to parse the container header and
@@ -42,22 +91,66 @@ impl Default for IndexOptions {
 pub fn scan_file_with_options<P: AsRef<Path>>(
     path: P,
     options: &IndexOptions,
+) -> Result<StreamIndex> {
+    scan_file_with_progress(path, options, &ScanProgress::default())
+}
+
+/// `scan_file_with_options()`, reporting the phase of the scan in `progress`.
+pub fn scan_file_with_progress<P: AsRef<Path>>(
+    path: P,
+    options: &IndexOptions,
+    progress: &ScanProgress,
 ) -> Result<StreamIndex> {
     let path = path.as_ref().to_path_buf();
+    progress.set_phase(ScanPhase::Opening);
 
     // Opening the file parses moov/cues and populates the demuxer index.
     // No media data is read at this point.
-    let mut context = ffmpeg::format::input(&path)
-        .map_err(|e| FfmpegError::OpenInput(format!("Failed to open {:?}: {}", path, e)))?;
+    let mut context = crate::source::open_input(&path)?;
 
     let mut index = StreamIndex::new(path.clone());
     index.duration_secs = context.duration() as f64 / ffmpeg::ffi::AV_TIME_BASE as f64;
+    progress.set_phase(ScanPhase::Tracks);
 
     // Analyze each stream
     for (i, stream) in context.streams().enumerate() {
         let medium = stream.parameters().medium();
 
+        // TrueHD/DTS, unless it is transcoded.
+        if medium == ffmpeg::media::Type::Audio {
+            let codec_id = stream.parameters().id();
+            if let Some(reason) = super::audio::unsupported_reason(codec_id) {
+                tracing::info!(
+                    "Excluding audio stream {} (codec={:?}): {}",
+                    i,
+                    codec_id,
+                    reason
+                );
+                index.excluded_tracks.push(ExcludedTrack {
+                    stream_index: i,
+                    codec_id,
+                    language: stream.metadata().get("language").map(|s| s.to_string()),
+                    reason,
+                });
+                continue;
+            }
+        }
+
         match medium {
+            ffmpeg::media::Type::Video if is_attached_pic(&stream) => {
+                match analyze_artwork_stream(&stream, i) {
+                    Some(artwork) => {
+                        tracing::debug!(
+                            "Found cover art: stream {} ({}, {} bytes)",
+                            i,
+                            artwork.mime_type,
+                            artwork.data.len()
+                        );
+                        index.artwork.push(artwork);
+                    }
+                    None => tracing::debug!("Skipping attached picture stream {}", i),
+                }
+            }
             ffmpeg::media::Type::Video => match analyze_video_stream(&stream, i) {
                 Ok(info) => {
                     tracing::debug!(
@@ -90,17 +183,55 @@ pub fn scan_file_with_options<P: AsRef<Path>>(
                         info.format
                     );
                     index.subtitle_streams.push(info);
+                } else {
+                    let codec_id = stream.parameters().id();
+                    let reason = super::subtitle::unsupported_reason(codec_id)
+                        .unwrap_or("unsupported subtitle codec");
+                    tracing::info!(
+                        "Excluding subtitle stream {} (codec={:?}): {}",
+                        i,
+                        codec_id,
+                        reason
+                    );
+                    index.excluded_tracks.push(ExcludedTrack {
+                        stream_index: i,
+                        codec_id,
+                        language: stream.metadata().get("language").map(|s| s.to_string()),
+                        reason,
+                    });
                 }
             }
+            ffmpeg::media::Type::Attachment => match analyze_attachment_stream(&stream, i) {
+                Some(attachment) => {
+                    tracing::debug!(
+                        "Found font attachment: {} ({}, {} bytes)",
+                        attachment.filename,
+                        attachment.mime_type,
+                        attachment.data.len()
+                    );
+                    index.attachments.push(attachment);
+                }
+                None => tracing::debug!("Skipping attachment stream {} (not a font)", i),
+            },
             _ => tracing::debug!("Skipping stream {} (type={:?})", i, medium),
         }
     }
 
-    if index.video_streams.is_empty() {
+    exclude_disabled_tracks(&mut index, &path);
+
+    // An MP3 or M4A with cover art has no video: the audio is served on its
+    // own, see `generate_audio_master_playlist`.
+    let audio_only = index.video_streams.is_empty();
+    if audio_only && (index.artwork.is_empty() || index.audio_streams.is_empty()) {
         return Err(HlsError::NoVideoStream);
     }
 
     if !options.index_segments {
+        // The main playlist needs the bitrates, see `StreamIndex::open_first_phase()`.
+        if options.bitrate_probe_secs > 0.0 {
+            progress.set_phase(ScanPhase::Bitrates);
+            measure_bitrates(&mut context, &mut index, options.bitrate_probe_secs);
+        }
         tracing::info!(
             "Parsed metadata for {:?}: duration={:.2}s, video={}, audio={}, subtitles={} (indexing skipped)",
             path,
@@ -114,7 +245,11 @@ pub fn scan_file_with_options<P: AsRef<Path>>(
 
     // --- Build everything from the demuxer index tables ---
 
-    let video_stream_idx = index.video_streams.first().unwrap().stream_index;
+    // Without video, the first audio track is the timeline.
+    let video_stream_idx = match index.video_streams.first() {
+        Some(video) => video.stream_index,
+        None => index.audio_streams[0].stream_index,
+    };
     let video_stream = context
         .streams()
         .nth(video_stream_idx)
@@ -136,8 +271,14 @@ pub fn scan_file_with_options<P: AsRef<Path>>(
         video_start_time as f64 * video_tb.numerator() as f64 / video_tb.denominator() as f64
     );
 
-    // Read the video stream's index entries (keyframe positions from moov/cues)
-    let video_entries = read_index_entries(&video_stream);
+    // Read the video stream's index entries (keyframe positions from moov/cues).
+    // Every audio frame can start a segment, so an audio-only file is cut
+    // at fixed durations.
+    let video_entries = if audio_only {
+        audio_only_entries(video_tb, index.duration_secs, options.segment_duration_secs)
+    } else {
+        read_index_entries(&video_stream)
+    };
     // Drop video_stream borrow so we can call context.packets() mutably below
     drop(video_stream);
     if video_entries.is_empty() {
@@ -157,25 +298,141 @@ pub fn scan_file_with_options<P: AsRef<Path>>(
         );
     }
 
+    // Some files mark every frame as a keyframe (an MP4 without `stss`), or
+    // hardly any. Find the real keyframes, or cut at fixed durations.
+    progress.set_phase(ScanPhase::Keyframes);
+    let mut video_entries = video_entries;
+    let mut fixed_duration = false;
+    match keyframe_spacing(&video_entries, video_tb) {
+        KeyframeSpacing::Regular => {}
+        KeyframeSpacing::Dense => {
+            // Intra-only codecs do have a keyframe at every frame.
+            if let Some(probed) = probe_keyframes(&mut context, video_stream_idx, &video_entries) {
+                if keyframe_spacing(&probed, video_tb) == KeyframeSpacing::Sparse {
+                    tracing::warn!(
+                        "{:?}: every frame is marked as a keyframe, but few are; \
+                         using fixed-duration segments",
+                        path
+                    );
+                    fixed_duration = true;
+                } else {
+                    tracing::info!(
+                        "{:?}: every frame is marked as a keyframe, found {} by probing",
+                        path,
+                        probed.iter().filter(|e| e.is_keyframe()).count()
+                    );
+                    video_entries = probed;
+                }
+            }
+        }
+        KeyframeSpacing::Sparse => {
+            tracing::warn!(
+                "{:?}: too few keyframes in the index; using fixed-duration segments",
+                path
+            );
+            fixed_duration = true;
+        }
+    }
+
+    // Keyframe statistics of every video track, from the same index tables.
+    if options.video_stats {
+        for video in &mut index.video_streams {
+            let Some(stream) = context.streams().nth(video.stream_index) else {
+                continue;
+            };
+            let has_b_frames =
+                crate::ffmpeg_utils::helpers::codec_params_video_delay(&stream.parameters()) > 0;
+            video.stats = if video.stream_index == video_stream_idx {
+                compute_video_stats(&video_entries, video_tb, has_b_frames)
+            } else {
+                compute_video_stats(
+                    &read_index_entries(&stream),
+                    stream.time_base(),
+                    has_b_frames,
+                )
+            };
+            tracing::debug!("Video stream {}: {:?}", video.stream_index, video.stats);
+        }
+    }
+
     // Determine encoder_delay for each audio stream by reading its first packet.
-    // FFmpeg signals encoder delay as a negative first-packet DTS — universal
-    // across all containers (MP4, MKV, …) and codecs (AAC, Opus, Vorbis, …).
+    // FFmpeg signals the container's encoder delay as a negative first-packet
+    // DTS, in the stream timebase (1/1000 for MKV); it is converted to samples.
+    // Opus takes its pre-skip from the OpusHead instead, see
+    // `audio::encoder_delay`.
     // The init segment's edit list tells the player:
     //   presentation = (tfdt - encoder_delay) / timescale
     // so we must set: tfdt = video_presentation * timescale + encoder_delay
+    //
+    // The first H.264/H.265 packet shows whether NAL units are Annex B framed.
+    //
+    // The first packet of the timeline track has its lowest decode timestamp,
+    // which is below its first presentation timestamp with B-frames. The
+    // negative timestamp policy shifts the title by that, see `NegativeTsPolicy`.
+    progress.set_phase(ScanPhase::FirstPackets);
     {
         use std::collections::HashMap;
         let audio_indices: std::collections::HashSet<usize> =
             index.audio_streams.iter().map(|a| a.stream_index).collect();
         let mut delays: HashMap<usize, i64> = HashMap::new();
+        let mut nal_pending: std::collections::HashSet<usize> = index
+            .video_streams
+            .iter()
+            .filter(|v| {
+                matches!(
+                    v.codec_id,
+                    ffmpeg::codec::Id::H264 | ffmpeg::codec::Id::HEVC
+                )
+            })
+            .map(|v| v.stream_index)
+            .collect();
+        let mut nal_formats: HashMap<usize, NalFormat> = HashMap::new();
+        let mut first_dts = None;
 
         for (stream, packet) in context.packets() {
             let idx = stream.index();
+            if idx == video_stream_idx && first_dts.is_none() {
+                first_dts = Some(packet.dts().or(packet.pts()).unwrap_or(0));
+            }
+            if nal_pending.remove(&idx) {
+                let annexb = packet.data().is_some_and(crate::segment::nal::is_annexb);
+                nal_formats.insert(
+                    idx,
+                    if annexb {
+                        NalFormat::AnnexB
+                    } else {
+                        NalFormat::LengthPrefixed
+                    },
+                );
+                if delays.len() == audio_indices.len()
+                    && nal_pending.is_empty()
+                    && first_dts.is_some()
+                {
+                    break;
+                }
+                continue;
+            }
             if !audio_indices.contains(&idx) || delays.contains_key(&idx) {
                 continue;
             }
             let dts = packet.dts().unwrap_or(0);
-            let delay = if dts < 0 { -dts } else { 0 };
+            let params = stream.parameters();
+            let sample_rate = crate::ffmpeg_utils::helpers::codec_params_sample_rate(&params);
+            let container_delay = if dts < 0 && sample_rate > 0 {
+                crate::ffmpeg_utils::utils::rescale_ts(
+                    -dts,
+                    stream.time_base(),
+                    ffmpeg::Rational::new(1, sample_rate as i32),
+                )
+            } else {
+                0
+            };
+            let delay = super::audio::encoder_delay(
+                params.id(),
+                &crate::ffmpeg_utils::helpers::codec_params_extradata(&params),
+                sample_rate,
+                container_delay,
+            );
             delays.insert(idx, delay);
             tracing::debug!(
                 "Audio stream {}: first_pkt_dts={}, encoder_delay={}",
@@ -183,24 +440,90 @@ pub fn scan_file_with_options<P: AsRef<Path>>(
                 dts,
                 delay
             );
-            if delays.len() == audio_indices.len() {
+            if delays.len() == audio_indices.len() && nal_pending.is_empty() && first_dts.is_some()
+            {
                 break;
             }
         }
 
+        index.first_dts = first_dts.unwrap_or(0);
         for audio in &mut index.audio_streams {
             audio.encoder_delay = *delays.get(&audio.stream_index).unwrap_or(&0);
         }
+        for video in &mut index.video_streams {
+            if let Some(&format) = nal_formats.get(&video.stream_index) {
+                if format == NalFormat::AnnexB {
+                    tracing::debug!("Video stream {}: Annex B packets", video.stream_index);
+                }
+                video.nal_format = format;
+            }
+        }
+    }
+
+    if options.bitrate_probe_secs > 0.0 {
+        progress.set_phase(ScanPhase::Bitrates);
+        measure_bitrates(&mut context, &mut index, options.bitrate_probe_secs);
     }
 
     // Build segment boundaries from keyframe entries
-    let segments = build_segments_from_entries(
-        &video_entries,
-        video_tb,
-        video_start_time,
-        index.duration_secs,
-        options.segment_duration_secs,
-    );
+    progress.set_phase(ScanPhase::Segments);
+    let target_duration_secs = match index.video_streams.first().and_then(|v| v.stats.as_ref()) {
+        Some(stats) if !fixed_duration => {
+            gop_aligned_duration(stats, options.segment_duration_secs)
+        }
+        _ => options.segment_duration_secs,
+    };
+    if target_duration_secs != options.segment_duration_secs {
+        tracing::debug!(
+            "Segment duration {:.3}s, aligned to the keyframe interval",
+            target_duration_secs
+        );
+    }
+    let build_segments = |target_duration_secs| {
+        if fixed_duration {
+            build_fixed_duration_segments(
+                &video_entries,
+                video_tb,
+                index.duration_secs,
+                target_duration_secs,
+            )
+        } else {
+            build_segments_from_entries(
+                &video_entries,
+                video_tb,
+                video_start_time,
+                index.duration_secs,
+                target_duration_secs,
+            )
+        }
+    };
+    let mut segments = build_segments(target_duration_secs);
+
+    // A day-long recording with short GOPs: fewer, longer segments keep the
+    // index and the playlists within reason.
+    if options.max_segments > 0 && segments.len() > options.max_segments {
+        let escalated = escalated_duration(
+            index.duration_secs,
+            target_duration_secs,
+            options.max_segments,
+        );
+        let escalated_segments = build_segments(escalated);
+        tracing::info!(
+            "{:?}: {} segments is over the limit of {}, {} segments of {:.0}s instead",
+            path,
+            segments.len(),
+            options.max_segments,
+            escalated_segments.len(),
+            escalated
+        );
+        index.segment_escalation = Some(SegmentEscalation {
+            max_segments: options.max_segments,
+            original_segments: segments.len(),
+            original_duration_secs: target_duration_secs,
+            duration_secs: escalated,
+        });
+        segments = escalated_segments;
+    }
 
     if let Some(seg0) = segments.first() {
         tracing::debug!(
@@ -249,8 +572,15 @@ pub fn scan_file_with_options<P: AsRef<Path>>(
         );
     }
 
+    index.discontinuities = find_discontinuities(&video_entries, video_tb, &segments);
+    if !index.discontinuities.is_empty() {
+        tracing::info!(
+            "{:?}: timeline discontinuities at segments {:?}",
+            path,
+            index.discontinuities
+        );
+    }
     index.segments = segments;
-    index.init_segment_first_pts();
     index.indexed_at = SystemTime::now();
 
     tracing::info!(
@@ -270,6 +600,58 @@ pub fn scan_file_with_options<P: AsRef<Path>>(
     Ok(index)
 }
 
+/// Target segment duration for a file of `total_duration_secs` with at most
+/// `max_segments` segments, in whole seconds.
+///
+/// Segments are closed at the first keyframe after 80% of the target, so
+/// the target is chosen for segments of 80% of it.
+fn escalated_duration(
+    total_duration_secs: f64,
+    target_duration_secs: f64,
+    max_segments: usize,
+) -> f64 {
+    let needed = total_duration_secs / (max_segments as f64 * 0.8);
+    needed
+        .max(target_duration_secs)
+        .max(MIN_ESCALATED_SEGMENT_SECS)
+        .ceil()
+}
+
+/// Move the tracks the operator disabled to `excluded_tracks`, see `index::disabled`.
+fn exclude_disabled_tracks(index: &mut StreamIndex, path: &Path) {
+    for disabled in super::disabled::disabled_tracks(path) {
+        let i = disabled.track;
+        let (codec_id, language) =
+            if let Some(pos) = index.video_streams.iter().position(|v| v.stream_index == i) {
+                (index.video_streams.remove(pos).codec_id, None)
+            } else if let Some(pos) = index.audio_streams.iter().position(|a| a.stream_index == i) {
+                let a = index.audio_streams.remove(pos);
+                (a.codec_id, a.language)
+            } else if let Some(pos) = index
+                .subtitle_streams
+                .iter()
+                .position(|s| s.stream_index == i)
+            {
+                let s = index.subtitle_streams.remove(pos);
+                (s.codec_id, s.language)
+            } else {
+                continue;
+            };
+        tracing::info!(
+            "Excluding stream {} (codec={:?}): disabled ({})",
+            i,
+            codec_id,
+            disabled.reason
+        );
+        index.excluded_tracks.push(ExcludedTrack {
+            stream_index: i,
+            codec_id,
+            language,
+            reason: super::disabled::DISABLED_REASON,
+        });
+    }
+}
+
 /// Build `SegmentInfo` list from video keyframe index entries.
 ///
 /// Walks the keyframe entries and closes a segment whenever the accumulated
@@ -338,6 +720,333 @@ fn build_segments_from_entries(
     segments
 }
 
+/// Index entries every `target_duration_secs` for an audio-only file, which
+/// `build_segments_from_entries` turns into segments of that duration.
+fn audio_only_entries(
+    timebase: ffmpeg::Rational,
+    total_duration_secs: f64,
+    target_duration_secs: f64,
+) -> Vec<crate::ffmpeg_utils::index::IndexEntry> {
+    let count = (total_duration_secs / target_duration_secs).ceil().max(1.0) as usize;
+    (0..count)
+        .map(|i| crate::ffmpeg_utils::index::IndexEntry {
+            pos: 0,
+            timestamp: seconds_to_pts(i as f64 * target_duration_secs, timebase),
+            size: 0,
+            flags: 0x0001,
+        })
+        .collect()
+}
+
+/// Build `SegmentInfo` list at fixed durations from all video index entries,
+/// for files whose keyframe index can't be used.
+///
+/// Segments are cut at any frame, so none is marked as starting with a
+/// keyframe, and the playlists leave out `EXT-X-INDEPENDENT-SEGMENTS`.
+fn build_fixed_duration_segments(
+    entries: &[crate::ffmpeg_utils::index::IndexEntry],
+    timebase: ffmpeg::Rational,
+    total_duration_secs: f64,
+    target_duration_secs: f64,
+) -> Vec<SegmentInfo> {
+    use crate::ffmpeg_utils::index::IndexEntry;
+
+    let entries: Vec<IndexEntry> = entries
+        .iter()
+        .map(|e| IndexEntry {
+            flags: e.flags | 0x0001,
+            ..e.clone()
+        })
+        .collect();
+    let mut segments = build_segments_from_entries(
+        &entries,
+        timebase,
+        0,
+        total_duration_secs,
+        target_duration_secs,
+    );
+    for segment in &mut segments {
+        segment.is_keyframe = false;
+    }
+    segments
+}
+
+/// Average keyframe interval (in seconds) below which the index is taken to
+/// mark every frame as a keyframe.
+const MIN_KEYFRAME_SPACING_SECS: f64 = 0.1;
+
+/// Longest stretch without a keyframe (in seconds) that still gives usable
+/// segment boundaries.
+const MAX_KEYFRAME_SPACING_SECS: f64 = 30.0;
+
+/// How the keyframes in a video index are spaced, see `keyframe_spacing`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyframeSpacing {
+    Regular,
+    /// (Nearly) every frame is a keyframe.
+    Dense,
+    /// No keyframes, or stretches longer than `MAX_KEYFRAME_SPACING_SECS`.
+    Sparse,
+}
+
+/// Check the keyframe spacing of the video index entries.
+///
+/// Timestamp jumps larger than `DISCONTINUITY_GAP_SECS` are left out, so a
+/// discontinuity does not count as a long stretch without keyframes.
+fn keyframe_spacing(
+    entries: &[crate::ffmpeg_utils::index::IndexEntry],
+    timebase: ffmpeg::Rational,
+) -> KeyframeSpacing {
+    let mut keyframes = 0usize;
+    let mut covered_secs = 0.0;
+    let mut since_keyframe = 0.0;
+    let mut max_gap: f64 = 0.0;
+    for (i, entry) in entries.iter().enumerate() {
+        if i > 0 {
+            let delta = pts_to_seconds(entry.timestamp - entries[i - 1].timestamp, timebase);
+            if (0.0..=DISCONTINUITY_GAP_SECS).contains(&delta) {
+                covered_secs += delta;
+                since_keyframe += delta;
+            }
+        }
+        if entry.is_keyframe() {
+            keyframes += 1;
+            max_gap = max_gap.max(since_keyframe);
+            since_keyframe = 0.0;
+        }
+    }
+    max_gap = max_gap.max(since_keyframe);
+
+    if keyframes == 0 || max_gap > MAX_KEYFRAME_SPACING_SECS {
+        KeyframeSpacing::Sparse
+    } else if keyframes > 1 && covered_secs / (keyframes as f64) < MIN_KEYFRAME_SPACING_SECS {
+        KeyframeSpacing::Dense
+    } else {
+        KeyframeSpacing::Regular
+    }
+}
+
+/// Find the real keyframes of a video stream whose index marks every frame
+/// as one, by reading the NAL unit headers of every packet.
+///
+/// Returns the index entries with only the IDR (H.264) or IRAP (HEVC)
+/// pictures marked as keyframes, or `None` for other codecs. Leaves the
+/// input at the start of the file.
+fn probe_keyframes(
+    context: &mut ffmpeg::format::context::Input,
+    stream_index: usize,
+    entries: &[crate::ffmpeg_utils::index::IndexEntry],
+) -> Option<Vec<crate::ffmpeg_utils::index::IndexEntry>> {
+    use std::collections::HashMap;
+
+    let params = context.stream(stream_index)?.parameters();
+    let codec_id = params.id();
+    if !matches!(codec_id, ffmpeg::codec::Id::H264 | ffmpeg::codec::Id::HEVC) {
+        return None;
+    }
+    let extradata = crate::ffmpeg_utils::helpers::codec_params_extradata(&params);
+    let length_size = crate::segment::nal::nal_length_size(codec_id, &extradata);
+
+    // Index entries by byte offset, packets are read in decode order.
+    let by_pos: HashMap<u64, usize> = entries
+        .iter()
+        .enumerate()
+        .map(|(i, e)| (e.pos, i))
+        .collect();
+    let mut probed = entries.to_vec();
+    for entry in &mut probed {
+        entry.flags &= !0x0001;
+    }
+    // The framing of the stream, from its first packet.
+    let mut nal_format = None;
+
+    if let Err(e) = context.seek(0, ..1) {
+        tracing::warn!("keyframe probe: seek to start failed: {}", e);
+        return None;
+    }
+    for (stream, packet) in context.packets() {
+        if stream.index() != stream_index {
+            continue;
+        }
+        let Some(&i) = u64::try_from(packet.position())
+            .ok()
+            .and_then(|pos| by_pos.get(&pos))
+        else {
+            continue;
+        };
+        let data = packet.data().unwrap_or_default();
+        let nal_format = *nal_format.get_or_insert_with(|| {
+            if crate::segment::nal::is_annexb(data) {
+                NalFormat::AnnexB
+            } else {
+                NalFormat::LengthPrefixed
+            }
+        });
+        let random_access =
+            crate::segment::nal::is_random_access(codec_id, data, nal_format, length_size);
+        if random_access == Some(true) {
+            probed[i].flags |= 0x0001;
+        }
+    }
+    if let Err(e) = context.seek(0, ..1) {
+        tracing::warn!("keyframe probe: seek to start failed: {}", e);
+    }
+    Some(probed)
+}
+
+/// Upper bound on the number of packets read by `measure_bitrates`.
+const BITRATE_PROBE_MAX_PACKETS: usize = 100_000;
+
+/// Estimate the bitrate of audio and video tracks that have none.
+///
+/// Reads packets from the start of the file until every such track has
+/// covered `probe_secs` seconds (or the file ends), and sets `bitrate` to the
+/// measured average. Tracks with a known bitrate are left alone.
+fn measure_bitrates(
+    context: &mut ffmpeg::format::context::Input,
+    index: &mut StreamIndex,
+    probe_secs: f64,
+) {
+    use std::collections::HashMap;
+
+    struct Probe {
+        bytes: u64,
+        first_ts: Option<i64>,
+        last_ts: i64,
+        timebase: ffmpeg::Rational,
+        done: bool,
+    }
+
+    let mut probes: HashMap<usize, Probe> = HashMap::new();
+    let unknown = index
+        .video_streams
+        .iter()
+        .filter(|v| v.bitrate == 0)
+        .map(|v| v.stream_index)
+        .chain(
+            index
+                .audio_streams
+                .iter()
+                .filter(|a| a.bitrate == 0)
+                .map(|a| a.stream_index),
+        );
+    for idx in unknown {
+        if let Some(stream) = context.stream(idx) {
+            probes.insert(
+                idx,
+                Probe {
+                    bytes: 0,
+                    first_ts: None,
+                    last_ts: 0,
+                    timebase: stream.time_base(),
+                    done: false,
+                },
+            );
+        }
+    }
+    if probes.is_empty() {
+        return;
+    }
+
+    if let Err(e) = context.seek(0, ..1) {
+        tracing::warn!("bitrate probe: seek to start failed: {}", e);
+        return;
+    }
+
+    let mut remaining = probes.len();
+    for (stream, packet) in context.packets().take(BITRATE_PROBE_MAX_PACKETS) {
+        let Some(probe) = probes.get_mut(&stream.index()) else {
+            continue;
+        };
+        let Some(ts) = packet.dts().or(packet.pts()) else {
+            continue;
+        };
+        if probe.done {
+            continue;
+        }
+        let first_ts = *probe.first_ts.get_or_insert(ts);
+        probe.last_ts = probe.last_ts.max(ts + packet.duration());
+        probe.bytes += packet.size() as u64;
+        if pts_to_seconds(probe.last_ts - first_ts, probe.timebase) >= probe_secs {
+            probe.done = true;
+            remaining -= 1;
+            if remaining == 0 {
+                break;
+            }
+        }
+    }
+
+    for (idx, probe) in probes {
+        let Some(first_ts) = probe.first_ts else {
+            continue;
+        };
+        let secs = pts_to_seconds(probe.last_ts - first_ts, probe.timebase);
+        if secs < 0.5 {
+            continue;
+        }
+        let bitrate = (probe.bytes as f64 * 8.0 / secs) as u64;
+        tracing::debug!(
+            "Stream {}: measured bitrate {} bps over {:.1}s",
+            idx,
+            bitrate,
+            secs
+        );
+        if let Some(v) = index
+            .video_streams
+            .iter_mut()
+            .find(|v| v.stream_index == idx)
+        {
+            v.bitrate = bitrate;
+        } else if let Some(a) = index
+            .audio_streams
+            .iter_mut()
+            .find(|a| a.stream_index == idx)
+        {
+            a.bitrate = bitrate;
+        }
+    }
+}
+
+/// Keyframe gap (in seconds) above which the timeline is considered discontinuous.
+const DISCONTINUITY_GAP_SECS: f64 = 10.0;
+
+/// Find the segments that start after a timeline discontinuity.
+///
+/// Clipped or stitched files can have keyframe timestamps that jump backwards
+/// or skip ahead by much more than a GOP. The segment that starts at such a
+/// keyframe is marked, so that every variant playlist emits
+/// `EXT-X-DISCONTINUITY` before the same segment. Returns sorted sequence numbers.
+fn find_discontinuities(
+    entries: &[crate::ffmpeg_utils::index::IndexEntry],
+    timebase: ffmpeg::Rational,
+    segments: &[SegmentInfo],
+) -> Vec<usize> {
+    let mut discontinuities = Vec::new();
+    let mut prev_pts: Option<i64> = None;
+
+    for entry in entries.iter().filter(|e| e.is_keyframe()) {
+        let pts = entry.timestamp;
+        if let Some(prev) = prev_pts {
+            let gap = pts_to_seconds(pts - prev, timebase);
+            if gap < 0.0 || gap > DISCONTINUITY_GAP_SECS {
+                if let Some(seg) = segments
+                    .iter()
+                    .find(|s| s.start_pts == pts && s.video_byte_offset == entry.pos)
+                {
+                    if seg.sequence > 0 {
+                        discontinuities.push(seg.sequence);
+                    }
+                }
+            }
+        }
+        prev_pts = Some(pts);
+    }
+
+    discontinuities.sort_unstable();
+    discontinuities.dedup();
+    discontinuities
+}
+
 /// Map an iterator of subtitle PTS values to the video segment sequences that
 /// contain them.  Returns a sorted, deduplicated `Vec<usize>`.
 fn map_pts_to_segments(
@@ -411,4 +1120,129 @@ mod tests {
         let pts = seconds_to_pts(2.5, timebase);
         assert!((pts_to_seconds(pts, timebase) - 2.5).abs() < 0.0001);
     }
+
+    #[test]
+    fn test_find_discontinuities() {
+        use crate::ffmpeg_utils::index::IndexEntry;
+
+        let timebase = ffmpeg::Rational::new(1, 1000);
+        // Keyframes every 4s, with a 60s jump before the one at 72s.
+        let entries: Vec<IndexEntry> = [0, 4000, 8000, 12000, 72000, 76000]
+            .iter()
+            .enumerate()
+            .map(|(i, &ts)| IndexEntry {
+                pos: i as u64 * 1000,
+                timestamp: ts,
+                size: 1000,
+                flags: 1,
+            })
+            .collect();
+        let segments = build_segments_from_entries(&entries, timebase, 0, 80.0, 4.0);
+        let discontinuities = find_discontinuities(&entries, timebase, &segments);
+        assert_eq!(discontinuities, vec![4]);
+        assert_eq!(segments[4].start_pts, 72000);
+    }
+
+    #[test]
+    fn test_keyframe_spacing() {
+        use crate::ffmpeg_utils::index::IndexEntry;
+
+        let timebase = ffmpeg::Rational::new(1, 1000);
+        // 60s at 25fps, with a keyframe every `gop` frames.
+        let entries = |gop: usize| -> Vec<IndexEntry> {
+            (0..1500)
+                .map(|i| IndexEntry {
+                    pos: i as u64 * 1000,
+                    timestamp: i as i64 * 40,
+                    size: 1000,
+                    flags: (gop > 0 && i % gop == 0) as i32,
+                })
+                .collect()
+        };
+        assert_eq!(
+            keyframe_spacing(&entries(50), timebase),
+            KeyframeSpacing::Regular
+        );
+        assert_eq!(
+            keyframe_spacing(&entries(1), timebase),
+            KeyframeSpacing::Dense
+        );
+        assert_eq!(
+            keyframe_spacing(&entries(1500), timebase),
+            KeyframeSpacing::Sparse
+        );
+        assert_eq!(
+            keyframe_spacing(&entries(0), timebase),
+            KeyframeSpacing::Sparse
+        );
+
+        // Keyframes of the discontinuity test, with a 60s jump.
+        let entries: Vec<IndexEntry> = [0, 4000, 8000, 12000, 72000, 76000]
+            .iter()
+            .map(|&ts| IndexEntry {
+                pos: ts as u64,
+                timestamp: ts,
+                size: 1000,
+                flags: 1,
+            })
+            .collect();
+        assert_eq!(
+            keyframe_spacing(&entries, timebase),
+            KeyframeSpacing::Regular
+        );
+    }
+
+    #[test]
+    fn test_build_fixed_duration_segments() {
+        use crate::ffmpeg_utils::index::IndexEntry;
+
+        let timebase = ffmpeg::Rational::new(1, 1000);
+        // 20s at 25fps, only the first frame is a keyframe.
+        let entries: Vec<IndexEntry> = (0..500)
+            .map(|i| IndexEntry {
+                pos: i as u64 * 1000,
+                timestamp: i as i64 * 40,
+                size: 1000,
+                flags: (i == 0) as i32,
+            })
+            .collect();
+        assert_eq!(
+            build_segments_from_entries(&entries, timebase, 0, 20.0, 4.0).len(),
+            1
+        );
+
+        let segments = build_fixed_duration_segments(&entries, timebase, 20.0, 4.0);
+        assert_eq!(segments.len(), 7);
+        assert!(segments.iter().all(|s| !s.is_keyframe));
+        assert!(segments[..6]
+            .iter()
+            .all(|s| (s.duration_secs - 3.2).abs() < 0.001));
+        assert_eq!(segments[1].start_pts, 3200);
+        assert_eq!(segments[1].video_byte_offset, 80_000);
+        assert_eq!(segments[6].end_pts, 20000);
+    }
+
+    #[test]
+    fn test_escalated_duration() {
+        use crate::ffmpeg_utils::index::IndexEntry;
+
+        // 24 hours with a keyframe every second.
+        let duration = 24.0 * 3600.0;
+        let escalated = escalated_duration(duration, 4.0, DEFAULT_MAX_SEGMENTS);
+        assert_eq!(escalated, 11.0);
+        let timebase = ffmpeg::Rational::new(1, 1000);
+        let entries: Vec<IndexEntry> = (0..duration as i64)
+            .map(|i| IndexEntry {
+                pos: i as u64 * 100_000,
+                timestamp: i * 1000,
+                size: 100_000,
+                flags: 1,
+            })
+            .collect();
+        let segments = build_segments_from_entries(&entries, timebase, 0, duration, escalated);
+        assert!(segments.len() <= DEFAULT_MAX_SEGMENTS);
+
+        // Just over the limit: at least 6 seconds.
+        assert_eq!(escalated_duration(41_000.0, 4.0, DEFAULT_MAX_SEGMENTS), 6.0);
+    }
 }
@@ -10,13 +10,9 @@ pub fn analyze_subtitle_stream(
 ) -> Option<SubtitleStreamInfo> {
     let codec_id = stream.parameters().id();
 
-    // Skip bitmap subtitles (PGS, DVB) - they can't be converted to WebVTT
-    if is_bitmap_subtitle(codec_id) {
-        return None;
-    }
-
-    // Only process text-based subtitles
-    if !is_text_subtitle(codec_id) {
+    // Only process text-based subtitles, bitmap subtitles (PGS, DVB) can't
+    // be converted to WebVTT.
+    if unsupported_reason(codec_id).is_some() {
         return None;
     }
 
@@ -37,6 +33,17 @@ pub fn analyze_subtitle_stream(
     })
 }
 
+/// Why a subtitle track can't be served as WebVTT, or `None` if it can.
+pub fn unsupported_reason(codec_id: ffmpeg::codec::Id) -> Option<&'static str> {
+    if is_bitmap_subtitle(codec_id) {
+        Some("bitmap subtitles can't be converted to WebVTT")
+    } else if !is_text_subtitle(codec_id) {
+        Some("unsupported subtitle codec")
+    } else {
+        None
+    }
+}
+
 /// Extract language from stream metadata
 fn get_stream_language(stream: &ffmpeg::Stream) -> Option<String> {
     stream.metadata().get("language").map(|s| s.to_string())
@@ -97,6 +104,16 @@ mod tests {
         assert!(!is_bitmap_subtitle(ffmpeg::codec::Id::SUBRIP));
     }
 
+    #[test]
+    fn test_unsupported_reason() {
+        assert!(unsupported_reason(ffmpeg::codec::Id::SUBRIP).is_none());
+        assert!(unsupported_reason(ffmpeg::codec::Id::WEBVTT).is_none());
+        assert!(unsupported_reason(ffmpeg::codec::Id::HDMV_PGS_SUBTITLE)
+            .unwrap()
+            .starts_with("bitmap"));
+        assert!(unsupported_reason(ffmpeg::codec::Id::EIA_608).is_some());
+    }
+
     #[test]
     fn test_get_subtitle_format() {
         assert_eq!(
@@ -1,7 +1,8 @@
 //! Video stream analysis
 
 use crate::error::Result;
-use crate::media::VideoStreamInfo;
+use crate::ffmpeg_utils::index::IndexEntry;
+use crate::media::{NalFormat, VideoStats, VideoStreamInfo};
 use ffmpeg_next as ffmpeg;
 
 /// Analyze a video stream and extract metadata
@@ -19,6 +20,27 @@ pub fn analyze_video_stream(stream: &ffmpeg::Stream, index: usize) -> Result<Vid
     // Get frame rate from stream
     let framerate = stream.avg_frame_rate();
 
+    // Container profile/level fields are often missing; the SPS is authoritative.
+    let codec_string = if codec_id == ffmpeg::codec::Id::H264 {
+        let extradata = crate::ffmpeg_utils::helpers::codec_params_extradata(&params);
+        super::h264::parse_extradata(&extradata).map(|sps| sps.codec_string())
+    } else {
+        None
+    };
+
+    // The scanner checks the packets as well; this covers unindexed opens.
+    let nal_format = match codec_id {
+        ffmpeg::codec::Id::H264 | ffmpeg::codec::Id::HEVC => {
+            let extradata = crate::ffmpeg_utils::helpers::codec_params_extradata(&params);
+            if crate::segment::nal::is_annexb(&extradata) {
+                NalFormat::AnnexB
+            } else {
+                NalFormat::LengthPrefixed
+            }
+        }
+        _ => NalFormat::LengthPrefixed,
+    };
+
     Ok(VideoStreamInfo {
         stream_index: index,
         codec_id,
@@ -29,13 +51,161 @@ pub fn analyze_video_stream(stream: &ffmpeg::Stream, index: usize) -> Result<Vid
         language: get_stream_language(stream),
         profile: if profile != -99 { Some(profile) } else { None },
         level: if level != -99 { Some(level) } else { None },
+        codec_string,
+        nal_format,
+        stats: None,
+    })
+}
+
+/// Keyframe interval and bitrate statistics from the index entries of a
+/// video stream. `None` if there are fewer than two keyframes.
+pub(crate) fn compute_video_stats(
+    entries: &[IndexEntry],
+    timebase: ffmpeg::Rational,
+    has_b_frames: bool,
+) -> Option<VideoStats> {
+    let keyframes: Vec<usize> = entries
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| e.is_keyframe())
+        .map(|(i, _)| i)
+        .collect();
+    // MKV cues list only keyframes, MP4 lists every frame.
+    let every_frame = keyframes.len() < entries.len();
+    let sizes_known = every_frame && entries.iter().all(|e| e.size > 0);
+
+    // (duration in seconds, frames, bytes) of every GOP. A timestamp that
+    // goes backwards (a discontinuity) does not end a GOP.
+    let gops: Vec<(f64, usize, u64)> = keyframes
+        .windows(2)
+        .map(|w| {
+            let duration =
+                (entries[w[1]].timestamp - entries[w[0]].timestamp) as f64 * f64::from(timebase);
+            let bytes = entries[w[0]..w[1]]
+                .iter()
+                .map(|e| e.size.max(0) as u64)
+                .sum();
+            (duration, w[1] - w[0], bytes)
+        })
+        .filter(|gop| gop.0 > 0.0)
+        .collect();
+    if gops.is_empty() {
+        return None;
+    }
+    let n = gops.len() as f64;
+
+    let bitrate_variation = sizes_known.then(|| {
+        let rates: Vec<f64> = gops.iter().map(|g| g.2 as f64 * 8.0 / g.0).collect();
+        let mean = rates.iter().sum::<f64>() / n;
+        let variance = rates.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / n;
+        if mean > 0.0 {
+            variance.sqrt() / mean
+        } else {
+            0.0
+        }
+    });
+
+    Some(VideoStats {
+        min_gop_secs: gops.iter().map(|g| g.0).fold(f64::INFINITY, f64::min),
+        avg_gop_secs: gops.iter().map(|g| g.0).sum::<f64>() / n,
+        max_gop_secs: gops.iter().map(|g| g.0).fold(0.0, f64::max),
+        avg_gop_frames: every_frame.then(|| gops.iter().map(|g| g.1).sum::<usize>() as f64 / n),
+        max_gop_frames: every_frame.then(|| gops.iter().map(|g| g.1).max().unwrap_or(0)),
+        has_b_frames,
+        bitrate_variation,
     })
 }
 
+/// Maximum spread of the keyframe interval, relative to the average, for
+/// which segments are aligned to whole GOPs.
+const REGULAR_GOP_SPREAD: f64 = 0.1;
+
+/// The target segment duration, rounded to a whole number of GOPs if the
+/// keyframe interval is regular.
+///
+/// Segments are closed at the first keyframe past 80% of the target, so with
+/// 3s GOPs a 4s target gives 6s segments; aligned, it gives 3s segments.
+pub(crate) fn gop_aligned_duration(stats: &VideoStats, target_secs: f64) -> f64 {
+    let gop = stats.avg_gop_secs;
+    if gop <= 0.0 || stats.max_gop_secs - stats.min_gop_secs > gop * REGULAR_GOP_SPREAD {
+        return target_secs;
+    }
+    (target_secs / gop).round().max(1.0) * gop
+}
+
 /// Extract language from stream metadata
 fn get_stream_language(stream: &ffmpeg::Stream) -> Option<String> {
     stream.metadata().get("language").map(|s| s.to_string())
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    fn entries(frames: &[(i64, bool, i32)]) -> Vec<IndexEntry> {
+        frames
+            .iter()
+            .enumerate()
+            .map(|(i, &(timestamp, key, size))| IndexEntry {
+                pos: i as u64 * 1000,
+                timestamp,
+                size,
+                flags: key as i32,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_compute_video_stats() {
+        let timebase = ffmpeg::Rational::new(1, 4);
+
+        // Every frame, 4 frames per second, a keyframe after 2s, 1s and 3s.
+        let mut frames = Vec::new();
+        for (gop_start, frame_count) in [(0, 8), (8, 4), (12, 12)] {
+            for i in 0..frame_count {
+                frames.push((gop_start + i, i == 0, 1000));
+            }
+        }
+        frames.push((24, true, 1000));
+        let stats = compute_video_stats(&entries(&frames), timebase, true).unwrap();
+        assert_eq!(stats.min_gop_secs, 1.0);
+        assert_eq!(stats.avg_gop_secs, 2.0);
+        assert_eq!(stats.max_gop_secs, 3.0);
+        assert_eq!(stats.avg_gop_frames, Some(8.0));
+        assert_eq!(stats.max_gop_frames, Some(12));
+        assert!(stats.has_b_frames);
+        // Constant frame size and rate, so a constant bitrate.
+        assert!(stats.bitrate_variation.unwrap() < 1e-9);
+
+        // Keyframes only (MKV cues): no per-frame statistics.
+        let cues = entries(&[(0, true, 0), (8, true, 0), (16, true, 0)]);
+        let stats = compute_video_stats(&cues, timebase, false).unwrap();
+        assert_eq!(stats.avg_gop_secs, 2.0);
+        assert_eq!(stats.avg_gop_frames, None);
+        assert_eq!(stats.bitrate_variation, None);
+
+        assert_eq!(compute_video_stats(&cues[..1], timebase, false), None);
+    }
+
+    #[test]
+    fn test_gop_aligned_duration() {
+        let regular = VideoStats {
+            min_gop_secs: 3.0,
+            avg_gop_secs: 3.0,
+            max_gop_secs: 3.0,
+            ..Default::default()
+        };
+        assert_eq!(gop_aligned_duration(&regular, 4.0), 3.0);
+        assert_eq!(gop_aligned_duration(&regular, 5.0), 6.0);
+        assert_eq!(gop_aligned_duration(&regular, 1.0), 3.0);
+
+        // Irregular keyframe intervals keep the target.
+        let irregular = VideoStats {
+            min_gop_secs: 1.0,
+            avg_gop_secs: 3.0,
+            max_gop_secs: 5.0,
+            ..Default::default()
+        };
+        assert_eq!(gop_aligned_duration(&irregular, 4.0), 4.0);
+    }
+}
@@ -42,6 +42,107 @@
 //! }
 //! ```
 //!
+//! Requests can also be built without a URL, starting from
+//! `HlsParams::main_playlist()`. URLs can address source files with the
+//! extensions of `default_source_extensions()`, or of `set_source_extensions()`.
+//! Media files can also be served from memory instead of the file system, see
+//! `add_memory_source()`.
+//!
+//! ## Cargo features
+//!
+//! - `transcode` (default): audio transcoding to AAC. Without it, only passthrough
+//!   packaging is available and transcode requests fail with `HlsError::FeatureDisabled`.
+//!   The AAC encoder (native or `libfdk_aac`) is chosen with `set_aac_encoder()`.
+//! - `subtitles` (default): WebVTT subtitle playlists and segments.
+//! - `cache` (default): the in-memory segment cache and look-ahead workers.
+//!
+//! Passthrough audio runs through per-codec bitstream filters (by default
+//! `aac_adtstoasc` for AAC), configurable with `set_audio_bitstream_filters()`.
+//! TrueHD and DTS can't be carried in fMP4; they are transcoded to AAC, or left
+//! out, see `set_hd_audio()`.
+//!
+//! Failed segments are retried and can be marked as gaps, see `set_retry_policy()`.
+//! The last failed requests of a stream are kept for debugging, see
+//! `StreamIndex::recent_errors()` and `cache::stream_errors()`.
+//! Audio-only main playlists can use longer segments than the video, see
+//! `set_audio_segment_duration()`.
+//! Overlapping subtitle cues (common in ASS/SSA) are merged into non-overlapping
+//! WebVTT cues, see `set_merge_overlapping_cues()`. Whether subtitle segments
+//! carry an `X-TIMESTAMP-MAP` is set with `set_subtitle_timestamps()`, or per
+//! presentation with `MainPlaylist::subtitle_timestamps()`. Cues that cross a
+//! segment boundary are written once by default, see `set_spanning_cues()`.
+//! Files with many subtitle tracks can be read once for all of them, see
+//! `set_subtitle_batch_extraction()`.
+//! Fonts attached to MKV files are listed in `StreamIndex::attachments`, and
+//! can be fetched with `cache::stream_attachment()`. Cover art (attached picture
+//! streams in MP3, M4A and MKV files) is not treated as video; it is listed in
+//! `StreamIndex::artwork` and can be fetched with `cache::stream_artwork()`.
+//! The segment timeline is available as `StreamIndex::segments()`, with
+//! `segment_for_time()` and `time_for_segment()` to map between presentation
+//! time and segment sequence numbers, e.g. for prefetchers or thumbnails.
+//! Media segments can also be addressed by start time in milliseconds
+//! (`v/0.t120000.m4s`); `HlsVideo::open()` maps the time to the segment that
+//! starts nearest to it, so clients can build segment URLs without the playlist.
+//! Keyframe interval, GOP size, B-frame and bitrate statistics of video tracks
+//! (`VideoStreamInfo::stats`) are computed at open after `set_video_stats()`,
+//! which also aligns segments to whole GOPs.
+//! Tracks that break playback can be disabled per file with `disable_track()`;
+//! the choice is kept in a sidecar file and honored on every open.
+//!
+//! The `ftyp`/`styp` brands, `tfdt` and `trun` versions and `EXT-X-VERSION`
+//! follow a `CompatibilityProfile`, see `set_compatibility_profile()`. So
+//! does the handling of titles that start at a negative decode time, which
+//! can be overridden with `set_negative_ts_policy()`.
+//! Media playlists without `EXT-X-ENDLIST` can advertise delta updates
+//! (`EXT-X-SKIP`) after `set_playlist_delta_updates()`;
+//! `playlist_delta_update()` cuts a playlist for a request with
+//! `_HLS_skip=YES`. Variant playlists over the size set with
+//! `set_playlist_byte_budget()` list runs of segments as one segment.
+//!
+//! A media segment can be muxed with two sets of `MuxOptions` and the results
+//! compared with `PlaylistOrSegment::compare_muxers()`, to check muxer changes.
+//! `MainPlaylist::check_consistency()` cross-checks the init segment of every
+//! playlist against one of its media segments (track ids, timescale, sample
+//! defaults). `MainPlaylist::check_drift()` follows the audio against the
+//! video over every segment of a title.
+//!
+//! FFmpeg messages are logged as `tracing` events with target `ffmpeg` once
+//! `ffmpeg_log_filter()` is installed; which messages are dropped, and the rate
+//! limit per FFmpeg component, are set with `set_ffmpeg_log_config()`.
+//!
+//! The codecs and formats of the linked FFmpeg are probed and logged at
+//! `ffmpeg_init()`, see `ffmpeg_capabilities()`. Audio that this build cannot
+//! transcode is not advertised in the main playlist.
+//!
+//! Memory use of all subsystems is reported by `memory::memory_stats()` and can
+//! be capped with `SegmentCacheConfig::memory_budget_mb`.
+//! Segments that took long to generate can be kept longer than cheap ones
+//! with `SegmentCacheConfig::eviction_policy`.
+//! Streams stay on the version of the file they were opened with: after the
+//! file changes they only get cached segments, and `HlsError::SourceChanged`
+//! for the rest, while new streams index the new file.
+//! Popular titles can be generated into the segment cache while no player is
+//! waiting, see `warmer::start_warmer()`. The hot set of the cache can be
+//! exported before a restart and warmed again after it, see
+//! `warmer::export_hot_set()` and `warmer::rewarm()`.
+//!
+//! `season::season_manifest()` lists the durations, tracks and playlist URLs
+//! of all episodes in a folder, indexing them on the way.
+//!
+//! The segment duration, AAC bitrates, sample rate of transcoded audio and
+//! stream timeout are a `LibConfig`, installed with `set_lib_config()` or
+//! passed per stream to `HlsVideo::open_with_config()`.
+//!
+//! `MainPlaylist::audio_mix()` lists two audio tracks mixed into one, e.g. a
+//! commentary over the film, as a synthetic AAC track of the master playlist.
+//!
+//! `StreamIndex::open_async()` indexes a file in a background thread and
+//! reports the progress of the scan, so a server can answer `202 Accepted`
+//! while a large file is opened. `HlsVideo::from_index()` takes it from there.
+//!
+//! `HlsVideo::estimate_size()` approximates the size of a playlist or segment
+//! without generating it, for quota checks and admission decisions.
+//!
 //! If you are using an async server such as Axum, you should wrap `HlsVideo::open`
 //! and `hls_video.generate()` in calls to `tokio::task::spawn_blocking()`.
 //!
@@ -50,20 +151,75 @@ pub(crate) mod ffmpeg_utils;
 pub(crate) mod index;
 pub(crate) mod playlist;
 pub(crate) mod segment;
+#[cfg(feature = "subtitles")]
 pub(crate) mod subtitle;
+#[cfg(feature = "transcode")]
 pub(crate) mod transcode;
 
 pub mod cache;
+pub mod config;
 pub mod hlsvideo;
+#[cfg(feature = "cache")]
 pub mod lookahead;
 pub mod media;
+pub mod memory;
 pub mod params;
+pub mod season;
+pub mod source;
+#[cfg(feature = "cache")]
+pub mod warmer;
 
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
+#[cfg(any(test, feature = "bench"))]
+pub(crate) mod fixtures;
 #[cfg(test)]
 pub(crate) mod tests;
 
-pub use error::{FfmpegError, HlsError, Result};
+pub use config::{
+    lib_config, set_lib_config, AacBitrates, AudioMixConfig, DownmixConfig, DownmixMode,
+    LibConfig,
+};
+pub use error::{ErrorContext, FfmpegError, HlsError, Result};
+pub use ffmpeg_utils::bsf::{default_audio_bitstream_filters, set_audio_bitstream_filters};
+pub use ffmpeg_utils::capabilities::{capabilities as ffmpeg_capabilities, FfmpegCapabilities};
+pub use ffmpeg_utils::log::{ffmpeg_log_config, set_ffmpeg_log_config, FfmpegLogConfig};
 pub use ffmpeg_utils::version_info as ffmpeg_version_info;
 pub use ffmpeg_utils::{init as ffmpeg_init, install_log_filter as ffmpeg_log_filter};
-pub use hlsvideo::HlsVideo;
-pub use params::HlsParams;
+pub use hlsvideo::{GeneratedResponse, GenerationStats, HlsVideo};
+pub use index::audio::{set_hd_audio, HdAudio};
+pub use index::disabled::{disable_track, disabled_tracks, enable_track, DisabledTrack};
+pub use index::progress::{OpenHandle, ScanPhase, ScanStatus};
+pub use index::scanner::{set_video_stats, DEFAULT_MAX_SEGMENTS};
+pub use params::{
+    default_source_extensions, percent_encode_path, set_source_extensions, set_subtitle_timestamps,
+    HlsParams, SubtitleTimestamps,
+};
+pub use playlist::codec::HDCP_LEVELS;
+pub use playlist::delta::{delta_update as playlist_delta_update, set_playlist_delta_updates};
+pub use playlist::variant::{set_audio_segment_duration, set_playlist_byte_budget};
+pub use segment::compare::{
+    analyze as analyze_segment, BoxInfo, SegmentComparison, SegmentStructure, TrackTiming,
+};
+pub use segment::consistency::{ConsistencyCheck, InitTrack};
+pub use segment::drift::{DriftReport, SegmentDrift, DRIFT_THRESHOLD_SECS};
+pub use segment::muxer::MuxOptions;
+pub use segment::profile::{
+    set_compatibility_profile, set_negative_ts_policy, CompatibilityProfile, NegativeTsPolicy,
+};
+pub use segment::retry::{set_retry_policy, ErrorClass, RetryPolicy};
+pub use source::{add_memory_source, remove_memory_source};
+#[cfg(feature = "subtitles")]
+pub use subtitle::extractor::set_subtitle_batch_extraction;
+#[cfg(feature = "subtitles")]
+pub use subtitle::webvtt::{set_merge_overlapping_cues, set_spanning_cues, SpanningCues};
+
+#[cfg(feature = "transcode")]
+pub use transcode::encoder::{
+    available_aac_encoders, set_aac_encoder, AacEncoderConfig, AacEncoderImpl,
+};
+
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub use segment::isobmff;
@@ -6,6 +6,7 @@ use crossbeam_channel::{Receiver, Sender};
 use crate::cache::segment_cache;
 use crate::hlsvideo::PlaylistOrSegment;
 use crate::media::StreamIndex;
+use crate::params::HlsParams;
 
 /// Global sender channel for notifying the threadpool about lookahead work.
 static LOOKAHEAD_QUEUE: OnceLock<Sender<Arc<StreamIndex>>> = OnceLock::new();
@@ -53,8 +54,6 @@ pub(crate) fn notify_lookahead(stream: Arc<StreamIndex>) {
 fn worker_loop(rx: Receiver<Arc<StreamIndex>>) {
     // Wait for notifications
     for stream in rx {
-        let stream_id = stream.stream_id.clone();
-
         // Process EXACTLY ONE task from this stream's queue.
         // This allows other workers in the threadpool to pick up remaining
         // tasks for the same stream, enabling true parallel generation.
@@ -74,46 +73,71 @@ fn worker_loop(rx: Receiver<Arc<StreamIndex>>) {
             continue;
         };
 
-        let segment_key = next_params.to_string();
+        pregenerate(&stream, next_params, "look-ahead", false);
+    }
+}
 
-        // Double-checked locking for dedup (fast path).
-        if let Some(c) = segment_cache() {
-            if c.get(&stream_id, &segment_key).is_some() {
-                continue; // already cached
-            }
+/// Generate the segment `params` of `stream` into the segment cache, unless
+/// it is cached already or being generated by another thread.
+///
+/// Returns the size of the generated segment. `what` names the caller in
+/// log messages. Segments of the warmer (`warming`) are cached without
+/// counting as an access, so they don't make the stream more popular.
+pub(crate) fn pregenerate(
+    stream: &Arc<StreamIndex>,
+    params: HlsParams,
+    what: &str,
+    warming: bool,
+) -> Option<usize> {
+    let stream_id = &stream.stream_id;
+    let segment_key = params.to_string();
+
+    // Double-checked locking for dedup (fast path).
+    if let Some(c) = segment_cache() {
+        if c.contains(stream_id, &segment_key) {
+            return None; // already cached
         }
+    }
 
-        tracing::debug!(segment_key = %segment_key, "look-ahead: starting pre-generation (worker)");
+    tracing::debug!(segment_key = %segment_key, "{}: starting pre-generation (worker)", what);
 
-        // Double-checked locking for dedup (locked path).
-        if let Some(c) = segment_cache() {
-            let lock = c.acquire_generation_lock(&stream_id, &segment_key);
-            let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
-            if c.get(&stream_id, &segment_key).is_some() {
-                c.cleanup_generation_lock(&stream_id, &segment_key);
-                continue; // completed by another thread
-            }
+    // Double-checked locking for dedup (locked path).
+    if let Some(c) = segment_cache() {
+        let lock = c.acquire_generation_lock(stream_id, &segment_key);
+        let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+        if c.contains(stream_id, &segment_key) {
+            c.cleanup_generation_lock(stream_id, &segment_key);
+            return None; // completed by another thread
         }
+    }
 
-        let ps = PlaylistOrSegment {
-            hls_params: next_params,
-            index: stream.clone(),
-        };
-
-        match ps.do_generate() {
-            Ok((data, _)) => {
-                if let Some(c) = segment_cache() {
-                    c.insert(&stream_id, &segment_key, Bytes::from(data));
-                    c.cleanup_generation_lock(&stream_id, &segment_key);
+    let ps = PlaylistOrSegment {
+        hls_params: params,
+        index: stream.clone(),
+    };
+
+    let started = std::time::Instant::now();
+    match ps.do_generate(None) {
+        Ok((data, _)) => {
+            let size = data.len();
+            if let Some(c) = segment_cache() {
+                let (data, cost) = (Bytes::from(data), started.elapsed());
+                if warming {
+                    c.insert_unrequested(stream_id, &segment_key, data, cost);
+                } else {
+                    c.insert_with_cost(stream_id, &segment_key, data, cost);
                 }
-                tracing::debug!(segment_key = %segment_key, "look-ahead: completed pre-generation (worker)");
+                c.cleanup_generation_lock(stream_id, &segment_key);
             }
-            Err(e) => {
-                if let Some(c) = segment_cache() {
-                    c.cleanup_generation_lock(&stream_id, &segment_key);
-                }
-                tracing::warn!(segment_key = %segment_key, error = %e, "look-ahead: pre-generation failed (worker)");
+            tracing::debug!(segment_key = %segment_key, "{}: completed pre-generation (worker)", what);
+            Some(size)
+        }
+        Err(e) => {
+            if let Some(c) = segment_cache() {
+                c.cleanup_generation_lock(stream_id, &segment_key);
             }
+            tracing::warn!(segment_key = %segment_key, error = %e, "{}: pre-generation failed (worker)", what);
+            None
         }
     }
 }
@@ -8,7 +8,7 @@
 //!     info.video_streams.len(), info.audio_streams.len());
 //! ```
 //!
-use std::collections::VecDeque;
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
@@ -21,6 +21,11 @@ use uuid::Uuid;
 
 use crate::cache::{get_stream_by_id, STREAMS_BY_ID};
 use crate::error::{HlsError, Result};
+use crate::ffmpeg_utils::io::InputContext;
+
+/// How long a request of a new session waits for phase 2 of
+/// `StreamIndex::open_first_phase()`, before it indexes the file itself.
+const PHASE_TWO_WAIT: std::time::Duration = std::time::Duration::from_secs(120);
 
 /// `ffmpeg_next::codec::Id`
 pub use ffmpeg_next::codec::Id;
@@ -31,8 +36,8 @@ pub use ffmpeg_next::Rational;
 /// A transparent wrapper to access an FFmpeg Input context.
 /// It can either hold a freshly opened context (Owned) or a locked reference to a cached one (Shared).
 pub(crate) enum ContextGuard<'a> {
-    Owned(ffmpeg::format::context::Input),
-    Shared(MutexGuard<'a, ffmpeg::format::context::Input>),
+    Owned(InputContext),
+    Shared(MutexGuard<'a, InputContext>),
 }
 
 impl<'a> Deref for ContextGuard<'a> {
@@ -55,6 +60,36 @@ impl<'a> DerefMut for ContextGuard<'a> {
     }
 }
 
+/// Maximum number of demuxer cursors kept per `StreamIndex`.
+const MAX_DEMUX_CURSORS: usize = 8;
+
+/// Number of generation errors kept per `StreamIndex`.
+const MAX_RECENT_ERRORS: usize = 20;
+
+/// A demuxer that stopped at the end of a media segment, so that the next
+/// segment of the same tracks can continue reading instead of seeking.
+pub(crate) struct DemuxCursor {
+    /// Dedicated input context, positioned right after `pending`
+    pub input: InputContext,
+    /// Sequence number of the segment the cursor is positioned at
+    pub next_sequence: usize,
+    /// Packets that were already read but belong to segment `next_sequence`
+    pub pending: Vec<crate::segment::generator::BufferedPacket>,
+}
+
+/// How to start demuxing a media segment.
+pub(crate) enum DemuxStart {
+    /// Continue reading from the cursor left by the previous segment.
+    Continue(DemuxCursor),
+    /// Sequential access without a cursor: open a dedicated input and seek.
+    Open,
+    /// Random access: seek the shared input.
+    Seek,
+}
+
+/// Key of a demuxer cursor: the (video, audio) tracks it reads.
+pub(crate) type DemuxTracks = (Option<usize>, Option<usize>);
+
 /// Video stream information
 #[derive(Debug, Clone)]
 pub struct VideoStreamInfo {
@@ -76,6 +111,47 @@ pub struct VideoStreamInfo {
     pub profile: Option<i32>,
     /// Video encoder level if detected
     pub level: Option<i32>,
+    /// Exact RFC 6381 codec string, if it could be derived from the bitstream
+    /// (e.g. `avc1.4d401f` from the H.264 SPS)
+    pub codec_string: Option<String>,
+    /// How H.264/H.265 NAL units are framed in the source packets
+    pub nal_format: NalFormat,
+    /// Keyframe interval and bitrate statistics, if the scanner computed them
+    /// (see `set_video_stats()`)
+    pub stats: Option<VideoStats>,
+}
+
+/// Keyframe interval and bitrate statistics of a video track.
+///
+/// Derived from the demuxer index. MKV cues only list keyframes, so the
+/// per-frame statistics are only available for MP4 and similar containers.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct VideoStats {
+    /// Shortest keyframe interval in seconds
+    pub min_gop_secs: f64,
+    /// Average keyframe interval in seconds
+    pub avg_gop_secs: f64,
+    /// Longest keyframe interval in seconds
+    pub max_gop_secs: f64,
+    /// Average GOP length in frames, if the index lists every frame
+    pub avg_gop_frames: Option<f64>,
+    /// Longest GOP in frames, if the index lists every frame
+    pub max_gop_frames: Option<usize>,
+    /// Whether the track has reordered (B-)frames
+    pub has_b_frames: bool,
+    /// Standard deviation of the per-GOP bitrate relative to its mean, if the
+    /// index lists every frame with its size
+    pub bitrate_variation: Option<f64>,
+}
+
+/// Framing of H.264/H.265 NAL units in the source packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NalFormat {
+    /// Length-prefixed, as in MP4 (and what fMP4 needs)
+    #[default]
+    LengthPrefixed,
+    /// Start code prefixed (Annex B), as in MPEG-TS
+    AnnexB,
 }
 
 /// Audio stream information
@@ -93,10 +169,17 @@ pub struct AudioStreamInfo {
     pub bitrate: u64,
     /// Language code as specified in the source file metadata
     pub language: Option<String>,
-    /// Encoder delay in stream-native timebase samples (e.g. 1024 @ 48kHz for AAC).
+    /// Encoder delay in samples at `sample_rate` (e.g. 1024 for AAC, the
+    /// pre-skip for Opus).
     pub encoder_delay: i64,
     /// transcode to other codec.
     pub transcode_to: Option<ffmpeg::codec::Id>,
+    /// Audio track mixed into this one: a synthetic track of the master
+    /// playlist, transcoded to AAC (see `MainPlaylist::audio_mix()`).
+    pub mix_with: Option<usize>,
+    /// Exact RFC 6381 codec string, if it could be derived from the bitstream
+    /// (e.g. `mp4a.40.5` for HE-AAC)
+    pub codec_string: Option<String>,
 }
 
 /// A reference to a single subtitle sample in the source file.
@@ -156,26 +239,95 @@ pub enum SubtitleFormat {
     Unknown,
 }
 
+/// A track present in the source file that is left out of the playlists,
+/// because we have no way to serve it.
+#[derive(Debug, Clone)]
+pub struct ExcludedTrack {
+    /// Zero-based index of this stream in the source file
+    pub stream_index: usize,
+    /// FFmpeg codec identifier of the stream
+    pub codec_id: ffmpeg::codec::Id,
+    /// Language code as specified in the source file metadata
+    pub language: Option<String>,
+    /// Why the track can't be served
+    pub reason: &'static str,
+}
+
+/// Longer segments for a file that would have too many of them, see
+/// `LibConfig::max_segments`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SegmentEscalation {
+    /// The limit that was exceeded
+    pub max_segments: usize,
+    /// The number of segments at the configured duration
+    pub original_segments: usize,
+    /// The configured (or GOP-aligned) target duration, in seconds
+    pub original_duration_secs: f64,
+    /// The target duration that was used instead, in seconds
+    pub duration_secs: f64,
+}
+
+/// A font attached to the source file (MKV attachments), for clients that
+/// render ASS subtitles themselves.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    /// Zero-based index of the attachment stream in the source file
+    pub stream_index: usize,
+    /// File name, as stored in the source file
+    pub filename: String,
+    /// MIME type
+    pub mime_type: String,
+    /// The font file
+    pub data: bytes::Bytes,
+}
+
+/// Cover art of the source file (an attached picture stream).
+#[derive(Debug, Clone)]
+pub struct Artwork {
+    /// Zero-based index of the picture stream in the source file
+    pub stream_index: usize,
+    /// MIME type of the image
+    pub mime_type: &'static str,
+    /// The image file
+    pub data: bytes::Bytes,
+}
+
 /// Segment information.
 /// Represents a single time-bounded slice of the original file, used to generate an HLS segment.
 #[derive(Debug, Clone)]
-pub(crate) struct SegmentInfo {
+pub struct SegmentInfo {
     /// The consecutive segment sequence number starting from 0
     pub sequence: usize,
-    /// Start presentation timestamp of the segment (in the video timeline's timebase)
+    /// Start presentation timestamp of the segment (in the video timeline's timebase).
+    /// Taken from the keyframe index at scan time; the `tfdt` of every track
+    /// is derived from it, so tracks align whatever order they're generated in.
     pub start_pts: i64,
     /// End presentation timestamp of the segment
     pub end_pts: i64,
     /// Length of the segment in seconds
     pub duration_secs: f64,
     /// Whether the segment begins with a keyframe
-    #[allow(dead_code)]
     pub is_keyframe: bool,
     /// Approximate byte offset in the file corresponding to the video start point
     #[allow(dead_code)]
     pub video_byte_offset: u64,
 }
 
+/// A request of a stream that failed, see `StreamIndex::recent_errors()`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct GenerationError {
+    /// Unix time in seconds
+    pub time: u64,
+    /// The playlist or segment, relative to the session, e.g. `v/0.12.m4s`
+    pub request: String,
+    /// Segment sequence number, for media segments
+    pub sequence: Option<usize>,
+    /// Whether a retry could have helped, see `RetryPolicy`
+    pub class: crate::segment::retry::ErrorClass,
+    /// The error message, with its context for FFmpeg errors
+    pub message: String,
+}
+
 /// Stream index - metadata about a media file.
 ///
 /// This struct holds information about audio/video/subtitle tracks.
@@ -194,24 +346,54 @@ pub struct StreamIndex {
     pub audio_streams: Vec<AudioStreamInfo>,
     /// List of subtitle streams present in the media
     pub subtitle_streams: Vec<SubtitleStreamInfo>,
+    /// Tracks that were found but can't be served (e.g. bitmap subtitles)
+    pub excluded_tracks: Vec<ExcludedTrack>,
+    /// Fonts attached to the file
+    pub attachments: Vec<Attachment>,
+    /// Cover art, from attached picture streams (not listed as video)
+    pub artwork: Vec<Artwork>,
     /// Pre-calculated timeline boundaries breaking the content into HLS segments
     pub(crate) segments: Vec<SegmentInfo>,
+    /// Decode timestamp of the first packet of the timeline track (the video,
+    /// or the audio of an audio-only title), in `video_timebase`
+    pub(crate) first_dts: i64,
+    /// Set if the segments were made longer to stay under `LibConfig::max_segments`
+    pub segment_escalation: Option<SegmentEscalation>,
+    /// Sorted sequence numbers of segments that start after a timeline discontinuity
+    pub(crate) discontinuities: Vec<usize>,
     /// Instant when the index was created
     pub(crate) indexed_at: SystemTime,
+    /// Version of the source file that was indexed, see `source_version()`
+    pub(crate) source_version: Option<String>,
+    /// The library config the stream was opened with
+    pub(crate) config: Arc<crate::config::LibConfig>,
     /// Last access timestamp mapped to Unix EPOCH for cache eviction checking
     pub(crate) last_accessed: AtomicU64,
-    /// Cache of the exact first PTS for each segment sequence, to perfectly align varying track timelines over time
-    pub(crate) segment_first_pts: Arc<Vec<AtomicI64>>,
+    /// Last keep-alive (heartbeat or playlist reload) timestamp, 0 if none yet
+    pub(crate) last_keepalive: AtomicU64,
     /// Protected cache of the opened FFmpeg format context to avoid reopening the file repeatedly
-    pub(crate) cached_context: Option<Arc<std::sync::Mutex<ffmpeg::format::context::Input>>>,
+    pub(crate) cached_context: Option<Arc<std::sync::Mutex<InputContext>>>,
     /// Whether generated segments for this media should be aggressively cached and LRU bumped
     pub(crate) cache_enabled: bool,
     /// The sequence number of the last explicitly requested segment, used for seek detection
     pub(crate) last_requested_segment: AtomicI64,
     /// Queue of pending look-ahead parameters to generate for this stream
     pub(crate) lookahead_queue: std::sync::Mutex<VecDeque<crate::params::HlsParams>>,
+    /// Per-track demuxer cursors: the last generated segment sequence, and the
+    /// cursor positioned at the next one (if any)
+    pub(crate) demux_cursors: std::sync::Mutex<HashMap<DemuxTracks, (usize, Option<DemuxCursor>)>>,
+    /// Sequence numbers of segments that could not be generated (EXT-X-GAP)
+    pub(crate) gap_segments: std::sync::Mutex<BTreeSet<usize>>,
+    /// The last `MAX_RECENT_ERRORS` failed requests, oldest first
+    pub(crate) recent_errors: std::sync::Mutex<VecDeque<GenerationError>>,
+    /// Lengths of the init segments of combined init playlists, by track,
+    /// audio track and audio transcode target of the playlist
+    pub(crate) init_lengths: std::sync::Mutex<InitLengths>,
 }
 
+/// See `StreamIndex::init_lengths`.
+pub(crate) type InitLengths = HashMap<(usize, Option<usize>, Option<String>), usize>;
+
 impl std::fmt::Debug for StreamIndex {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("StreamIndex")
@@ -222,10 +404,18 @@ impl std::fmt::Debug for StreamIndex {
             .field("video_streams", &self.video_streams)
             .field("audio_streams", &self.audio_streams)
             .field("subtitle_streams", &self.subtitle_streams)
+            .field("excluded_tracks", &self.excluded_tracks)
+            .field("attachments", &self.attachments.len())
+            .field("artwork", &self.artwork.len())
             .field("segments", &self.segments)
+            .field("first_dts", &self.first_dts)
+            .field("segment_escalation", &self.segment_escalation)
+            .field("discontinuities", &self.discontinuities)
             .field("indexed_at", &self.indexed_at)
+            .field("source_version", &self.source_version)
+            .field("config", &self.config)
             .field("last_accessed", &self.last_accessed)
-            .field("segment_first_pts", &self.segment_first_pts)
+            .field("last_keepalive", &self.last_keepalive)
             .field(
                 "cached_context",
                 &if self.cached_context.is_some() {
@@ -248,10 +438,18 @@ impl Clone for StreamIndex {
             video_streams: self.video_streams.clone(),
             audio_streams: self.audio_streams.clone(),
             subtitle_streams: self.subtitle_streams.clone(),
+            excluded_tracks: self.excluded_tracks.clone(),
+            attachments: self.attachments.clone(),
+            artwork: self.artwork.clone(),
             segments: self.segments.clone(),
+            first_dts: self.first_dts,
+            segment_escalation: self.segment_escalation,
+            discontinuities: self.discontinuities.clone(),
             indexed_at: self.indexed_at,
+            source_version: self.source_version.clone(),
+            config: self.config.clone(),
             last_accessed: AtomicU64::new(self.last_accessed.load(Ordering::Relaxed)),
-            segment_first_pts: Arc::clone(&self.segment_first_pts),
+            last_keepalive: AtomicU64::new(self.last_keepalive.load(Ordering::Relaxed)),
             cached_context: self.cached_context.clone(),
             cache_enabled: self.cache_enabled,
             last_requested_segment: AtomicI64::new(
@@ -261,6 +459,26 @@ impl Clone for StreamIndex {
             // If we actually share it widely, we would wrap this in Arc. Given usage,
             // we will primarily rely on the original Arc<StreamIndex> for the global queue.
             lookahead_queue: std::sync::Mutex::new(VecDeque::new()),
+            // Cursors own their input context, a clone starts without any.
+            demux_cursors: std::sync::Mutex::new(HashMap::new()),
+            gap_segments: std::sync::Mutex::new(
+                self.gap_segments
+                    .lock()
+                    .map(|g| g.clone())
+                    .unwrap_or_default(),
+            ),
+            recent_errors: std::sync::Mutex::new(
+                self.recent_errors
+                    .lock()
+                    .map(|e| e.clone())
+                    .unwrap_or_default(),
+            ),
+            init_lengths: std::sync::Mutex::new(
+                self.init_lengths
+                    .lock()
+                    .map(|l| l.clone())
+                    .unwrap_or_default(),
+            ),
         }
     }
 }
@@ -282,21 +500,101 @@ impl StreamIndex {
             video_streams: Vec::new(),
             audio_streams: Vec::new(),
             subtitle_streams: Vec::new(),
+            excluded_tracks: Vec::new(),
+            attachments: Vec::new(),
+            artwork: Vec::new(),
             segments: Vec::new(),
+            first_dts: 0,
+            segment_escalation: None,
+            discontinuities: Vec::new(),
             indexed_at: SystemTime::now(),
+            source_version: None,
+            config: Arc::default(),
             last_accessed: AtomicU64::new(0),
-            segment_first_pts: Arc::new(Vec::new()),
+            last_keepalive: AtomicU64::new(0),
             cached_context: None,
             cache_enabled: true,
             last_requested_segment: AtomicI64::new(-1), // nothing requested yet
             lookahead_queue: std::sync::Mutex::new(VecDeque::new()),
+            demux_cursors: std::sync::Mutex::new(HashMap::new()),
+            gap_segments: std::sync::Mutex::new(BTreeSet::new()),
+            recent_errors: std::sync::Mutex::new(VecDeque::new()),
+            init_lengths: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The stream id, as used in URLs.
+    pub fn stream_id(&self) -> &str {
+        &self.stream_id
+    }
+
+    /// The attached font named `filename`.
+    pub fn attachment(&self, filename: &str) -> Option<&Attachment> {
+        self.attachments.iter().find(|a| a.filename == filename)
+    }
+
+    /// The cover art in stream `stream_index`.
+    pub fn artwork(&self, stream_index: usize) -> Option<&Artwork> {
+        self.artwork.iter().find(|a| a.stream_index == stream_index)
+    }
+
+    /// Whether segment `sequence` starts after a timeline discontinuity.
+    pub(crate) fn is_discontinuity(&self, sequence: usize) -> bool {
+        self.discontinuities.binary_search(&sequence).is_ok()
+    }
+
+    /// Whether every segment begins with a keyframe, so that it can be
+    /// decoded without the previous one (`EXT-X-INDEPENDENT-SEGMENTS`).
+    pub(crate) fn all_segments_independent(&self) -> bool {
+        self.segments.iter().all(|s| s.is_keyframe)
+    }
+
+    /// Mark segment `sequence` as a gap, see `segment::retry`.
+    pub(crate) fn mark_gap(&self, sequence: usize) {
+        if let Ok(mut gaps) = self.gap_segments.lock() {
+            gaps.insert(sequence);
+        }
+    }
+
+    /// Whether segment `sequence` failed to generate and is a gap.
+    pub(crate) fn is_gap(&self, sequence: usize) -> bool {
+        self.gap_segments
+            .lock()
+            .is_ok_and(|gaps| gaps.contains(&sequence))
+    }
+
+    /// Whether any segment is a gap.
+    pub(crate) fn has_gaps(&self) -> bool {
+        self.gap_segments.lock().is_ok_and(|gaps| !gaps.is_empty())
+    }
+
+    /// Record that `request` failed with `error`. Only the last
+    /// `MAX_RECENT_ERRORS` are kept.
+    pub(crate) fn record_error(&self, request: &str, sequence: Option<usize>, error: &HlsError) {
+        let Ok(mut errors) = self.recent_errors.lock() else {
+            return;
+        };
+        if errors.len() >= MAX_RECENT_ERRORS {
+            errors.pop_front();
         }
+        errors.push_back(GenerationError {
+            time: unix_now(),
+            request: request.to_string(),
+            sequence,
+            class: crate::segment::retry::classify(error),
+            message: error.to_string(),
+        });
     }
 
-    pub(crate) fn init_segment_first_pts(&mut self) {
-        let n = self.segments.len();
-        let v: Vec<AtomicI64> = (0..n).map(|_| AtomicI64::new(i64::MIN)).collect();
-        self.segment_first_pts = Arc::new(v);
+    /// The last failed requests of this stream, oldest first.
+    ///
+    /// This shows intermittent failures after the fact, without raising
+    /// the log level.
+    pub fn recent_errors(&self) -> Vec<GenerationError> {
+        self.recent_errors
+            .lock()
+            .map(|e| e.iter().cloned().collect())
+            .unwrap_or_default()
     }
 
     pub(crate) fn get_segment(
@@ -315,6 +613,31 @@ impl StreamIndex {
             })
     }
 
+    /// The PTS range, in `timebase`, of the audio packets that belong to `segment`.
+    ///
+    /// The video boundaries are converted to the audio stream's own timebase,
+    /// the same way for every segment: the end of one segment is exactly the
+    /// start of the next, so every audio packet is in exactly one segment.
+    /// The last segment takes everything up to the end of the file.
+    pub(crate) fn audio_packet_range(
+        &self,
+        segment: &SegmentInfo,
+        timebase: ffmpeg::Rational,
+    ) -> std::ops::Range<i64> {
+        let convert =
+            |pts| crate::ffmpeg_utils::utils::rescale_ts(pts, self.video_timebase, timebase);
+        let is_last = self
+            .segments
+            .last()
+            .is_some_and(|last| segment.end_pts >= last.end_pts);
+        let end = if is_last {
+            i64::MAX
+        } else {
+            convert(segment.end_pts)
+        };
+        convert(segment.start_pts)..end
+    }
+
     /// Retrieve a context to read the file.
     /// Returns either the locked cached context, or freshly opens the file if none is cached.
     pub(crate) fn get_context(&self) -> Result<ContextGuard<'_>> {
@@ -326,35 +649,238 @@ impl StreamIndex {
             })?;
             Ok(ContextGuard::Shared(guard))
         } else {
-            let input = ffmpeg::format::input(&self.source_path).map_err(|e| {
-                HlsError::Ffmpeg(crate::error::FfmpegError::OpenInput(e.to_string()))
-            })?;
+            let input = crate::source::open_input(&self.source_path)?;
             Ok(ContextGuard::Owned(input))
         }
     }
 
-    /// Parse a mp4/mkv/webm file.
+    /// Decide how to start demuxing segment `sequence` of `tracks`.
+    ///
+    /// Takes the cursor of `tracks` if it is positioned at `sequence`.
+    pub(crate) fn demux_start(&self, tracks: DemuxTracks, sequence: usize) -> DemuxStart {
+        let Ok(mut cursors) = self.demux_cursors.lock() else {
+            return DemuxStart::Seek;
+        };
+        match cursors.get_mut(&tracks) {
+            Some((_, cursor)) if cursor.as_ref().map(|c| c.next_sequence) == Some(sequence) => {
+                DemuxStart::Continue(cursor.take().unwrap())
+            }
+            Some((last, _)) if *last + 1 == sequence => DemuxStart::Open,
+            _ => DemuxStart::Seek,
+        }
+    }
+
+    /// Record that segment `sequence` of `tracks` was generated, together
+    /// with the cursor positioned at the next segment, if any.
+    pub(crate) fn store_demux_cursor(
+        &self,
+        tracks: DemuxTracks,
+        sequence: usize,
+        cursor: Option<DemuxCursor>,
+    ) {
+        let Ok(mut cursors) = self.demux_cursors.lock() else {
+            return;
+        };
+        if !cursors.contains_key(&tracks) && cursors.len() >= MAX_DEMUX_CURSORS {
+            // Evict the track set that has been furthest behind.
+            if let Some(oldest) = cursors.iter().min_by_key(|(_, v)| v.0).map(|(k, _)| *k) {
+                cursors.remove(&oldest);
+            }
+        }
+        cursors.insert(tracks, (sequence, cursor));
+    }
+
+    /// Approximate memory used by the index itself (segment table etc).
+    pub(crate) fn index_memory(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.segments.capacity() * std::mem::size_of::<SegmentInfo>()
+            + self.discontinuities.capacity() * std::mem::size_of::<usize>()
+            + self.video_streams.capacity() * std::mem::size_of::<VideoStreamInfo>()
+            + self.audio_streams.capacity() * std::mem::size_of::<AudioStreamInfo>()
+            + self.subtitle_streams.capacity() * std::mem::size_of::<SubtitleStreamInfo>()
+            + self.attachments.iter().map(|a| a.data.len()).sum::<usize>()
+            + self.artwork.iter().map(|a| a.data.len()).sum::<usize>()
+    }
+
+    /// Approximate memory used by the cached input context.
+    ///
+    /// Does not wait for the context if a segment is being read from it; the
+    /// size of its index tables is then unknown and not counted.
+    pub(crate) fn context_memory(&self) -> usize {
+        match &self.cached_context {
+            Some(ctx) => match ctx.try_lock() {
+                Ok(input) => crate::ffmpeg_utils::index::input_memory(&input),
+                Err(_) => 0,
+            },
+            None => 0,
+        }
+    }
+
+    /// Approximate memory used by the demuxer cursors and their read-ahead packets.
+    pub(crate) fn demux_cursor_memory(&self) -> usize {
+        let Ok(cursors) = self.demux_cursors.lock() else {
+            return 0;
+        };
+        cursors
+            .values()
+            .filter_map(|(_, cursor)| cursor.as_ref())
+            .map(|c| {
+                crate::ffmpeg_utils::index::input_memory(&c.input)
+                    + c.pending.iter().map(|p| p.packet.size()).sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// Close all demuxer cursors. Sequential access continues by seeking.
+    pub(crate) fn drop_demux_cursors(&self) {
+        if let Ok(mut cursors) = self.demux_cursors.lock() {
+            for (_, cursor) in cursors.values_mut() {
+                *cursor = None;
+            }
+        }
+    }
+
+    /// Parse a media file.
     pub fn parse(path: &Path) -> Result<StreamIndex> {
         let options = crate::index::scanner::IndexOptions {
             segment_duration_secs: 4.0,
             index_segments: false,
+            bitrate_probe_secs: 0.0,
+            video_stats: false,
+            max_segments: 0,
         };
         crate::index::scanner::scan_file_with_options(path, &options)
     }
 
     pub(crate) fn open(path: &Path, stream_id: Option<String>) -> Result<Arc<StreamIndex>> {
+        Self::open_with_config(path, stream_id, crate::config::lib_config())
+    }
+
+    /// Open `path` with library config `config`, see `HlsVideo::open_with_config()`.
+    pub(crate) fn open_with_config(
+        path: &Path,
+        stream_id: Option<String>,
+        config: Arc<crate::config::LibConfig>,
+    ) -> Result<Arc<StreamIndex>> {
+        Self::open_with_progress(path, stream_id, config, &Default::default())
+    }
+
+    /// Open `path` in a background thread, see `index::progress`.
+    ///
+    /// Returns right away with a handle that reports the progress of the
+    /// scan and gives the index when it is done. Opens of the same file
+    /// share one scan.
+    pub fn open_async(
+        path: &Path,
+        stream_id: Option<String>,
+        config: Arc<crate::config::LibConfig>,
+    ) -> crate::index::progress::OpenHandle {
+        let key = stream_id
+            .clone()
+            .unwrap_or_else(|| path.display().to_string());
+        let open_path = path.to_path_buf();
+        crate::index::progress::open_in_background(&key, move |progress| {
+            Self::open_with_progress(&open_path, stream_id, config, progress)
+        })
+    }
+
+    /// Open `path` for a new session in two phases (`LibConfig::fast_start`).
+    ///
+    /// Phase 1 reads the tracks, the duration and the bitrates, which is all
+    /// the main playlist needs, and returns that index. Phase 2 builds the
+    /// complete index in the background, under the same stream id; the
+    /// other requests of the session wait for it in `open_with_config()`.
+    pub(crate) fn open_first_phase(
+        path: &Path,
+        config: Arc<crate::config::LibConfig>,
+    ) -> Result<Arc<StreamIndex>> {
+        let options = Self::index_options(&config);
+        let stream_id = match crate::cache::stable_stream_ids() {
+            true => Some(stable_stream_id(path, &options)?),
+            false => None,
+        };
+        if let Some(media) = stream_id.as_deref().and_then(get_stream_by_id) {
+            media.touch();
+            return Ok(media);
+        }
+
+        let first_phase = crate::index::scanner::IndexOptions {
+            index_segments: false,
+            ..options.clone()
+        };
+        let mut index = crate::index::scanner::scan_file_with_options(path, &first_phase)?;
+        index.config = Arc::clone(&config);
+        if let Some(id) = stream_id {
+            index.stream_id = id;
+        }
+
+        let id = index.stream_id.clone();
+        let second_path = path.to_path_buf();
+        crate::index::progress::open_in_background(&index.stream_id, move |progress| {
+            Self::index_file(&second_path, &options, Some(id), config, progress)
+        });
+        Ok(Arc::new(index))
+    }
+
+    fn index_options(config: &crate::config::LibConfig) -> crate::index::scanner::IndexOptions {
+        crate::index::scanner::IndexOptions {
+            segment_duration_secs: config.segment_duration_secs,
+            index_segments: true,
+            video_stats: crate::index::scanner::video_stats(),
+            max_segments: config.max_segments,
+            ..Default::default()
+        }
+    }
+
+    fn open_with_progress(
+        path: &Path,
+        stream_id: Option<String>,
+        config: Arc<crate::config::LibConfig>,
+        progress: &crate::index::progress::ScanProgress,
+    ) -> Result<Arc<StreamIndex>> {
+        let options = Self::index_options(&config);
+        let stream_id = match stream_id {
+            Some(id) => Some(id),
+            None if crate::cache::stable_stream_ids() => Some(stable_stream_id(path, &options)?),
+            None => None,
+        };
+
         if let Some(id) = &stream_id {
             if let Some(media) = get_stream_by_id(id) {
+                if !media.tracks_changed() {
+                    media.touch();
+                    return Ok(media);
+                }
+                // `disable_track()` removes the streams of this process only,
+                // worker processes find out here.
+                crate::cache::remove_stream_by_id(id);
+            }
+            // Phase 2 of `open_first_phase()`. If it failed, or takes
+            // too long, index the file here.
+            let pending =
+                crate::index::progress::pending(id).and_then(|handle| handle.wait(PHASE_TWO_WAIT));
+            if let Some(Ok(media)) = pending {
                 media.touch();
                 return Ok(media);
             }
         }
 
-        let options = crate::index::scanner::IndexOptions {
-            segment_duration_secs: 4.0,
-            index_segments: true,
-        };
-        let mut index = crate::index::scanner::scan_file_with_options(path, &options)?;
+        Self::index_file(path, &options, stream_id, config, progress)
+    }
+
+    /// Scan `path` and register the stream.
+    fn index_file(
+        path: &Path,
+        options: &crate::index::scanner::IndexOptions,
+        stream_id: Option<String>,
+        config: Arc<crate::config::LibConfig>,
+        progress: &crate::index::progress::ScanProgress,
+    ) -> Result<Arc<StreamIndex>> {
+        // Before scanning, so that a change during the scan makes it stale.
+        let source_version = source_version(path).ok().map(|(_, version)| version);
+        let mut index = crate::index::scanner::scan_file_with_progress(path, options, progress)?;
+        index.source_version = source_version;
+        index.config = config;
 
         if let Some(id) = stream_id {
             index.stream_id = id;
@@ -365,10 +891,37 @@ impl StreamIndex {
         STREAMS_BY_ID
             .get_or_init(dashmap::DashMap::new)
             .insert(media.stream_id.clone(), media.clone());
+        crate::memory::enforce_budget();
 
         Ok(media)
     }
 
+    /// Whether the source file changed, or is gone, since it was indexed.
+    ///
+    /// Sessions stay pinned to the index of the version they started with:
+    /// they get their playlists and cached segments, but segments that are
+    /// not cached can't be generated anymore. New sessions index the file
+    /// again, and with stable stream ids get a new id.
+    pub(crate) fn source_changed(&self) -> bool {
+        let Some(indexed) = &self.source_version else {
+            return false;
+        };
+        match source_version(&self.source_path) {
+            Ok((_, version)) => &version != indexed,
+            Err(_) => true,
+        }
+    }
+
+    /// Whether the tracks disabled by the operator changed since the file
+    /// was indexed, see `index::disabled`.
+    pub(crate) fn tracks_changed(&self) -> bool {
+        let Some(indexed) = &self.source_version else {
+            return false;
+        };
+        let indexed = indexed.split_once(DISABLED_VERSION).map(|(_, v)| v);
+        indexed != crate::index::disabled::sidecar_version(&self.source_path).as_deref()
+    }
+
     pub fn primary_video(&self) -> Option<&VideoStreamInfo> {
         self.video_streams.first()
     }
@@ -432,20 +985,270 @@ impl StreamIndex {
         self.segments.len()
     }
 
+    /// The segments, in order. Sequence numbers are the same as in the
+    /// segment URLs.
+    pub fn segments(&self) -> &[SegmentInfo] {
+        &self.segments
+    }
+
+    /// The segment that plays at `secs` seconds into the presentation.
+    ///
+    /// Presentation time is the time a player shows: the sum of the `EXTINF`
+    /// durations of the earlier segments. Returns `None` past the end.
+    pub fn segment_for_time(&self, secs: f64) -> Option<&SegmentInfo> {
+        if secs < 0.0 {
+            return None;
+        }
+        let mut start = 0.0;
+        for segment in &self.segments {
+            start += segment.duration_secs;
+            if secs < start {
+                return Some(segment);
+            }
+        }
+        None
+    }
+
+    /// The segment that starts nearest to `secs` seconds into the
+    /// presentation, see `segment_for_time`. Times past the end map to the
+    /// last segment.
+    pub fn segment_near_time(&self, secs: f64) -> Option<&SegmentInfo> {
+        let mut start = 0.0;
+        let mut nearest = None;
+        let mut best = f64::INFINITY;
+        for segment in &self.segments {
+            let distance = (secs - start).abs();
+            if distance >= best {
+                break;
+            }
+            nearest = Some(segment);
+            best = distance;
+            start += segment.duration_secs;
+        }
+        nearest
+    }
+
+    /// The presentation time in seconds at which segment `sequence` starts,
+    /// see `segment_for_time`.
+    pub fn time_for_segment(&self, sequence: usize) -> Option<f64> {
+        let pos = self.segments.iter().position(|s| s.sequence == sequence)?;
+        Some(self.segments[..pos].iter().map(|s| s.duration_secs).sum())
+    }
+
     pub(crate) fn touch(&self) {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
+        self.last_accessed.store(unix_now(), Ordering::Relaxed);
+    }
+
+    /// Mark the player as alive, even if it does not fetch segments (paused).
+    pub(crate) fn keepalive(&self) {
+        let now = unix_now();
         self.last_accessed.store(now, Ordering::Relaxed);
+        self.last_keepalive.store(now, Ordering::Relaxed);
     }
 
     pub(crate) fn time_since_last_access(&self) -> u64 {
-        let last = self.last_accessed.load(Ordering::Relaxed);
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
-        now.saturating_sub(last)
+        unix_now().saturating_sub(self.last_accessed.load(Ordering::Relaxed))
+    }
+
+    /// Whether the stream can be dropped.
+    ///
+    /// A stream expires when it has not been accessed for `timeout_secs`.
+    /// After a keep-alive the player is assumed to be paused, and the stream
+    /// is kept for at least `paused_timeout_secs`.
+    pub(crate) fn is_expired(&self, timeout_secs: u64, paused_timeout_secs: u64) -> bool {
+        if self.time_since_last_access() <= timeout_secs {
+            return false;
+        }
+        let keepalive = self.last_keepalive.load(Ordering::Relaxed);
+        keepalive == 0 || unix_now().saturating_sub(keepalive) > paused_timeout_secs
+    }
+}
+
+/// Derive a stream id from the file and the indexing options.
+///
+/// Opening the same, unchanged file again gives the same id, so that URLs
+/// (and bookmarks) stay valid and cached segments are reused, also across
+/// restarts. A modified file gets a new id.
+pub(crate) fn stable_stream_id(
+    path: &Path,
+    options: &crate::index::scanner::IndexOptions,
+) -> Result<String> {
+    let (path, version) = source_version(path)?;
+    let mut key = format!(
+        "{}\0{}\0{}\0{}\0{}",
+        path.display(),
+        version,
+        options.segment_duration_secs,
+        options.index_segments,
+        options.bitrate_probe_secs,
+    );
+    // GOP-aligned segments differ; without them, ids stay as they were.
+    if options.video_stats {
+        key.push_str("\0video_stats");
+    }
+    // Only files over the limit are affected; with the default, ids stay
+    // as they were.
+    if options.max_segments != crate::index::scanner::DEFAULT_MAX_SEGMENTS {
+        key.push_str(&format!("\0max_segments={}", options.max_segments));
+    }
+    Ok(Uuid::new_v5(&Uuid::NAMESPACE_URL, key.as_bytes()).to_string())
+}
+
+/// Separates the version of the disabled tracks sidecar file in a source version.
+const DISABLED_VERSION: &str = "\0disabled=";
+
+/// The canonical path of a file and the version of its content: the
+/// modification time and the size, or the generation and the size of a file
+/// in memory, which has no mtime. The version of the disabled tracks
+/// sidecar file is added, if there is one.
+pub(crate) fn source_version(path: &Path) -> Result<(PathBuf, String)> {
+    if let Some((len, generation)) = crate::source::memory_source_version(path) {
+        return Ok((
+            path.to_path_buf(),
+            format!("memory.{}\0{}", generation, len),
+        ));
+    }
+    let path = path.canonicalize()?;
+    let meta = std::fs::metadata(&path)?;
+    let mtime = meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut version = format!(
+        "{}.{:09}\0{}",
+        mtime.as_secs(),
+        mtime.subsec_nanos(),
+        meta.len()
+    );
+    if let Some(disabled) = crate::index::disabled::sidecar_version(&path) {
+        version.push_str(DISABLED_VERSION);
+        version.push_str(&disabled);
+    }
+    Ok((path, version))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segment_timeline() {
+        let mut index = StreamIndex::new(PathBuf::from("/test/video.mp4"));
+        for (sequence, duration_secs) in [4.0, 2.5, 4.0].into_iter().enumerate() {
+            index.segments.push(SegmentInfo {
+                sequence,
+                start_pts: 0,
+                end_pts: 0,
+                duration_secs,
+                is_keyframe: true,
+                video_byte_offset: 0,
+            });
+        }
+
+        assert_eq!(index.segments().len(), 3);
+        assert_eq!(index.time_for_segment(0), Some(0.0));
+        assert_eq!(index.time_for_segment(2), Some(6.5));
+        assert_eq!(index.time_for_segment(3), None);
+
+        let sequence = |secs| index.segment_for_time(secs).map(|s| s.sequence);
+        assert_eq!(sequence(0.0), Some(0));
+        assert_eq!(sequence(4.0), Some(1));
+        assert_eq!(sequence(6.49), Some(1));
+        assert_eq!(sequence(10.4), Some(2));
+        assert_eq!(sequence(10.5), None);
+        assert_eq!(sequence(-1.0), None);
+
+        let nearest = |secs| index.segment_near_time(secs).map(|s| s.sequence);
+        assert_eq!(nearest(0.0), Some(0));
+        assert_eq!(nearest(1.9), Some(0));
+        assert_eq!(nearest(2.1), Some(1));
+        assert_eq!(nearest(6.0), Some(2));
+        assert_eq!(nearest(120.0), Some(2));
+        assert_eq!(nearest(-1.0), Some(0));
+    }
+
+    #[test]
+    fn test_source_changed() {
+        use std::io::Write;
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"not really a video").unwrap();
+        let mut index = StreamIndex::new(file.path().to_path_buf());
+        assert!(!index.source_changed());
+
+        index.source_version = source_version(file.path()).ok().map(|(_, v)| v);
+        assert!(!index.source_changed());
+        file.write_all(b", longer now").unwrap();
+        assert!(index.source_changed());
+
+        let path = PathBuf::from("/memory/test_source_changed.mp4");
+        crate::source::add_memory_source(&path, &b"first"[..]);
+        let mut index = StreamIndex::new(path.clone());
+        index.source_version = source_version(&path).ok().map(|(_, v)| v);
+        assert!(!index.source_changed());
+        crate::source::add_memory_source(&path, &b"first"[..]);
+        assert!(index.source_changed());
+        crate::source::remove_memory_source(&path);
+        assert!(index.source_changed());
+    }
+
+    #[test]
+    fn test_tracks_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("movie.mkv");
+        std::fs::write(&path, b"not really a video").unwrap();
+        let mut index = StreamIndex::new(path.clone());
+        index.source_version = source_version(&path).ok().map(|(_, v)| v);
+        assert!(!index.tracks_changed());
+
+        crate::index::disabled::disable_track(&path, 1, "").unwrap();
+        assert!(index.tracks_changed());
+        assert!(index.source_changed());
+
+        index.source_version = source_version(&path).ok().map(|(_, v)| v);
+        assert!(!index.tracks_changed());
+        crate::index::disabled::enable_track(&path, 1).unwrap();
+        assert!(index.tracks_changed());
+    }
+
+    #[test]
+    fn test_recent_errors() {
+        let index = StreamIndex::new(PathBuf::from("/test/video.mp4"));
+        assert!(index.recent_errors().is_empty());
+        for sequence in 0..MAX_RECENT_ERRORS + 5 {
+            let error = HlsError::Muxing(format!("segment {}", sequence));
+            index.record_error(&format!("v/0.{}.m4s", sequence), Some(sequence), &error);
+        }
+        let errors = index.recent_errors();
+        assert_eq!(errors.len(), MAX_RECENT_ERRORS);
+        assert_eq!(errors[0].sequence, Some(5));
+        assert_eq!(errors[0].request, "v/0.5.m4s");
+        assert_eq!(errors[0].class, crate::segment::retry::ErrorClass::Mux);
+        assert_eq!(errors[0].message, "Muxing error: segment 5");
+    }
+
+    #[test]
+    fn test_open_first_phase() {
+        let spec = crate::tests::fixtures::generate::FixtureSpec::h264_aac();
+        let Some(path) = crate::tests::fixtures::generate::generate(&spec) else {
+            return; // No H.264 encoder
+        };
+        let config = Arc::new(crate::config::LibConfig::default().fast_start(true));
+        let first = StreamIndex::open_first_phase(&path, Arc::clone(&config)).unwrap();
+        assert!(first.segments.is_empty());
+        assert!(!first.audio_streams.is_empty());
+
+        // The session gets the index of phase 2, under the same id.
+        let full =
+            StreamIndex::open_with_config(&path, Some(first.stream_id.clone()), config).unwrap();
+        assert_eq!(full.stream_id, first.stream_id);
+        assert!(!full.segments.is_empty());
     }
 }
@@ -0,0 +1,194 @@
+//! Memory budget accounting.
+//!
+//! The segment cache has its own size limit, but it is not the only
+//! subsystem that holds on to memory: every open stream keeps its segment
+//! table and a cached input context (with the demuxer's sample index), the
+//! demuxer cursors keep an input context of their own, and segments that are
+//! being generated buffer their packets.
+//!
+//! This module adds up the usage of all of them. If a global budget is set
+//! (see `SegmentCacheConfig::memory_budget_mb`) and usage exceeds it, memory
+//! is released in order of how cheap it is to get back:
+//!
+//! 1. demuxer cursors (the next segment seeks instead),
+//! 2. cached segments, least recently used first,
+//! 3. idle streams, least recently accessed first.
+//!
+//! In-flight segments are counted but never evicted.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::cache::{remove_stream_by_id, segment_cache, STREAMS_BY_ID};
+
+/// Global budget in bytes, 0 means unlimited.
+static BUDGET: AtomicUsize = AtomicUsize::new(0);
+/// Bytes buffered by segments that are being generated.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+/// Number of times the budget had to be enforced.
+static EVICTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Set the global memory budget in bytes (0 = unlimited).
+pub fn set_memory_budget(bytes: usize) {
+    BUDGET.store(bytes, Ordering::Relaxed);
+}
+
+/// Memory usage per subsystem, in bytes.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MemoryStats {
+    /// Configured budget (0 = unlimited)
+    pub budget_bytes: usize,
+    /// Cached segments
+    pub segment_cache_bytes: usize,
+    /// Stream indexes (segment tables, track info)
+    pub stream_index_bytes: usize,
+    /// Cached input contexts of the open streams
+    pub input_context_bytes: usize,
+    /// Demuxer cursors and their read-ahead packets
+    pub demux_cursor_bytes: usize,
+    /// Packets buffered by segments that are being generated
+    pub in_flight_bytes: usize,
+    /// Number of times memory was released to stay within the budget
+    pub evictions: u64,
+}
+
+impl MemoryStats {
+    /// Total usage over all subsystems.
+    pub fn total_bytes(&self) -> usize {
+        self.segment_cache_bytes
+            + self.stream_index_bytes
+            + self.input_context_bytes
+            + self.demux_cursor_bytes
+            + self.in_flight_bytes
+    }
+
+    fn over_budget(&self) -> usize {
+        match self.budget_bytes {
+            0 => 0,
+            budget => self.total_bytes().saturating_sub(budget),
+        }
+    }
+}
+
+/// Current memory usage of all subsystems.
+pub fn memory_stats() -> MemoryStats {
+    let mut stats = MemoryStats {
+        budget_bytes: BUDGET.load(Ordering::Relaxed),
+        segment_cache_bytes: segment_cache().map(|c| c.memory_usage()).unwrap_or(0),
+        in_flight_bytes: IN_FLIGHT.load(Ordering::Relaxed),
+        evictions: EVICTIONS.load(Ordering::Relaxed),
+        ..Default::default()
+    };
+    for entry in STREAMS_BY_ID.get_or_init(dashmap::DashMap::new).iter() {
+        let index = entry.value();
+        stats.stream_index_bytes += index.index_memory();
+        stats.input_context_bytes += index.context_memory();
+        stats.demux_cursor_bytes += index.demux_cursor_memory();
+    }
+    stats
+}
+
+/// Release memory until usage fits in the budget again.
+///
+/// Called after anything that grows usage (segment cache inserts, opening
+/// a stream). A no-op without a budget.
+pub(crate) fn enforce_budget() {
+    if BUDGET.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let mut over = memory_stats().over_budget();
+    if over == 0 {
+        return;
+    }
+    EVICTIONS.fetch_add(1, Ordering::Relaxed);
+    tracing::debug!("memory budget exceeded by {} bytes, evicting", over);
+
+    let streams: Vec<_> = STREAMS_BY_ID
+        .get_or_init(dashmap::DashMap::new)
+        .iter()
+        .map(|e| e.value().clone())
+        .collect();
+
+    // Demuxer cursors.
+    for index in &streams {
+        index.drop_demux_cursors();
+    }
+    over = memory_stats().over_budget();
+    if over == 0 {
+        return;
+    }
+
+    // Cached segments.
+    if let Some(c) = segment_cache() {
+        let freed = c.evict(over);
+        over = over.saturating_sub(freed);
+    }
+    if over == 0 {
+        return;
+    }
+
+    // Idle streams. A stream that is referenced outside of the stream table
+    // is in use by a request and is left alone.
+    let mut idle: Vec<_> = streams
+        .into_iter()
+        .filter(|index| Arc::strong_count(index) <= 2)
+        .collect();
+    idle.sort_by_key(|index| index.last_accessed.load(Ordering::Relaxed));
+    for index in idle {
+        if over == 0 {
+            break;
+        }
+        let size = index.index_memory() + index.context_memory();
+        tracing::info!(
+            "memory budget: dropping idle stream {} ({})",
+            index.stream_id,
+            index.source_path.display()
+        );
+        if remove_stream_by_id(&index.stream_id) {
+            over = over.saturating_sub(size);
+        }
+    }
+}
+
+/// Accounts the packets buffered for a segment while it is being generated.
+pub(crate) struct InFlight(usize);
+
+impl InFlight {
+    pub(crate) fn new(bytes: usize) -> Self {
+        IN_FLIGHT.fetch_add(bytes, Ordering::Relaxed);
+        InFlight(bytes)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(self.0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_over_budget() {
+        let mut stats = MemoryStats {
+            segment_cache_bytes: 600,
+            stream_index_bytes: 200,
+            input_context_bytes: 100,
+            demux_cursor_bytes: 50,
+            in_flight_bytes: 50,
+            ..Default::default()
+        };
+        assert_eq!(stats.total_bytes(), 1000);
+        assert_eq!(stats.over_budget(), 0);
+
+        stats.budget_bytes = 800;
+        assert_eq!(stats.over_budget(), 200);
+
+        stats.budget_bytes = 2000;
+        assert_eq!(stats.over_budget(), 0);
+    }
+}
@@ -2,6 +2,10 @@
 
 use std::fmt;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{OnceLock, RwLock};
+
+use serde::{Deserialize, Serialize};
 
 /// HlsParams contains a video playlist or segment decoded from a URL.
 #[derive(Debug, Clone)]
@@ -18,10 +22,14 @@ pub struct HlsParams {
 #[derive(Debug, Clone)]
 pub enum UrlType {
     MainPlaylist,
+    /// Main playlist of an audio-only presentation of one audio track.
+    AudioMainPlaylist(AudioMainPlaylist),
     Playlist(Playlist),
     VideoSegment(VideoSegment),
     AudioSegment(AudioSegment),
     VttSegment(VttSegment),
+    /// A WebVTT segment without cues, shared by all empty subtitle periods.
+    EmptyVtt,
 }
 
 // helper.
@@ -42,16 +50,428 @@ fn usize_from_str(s: &str) -> usize {
     usize::from_str(s).expect("a number")
 }
 
+// A segment id, or a start time in milliseconds: `t<ms>`.
+fn segment_id_or_time(s: &str) -> Option<(Option<usize>, Option<u64>)> {
+    match s.strip_prefix('t') {
+        Some(ms) => Some((None, Some(u64::from_str(ms).ok()?))),
+        None => Some((Some(usize::from_str(s).ok()?), None)),
+    }
+}
+
+/// Percent-encode a URL path (RFC 3986): everything but the unreserved
+/// characters, the sub-delimiters and `/`. `:` is encoded as well, so a
+/// relative URL never looks like it has a scheme.
+pub fn percent_encode_path(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' => {
+                out.push(b as char)
+            }
+            b'/' | b'@' => out.push(b as char),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Decode the percent-escapes of a URL path. `+` stays `+`, and a `%` that
+/// does not start an escape is kept. Returns `None` if the result is not UTF-8.
+fn percent_decode_path(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = |b: u8| (b as char).to_digit(16);
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(h), Some(l)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                out.push((h * 16 + l) as u8);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8(out).ok()
+}
+
+/// How WebVTT subtitle segments are timed.
+///
+/// Players disagree on `X-TIMESTAMP-MAP`: some want it in every segment and
+/// are off by the media start time without it, others apply it wrongly and
+/// show subtitles with a constant offset. In both modes the cue times are on
+/// the media timeline, the same as the `tfdt` of the video segments.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubtitleTimestamps {
+    /// Zero-based cue times, without `X-TIMESTAMP-MAP`.
+    #[default]
+    Zero,
+    /// `X-TIMESTAMP-MAP=MPEGTS:<segment start>,LOCAL:<segment start>`,
+    /// anchoring every segment to the 90 kHz media timeline.
+    Mpegts,
+}
+
+impl SubtitleTimestamps {
+    /// The name used in URLs and configuration.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubtitleTimestamps::Zero => "zero",
+            SubtitleTimestamps::Mpegts => "mpegts",
+        }
+    }
+}
+
+impl FromStr for SubtitleTimestamps {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(SubtitleTimestamps::Zero),
+            "mpegts" => Ok(SubtitleTimestamps::Mpegts),
+            _ => Err(format!("invalid subtitle timestamp mode: {}", s)),
+        }
+    }
+}
+
+/// Whether the default subtitle timestamp mode is `Mpegts`.
+static MPEGTS_TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+
+/// Set the subtitle timestamp mode for requests that do not select one.
+pub fn set_subtitle_timestamps(mode: SubtitleTimestamps) {
+    MPEGTS_TIMESTAMPS.store(mode == SubtitleTimestamps::Mpegts, Ordering::Relaxed);
+}
+
+/// The subtitle timestamp mode for requests that do not select one.
+pub fn subtitle_timestamps() -> SubtitleTimestamps {
+    if MPEGTS_TIMESTAMPS.load(Ordering::Relaxed) {
+        SubtitleTimestamps::Mpegts
+    } else {
+        SubtitleTimestamps::Zero
+    }
+}
+
+/// The AAC bitrates in kbit/s a transcode target like `aac96` can ask for.
+pub const AAC_BITRATE_RANGE_KBPS: std::ops::RangeInclusive<u64> = 32..=512;
+
+/// Split a transcode target from a URL, `aac` or `aac<kbps>`, into the codec
+/// name and the requested bitrate in bits per second.
+///
+/// Returns `None` if the bitrate is not in `AAC_BITRATE_RANGE_KBPS`. Other
+/// codecs have no bitrate, `ac3` is just `ac3`. A mix (`aac96m2`, see
+/// `split_audio_mix`) is left out.
+pub fn parse_transcode_target(target: &str) -> Option<(&str, Option<u64>)> {
+    let (target, _) = split_audio_mix(target);
+    match target.strip_prefix("aac") {
+        Some(kbps) if !kbps.is_empty() => {
+            if kbps.starts_with('0') || !kbps.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            let kbps: u64 = kbps.parse().ok()?;
+            AAC_BITRATE_RANGE_KBPS
+                .contains(&kbps)
+                .then_some(("aac", Some(kbps * 1000)))
+        }
+        _ => Some((target, None)),
+    }
+}
+
+/// The transcode target of AAC at `bitrate` bits per second, e.g. `aac96`.
+pub fn aac_transcode_target(bitrate: Option<u64>) -> String {
+    match bitrate {
+        Some(bitrate) => format!("aac{}", bitrate / 1000),
+        None => "aac".to_string(),
+    }
+}
+
+/// The transcode target of AAC at `bitrate` bits per second with audio
+/// track `mix_track` mixed in, e.g. `aac96m2`.
+pub fn audio_mix_target(bitrate: Option<u64>, mix_track: usize) -> String {
+    format!("{}m{}", aac_transcode_target(bitrate), mix_track)
+}
+
+/// Split a transcode target into the target without the mix and the audio
+/// track that is mixed in: `aac96m2` is `aac96` mixed with track 2.
+///
+/// Only AAC can have a mix. Other targets are returned as they are.
+pub fn split_audio_mix(target: &str) -> (&str, Option<usize>) {
+    if let Some((codec, track)) = target.rsplit_once('m') {
+        if codec.starts_with("aac")
+            && !track.starts_with('0')
+            && !track.is_empty()
+            && track.bytes().all(|b| b.is_ascii_digit())
+        {
+            if let Ok(track) = track.parse() {
+                return (codec, Some(track));
+            }
+        }
+    }
+    (target, None)
+}
+
+/// Whether the transcode target captured from a URL, if any, is valid.
+/// Only audio tracks of their own can be a mix, not muxed ones.
+fn valid_transcode_target(target: Option<regex::Match>, allow_mix: bool) -> bool {
+    target.is_none_or(|m| {
+        parse_transcode_target(m.as_str()).is_some()
+            && (allow_mix || split_audio_mix(m.as_str()).1.is_none())
+    })
+}
+
+/// Extensions of the source files that URLs can address, `None` until configured.
+static SOURCE_EXTENSIONS: RwLock<Option<Vec<String>>> = RwLock::new(None);
+
+/// The source file extensions accepted when nothing was configured.
+pub fn default_source_extensions() -> Vec<String> {
+    ["mp4", "m4v", "mov", "mkv", "webm", "ts"]
+        .iter()
+        .map(|e| e.to_string())
+        .collect()
+}
+
+/// Set the extensions of the source files that URLs can address, without
+/// the dot. Replaces the defaults. Any format FFmpeg can demux will do,
+/// but segments are only generated for the codecs fMP4 can carry.
+pub fn set_source_extensions(extensions: Vec<String>) {
+    *SOURCE_EXTENSIONS.write().unwrap_or_else(|e| e.into_inner()) = Some(extensions);
+}
+
+/// Whether `ext` is the extension of a source file that URLs can address.
+/// Extensions are compared case-insensitively, `MOVIE.MKV` is a `mkv` file.
+pub fn is_source_extension(ext: &str) -> bool {
+    let extensions = SOURCE_EXTENSIONS.read().unwrap_or_else(|e| e.into_inner());
+    match extensions.as_ref() {
+        Some(extensions) => extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)),
+        None => default_source_extensions()
+            .iter()
+            .any(|e| e.eq_ignore_ascii_case(ext)),
+    }
+}
+
+/// Encoding and decoding of HLS URLs.
+///
+/// Playlists and segments are generated the same way regardless of how the URLs
+/// that point to them look. An embedder that wants a different URL layout (for
+/// example Jellyfin-style paths, or signed tokens) implements this trait and
+/// installs it with [`set_url_codec`]. The built-in scheme is [`DefaultUrlCodec`].
+pub trait UrlCodec: Send + Sync {
+    /// Decode a request path. Returns `None` if it is not a valid HLS URL.
+    fn parse(&self, url: &str) -> Option<HlsParams>;
+
+    /// Encode the URL for `params`, relative to the playlist it appears in.
+    fn encode(&self, params: &HlsParams) -> String;
+
+    /// Whether `encode` gives the same URL every time for the same `params`.
+    ///
+    /// Main playlists are only cached with a codec that does: a cached
+    /// playlist would otherwise hand out URLs that have expired, like those
+    /// of [`SignedUrlCodec`].
+    fn is_stable(&self) -> bool {
+        false
+    }
+}
+
+static URL_CODEC: OnceLock<Box<dyn UrlCodec>> = OnceLock::new();
+
+/// Install a custom URL codec.
+///
+/// This should be called once at application startup, before any request is
+/// handled. Returns `false` if a codec was already installed (or already in use).
+pub fn set_url_codec(codec: impl UrlCodec + 'static) -> bool {
+    URL_CODEC.set(Box::new(codec)).is_ok()
+}
+
+/// Access the active URL codec.
+pub(crate) fn url_codec() -> &'static dyn UrlCodec {
+    URL_CODEC.get_or_init(|| Box::new(DefaultUrlCodec)).as_ref()
+}
+
+/// The built-in URL scheme.
+///
+/// - `video.mp4.as.m3u8`: main playlist
+/// - `video.mp4.audio.<track>.m3u8`: audio-only main playlist
+/// - `video.mp4/<session>/t.<track>[+<audio>][-<codec>][.hdr][.long][.<ts>].m3u8`: variant playlist
+/// - `a/<track>[-<codec>].{init.mp4,<seq>[.hdr].m4s}`: audio segment
+/// - `v/<track>[+<audio>[-<codec>]].{init.mp4,<seq>[.hdr].m4s}`: video segment
+/// - `s/<track>.<start>-<end>[.<ts>].vtt`: subtitle segment
+/// - `s/empty.vtt`: subtitle segment without cues
+///
+/// `.hdr` marks a variant playlist whose `EXT-X-MAP` is a byterange of the
+/// first media segment, and that segment with the init segment prepended.
+/// `.long` marks the audio playlist of an audio-only main playlist, which
+/// has long audio segments.
+/// `<ts>` is the subtitle timestamp mode (`zero` or `mpegts`), if not the default.
+/// Instead of `mp4`, the video can have any extension that
+/// [`is_source_extension`] accepts.
+///
+/// URLs are percent-encoded paths: the video path is encoded when a URL is
+/// written, and the whole URL is decoded when it is parsed. So the input of
+/// `parse` must be the path as it was requested, not decoded already.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultUrlCodec;
+
+impl UrlCodec for DefaultUrlCodec {
+    fn parse(&self, url: &str) -> Option<HlsParams> {
+        parse_default(url)
+    }
+
+    fn encode(&self, params: &HlsParams) -> String {
+        params.to_string()
+    }
+
+    fn is_stable(&self) -> bool {
+        true
+    }
+}
+
+impl<T: UrlCodec + ?Sized> UrlCodec for std::sync::Arc<T> {
+    fn parse(&self, url: &str) -> Option<HlsParams> {
+        (**self).parse(url)
+    }
+
+    fn encode(&self, params: &HlsParams) -> String {
+        (**self).encode(params)
+    }
+
+    fn is_stable(&self) -> bool {
+        (**self).is_stable()
+    }
+}
+
+/// Computes the signature for a signed URL.
+///
+/// `path` is the canonical request path (see [`HlsParams::request_path`]) and
+/// `expires` the expiry time in seconds since the UNIX epoch. Implemented for
+/// any `Fn(&str, u64) -> String + Send + Sync` closure.
+pub trait UrlSigner: Send + Sync {
+    fn sign(&self, path: &str, expires: u64) -> String;
+}
+
+impl<F> UrlSigner for F
+where
+    F: Fn(&str, u64) -> String + Send + Sync,
+{
+    fn sign(&self, path: &str, expires: u64) -> String {
+        self(path, expires)
+    }
+}
+
+/// A `UrlCodec` that adds an expiry time and a signature to every URL.
+///
+/// The URLs of variant playlists and segments get a `?exp=<unix time>&sig=<signature>`
+/// query string, so a CDN (or the server) can check them without any shared
+/// session state. The main playlist URL is the entry point and is left alone.
+///
+/// Parsing is delegated to the inner codec; the query string is not part of
+/// the path, so checking it is up to the caller, using [`SignedUrlCodec::verify`].
+pub struct SignedUrlCodec<C: UrlCodec = DefaultUrlCodec> {
+    inner: C,
+    signer: Box<dyn UrlSigner>,
+    ttl: std::time::Duration,
+}
+
+impl SignedUrlCodec<DefaultUrlCodec> {
+    /// Sign URLs of the default scheme, valid for `ttl` after the playlist was generated.
+    pub fn new(signer: impl UrlSigner + 'static, ttl: std::time::Duration) -> Self {
+        Self::with_codec(DefaultUrlCodec, signer, ttl)
+    }
+}
+
+impl<C: UrlCodec> SignedUrlCodec<C> {
+    /// Sign URLs generated by `inner`.
+    pub fn with_codec(
+        inner: C,
+        signer: impl UrlSigner + 'static,
+        ttl: std::time::Duration,
+    ) -> Self {
+        Self {
+            inner,
+            signer: Box::new(signer),
+            ttl,
+        }
+    }
+
+    /// The request path of `params` with an expiry time and signature.
+    ///
+    /// Unlike `encode`, this signs main playlists too: a server can hand out
+    /// signed main playlist URLs that are valid without a media root token.
+    pub fn signed_request_path(&self, params: &HlsParams) -> String {
+        let path = params.request_path();
+        let expires = unix_time() + self.ttl.as_secs();
+        let sig = self.signer.sign(&path, expires);
+        format!("{}?exp={}&sig={}", path, expires, sig)
+    }
+
+    /// Check the expiry time and signature of a request for `params`.
+    pub fn verify(&self, params: &HlsParams, expires: u64, signature: &str) -> bool {
+        if expires < unix_time() {
+            return false;
+        }
+        let expected = self.signer.sign(&params.request_path(), expires);
+        // Compare in constant time.
+        expected.len() == signature.len()
+            && expected
+                .bytes()
+                .zip(signature.bytes())
+                .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+impl<C: UrlCodec> UrlCodec for SignedUrlCodec<C> {
+    fn parse(&self, url: &str) -> Option<HlsParams> {
+        let path = url.split_once('?').map(|(p, _)| p).unwrap_or(url);
+        self.inner.parse(path)
+    }
+
+    fn encode(&self, params: &HlsParams) -> String {
+        let url = self.inner.encode(params);
+        if params.is_main_playlist() {
+            return url;
+        }
+        let expires = unix_time() + self.ttl.as_secs();
+        let sig = self.signer.sign(&params.request_path(), expires);
+        format!("{}?exp={}&sig={}", url, expires, sig)
+    }
+}
+
+// helper.
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 impl fmt::Display for HlsParams {
-    /// Generate the encoded url, relative to the playlist it's in.
+    /// Generate the encoded url (default scheme), relative to the playlist it's in.
+    ///
+    /// This is also used as the segment cache key, so it does not depend on
+    /// the installed `UrlCodec`.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &self.url_type {
-            UrlType::MainPlaylist => write!(f, "{}.as.m3u8", basename(&self.video_url)),
+            UrlType::MainPlaylist => {
+                write!(
+                    f,
+                    "{}.as.m3u8",
+                    percent_encode_path(basename(&self.video_url))
+                )
+            }
+            UrlType::AudioMainPlaylist(a) => write!(
+                f,
+                "{}.audio.{}.m3u8",
+                percent_encode_path(basename(&self.video_url)),
+                a.track_id
+            ),
             UrlType::Playlist(s) => {
                 // A playlist is included in from the main playlist, and at the same relative
                 // position in the URL as the video file / the video.as.m3u8. So, we need
                 // to prepend the videos' name, and the session id.
-                write!(f, "{}/", basename(&self.video_url))?;
+                write!(f, "{}/", percent_encode_path(basename(&self.video_url)))?;
                 if let Some(session_id) = &self.session_id {
                     write!(f, "{}/", session_id)?;
                 }
@@ -60,171 +480,335 @@ impl fmt::Display for HlsParams {
             UrlType::VideoSegment(s) => s.fmt(f),
             UrlType::AudioSegment(s) => s.fmt(f),
             UrlType::VttSegment(s) => s.fmt(f),
+            UrlType::EmptyVtt => write!(f, "s/empty.vtt"),
         }
     }
 }
 
-impl HlsParams {
-    /// Parse a HLS URL.
-    pub fn parse(url: &str) -> Option<HlsParams> {
-        // Check for video.mp4.as.m3u8.
-        if let Some(caps) = regex!(r"^(.+\.(?:mp4|mkv|webm))\.as\.m3u8$").captures(url) {
-            return Some(HlsParams {
-                url_type: UrlType::MainPlaylist,
-                session_id: None,
-                video_url: caps[1].to_string(),
-            });
+// Parse a URL using the built-in scheme.
+fn parse_default(url: &str) -> Option<HlsParams> {
+    let url = percent_decode_path(url)?;
+    let url = url.as_str();
+
+    // Check for video.mp4.as.m3u8.
+    if let Some(caps) = regex!(r"^(.+\.([^./]+))\.as\.m3u8$").captures(url) {
+        if !is_source_extension(&caps[2]) {
+            return None;
         }
+        return Some(HlsParams {
+            url_type: UrlType::MainPlaylist,
+            session_id: None,
+            video_url: caps[1].to_string(),
+        });
+    }
 
-        // Then something with a session id.
-        let caps = regex!(r"^(.+\.(?:mp4|mkv|webm))/([^/]+)/(.+)$").captures(url)?;
-        let video_url = caps[1].to_string();
-        let session_id = Some(caps[2].to_string());
-        let rest = &caps[3];
-
-        // Playlists.
-        // t.<track_id>.m3u8
-        // t.<track_id>+<audio_track_id>.m3u8
-        // t.<track_id>+<audio_track_id>-<codec>.m3u8
-        if let Some(caps) = regex!(r"^t.(\d+)(?:\+(\d+))?(?:-(.+))?.(m3u8)").captures(rest) {
-            return Some(HlsParams {
-                url_type: UrlType::Playlist(Playlist {
-                    track_id: usize_from_str(&caps[1]),
-                    audio_track_id: caps.get(2).map(|m| usize_from_str(m.as_str())),
-                    audio_transcode_to: caps.get(3).map(|m| m.as_str().to_string()),
-                }),
-                session_id,
-                video_url,
-            });
+    // Audio-only presentation: video.mp4.audio.<track_id>.m3u8
+    if let Some(caps) = regex!(r"^(.+\.([^./]+))\.audio\.(\d+)\.m3u8$").captures(url) {
+        if !is_source_extension(&caps[2]) {
+            return None;
         }
+        return Some(HlsParams {
+            url_type: UrlType::AudioMainPlaylist(AudioMainPlaylist {
+                track_id: usize_from_str(&caps[3]),
+            }),
+            session_id: None,
+            video_url: caps[1].to_string(),
+        });
+    }
 
-        // Audio URL.
-        //
-        // a/<track_id>.init.mp4
-        // a/<track_id>-<transcode_to>.init.mp4
-        //
-        // a/<track_id>.<segment_id>.m4s
-        // a/<track_id>-<transcode_to>.<segment_id>.m4s
-        if let Some(caps) =
-            regex!(r"^a/(\d+)(?:-([a-z]+))?(?:\.(\d+))?\.(m4s|init.mp4)$").captures(rest)
-        {
-            if (&caps[4] == "init.mp4" && caps.get(3).is_some())
-                || (&caps[4] == "m4s" && caps.get(3).is_none())
-            {
-                return None;
-            }
-            return Some(HlsParams {
-                url_type: UrlType::AudioSegment(AudioSegment {
-                    track_id: usize_from_str(&caps[1]),
-                    transcode_to: caps.get(2).map(|m| m.as_str().to_string()),
-                    segment_id: caps.get(3).map(|m| usize_from_str(m.as_str())),
-                }),
-                session_id,
-                video_url,
-            });
+    // Then something with a session id.
+    let caps = regex!(r"^(.+\.([^./]+))/([^/]+)/(.+)$").captures(url)?;
+    if !is_source_extension(&caps[2]) {
+        return None;
+    }
+    let video_url = caps[1].to_string();
+    let session_id = Some(caps[3].to_string());
+    let rest = &caps[4];
+
+    // Playlists.
+    // t.<track_id>.m3u8
+    // t.<track_id>+<audio_track_id>.m3u8
+    // t.<track_id>+<audio_track_id>-<codec>.m3u8
+    // t.<track_id>+<audio_track_id>-<codec>.hdr.m3u8
+    // t.<track_id>.long.m3u8
+    // t.<track_id>.<timestamps>.m3u8
+    if let Some(caps) =
+        regex!(r"^t.(\d+)(?:\+(\d+))?(?:-([^.]+))?(\.hdr)?(\.long)?(?:\.(zero|mpegts))?.(m3u8)")
+            .captures(rest)
+    {
+        if !valid_transcode_target(caps.get(3), caps.get(2).is_none()) {
+            return None;
         }
+        return Some(HlsParams {
+            url_type: UrlType::Playlist(Playlist {
+                track_id: usize_from_str(&caps[1]),
+                audio_track_id: caps.get(2).map(|m| usize_from_str(m.as_str())),
+                audio_transcode_to: caps.get(3).map(|m| m.as_str().to_string()),
+                combined_init: caps.get(4).is_some(),
+                long_segments: caps.get(5).is_some(),
+                subtitle_timestamps: caps.get(6).and_then(|m| m.as_str().parse().ok()),
+            }),
+            session_id,
+            video_url,
+        });
+    }
 
-        // Video URL.
-        //
-        // v/<track_id>.init.mp4
-        // v/<track_id>+<audio_track_id>.init.mp4
-        // v/<track_id>+<audio_track_id>-<audio_transcode_to>.init.mp4
-        //
-        // v/<track_id>.<segment_id>.m4s
-        // v/<track_id>+<audio_track_id>.<segment_id>.m4s
-        // v/<track_id>+<audio_track_id>-<audio_transcode_to>.<segment_id>.m4s
-        if let Some(caps) =
-            regex!(r"^v/(\d+)(?:\+(\d+)(?:-([a-z]+))?)?(?:\.(\d+))?\.(m4s|init.mp4)").captures(rest)
+    // Audio URL.
+    //
+    // a/<track_id>.init.mp4
+    // a/<track_id>-<transcode_to>.init.mp4
+    //
+    // a/<track_id>.<segment_id>.m4s
+    // a/<track_id>-<transcode_to>.<segment_id>.m4s
+    // a/<track_id>-<transcode_to>.<segment_id>.hdr.m4s
+    // a/<track_id>-<transcode_to>.<segment_id>-<end_segment_id>.m4s
+    // a/<track_id>-<transcode_to>.t<start_ms>.m4s
+    //
+    // <transcode_to> is a codec, AAC can have a bitrate in kbit/s (`aac96`)
+    // and an audio track that is mixed in (`aacm2`, `aac96m2`).
+    if let Some(caps) = regex!(
+        r"^a/(\d+)(?:-([a-z]+\d*|aac\d*m\d+))?(?:\.(t?\d+)(?:-(\d+))?)?(\.hdr)?\.(m4s|init.mp4)$"
+    )
+    .captures(rest)
+    {
+        if (&caps[6] == "init.mp4" && (caps.get(3).is_some() || caps.get(5).is_some()))
+            || (&caps[6] == "m4s" && caps.get(3).is_none())
+            || !valid_transcode_target(caps.get(2), true)
         {
-            if (&caps[5] == "init.mp4" && caps.get(4).is_some())
-                || (&caps[5] == "m4s" && caps.get(4).is_none())
-            {
-                return None;
-            }
-            return Some(HlsParams {
-                url_type: UrlType::VideoSegment(VideoSegment {
-                    track_id: usize_from_str(&caps[1]),
-                    audio_track_id: caps.get(2).map(|m| usize_from_str(m.as_str())),
-                    audio_transcode_to: caps
-                        .get(2)
-                        .and_then(|_| caps.get(3).map(|m| m.as_str().to_string())),
-                    segment_id: caps.get(4).map(|m| usize_from_str(m.as_str())),
-                }),
-                session_id,
-                video_url,
-            });
+            return None;
         }
+        let (segment_id, start_ms) = match caps.get(3) {
+            Some(m) => segment_id_or_time(m.as_str())?,
+            None => (None, None),
+        };
+        let end_segment_id = caps.get(4).map(|m| usize_from_str(m.as_str()));
+        if end_segment_id.is_some() && (start_ms.is_some() || end_segment_id <= segment_id) {
+            return None;
+        }
+        return Some(HlsParams {
+            url_type: UrlType::AudioSegment(AudioSegment {
+                track_id: usize_from_str(&caps[1]),
+                transcode_to: caps.get(2).map(|m| m.as_str().to_string()),
+                segment_id,
+                start_ms,
+                end_segment_id,
+                with_init: caps.get(5).is_some(),
+            }),
+            session_id,
+            video_url,
+        });
+    }
 
-        // Subtitle URL.
-        // s/<track_id>.<start_cue>.<end_cue>.vtt
-        if let Some(caps) = regex!(r"^s/(\d+)\.(\d+)-(\d+)\.vtt$").captures(rest) {
-            return Some(HlsParams {
-                url_type: UrlType::VttSegment(VttSegment {
-                    track_id: usize_from_str(&caps[1]),
-                    start_cue: usize_from_str(&caps[2]),
-                    end_cue: usize_from_str(&caps[3]),
-                }),
-                session_id,
-                video_url,
-            });
+    // Video URL.
+    //
+    // v/<track_id>.init.mp4
+    // v/<track_id>+<audio_track_id>.init.mp4
+    // v/<track_id>+<audio_track_id>-<audio_transcode_to>.init.mp4
+    //
+    // v/<track_id>.<segment_id>.m4s
+    // v/<track_id>+<audio_track_id>.<segment_id>.m4s
+    // v/<track_id>+<audio_track_id>-<audio_transcode_to>.<segment_id>.m4s
+    // v/<track_id>+<audio_track_id>-<audio_transcode_to>.<segment_id>.hdr.m4s
+    // v/<track_id>+<audio_track_id>-<audio_transcode_to>.<segment_id>-<end_segment_id>.m4s
+    // v/<track_id>+<audio_track_id>-<audio_transcode_to>.t<start_ms>.m4s
+    if let Some(caps) = regex!(
+        r"^v/(\d+)(?:\+(\d+)(?:-([a-z]+\d*))?)?(?:\.(t?\d+)(?:-(\d+))?)?(\.hdr)?\.(m4s|init.mp4)"
+    )
+    .captures(rest)
+    {
+        if (&caps[7] == "init.mp4" && (caps.get(4).is_some() || caps.get(6).is_some()))
+            || (&caps[7] == "m4s" && caps.get(4).is_none())
+            || !valid_transcode_target(caps.get(3), false)
+        {
+            return None;
+        }
+        let (segment_id, start_ms) = match caps.get(4) {
+            Some(m) => segment_id_or_time(m.as_str())?,
+            None => (None, None),
+        };
+        let end_segment_id = caps.get(5).map(|m| usize_from_str(m.as_str()));
+        if end_segment_id.is_some() && (start_ms.is_some() || end_segment_id <= segment_id) {
+            return None;
         }
+        return Some(HlsParams {
+            url_type: UrlType::VideoSegment(VideoSegment {
+                track_id: usize_from_str(&caps[1]),
+                audio_track_id: caps.get(2).map(|m| usize_from_str(m.as_str())),
+                audio_transcode_to: caps
+                    .get(2)
+                    .and_then(|_| caps.get(3).map(|m| m.as_str().to_string())),
+                segment_id,
+                start_ms,
+                end_segment_id,
+                with_init: caps.get(6).is_some(),
+            }),
+            session_id,
+            video_url,
+        });
+    }
 
-        None
+    // Empty subtitle segment.
+    if rest == "s/empty.vtt" {
+        return Some(HlsParams {
+            url_type: UrlType::EmptyVtt,
+            session_id,
+            video_url,
+        });
     }
 
-    /// Encode the HlsParams to a string.
+    // Subtitle URL.
+    // s/<track_id>.<start_cue>.<end_cue>.vtt
+    // s/<track_id>.<start_cue>.<end_cue>.<timestamps>.vtt
+    if let Some(caps) = regex!(r"^s/(\d+)\.(\d+)-(\d+)(?:\.(zero|mpegts))?\.vtt$").captures(rest) {
+        return Some(HlsParams {
+            url_type: UrlType::VttSegment(VttSegment {
+                track_id: usize_from_str(&caps[1]),
+                start_cue: usize_from_str(&caps[2]),
+                end_cue: usize_from_str(&caps[3]),
+                timestamps: caps.get(4).and_then(|m| m.as_str().parse().ok()),
+            }),
+            session_id,
+            video_url,
+        });
+    }
+
+    None
+}
+
+impl HlsParams {
+    /// Parse a HLS URL, using the installed `UrlCodec`.
+    pub fn parse(url: &str) -> Option<HlsParams> {
+        url_codec().parse(url)
+    }
+
+    /// Encode the HlsParams to a string, using the installed `UrlCodec`.
     pub fn encode_url(&self) -> String {
-        self.to_string()
+        url_codec().encode(self)
+    }
+
+    /// The full request path of this URL in the default scheme.
+    ///
+    /// Unlike `encode_url`, which is relative to the playlist the URL appears
+    /// in, this includes the video path and session id. It is what
+    /// `SignedUrlCodec` signs. The video path is percent-encoded.
+    pub fn request_path(&self) -> String {
+        let video_url = percent_encode_path(&self.video_url);
+        let prefix = match &self.session_id {
+            Some(session_id) => format!("{}/{}", video_url, session_id),
+            None => video_url.clone(),
+        };
+        match &self.url_type {
+            UrlType::MainPlaylist => format!("{}.as.m3u8", video_url),
+            UrlType::AudioMainPlaylist(a) => format!("{}.audio.{}.m3u8", video_url, a.track_id),
+            UrlType::Playlist(p) => format!("{}/{}", prefix, p),
+            UrlType::VideoSegment(s) => format!("{}/{}", prefix, s),
+            UrlType::AudioSegment(s) => format!("{}/{}", prefix, s),
+            UrlType::VttSegment(s) => format!("{}/{}", prefix, s),
+            UrlType::EmptyVtt => format!("{}/s/empty.vtt", prefix),
+        }
+    }
+
+    /// Whether this is a main playlist (the entry point of a presentation).
+    pub fn is_main_playlist(&self) -> bool {
+        matches!(
+            self.url_type,
+            UrlType::MainPlaylist | UrlType::AudioMainPlaylist(_)
+        )
+    }
+
+    /// Whether this is a playlist rather than a (media or init) segment.
+    pub fn is_playlist(&self) -> bool {
+        matches!(
+            self.url_type,
+            UrlType::MainPlaylist | UrlType::AudioMainPlaylist(_) | UrlType::Playlist(_)
+        )
     }
 
     /// Return the MIME type.
-    pub(crate) fn mime_type(&self) -> &'static str {
+    pub fn mime_type(&self) -> &'static str {
         match &self.url_type {
-            UrlType::MainPlaylist | UrlType::Playlist(_) => "application/vnd.apple.mpegurl",
+            UrlType::MainPlaylist | UrlType::AudioMainPlaylist(_) | UrlType::Playlist(_) => {
+                "application/vnd.apple.mpegurl"
+            }
             UrlType::VideoSegment(v) => {
-                if v.segment_id.is_none() {
+                if v.segment_id.is_none() && v.start_ms.is_none() {
                     "video/mp4"
                 } else {
                     "video/iso.segment"
                 }
             }
             UrlType::AudioSegment(a) => {
-                if a.segment_id.is_none() {
+                if a.segment_id.is_none() && a.start_ms.is_none() {
                     "video/mp4"
                 } else {
                     "audio/mp4"
                 }
             }
-            UrlType::VttSegment(_) => "text/vtt",
+            UrlType::VttSegment(_) | UrlType::EmptyVtt => "text/vtt",
         }
     }
 
     /// Return cache-control header hint.
-    pub(crate) fn cache_control(&self) -> &'static str {
+    pub fn cache_control(&self) -> &'static str {
         match &self.url_type {
-            UrlType::MainPlaylist | UrlType::Playlist(_) => "no-cache",
+            UrlType::MainPlaylist | UrlType::AudioMainPlaylist(_) | UrlType::Playlist(_) => {
+                "no-cache"
+            }
             _ => "max-age=3600",
         }
     }
 
+    /// Turn a time-addressed segment (`v/0.t120000.m4s`) into the segment
+    /// that starts nearest to that time in `index`.
+    ///
+    /// With time addressing, clients can build segment URLs without the
+    /// playlist. Returns `false` if the stream has no segments.
+    pub fn resolve_start_time(&mut self, index: &crate::media::StreamIndex) -> bool {
+        let (start_ms, segment_id) = match &mut self.url_type {
+            UrlType::VideoSegment(v) => (&mut v.start_ms, &mut v.segment_id),
+            UrlType::AudioSegment(a) => (&mut a.start_ms, &mut a.segment_id),
+            _ => return true,
+        };
+        let Some(ms) = start_ms.take() else {
+            return true;
+        };
+        match index.segment_near_time(ms as f64 / 1000.0) {
+            Some(segment) => {
+                *segment_id = Some(segment.sequence);
+                true
+            }
+            None => false,
+        }
+    }
+
     /// Create a new `HlsParams` for the next segment (segment_id + offset).
     ///
     /// Returns `None` for init segments, playlists, subtitles, or if no segment_id.
     pub fn with_segment_offset(&self, offset: usize) -> Option<HlsParams> {
         let new_url_type = match &self.url_type {
+            // The grouping of long video segments depends on the playlist.
+            UrlType::VideoSegment(v) if v.end_segment_id.is_some() => None,
             UrlType::VideoSegment(v) => v.segment_id.map(|id| {
                 UrlType::VideoSegment(VideoSegment {
                     track_id: v.track_id,
                     audio_track_id: v.audio_track_id,
                     audio_transcode_to: v.audio_transcode_to.clone(),
                     segment_id: Some(id + offset),
+                    start_ms: None,
+                    end_segment_id: None,
+                    with_init: false,
                 })
             }),
+            // Long audio segments are looked up in the audio playlist's grouping.
+            UrlType::AudioSegment(a) if a.end_segment_id.is_some() => None,
             UrlType::AudioSegment(a) => a.segment_id.map(|id| {
                 UrlType::AudioSegment(AudioSegment {
                     track_id: a.track_id,
                     transcode_to: a.transcode_to.clone(),
                     segment_id: Some(id + offset),
+                    start_ms: None,
+                    end_segment_id: None,
+                    with_init: false,
                 })
             }),
             _ => None,
@@ -238,6 +822,218 @@ impl HlsParams {
     }
 }
 
+/// Building requests without going through a URL.
+///
+/// Start with the main playlist of a video, add the session id, and derive
+/// the playlists and segments of that session from it:
+///
+/// ```ignore
+/// use hls_vod_lib::{HlsParams, SubtitleTimestamps};
+///
+/// let main = HlsParams::main_playlist("movies/movie.mkv");
+/// let session = main.session("abc");
+/// let video = session.video_segment(0, 3).interleave(1, Some("aac"));
+/// assert_eq!(video.request_path(), "movies/movie.mkv/abc/v/0+1-aac.3.m4s");
+/// let subs = session
+///     .subtitle_segment(2, 4..=7)
+///     .timestamps(SubtitleTimestamps::Mpegts);
+/// assert_eq!(subs.request_path(), "movies/movie.mkv/abc/s/2.4-7.mpegts.vtt");
+/// ```
+///
+/// The modifiers (`interleave`, `with_init`, `until`, `timestamps`) are
+/// ignored for requests they do not apply to.
+impl HlsParams {
+    /// The main playlist of `video_url`.
+    pub fn main_playlist(video_url: impl Into<String>) -> HlsParams {
+        HlsParams {
+            url_type: UrlType::MainPlaylist,
+            session_id: None,
+            video_url: video_url.into(),
+        }
+    }
+
+    /// The audio-only main playlist of audio track `track_id` of `video_url`.
+    pub fn audio_main_playlist(video_url: impl Into<String>, track_id: usize) -> HlsParams {
+        HlsParams {
+            url_type: UrlType::AudioMainPlaylist(AudioMainPlaylist { track_id }),
+            session_id: None,
+            video_url: video_url.into(),
+        }
+    }
+
+    /// The same request, in session `session_id`.
+    pub fn session(&self, session_id: impl Into<String>) -> HlsParams {
+        HlsParams {
+            url_type: self.url_type.clone(),
+            session_id: Some(session_id.into()),
+            video_url: self.video_url.clone(),
+        }
+    }
+
+    // A request for the same video and session.
+    fn derive(&self, url_type: UrlType) -> HlsParams {
+        HlsParams {
+            url_type,
+            session_id: self.session_id.clone(),
+            video_url: self.video_url.clone(),
+        }
+    }
+
+    /// The playlist of track `track_id`.
+    pub fn playlist(&self, track_id: usize) -> HlsParams {
+        self.derive(UrlType::Playlist(Playlist {
+            track_id,
+            audio_track_id: None,
+            audio_transcode_to: None,
+            combined_init: false,
+            long_segments: false,
+            subtitle_timestamps: None,
+        }))
+    }
+
+    /// The init segment of video track `track_id`.
+    pub fn video_init(&self, track_id: usize) -> HlsParams {
+        self.derive(UrlType::VideoSegment(VideoSegment {
+            track_id,
+            audio_track_id: None,
+            audio_transcode_to: None,
+            segment_id: None,
+            start_ms: None,
+            end_segment_id: None,
+            with_init: false,
+        }))
+    }
+
+    /// Media segment `segment_id` of video track `track_id`.
+    pub fn video_segment(&self, track_id: usize, segment_id: usize) -> HlsParams {
+        self.derive(UrlType::VideoSegment(VideoSegment {
+            track_id,
+            audio_track_id: None,
+            audio_transcode_to: None,
+            segment_id: Some(segment_id),
+            start_ms: None,
+            end_segment_id: None,
+            with_init: false,
+        }))
+    }
+
+    /// The init segment of audio track `track_id`, transcoded to
+    /// `transcode_to` (e.g. `"aac"`) if set.
+    pub fn audio_init(&self, track_id: usize, transcode_to: Option<&str>) -> HlsParams {
+        self.derive(UrlType::AudioSegment(AudioSegment {
+            track_id,
+            transcode_to: transcode_to.map(|t| t.to_string()),
+            segment_id: None,
+            start_ms: None,
+            end_segment_id: None,
+            with_init: false,
+        }))
+    }
+
+    /// Media segment `segment_id` of audio track `track_id`, transcoded to
+    /// `transcode_to` (e.g. `"aac"`) if set.
+    pub fn audio_segment(
+        &self,
+        track_id: usize,
+        segment_id: usize,
+        transcode_to: Option<&str>,
+    ) -> HlsParams {
+        self.derive(UrlType::AudioSegment(AudioSegment {
+            track_id,
+            transcode_to: transcode_to.map(|t| t.to_string()),
+            segment_id: Some(segment_id),
+            start_ms: None,
+            end_segment_id: None,
+            with_init: false,
+        }))
+    }
+
+    /// The cues of subtitle track `track_id` in segments `segments`.
+    pub fn subtitle_segment(
+        &self,
+        track_id: usize,
+        segments: std::ops::RangeInclusive<usize>,
+    ) -> HlsParams {
+        self.derive(UrlType::VttSegment(VttSegment {
+            track_id,
+            start_cue: *segments.start(),
+            end_cue: *segments.end(),
+            timestamps: None,
+        }))
+    }
+
+    /// The subtitle segment without cues.
+    pub fn empty_subtitle_segment(&self) -> HlsParams {
+        self.derive(UrlType::EmptyVtt)
+    }
+
+    /// Interleave audio track `audio_track_id` with a video playlist or
+    /// segment, transcoded to `transcode_to` if set.
+    pub fn interleave(mut self, audio_track_id: usize, transcode_to: Option<&str>) -> HlsParams {
+        let transcode_to = transcode_to.map(|t| t.to_string());
+        match &mut self.url_type {
+            UrlType::Playlist(p) => {
+                p.audio_track_id = Some(audio_track_id);
+                p.audio_transcode_to = transcode_to;
+            }
+            UrlType::VideoSegment(v) => {
+                v.audio_track_id = Some(audio_track_id);
+                v.audio_transcode_to = transcode_to;
+            }
+            _ => {}
+        }
+        self
+    }
+
+    /// Prepend the init segment to a media segment, or, for a playlist,
+    /// serve the init segment as a byterange of the first media segment.
+    pub fn with_init(mut self) -> HlsParams {
+        match &mut self.url_type {
+            UrlType::Playlist(p) => p.combined_init = true,
+            UrlType::VideoSegment(v) if v.segment_id.is_some() => v.with_init = true,
+            UrlType::AudioSegment(a) if a.segment_id.is_some() => a.with_init = true,
+            _ => {}
+        }
+        self
+    }
+
+    /// Extend an audio or video segment up to and including segment
+    /// `end_segment_id`.
+    ///
+    /// Returns `None` if `end_segment_id` is not after the first segment.
+    pub fn until(mut self, end_segment_id: usize) -> Option<HlsParams> {
+        let (segment_id, end) = match &mut self.url_type {
+            UrlType::VideoSegment(v) => (v.segment_id, &mut v.end_segment_id),
+            UrlType::AudioSegment(a) => (a.segment_id, &mut a.end_segment_id),
+            _ => return Some(self),
+        };
+        if let Some(segment_id) = segment_id {
+            if end_segment_id <= segment_id {
+                return None;
+            }
+            *end = Some(end_segment_id);
+        }
+        Some(self)
+    }
+
+    /// Set the timestamp mode of a subtitle playlist or segment.
+    pub fn timestamps(mut self, mode: SubtitleTimestamps) -> HlsParams {
+        match &mut self.url_type {
+            UrlType::Playlist(p) => p.subtitle_timestamps = Some(mode),
+            UrlType::VttSegment(s) => s.timestamps = Some(mode),
+            _ => {}
+        }
+        self
+    }
+}
+
+/// Audio-only main playlist.
+#[derive(Debug, Clone)]
+pub struct AudioMainPlaylist {
+    /// Audio track id.
+    pub track_id: usize,
+}
+
 /// A video segment.
 #[derive(Debug, Clone)]
 pub struct VideoSegment {
@@ -249,6 +1045,14 @@ pub struct VideoSegment {
     pub audio_transcode_to: Option<String>,
     /// Segment id. If None, this is the init segment.
     pub segment_id: Option<usize>,
+    /// Start time in milliseconds of a time-addressed segment, see
+    /// `HlsParams::resolve_start_time`.
+    pub start_ms: Option<u64>,
+    /// Last segment id of a long video segment that spans several segments,
+    /// see `set_playlist_byte_budget()`.
+    pub end_segment_id: Option<usize>,
+    /// Prepend the init segment (the first segment of a `.hdr` playlist).
+    pub with_init: bool,
 }
 
 impl fmt::Display for VideoSegment {
@@ -260,8 +1064,21 @@ impl fmt::Display for VideoSegment {
                 write!(f, "-{}", audio_transcode_to)?;
             }
         }
-        if let Some(segment_id) = self.segment_id {
-            write!(f, ".{}.m4s", segment_id)?;
+        if let Some(start_ms) = self.start_ms {
+            write!(f, ".t{}", start_ms)?;
+            if self.with_init {
+                write!(f, ".hdr")?;
+            }
+            write!(f, ".m4s")?;
+        } else if let Some(segment_id) = self.segment_id {
+            write!(f, ".{}", segment_id)?;
+            if let Some(end_segment_id) = self.end_segment_id {
+                write!(f, "-{}", end_segment_id)?;
+            }
+            if self.with_init {
+                write!(f, ".hdr")?;
+            }
+            write!(f, ".m4s")?;
         } else {
             write!(f, ".init.mp4")?;
         }
@@ -278,6 +1095,13 @@ pub struct AudioSegment {
     pub transcode_to: Option<String>,
     /// Segment id. If None, this is the init segment.
     pub segment_id: Option<usize>,
+    /// Start time in milliseconds of a time-addressed segment, see
+    /// `HlsParams::resolve_start_time`.
+    pub start_ms: Option<u64>,
+    /// Last segment id of a long audio segment that spans several segments.
+    pub end_segment_id: Option<usize>,
+    /// Prepend the init segment (the first segment of a `.hdr` playlist).
+    pub with_init: bool,
 }
 
 impl fmt::Display for AudioSegment {
@@ -286,8 +1110,21 @@ impl fmt::Display for AudioSegment {
         if let Some(transcode_to) = &self.transcode_to {
             write!(f, "-{}", transcode_to)?;
         }
-        if let Some(segment_id) = self.segment_id {
-            write!(f, ".{}.m4s", segment_id)?;
+        if let Some(start_ms) = self.start_ms {
+            write!(f, ".t{}", start_ms)?;
+            if self.with_init {
+                write!(f, ".hdr")?;
+            }
+            write!(f, ".m4s")?;
+        } else if let Some(segment_id) = self.segment_id {
+            write!(f, ".{}", segment_id)?;
+            if let Some(end_segment_id) = self.end_segment_id {
+                write!(f, "-{}", end_segment_id)?;
+            }
+            if self.with_init {
+                write!(f, ".hdr")?;
+            }
+            write!(f, ".m4s")?;
         } else {
             write!(f, ".init.mp4")?;
         }
@@ -304,16 +1141,18 @@ pub struct VttSegment {
     pub start_cue: usize,
     ///
     pub end_cue: usize,
+    /// Timestamp mode. If None, the configured default.
+    pub timestamps: Option<SubtitleTimestamps>,
 }
 
 /// A vtt segment (subtitles).
 impl fmt::Display for VttSegment {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "s/{}.{}-{}.vtt",
-            self.track_id, self.start_cue, self.end_cue
-        )
+        write!(f, "s/{}.{}-{}", self.track_id, self.start_cue, self.end_cue)?;
+        if let Some(timestamps) = self.timestamps {
+            write!(f, ".{}", timestamps.as_str())?;
+        }
+        write!(f, ".vtt")
     }
 }
 
@@ -326,6 +1165,12 @@ pub struct Playlist {
     pub audio_track_id: Option<usize>,
     /// Transcode audio.
     pub audio_transcode_to: Option<String>,
+    /// Serve the init segment as a byterange of the first media segment.
+    pub combined_init: bool,
+    /// Long audio segments, see `set_audio_segment_duration()`.
+    pub long_segments: bool,
+    /// Timestamp mode of the segments of a subtitle playlist.
+    pub subtitle_timestamps: Option<SubtitleTimestamps>,
 }
 
 impl fmt::Display for Playlist {
@@ -337,6 +1182,361 @@ impl fmt::Display for Playlist {
         if let Some(audio_transcode_to) = &self.audio_transcode_to {
             write!(f, "-{}", audio_transcode_to)?;
         }
+        if self.combined_init {
+            write!(f, ".hdr")?;
+        }
+        if self.long_segments {
+            write!(f, ".long")?;
+        }
+        if let Some(timestamps) = self.subtitle_timestamps {
+            write!(f, ".{}", timestamps.as_str())?;
+        }
         write!(f, ".m3u8")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_codec_roundtrip() {
+        let codec = DefaultUrlCodec;
+        for url in [
+            "movie.mkv.audio.2.m3u8",
+            "movie.mkv/abc/t.0+1-aac.m3u8",
+            "movie.mkv/abc/t.0+1-aac.hdr.m3u8",
+            "movie.mkv/abc/t.1.hdr.m3u8",
+            "movie.mkv/abc/t.1-aac.hdr.long.m3u8",
+            "movie.mkv/abc/v/0+1-aac.3.m4s",
+            "movie.mkv/abc/v/0+1-aac.0.hdr.m4s",
+            "movie.mkv/abc/a/1-aac.0.hdr.m4s",
+            "movie.mkv/abc/a/1.init.mp4",
+            "movie.mkv/abc/s/2.4-7.vtt",
+            "movie.mkv/abc/t.2.mpegts.m3u8",
+            "movie.mkv/abc/s/2.4-7.mpegts.vtt",
+            "movie.mkv/abc/s/2.4-7.zero.vtt",
+            "movie.mkv/abc/s/empty.vtt",
+            "movie.mkv/abc/a/1.3-5.m4s",
+            "movie.mkv/abc/a/1-aac.0-2.hdr.m4s",
+        ] {
+            let params = codec.parse(url).expect("valid url");
+            let encoded = codec.encode(&params);
+            assert!(url.ends_with(&encoded), "{} vs {}", url, encoded);
+        }
+        assert!(codec.parse("movie.avi/abc/t.0.m3u8").is_none());
+        assert!(codec.parse("movie.mkv/abc/a/1.hdr.init.mp4").is_none());
+        assert!(codec.parse("movie.mkv/abc/v/0.hdr.init.mp4").is_none());
+        assert!(codec.parse("movie.mkv/abc/s/2.4-7.ts.vtt").is_none());
+        assert!(codec.parse("movie.mkv/abc/a/1.5-3.m4s").is_none());
+        assert!(codec.parse("movie.mkv/abc/a/1.5-5.m4s").is_none());
+    }
+
+    #[test]
+    fn test_long_audio_segment_url() {
+        let params = parse_default("movie.mkv/abc/a/1-aac.3-5.m4s").unwrap();
+        match &params.url_type {
+            UrlType::AudioSegment(a) => {
+                assert_eq!(a.segment_id, Some(3));
+                assert_eq!(a.end_segment_id, Some(5));
+                assert_eq!(a.transcode_to.as_deref(), Some("aac"));
+            }
+            _ => panic!("not an audio segment"),
+        }
+        assert!(params.with_segment_offset(1).is_none());
+    }
+
+    #[test]
+    fn test_long_video_segment_url() {
+        let url = "movie.mkv/abc/v/0+1-aac.3-5.hdr.m4s";
+        let params = parse_default(url).unwrap();
+        match &params.url_type {
+            UrlType::VideoSegment(v) => {
+                assert_eq!(v.segment_id, Some(3));
+                assert_eq!(v.end_segment_id, Some(5));
+                assert!(v.with_init);
+            }
+            _ => panic!("not a video segment"),
+        }
+        assert!(url.ends_with(&params.to_string()));
+        assert!(params.with_segment_offset(1).is_none());
+        assert!(parse_default("movie.mkv/abc/v/0.5-5.m4s").is_none());
+        assert!(parse_default("movie.mkv/abc/v/0.t4000-5.m4s").is_none());
+        assert!(parse_default("movie.mkv/abc/v/0-3.init.mp4").is_none());
+    }
+
+    #[test]
+    fn test_time_addressed_segments() {
+        for url in [
+            "movie.mkv/abc/v/0.t120000.m4s",
+            "movie.mkv/abc/v/0+1-aac.t4000.hdr.m4s",
+            "movie.mkv/abc/a/1-aac.t0.m4s",
+        ] {
+            assert!(url.ends_with(&parse_default(url).unwrap().to_string()));
+        }
+        assert!(parse_default("movie.mkv/abc/a/1.t4000-5.m4s").is_none());
+        assert!(parse_default("movie.mkv/abc/v/0.t.m4s").is_none());
+
+        let mut index = crate::media::StreamIndex::new("/test/movie.mkv".into());
+        for sequence in 0..40 {
+            index.segments.push(crate::media::SegmentInfo {
+                sequence,
+                start_pts: 0,
+                end_pts: 0,
+                duration_secs: 4.0,
+                is_keyframe: true,
+                video_byte_offset: 0,
+            });
+        }
+        let mut params = parse_default("movie.mkv/abc/v/0.t121000.m4s").unwrap();
+        assert_eq!(params.mime_type(), "video/iso.segment");
+        assert!(params.resolve_start_time(&index));
+        assert_eq!(params.to_string(), "v/0.30.m4s");
+
+        let mut params = parse_default("movie.mkv/abc/a/1.t0.m4s").unwrap();
+        assert!(!params.resolve_start_time(&crate::media::StreamIndex::new("/x.mkv".into())));
+    }
+
+    #[test]
+    fn test_subtitle_timestamps_url() {
+        let params = parse_default("movie.mkv/abc/t.2.mpegts.m3u8").unwrap();
+        match params.url_type {
+            UrlType::Playlist(p) => {
+                assert_eq!(p.track_id, 2);
+                assert_eq!(p.subtitle_timestamps, Some(SubtitleTimestamps::Mpegts));
+            }
+            _ => panic!("not a playlist"),
+        }
+        let params = parse_default("movie.mkv/abc/s/2.4-7.vtt").unwrap();
+        match params.url_type {
+            UrlType::VttSegment(s) => assert_eq!(s.timestamps, None),
+            _ => panic!("not a subtitle segment"),
+        }
+    }
+
+    #[test]
+    fn test_aac_bitrate_url() {
+        for url in [
+            "movie.mkv/abc/t.1-aac96.m3u8",
+            "movie.mkv/abc/t.0+1-aac192.m3u8",
+            "movie.mkv/abc/a/1-aac96.init.mp4",
+            "movie.mkv/abc/a/1-aac96.3-5.m4s",
+            "movie.mkv/abc/v/0+1-aac64.3.hdr.m4s",
+        ] {
+            let params = parse_default(url).unwrap_or_else(|| panic!("{} did not parse", url));
+            assert_eq!(params.request_path(), url);
+        }
+        match parse_default("movie.mkv/abc/a/1-aac96.3.m4s")
+            .unwrap()
+            .url_type
+        {
+            UrlType::AudioSegment(a) => assert_eq!(a.transcode_to.as_deref(), Some("aac96")),
+            _ => panic!("not an audio segment"),
+        }
+        for url in [
+            "movie.mkv/abc/t.1-aac8.m3u8",
+            "movie.mkv/abc/a/1-aac1024.3.m4s",
+            "movie.mkv/abc/v/0+1-aac096.3.m4s",
+        ] {
+            assert!(parse_default(url).is_none(), "{} parsed", url);
+        }
+
+        assert_eq!(parse_transcode_target("aac"), Some(("aac", None)));
+        assert_eq!(parse_transcode_target("aac96"), Some(("aac", Some(96_000))));
+        assert_eq!(parse_transcode_target("ac3"), Some(("ac3", None)));
+        assert_eq!(parse_transcode_target("aac31"), None);
+        assert_eq!(aac_transcode_target(Some(96_000)), "aac96");
+        assert_eq!(aac_transcode_target(None), "aac");
+    }
+
+    #[test]
+    fn test_audio_mix_url() {
+        for url in [
+            "movie.mkv/abc/t.1-aacm2.m3u8",
+            "movie.mkv/abc/a/1-aacm2.init.mp4",
+            "movie.mkv/abc/a/1-aac96m2.3.m4s",
+        ] {
+            let params = parse_default(url).unwrap_or_else(|| panic!("{} did not parse", url));
+            assert_eq!(params.request_path(), url);
+        }
+        for url in [
+            "movie.mkv/abc/t.0+1-aacm2.m3u8",
+            "movie.mkv/abc/v/0+1-aacm2.3.m4s",
+            "movie.mkv/abc/a/1-aac8m2.3.m4s",
+            "movie.mkv/abc/a/1-ac3m2.3.m4s",
+        ] {
+            assert!(parse_default(url).is_none(), "{} parsed", url);
+        }
+
+        assert_eq!(split_audio_mix("aac96m2"), ("aac96", Some(2)));
+        assert_eq!(split_audio_mix("aacm12"), ("aac", Some(12)));
+        assert_eq!(split_audio_mix("aac"), ("aac", None));
+        assert_eq!(split_audio_mix("mp3"), ("mp3", None));
+        assert_eq!(split_audio_mix("ac3m2"), ("ac3m2", None));
+        assert_eq!(
+            parse_transcode_target("aac96m2"),
+            Some(("aac", Some(96_000)))
+        );
+        assert_eq!(audio_mix_target(Some(96_000), 2), "aac96m2");
+        assert_eq!(audio_mix_target(None, 2), "aacm2");
+    }
+
+    #[test]
+    fn test_source_extensions() {
+        for url in [
+            "movie.mov.as.m3u8",
+            "movie.m4v.audio.1.m3u8",
+            "recordings/show.2024.ts/abc/v/0.3.m4s",
+        ] {
+            let params = parse_default(url).unwrap_or_else(|| panic!("{} did not parse", url));
+            assert_eq!(params.request_path(), url);
+        }
+        assert_eq!(
+            parse_default("show.2024.ts/abc/v/0.3.m4s")
+                .unwrap()
+                .video_url,
+            "show.2024.ts"
+        );
+        assert!(parse_default("movie.avi.as.m3u8").is_none());
+        assert!(parse_default("movie.avi/abc/v/0.3.m4s").is_none());
+        assert!(parse_default("movie.as.m3u8").is_none());
+    }
+
+    #[test]
+    fn test_percent_encoded_paths() {
+        let main = parse_default("Films/My%20Movie%20(2020).MKV.as.m3u8").unwrap();
+        assert_eq!(main.video_url, "Films/My Movie (2020).MKV");
+        assert_eq!(main.request_path(), "Films/My%20Movie%20(2020).MKV.as.m3u8");
+
+        // The variant playlist URL in the main playlist, relative to it.
+        let playlist = main.session("abc").playlist(0);
+        assert_eq!(playlist.to_string(), "My%20Movie%20(2020).MKV/abc/t.0.m3u8");
+        let parsed = parse_default(&playlist.request_path()).unwrap();
+        assert_eq!(parsed.video_url, main.video_url);
+        assert_eq!(parsed.to_string(), playlist.to_string());
+
+        for url in [
+            "Films/Am%C3%A9lie.mkv/abc/v/0+1-aac.3.m4s",
+            "Films/100%25%20%23hash%3F.mp4/abc/s/2.4-7.vtt",
+            "Films/a%3Ab.mov.audio.1.m3u8",
+        ] {
+            let params = parse_default(url).unwrap_or_else(|| panic!("{} did not parse", url));
+            assert_eq!(params.request_path(), url);
+        }
+        assert_eq!(
+            parse_default("Films/Am%C3%A9lie.mkv.as.m3u8")
+                .unwrap()
+                .video_url,
+            "Films/Amélie.mkv"
+        );
+
+        // Unencoded spaces and stray `%`s are accepted too.
+        assert_eq!(
+            parse_default("My Movie 100%.mp4.as.m3u8")
+                .unwrap()
+                .video_url,
+            "My Movie 100%.mp4"
+        );
+        // Not UTF-8.
+        assert!(parse_default("movie%FF.mp4.as.m3u8").is_none());
+    }
+
+    #[test]
+    fn test_request_path_roundtrip() {
+        for url in [
+            "dir/movie.mkv.as.m3u8",
+            "dir/movie.mkv.audio.2.m3u8",
+            "dir/movie.mkv/abc/t.0+1-aac.m3u8",
+            "dir/movie.mkv/abc/v/0+1-aac.3.m4s",
+            "dir/movie.mkv/abc/s/2.4-7.vtt",
+            "dir/movie.mkv/abc/s/empty.vtt",
+        ] {
+            assert_eq!(parse_default(url).unwrap().request_path(), url);
+        }
+    }
+
+    #[test]
+    fn test_builder() {
+        let main = HlsParams::main_playlist("dir/movie.mkv");
+        let session = main.session("abc");
+        for (params, url) in [
+            (main.clone(), "dir/movie.mkv.as.m3u8"),
+            (
+                HlsParams::audio_main_playlist("dir/movie.mkv", 2),
+                "dir/movie.mkv.audio.2.m3u8",
+            ),
+            (
+                session.playlist(0).interleave(1, Some("aac")).with_init(),
+                "dir/movie.mkv/abc/t.0+1-aac.hdr.m3u8",
+            ),
+            (
+                session.playlist(2).timestamps(SubtitleTimestamps::Mpegts),
+                "dir/movie.mkv/abc/t.2.mpegts.m3u8",
+            ),
+            (
+                session.video_init(0).with_init(),
+                "dir/movie.mkv/abc/v/0.init.mp4",
+            ),
+            (
+                session.video_segment(0, 3).interleave(1, None),
+                "dir/movie.mkv/abc/v/0+1.3.m4s",
+            ),
+            (
+                session.audio_init(1, None),
+                "dir/movie.mkv/abc/a/1.init.mp4",
+            ),
+            (
+                session
+                    .audio_segment(1, 0, Some("aac"))
+                    .until(2)
+                    .unwrap()
+                    .with_init(),
+                "dir/movie.mkv/abc/a/1-aac.0-2.hdr.m4s",
+            ),
+            (
+                session.subtitle_segment(2, 4..=7),
+                "dir/movie.mkv/abc/s/2.4-7.vtt",
+            ),
+            (
+                session.empty_subtitle_segment(),
+                "dir/movie.mkv/abc/s/empty.vtt",
+            ),
+        ] {
+            assert_eq!(params.request_path(), url);
+            assert_eq!(parse_default(url).unwrap().request_path(), url);
+        }
+        assert!(session.audio_segment(1, 2, None).until(2).is_none());
+    }
+
+    #[test]
+    fn test_signed_url_codec() {
+        let signer = |path: &str, expires: u64| format!("{:x}", path.len() as u64 ^ expires);
+        let codec = SignedUrlCodec::new(signer, std::time::Duration::from_secs(60));
+
+        let params = codec.parse("movie.mkv/abc/a/1.5.m4s").unwrap();
+        let encoded = codec.encode(&params);
+        let (path, query) = encoded.split_once('?').expect("query string");
+        assert_eq!(path, "a/1.5.m4s");
+
+        let mut exp = 0;
+        let mut sig = "";
+        for kv in query.split('&') {
+            match kv.split_once('=') {
+                Some(("exp", v)) => exp = v.parse().unwrap(),
+                Some(("sig", v)) => sig = v,
+                _ => panic!("unexpected query parameter {}", kv),
+            }
+        }
+        assert!(codec.verify(&params, exp, sig));
+        assert!(!codec.verify(&params, exp, "bad"));
+        assert!(!codec.verify(&params, 1, &signer(&params.request_path(), 1)));
+
+        // The main playlist is not signed.
+        let main = codec.parse("movie.mkv.as.m3u8").unwrap();
+        assert_eq!(codec.encode(&main), "movie.mkv.as.m3u8");
+
+        // Playlists with signed URLs are not cached.
+        assert!(!codec.is_stable());
+        assert!(DefaultUrlCodec.is_stable());
+    }
+}
@@ -24,6 +24,23 @@ pub fn get_video_codec_string(
     }
 }
 
+/// HLS codec string for a video stream.
+///
+/// Uses the exact string derived from the bitstream at scan time if there is
+/// one, and falls back to guessing from the container's profile/level fields.
+pub fn video_codec_string(video: &crate::media::VideoStreamInfo) -> Option<String> {
+    video.codec_string.clone().or_else(|| {
+        get_video_codec_string(
+            video.codec_id,
+            video.width,
+            video.height,
+            video.bitrate,
+            video.profile,
+            video.level,
+        )
+    })
+}
+
 /// Get HLS codec string for an audio codec
 pub fn get_audio_codec_string(codec_id: ffmpeg::codec::Id) -> Option<&'static str> {
     match codec_id {
@@ -38,32 +55,33 @@ pub fn get_audio_codec_string(codec_id: ffmpeg::codec::Id) -> Option<&'static st
     }
 }
 
+/// HLS codec string for an audio stream.
+///
+/// Passthrough streams use the exact string derived from the bitstream at
+/// scan time if there is one (e.g. `mp4a.40.5` for HE-AAC); transcoded streams
+/// are always advertised by their target codec.
+pub fn audio_codec_string(audio: &crate::media::AudioStreamInfo) -> String {
+    match audio.transcode_to {
+        Some(codec) => codec_name(codec),
+        None => audio
+            .codec_string
+            .clone()
+            .unwrap_or_else(|| codec_name(audio.codec_id)),
+    }
+}
+
 /// Build codec attribute for HLS variant
 /// Combines video and audio codec strings
 pub fn build_codec_attribute(
-    video_codec: Option<ffmpeg::codec::Id>,
-    video_width: u32,
-    video_height: u32,
-    video_bitrate: u64,
-    video_profile: Option<i32>,
-    video_level: Option<i32>,
+    video: Option<&crate::media::VideoStreamInfo>,
     audio_codecs: &[ffmpeg::codec::Id],
     has_subtitles: bool,
 ) -> Option<String> {
     let mut codecs = Vec::new();
 
     // Add video codec
-    if let Some(vid) = video_codec {
-        if let Some(codec_str) = get_video_codec_string(
-            vid,
-            video_width,
-            video_height,
-            video_bitrate,
-            video_profile,
-            video_level,
-        ) {
-            codecs.push(codec_str);
-        }
+    if let Some(codec_str) = video.and_then(video_codec_string) {
+        codecs.push(codec_str);
     }
 
     // Add audio codecs
@@ -161,9 +179,40 @@ pub fn calculate_bandwidth(bitrate: u64, audio_bitrate: u32) -> u64 {
     bitrate + (audio_bitrate as u64) * 160 / 100
 }
 
+/// Valid values of the `HDCP-LEVEL` attribute.
+pub const HDCP_LEVELS: &[&str] = &["TYPE-0", "TYPE-1", "NONE"];
+
+/// Extra `EXT-X-STREAM-INF` attributes of a video variant: `,FRAME-RATE=<fps>`
+/// (if known) and `,HDCP-LEVEL=<level>` (if configured).
+pub fn video_stream_inf_attrs(
+    video: &crate::media::VideoStreamInfo,
+    hdcp_level: Option<&str>,
+) -> String {
+    let mut attrs = String::new();
+    let (num, den) = (video.framerate.numerator(), video.framerate.denominator());
+    if num > 0 && den > 0 {
+        attrs.push_str(&format!(",FRAME-RATE={:.3}", num as f64 / den as f64));
+    }
+    if let Some(level) = hdcp_level {
+        attrs.push_str(&format!(",HDCP-LEVEL={}", level));
+    }
+    attrs
+}
+
+/// `,AVERAGE-BANDWIDTH=<n>` for a variant, or an empty string if the video
+/// bitrate is unknown (a sum of the audio bitrate alone would be misleading).
+pub fn average_bandwidth_attr(bitrate: u64, audio_bitrate: u32) -> String {
+    if bitrate == 0 {
+        return String::new();
+    }
+    format!(",AVERAGE-BANDWIDTH={}", bitrate + audio_bitrate as u64)
+}
+
 pub fn codec_id(name: &str) -> Option<ffmpeg::codec::Id> {
     Some(match name {
         "mp4a.40.2" => ffmpeg::codec::Id::AAC,
+        "mp4a.40.5" => ffmpeg::codec::Id::AAC,
+        "mp4a.40.29" => ffmpeg::codec::Id::AAC,
         "aac" => ffmpeg::codec::Id::AAC,
         "ac-3" => ffmpeg::codec::Id::AC3,
         "ac3" => ffmpeg::codec::Id::AC3,
@@ -223,18 +272,195 @@ pub fn codec_label(codec_id: ffmpeg::codec::Id) -> &'static str {
 }
 
 // Convert 3-letter language code to 2-letter (RFC5646)
+//
+// Both the bibliographic (ISO 639-2/B, "dut") and the terminology (639-2/T,
+// "nld") code map to the ISO 639-1 code. Languages without a 639-1 code
+// keep their 3-letter code, which is valid in RFC5646 as well.
 pub fn to_rfc5646(lang: &str) -> &str {
     match lang {
+        "aar" => "aa",
+        "abk" => "ab",
+        "ave" => "ae",
+        "afr" => "af",
+        "aka" => "ak",
+        "amh" => "am",
+        "arg" => "an",
+        "ara" => "ar",
+        "asm" => "as",
+        "ava" => "av",
+        "aym" => "ay",
+        "aze" => "az",
+        "bak" => "ba",
+        "bel" => "be",
+        "bul" => "bg",
+        "bis" => "bi",
+        "bam" => "bm",
+        "ben" => "bn",
+        "bod" | "tib" => "bo",
+        "bre" => "br",
+        "bos" => "bs",
+        "cat" => "ca",
+        "che" => "ce",
+        "cha" => "ch",
+        "cos" => "co",
+        "cre" => "cr",
+        "ces" | "cze" => "cs",
+        "chu" => "cu",
+        "chv" => "cv",
+        "cym" | "wel" => "cy",
+        "dan" => "da",
+        "deu" | "ger" => "de",
+        "div" => "dv",
+        "dzo" => "dz",
+        "ewe" => "ee",
+        "ell" | "gre" => "el",
         "eng" => "en",
-        "fre" => "fr",
-        "ger" => "de",
+        "epo" => "eo",
         "spa" => "es",
+        "est" => "et",
+        "eus" | "baq" => "eu",
+        "fas" | "per" => "fa",
+        "ful" => "ff",
+        "fin" => "fi",
+        "fij" => "fj",
+        "fao" => "fo",
+        "fra" | "fre" => "fr",
+        "fry" => "fy",
+        "gle" => "ga",
+        "gla" => "gd",
+        "glg" => "gl",
+        "grn" => "gn",
+        "guj" => "gu",
+        "glv" => "gv",
+        "hau" => "ha",
+        "heb" => "he",
+        "hin" => "hi",
+        "hmo" => "ho",
+        "hrv" => "hr",
+        "hat" => "ht",
+        "hun" => "hu",
+        "hye" | "arm" => "hy",
+        "her" => "hz",
+        "ina" => "ia",
+        "ind" => "id",
+        "ile" => "ie",
+        "ibo" => "ig",
+        "iii" => "ii",
+        "ipk" => "ik",
+        "ido" => "io",
+        "isl" | "ice" => "is",
         "ita" => "it",
+        "iku" => "iu",
         "jpn" => "ja",
+        "jav" => "jv",
+        "kat" | "geo" => "ka",
+        "kon" => "kg",
+        "kik" => "ki",
+        "kua" => "kj",
+        "kaz" => "kk",
+        "kal" => "kl",
+        "khm" => "km",
+        "kan" => "kn",
         "kor" => "ko",
-        "chi" => "zh",
-        "rus" => "ru",
+        "kau" => "kr",
+        "kas" => "ks",
+        "kur" => "ku",
+        "kom" => "kv",
+        "cor" => "kw",
+        "kir" => "ky",
+        "lat" => "la",
+        "ltz" => "lb",
+        "lug" => "lg",
+        "lim" => "li",
+        "lin" => "ln",
+        "lao" => "lo",
+        "lit" => "lt",
+        "lub" => "lu",
+        "lav" => "lv",
+        "mlg" => "mg",
+        "mah" => "mh",
+        "mri" | "mao" => "mi",
+        "mkd" | "mac" => "mk",
+        "mal" => "ml",
+        "mon" => "mn",
+        "mar" => "mr",
+        "msa" | "may" => "ms",
+        "mlt" => "mt",
+        "mya" | "bur" => "my",
+        "nau" => "na",
+        "nob" => "nb",
+        "nde" => "nd",
+        "nep" => "ne",
+        "ndo" => "ng",
+        "nld" | "dut" => "nl",
+        "nno" => "nn",
+        "nor" => "no",
+        "nbl" => "nr",
+        "nav" => "nv",
+        "nya" => "ny",
+        "oci" => "oc",
+        "oji" => "oj",
+        "orm" => "om",
+        "ori" => "or",
+        "oss" => "os",
+        "pan" => "pa",
+        "pli" => "pi",
+        "pol" => "pl",
+        "pus" => "ps",
         "por" => "pt",
+        "que" => "qu",
+        "roh" => "rm",
+        "run" => "rn",
+        "ron" | "rum" => "ro",
+        "rus" => "ru",
+        "kin" => "rw",
+        "san" => "sa",
+        "srd" => "sc",
+        "snd" => "sd",
+        "sme" => "se",
+        "sag" => "sg",
+        "sin" => "si",
+        "slk" | "slo" => "sk",
+        "slv" => "sl",
+        "smo" => "sm",
+        "sna" => "sn",
+        "som" => "so",
+        "sqi" | "alb" => "sq",
+        "srp" => "sr",
+        "ssw" => "ss",
+        "sot" => "st",
+        "sun" => "su",
+        "swe" => "sv",
+        "swa" => "sw",
+        "tam" => "ta",
+        "tel" => "te",
+        "tgk" => "tg",
+        "tha" => "th",
+        "tir" => "ti",
+        "tuk" => "tk",
+        "tgl" => "tl",
+        "tsn" => "tn",
+        "ton" => "to",
+        "tur" => "tr",
+        "tso" => "ts",
+        "tat" => "tt",
+        "twi" => "tw",
+        "tah" => "ty",
+        "uig" => "ug",
+        "ukr" => "uk",
+        "urd" => "ur",
+        "uzb" => "uz",
+        "ven" => "ve",
+        "vie" => "vi",
+        "vol" => "vo",
+        "wln" => "wa",
+        "wol" => "wo",
+        "xho" => "xh",
+        "yid" => "yi",
+        "yor" => "yo",
+        "zha" => "za",
+        "zho" | "chi" => "zh",
+        "zul" => "zu",
         _ => lang,
     }
 }
@@ -272,18 +498,32 @@ mod tests {
 
     #[test]
     fn test_build_codec_attribute() {
+        let mut video = crate::media::VideoStreamInfo {
+            stream_index: 0,
+            codec_id: ffmpeg::codec::Id::H264,
+            width: 1920,
+            height: 1080,
+            bitrate: 5000000,
+            framerate: ffmpeg::Rational::new(25, 1),
+            language: None,
+            profile: Some(100),
+            level: Some(41), // Level 4.1 -> 0x29
+            codec_string: None,
+            nal_format: Default::default(),
+            stats: None,
+        };
         let codecs = build_codec_attribute(
-            Some(ffmpeg::codec::Id::H264),
-            1920,
-            1080,
-            5000000,
-            Some(100),
-            Some(41), // Level 4.1 -> 0x29
+            Some(&video),
             &[ffmpeg::codec::Id::AAC],
             true, // has subtitles
         );
         assert!(codecs.is_some());
         assert_eq!(codecs.unwrap(), "avc1.640029,mp4a.40.2,wvtt");
+
+        // The codec string from the SPS wins over the container fields.
+        video.codec_string = Some("avc1.64002a".to_string());
+        let codecs = build_codec_attribute(Some(&video), &[], false);
+        assert_eq!(codecs.unwrap(), "avc1.64002a");
     }
 
     #[test]
@@ -306,4 +546,18 @@ mod tests {
             "avc1.640028"
         );
     }
+
+    #[test]
+    fn test_to_rfc5646() {
+        assert_eq!(to_rfc5646("eng"), "en");
+        // Bibliographic and terminology codes.
+        assert_eq!(to_rfc5646("dut"), "nl");
+        assert_eq!(to_rfc5646("nld"), "nl");
+        assert_eq!(to_rfc5646("ger"), "de");
+        assert_eq!(to_rfc5646("deu"), "de");
+        assert_eq!(to_rfc5646("swe"), "sv");
+        // No 639-1 code, or already one.
+        assert_eq!(to_rfc5646("haw"), "haw");
+        assert_eq!(to_rfc5646("nl"), "nl");
+    }
 }
@@ -0,0 +1,213 @@
+//! Playlist delta updates
+//!
+//! A player that reloads a media playlist can ask for a delta update with
+//! `_HLS_skip=YES` (RFC 8216bis, section 6.2.5.1): the segments more than
+//! `CAN-SKIP-UNTIL` seconds from the end, which it already has, are replaced
+//! by one `EXT-X-SKIP` tag. Media playlists that are still growing advertise
+//! this with `EXT-X-SERVER-CONTROL:CAN-SKIP-UNTIL`, at six target durations,
+//! the smallest value the spec allows. Complete playlists, with
+//! `EXT-X-ENDLIST`, are not advertised: players don't reload them. Requests
+//! with `_HLS_skip` are answered all the same.
+//!
+//! The playlists are generated in full and then cut, so every kind of media
+//! playlist (video, audio, interleaved, subtitles) supports it the same way.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// `EXT-X-VERSION` required by `EXT-X-SKIP`.
+const SKIP_VERSION: u32 = 9;
+
+static DELTA_UPDATES: AtomicBool = AtomicBool::new(false);
+
+/// Advertise and serve playlist delta updates. The default is off.
+pub fn set_playlist_delta_updates(enabled: bool) {
+    DELTA_UPDATES.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn delta_updates() -> bool {
+    DELTA_UPDATES.load(Ordering::Relaxed)
+}
+
+/// `CAN-SKIP-UNTIL` of a playlist with target duration `target_duration`.
+fn can_skip_until(target_duration: u32) -> f64 {
+    6.0 * target_duration as f64
+}
+
+fn target_duration(playlist: &str) -> Option<u32> {
+    playlist
+        .lines()
+        .find_map(|l| l.strip_prefix("#EXT-X-TARGETDURATION:"))
+        .and_then(|v| v.trim().parse().ok())
+}
+
+/// Add `EXT-X-SERVER-CONTROL` to a media playlist without `EXT-X-ENDLIST`,
+/// after its target duration.
+pub(crate) fn advertise(playlist: String) -> String {
+    if playlist.lines().any(|l| l == "#EXT-X-ENDLIST") {
+        return playlist;
+    }
+    let Some(target) = target_duration(&playlist) else {
+        return playlist;
+    };
+    let mut output = String::with_capacity(playlist.len() + 48);
+    for line in playlist.lines() {
+        output.push_str(line);
+        output.push('\n');
+        if line.starts_with("#EXT-X-TARGETDURATION:") {
+            output.push_str(&format!(
+                "#EXT-X-SERVER-CONTROL:CAN-SKIP-UNTIL={:.1}\n",
+                can_skip_until(target)
+            ));
+        }
+    }
+    output
+}
+
+/// The delta update of `playlist`, for a request with `_HLS_skip=YES`.
+///
+/// The playlist is returned as-is if delta updates are off, if it is not a
+/// media playlist, or if it is too short to skip anything.
+pub fn delta_update(playlist: &str) -> String {
+    if !delta_updates() {
+        return playlist.to_string();
+    }
+    skip(playlist)
+}
+
+/// Whether `line` belongs to the segment that follows it, rather than to
+/// the playlist header.
+fn is_segment_tag(line: &str) -> bool {
+    line.starts_with("#EXTINF:")
+        || line == "#EXT-X-DISCONTINUITY"
+        || line == "#EXT-X-GAP"
+        || line.starts_with("#EXT-X-BYTERANGE:")
+}
+
+/// Cut a media playlist: the segments that end more than
+/// `CAN-SKIP-UNTIL` seconds before the end of the playlist are replaced by
+/// `EXT-X-SKIP`. The playlist is returned as-is if there is nothing to skip.
+pub(crate) fn skip(playlist: &str) -> String {
+    let Some(target) = target_duration(playlist) else {
+        return playlist.to_string();
+    };
+
+    // Header, segments (duration and lines), and what comes after them.
+    let mut header: Vec<&str> = Vec::new();
+    let mut segments: Vec<(f64, Vec<&str>)> = Vec::new();
+    let mut current: Option<(f64, Vec<&str>)> = None;
+    let mut trailer: Vec<&str> = Vec::new();
+    for line in playlist.lines() {
+        if let Some((duration, lines)) = current.as_mut() {
+            lines.push(line);
+            if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+                let value = extinf.split(',').next().unwrap_or_default();
+                *duration = value.trim().parse().unwrap_or(0.0);
+            } else if !line.is_empty() && !line.starts_with('#') {
+                segments.extend(current.take());
+            }
+        } else if is_segment_tag(line) {
+            let mut segment = (0.0, vec![line]);
+            if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+                let value = extinf.split(',').next().unwrap_or_default();
+                segment.0 = value.trim().parse().unwrap_or(0.0);
+            }
+            current = Some(segment);
+        } else if segments.is_empty() {
+            header.push(line);
+        } else {
+            trailer.push(line);
+        }
+    }
+
+    let total: f64 = segments.iter().map(|(d, _)| d).sum();
+    let until = can_skip_until(target);
+    let mut end = 0.0;
+    let skipped = segments
+        .iter()
+        .take_while(|(duration, _)| {
+            end += duration;
+            total - end > until
+        })
+        .count();
+    if skipped == 0 {
+        return playlist.to_string();
+    }
+
+    let mut output = String::with_capacity(playlist.len() / 2);
+    for line in header {
+        match line.strip_prefix("#EXT-X-VERSION:") {
+            Some(v) => {
+                let version = v.trim().parse::<u32>().unwrap_or(0).max(SKIP_VERSION);
+                output.push_str(&format!("#EXT-X-VERSION:{}\n", version));
+            }
+            None => {
+                output.push_str(line);
+                output.push('\n');
+            }
+        }
+    }
+    output.push_str(&format!("#EXT-X-SKIP:SKIPPED-SEGMENTS={}\n", skipped));
+    for line in segments[skipped..].iter().flat_map(|(_, lines)| lines) {
+        output.push_str(line);
+        output.push('\n');
+    }
+    for line in trailer {
+        output.push_str(line);
+        output.push('\n');
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playlist(segments: usize) -> String {
+        let mut p = live_playlist(segments);
+        p.push_str("#EXT-X-ENDLIST\n");
+        p
+    }
+
+    fn live_playlist(segments: usize) -> String {
+        let mut p = String::from(
+            "#EXTM3U\n#EXT-X-VERSION:7\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-MAP:URI=\"v/init.mp4\"\n\n",
+        );
+        for i in 0..segments {
+            if i == 3 {
+                p.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+            p.push_str(&format!("#EXTINF:4.000,\nv/{}.m4s\n", i));
+        }
+        p
+    }
+
+    #[test]
+    fn test_advertise() {
+        let p = advertise(live_playlist(2));
+        assert!(p.contains("#EXT-X-TARGETDURATION:4\n#EXT-X-SERVER-CONTROL:CAN-SKIP-UNTIL=24.0\n"));
+        // Complete playlists are not reloaded.
+        assert_eq!(advertise(playlist(2)), playlist(2));
+    }
+
+    #[test]
+    fn test_skip() {
+        // 10 segments of 4s: the first 3 end more than 24s before the end.
+        let p = skip(&playlist(10));
+        assert!(p.contains("#EXT-X-VERSION:9\n"));
+        assert!(p.contains("#EXT-X-MEDIA-SEQUENCE:0\n"));
+        assert!(p.contains("#EXT-X-MAP:URI=\"v/init.mp4\"\n"));
+        assert!(p.contains(
+            "#EXT-X-SKIP:SKIPPED-SEGMENTS=3\n#EXT-X-DISCONTINUITY\n#EXTINF:4.000,\nv/3.m4s\n"
+        ));
+        assert!(!p.contains("v/2.m4s"));
+        assert!(p.ends_with("v/9.m4s\n#EXT-X-ENDLIST\n"));
+
+        // The version of the Legacy profile is raised too.
+        let legacy = playlist(10).replace("#EXT-X-VERSION:7", "#EXT-X-VERSION:6");
+        assert!(skip(&legacy).contains("#EXT-X-VERSION:9\n"));
+
+        // Nothing older than CAN-SKIP-UNTIL.
+        assert_eq!(skip(&playlist(6)), playlist(6));
+    }
+}
@@ -8,6 +8,47 @@ use ffmpeg_next as ffmpeg;
 
 use super::codec::*;
 use crate::media::StreamIndex;
+use crate::segment::profile::compatibility_profile;
+
+/// Options of `generate_master_playlist`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MasterOptions<'a> {
+    /// Also advertise every audio track that is not AAC transcoded to AAC, in
+    /// the `audio-aac` group, so that an ABR player can switch to the lighter
+    /// audio on a constrained network.
+    pub aac_alternates: bool,
+    /// One muxed audio-video playlist per (primary video, audio track) pair
+    /// instead of separate audio renditions.
+    pub interleaved: bool,
+    /// List the muxed variants after the variants with separate audio
+    /// renditions (unless `interleaved` is set), for players that can't play
+    /// the latter. The muxed segments are generated separately from the
+    /// video-only ones, from the same index.
+    pub interleaved_fallback: bool,
+    /// The audio and video variant playlists serve the init segment as a
+    /// byterange of the first media segment.
+    pub combined_init: bool,
+    /// The `HDCP-LEVEL` of the video variants.
+    pub hdcp_level: Option<&'a str>,
+    /// The timestamp mode of the subtitle segments.
+    pub subtitle_timestamps: Option<crate::params::SubtitleTimestamps>,
+    /// The preferred languages, most preferred first (e.g. from
+    /// `Accept-Language`). Audio and subtitle tracks in those languages are
+    /// listed first and are the `DEFAULT`, and so is the variant of their
+    /// audio group. Without preferred languages, tracks are in source order.
+    pub languages: &'a [String],
+    /// The requested bitrate of the audio transcoded to AAC. It is in the
+    /// URLs of those tracks (`aac96`) and in the `BANDWIDTH` of their
+    /// variants; `None` is the configured bitrate.
+    pub aac_bitrate: Option<u64>,
+    /// A (main, secondary) pair of audio tracks that is also listed mixed
+    /// together, as one more AAC rendition in the `audio-aac` group
+    /// (`aacm<secondary>` in its URL). A mix is not a muxed variant.
+    pub audio_mix: Option<(usize, usize)>,
+    /// Where playback starts, in seconds from the start of the media, e.g. a
+    /// resume position. It is sent as `EXT-X-START`.
+    pub start_offset: Option<f64>,
+}
 
 /// Generate master playlist content
 ///
@@ -18,9 +59,12 @@ use crate::media::StreamIndex;
 ///   same video variant playlist but differing in `AUDIO=` and `CODECS=`
 /// - Subtitle MEDIA entries for text tracks
 ///
-/// When `interleaved` is true and there's exactly one video and one audio track,
-/// generates a single muxed audio-video playlist instead of separate tracks.
-/// When `force_aac` is also true, the audio will be transcoded to AAC.
+/// Video variants carry `FRAME-RATE` and, if set, `HDCP-LEVEL`.
+///
+/// Audio tracks that would need transcoding are left out if the linked FFmpeg
+/// cannot decode them, see `ffmpeg_utils::capabilities`.
+///
+/// See `MasterOptions` for the other options.
 pub fn generate_master_playlist(
     index: &StreamIndex,
     video_url: &str,
@@ -28,13 +72,34 @@ pub fn generate_master_playlist(
     codecs: &[String],
     tracks_enabled: &HashSet<usize>,
     transcode: &HashMap<usize, String>,
-    interleaved: bool,
+    options: &MasterOptions<'_>,
 ) -> String {
+    let MasterOptions {
+        aac_alternates,
+        interleaved,
+        interleaved_fallback,
+        combined_init,
+        hdcp_level,
+        subtitle_timestamps,
+        languages,
+        aac_bitrate,
+        audio_mix,
+        start_offset,
+    } = *options;
     let mut output = String::new();
 
     // Header
     output.push_str("#EXTM3U\n");
-    output.push_str("#EXT-X-VERSION:7\n");
+    output.push_str(&format!(
+        "#EXT-X-VERSION:{}\n",
+        compatibility_profile().hls_version()
+    ));
+    if let Some(offset) = start_offset {
+        output.push_str(&format!(
+            "#EXT-X-START:TIME-OFFSET={:.3},PRECISE=YES\n",
+            offset
+        ));
+    }
     output.push('\n');
 
     // Remove tracks that aren't enabled.
@@ -50,12 +115,74 @@ pub fn generate_master_playlist(
         .subtitle_streams
         .retain(|s| tracks_enabled.contains(&s.stream_index));
 
+    // Without subtitle support there is nothing to serve for text tracks.
+    #[cfg(not(feature = "subtitles"))]
+    index.subtitle_streams.clear();
+
     // Mark tracks to be transcoded (audio only for now).
+    #[cfg(feature = "transcode")]
     for (idx, codec) in transcode.iter() {
         if let Some(t) = index.get_audio_stream_mut(*idx) {
             t.transcode_to = codec_id(codec);
         }
     }
+    #[cfg(not(feature = "transcode"))]
+    let _ = transcode;
+
+    // Leave out tracks that this FFmpeg build cannot transcode.
+    let caps = crate::ffmpeg_utils::capabilities::capabilities();
+    index
+        .audio_streams
+        .retain(|a| a.transcode_to.is_none() || caps.can_transcode_audio(a.codec_id));
+
+    // Add the AAC alternates, at the bitrate of the AAC encoder.
+    #[cfg(feature = "transcode")]
+    if aac_alternates {
+        let alternates: Vec<_> = index
+            .audio_streams
+            .iter()
+            .filter(|a| {
+                a.transcode_to.unwrap_or(a.codec_id) != ffmpeg::codec::Id::AAC
+                    && caps.can_transcode_audio(a.codec_id)
+            })
+            .map(|a| crate::media::AudioStreamInfo {
+                transcode_to: Some(ffmpeg::codec::Id::AAC),
+                bitrate: crate::transcode::encoder::aac_bitrate(
+                    a.channels,
+                    &index.config.aac_bitrates,
+                ),
+                ..a.clone()
+            })
+            .collect();
+        index.audio_streams.extend(alternates);
+    }
+    #[cfg(not(feature = "transcode"))]
+    let _ = aac_alternates;
+
+    // Add the audio mix, if both tracks are enabled and can be decoded.
+    #[cfg(feature = "transcode")]
+    if let Some((main, secondary)) = audio_mix {
+        let find = |idx| {
+            orig_index.audio_streams.iter().find(|a| {
+                a.stream_index == idx
+                    && tracks_enabled.contains(&idx)
+                    && caps.can_transcode_audio(a.codec_id)
+            })
+        };
+        if let (Some(a), Some(_)) = (find(main), find(secondary)) {
+            index.audio_streams.push(crate::media::AudioStreamInfo {
+                transcode_to: Some(ffmpeg::codec::Id::AAC),
+                mix_with: Some(secondary),
+                bitrate: crate::transcode::encoder::aac_bitrate(
+                    a.channels,
+                    &index.config.aac_bitrates,
+                ),
+                ..a.clone()
+            });
+        }
+    }
+    #[cfg(not(feature = "transcode"))]
+    let _ = audio_mix;
 
     // Filter out unsupported codecs (only when a codec list was supplied).
     // When codecs is empty (no ?codecs= query param), keep all audio streams.
@@ -75,18 +202,20 @@ pub fn generate_master_playlist(
 
     // Now, if we have no audio streams left, but 'aac' was
     // in the supported list, add transcoded streams.
-    if index.audio_streams.is_empty() && !orig_index.audio_streams.is_empty() {
+    // Only possible when transcoding support is compiled in.
+    if cfg!(feature = "transcode")
+        && index.audio_streams.is_empty()
+        && !orig_index.audio_streams.is_empty()
+    {
         let has_aac = codecs
             .iter()
             .filter_map(|c| codec_id(c))
             .any(|id| id == ffmpeg::codec::Id::AAC);
         if has_aac {
             let mut src_codec = None;
-            for s in orig_index
-                .audio_streams
-                .iter()
-                .filter(|a| tracks_enabled.contains(&a.stream_index))
-            {
+            for s in orig_index.audio_streams.iter().filter(|a| {
+                tracks_enabled.contains(&a.stream_index) && caps.can_transcode_audio(a.codec_id)
+            }) {
                 if src_codec.is_none() {
                     src_codec = Some(s.codec_id);
                 }
@@ -99,6 +228,15 @@ pub fn generate_master_playlist(
         }
     }
 
+    // Audio transcoded at the requested bitrate.
+    if let Some(bitrate) = aac_bitrate {
+        for a in index.audio_streams.iter_mut() {
+            if a.transcode_to == Some(ffmpeg::codec::Id::AAC) {
+                a.bitrate = bitrate;
+            }
+        }
+    }
+
     /// Return the codec-family GROUP-ID for a given stream.
     // FIXME: codec_name_short can fail, not sure about the fallback to aac.
     // Probably better to filter out unknown codecs.
@@ -107,30 +245,55 @@ pub fn generate_master_playlist(
         format!("audio-{}", codec_name_short(codec).unwrap_or("aac"))
     }
 
-    /// HLS codec string we advertise for a given group.
-    fn codec_str_for_group(group_id: &str) -> String {
-        let name = group_id.strip_prefix("audio-").unwrap();
-        codec_name_normalized(name).unwrap_or(name.to_string())
+    /// HLS codec string(s) we advertise for a given group. A group can hold
+    /// more than one profile of a codec family (e.g. AAC-LC and HE-AAC).
+    fn codec_str_for_group(streams: &[crate::media::AudioStreamInfo], group_id: &str) -> String {
+        let mut codecs: Vec<String> = Vec::new();
+        for s in streams
+            .iter()
+            .filter(|s| group_id_for_stream(s) == group_id)
+        {
+            let codec = audio_codec_string(s);
+            if !codecs.contains(&codec) {
+                codecs.push(codec);
+            }
+        }
+        codecs.join(",")
     }
 
+    // Preferred languages first; the sorts are stable, so source order otherwise.
+    index
+        .audio_streams
+        .sort_by_key(|a| language_rank(a.language.as_deref(), languages));
+    index
+        .subtitle_streams
+        .sort_by_key(|s| language_rank(s.language.as_deref(), languages));
+
     // Skip separate audio tracks section when using interleaved mode
     // (audio is already muxed into the video stream)
-    let skip_audio_section =
-        interleaved && index.video_streams.len() == 1 && index.audio_streams.len() == 1;
+    let skip_audio_section = interleaved && index.primary_video().is_some();
 
     if !index.audio_streams.is_empty() && !skip_audio_section {
         output.push_str("# Audio Tracks\n");
 
-        // Sort variants for stable output: by group_id then stream_index
+        // Sort variants for stable output: by group_id, preferred language,
+        // mixes after the tracks, then stream_index
         let mut streams_sorted = index.audio_streams.clone();
         streams_sorted.sort_by(|a, b| {
             let ga = group_id_for_stream(a);
             let gb = group_id_for_stream(b);
-            ga.cmp(&gb).then(a.stream_index.cmp(&b.stream_index))
+            let la = language_rank(a.language.as_deref(), languages);
+            let lb = language_rank(b.language.as_deref(), languages);
+            ga.cmp(&gb)
+                .then(la.cmp(&lb))
+                .then(a.mix_with.is_some().cmp(&b.mix_with.is_some()))
+                .then(a.stream_index.cmp(&b.stream_index))
         });
 
         // Track which group_ids we've seen so we can mark the first of each as DEFAULT
         let mut seen_groups: std::collections::HashSet<String> = std::collections::HashSet::new();
+        // NAME must be unique within a group.
+        let mut seen_names: HashSet<(String, String)> = HashSet::new();
 
         for variant in &streams_sorted {
             let group_id = group_id_for_stream(variant);
@@ -144,14 +307,22 @@ pub fn generate_master_playlist(
             } else {
                 format!("{} {}", language.to_uppercase(), label)
             };
+            let name = match variant.mix_with {
+                Some(_) => format!("{} Mix", name),
+                None => name,
+            };
+            let name = (1..)
+                .map(|n| match n {
+                    1 => name.clone(),
+                    n => format!("{} {}", name, n),
+                })
+                .find(|name| seen_names.insert((group_id.clone(), name.clone())))
+                .unwrap();
 
             let is_first_in_group = seen_groups.insert(group_id.clone());
             let default = if is_first_in_group { "YES" } else { "NO" };
 
-            let audio_transcode_to = variant
-                .transcode_to
-                .and_then(|c| codec_name_short(c))
-                .map(String::from);
+            let audio_transcode_to = transcode_target(variant, aac_bitrate);
             println!("audio_transcode_to: {:?}", audio_transcode_to);
 
             let uri = crate::params::HlsParams {
@@ -161,6 +332,9 @@ pub fn generate_master_playlist(
                     track_id: variant.stream_index,
                     audio_track_id: None,
                     audio_transcode_to,
+                    combined_init,
+                    long_segments: false,
+                    subtitle_timestamps: None,
                 }),
             };
             println!("uri 1: {:?}", uri);
@@ -190,6 +364,9 @@ pub fn generate_master_playlist(
                     track_id: sub.stream_index,
                     audio_track_id: None,
                     audio_transcode_to: None,
+                    combined_init: false,
+                    long_segments: false,
+                    subtitle_timestamps,
                 }),
             };
 
@@ -207,6 +384,8 @@ pub fn generate_master_playlist(
     output.push_str("# Video Variants\n");
     if let Some(video) = index.primary_video() {
         let resolution = format!("{}x{}", video.width, video.height);
+        // FRAME-RATE and HDCP-LEVEL
+        let video_attrs = video_stream_inf_attrs(video, hdcp_level);
 
         // Subtitle group attribute (same for all variants)
         let subtitle_attr = if !index.subtitle_streams.is_empty() {
@@ -228,122 +407,127 @@ pub fn generate_master_playlist(
             groups
         };
 
-        // Check if we should use interleaved mode (single muxed A/V playlist)
+        // Check if we should use interleaved mode (muxed A/V playlists)
         // Subtitles are allowed as separate text tracks
-        let use_interleaved =
-            interleaved && index.video_streams.len() == 1 && index.audio_streams.len() == 1;
+        let use_interleaved = interleaved && !index.audio_streams.is_empty();
+        let add_interleaved =
+            (interleaved || interleaved_fallback) && !index.audio_streams.is_empty();
 
-        if use_interleaved {
-            // Single interleaved audio-video playlist
-            // Subtitles are handled as a separate MEDIA group
-            let audio = &index.audio_streams[0];
-            let video_idx = video.stream_index;
-            let audio_idx = audio.stream_index;
+        // With interleaving there are only the muxed variants, below.
+        if !use_interleaved {
+            if audio_groups.is_empty() {
+                // No audio: single variant with only video codec
+                let codecs =
+                    build_codec_attribute(Some(video), &[], !index.subtitle_streams.is_empty());
+                let bandwidth = calculate_bandwidth(video.bitrate.max(100000), 0);
+                let codec_attr = codecs
+                    .map(|c| format!(",CODECS=\"{}\"", c))
+                    .unwrap_or_default();
 
-            // Get codec name.
-            let audio_codec = audio.transcode_to.unwrap_or(audio.codec_id);
-            let audio_codec_str = codec_name(audio_codec);
+                let uri = crate::params::HlsParams {
+                    video_url: video_url.to_string(),
+                    session_id: session_id.map(|s| s.to_string()),
+                    url_type: crate::params::UrlType::Playlist(crate::params::Playlist {
+                        track_id: video.stream_index,
+                        audio_track_id: None,
+                        audio_transcode_to: None,
+                        combined_init,
+                        long_segments: false,
+                        subtitle_timestamps: None,
+                    }),
+                };
 
-            let has_subs = !index.subtitle_streams.is_empty();
-            let video_codec_str = build_codec_attribute(
-                Some(video.codec_id),
-                video.width,
-                video.height,
-                video.bitrate,
-                video.profile,
-                video.level,
-                &[],
-                false,
-            );
+                let average_bandwidth = average_bandwidth_attr(video.bitrate, 0);
 
-            let mut codec_list = Vec::new();
-            if let Some(vc) = video_codec_str {
-                codec_list.push(vc);
-            }
-            codec_list.push(audio_codec_str.to_string());
-            if has_subs {
-                codec_list.push("wvtt".to_string());
-            }
-            let codecs = codec_list.join(",");
+                output.push_str(&format!(
+                    "#EXT-X-STREAM-INF:BANDWIDTH={}{},RESOLUTION={}{}{}{}\n",
+                    bandwidth,
+                    average_bandwidth,
+                    resolution,
+                    video_attrs,
+                    subtitle_attr,
+                    codec_attr
+                ));
+                output.push_str(&format!("{}\n", uri.encode_url()));
+            } else {
+                // One variant per audio codec group
+                for group_id in &audio_groups {
+                    let audio_codec_str = codec_str_for_group(&index.audio_streams, group_id);
+
+                    // Build full codec string: video + this audio group's codec
+                    // Build full codec string: video + audio + subtitles
+                    let has_subs = !index.subtitle_streams.is_empty();
+                    let video_codec_str = video_codec_string(video);
+
+                    let mut codec_list = Vec::new();
+                    if let Some(vc) = video_codec_str {
+                        codec_list.push(vc);
+                    }
+                    codec_list.push(audio_codec_str.to_string());
+                    if has_subs {
+                        codec_list.push("wvtt".to_string());
+                    }
+                    let codecs = codec_list.join(",");
+
+                    // Bandwidth: video + highest bitrate audio stream in this group
+                    let audio_bitrate: u32 = index
+                        .audio_streams
+                        .iter()
+                        .filter(|s| group_id_for_stream(s) == *group_id)
+                        .map(|s| s.bitrate as u32)
+                        .max()
+                        .unwrap_or(0);
 
-            let bandwidth = calculate_bandwidth(video.bitrate.max(100_000), audio.bitrate as u32);
+                    let bandwidth = calculate_bandwidth(video.bitrate.max(100_000), audio_bitrate);
+
+                    let uri = crate::params::HlsParams {
+                        video_url: video_url.to_string(),
+                        session_id: session_id.map(|s| s.to_string()),
+                        url_type: crate::params::UrlType::Playlist(crate::params::Playlist {
+                            track_id: video.stream_index,
+                            audio_track_id: None,
+                            audio_transcode_to: None,
+                            combined_init,
+                            long_segments: false,
+                            subtitle_timestamps: None,
+                        }),
+                    };
+
+                    let average_bandwidth = average_bandwidth_attr(video.bitrate, audio_bitrate);
+
+                    output.push_str(&format!(
+                        "#EXT-X-STREAM-INF:BANDWIDTH={}{},RESOLUTION={}{},AUDIO=\"{}\",CODECS=\"{}\"{}\n",
+                        bandwidth,
+                        average_bandwidth,
+                        resolution,
+                        video_attrs,
+                        group_id,
+                        codecs,
+                        subtitle_attr
+                    ));
+                    output.push_str(&format!("{}\n", uri.encode_url()));
+                }
+            }
+        }
 
+        if add_interleaved {
+            // One interleaved audio-video playlist per audio track, preferred
+            // languages first, so the first variant carries the default audio track.
+            // Subtitles are handled as a separate MEDIA group
+            let has_subs = !index.subtitle_streams.is_empty();
             let subtitle_attr = if has_subs {
                 ",SUBTITLES=\"subs\"".to_string()
             } else {
                 String::new()
             };
 
-            let audio_transcode_to = audio
-                .transcode_to
-                .and_then(|c| codec_name_short(c))
-                .map(String::from);
+            for audio in index.audio_streams.iter().filter(|a| a.mix_with.is_none()) {
+                let video_idx = video.stream_index;
+                let audio_idx = audio.stream_index;
 
-            let uri = crate::params::HlsParams {
-                video_url: video_url.to_string(),
-                session_id: session_id.map(|s| s.to_string()),
-                url_type: crate::params::UrlType::Playlist(crate::params::Playlist {
-                    track_id: video_idx,
-                    audio_track_id: Some(audio_idx),
-                    audio_transcode_to,
-                }),
-            };
-
-            output.push_str(&format!(
-                "#EXT-X-STREAM-INF:BANDWIDTH={},RESOLUTION={},CODECS=\"{}\"{}\n",
-                bandwidth, resolution, codecs, subtitle_attr
-            ));
-            output.push_str(&format!("{}\n", uri.encode_url()));
-        } else if audio_groups.is_empty() {
-            // No audio: single variant with only video codec
-            let codecs = build_codec_attribute(
-                Some(video.codec_id),
-                video.width,
-                video.height,
-                video.bitrate,
-                video.profile,
-                video.level,
-                &[],
-                !index.subtitle_streams.is_empty(),
-            );
-            let bandwidth = calculate_bandwidth(video.bitrate.max(100000), 0);
-            let codec_attr = codecs
-                .map(|c| format!(",CODECS=\"{}\"", c))
-                .unwrap_or_default();
-
-            let uri = crate::params::HlsParams {
-                video_url: video_url.to_string(),
-                session_id: session_id.map(|s| s.to_string()),
-                url_type: crate::params::UrlType::Playlist(crate::params::Playlist {
-                    track_id: video.stream_index,
-                    audio_track_id: None,
-                    audio_transcode_to: None,
-                }),
-            };
-
-            output.push_str(&format!(
-                "#EXT-X-STREAM-INF:BANDWIDTH={},RESOLUTION={}{}{}\n",
-                bandwidth, resolution, subtitle_attr, codec_attr
-            ));
-            output.push_str(&format!("{}\n", uri.encode_url()));
-        } else {
-            // One variant per audio codec group
-            for group_id in &audio_groups {
-                let audio_codec_str = codec_str_for_group(group_id);
-
-                // Build full codec string: video + this audio group's codec
-                // Build full codec string: video + audio + subtitles
-                let has_subs = !index.subtitle_streams.is_empty();
-                let video_codec_str = build_codec_attribute(
-                    Some(video.codec_id),
-                    video.width,
-                    video.height,
-                    video.bitrate,
-                    video.profile,
-                    video.level,
-                    &[],
-                    false,
-                );
+                // Get codec name.
+                let audio_codec_str = audio_codec_string(audio);
+                let video_codec_str = video_codec_string(video);
 
                 let mut codec_list = Vec::new();
                 if let Some(vc) = video_codec_str {
@@ -355,30 +539,29 @@ pub fn generate_master_playlist(
                 }
                 let codecs = codec_list.join(",");
 
-                // Bandwidth: video + highest bitrate audio stream in this group
-                let audio_bitrate: u32 = index
-                    .audio_streams
-                    .iter()
-                    .filter(|s| group_id_for_stream(s) == *group_id)
-                    .map(|s| s.bitrate as u32)
-                    .max()
-                    .unwrap_or(0);
+                let bandwidth =
+                    calculate_bandwidth(video.bitrate.max(100_000), audio.bitrate as u32);
 
-                let bandwidth = calculate_bandwidth(video.bitrate.max(100_000), audio_bitrate);
+                let audio_transcode_to = transcode_target(audio, aac_bitrate);
 
                 let uri = crate::params::HlsParams {
                     video_url: video_url.to_string(),
                     session_id: session_id.map(|s| s.to_string()),
                     url_type: crate::params::UrlType::Playlist(crate::params::Playlist {
-                        track_id: video.stream_index,
-                        audio_track_id: None,
-                        audio_transcode_to: None,
+                        track_id: video_idx,
+                        audio_track_id: Some(audio_idx),
+                        audio_transcode_to,
+                        combined_init,
+                        long_segments: false,
+                        subtitle_timestamps: None,
                     }),
                 };
 
+                let average_bandwidth = average_bandwidth_attr(video.bitrate, audio.bitrate as u32);
+
                 output.push_str(&format!(
-                    "#EXT-X-STREAM-INF:BANDWIDTH={},RESOLUTION={},AUDIO=\"{}\",CODECS=\"{}\"{}\n",
-                    bandwidth, resolution, group_id, codecs, subtitle_attr
+                    "#EXT-X-STREAM-INF:BANDWIDTH={}{},RESOLUTION={}{},CODECS=\"{}\"{}\n",
+                    bandwidth, average_bandwidth, resolution, video_attrs, codecs, subtitle_attr
                 ));
                 output.push_str(&format!("{}\n", uri.encode_url()));
             }
@@ -387,10 +570,185 @@ pub fn generate_master_playlist(
 
     output
 }
+
+/// The transcode target of `stream` in URLs, AAC at `aac_bitrate` if set.
+/// The target of an audio mix names the track that is mixed in.
+fn transcode_target(
+    stream: &crate::media::AudioStreamInfo,
+    aac_bitrate: Option<u64>,
+) -> Option<String> {
+    match stream.transcode_to? {
+        ffmpeg::codec::Id::AAC => Some(match stream.mix_with {
+            Some(track) => crate::params::audio_mix_target(aac_bitrate, track),
+            None => crate::params::aac_transcode_target(aac_bitrate),
+        }),
+        codec => codec_name_short(codec).map(String::from),
+    }
+}
+
+/// Position of `language` in the preferred languages, or after all of them.
+///
+/// Only the primary subtag is compared, so `en-US` matches a track tagged
+/// `eng`. Tracks without a language come last.
+fn language_rank(language: Option<&str>, preferred: &[String]) -> usize {
+    let Some(language) = language.map(|l| l.to_ascii_lowercase()) else {
+        return preferred.len();
+    };
+    let short = to_rfc5646(&language);
+    preferred
+        .iter()
+        .map(|p| p.split('-').next().unwrap_or("").to_ascii_lowercase())
+        .position(|p| p == short || p == language)
+        .unwrap_or(preferred.len())
+}
+
+/// The enabled tracks without the video tracks that are taller than
+/// `max_height` or have a higher bitrate than `max_bitrate`.
+///
+/// A video track with an unknown bitrate is within any bitrate cap. Fails
+/// if video tracks were enabled but none is within the caps: there is no
+/// video transcoding to make a smaller rendition from.
+pub(crate) fn cap_video_tracks(
+    index: &StreamIndex,
+    tracks_enabled: &HashSet<usize>,
+    max_height: Option<u32>,
+    max_bitrate: Option<u64>,
+) -> crate::error::Result<HashSet<usize>> {
+    let over_cap = |v: &crate::media::VideoStreamInfo| {
+        max_height.is_some_and(|h| v.height > h) || max_bitrate.is_some_and(|b| v.bitrate > b)
+    };
+    let enabled: Vec<_> = index
+        .video_streams
+        .iter()
+        .filter(|v| tracks_enabled.contains(&v.stream_index))
+        .collect();
+    if !enabled.is_empty() && enabled.iter().all(|v| over_cap(v)) {
+        return Err(crate::error::HlsError::StreamNotFound(format!(
+            "no video track within maxheight {:?}, maxbitrate {:?}",
+            max_height, max_bitrate
+        )));
+    }
+    let mut tracks = tracks_enabled.clone();
+    for v in enabled.into_iter().filter(|v| over_cap(v)) {
+        tracks.remove(&v.stream_index);
+    }
+    Ok(tracks)
+}
+
+/// Generate the main playlist of an audio-only presentation.
+///
+/// Exposes the single audio track `track_id` as a standalone HLS presentation
+/// (one `#EXT-X-MEDIA` audio rendition plus an audio-only `#EXT-X-STREAM-INF`
+/// variant, both pointing at the audio variant playlist), for clients that
+/// only want to listen.
+///
+/// The track is transcoded if `transcode` says so, or if `codecs` is not empty,
+/// does not contain the track's codec, but does contain AAC. Transcoded to
+/// AAC, it is at `aac_bitrate` if set, as in `generate_master_playlist()`.
+pub fn generate_audio_master_playlist(
+    index: &StreamIndex,
+    video_url: &str,
+    session_id: Option<&str>,
+    track_id: usize,
+    codecs: &[String],
+    transcode: &HashMap<usize, String>,
+    combined_init: bool,
+    aac_bitrate: Option<u64>,
+) -> crate::error::Result<String> {
+    let mut audio = index
+        .audio_streams
+        .iter()
+        .find(|a| a.stream_index == track_id)
+        .cloned()
+        .ok_or_else(|| {
+            crate::error::HlsError::StreamNotFound(format!("audio track {}", track_id))
+        })?;
+
+    if cfg!(feature = "transcode") {
+        if let Some(codec) = transcode.get(&track_id) {
+            audio.transcode_to = codec_id(codec);
+        } else if !codecs.is_empty() {
+            let wanted: Vec<_> = codecs.iter().filter_map(|c| codec_id(c)).collect();
+            if !wanted.contains(&audio.codec_id) && wanted.contains(&ffmpeg::codec::Id::AAC) {
+                audio.transcode_to = Some(ffmpeg::codec::Id::AAC);
+            }
+        }
+    }
+
+    if audio.transcode_to.is_some()
+        && !crate::ffmpeg_utils::capabilities::capabilities().can_transcode_audio(audio.codec_id)
+    {
+        return Err(
+            crate::error::FfmpegError::DecoderNotFound(audio.codec_id.name().to_string()).into(),
+        );
+    }
+    if let Some(bitrate) =
+        aac_bitrate.filter(|_| audio.transcode_to == Some(ffmpeg::codec::Id::AAC))
+    {
+        audio.bitrate = bitrate;
+    }
+
+    let codec = audio.transcode_to.unwrap_or(audio.codec_id);
+    let group_id = format!("audio-{}", codec_name_short(codec).unwrap_or("aac"));
+    let language = audio.language.as_deref().unwrap_or("und");
+    let name = if language == "und" {
+        codec_label(codec).to_string()
+    } else {
+        format!("{} {}", language.to_uppercase(), codec_label(codec))
+    };
+
+    let uri = crate::params::HlsParams {
+        video_url: video_url.to_string(),
+        session_id: session_id.map(|s| s.to_string()),
+        url_type: crate::params::UrlType::Playlist(crate::params::Playlist {
+            track_id,
+            audio_track_id: None,
+            audio_transcode_to: transcode_target(&audio, aac_bitrate),
+            combined_init,
+            long_segments: true,
+            subtitle_timestamps: None,
+        }),
+    }
+    .encode_url();
+
+    let mut output = String::new();
+    output.push_str("#EXTM3U\n");
+    output.push_str(&format!(
+        "#EXT-X-VERSION:{}\n",
+        compatibility_profile().hls_version()
+    ));
+    output.push('\n');
+    output.push_str("# Audio Track\n");
+    output.push_str(&format!(
+        "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"{}\",LANGUAGE=\"{}\",NAME=\"{}\",DEFAULT=YES,AUTOSELECT=YES,URI=\"{}\"\n",
+        group_id,
+        to_rfc5646(language),
+        name,
+        uri
+    ));
+    output.push('\n');
+    output.push_str("# Audio Variant\n");
+    output.push_str(&format!(
+        "#EXT-X-STREAM-INF:BANDWIDTH={}{},AUDIO=\"{}\",CODECS=\"{}\"\n",
+        calculate_bandwidth(0, (audio.bitrate as u32).max(64_000)),
+        if audio.bitrate > 0 {
+            format!(",AVERAGE-BANDWIDTH={}", audio.bitrate)
+        } else {
+            String::new()
+        },
+        group_id,
+        audio_codec_string(&audio)
+    ));
+    output.push_str(&format!("{}\n", uri));
+
+    Ok(output)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::media::{AudioStreamInfo, SubtitleFormat, SubtitleStreamInfo, VideoStreamInfo};
+    use crate::tests::golden::attribute;
     use ffmpeg_next as ffmpeg;
     use std::path::PathBuf;
 
@@ -407,6 +765,9 @@ mod tests {
             language: None,
             profile: None,
             level: None,
+            codec_string: None,
+            nal_format: Default::default(),
+            stats: None,
         });
 
         index.audio_streams.push(AudioStreamInfo {
@@ -417,6 +778,8 @@ mod tests {
             bitrate: 128000,
             language: Some("en".to_string()),
             transcode_to: None,
+            mix_with: None,
+            codec_string: None,
             encoder_delay: 0,
         });
 
@@ -440,7 +803,7 @@ mod tests {
             &[],
             &tracks,
             &HashMap::new(),
-            false,
+            &MasterOptions::default(),
         );
 
         assert!(playlist.contains("#EXTM3U"));
@@ -467,7 +830,7 @@ mod tests {
             &[],
             &tracks,
             &HashMap::new(),
-            false,
+            &MasterOptions::default(),
         );
 
         assert!(playlist.contains("TYPE=AUDIO"));
@@ -503,12 +866,27 @@ mod tests {
             &[],
             &tracks,
             &HashMap::new(),
-            false,
+            &MasterOptions::default(),
         );
 
         assert!(playlist.contains("TYPE=SUBTITLES"));
         assert!(playlist.contains("video.mp4/t.2.m3u8"));
         assert!(playlist.contains("CODECS=\"avc1.640028,mp4a.40.2,wvtt\""));
+
+        let playlist = generate_master_playlist(
+            &index,
+            "video.mp4",
+            None,
+            &[],
+            &tracks,
+            &HashMap::new(),
+            &MasterOptions {
+                subtitle_timestamps: Some(crate::params::SubtitleTimestamps::Mpegts),
+                ..Default::default()
+            },
+        );
+        assert!(playlist.contains("video.mp4/t.2.mpegts.m3u8"));
+        assert!(playlist.contains("video.mp4/t.0.m3u8"));
     }
 
     #[test]
@@ -527,7 +905,10 @@ mod tests {
             &[],
             &tracks,
             &HashMap::new(),
-            true,
+            &MasterOptions {
+                interleaved: true,
+                ..Default::default()
+            },
         );
 
         assert!(playlist.contains("#EXTM3U"));
@@ -540,6 +921,169 @@ mod tests {
         assert!(!playlist.contains("TYPE=AUDIO")); // No separate audio entries
     }
 
+    #[test]
+    fn test_interleaved_fallback() {
+        let index = create_test_index();
+        let tracks: HashSet<usize> = index
+            .video_streams
+            .iter()
+            .map(|v| v.stream_index)
+            .chain(index.audio_streams.iter().map(|a| a.stream_index))
+            .collect();
+        let playlist = generate_master_playlist(
+            &index,
+            "video.mp4",
+            None,
+            &[],
+            &tracks,
+            &HashMap::new(),
+            &MasterOptions {
+                interleaved_fallback: true,
+                ..Default::default()
+            },
+        );
+
+        // The separate audio rendition and its variant come first, then the
+        // muxed variant.
+        assert!(playlist.contains("TYPE=AUDIO"));
+        let demuxed = playlist.find("\nvideo.mp4/t.0.m3u8").unwrap();
+        let muxed = playlist.find("\nvideo.mp4/t.0+1.m3u8").unwrap();
+        assert!(demuxed < muxed);
+        let stream_infs: Vec<_> = playlist
+            .lines()
+            .filter(|l| l.starts_with("#EXT-X-STREAM-INF"))
+            .collect();
+        assert_eq!(stream_infs.len(), 2);
+        assert!(stream_infs[0].contains("AUDIO=\"audio-aac\""));
+        assert!(!stream_infs[1].contains("AUDIO="));
+    }
+
+    #[test]
+    fn test_preferred_languages() {
+        let mut index = create_test_index();
+        let mut dutch = index.audio_streams[0].clone();
+        dutch.stream_index = 2;
+        dutch.language = Some("dut".to_string());
+        index.audio_streams.push(dutch);
+        let mut german = index.audio_streams[0].clone();
+        german.stream_index = 3;
+        german.language = Some("ger".to_string());
+        index.audio_streams.push(german);
+        let tracks: HashSet<usize> = [0, 1, 2, 3].into();
+        let generate = |languages: &[String]| {
+            generate_master_playlist(
+                &index,
+                "video.mp4",
+                None,
+                &[],
+                &tracks,
+                &HashMap::new(),
+                &MasterOptions {
+                    languages,
+                    ..Default::default()
+                },
+            )
+        };
+        let default_audio = |playlist: &str| {
+            playlist
+                .lines()
+                .find(|l| l.starts_with("#EXT-X-MEDIA:TYPE=AUDIO") && l.contains("DEFAULT=YES"))
+                .and_then(|l| attribute(l, "LANGUAGE"))
+                .map(|l| l.to_string())
+        };
+
+        // Source order without preferences.
+        assert_eq!(default_audio(&generate(&[])).as_deref(), Some("en"));
+        let languages = ["de-DE".to_string(), "nl-NL".to_string()];
+        let playlist = generate(&languages);
+        assert_eq!(default_audio(&playlist).as_deref(), Some("de"));
+        let order: Vec<_> = playlist
+            .lines()
+            .filter(|l| l.starts_with("#EXT-X-MEDIA:TYPE=AUDIO"))
+            .filter_map(|l| attribute(l, "LANGUAGE"))
+            .collect();
+        assert_eq!(order, ["de", "nl", "en"]);
+    }
+
+    #[test]
+    fn test_cap_video_tracks() {
+        let mut index = create_test_index();
+        let mut uhd = index.video_streams[0].clone();
+        uhd.stream_index = 2;
+        uhd.width = 3840;
+        uhd.height = 2160;
+        uhd.bitrate = 20_000_000;
+        index.video_streams.insert(0, uhd);
+        let tracks: HashSet<usize> = [0, 1, 2].into();
+
+        assert_eq!(
+            cap_video_tracks(&index, &tracks, None, None).unwrap(),
+            tracks
+        );
+        let capped = cap_video_tracks(&index, &tracks, Some(1080), None).unwrap();
+        assert_eq!(capped, HashSet::from([0, 1]));
+        let capped = cap_video_tracks(&index, &tracks, None, Some(8_000_000)).unwrap();
+        assert_eq!(capped, HashSet::from([0, 1]));
+
+        // The 1080p track becomes the primary video.
+        let playlist = generate_master_playlist(
+            &index,
+            "video.mp4",
+            None,
+            &[],
+            &capped,
+            &HashMap::new(),
+            &MasterOptions::default(),
+        );
+        assert!(playlist.contains("RESOLUTION=1920x1080"));
+        assert!(!playlist.contains("3840x2160"));
+
+        // Nothing fits: no video transcoding to fall back to.
+        assert!(cap_video_tracks(&index, &tracks, Some(720), None).is_err());
+        // Audio-only selections are not affected.
+        let audio: HashSet<usize> = [1].into();
+        assert_eq!(
+            cap_video_tracks(&index, &audio, Some(720), None).unwrap(),
+            audio
+        );
+    }
+
+    #[test]
+    fn test_generate_master_playlist_interleaved_multiple_audio() {
+        let mut index = create_test_index();
+        let mut ac3 = index.audio_streams[0].clone();
+        ac3.stream_index = 2;
+        ac3.codec_id = ffmpeg::codec::Id::AC3;
+        ac3.language = Some("de".to_string());
+        index.audio_streams.push(ac3);
+
+        let tracks: HashSet<usize> = [0, 1, 2].into();
+        let transcode: HashMap<usize, String> = [(2, "aac".to_string())].into();
+        let playlist = generate_master_playlist(
+            &index,
+            "video.mp4",
+            None,
+            &[],
+            &tracks,
+            &transcode,
+            &MasterOptions {
+                interleaved: true,
+                ..Default::default()
+            },
+        );
+
+        // One muxed variant per audio track, in source order.
+        assert_eq!(playlist.matches("#EXT-X-STREAM-INF").count(), 2);
+        let en = playlist.find("video.mp4/t.0+1.m3u8").unwrap();
+        let de = if cfg!(feature = "transcode") {
+            playlist.find("video.mp4/t.0+2-aac.m3u8").unwrap()
+        } else {
+            playlist.find("video.mp4/t.0+2.m3u8").unwrap()
+        };
+        assert!(en < de);
+        assert!(!playlist.contains("TYPE=AUDIO"));
+    }
+
     #[test]
     fn test_generate_master_playlist_interleaved_with_subtitles() {
         let mut index = create_test_index();
@@ -568,7 +1112,10 @@ mod tests {
             &[],
             &tracks,
             &HashMap::new(),
-            true,
+            &MasterOptions {
+                interleaved: true,
+                ..Default::default()
+            },
         );
 
         assert!(playlist.contains("#EXTM3U"));
@@ -590,8 +1137,18 @@ mod tests {
             .chain(index.audio_streams.iter().map(|a| a.stream_index))
             .collect();
         let transcode: HashMap<usize, String> = [(1, "aac".to_string())].into();
-        let playlist =
-            generate_master_playlist(&index, "video.mp4", None, &[], &tracks, &transcode, true);
+        let playlist = generate_master_playlist(
+            &index,
+            "video.mp4",
+            None,
+            &[],
+            &tracks,
+            &transcode,
+            &MasterOptions {
+                interleaved: true,
+                ..Default::default()
+            },
+        );
 
         assert!(playlist.contains("#EXTM3U"));
         assert!(playlist.contains("#EXT-X-VERSION:7"));
@@ -602,4 +1159,325 @@ mod tests {
         assert!(playlist.contains("CODECS=\"avc1.640028,mp4a.40.2\""));
         assert!(!playlist.contains("TYPE=AUDIO")); // No separate audio entries
     }
+
+    #[test]
+    #[cfg(feature = "transcode")]
+    fn test_aac_alternates() {
+        let mut index = create_test_index();
+        index.audio_streams.push(AudioStreamInfo {
+            stream_index: 2,
+            codec_id: ffmpeg::codec::Id::AC3,
+            channels: 6,
+            bitrate: 640000,
+            ..index.audio_streams[0].clone()
+        });
+        let tracks: HashSet<usize> = [0, 1, 2].into();
+        let playlist = generate_master_playlist(
+            &index,
+            "video.mp4",
+            None,
+            &[],
+            &tracks,
+            &HashMap::new(),
+            &MasterOptions {
+                aac_alternates: true,
+                ..Default::default()
+            },
+        );
+
+        // The AC-3 track is in its own group, and also in the AAC group,
+        // under a NAME that is unique in that group.
+        assert!(playlist.contains(
+            "GROUP-ID=\"audio-ac3\",LANGUAGE=\"en\",NAME=\"EN Dolby Digital\",DEFAULT=YES,AUTOSELECT=YES,URI=\"video.mp4/t.2.m3u8\""
+        ));
+        assert!(playlist.contains(
+            "GROUP-ID=\"audio-aac\",LANGUAGE=\"en\",NAME=\"EN AAC\",DEFAULT=YES,AUTOSELECT=YES,URI=\"video.mp4/t.1.m3u8\""
+        ));
+        assert!(playlist.contains(
+            "GROUP-ID=\"audio-aac\",LANGUAGE=\"en\",NAME=\"EN AAC 2\",DEFAULT=NO,AUTOSELECT=YES,URI=\"video.mp4/t.2-aac.m3u8\""
+        ));
+        assert!(playlist.contains("AUDIO=\"audio-aac\""));
+        assert!(playlist.contains("AUDIO=\"audio-ac3\""));
+
+        // AAC tracks get no alternate.
+        assert!(!playlist.contains("t.1-aac.m3u8"));
+    }
+
+    #[test]
+    #[cfg(feature = "transcode")]
+    fn test_audio_bitrate() {
+        let mut index = create_test_index();
+        index.audio_streams.push(AudioStreamInfo {
+            stream_index: 2,
+            codec_id: ffmpeg::codec::Id::AC3,
+            channels: 6,
+            bitrate: 640000,
+            ..index.audio_streams[0].clone()
+        });
+        let tracks: HashSet<usize> = [0, 1, 2].into();
+        let transcode: HashMap<usize, String> = [(2, "aac".to_string())].into();
+        let playlist = generate_master_playlist(
+            &index,
+            "video.mp4",
+            None,
+            &[],
+            &tracks,
+            &transcode,
+            &MasterOptions {
+                interleaved: true,
+                aac_bitrate: Some(96_000),
+                ..Default::default()
+            },
+        );
+
+        // Only the transcoded track is at the requested bitrate.
+        assert!(playlist.contains("AVERAGE-BANDWIDTH=5096000,"));
+        assert!(playlist.contains("video.mp4/t.0+2-aac96.m3u8"));
+        assert!(playlist.contains("AVERAGE-BANDWIDTH=5128000,"));
+        assert!(playlist.contains("video.mp4/t.0+1.m3u8"));
+
+        let playlist = generate_audio_master_playlist(
+            &index,
+            "video.mp4",
+            None,
+            2,
+            &[],
+            &transcode,
+            false,
+            Some(96_000),
+        )
+        .unwrap();
+        assert!(playlist.contains("AVERAGE-BANDWIDTH=96000,"));
+        assert!(playlist.contains("video.mp4/t.2-aac96.m3u8"));
+    }
+
+    #[test]
+    #[cfg(feature = "transcode")]
+    fn test_audio_mix() {
+        let mut index = create_test_index();
+        index.audio_streams.push(AudioStreamInfo {
+            stream_index: 2,
+            ..index.audio_streams[0].clone()
+        });
+        let tracks: HashSet<usize> = [0, 1, 2].into();
+        let generate = |interleaved, audio_mix| {
+            generate_master_playlist(
+                &index,
+                "video.mp4",
+                None,
+                &[],
+                &tracks,
+                &HashMap::new(),
+                &MasterOptions {
+                    interleaved,
+                    audio_mix,
+                    ..Default::default()
+                },
+            )
+        };
+
+        // The mix is listed after the tracks it is made of.
+        let playlist = generate(false, Some((1, 2)));
+        let mix = playlist
+            .lines()
+            .find(|l| l.contains("video.mp4/t.1-aacm2.m3u8"))
+            .unwrap();
+        assert!(mix.contains("GROUP-ID=\"audio-aac\""));
+        assert!(mix.contains(" Mix\",DEFAULT=NO"));
+        assert_eq!(playlist.matches("TYPE=AUDIO").count(), 3);
+
+        // Not as a muxed variant, and not with a track that doesn't exist.
+        assert!(!generate(true, Some((1, 2))).contains("aacm2"));
+        assert!(!generate(false, Some((1, 3))).contains("aacm3"));
+    }
+
+    #[test]
+    fn test_start_offset() {
+        let index = create_test_index();
+        let tracks: HashSet<usize> = [0, 1].into();
+        let generate = |start_offset| {
+            generate_master_playlist(
+                &index,
+                "video.mp4",
+                None,
+                &[],
+                &tracks,
+                &HashMap::new(),
+                &MasterOptions {
+                    start_offset,
+                    ..Default::default()
+                },
+            )
+        };
+        assert!(!generate(None).contains("#EXT-X-START"));
+        let playlist = generate(Some(754.5));
+        assert!(
+            playlist.contains("#EXT-X-VERSION:7\n#EXT-X-START:TIME-OFFSET=754.500,PRECISE=YES\n")
+        );
+    }
+
+    #[test]
+    fn test_frame_rate_and_hdcp() {
+        let index = create_test_index();
+        let tracks: HashSet<usize> = [0, 1].into();
+        let playlist = generate_master_playlist(
+            &index,
+            "video.mp4",
+            None,
+            &[],
+            &tracks,
+            &HashMap::new(),
+            &MasterOptions {
+                hdcp_level: Some("TYPE-0"),
+                ..Default::default()
+            },
+        );
+        assert!(
+            playlist.contains("RESOLUTION=1920x1080,FRAME-RATE=30.000,HDCP-LEVEL=TYPE-0,AUDIO=")
+        );
+    }
+
+    #[test]
+    fn test_combined_init() {
+        let index = create_test_index();
+        let tracks: HashSet<usize> = [0, 1].into();
+        let playlist = generate_master_playlist(
+            &index,
+            "video.mp4",
+            None,
+            &[],
+            &tracks,
+            &HashMap::new(),
+            &MasterOptions {
+                combined_init: true,
+                ..Default::default()
+            },
+        );
+        assert!(playlist.contains("video.mp4/t.0.hdr.m3u8"));
+        assert!(playlist.contains("video.mp4/t.1.hdr.m3u8"));
+        assert!(!playlist.contains("video.mp4/t.0.m3u8"));
+    }
+
+    #[test]
+    fn test_codec_string_from_sps() {
+        let mut index = create_test_index();
+        index.video_streams[0].codec_string = Some("avc1.4d401f".to_string());
+        let tracks: HashSet<usize> = [0, 1].into();
+        let playlist = generate_master_playlist(
+            &index,
+            "video.mp4",
+            None,
+            &[],
+            &tracks,
+            &HashMap::new(),
+            &MasterOptions::default(),
+        );
+        assert!(playlist.contains("CODECS=\"avc1.4d401f,mp4a.40.2\""));
+    }
+
+    #[test]
+    fn test_he_aac_codec_string() {
+        let mut index = create_test_index();
+        index.audio_streams[0].codec_string = Some("mp4a.40.5".to_string());
+        let mut lc = index.audio_streams[0].clone();
+        lc.stream_index = 2;
+        lc.codec_string = Some("mp4a.40.2".to_string());
+        index.audio_streams.push(lc);
+        let tracks: HashSet<usize> = [0, 1, 2].into();
+        let playlist = generate_master_playlist(
+            &index,
+            "video.mp4",
+            None,
+            &[],
+            &tracks,
+            &HashMap::new(),
+            &MasterOptions::default(),
+        );
+        assert!(playlist.contains("CODECS=\"avc1.640028,mp4a.40.5,mp4a.40.2\""));
+
+        // Transcoded audio is advertised as what the encoder produces.
+        let transcode: HashMap<usize, String> = [(1, "aac".to_string())].into();
+        let playlist = generate_audio_master_playlist(
+            &index,
+            "video.mp4",
+            None,
+            1,
+            &[],
+            &transcode,
+            false,
+            None,
+        )
+        .unwrap();
+        let expected = if cfg!(feature = "transcode") {
+            "mp4a.40.2"
+        } else {
+            "mp4a.40.5"
+        };
+        assert!(playlist.contains(&format!("CODECS=\"{}\"", expected)));
+    }
+
+    #[test]
+    fn test_average_bandwidth() {
+        let index = create_test_index();
+        let tracks: HashSet<usize> = [0, 1].into();
+        let playlist = generate_master_playlist(
+            &index,
+            "video.mp4",
+            None,
+            &[],
+            &tracks,
+            &HashMap::new(),
+            &MasterOptions::default(),
+        );
+        assert!(playlist.contains("AVERAGE-BANDWIDTH=5128000,"));
+
+        // Unknown video bitrate: no AVERAGE-BANDWIDTH.
+        let mut index = create_test_index();
+        index.video_streams[0].bitrate = 0;
+        let playlist = generate_master_playlist(
+            &index,
+            "video.mp4",
+            None,
+            &[],
+            &tracks,
+            &HashMap::new(),
+            &MasterOptions::default(),
+        );
+        assert!(!playlist.contains("AVERAGE-BANDWIDTH"));
+    }
+
+    #[test]
+    fn test_generate_audio_master_playlist() {
+        let index = create_test_index();
+        let playlist = generate_audio_master_playlist(
+            &index,
+            "video.mp4",
+            None,
+            1,
+            &[],
+            &HashMap::new(),
+            false,
+            None,
+        )
+        .unwrap();
+
+        assert!(playlist.contains("TYPE=AUDIO"));
+        assert!(playlist.contains("CODECS=\"mp4a.40.2\""));
+        assert!(!playlist.contains("RESOLUTION="));
+        assert!(playlist.contains("video.mp4/t.1.long.m3u8"));
+        assert!(!playlist.contains("t.0.m3u8"));
+
+        // Not an audio track.
+        assert!(generate_audio_master_playlist(
+            &index,
+            "video.mp4",
+            None,
+            0,
+            &[],
+            &HashMap::new(),
+            false,
+            None
+        )
+        .is_err());
+    }
 }
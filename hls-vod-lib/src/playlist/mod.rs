@@ -2,13 +2,16 @@
 //!
 //! This module handles HLS playlist generation:
 //! - Master playlist (master.m3u8) with all variants
+//! - Audio-only master playlist for a single audio track
 //! - Video variant playlist (video.m3u8)
 //! - Audio variant playlists (audio_*.m3u8)
 //! - Subtitle variant playlists (sub_*.m3u8)
+//! - Delta updates of the variant playlists (EXT-X-SKIP)
 //! - Proper HLS tags and codec strings
 
 pub mod codec;
+pub mod delta;
 pub mod master;
 pub mod variant;
 
-pub use master::generate_master_playlist;
+pub use master::{generate_audio_master_playlist, generate_master_playlist, MasterOptions};
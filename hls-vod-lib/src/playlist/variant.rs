@@ -2,46 +2,434 @@
 //!
 //! Generates HLS variant playlists for video, audio, and subtitles.
 
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
 use super::codec::*;
 use crate::media::StreamIndex;
+use crate::params::{HlsParams, UrlType};
+use crate::segment::profile::compatibility_profile;
+
+// f64 bits, 0 = follow the video segments.
+static AUDIO_SEGMENT_DURATION: AtomicU64 = AtomicU64::new(0);
+
+/// Set the target duration of segments in audio-only main playlists
+/// (`video.mp4.audio.<track>.m3u8`), in seconds.
+///
+/// Audio has no GOP, so its segments can be longer than the video segments,
+/// which cuts the number of requests. Audio segments always consist of whole
+/// video segments. The audio renditions of a title with video keep the
+/// video segments, so that audio and video segments line up. The default is
+/// 0, which uses the video segments as-is.
+pub fn set_audio_segment_duration(secs: f64) {
+    AUDIO_SEGMENT_DURATION.store(secs.max(0.0).to_bits(), Ordering::Relaxed);
+}
+
+pub(crate) fn audio_segment_duration() -> f64 {
+    f64::from_bits(AUDIO_SEGMENT_DURATION.load(Ordering::Relaxed))
+}
+
+// 0 = no budget.
+static PLAYLIST_BYTE_BUDGET: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the maximum size of a variant playlist, in bytes.
+///
+/// Some embedded players choke on playlists of several megabytes, which a
+/// long file with signed segment URLs easily gets. If a playlist of a title
+/// is over the budget, every playlist of the title lists runs of consecutive
+/// segments as one longer segment, the runs as long as needed for the
+/// largest one to fit. The default is 0, no budget.
+pub fn set_playlist_byte_budget(bytes: usize) {
+    PLAYLIST_BYTE_BUDGET.store(bytes, Ordering::Relaxed);
+}
+
+pub(crate) fn playlist_byte_budget() -> usize {
+    PLAYLIST_BYTE_BUDGET.load(Ordering::Relaxed)
+}
+
+/// A run of consecutive segments that is served as one segment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct SegmentGroup {
+    /// First segment sequence number.
+    pub start: usize,
+    /// Last segment sequence number (inclusive).
+    pub end: usize,
+    /// Total duration in seconds.
+    pub duration_secs: f64,
+}
+
+impl SegmentGroup {
+    /// The `end_segment_id` of the URL of the group, `None` for a single
+    /// segment.
+    pub fn end_segment_id(&self) -> Option<usize> {
+        (self.end > self.start).then_some(self.end)
+    }
+}
+
+/// Group segments into segments of about `target_secs`, 0 for one group
+/// per segment.
+///
+/// A group is closed once it reaches 80% of the target (the same threshold
+/// the scanner uses), and never spans a discontinuity.
+pub(crate) fn segment_groups(index: &StreamIndex, target_secs: f64) -> Vec<SegmentGroup> {
+    let mut groups: Vec<SegmentGroup> = Vec::new();
+    let mut open = false;
+    for segment in &index.segments {
+        match groups.last_mut() {
+            Some(group) if open && !index.is_discontinuity(segment.sequence) => {
+                group.end = segment.sequence;
+                group.duration_secs += segment.duration_secs;
+            }
+            _ => groups.push(SegmentGroup {
+                start: segment.sequence,
+                end: segment.sequence,
+                duration_secs: segment.duration_secs,
+            }),
+        }
+        open = groups.last().unwrap().duration_secs < target_secs * 0.8;
+    }
+    groups
+}
+
+/// Maximum duration of one entry for a run of empty subtitle segments.
+const MAX_EMPTY_SUBTITLE_DURATION: f64 = 30.0;
+
+/// A run of consecutive segments in a subtitle playlist.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SubtitleSegmentGroup {
+    /// First segment sequence number.
+    start: usize,
+    /// Last segment sequence number (inclusive).
+    end: usize,
+    /// Total duration in seconds.
+    duration_secs: f64,
+    /// No subtitle cue starts in these segments.
+    empty: bool,
+}
+
+/// Group the segments of subtitle track `track_index`.
+///
+/// Segments with cues are listed one by one, or in runs of about
+/// `target_secs` if that is not 0. Runs of segments without cues
+/// are coalesced into entries of up to `MAX_EMPTY_SUBTITLE_DURATION`, which
+/// all point at the shared `s/empty.vtt`, so that a player can serve them
+/// from its cache. Runs never span a discontinuity, or the subtitle timeline
+/// would get out of step with the audio and video variants.
+///
+/// With `repeat_spanning` (`SpanningCues::Repeat`) a cue is also written to
+/// the segments it spans into. Cue durations are not indexed, so then only
+/// the segments before the first cue are known to be empty.
+fn subtitle_segment_groups(
+    index: &StreamIndex,
+    track_index: usize,
+    target_secs: f64,
+    repeat_spanning: bool,
+) -> Vec<SubtitleSegmentGroup> {
+    let sub_info = index
+        .subtitle_streams
+        .iter()
+        .find(|s| s.stream_index == track_index);
+    // The scanner lists every segment in which a cue starts.
+    let is_empty = |sequence: usize| {
+        sub_info
+            .map(|info| {
+                let cues = &info.non_empty_sequences;
+                let spanned = repeat_spanning && cues.first().is_some_and(|&f| sequence > f);
+                cues.binary_search(&sequence).is_err() && !spanned
+            })
+            .unwrap_or(false)
+    };
+
+    let max_empty_secs = MAX_EMPTY_SUBTITLE_DURATION.max(target_secs);
+    let mut groups: Vec<SubtitleSegmentGroup> = Vec::new();
+    for segment in &index.segments {
+        let empty = is_empty(segment.sequence);
+        match groups.last_mut() {
+            Some(group)
+                if empty
+                    && group.empty
+                    && !index.is_discontinuity(segment.sequence)
+                    && group.duration_secs + segment.duration_secs <= max_empty_secs =>
+            {
+                group.end = segment.sequence;
+                group.duration_secs += segment.duration_secs;
+            }
+            Some(group)
+                if !empty
+                    && !group.empty
+                    && !index.is_discontinuity(segment.sequence)
+                    && group.duration_secs < target_secs * 0.8 =>
+            {
+                group.end = segment.sequence;
+                group.duration_secs += segment.duration_secs;
+            }
+            _ => groups.push(SubtitleSegmentGroup {
+                start: segment.sequence,
+                end: segment.sequence,
+                duration_secs: segment.duration_secs,
+                empty,
+            }),
+        }
+    }
+    groups
+}
+
+/// Encode the URL of a segment, relative to the variant playlist.
+fn segment_uri(video_url: &str, session_id: Option<&str>, url_type: UrlType) -> String {
+    HlsParams {
+        url_type,
+        session_id: session_id.map(|s| s.to_string()),
+        video_url: video_url.to_string(),
+    }
+    .encode_url()
+}
+
+/// Write `EXT-X-DISCONTINUITY-SEQUENCE` if the timeline has discontinuities.
+///
+/// A VOD playlist always starts with its first segment, so the sequence is 0.
+/// All variants are generated from the same segment list, so they share the
+/// discontinuity markers and stay aligned.
+fn write_discontinuity_sequence(output: &mut String, index: &StreamIndex) {
+    if !index.discontinuities.is_empty() {
+        output.push_str("#EXT-X-DISCONTINUITY-SEQUENCE:0\n");
+    }
+}
+
+/// `EXT-X-VERSION` required by `EXT-X-GAP`.
+const GAP_VERSION: u32 = 8;
+
+/// The `EXT-X-VERSION` of a media playlist of `index`: that of the
+/// compatibility profile, or higher if the playlist has `EXT-X-GAP` tags.
+fn playlist_version(index: &StreamIndex) -> u32 {
+    let version = compatibility_profile().hls_version();
+    if index.has_gaps() {
+        return version.max(GAP_VERSION);
+    }
+    version
+}
+
+/// Write `EXT-X-DISCONTINUITY` if segment `sequence` starts a new timeline.
+fn write_discontinuity(output: &mut String, index: &StreamIndex, sequence: usize) {
+    if index.is_discontinuity(sequence) {
+        output.push_str("#EXT-X-DISCONTINUITY\n");
+    }
+}
+
+/// Write the tags of the entry of `group`: `EXT-X-DISCONTINUITY` if it
+/// starts a new timeline, `EXTINF`, and `EXT-X-GAP` if one of its segments
+/// failed to generate.
+fn write_group(output: &mut String, index: &StreamIndex, group: &SegmentGroup) {
+    write_discontinuity(output, index, group.start);
+    write_extinf(output, group.duration_secs);
+    if (group.start..=group.end).any(|sequence| index.is_gap(sequence)) {
+        output.push_str("#EXT-X-GAP\n");
+    }
+}
+
+/// Write `EXT-X-INDEPENDENT-SEGMENTS` if every segment starts with a keyframe.
+fn write_independent_segments(output: &mut String, index: &StreamIndex) {
+    if index.all_segments_independent() {
+        output.push_str("#EXT-X-INDEPENDENT-SEGMENTS\n");
+    }
+}
+
+/// Write `EXTINF`, with millisecond precision.
+fn write_extinf(output: &mut String, duration_secs: f64) {
+    output.push_str(&format!("#EXTINF:{:.3},\n", duration_secs));
+}
+
+/// Write `EXT-X-MAP`.
+///
+/// Normally it points to the separate init segment `init`. With `init_len`
+/// it is a byterange of the first media segment `first` instead, which is
+/// then served with the init segment prepended.
+fn write_map(
+    output: &mut String,
+    video_url: &str,
+    session_id: Option<&str>,
+    init: UrlType,
+    first: Option<UrlType>,
+    init_len: Option<usize>,
+) {
+    match (init_len, first) {
+        (Some(len), Some(first)) => output.push_str(&format!(
+            "#EXT-X-MAP:URI=\"{}\",BYTERANGE=\"{}@0\"\n",
+            segment_uri(video_url, session_id, first),
+            len
+        )),
+        _ => output.push_str(&format!(
+            "#EXT-X-MAP:URI=\"{}\"\n",
+            segment_uri(video_url, session_id, init)
+        )),
+    }
+}
+
+/// The codec to transcode audio track `track_index` to: the requested one,
+/// or the one the track was marked for in the master playlist.
+pub(crate) fn audio_transcode_to(
+    index: &StreamIndex,
+    track_index: usize,
+    requested: Option<&str>,
+) -> Option<String> {
+    requested.map(String::from).or_else(|| {
+        index
+            .get_audio_stream(track_index)
+            .ok()
+            .and_then(|s| s.transcode_to)
+            .and_then(codec_name_short)
+            .map(String::from)
+    })
+}
+
+/// The duration of the runs of consecutive segments that every variant
+/// playlist of `index` lists as one segment, to stay within `budget` bytes.
+/// 0 if the playlists fit as they are. See `set_playlist_byte_budget()`.
+///
+/// The runs must be the same in every rendition, or the segments of the
+/// audio and the video no longer line up. So the duration is that at which
+/// the largest rendition fits, see `largest_rendition()`.
+///
+/// The first try lists every segment. If that is over the budget, the runs
+/// get longer in proportion to the excess until it fits. If it does not fit
+/// with one run per timeline, that is what is used.
+fn title_group_secs(
+    index: &StreamIndex,
+    video_url: &str,
+    session_id: Option<&str>,
+    budget: usize,
+) -> f64 {
+    if budget == 0 || index.segments.is_empty() {
+        return 0.0;
+    }
+    let size = |group_secs| largest_rendition(index, video_url, session_id, group_secs);
+    let mut len = size(0.0);
+    if len <= budget {
+        return 0.0;
+    }
+    let total_secs: f64 = index.segments.iter().map(|s| s.duration_secs).sum();
+    let mut group_secs = total_secs / index.segments.len() as f64;
+    let full_len = len;
+    while len > budget && group_secs < total_secs {
+        // Runs are closed at 80% of their target, see `segment_groups()`.
+        group_secs *= len as f64 / budget as f64 / 0.8;
+        len = size(group_secs);
+    }
+    if len > budget {
+        tracing::warn!(
+            "{}: playlist of {} bytes does not fit the budget of {} bytes",
+            index.source_path.display(),
+            len,
+            budget
+        );
+    } else {
+        tracing::debug!(
+            "{}: playlist of {} bytes over budget, using segments of about {:.0}s",
+            index.source_path.display(),
+            full_len,
+            group_secs
+        );
+    }
+    group_secs
+}
+
+/// Size in bytes of the largest variant playlist of `index` with runs of
+/// about `group_secs`: that of the video, of an audio track, or of an
+/// interleaved variant, whose segment URLs are the longest.
+fn largest_rendition(
+    index: &StreamIndex,
+    video_url: &str,
+    session_id: Option<&str>,
+    group_secs: f64,
+) -> usize {
+    let video = index.primary_video().map(|v| v.stream_index);
+    let mut size = match video {
+        Some(_) => video_playlist(index, video_url, session_id, None, group_secs).len(),
+        None => 0,
+    };
+    for audio in &index.audio_streams {
+        let track = audio.stream_index;
+        let audio = audio_playlist(index, video_url, session_id, track, None, None, group_secs);
+        size = size.max(audio.len());
+        if let Some(video) = video {
+            let interleaved = interleaved_playlist(
+                index, video_url, session_id, video, track, None, None, group_secs,
+            );
+            size = size.max(interleaved.len());
+        }
+    }
+    size
+}
 
 /// Generate video variant playlist
 ///
-/// Creates video.m3u8 with segment references
-pub(crate) fn generate_video_playlist(index: &StreamIndex) -> String {
+/// Creates video.m3u8 with segment references. With `init_len` (the length
+/// of the init segment), the init segment is a byterange of the first segment.
+pub(crate) fn generate_video_playlist(
+    index: &StreamIndex,
+    video_url: &str,
+    session_id: Option<&str>,
+    init_len: Option<usize>,
+) -> String {
+    let group_secs = title_group_secs(index, video_url, session_id, playlist_byte_budget());
+    video_playlist(index, video_url, session_id, init_len, group_secs)
+}
+
+/// The video variant playlist, with segments grouped into runs of about
+/// `group_secs`.
+fn video_playlist(
+    index: &StreamIndex,
+    video_url: &str,
+    session_id: Option<&str>,
+    init_len: Option<usize>,
+    group_secs: f64,
+) -> String {
     let mut output = String::new();
 
-    // Calculate target duration
-    let target_duration = calculate_target_duration(&index.segments);
+    let groups = segment_groups(index, group_secs);
+    let target_duration = media_target_duration(index, group_secs);
 
     // Header
     output.push_str("#EXTM3U\n");
-    output.push_str("#EXT-X-VERSION:7\n");
+    output.push_str(&format!("#EXT-X-VERSION:{}\n", playlist_version(index)));
     output.push_str(&format!("#EXT-X-TARGETDURATION:{}\n", target_duration));
     output.push_str("#EXT-X-MEDIA-SEQUENCE:0\n");
+    write_discontinuity_sequence(&mut output, index);
     output.push_str("#EXT-X-PLAYLIST-TYPE:VOD\n");
-    output.push_str("#EXT-X-INDEPENDENT-SEGMENTS\n");
+    write_independent_segments(&mut output, index);
     let video_index = index.primary_video().map(|v| v.stream_index).unwrap_or(0);
-    let init_seg = crate::params::VideoSegment {
-        track_id: video_index,
-        audio_track_id: None,
-        audio_transcode_to: None,
-        segment_id: None,
+    let seg = |segment_id, end_segment_id, with_init| {
+        UrlType::VideoSegment(crate::params::VideoSegment {
+            track_id: video_index,
+            audio_track_id: None,
+            audio_transcode_to: None,
+            segment_id,
+            start_ms: None,
+            end_segment_id,
+            with_init,
+        })
     };
     // EXT-X-MAP points to video init segment
-    output.push_str(&format!("#EXT-X-MAP:URI=\"{}\"\n", init_seg));
+    let first = groups
+        .first()
+        .map(|g| seg(Some(g.start), g.end_segment_id(), true));
+    write_map(
+        &mut output,
+        video_url,
+        session_id,
+        seg(None, None, false),
+        first,
+        init_len,
+    );
     output.push('\n');
 
     // Generate segment entries
-    for segment in &index.segments {
-        let seg = crate::params::VideoSegment {
-            track_id: video_index,
-            audio_track_id: None,
-            audio_transcode_to: None,
-            segment_id: Some(segment.sequence),
-        };
-        output.push_str(&format!("#EXTINF:{:.3},\n", segment.duration_secs));
-        output.push_str(&format!("{}\n", seg));
+    for (i, group) in groups.iter().enumerate() {
+        let seg = seg(
+            Some(group.start),
+            group.end_segment_id(),
+            init_len.is_some() && i == 0,
+        );
+        write_group(&mut output, index, group);
+        output.push_str(&format!("{}\n", segment_uri(video_url, session_id, seg)));
     }
 
     // End list
@@ -52,53 +440,94 @@ pub(crate) fn generate_video_playlist(index: &StreamIndex) -> String {
 
 /// Generate audio variant playlist
 ///
-/// Creates a/<track_index>.m3u8 with segment references
+/// Creates a/<track_index>.m3u8 with segment references. With
+/// `long_segments`, segments are grouped into longer audio segments, see
+/// `set_audio_segment_duration()`.
 pub(crate) fn generate_audio_playlist(
     index: &StreamIndex,
+    video_url: &str,
+    session_id: Option<&str>,
+    track_index: usize,
+    requested_transcode: Option<&str>,
+    init_len: Option<usize>,
+    long_segments: bool,
+) -> String {
+    let mut group_secs = title_group_secs(index, video_url, session_id, playlist_byte_budget());
+    if long_segments {
+        group_secs = group_secs.max(audio_segment_duration());
+    }
+    audio_playlist(
+        index,
+        video_url,
+        session_id,
+        track_index,
+        requested_transcode,
+        init_len,
+        group_secs,
+    )
+}
+
+/// The audio variant playlist, with segments grouped into runs of about
+/// `group_secs`.
+fn audio_playlist(
+    index: &StreamIndex,
+    video_url: &str,
+    session_id: Option<&str>,
     track_index: usize,
     requested_transcode: Option<&str>,
+    init_len: Option<usize>,
+    group_secs: f64,
 ) -> String {
     let mut output = String::new();
 
-    // Calculate target duration
-    let target_duration = calculate_target_duration(&index.segments);
+    let groups = segment_groups(index, group_secs);
+    let target_duration = media_target_duration(index, group_secs);
 
     // Header
     output.push_str("#EXTM3U\n");
-    output.push_str("#EXT-X-VERSION:7\n");
+    output.push_str(&format!("#EXT-X-VERSION:{}\n", playlist_version(index)));
     output.push_str(&format!("#EXT-X-TARGETDURATION:{}\n", target_duration));
     output.push_str("#EXT-X-MEDIA-SEQUENCE:0\n");
+    write_discontinuity_sequence(&mut output, index);
     output.push_str("#EXT-X-PLAYLIST-TYPE:VOD\n");
+    // Audio frames are all sync samples.
     output.push_str("#EXT-X-INDEPENDENT-SEGMENTS\n");
 
-    let transcode_to = requested_transcode.map(String::from).or_else(|| {
-        index
-            .get_audio_stream(track_index)
-            .ok()
-            .and_then(|s| s.transcode_to)
-            .and_then(codec_name_short)
-            .map(String::from)
-    });
-
-    let init_seg = crate::params::AudioSegment {
-        track_id: track_index,
-        transcode_to: transcode_to.clone(),
-        segment_id: None,
+    let transcode_to = audio_transcode_to(index, track_index, requested_transcode);
+    let seg = |segment_id, end_segment_id, with_init| {
+        UrlType::AudioSegment(crate::params::AudioSegment {
+            track_id: track_index,
+            transcode_to: transcode_to.clone(),
+            segment_id,
+            start_ms: None,
+            end_segment_id,
+            with_init,
+        })
     };
 
     // EXT-X-MAP points to init segment for CMAF-style HLS
-    output.push_str(&format!("#EXT-X-MAP:URI=\"{}\"\n", init_seg));
+    let first = groups
+        .first()
+        .map(|g| seg(Some(g.start), g.end_segment_id(), true));
+    write_map(
+        &mut output,
+        video_url,
+        session_id,
+        seg(None, None, false),
+        first,
+        init_len,
+    );
     output.push('\n');
 
     // Generate segment entries
-    for segment in &index.segments {
-        let seg = crate::params::AudioSegment {
-            track_id: track_index,
-            transcode_to: transcode_to.clone(),
-            segment_id: Some(segment.sequence),
-        };
-        output.push_str(&format!("#EXTINF:{:.3},\n", segment.duration_secs));
-        output.push_str(&format!("{}\n", seg));
+    for (i, group) in groups.iter().enumerate() {
+        let seg = seg(
+            Some(group.start),
+            group.end_segment_id(),
+            init_len.is_some() && i == 0,
+        );
+        write_group(&mut output, index, group);
+        output.push_str(&format!("{}\n", segment_uri(video_url, session_id, seg)));
     }
 
     // End list
@@ -112,53 +541,89 @@ pub(crate) fn generate_audio_playlist(
 /// Creates v/<video_idx>.<audio_idx>.media.m3u8 with references to muxed A/V segments
 pub(crate) fn generate_interleaved_playlist(
     index: &StreamIndex,
+    video_url: &str,
+    session_id: Option<&str>,
     video_idx: usize,
     audio_idx: usize,
     requested_audio_transcode: Option<&str>,
+    init_len: Option<usize>,
+) -> String {
+    let group_secs = title_group_secs(index, video_url, session_id, playlist_byte_budget());
+    interleaved_playlist(
+        index,
+        video_url,
+        session_id,
+        video_idx,
+        audio_idx,
+        requested_audio_transcode,
+        init_len,
+        group_secs,
+    )
+}
+
+/// The interleaved variant playlist, with segments grouped into runs of
+/// about `group_secs`.
+#[allow(clippy::too_many_arguments)]
+fn interleaved_playlist(
+    index: &StreamIndex,
+    video_url: &str,
+    session_id: Option<&str>,
+    video_idx: usize,
+    audio_idx: usize,
+    requested_audio_transcode: Option<&str>,
+    init_len: Option<usize>,
+    group_secs: f64,
 ) -> String {
     let mut output = String::new();
 
-    // Calculate target duration
-    let target_duration = calculate_target_duration(&index.segments);
+    let groups = segment_groups(index, group_secs);
+    let target_duration = media_target_duration(index, group_secs);
 
     // Header
     output.push_str("#EXTM3U\n");
-    output.push_str("#EXT-X-VERSION:7\n");
+    output.push_str(&format!("#EXT-X-VERSION:{}\n", playlist_version(index)));
     output.push_str(&format!("#EXT-X-TARGETDURATION:{}\n", target_duration));
     output.push_str("#EXT-X-MEDIA-SEQUENCE:0\n");
+    write_discontinuity_sequence(&mut output, index);
     output.push_str("#EXT-X-PLAYLIST-TYPE:VOD\n");
-    output.push_str("#EXT-X-INDEPENDENT-SEGMENTS\n");
-
-    let audio_transcode_to = requested_audio_transcode.map(String::from).or_else(|| {
-        index
-            .get_audio_stream(audio_idx)
-            .ok()
-            .and_then(|s| s.transcode_to)
-            .and_then(codec_name_short)
-            .map(String::from)
-    });
+    write_independent_segments(&mut output, index);
 
-    let init_seg = crate::params::VideoSegment {
-        track_id: video_idx,
-        audio_track_id: Some(audio_idx),
-        audio_transcode_to: audio_transcode_to.clone(),
-        segment_id: None,
+    let audio_transcode_to = audio_transcode_to(index, audio_idx, requested_audio_transcode);
+    let seg = |segment_id, end_segment_id, with_init| {
+        UrlType::VideoSegment(crate::params::VideoSegment {
+            track_id: video_idx,
+            audio_track_id: Some(audio_idx),
+            audio_transcode_to: audio_transcode_to.clone(),
+            segment_id,
+            start_ms: None,
+            end_segment_id,
+            with_init,
+        })
     };
 
     // EXT-X-MAP points to interleaved init segment
-    output.push_str(&format!("#EXT-X-MAP:URI=\"{}\"\n", init_seg));
+    let first = groups
+        .first()
+        .map(|g| seg(Some(g.start), g.end_segment_id(), true));
+    write_map(
+        &mut output,
+        video_url,
+        session_id,
+        seg(None, None, false),
+        first,
+        init_len,
+    );
     output.push('\n');
 
     // Generate segment entries
-    for segment in &index.segments {
-        let seg = crate::params::VideoSegment {
-            track_id: video_idx,
-            audio_track_id: Some(audio_idx),
-            audio_transcode_to: audio_transcode_to.clone(),
-            segment_id: Some(segment.sequence),
-        };
-        output.push_str(&format!("#EXTINF:{:.3},\n", segment.duration_secs));
-        output.push_str(&format!("{}\n", seg));
+    for (i, group) in groups.iter().enumerate() {
+        let seg = seg(
+            Some(group.start),
+            group.end_segment_id(),
+            init_len.is_some() && i == 0,
+        );
+        write_group(&mut output, index, group);
+        output.push_str(&format!("{}\n", segment_uri(video_url, session_id, seg)));
     }
 
     // End list
@@ -170,111 +635,65 @@ pub(crate) fn generate_interleaved_playlist(
 /// Generate subtitle variant playlist
 ///
 /// Creates s/<track_index>.m3u8 with WebVTT segment references
-pub(crate) fn generate_subtitle_playlist(index: &StreamIndex, track_index: usize) -> String {
-    let mut output = String::new();
-
-    // Find the subtitle stream info to check for non-empty sequences
-    let sub_info = index
-        .subtitle_streams
-        .iter()
-        .find(|s| s.stream_index == track_index);
-
-    let _is_non_empty = |sequence: usize| -> bool {
-        if let Some(info) = sub_info {
-            // If the list is empty, it means we haven't scanned (or there are no subtitles at all).
-            // We'll be safe and include them if the list is completely empty (legacy behavior),
-            // but the scanner now populates it, so if it's empty, we drop all.
-            // Wait, if the scanner hasn't run, `non_empty_sequences` is empty.
-            // But if it has run and there are no subtitles, it's also empty.
-            // Since we populate it during indexing, it's safe to strictly require presence.
-            info.non_empty_sequences.binary_search(&sequence).is_ok()
-        } else {
-            true // Fallback if stream info not found (shouldn't happen)
-        }
-    };
-
-    // Generate segment entries merging durations of consecutive empty segments
-    // to keep the timeline consistent without using EXT-X-GAP (fixes VLC compatibility).
-    // The user requested: cap any merged segments at 30 seconds.
-    let mut merged_segments = Vec::new();
-    let mut accumulated_duration = 0.0;
-    let mut accumulated_start_seq = None;
-
-    for segment in &index.segments {
-        let is_empty = if let Some(stream_info) = index
-            .subtitle_streams
-            .iter()
-            .find(|s| s.stream_index == track_index)
-        {
-            stream_info
-                .non_empty_sequences
-                .binary_search(&segment.sequence)
-                .is_err()
-        } else {
-            false
-        };
-
-        if accumulated_start_seq.is_none() {
-            accumulated_start_seq = Some(segment.sequence);
-        }
-
-        // Check if adding this segment would exceed our 30-second cap
-        if accumulated_duration > 0.0 && accumulated_duration + segment.duration_secs > 30.0 {
-            let start_s = accumulated_start_seq.unwrap();
-            let end_s = segment.sequence.saturating_sub(1);
-            let end_s = std::cmp::max(start_s, end_s);
-
-            merged_segments.push((start_s, end_s, accumulated_duration));
-            accumulated_duration = 0.0;
-            accumulated_start_seq = Some(segment.sequence);
-        }
-
-        accumulated_duration += segment.duration_secs;
-
-        // Flush immediately if it is a non-empty segment so it doesn't get inappropriately swallowed by subsequent empty segments
-        if !is_empty {
-            let start_s = accumulated_start_seq.unwrap();
-            merged_segments.push((start_s, segment.sequence, accumulated_duration));
-
-            accumulated_duration = 0.0;
-            accumulated_start_seq = None;
-        }
-    }
+pub(crate) fn generate_subtitle_playlist(
+    index: &StreamIndex,
+    video_url: &str,
+    session_id: Option<&str>,
+    track_index: usize,
+    timestamps: Option<crate::params::SubtitleTimestamps>,
+) -> String {
+    let group_secs = title_group_secs(index, video_url, session_id, playlist_byte_budget());
+    subtitle_playlist(
+        index,
+        video_url,
+        session_id,
+        track_index,
+        timestamps,
+        group_secs,
+    )
+}
 
-    if accumulated_duration > 0.0 {
-        let start_s = accumulated_start_seq.unwrap_or(0);
-        let last_s = index.segments.last().map(|s| s.sequence).unwrap_or(0);
-        merged_segments.push((start_s, last_s, accumulated_duration));
-    }
+/// The subtitle variant playlist, with the segments with cues grouped into
+/// runs of about `group_secs`.
+fn subtitle_playlist(
+    index: &StreamIndex,
+    video_url: &str,
+    session_id: Option<&str>,
+    track_index: usize,
+    timestamps: Option<crate::params::SubtitleTimestamps>,
+    group_secs: f64,
+) -> String {
+    let mut output = String::new();
 
-    // Calculate dynamic target duration from the merged segments (capped at 30)
-    let mut max_duration = 0.0_f64;
-    for &(_, _, dur) in &merged_segments {
-        if dur > max_duration {
-            max_duration = dur;
-        }
-    }
-    let target_duration = std::cmp::max(
-        max_duration.ceil() as u32,
-        crate::playlist::variant::calculate_target_duration(&index.segments), // fallback to standard video target if smaller
-    );
+    let groups = subtitle_segment_groups(index, track_index, group_secs, repeat_spanning());
+    let target_duration = media_target_duration(index, group_secs);
 
     // Header
     output.push_str("#EXTM3U\n");
-    output.push_str("#EXT-X-VERSION:7\n");
+    output.push_str(&format!(
+        "#EXT-X-VERSION:{}\n",
+        compatibility_profile().hls_version()
+    ));
     output.push_str(&format!("#EXT-X-TARGETDURATION:{}\n", target_duration));
     output.push_str("#EXT-X-MEDIA-SEQUENCE:0\n");
+    write_discontinuity_sequence(&mut output, index);
     output.push_str("#EXT-X-PLAYLIST-TYPE:VOD\n");
     output.push('\n');
 
-    for (start_s, end_s, dur) in merged_segments {
-        let seg = crate::params::VttSegment {
-            track_id: track_index,
-            start_cue: start_s,
-            end_cue: end_s,
+    for group in groups {
+        let seg = if group.empty {
+            UrlType::EmptyVtt
+        } else {
+            UrlType::VttSegment(crate::params::VttSegment {
+                track_id: track_index,
+                start_cue: group.start,
+                end_cue: group.end,
+                timestamps,
+            })
         };
-        output.push_str(&format!("#EXTINF:{:.6},\n", dur));
-        output.push_str(&format!("{}\n", seg));
+        write_discontinuity(&mut output, index, group.start);
+        write_extinf(&mut output, group.duration_secs);
+        output.push_str(&format!("{}\n", segment_uri(video_url, session_id, seg)));
     }
 
     // End list
@@ -283,19 +702,53 @@ pub(crate) fn generate_subtitle_playlist(index: &StreamIndex, track_index: usize
     output
 }
 
+/// Whether a subtitle cue is repeated in the segments it spans into, see
+/// `subtitle_segment_groups()`.
+fn repeat_spanning() -> bool {
+    #[cfg(feature = "subtitles")]
+    let repeat =
+        crate::subtitle::webvtt::spanning_cues() == crate::subtitle::webvtt::SpanningCues::Repeat;
+    #[cfg(not(feature = "subtitles"))]
+    let repeat = false;
+    repeat
+}
+
+/// The `EXT-X-TARGETDURATION` of every media playlist of `index`, with
+/// segments grouped into runs of about `group_secs`: that of the longest
+/// entry of any of them, so that all variants agree.
+fn media_target_duration(index: &StreamIndex, group_secs: f64) -> u32 {
+    let repeat_spanning = repeat_spanning();
+    let subtitles = index.subtitle_streams.iter().flat_map(|s| {
+        subtitle_segment_groups(index, s.stream_index, group_secs, repeat_spanning)
+            .into_iter()
+            .map(|g| g.duration_secs)
+    });
+    target_duration(
+        segment_groups(index, group_secs)
+            .iter()
+            .map(|g| g.duration_secs)
+            .chain(subtitles),
+    )
+    .max(calculate_target_duration(&index.segments))
+}
+
 /// Calculate target duration from segments
 pub fn calculate_target_duration(segments: &[crate::media::SegmentInfo]) -> u32 {
-    if segments.is_empty() {
-        return 6; // Default
-    }
-
-    let max_duration = segments
-        .iter()
-        .map(|s| s.duration_secs)
-        .fold(0.0f64, |a, b| a.max(b));
+    target_duration(segments.iter().map(|s| s.duration_secs))
+}
 
-    // Round up to nearest integer, minimum 6
-    (max_duration.ceil() as u32).max(6)
+/// `EXT-X-TARGETDURATION` for segments of these durations: the longest
+/// EXTINF, rounded up (RFC 8216, section 4.3.3.1).
+///
+/// Durations are rounded to the precision of EXTINF first, so that a
+/// 4.0000001 second segment (written as `4.000`) does not get a target
+/// duration of 5.
+pub fn target_duration(durations: impl IntoIterator<Item = f64>) -> u32 {
+    durations
+        .into_iter()
+        .map(|d| ((d.max(0.0) * 1000.0).round() as u64).div_ceil(1000) as u32)
+        .max()
+        .unwrap_or(6) // Default
 }
 
 #[cfg(test)]
@@ -318,6 +771,9 @@ mod tests {
             language: None,
             profile: None,
             level: None,
+            codec_string: None,
+            nal_format: Default::default(),
+            stats: None,
         });
 
         index.audio_streams.push(AudioStreamInfo {
@@ -328,6 +784,8 @@ mod tests {
             bitrate: 128000,
             language: Some("en".to_string()),
             transcode_to: None,
+            mix_with: None,
+            codec_string: None,
             encoder_delay: 0,
         });
 
@@ -354,12 +812,13 @@ mod tests {
     #[test]
     fn test_generate_video_playlist() {
         let index = create_test_index();
-        let playlist = generate_video_playlist(&index);
+        let playlist = generate_video_playlist(&index, "video.mp4", None, None);
 
         assert!(playlist.contains("#EXTM3U"));
         assert!(playlist.contains("#EXT-X-VERSION:7"));
-        assert!(playlist.contains("#EXT-X-TARGETDURATION:6"));
+        assert!(playlist.contains("#EXT-X-TARGETDURATION:4"));
         assert!(playlist.contains("#EXT-X-PLAYLIST-TYPE:VOD"));
+        assert!(playlist.contains("#EXT-X-INDEPENDENT-SEGMENTS"));
         assert!(playlist.contains("#EXT-X-ENDLIST"));
         assert!(playlist.contains("0.0.m4s"));
         assert!(playlist.contains("0.1.m4s"));
@@ -368,7 +827,7 @@ mod tests {
     #[test]
     fn test_generate_audio_playlist() {
         let index = create_test_index();
-        let playlist = generate_audio_playlist(&index, 1, None);
+        let playlist = generate_audio_playlist(&index, "video.mp4", None, 1, None, None, false);
 
         assert!(playlist.contains("#EXTM3U"));
         assert!(playlist.contains("#EXT-X-VERSION:7"));
@@ -380,13 +839,275 @@ mod tests {
     #[test]
     fn test_generate_subtitle_playlist() {
         let index = create_test_index();
-        let playlist = generate_subtitle_playlist(&index, 2);
+        let playlist = generate_subtitle_playlist(&index, "video.mp4", None, 2, None);
 
         assert!(playlist.contains("#EXTM3U"));
         assert!(playlist.contains("#EXT-X-VERSION:7"));
         assert!(playlist.contains("2.0-0.vtt"));
         assert!(playlist.contains("2.1-1.vtt"));
         assert!(playlist.contains("#EXT-X-ENDLIST"));
+
+        let playlist = generate_subtitle_playlist(
+            &index,
+            "video.mp4",
+            None,
+            2,
+            Some(crate::params::SubtitleTimestamps::Mpegts),
+        );
+        assert!(playlist.contains("2.0-0.mpegts.vtt"));
+    }
+
+    #[test]
+    fn test_empty_subtitle_periods() {
+        use crate::media::{SubtitleFormat, SubtitleStreamInfo};
+
+        let mut index = create_test_index();
+        for sequence in 2..12 {
+            index.segments.push(SegmentInfo {
+                sequence,
+                start_pts: sequence as i64 * 90000,
+                end_pts: (sequence as i64 + 1) * 90000,
+                duration_secs: 4.0,
+                is_keyframe: true,
+                video_byte_offset: sequence as u64 * 1000,
+            });
+        }
+        index.subtitle_streams.push(SubtitleStreamInfo {
+            stream_index: 2,
+            codec_id: ffmpeg::codec::Id::SUBRIP,
+            language: None,
+            format: SubtitleFormat::SubRip,
+            non_empty_sequences: vec![1],
+            sample_index: Vec::new(),
+            timebase: ffmpeg::Rational::new(1, 1000),
+            start_time: 0,
+        });
+
+        let ranges = |repeat_spanning| -> Vec<(usize, usize, bool)> {
+            subtitle_segment_groups(&index, 2, 0.0, repeat_spanning)
+                .iter()
+                .map(|g| (g.start, g.end, g.empty))
+                .collect()
+        };
+        assert_eq!(
+            ranges(false),
+            vec![(0, 0, true), (1, 1, false), (2, 8, true), (9, 11, true)]
+        );
+        // The cue of segment 1 may be repeated in any segment after it.
+        let spanned = ranges(true);
+        assert_eq!(spanned[..2], [(0, 0, true), (1, 1, false)]);
+        assert!(spanned[2..].iter().all(|g| !g.2 && g.0 == g.1));
+
+        let playlist = generate_subtitle_playlist(&index, "video.mp4", None, 2, None);
+        assert_eq!(playlist.matches("\ns/empty.vtt\n").count(), 3);
+        assert!(playlist.contains("#EXTINF:4.000,\ns/2.1-1.vtt\n"));
+        assert!(playlist.contains("#EXTINF:28.000,\ns/empty.vtt\n"));
+        assert!(playlist.contains("#EXT-X-TARGETDURATION:28\n"));
+        // Every variant has the same target duration.
+        let video = generate_video_playlist(&index, "video.mp4", None, None);
+        assert!(video.contains("#EXT-X-TARGETDURATION:28\n"));
+
+        // Empty runs are split at a discontinuity.
+        index.discontinuities = vec![5];
+        let groups = subtitle_segment_groups(&index, 2, 0.0, false);
+        assert!(groups.iter().any(|g| g.start == 5 && g.empty));
+        assert!(groups.iter().any(|g| g.end == 4 && g.empty));
+    }
+
+    #[test]
+    fn test_discontinuity_alignment() {
+        let mut index = create_test_index();
+        index.discontinuities = vec![1];
+
+        let playlists = [
+            generate_video_playlist(&index, "video.mp4", None, None),
+            generate_audio_playlist(&index, "video.mp4", None, 1, None, None, false),
+            generate_interleaved_playlist(&index, "video.mp4", None, 0, 1, None, None),
+            generate_subtitle_playlist(&index, "video.mp4", None, 2, None),
+        ];
+        for playlist in &playlists {
+            assert!(playlist.contains("#EXT-X-DISCONTINUITY-SEQUENCE:0\n"));
+            let lines: Vec<&str> = playlist.lines().collect();
+            let pos = lines
+                .iter()
+                .position(|l| *l == "#EXT-X-DISCONTINUITY")
+                .expect("discontinuity tag");
+            // The tag is followed by the EXTINF and URI of the second segment.
+            assert!(lines[pos + 2].contains(".1.m4s") || lines[pos + 2].contains(".1-1.vtt"));
+        }
+
+        // No discontinuities, no tags.
+        let playlist = generate_video_playlist(&create_test_index(), "video.mp4", None, None);
+        assert!(!playlist.contains("DISCONTINUITY"));
+    }
+
+    #[test]
+    fn test_gap_segment() {
+        use crate::segment::profile::{set_thread_compatibility_profile, CompatibilityProfile};
+
+        let index = create_test_index();
+        let playlist = generate_video_playlist(&index, "video.mp4", None, None);
+        assert!(playlist.contains("#EXT-X-VERSION:7\n"));
+        index.mark_gap(1);
+
+        let playlists = [
+            generate_video_playlist(&index, "video.mp4", None, None),
+            generate_audio_playlist(&index, "video.mp4", None, 1, None, None, false),
+            generate_interleaved_playlist(&index, "video.mp4", None, 0, 1, None, None),
+        ];
+        for playlist in &playlists {
+            let lines: Vec<&str> = playlist.lines().collect();
+            let pos = lines
+                .iter()
+                .position(|l| *l == "#EXT-X-GAP")
+                .expect("gap tag");
+            assert!(lines[pos - 1].starts_with("#EXTINF:"));
+            assert!(lines[pos + 1].contains(".1.m4s"));
+            assert_eq!(playlist.matches("#EXT-X-GAP").count(), 1);
+            assert!(playlist.contains("#EXT-X-VERSION:8\n"));
+        }
+
+        // Also above the version of the profile.
+        set_thread_compatibility_profile(Some(CompatibilityProfile::Legacy));
+        let playlist = generate_video_playlist(&index, "video.mp4", None, None);
+        set_thread_compatibility_profile(None);
+        assert!(playlist.contains("#EXT-X-VERSION:8\n"));
+    }
+
+    #[test]
+    fn test_combined_init_playlist() {
+        let index = create_test_index();
+        let playlist =
+            generate_interleaved_playlist(&index, "video.mp4", None, 0, 1, None, Some(812));
+        assert!(playlist.contains("#EXT-X-MAP:URI=\"v/0+1.0.hdr.m4s\",BYTERANGE=\"812@0\"\n"));
+        assert!(playlist.contains("\nv/0+1.0.hdr.m4s\n"));
+        assert!(playlist.contains("\nv/0+1.1.m4s\n"));
+        assert!(!playlist.contains("init.mp4"));
+
+        let playlist = generate_audio_playlist(&index, "video.mp4", None, 1, None, None, false);
+        assert!(playlist.contains("#EXT-X-MAP:URI=\"a/1.init.mp4\"\n"));
+        assert!(!playlist.contains(".hdr."));
+    }
+
+    #[test]
+    fn test_segment_groups() {
+        let mut index = create_test_index();
+        for sequence in 2..6 {
+            index.segments.push(SegmentInfo {
+                sequence,
+                start_pts: sequence as i64 * 90000,
+                end_pts: (sequence as i64 + 1) * 90000,
+                duration_secs: 4.0,
+                is_keyframe: true,
+                video_byte_offset: sequence as u64 * 1000,
+            });
+        }
+        index.discontinuities = vec![3];
+
+        // Disabled: one group per segment.
+        let groups = segment_groups(&index, 0.0);
+        assert_eq!(groups.len(), 6);
+        assert!(groups.iter().all(|g| g.start == g.end));
+
+        // 10s target: closed at 8s, and split at the discontinuity.
+        let groups = segment_groups(&index, 10.0);
+        let ranges: Vec<(usize, usize)> = groups.iter().map(|g| (g.start, g.end)).collect();
+        assert_eq!(ranges, vec![(0, 1), (2, 2), (3, 4), (5, 5)]);
+        assert_eq!(groups[0].duration_secs, 8.0);
+        assert_eq!(groups[1].duration_secs, 4.0);
+    }
+
+    #[test]
+    fn test_playlist_byte_budget() {
+        let mut index = create_test_index();
+        for sequence in 2..100 {
+            index.segments.push(SegmentInfo {
+                sequence,
+                start_pts: sequence as i64 * 90000,
+                end_pts: (sequence as i64 + 1) * 90000,
+                duration_secs: 4.0,
+                is_keyframe: true,
+                video_byte_offset: sequence as u64 * 1000,
+            });
+        }
+        index.discontinuities = vec![50];
+        index.mark_gap(10);
+        let video = |budget| {
+            let group_secs = title_group_secs(&index, "video.mp4", None, budget);
+            video_playlist(&index, "video.mp4", None, None, group_secs)
+        };
+
+        // Within the budget: every segment.
+        let full = video(0);
+        let largest = largest_rendition(&index, "video.mp4", None, 0.0);
+        assert!(largest > full.len());
+        assert_eq!(video(largest), full);
+        assert!(full.contains("\nv/0.99.m4s\n"));
+
+        // Over it: runs of segments, split at the discontinuity.
+        let playlist = video(full.len() / 3);
+        assert!(playlist.len() <= full.len() / 3, "{}", playlist);
+        assert!(playlist.contains("\nv/0.0-"));
+        assert!(playlist.contains("#EXT-X-DISCONTINUITY\n#EXTINF:"));
+        assert_eq!(playlist.matches("#EXT-X-GAP").count(), 1);
+        let longest = playlist
+            .lines()
+            .filter_map(|l| l.strip_prefix("#EXTINF:"))
+            .filter_map(|d| d.trim_end_matches(',').parse::<f64>().ok())
+            .fold(0.0, f64::max);
+        assert!(playlist.contains(&format!(
+            "#EXT-X-TARGETDURATION:{}\n",
+            target_duration([longest])
+        )));
+
+        // Can't fit: one run per timeline.
+        let playlist = video(100);
+        assert_eq!(playlist.matches("#EXTINF:").count(), 2);
+    }
+
+    #[test]
+    fn test_playlist_byte_budget_alignment() {
+        let mut index = create_test_index();
+        index.audio_streams.push(AudioStreamInfo {
+            stream_index: 2,
+            codec_id: ffmpeg::codec::Id::AC3,
+            transcode_to: Some(ffmpeg::codec::Id::AAC),
+            ..index.audio_streams[0].clone()
+        });
+        for sequence in 2..100 {
+            index.segments.push(SegmentInfo {
+                sequence,
+                start_pts: sequence as i64 * 90000,
+                end_pts: (sequence as i64 + 1) * 90000,
+                duration_secs: 4.0 + (sequence % 3) as f64,
+                is_keyframe: true,
+                video_byte_offset: sequence as u64 * 1000,
+            });
+        }
+        let budget = largest_rendition(&index, "video.mp4", None, 0.0) / 3;
+        let group_secs = title_group_secs(&index, "video.mp4", None, budget);
+        assert!(group_secs > 0.0);
+
+        // The segment numbers of every entry, e.g. `12-15` of `a/2-aac.12-15.m4s`.
+        let runs = |playlist: String| -> Vec<String> {
+            playlist
+                .lines()
+                .filter(|l| l.ends_with(".m4s"))
+                .map(|l| {
+                    let (_, run) = l.trim_end_matches(".m4s").rsplit_once('.').unwrap();
+                    run.to_string()
+                })
+                .collect()
+        };
+        let video = runs(video_playlist(&index, "video.mp4", None, None, group_secs));
+        assert!(video.iter().any(|r| r.contains('-')), "{:?}", video);
+        for track in [1, 2] {
+            let audio = audio_playlist(&index, "video.mp4", None, track, None, None, group_secs);
+            assert_eq!(runs(audio), video, "audio track {}", track);
+            let interleaved =
+                interleaved_playlist(&index, "video.mp4", None, 0, track, None, None, group_secs);
+            assert_eq!(runs(interleaved), video, "interleaved track {}", track);
+        }
     }
 
     #[test]
@@ -423,4 +1144,51 @@ mod tests {
 
         assert_eq!(calculate_target_duration(&segments), 10);
     }
+
+    #[test]
+    fn test_target_duration_rounding() {
+        assert_eq!(target_duration([4.0, 4.0000001]), 4);
+        assert_eq!(target_duration([4.0, 4.0006]), 5);
+        assert_eq!(target_duration([5.9996]), 6);
+        assert_eq!(target_duration([]), 6);
+    }
+
+    #[test]
+    fn test_playlist_compliance() {
+        use crate::tests::validation::{validate_variant_playlist, PlaylistType};
+
+        let mut index = create_test_index();
+        index.segments[1].duration_secs = 4.0000001;
+
+        let playlists = [
+            (
+                generate_video_playlist(&index, "video.mp4", None, None),
+                PlaylistType::Video,
+            ),
+            (
+                generate_audio_playlist(&index, "video.mp4", None, 1, None, None, false),
+                PlaylistType::Audio,
+            ),
+            (
+                generate_interleaved_playlist(&index, "video.mp4", None, 0, 1, None, None),
+                PlaylistType::Video,
+            ),
+            (
+                generate_subtitle_playlist(&index, "video.mp4", None, 2, None),
+                PlaylistType::Subtitle,
+            ),
+        ];
+        for (playlist, playlist_type) in &playlists {
+            let result = validate_variant_playlist(playlist, *playlist_type);
+            assert!(result.is_valid, "{:?}\n{}", result.errors, playlist);
+            assert!(playlist.contains("#EXT-X-TARGETDURATION:4\n"));
+        }
+
+        // A segment that does not start with a keyframe.
+        index.segments[1].is_keyframe = false;
+        let playlist = generate_video_playlist(&index, "video.mp4", None, None);
+        assert!(!playlist.contains("#EXT-X-INDEPENDENT-SEGMENTS"));
+        let playlist = generate_audio_playlist(&index, "video.mp4", None, 1, None, None, false);
+        assert!(playlist.contains("#EXT-X-INDEPENDENT-SEGMENTS"));
+    }
 }
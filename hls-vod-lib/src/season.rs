@@ -0,0 +1,183 @@
+//! Manifests of a folder of episodes.
+//!
+//! A front-end that shows a season of a series needs the duration and tracks
+//! of every episode, and the URL to play it. `season_manifest()` probes every
+//! media file in a folder and returns all of that at once, instead of one
+//! probe per episode. Only with stable stream ids (see `SegmentCacheConfig`)
+//! are the episodes indexed as they would be for playback, so starting one
+//! of them afterwards doesn't wait for the scanner; otherwise playback would
+//! not find the index again, and it is not kept.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::hlsvideo::HlsVideo;
+use crate::media::StreamIndex;
+use crate::params::{is_source_extension, HlsParams};
+
+/// The episodes in a folder, see `season_manifest()`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SeasonManifest {
+    /// Episodes in file name order
+    pub episodes: Vec<Episode>,
+    /// Sum of the durations of the episodes, in seconds
+    pub duration_secs: f64,
+}
+
+/// One media file of a `SeasonManifest`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Episode {
+    /// File name, without the folder
+    pub file_name: String,
+    /// Request path of the main playlist (`.as.m3u8`), percent-encoded
+    pub url: String,
+    /// Stream id of the index, with stable stream ids and if the file could
+    /// be opened
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_id: Option<String>,
+    /// Duration in seconds
+    pub duration_secs: f64,
+    /// Tracks of the main playlist
+    pub tracks: Vec<EpisodeTrack>,
+    /// Why the file could not be opened
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A track of an `Episode`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EpisodeTrack {
+    /// Zero-based index of the stream in the source file
+    pub track: usize,
+    /// `video`, `audio` or `subtitle`
+    pub kind: String,
+    /// FFmpeg codec name
+    pub codec: String,
+    /// Language tag of the track, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+/// Probe every media file in `dir`, and list their durations, tracks and
+/// main playlist URLs.
+///
+/// `video_url` is the URL path of `dir`, the episode URLs are built from it.
+/// Files with other extensions than the source extensions (see
+/// `set_source_extensions()`) are skipped; a media file that fails to open
+/// is listed with an `error`. This reads every episode, so call it from a
+/// blocking thread.
+pub fn season_manifest(dir: &Path, video_url: &str) -> Result<SeasonManifest> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| {
+            Path::new(name)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .is_some_and(is_source_extension)
+        })
+        .collect();
+    names.sort();
+
+    let video_url = video_url.trim_end_matches('/');
+    let mut manifest = SeasonManifest::default();
+    for name in names {
+        let params = HlsParams::main_playlist(format!("{}/{}", video_url, name));
+        let mut episode = Episode {
+            file_name: name.clone(),
+            url: params.request_path(),
+            ..Default::default()
+        };
+        match open_episode(&dir.join(&name), params) {
+            Ok(index) => {
+                if crate::cache::stable_stream_ids() {
+                    episode.stream_id = Some(index.stream_id().to_string());
+                }
+                episode.duration_secs = index.duration_secs;
+                episode.tracks = episode_tracks(&index);
+                manifest.duration_secs += index.duration_secs;
+            }
+            Err(e) => {
+                tracing::warn!("season manifest: {:?}: {}", dir.join(&name), e);
+                episode.error = Some(e.to_string());
+            }
+        }
+        manifest.episodes.push(episode);
+    }
+    Ok(manifest)
+}
+
+// Index the episode as for playback if playback can find the index again
+// by its stable stream id, else only read its tracks and duration.
+fn open_episode(path: &Path, params: HlsParams) -> Result<Arc<StreamIndex>> {
+    if !crate::cache::stable_stream_ids() {
+        return StreamIndex::parse(path).map(Arc::new);
+    }
+    match HlsVideo::open(path, params)? {
+        HlsVideo::MainPlaylist(p) => Ok(p.index),
+        HlsVideo::PlaylistOrSegment(p) => Ok(p.index),
+    }
+}
+
+fn episode_tracks(index: &StreamIndex) -> Vec<EpisodeTrack> {
+    let video = index.video_streams.iter().map(|v| EpisodeTrack {
+        track: v.stream_index,
+        kind: "video".to_string(),
+        codec: v.codec_id.name().to_string(),
+        language: v.language.clone(),
+    });
+    let audio = index.audio_streams.iter().map(|a| EpisodeTrack {
+        track: a.stream_index,
+        kind: "audio".to_string(),
+        codec: a.codec_id.name().to_string(),
+        language: a.language.clone(),
+    });
+    let subtitles = index.subtitle_streams.iter().map(|s| EpisodeTrack {
+        track: s.stream_index,
+        kind: "subtitle".to_string(),
+        codec: s.codec_id.name().to_string(),
+        language: s.language.clone(),
+    });
+    video.chain(audio).chain(subtitles).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::fixtures::generate::{generate, FixtureSpec};
+
+    #[test]
+    fn test_season_manifest() {
+        let Some(media) = generate(&FixtureSpec::h264_aac()) else {
+            return;
+        };
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::copy(&media, dir.path().join("S01E02.mp4")).unwrap();
+        std::fs::copy(&media, dir.path().join("S01E01.mp4")).unwrap();
+        std::fs::write(dir.path().join("S01E03.mkv"), b"not a media file").unwrap();
+        std::fs::write(dir.path().join("cover.jpg"), b"").unwrap();
+
+        let manifest = season_manifest(dir.path(), "Series/Season 1/").unwrap();
+        let names: Vec<&str> = manifest
+            .episodes
+            .iter()
+            .map(|e| e.file_name.as_str())
+            .collect();
+        assert_eq!(names, ["S01E01.mp4", "S01E02.mp4", "S01E03.mkv"]);
+
+        let first = &manifest.episodes[0];
+        assert_eq!(first.url, "Series/Season%201/S01E01.mp4.as.m3u8");
+        assert!(first.stream_id.is_none() && first.error.is_none());
+        assert!(first.duration_secs > 0.0);
+        assert!(first.tracks.iter().any(|t| t.kind == "video"));
+        assert!(first.tracks.iter().any(|t| t.kind == "audio"));
+
+        let broken = &manifest.episodes[2];
+        assert!(broken.stream_id.is_none() && broken.error.is_some());
+        assert!((manifest.duration_secs - 2.0 * first.duration_secs).abs() < 0.001);
+    }
+}
@@ -0,0 +1,485 @@
+//! Compare a media segment muxed with two muxer configurations.
+//!
+//! Used to check muxer changes (`delay_moov`, `styp`, ...) against real
+//! files: the segment is generated twice, and the box trees and timing
+//! values of both results are compared.
+
+use serde::Serialize;
+
+use crate::error::Result;
+use crate::media::{SegmentInfo, StreamIndex};
+use crate::segment::generator::generate_media_segment_ffmpeg;
+use crate::segment::isobmff::{box_header, tfdt_time};
+use crate::segment::muxer::MuxOptions;
+use crate::segment::retry::Attempt;
+
+/// Maximum nesting depth that is walked.
+const MAX_DEPTH: usize = 8;
+
+/// Boxes that are descended into.
+const CONTAINERS: &[&[u8; 4]] = &[
+    b"moov", b"trak", b"mdia", b"minf", b"stbl", b"mvex", b"edts", b"moof", b"traf",
+];
+
+/// A box in the tree, in file order.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BoxInfo {
+    /// Box types from the top level down, e.g. `moof/traf/trun`
+    pub path: String,
+    /// Size including the header
+    pub size: usize,
+}
+
+/// Timing of one track, summed over all fragments.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TrackTiming {
+    pub track_id: u32,
+    /// Number of `traf` boxes of this track
+    pub fragments: u32,
+    /// `tfdt` of the first fragment
+    pub base_decode_time: Option<u64>,
+    pub sample_count: u64,
+    /// Sum of the sample durations, in the track timescale
+    pub duration: u64,
+    /// Composition time offset of the first sample
+    pub first_cts_offset: Option<i64>,
+}
+
+/// Structure of a media segment.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SegmentStructure {
+    pub size: usize,
+    pub boxes: Vec<BoxInfo>,
+    /// `mfhd` sequence numbers
+    pub sequence_numbers: Vec<u32>,
+    pub tracks: Vec<TrackTiming>,
+    /// Track id and `mdhd` timescale of the tracks in the `moov` (init segments)
+    pub timescales: Vec<(u32, u32)>,
+}
+
+/// Result of comparing two muxer configurations.
+#[derive(Debug, Clone, Serialize)]
+pub struct SegmentComparison {
+    pub options_a: MuxOptions,
+    pub options_b: MuxOptions,
+    pub a: SegmentStructure,
+    pub b: SegmentStructure,
+    /// Human readable differences, empty if the structures are the same
+    pub differences: Vec<String>,
+}
+
+fn be_u32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+// Per-traf values, merged into the TrackTiming of the track afterwards.
+#[derive(Default)]
+struct Traf {
+    track_id: u32,
+    default_duration: u32,
+    base_decode_time: Option<u64>,
+    sample_count: u64,
+    duration: u64,
+    // Sample count of truns without per-sample durations.
+    default_samples: u64,
+    first_cts_offset: Option<i64>,
+}
+
+impl Traf {
+    fn tfhd(&mut self, payload: &[u8]) {
+        let flags = be_u32(payload, 0).unwrap_or(0) & 0xff_ffff;
+        self.track_id = be_u32(payload, 4).unwrap_or(0);
+        let mut pos = 8;
+        if flags & 0x1 != 0 {
+            pos += 8;
+        }
+        if flags & 0x2 != 0 {
+            pos += 4;
+        }
+        if flags & 0x8 != 0 {
+            self.default_duration = be_u32(payload, pos).unwrap_or(0);
+        }
+    }
+
+    fn tfdt(&mut self, payload: &[u8]) {
+        self.base_decode_time = tfdt_time(payload);
+    }
+
+    fn trun(&mut self, payload: &[u8]) {
+        let version = payload.first().copied().unwrap_or(0);
+        let flags = be_u32(payload, 0).unwrap_or(0) & 0xff_ffff;
+        let count = be_u32(payload, 4).unwrap_or(0);
+        self.sample_count += count as u64;
+
+        let mut pos = 8;
+        if flags & 0x1 != 0 {
+            pos += 4;
+        }
+        if flags & 0x4 != 0 {
+            pos += 4;
+        }
+        let fields = [0x100, 0x200, 0x400, 0x800]
+            .iter()
+            .filter(|f| flags & **f != 0)
+            .count();
+        if flags & 0x100 == 0 {
+            self.default_samples += count as u64;
+        }
+        // Without per-sample durations, only the first sample is of interest.
+        let samples = if flags & 0x100 != 0 {
+            count
+        } else {
+            count.min(1)
+        };
+        for i in 0..samples as usize {
+            let mut field = pos + i * fields * 4;
+            if flags & 0x100 != 0 {
+                match be_u32(payload, field) {
+                    Some(d) => self.duration += d as u64,
+                    None => break,
+                }
+                field += 4;
+            }
+            if flags & 0x200 != 0 {
+                field += 4;
+            }
+            if flags & 0x400 != 0 {
+                field += 4;
+            }
+            if i == 0 && flags & 0x800 != 0 && self.first_cts_offset.is_none() {
+                self.first_cts_offset = be_u32(payload, field).map(|v| {
+                    if version == 0 {
+                        v as i64
+                    } else {
+                        v as i32 as i64
+                    }
+                });
+            }
+        }
+    }
+}
+
+fn walk(
+    data: &[u8],
+    prefix: &str,
+    structure: &mut SegmentStructure,
+    traf: &mut Option<Traf>,
+    depth: usize,
+) {
+    let mut pos = 0;
+    while let Some((header_len, size, btype)) = box_header(data, pos) {
+        let name = String::from_utf8_lossy(&btype).into_owned();
+        let path = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        structure.boxes.push(BoxInfo {
+            path: path.clone(),
+            size,
+        });
+        let payload = &data[pos + header_len..pos + size];
+        match &btype {
+            b"mfhd" => {
+                if let Some(seq) = be_u32(payload, 4) {
+                    structure.sequence_numbers.push(seq);
+                }
+            }
+            b"tkhd" => {
+                let pos = if payload.first() == Some(&1) { 20 } else { 12 };
+                structure
+                    .timescales
+                    .push((be_u32(payload, pos).unwrap_or(0), 0));
+            }
+            b"mdhd" => {
+                let pos = if payload.first() == Some(&1) { 20 } else { 12 };
+                if let Some(last) = structure.timescales.last_mut() {
+                    last.1 = be_u32(payload, pos).unwrap_or(0);
+                }
+            }
+            b"tfhd" => traf.get_or_insert_with(Traf::default).tfhd(payload),
+            b"tfdt" => traf.get_or_insert_with(Traf::default).tfdt(payload),
+            b"trun" => traf.get_or_insert_with(Traf::default).trun(payload),
+            _ => {}
+        }
+        if depth < MAX_DEPTH && CONTAINERS.contains(&&btype) {
+            if &btype == b"traf" {
+                let mut inner = Some(Traf::default());
+                walk(payload, &path, structure, &mut inner, depth + 1);
+                if let Some(t) = inner {
+                    add_traf(structure, t);
+                }
+            } else {
+                walk(payload, &path, structure, traf, depth + 1);
+            }
+        }
+        pos += size;
+    }
+}
+
+fn add_traf(structure: &mut SegmentStructure, t: Traf) {
+    let idx = match structure
+        .tracks
+        .iter()
+        .position(|x| x.track_id == t.track_id)
+    {
+        Some(idx) => idx,
+        None => {
+            structure.tracks.push(TrackTiming {
+                track_id: t.track_id,
+                ..Default::default()
+            });
+            structure.tracks.len() - 1
+        }
+    };
+    let track = &mut structure.tracks[idx];
+    track.fragments += 1;
+    track.base_decode_time = track.base_decode_time.or(t.base_decode_time);
+    track.sample_count += t.sample_count;
+    track.duration += t.duration + t.default_samples * t.default_duration as u64;
+    track.first_cts_offset = track.first_cts_offset.or(t.first_cts_offset);
+}
+
+/// Parse the box tree and the timing values of a media segment.
+///
+/// Malformed boxes end the walk, like everywhere in `isobmff`.
+pub fn analyze(data: &[u8]) -> SegmentStructure {
+    let mut structure = SegmentStructure {
+        size: data.len(),
+        ..Default::default()
+    };
+    walk(data, "", &mut structure, &mut None, 0);
+    structure
+}
+
+fn opt<T: std::fmt::Display>(v: &Option<T>) -> String {
+    v.as_ref()
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// List the differences between two segment structures.
+pub fn diff(a: &SegmentStructure, b: &SegmentStructure) -> Vec<String> {
+    let mut out = Vec::new();
+    if a.size != b.size {
+        out.push(format!("size: {} vs {}", a.size, b.size));
+    }
+
+    let paths_a: Vec<&str> = a.boxes.iter().map(|b| b.path.as_str()).collect();
+    let paths_b: Vec<&str> = b.boxes.iter().map(|b| b.path.as_str()).collect();
+    if paths_a != paths_b {
+        for p in &paths_a {
+            if !paths_b.contains(p) {
+                out.push(format!("box {}: only in a", p));
+            }
+        }
+        for p in &paths_b {
+            if !paths_a.contains(p) {
+                out.push(format!("box {}: only in b", p));
+            }
+        }
+        if out.iter().all(|d| !d.starts_with("box ")) {
+            out.push(format!(
+                "box order: {} vs {}",
+                paths_a.join(","),
+                paths_b.join(",")
+            ));
+        }
+    } else {
+        for (x, y) in a.boxes.iter().zip(&b.boxes) {
+            if x.size != y.size {
+                out.push(format!("box {} size: {} vs {}", x.path, x.size, y.size));
+            }
+        }
+    }
+
+    if a.sequence_numbers != b.sequence_numbers {
+        out.push(format!(
+            "mfhd sequence numbers: {:?} vs {:?}",
+            a.sequence_numbers, b.sequence_numbers
+        ));
+    }
+
+    for ta in &a.tracks {
+        let Some(tb) = b.tracks.iter().find(|t| t.track_id == ta.track_id) else {
+            out.push(format!("track {}: only in a", ta.track_id));
+            continue;
+        };
+        let id = ta.track_id;
+        if ta.fragments != tb.fragments {
+            out.push(format!(
+                "track {} fragments: {} vs {}",
+                id, ta.fragments, tb.fragments
+            ));
+        }
+        if ta.base_decode_time != tb.base_decode_time {
+            out.push(format!(
+                "track {} tfdt: {} vs {}",
+                id,
+                opt(&ta.base_decode_time),
+                opt(&tb.base_decode_time)
+            ));
+        }
+        if ta.sample_count != tb.sample_count {
+            out.push(format!(
+                "track {} samples: {} vs {}",
+                id, ta.sample_count, tb.sample_count
+            ));
+        }
+        if ta.duration != tb.duration {
+            out.push(format!(
+                "track {} duration: {} vs {}",
+                id, ta.duration, tb.duration
+            ));
+        }
+        if ta.first_cts_offset != tb.first_cts_offset {
+            out.push(format!(
+                "track {} first cts offset: {} vs {}",
+                id,
+                opt(&ta.first_cts_offset),
+                opt(&tb.first_cts_offset)
+            ));
+        }
+    }
+    for tb in &b.tracks {
+        if !a.tracks.iter().any(|t| t.track_id == tb.track_id) {
+            out.push(format!("track {}: only in b", tb.track_id));
+        }
+    }
+    out
+}
+
+/// Generate `segment` with muxer options `a` and `b`, and compare the results.
+///
+/// Both are generated from a freshly opened input and without retries, so
+/// that errors show up as they are.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn compare_segment(
+    index: &StreamIndex,
+    segment: &SegmentInfo,
+    segment_type: &str,
+    video_track_index: Option<usize>,
+    audio_track_index: Option<usize>,
+    transcode_audio: bool,
+    a: MuxOptions,
+    b: MuxOptions,
+) -> Result<SegmentComparison> {
+    let generate = |mux| {
+        let attempt = Attempt {
+            fresh_input: true,
+            transcode_audio,
+            mux,
+            ..Default::default()
+        };
+        generate_media_segment_ffmpeg(
+            segment,
+            segment_type,
+            video_track_index,
+            audio_track_index,
+            index,
+            attempt,
+            None,
+        )
+        .map(|data| analyze(&data))
+    };
+    let structure_a = generate(a)?;
+    let structure_b = generate(b)?;
+    let differences = diff(&structure_a, &structure_b);
+    Ok(SegmentComparison {
+        options_a: a,
+        options_b: b,
+        a: structure_a,
+        b: structure_b,
+        differences,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::segment::isobmff::write_box_header;
+
+    fn mk_box(btype: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_box_header(&mut out, btype, payload.len());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    fn fragment(seq: u32, tfdt: u64, durations: &[u32]) -> Vec<u8> {
+        let mfhd = mk_box(b"mfhd", &[&[0u8; 4][..], &seq.to_be_bytes()].concat());
+        let tfhd = mk_box(b"tfhd", &[0, 0, 0, 0, 0, 0, 0, 1]);
+        let tfdt = mk_box(
+            b"tfdt",
+            &[&[1u8, 0, 0, 0][..], &tfdt.to_be_bytes()].concat(),
+        );
+        // Sample duration (0x100) and composition time offset (0x800) per sample.
+        let mut trun = vec![0, 0, 0x09, 0x00];
+        trun.extend_from_slice(&(durations.len() as u32).to_be_bytes());
+        for d in durations {
+            trun.extend_from_slice(&d.to_be_bytes());
+            trun.extend_from_slice(&0u32.to_be_bytes());
+        }
+        let trun = mk_box(b"trun", &trun);
+        let traf = mk_box(b"traf", &[tfhd, tfdt, trun].concat());
+        let moof = mk_box(b"moof", &[mfhd, traf].concat());
+        [moof, mk_box(b"mdat", &[0; 16])].concat()
+    }
+
+    #[test]
+    fn test_analyze() {
+        let data = [
+            mk_box(b"styp", b"msdhcmfs"),
+            fragment(5, 9000, &[3000, 3000]),
+        ]
+        .concat();
+        let s = analyze(&data);
+        let paths: Vec<&str> = s.boxes.iter().map(|b| b.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "styp",
+                "moof",
+                "moof/mfhd",
+                "moof/traf",
+                "moof/traf/tfhd",
+                "moof/traf/tfdt",
+                "moof/traf/trun",
+                "mdat"
+            ]
+        );
+        assert_eq!(s.sequence_numbers, vec![5]);
+        assert_eq!(
+            s.tracks,
+            vec![TrackTiming {
+                track_id: 1,
+                fragments: 1,
+                base_decode_time: Some(9000),
+                sample_count: 2,
+                duration: 6000,
+                first_cts_offset: Some(0),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let a = analyze(&[mk_box(b"styp", b"msdhcmfs"), fragment(5, 9000, &[3000])].concat());
+        let b = analyze(&fragment(5, 9000, &[3000, 3000]));
+        let d = diff(&a, &b);
+        assert!(d.contains(&"box styp: only in a".to_string()));
+        assert!(d.contains(&"track 1 samples: 1 vs 2".to_string()));
+        assert!(d.contains(&"track 1 duration: 3000 vs 6000".to_string()));
+
+        assert!(diff(&a, &a).is_empty());
+    }
+
+    #[test]
+    fn test_analyze_truncated() {
+        let mut data = fragment(1, 0, &[1000]);
+        data.truncate(data.len() - 4);
+        // The truncated mdat ends the walk, nothing panics.
+        let s = analyze(&data);
+        assert_eq!(s.boxes.last().unwrap().path, "moof/traf/trun");
+    }
+}
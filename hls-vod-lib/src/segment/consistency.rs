@@ -0,0 +1,446 @@
+//! Consistency checks between init segments and media segments.
+//!
+//! A media segment is only decodable together with the init segment that
+//! `EXT-X-MAP` points to. If they disagree (a track id that is not in the
+//! `moov`, a `tfdt` that only makes sense in another timescale, sample
+//! defaults that are in neither `tfhd` nor `trex`), players glitch or stall,
+//! typically only on a track switch. `check_segment` cross-checks a media
+//! segment against its init segment and reports every discrepancy it finds.
+
+use serde::Serialize;
+
+use super::isobmff::{child_boxes, find_box, tfdt_time};
+
+/// Tolerance of the timing checks, in seconds.
+const TOLERANCE: f64 = 0.1;
+
+const TFHD_BASE_DATA_OFFSET: u32 = 0x1;
+const TFHD_SAMPLE_DESCRIPTION_INDEX: u32 = 0x2;
+const TFHD_DEFAULT_DURATION: u32 = 0x8;
+const TFHD_DEFAULT_SIZE: u32 = 0x10;
+const TFHD_DEFAULT_FLAGS: u32 = 0x20;
+
+const TRUN_DATA_OFFSET: u32 = 0x1;
+const TRUN_FIRST_SAMPLE_FLAGS: u32 = 0x4;
+const TRUN_DURATION: u32 = 0x100;
+const TRUN_SIZE: u32 = 0x200;
+const TRUN_FLAGS: u32 = 0x400;
+const TRUN_CTS: u32 = 0x800;
+
+/// `sample_is_non_sync_sample` in the sample flags.
+const NON_SYNC_SAMPLE: u32 = 0x10000;
+
+/// A track of an init segment.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct InitTrack {
+    pub track_id: u32,
+    /// `mdhd` timescale
+    pub timescale: u32,
+    /// `hdlr` handler type, e.g. `vide` or `soun`
+    pub handler: String,
+    /// `trex` default sample duration, size and flags
+    pub trex: Option<(u32, u32, u32)>,
+}
+
+/// The result of checking one variant playlist.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ConsistencyCheck {
+    /// Playlist URI, relative to the main playlist
+    pub playlist: String,
+    /// `EXT-X-MAP` URI
+    pub init: String,
+    /// URI of the media segment that was checked
+    pub segment: String,
+    /// Start of the segment according to the playlist, in seconds
+    pub expected_start: f64,
+    /// `EXTINF` of the segment
+    pub expected_duration: f64,
+    /// The tracks of the init segment
+    pub tracks: Vec<InitTrack>,
+    /// Discrepancies; empty if the segment matches the init segment
+    pub problems: Vec<String>,
+}
+
+// Sample information of one `traf`.
+#[derive(Debug, Default)]
+struct Fragment {
+    track_id: u32,
+    default_duration: Option<u32>,
+    default_size: Option<u32>,
+    default_flags: Option<u32>,
+    base_decode_time: Option<u64>,
+    runs: Vec<Run>,
+}
+
+// Sample information of one `trun`.
+#[derive(Debug, Default)]
+struct Run {
+    flags: u32,
+    sample_count: u32,
+    first_sample_flags: Option<u32>,
+    // Flags and composition offset of the first sample, if per sample.
+    sample_flags: Option<u32>,
+    cts_offset: Option<i64>,
+    // Sum of the per-sample durations, if per sample.
+    duration: u64,
+}
+
+fn be_u32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+/// The tracks of an init segment.
+pub fn parse_init(data: &[u8]) -> Vec<InitTrack> {
+    let Some(moov) = find_box(data, b"moov") else {
+        return Vec::new();
+    };
+    let mut tracks = Vec::new();
+    for (_, trak) in child_boxes(moov).into_iter().filter(|(t, _)| t == b"trak") {
+        let mut track = InitTrack::default();
+        if let Some(tkhd) = find_box(trak, b"tkhd") {
+            let pos = if tkhd.first() == Some(&1) { 20 } else { 12 };
+            track.track_id = be_u32(tkhd, pos).unwrap_or(0);
+        }
+        if let Some(mdia) = find_box(trak, b"mdia") {
+            if let Some(mdhd) = find_box(mdia, b"mdhd") {
+                let pos = if mdhd.first() == Some(&1) { 20 } else { 12 };
+                track.timescale = be_u32(mdhd, pos).unwrap_or(0);
+            }
+            if let Some(handler) = find_box(mdia, b"hdlr").and_then(|h| h.get(8..12)) {
+                track.handler = String::from_utf8_lossy(handler).into_owned();
+            }
+        }
+        tracks.push(track);
+    }
+    if let Some(mvex) = find_box(moov, b"mvex") {
+        for (_, trex) in child_boxes(mvex).into_iter().filter(|(t, _)| t == b"trex") {
+            let id = be_u32(trex, 4);
+            let defaults = match (be_u32(trex, 12), be_u32(trex, 16), be_u32(trex, 20)) {
+                (Some(duration), Some(size), Some(flags)) => Some((duration, size, flags)),
+                _ => None,
+            };
+            if let Some(track) = tracks.iter_mut().find(|t| Some(t.track_id) == id) {
+                track.trex = defaults;
+            }
+        }
+    }
+    tracks
+}
+
+fn parse_tfhd(tfhd: &[u8], frag: &mut Fragment) -> Option<()> {
+    let flags = be_u32(tfhd, 0)? & 0xffffff;
+    frag.track_id = be_u32(tfhd, 4)?;
+    let mut pos = 8;
+    if flags & TFHD_BASE_DATA_OFFSET != 0 {
+        pos += 8;
+    }
+    if flags & TFHD_SAMPLE_DESCRIPTION_INDEX != 0 {
+        pos += 4;
+    }
+    if flags & TFHD_DEFAULT_DURATION != 0 {
+        frag.default_duration = Some(be_u32(tfhd, pos)?);
+        pos += 4;
+    }
+    if flags & TFHD_DEFAULT_SIZE != 0 {
+        frag.default_size = Some(be_u32(tfhd, pos)?);
+        pos += 4;
+    }
+    if flags & TFHD_DEFAULT_FLAGS != 0 {
+        frag.default_flags = Some(be_u32(tfhd, pos)?);
+    }
+    Some(())
+}
+
+fn parse_trun(trun: &[u8]) -> Option<Run> {
+    let version = *trun.first()?;
+    let flags = be_u32(trun, 0)? & 0xffffff;
+    let mut run = Run {
+        flags,
+        sample_count: be_u32(trun, 4)?,
+        ..Default::default()
+    };
+    let mut pos = 8;
+    if flags & TRUN_DATA_OFFSET != 0 {
+        pos += 4;
+    }
+    if flags & TRUN_FIRST_SAMPLE_FLAGS != 0 {
+        run.first_sample_flags = Some(be_u32(trun, pos)?);
+        pos += 4;
+    }
+    let fields = [TRUN_DURATION, TRUN_SIZE, TRUN_FLAGS, TRUN_CTS];
+    let sample_len = 4 * fields.iter().filter(|f| flags & **f != 0).count();
+    for n in 0..run.sample_count as usize {
+        let mut p = pos + n * sample_len;
+        if flags & TRUN_DURATION != 0 {
+            run.duration += be_u32(trun, p)? as u64;
+            p += 4;
+        }
+        if flags & TRUN_SIZE != 0 {
+            p += 4;
+        }
+        if flags & TRUN_FLAGS != 0 {
+            if n == 0 {
+                run.sample_flags = Some(be_u32(trun, p)?);
+            }
+            p += 4;
+        }
+        if flags & TRUN_CTS != 0 && n == 0 {
+            let raw = be_u32(trun, p)?;
+            run.cts_offset = Some(if version == 0 {
+                raw as i64
+            } else {
+                raw as i32 as i64
+            });
+        }
+    }
+    Some(run)
+}
+
+// The `traf`s of all `moof`s in a media segment, in order.
+fn parse_fragments(data: &[u8]) -> Result<Vec<Fragment>, String> {
+    let mut fragments = Vec::new();
+    for (_, moof) in child_boxes(data).into_iter().filter(|(t, _)| t == b"moof") {
+        for (_, traf) in child_boxes(moof).into_iter().filter(|(t, _)| t == b"traf") {
+            let mut frag = Fragment::default();
+            let tfhd = find_box(traf, b"tfhd").ok_or("traf without tfhd")?;
+            parse_tfhd(tfhd, &mut frag).ok_or("truncated tfhd")?;
+            frag.base_decode_time = find_box(traf, b"tfdt").and_then(tfdt_time);
+            for (_, trun) in child_boxes(traf).into_iter().filter(|(t, _)| t == b"trun") {
+                let run = parse_trun(trun)
+                    .ok_or_else(|| format!("track {}: truncated trun", frag.track_id))?;
+                frag.runs.push(run);
+            }
+            fragments.push(frag);
+        }
+    }
+    Ok(fragments)
+}
+
+/// Cross-check media segment `segment` against init segment `init`.
+///
+/// `expected` is the start and duration of the segment according to the
+/// playlist, in seconds. Returns the discrepancies found.
+pub fn check_segment(init: &[u8], segment: &[u8], expected: Option<(f64, f64)>) -> Vec<String> {
+    let mut problems = Vec::new();
+    let tracks = parse_init(init);
+    if tracks.is_empty() {
+        problems.push("init segment has no moov or no tracks".to_string());
+        return problems;
+    }
+    let fragments = match parse_fragments(segment) {
+        Ok(f) if f.is_empty() => {
+            problems.push("media segment has no moof/traf".to_string());
+            return problems;
+        }
+        Ok(f) => f,
+        Err(e) => {
+            problems.push(e);
+            return problems;
+        }
+    };
+
+    for track in &tracks {
+        if track.timescale == 0 {
+            problems.push(format!("track {}: mdhd timescale is 0", track.track_id));
+        }
+        if track.trex.is_none() {
+            problems.push(format!(
+                "track {}: no trex in the init segment",
+                track.track_id
+            ));
+        }
+        if !fragments.iter().any(|f| f.track_id == track.track_id) {
+            problems.push(format!(
+                "track {}: in the init segment but not in the media segment",
+                track.track_id
+            ));
+        }
+    }
+
+    let mut seen = Vec::new();
+    for frag in &fragments {
+        let id = frag.track_id;
+        let Some(track) = tracks.iter().find(|t| t.track_id == id) else {
+            if !seen.contains(&id) {
+                let ids: Vec<_> = tracks.iter().map(|t| t.track_id.to_string()).collect();
+                problems.push(format!(
+                    "track {}: not in the init segment (tracks {})",
+                    id,
+                    ids.join(", ")
+                ));
+                seen.push(id);
+            }
+            continue;
+        };
+        let (trex_duration, trex_size, trex_flags) = track.trex.unwrap_or_default();
+        if frag.base_decode_time.is_none() {
+            problems.push(format!("track {}: traf without tfdt", id));
+        }
+        for run in &frag.runs {
+            if run.flags & TRUN_DURATION == 0 && frag.default_duration.unwrap_or(trex_duration) == 0
+            {
+                problems.push(format!(
+                    "track {}: trun has no sample durations, and neither tfhd nor trex has a default",
+                    id
+                ));
+            }
+            if run.flags & TRUN_SIZE == 0 && frag.default_size.unwrap_or(trex_size) == 0 {
+                problems.push(format!(
+                    "track {}: trun has no sample sizes, and neither tfhd nor trex has a default",
+                    id
+                ));
+            }
+        }
+
+        // Timing and the sync sample only apply to the first traf of a track.
+        if seen.contains(&id) {
+            continue;
+        }
+        seen.push(id);
+        if track.handler == "vide" {
+            if let Some(run) = frag.runs.first() {
+                let flags = run
+                    .first_sample_flags
+                    .or(run.sample_flags)
+                    .or(frag.default_flags)
+                    .unwrap_or(trex_flags);
+                if flags & NON_SYNC_SAMPLE != 0 {
+                    problems.push(format!(
+                        "track {}: first sample is not a sync sample (flags 0x{:08x})",
+                        id, flags
+                    ));
+                }
+            }
+        }
+        if track.timescale == 0 {
+            continue;
+        }
+        let timescale = track.timescale as f64;
+        if let (Some((start, duration)), Some(tfdt)) = (expected, frag.base_decode_time) {
+            let cts = frag.runs.first().and_then(|r| r.cts_offset).unwrap_or(0);
+            let actual = (tfdt as i64 + cts) as f64 / timescale;
+            if (actual - start).abs() > TOLERANCE {
+                problems.push(format!(
+                    "track {}: starts at {:.3}s (tfdt {} / timescale {}), playlist says {:.3}s",
+                    id, actual, tfdt, track.timescale, start
+                ));
+            }
+            let total: u64 = fragments
+                .iter()
+                .filter(|f| f.track_id == id)
+                .flat_map(|f| f.runs.iter().map(move |r| (f, r)))
+                .map(|(f, r)| {
+                    if r.flags & TRUN_DURATION != 0 {
+                        r.duration
+                    } else {
+                        r.sample_count as u64 * f.default_duration.unwrap_or(trex_duration) as u64
+                    }
+                })
+                .sum();
+            let actual = total as f64 / timescale;
+            if (actual - duration).abs() > TOLERANCE {
+                problems.push(format!(
+                    "track {}: duration {:.3}s, EXTINF says {:.3}s",
+                    id, actual, duration
+                ));
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::segment::isobmff::write_box_header;
+
+    fn mk_box(btype: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_box_header(&mut out, btype, payload.len());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    fn init(timescale: u32, trex_flags: u32) -> Vec<u8> {
+        let tkhd = mk_box(
+            b"tkhd",
+            &[&[0u8; 12][..], &1u32.to_be_bytes(), &[0; 8]].concat(),
+        );
+        let mdhd = mk_box(
+            b"mdhd",
+            &[&[0u8; 12][..], &timescale.to_be_bytes(), &[0; 8]].concat(),
+        );
+        let hdlr = mk_box(b"hdlr", &[&[0u8; 8][..], b"vide", &[0; 13]].concat());
+        let mdia = mk_box(b"mdia", &[mdhd, hdlr].concat());
+        let trak = mk_box(b"trak", &[tkhd, mdia].concat());
+        // Default duration 0, default size 100.
+        let trex = [0, 1, 1, 0, 100, trex_flags]
+            .iter()
+            .flat_map(|v: &u32| v.to_be_bytes())
+            .collect::<Vec<u8>>();
+        let mvex = mk_box(b"mvex", &mk_box(b"trex", &trex));
+        [
+            mk_box(b"ftyp", b"iso6"),
+            mk_box(b"moov", &[trak, mvex].concat()),
+        ]
+        .concat()
+    }
+
+    // A fragment of track `track_id` with per-sample durations.
+    fn segment(track_id: u32, tfdt: u64, durations: &[u32]) -> Vec<u8> {
+        let tfhd = mk_box(b"tfhd", &[&[0u8; 4][..], &track_id.to_be_bytes()].concat());
+        let tfdt = mk_box(
+            b"tfdt",
+            &[&[1u8, 0, 0, 0][..], &tfdt.to_be_bytes()].concat(),
+        );
+        let mut trun = vec![0, 0, 0x01, 0x00];
+        trun.extend_from_slice(&(durations.len() as u32).to_be_bytes());
+        for d in durations {
+            trun.extend_from_slice(&d.to_be_bytes());
+        }
+        let traf = mk_box(b"traf", &[tfhd, tfdt, mk_box(b"trun", &trun)].concat());
+        let moof = mk_box(b"moof", &traf);
+        [moof, mk_box(b"mdat", &[0; 16])].concat()
+    }
+
+    #[test]
+    fn test_parse_init() {
+        assert_eq!(
+            parse_init(&init(90000, 0)),
+            vec![InitTrack {
+                track_id: 1,
+                timescale: 90000,
+                handler: "vide".to_string(),
+                trex: Some((0, 100, 0)),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_check_segment() {
+        let media = segment(1, 900_000, &[3000; 30]);
+        assert!(check_segment(&init(90000, 0), &media, Some((10.0, 1.0))).is_empty());
+
+        // tfdt in another timescale than the mdhd of the init segment.
+        let problems = check_segment(&init(48000, 0), &media, Some((10.0, 1.0)));
+        assert!(problems[0].starts_with("track 1: starts at 18.750s"));
+
+        // Track id mismatch.
+        let problems = check_segment(&init(90000, 0), &segment(2, 0, &[3000]), None);
+        assert_eq!(
+            problems,
+            vec![
+                "track 1: in the init segment but not in the media segment",
+                "track 2: not in the init segment (tracks 1)",
+            ]
+        );
+
+        // trex says the first sample is not a sync sample.
+        let problems = check_segment(&init(90000, NON_SYNC_SAMPLE), &media, None);
+        assert_eq!(
+            problems,
+            vec!["track 1: first sample is not a sync sample (flags 0x00010000)"]
+        );
+    }
+}
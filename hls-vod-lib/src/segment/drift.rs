@@ -0,0 +1,272 @@
+//! Audio/video drift over a whole title.
+//!
+//! Audio that slowly runs ahead of or behind the video is hard to spot in a
+//! single segment. `check_drift` generates the video segment and the audio
+//! segment of every sequence number, keeps only their timing (`tfdt` and
+//! the sum of the sample durations, see `compare::analyze`), and tracks two
+//! values over the title:
+//!
+//! - the offset: the `tfdt` of the audio minus that of the video, which is
+//!   what a player that honors `tfdt` sees;
+//! - the drift: where the audio ends up relative to the video if the
+//!   segments of each track are played back to back, which is what a player
+//!   that concatenates segments sees. It restarts at every discontinuity.
+//!
+//! Segments where either one exceeds the threshold are flagged.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+
+use crate::error::Result;
+use crate::media::StreamIndex;
+use crate::segment::compare::analyze;
+use crate::segment::generator::{
+    generate_audio_init_segment, generate_audio_segment, generate_video_init_segment,
+    generate_video_segment,
+};
+
+/// Default threshold for flagging a segment, in seconds.
+pub const DRIFT_THRESHOLD_SECS: f64 = 0.1;
+
+/// Timing of the video and the audio of one segment, in seconds.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SegmentDrift {
+    pub sequence: usize,
+    /// Start of the segment according to the segment index
+    pub expected_start: f64,
+    /// `tfdt` of the video segment
+    pub video_start: f64,
+    /// Sum of the sample durations of the video segment
+    pub video_duration: f64,
+    /// `tfdt` of the audio segment
+    pub audio_start: f64,
+    /// Sum of the sample durations of the audio segment
+    pub audio_duration: f64,
+    /// `audio_start - video_start`
+    pub offset: f64,
+    /// Audio minus video at the end of the segment, played back to back
+    /// since the start of the title or the last discontinuity
+    pub drift: f64,
+    /// The offset or the drift exceeds the threshold
+    pub flagged: bool,
+    /// The segments could not be generated or parsed
+    pub error: Option<String>,
+}
+
+/// Drift of an audio track against the video over a whole title.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DriftReport {
+    pub video_track: usize,
+    pub audio_track: usize,
+    pub threshold_secs: f64,
+    /// Largest absolute offset
+    pub max_offset: f64,
+    /// Largest absolute drift
+    pub max_drift: f64,
+    /// Sequence numbers of the flagged segments
+    pub flagged: Vec<usize>,
+    /// The check was cancelled before the end of the title
+    pub cancelled: bool,
+    pub segments: Vec<SegmentDrift>,
+    // End of the audio and of the video, played back to back.
+    #[serde(skip)]
+    timeline: Option<(f64, f64)>,
+}
+
+/// Start (`tfdt`) and duration in seconds of the first track of media
+/// segment `segment`, in the timescale of init segment `init`.
+pub(crate) fn track_timing(init: &[u8], segment: &[u8]) -> Option<(f64, f64)> {
+    let timescale = analyze(init).timescales.first()?.1;
+    if timescale == 0 {
+        return None;
+    }
+    let track = analyze(segment).tracks.into_iter().next()?;
+    let timescale = timescale as f64;
+    Some((
+        track.base_decode_time? as f64 / timescale,
+        track.duration as f64 / timescale,
+    ))
+}
+
+impl DriftReport {
+    fn new(video_track: usize, audio_track: usize, threshold_secs: f64) -> Self {
+        Self {
+            video_track,
+            audio_track,
+            threshold_secs,
+            ..Default::default()
+        }
+    }
+
+    /// Add the next segment: the start and duration of the video and of
+    /// the audio, or why they are not known.
+    fn push(
+        &mut self,
+        sequence: usize,
+        expected_start: f64,
+        discontinuity: bool,
+        timing: std::result::Result<((f64, f64), (f64, f64)), String>,
+    ) {
+        let mut point = SegmentDrift {
+            sequence,
+            expected_start,
+            ..Default::default()
+        };
+        let ((video_start, video_duration), (audio_start, audio_duration)) = match timing {
+            Ok(timing) => timing,
+            Err(e) => {
+                // The next segment starts a new back to back timeline.
+                self.timeline = None;
+                point.error = Some(e);
+                self.segments.push(point);
+                return;
+            }
+        };
+        let (audio_end, video_end) = match self.timeline {
+            Some(timeline) if !discontinuity => timeline,
+            _ => (audio_start, video_start),
+        };
+        let timeline = (audio_end + audio_duration, video_end + video_duration);
+        self.timeline = Some(timeline);
+
+        point.video_start = video_start;
+        point.video_duration = video_duration;
+        point.audio_start = audio_start;
+        point.audio_duration = audio_duration;
+        point.offset = audio_start - video_start;
+        point.drift = timeline.0 - timeline.1;
+        point.flagged =
+            point.offset.abs() > self.threshold_secs || point.drift.abs() > self.threshold_secs;
+
+        self.max_offset = self.max_offset.max(point.offset.abs());
+        self.max_drift = self.max_drift.max(point.drift.abs());
+        if point.flagged {
+            self.flagged.push(sequence);
+        }
+        self.segments.push(point);
+    }
+}
+
+/// Generate the video segments of `video_track` and the audio segments of
+/// `audio_track` of the whole title, and report the drift between them.
+///
+/// The segments are generated as a player would get them (default audio
+/// transcoding, retries), but not cached. This takes as long as generating
+/// the title, so it is for debugging only. Setting `cancel` stops it after
+/// the current segment.
+pub(crate) fn check_drift(
+    index: &StreamIndex,
+    video_track: usize,
+    audio_track: usize,
+    threshold_secs: f64,
+    cancel: &AtomicBool,
+) -> Result<DriftReport> {
+    let video_init = generate_video_init_segment(index)?;
+    let audio_init = generate_audio_init_segment(index, audio_track, None)?;
+    let source = &index.source_path;
+    let timebase = f64::from(index.video_timebase);
+
+    let mut report = DriftReport::new(video_track, audio_track, threshold_secs);
+    for segment in &index.segments {
+        if cancel.load(Ordering::Relaxed) {
+            report.cancelled = true;
+            break;
+        }
+        let sequence = segment.sequence;
+        let timing = generate_video_segment(index, video_track, sequence, source, None)
+            .and_then(|video| {
+                let audio =
+                    generate_audio_segment(index, audio_track, sequence, source, None, None)?;
+                Ok((video, audio))
+            })
+            .map_err(|e| e.to_string())
+            .and_then(|(video, audio)| {
+                let video = track_timing(&video_init, &video);
+                let audio = track_timing(&audio_init, &audio);
+                video.zip(audio).ok_or_else(|| "no tfdt".to_string())
+            });
+        report.push(
+            sequence,
+            segment.start_pts as f64 * timebase,
+            index.is_discontinuity(sequence),
+            timing,
+        );
+    }
+    if !report.flagged.is_empty() {
+        tracing::warn!(
+            "{}: audio track {} drifts more than {}s in {} segment(s), at most {:.3}s",
+            source.display(),
+            audio_track,
+            threshold_secs,
+            report.flagged.len(),
+            report.max_offset.max(report.max_drift)
+        );
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drift_report() {
+        let mut report = DriftReport::new(0, 1, 0.1);
+        // Audio segments 40ms short: the drift adds up, the offset doesn't.
+        for sequence in 0..4 {
+            let start = sequence as f64 * 4.0;
+            report.push(
+                sequence,
+                start,
+                false,
+                Ok(((start, 4.0), (start + 0.01, 3.96))),
+            );
+        }
+        let drifts: Vec<f64> = report.segments.iter().map(|s| s.drift).collect();
+        assert!((drifts[3] + 0.15).abs() < 1e-9, "{:?}", drifts);
+        assert!((report.segments[3].offset - 0.01).abs() < 1e-9);
+        assert_eq!(report.flagged, vec![2, 3]);
+
+        // A failed segment, then a discontinuity: both restart the timeline.
+        report.push(4, 16.0, false, Err("read error".to_string()));
+        report.push(5, 20.0, false, Ok(((20.0, 4.0), (20.0, 4.0))));
+        report.push(6, 0.0, true, Ok(((0.0, 4.0), (0.0, 4.0))));
+        assert_eq!(report.segments[4].error.as_deref(), Some("read error"));
+        assert_eq!(report.segments[5].drift, 0.0);
+        assert_eq!(report.segments[6].drift, 0.0);
+        assert_eq!(report.flagged, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_check_drift() {
+        use crate::tests::fixtures::generate::{generate, FixtureSpec};
+
+        let Some(path) = generate(&FixtureSpec::h264_aac()) else {
+            return;
+        };
+        let index = StreamIndex::open(&path, None).unwrap();
+        let video = index.video_streams[0].stream_index;
+        let audio = index.audio_streams[0].stream_index;
+
+        let report = check_drift(
+            &index,
+            video,
+            audio,
+            DRIFT_THRESHOLD_SECS,
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+        assert!(!report.cancelled);
+        assert_eq!(report.segments.len(), index.segments.len());
+        for segment in &report.segments {
+            assert_eq!(segment.error, None, "segment {}", segment.sequence);
+            assert!(segment.video_duration > 0.0 && segment.audio_duration > 0.0);
+        }
+        assert!(report.flagged.is_empty(), "{:?}", report.segments);
+
+        let report = check_drift(&index, video, audio, 0.1, &AtomicBool::new(true)).unwrap();
+        assert!(report.cancelled);
+        assert!(report.segments.is_empty());
+    }
+}
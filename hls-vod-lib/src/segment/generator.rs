@@ -6,13 +6,55 @@ use std::path::Path;
 use ffmpeg_next::{self as ffmpeg, Rescale};
 
 use crate::error::{HlsError, Result};
-use crate::media::{SegmentInfo, StreamIndex};
+use crate::hlsvideo::ProgressObserver;
+use crate::media::{ContextGuard, DemuxCursor, DemuxStart, SegmentInfo, StreamIndex};
 use crate::segment::muxer::Fmp4Muxer;
+use crate::segment::retry::{generate_with_retry, Attempt};
+#[cfg(feature = "subtitles")]
 use crate::subtitle::decoder::is_bitmap_subtitle_codec;
+#[cfg(feature = "subtitles")]
 use crate::subtitle::extractor::SubtitleExtractor;
-use crate::subtitle::webvtt::{WebVttConfig, WebVttWriter};
-use crate::transcode::encoder::{get_recommended_bitrate, AacEncoder};
-use crate::transcode::resampler::HLS_SAMPLE_RATE;
+#[cfg(feature = "subtitles")]
+use crate::subtitle::webvtt::{SpanningCues, TimestampMap, WebVttConfig, WebVttWriter};
+#[cfg(feature = "transcode")]
+use crate::transcode::encoder::{aac_bitrate, AacEncoder};
+
+/// Codec parameters of the AAC encoder used for audio transcoding, at
+/// `bitrate` if one was requested.
+#[cfg(feature = "transcode")]
+fn aac_codec_parameters(
+    channels: u16,
+    config: &crate::config::LibConfig,
+    bitrate: Option<u64>,
+) -> Result<ffmpeg::codec::Parameters> {
+    let bitrate = bitrate.unwrap_or_else(|| aac_bitrate(channels, &config.aac_bitrates));
+    let encoder = AacEncoder::open(config.sample_rate, 2, bitrate)?;
+    Ok(encoder.codec_parameters())
+}
+
+#[cfg(not(feature = "transcode"))]
+fn aac_codec_parameters(
+    _channels: u16,
+    _config: &crate::config::LibConfig,
+    _bitrate: Option<u64>,
+) -> Result<ffmpeg::codec::Parameters> {
+    Err(HlsError::FeatureDisabled("transcode"))
+}
+
+/// Whether the transcode target `requested` from a URL is AAC, and the
+/// bitrate it asks for (see `params::parse_transcode_target`).
+fn requested_aac(requested: Option<&str>) -> (bool, Option<u64>) {
+    match requested.and_then(crate::params::parse_transcode_target) {
+        Some(("aac", bitrate)) => (true, bitrate),
+        _ => (false, None),
+    }
+}
+
+/// The audio track that the transcode target `requested` from a URL mixes
+/// in, if any (see `params::split_audio_mix`).
+fn requested_mix(requested: Option<&str>) -> Option<usize> {
+    requested.and_then(|t| crate::params::split_audio_mix(t).1)
+}
 
 /// Builder for configuring and generating an initialization segment (`init.mp4`).
 pub(crate) struct InitSegmentBuilder<'a> {
@@ -20,6 +62,7 @@ pub(crate) struct InitSegmentBuilder<'a> {
     video_idx: Option<usize>,
     audio_idx: Option<usize>,
     transcode_audio_to_aac: bool,
+    aac_bitrate: Option<u64>,
 }
 
 impl<'a> InitSegmentBuilder<'a> {
@@ -30,6 +73,7 @@ impl<'a> InitSegmentBuilder<'a> {
             video_idx: None,
             audio_idx: None,
             transcode_audio_to_aac: false,
+            aac_bitrate: None,
         }
     }
 
@@ -52,6 +96,12 @@ impl<'a> InitSegmentBuilder<'a> {
         self
     }
 
+    /// Bitrate of the transcoded AAC audio, `None` for the configured one.
+    pub fn aac_bitrate(mut self, bitrate: Option<u64>) -> Self {
+        self.aac_bitrate = bitrate;
+        self
+    }
+
     /// Construct the initialization segment bytes.
     pub fn build(self) -> Result<Bytes> {
         let mut input = self.index.get_context()?;
@@ -81,12 +131,12 @@ impl<'a> InitSegmentBuilder<'a> {
             } else if is_target_audio {
                 if self.transcode_audio_to_aac {
                     let audio_info = self.index.get_audio_stream(idx);
-                    let bitrate =
-                        get_recommended_bitrate(audio_info.map(|a| a.channels).unwrap_or(2));
-                    let encoder = AacEncoder::open(HLS_SAMPLE_RATE, 2, bitrate)?;
-                    muxer.add_audio_stream(&encoder.codec_parameters(), idx)?;
-                } else {
+                    let channels = audio_info.map(|a| a.channels).unwrap_or(2);
+                    let params =
+                        aac_codec_parameters(channels, &self.index.config, self.aac_bitrate)?;
                     muxer.add_audio_stream(&params, idx)?;
+                } else {
+                    muxer.add_passthrough_audio_stream(&params, idx)?;
                 }
                 has_audio = true;
             }
@@ -119,6 +169,10 @@ impl<'a> InitSegmentBuilder<'a> {
         // Pass 3: Fix TREX durations
         self.apply_trex_fixes(&mut data, has_video, has_audio);
 
+        if let Some(ftyp) = crate::segment::profile::compatibility_profile().ftyp() {
+            crate::segment::isobmff::replace_ftyp(&mut data, &ftyp);
+        }
+
         Ok(Bytes::from(data))
     }
 
@@ -227,12 +281,13 @@ pub(crate) fn generate_audio_init_segment(
     requested_transcode: Option<&str>,
 ) -> Result<Bytes> {
     let audio_info = index.get_audio_stream(track_index)?;
-    let transcode_to_aac = requested_transcode == Some("aac")
-        || audio_info.transcode_to == Some(ffmpeg::codec::Id::AAC);
+    let (requested_aac, aac_bitrate) = requested_aac(requested_transcode);
+    let transcode_to_aac = requested_aac || audio_info.transcode_to == Some(ffmpeg::codec::Id::AAC);
 
     InitSegmentBuilder::new(index)
         .with_audio_track(track_index)
         .transcode_audio_to_aac(transcode_to_aac)
+        .aac_bitrate(aac_bitrate)
         .build()
 }
 
@@ -244,7 +299,8 @@ pub(crate) fn generate_interleaved_init_segment(
     requested_audio_transcode: Option<&str>,
 ) -> Result<Bytes> {
     let audio_info = index.get_audio_stream(audio_idx).ok();
-    let transcode_to_aac = requested_audio_transcode == Some("aac")
+    let (requested_aac, aac_bitrate) = requested_aac(requested_audio_transcode);
+    let transcode_to_aac = requested_aac
         || audio_info
             .map(|t| t.transcode_to == Some(ffmpeg::codec::Id::AAC))
             .unwrap_or(false);
@@ -253,13 +309,29 @@ pub(crate) fn generate_interleaved_init_segment(
         .with_video_track(video_idx)
         .with_audio_track(audio_idx)
         .transcode_audio_to_aac(transcode_to_aac)
+        .aac_bitrate(aac_bitrate)
         .build()
 }
 
+/// The track ID of the audio track in an interleaved init segment.
+///
+/// The muxer adds the tracks in the order of the streams in the file, and
+/// FFmpeg numbers them from 1.
+fn interleaved_audio_track_id(video_idx: usize, audio_idx: usize) -> u32 {
+    if audio_idx < video_idx {
+        1
+    } else {
+        2
+    }
+}
+
 /// Generate an interleaved audio+video media segment (`.m4s`).
 ///
 /// Resolves whether the audio track needs transcoding to AAC and delegates to
-/// the common FFmpeg muxing path.
+/// the common FFmpeg muxing path. Transcoded segments are generated as
+/// separate audio and video halves in parallel, see
+/// `generate_interleaved_segment_parallel`. Those are not streamed in
+/// fragments: an observer that wants data gets the merged segment at once.
 pub(crate) fn generate_interleaved_segment(
     index: &StreamIndex,
     video_idx: usize,
@@ -267,6 +339,7 @@ pub(crate) fn generate_interleaved_segment(
     segment: &SegmentInfo,
     _source_path: &Path,
     requested_audio_transcode: Option<&str>,
+    progress: Option<&dyn ProgressObserver>,
 ) -> Result<Bytes> {
     if index.video_streams.is_empty() || index.audio_streams.is_empty() {
         return Err(HlsError::StreamNotFound(
@@ -274,147 +347,497 @@ pub(crate) fn generate_interleaved_segment(
         ));
     }
 
-    let transcode_to_aac = requested_audio_transcode == Some("aac")
+    let (requested_aac, aac_bitrate) = requested_aac(requested_audio_transcode);
+    let transcode_to_aac = requested_aac
         || index
             .get_audio_stream(audio_idx)
             .map(|t| t.transcode_to == Some(ffmpeg::codec::Id::AAC))
             .unwrap_or(false);
 
-    generate_media_segment_ffmpeg(
-        segment,
-        "av",
-        Some(video_idx),
-        Some(audio_idx),
+    let attempt = Attempt {
+        transcode_audio: transcode_to_aac,
+        aac_bitrate,
+        ..Default::default()
+    };
+    let audio_track_id = interleaved_audio_track_id(video_idx, audio_idx);
+    generate_with_retry(
         index,
-        transcode_to_aac,
+        segment.sequence,
+        attempt,
+        true,
+        progress,
+        |attempt, progress| {
+            if attempt.transcode_audio {
+                if let Some(data) = generate_interleaved_segment_parallel(
+                    index,
+                    video_idx,
+                    audio_idx,
+                    audio_track_id,
+                    segment,
+                    attempt,
+                    progress,
+                )? {
+                    if let Some(progress) = progress.filter(|p| p.wants_data()) {
+                        progress.on_data(data.clone());
+                    }
+                    return Ok(data);
+                }
+            }
+
+            generate_media_segment_ffmpeg(
+                segment,
+                "av",
+                Some(video_idx),
+                Some(audio_idx),
+                index,
+                attempt,
+                progress,
+            )
+        },
     )
 }
 
+/// Generate the video and the transcoded audio half of an interleaved segment
+/// in parallel, and merge them into one `moof` + `mdat`.
+///
+/// Audio transcoding dominates the generation time of a muxed segment, so
+/// running it next to the video demux/mux roughly halves the latency. Each
+/// half gets the same `tfdt` it would have had in the interleaved segment,
+/// and the audio half `audio_track_id`, the track ID of the audio track of
+/// the interleaved init segment. `progress` only gets the fragment progress
+/// of the video half. Returns `None` if the halves could not be merged.
+fn generate_interleaved_segment_parallel(
+    index: &StreamIndex,
+    video_idx: usize,
+    audio_idx: usize,
+    audio_track_id: u32,
+    segment: &SegmentInfo,
+    attempt: Attempt,
+    progress: Option<&dyn ProgressObserver>,
+) -> Result<Option<Bytes>> {
+    let progress = progress.map(FragmentProgress);
+    let progress = progress.as_ref().map(|p| p as &dyn ProgressObserver);
+    let fresh_input = attempt.fresh_input;
+    // The audio half logs in the span of the request, like the video half.
+    let span = tracing::Span::current();
+    let (video, audio) = std::thread::scope(|s| {
+        let audio = s.spawn(|| {
+            let _span = span.enter();
+            generate_media_segment_ffmpeg(
+                segment,
+                "audio",
+                None,
+                Some(audio_idx),
+                index,
+                Attempt {
+                    fresh_input,
+                    transcode_audio: true,
+                    aac_bitrate: attempt.aac_bitrate,
+                    ..Default::default()
+                },
+                None,
+            )
+        });
+        let video = generate_media_segment_ffmpeg(
+            segment,
+            "video",
+            Some(video_idx),
+            None,
+            index,
+            Attempt {
+                fresh_input,
+                transcode_audio: false,
+                ..Default::default()
+            },
+            progress,
+        );
+        let audio = audio.join().unwrap_or_else(|_| {
+            Err(HlsError::Transcode(
+                "audio transcoding thread panicked".to_string(),
+            ))
+        });
+        (video, audio)
+    });
+    let (video, audio) = (video?, audio?);
+
+    let merged = crate::segment::isobmff::merge_track_fragments(&video, &audio, audio_track_id);
+    let Some(merged) = merged else {
+        tracing::debug!(
+            "Could not merge audio/video halves of segment {}, muxing serially",
+            segment.sequence
+        );
+        return Ok(None);
+    };
+    Ok(Some(Bytes::from(apply_profile(merged, true))))
+}
+
+/// An observer that passes on the fragment progress, but not the data.
+struct FragmentProgress<'a>(&'a dyn ProgressObserver);
+
+impl ProgressObserver for FragmentProgress<'_> {
+    fn on_fragment(&self, progress: &crate::hlsvideo::SegmentProgress) {
+        self.0.on_fragment(progress)
+    }
+}
+
+/// Segment `sequence`, extended up to and including segment `end_sequence`.
+pub(crate) fn segment_range(
+    index: &StreamIndex,
+    kind: &str,
+    sequence: usize,
+    end_sequence: usize,
+) -> Result<SegmentInfo> {
+    let mut segment = index.get_segment(kind, sequence)?.clone();
+    if end_sequence > sequence {
+        let end_segment = index.get_segment(kind, end_sequence)?;
+        segment.end_pts = end_segment.end_pts;
+        segment.duration_secs = index
+            .segments
+            .iter()
+            .filter(|s| (sequence..=end_sequence).contains(&s.sequence))
+            .map(|s| s.duration_secs)
+            .sum();
+    }
+    Ok(segment)
+}
+
 /// Generate a video-only media segment (`.m4s`) for the given sequence number.
 pub(crate) fn generate_video_segment(
     index: &StreamIndex,
     track_index: usize,
     sequence: usize,
+    source_path: &Path,
+    progress: Option<&dyn ProgressObserver>,
+) -> Result<Bytes> {
+    generate_video_segment_range(
+        index,
+        track_index,
+        sequence,
+        sequence,
+        source_path,
+        progress,
+    )
+}
+
+/// Generate a long video segment that spans segments `sequence` up to and
+/// including `end_sequence`. Segments start with a keyframe, so a run of
+/// them is a valid segment too.
+pub(crate) fn generate_video_segment_range(
+    index: &StreamIndex,
+    track_index: usize,
+    sequence: usize,
+    end_sequence: usize,
     _source_path: &Path,
+    progress: Option<&dyn ProgressObserver>,
 ) -> Result<Bytes> {
-    let segment = index.get_segment("video", sequence)?;
-    generate_media_segment_ffmpeg(segment, "video", Some(track_index), None, index, false)
+    let segment = &segment_range(index, "video", sequence, end_sequence)?;
+    generate_with_retry(
+        index,
+        sequence,
+        Attempt::default(),
+        false,
+        progress,
+        |attempt, progress| {
+            generate_media_segment_ffmpeg(
+                segment,
+                "video",
+                Some(track_index),
+                None,
+                index,
+                attempt,
+                progress,
+            )
+        },
+    )
 }
 
 /// Generate an audio segment
 ///
 /// Dispatches to the transcoding pipeline for non-AAC streams; falls back to
-/// direct packet copy for AAC streams.
+/// direct packet copy for AAC streams. A transcode target with a mix
+/// (`aacm2`) mixes another audio track into this one.
 pub(crate) fn generate_audio_segment(
     index: &StreamIndex,
     track_index: usize,
     sequence: usize,
+    source_path: &Path,
+    requested_transcode: Option<&str>,
+    progress: Option<&dyn ProgressObserver>,
+) -> Result<Bytes> {
+    generate_audio_segment_range(
+        index,
+        track_index,
+        sequence,
+        sequence,
+        source_path,
+        requested_transcode,
+        progress,
+    )
+}
+
+/// Generate a long audio segment that spans segments `sequence` up to and
+/// including `end_sequence`.
+///
+/// It is generated as a single fragment, with the sequence number and tfdt
+/// of the first segment and the end of the last one.
+pub(crate) fn generate_audio_segment_range(
+    index: &StreamIndex,
+    track_index: usize,
+    sequence: usize,
+    end_sequence: usize,
     _source_path: &Path,
     requested_transcode: Option<&str>,
+    progress: Option<&dyn ProgressObserver>,
 ) -> Result<Bytes> {
-    let segment = index.get_segment("audio", sequence)?;
+    let segment = &segment_range(index, "audio", sequence, end_sequence)?;
 
     // Check if this track needs transcoding
     // TODO: support more codecs than aac.
     let audio_info = index.get_audio_stream(track_index)?;
-    let transcode_to_aac = requested_transcode == Some("aac")
-        || audio_info.transcode_to == Some(ffmpeg::codec::Id::AAC);
-
-    if transcode_to_aac {
-        generate_media_segment_ffmpeg(
-            segment,
-            "audio",
-            None,
-            Some(track_index),
-            index,
-            transcode_to_aac,
-        )
-    } else {
-        generate_media_segment_ffmpeg(segment, "audio", None, Some(track_index), index, false)
+    let (requested_aac, aac_bitrate) = requested_aac(requested_transcode);
+    let transcode_to_aac = requested_aac || audio_info.transcode_to == Some(ffmpeg::codec::Id::AAC);
+
+    // An audio mix: the other track is mixed into this one.
+    let mix_track = requested_mix(requested_transcode);
+    if let Some(mix_track) = mix_track {
+        if mix_track == track_index {
+            return Err(HlsError::StreamNotFound(format!(
+                "audio track {} can't be mixed with itself",
+                track_index
+            )));
+        }
+        index.get_audio_stream(mix_track)?;
     }
+
+    let attempt = Attempt {
+        transcode_audio: transcode_to_aac,
+        aac_bitrate,
+        mix_track,
+        ..Default::default()
+    };
+    generate_with_retry(
+        index,
+        sequence,
+        attempt,
+        true,
+        progress,
+        |attempt, progress| {
+            generate_media_segment_ffmpeg(
+                segment,
+                "audio",
+                None,
+                Some(track_index),
+                index,
+                attempt,
+                progress,
+            )
+        },
+    )
 }
 
+/// A WebVTT segment without cues, served for empty subtitle periods.
+pub(crate) const EMPTY_SUBTITLE_SEGMENT: &[u8] = b"WEBVTT\n\n";
+
 /// Generate a subtitle segment (WebVTT).
 ///
 /// Uses the per-sample byte-offset index built at scan time to seek directly
 /// to each subtitle sample in the file.  No full-file scan, no iteration over
 /// video/audio packets — only the subtitle samples that fall within the
 /// requested time range are read.
+///
+/// With `SubtitleTimestamps::Mpegts` the segment starts with an
+/// `X-TIMESTAMP-MAP` that anchors it to the start of the first segment.
+#[cfg(feature = "subtitles")]
 pub(crate) fn generate_subtitle_segment(
     index: &StreamIndex,
     track_index: usize,
     start_sequence: usize,
     end_sequence: usize,
     _source_path: &Path,
+    timestamps: crate::params::SubtitleTimestamps,
 ) -> Result<Bytes> {
-    let start_segment = index.get_segment("subtitle", start_sequence)?;
-    let end_segment = index.get_segment("subtitle", end_sequence)?;
+    let mut segments = generate_subtitle_segments(
+        index,
+        &[track_index],
+        start_sequence,
+        end_sequence,
+        timestamps,
+    )?;
+    Ok(segments.remove(0))
+}
 
-    let sub_info = index.get_subtitle_stream(track_index)?;
+/// The samples of one subtitle track in the requested segments, and the
+/// cues read from them so far.
+#[cfg(feature = "subtitles")]
+struct SubtitleWindow {
+    track_index: usize,
+    extractor: SubtitleExtractor,
+    stream_timebase: ffmpeg::Rational,
+    sub_start_time: i64,
+    /// Segment boundaries in the subtitle stream's absolute PTS space
+    abs_start: i64,
+    abs_end: i64,
+    /// The first sample in the segments, if any
+    first_sample_pts: Option<i64>,
+    /// PTS of the samples in the segments that were not read yet, so that
+    /// reading can stop early once all are seen
+    remaining: std::collections::HashSet<i64>,
+    cues: Vec<crate::subtitle::extractor::SubtitleCue>,
+}
 
-    if is_bitmap_subtitle_codec(sub_info.codec_id) {
-        return Err(HlsError::Muxing(format!(
-            "Subtitle stream {} uses a bitmap codec ({:?}) which cannot be converted to WebVTT",
-            track_index, sub_info.codec_id
-        )));
-    }
+#[cfg(feature = "subtitles")]
+impl SubtitleWindow {
+    fn new(
+        index: &StreamIndex,
+        track_index: usize,
+        start_segment: &SegmentInfo,
+        end_segment: &SegmentInfo,
+    ) -> Result<SubtitleWindow> {
+        let sub_info = index.get_subtitle_stream(track_index)?;
+
+        if is_bitmap_subtitle_codec(sub_info.codec_id) {
+            return Err(HlsError::Muxing(format!(
+                "Subtitle stream {} uses a bitmap codec ({:?}) which cannot be converted to WebVTT",
+                track_index, sub_info.codec_id
+            )));
+        }
 
-    let video_tb = index.video_timebase;
-    let stream_timebase = sub_info.timebase;
-    let sub_start_time = sub_info.start_time;
+        let video_tb = index.video_timebase;
+        let stream_timebase = sub_info.timebase;
+        let sub_start_time = sub_info.start_time;
 
-    // Compute segment boundaries in the subtitle stream's timebase (playtime-relative)
-    // video_st is 0 for the purpose of playtime since start_pts already accounts for it.
-    let seg_start_playtime = start_segment.start_pts;
-    let seg_end_playtime = end_segment.end_pts;
+        // Compute segment boundaries in the subtitle stream's timebase (playtime-relative)
+        // video_st is 0 for the purpose of playtime since start_pts already accounts for it.
+        let start_ts_playtime = crate::ffmpeg_utils::utils::rescale_ts(
+            start_segment.start_pts,
+            video_tb,
+            stream_timebase,
+        );
+        let end_ts_playtime =
+            crate::ffmpeg_utils::utils::rescale_ts(end_segment.end_pts, video_tb, stream_timebase);
+
+        // Binary-search the sample index for the first entry that could overlap
+        // the segment: find the first entry whose pts + (some duration) >= start.
+        // Since we don't store duration in the index, we use pts >= start - 10s
+        // as a conservative lower bound (subtitle cues are rarely > 10s long).
+        let search_start_ts = start_ts_playtime + sub_start_time
+            - crate::ffmpeg_utils::utils::rescale_ts(
+                10,
+                ffmpeg::Rational::new(1, 1),
+                stream_timebase,
+            );
+
+        let first_idx = sub_info
+            .sample_index
+            .partition_point(|s| s.pts < search_start_ts);
+
+        // Collect the subset of samples that fall within [start_ts_playtime, end_ts_playtime)
+        // expressed in the subtitle stream's absolute PTS space.
+        let abs_start = start_ts_playtime + sub_start_time;
+        let abs_end = end_ts_playtime + sub_start_time;
+
+        let matching: Vec<i64> = sub_info.sample_index[first_idx..]
+            .iter()
+            .take_while(|s| s.pts < abs_end)
+            .map(|s| s.pts)
+            .collect();
+
+        Ok(SubtitleWindow {
+            track_index,
+            extractor: SubtitleExtractor::new(sub_info.codec_id, stream_timebase),
+            stream_timebase,
+            sub_start_time,
+            abs_start,
+            abs_end,
+            first_sample_pts: matching.first().copied(),
+            remaining: matching.into_iter().collect(),
+            cues: Vec::new(),
+        })
+    }
+}
 
-    let start_ts_playtime =
-        crate::ffmpeg_utils::utils::rescale_ts(seg_start_playtime, video_tb, stream_timebase);
-    let end_ts_playtime =
-        crate::ffmpeg_utils::utils::rescale_ts(seg_end_playtime, video_tb, stream_timebase);
+/// Generate the WebVTT segments of several subtitle tracks for the same
+/// segments, reading the file once for all of them.
+///
+/// Returns the segments in the order of `tracks`, which must be distinct.
+#[cfg(feature = "subtitles")]
+pub(crate) fn generate_subtitle_segments(
+    index: &StreamIndex,
+    tracks: &[usize],
+    start_sequence: usize,
+    end_sequence: usize,
+    timestamps: crate::params::SubtitleTimestamps,
+) -> Result<Vec<Bytes>> {
+    let start_segment = index.get_segment("subtitle", start_sequence)?;
+    let end_segment = index.get_segment("subtitle", end_sequence)?;
+    let video_tb = index.video_timebase;
 
-    // Segment bounds in milliseconds for clamping
+    // Segment start in milliseconds, on the cue timeline
     let seg_start_ms = crate::ffmpeg_utils::utils::rescale_ts(
         start_segment.start_pts,
         video_tb,
         ffmpeg::Rational::new(1, 1000),
     );
-    let seg_end_ms = crate::ffmpeg_utils::utils::rescale_ts(
-        end_segment.end_pts,
-        video_tb,
-        ffmpeg::Rational::new(1, 1000),
-    );
-
-    // Binary-search the sample index for the first entry that could overlap
-    // the segment: find the first entry whose pts + (some duration) >= start.
-    // Since we don't store duration in the index, we use pts >= start - 10s
-    // as a conservative lower bound (subtitle cues are rarely > 10s long).
-    let search_start_ts = start_ts_playtime + sub_start_time
-        - crate::ffmpeg_utils::utils::rescale_ts(10, ffmpeg::Rational::new(1, 1), stream_timebase);
-
-    let first_idx = sub_info
-        .sample_index
-        .partition_point(|s| s.pts < search_start_ts);
 
-    // Collect the subset of samples that fall within [start_ts_playtime, end_ts_playtime)
-    // expressed in the subtitle stream's absolute PTS space.
-    let abs_start = start_ts_playtime + sub_start_time;
-    let abs_end = end_ts_playtime + sub_start_time;
+    // The cue times are on the media timeline, the same as the video tfdt,
+    // which may be shifted by the negative timestamp policy.
+    let timestamp_map = match timestamps {
+        crate::params::SubtitleTimestamps::Zero => None,
+        crate::params::SubtitleTimestamps::Mpegts => Some(TimestampMap {
+            mpegts: (crate::ffmpeg_utils::utils::rescale_ts(
+                start_segment.start_pts,
+                video_tb,
+                ffmpeg::Rational::new(1, 90000),
+            ) + timeline_shift(index, ffmpeg::Rational::new(1, 90000)))
+            .max(0) as u64,
+            local_ms: seg_start_ms,
+        }),
+    };
 
-    let matching: Vec<_> = sub_info.sample_index[first_idx..]
+    let mut windows = tracks
         .iter()
-        .take_while(|s| s.pts < abs_end)
-        .collect();
+        .map(|&track_index| SubtitleWindow::new(index, track_index, start_segment, end_segment))
+        .collect::<Result<Vec<_>>>()?;
 
-    if matching.is_empty() {
-        // No subtitle cues in this segment — return an empty WebVTT
-        let config = WebVttConfig {
-            include_header_comment: false,
-        };
-        let mut writer = WebVttWriter::with_config(config);
-        return Ok(writer.write(&[]));
+    // Segments without subtitle cues don't need the file.
+    if windows.iter().any(|w| !w.remaining.is_empty()) {
+        read_subtitle_packets(index, &mut windows)?;
     }
 
+    let segments = windows
+        .into_iter()
+        .map(|mut window| {
+            // Cue times are not clamped to the segment: a cue that is cut at the
+            // segment boundary would be a different cue in every segment.
+            window
+                .cues
+                .retain(|cue| cue.start_ms < cue.end_ms && cue.end_ms > seg_start_ms);
+
+            let config = WebVttConfig {
+                include_header_comment: false,
+                timestamp_map,
+                ..Default::default()
+            };
+            let mut writer = WebVttWriter::with_config(config);
+            let bytes = writer.write(&window.cues);
+
+            tracing::debug!(
+                track_index = window.track_index,
+                start_sequence,
+                end_sequence,
+                cues = window.cues.len(),
+                "generate_subtitle_segment: done"
+            );
+            bytes
+        })
+        .collect();
+    Ok(segments)
+}
+
+/// Read the subtitle packets of `windows` into their cues, until every
+/// track has seen all of its samples in the segments.
+#[cfg(feature = "subtitles")]
+fn read_subtitle_packets(index: &StreamIndex, windows: &mut [SubtitleWindow]) -> Result<()> {
     // Open the file and seek once to the start of the subtitle window.
     // AVSEEK_FLAG_BYTE is not used: avformat_find_stream_info reads ~13MB on open,
     // after which backward byte-seeks are ignored by the MP4 demuxer.
@@ -423,20 +846,22 @@ pub(crate) fn generate_subtitle_segment(
     // PTS range so we never scan the whole file.
     let mut input = index.get_context()?;
 
-    // Seek to just before the first matching sample using AV_TIME_BASE (µs).
-    let first_sample_pts = matching.first().map(|s| s.pts).unwrap_or(abs_start);
-    // Convert from subtitle stream timebase to AV_TIME_BASE (µs)
-    let seek_us = crate::ffmpeg_utils::utils::rescale_ts(
-        first_sample_pts,
-        stream_timebase,
-        ffmpeg::Rational::new(1, 1_000_000),
-    );
-    let _ = input.seek(seek_us, ..seek_us); // non-fatal; worst case we read a few extra packets
-
-    let extractor = SubtitleExtractor::new(sub_info.codec_id, stream_timebase);
-    let mut cues = Vec::new();
+    // Seek to just before the earliest sample of any track, in AV_TIME_BASE (µs).
+    let seek_us = windows
+        .iter()
+        .filter_map(|w| {
+            Some(crate::ffmpeg_utils::utils::rescale_ts(
+                w.first_sample_pts?,
+                w.stream_timebase,
+                ffmpeg::Rational::new(1, 1_000_000),
+            ))
+        })
+        .min();
+    if let Some(seek_us) = seek_us {
+        let _ = input.seek(seek_us, ..seek_us); // non-fatal; worst case we read a few extra packets
+    }
 
-    // video_st_in_sub_tb: used to align subtitle PTS to the video timeline
+    // video_st: used to align subtitle PTS to the video timeline
     let video_st = {
         let st = index
             .video_streams
@@ -450,72 +875,68 @@ pub(crate) fn generate_subtitle_segment(
             st
         }
     };
-    let video_st_in_sub_tb =
-        crate::ffmpeg_utils::utils::rescale_ts(video_st, video_tb, stream_timebase);
 
-    // Build a set of the expected PTS values so we can stop early once all are seen.
-    let mut remaining: std::collections::HashSet<i64> = matching.iter().map(|s| s.pts).collect();
+    // Cues that start in an earlier segment are only needed to repeat them.
+    let spanning = crate::subtitle::webvtt::spanning_cues();
+
+    // Tracks that have samples left to read.
+    let mut pending = windows.iter().filter(|w| !w.remaining.is_empty()).count();
 
     for (stream, mut packet) in input.packets() {
-        if stream.index() != track_index {
+        let Some(window) = windows
+            .iter_mut()
+            .find(|w| w.track_index == stream.index() && !w.remaining.is_empty())
+        else {
             continue;
-        }
+        };
         let pts = packet.pts().unwrap_or(0);
-        if pts >= abs_end {
-            break;
-        }
-        if pts < abs_start {
-            continue;
-        }
-
-        remaining.remove(&pts);
-
-        let sub_playtime = pts.saturating_sub(sub_start_time);
-        let aligned_pts = sub_playtime + video_st_in_sub_tb;
-        packet.set_pts(Some(aligned_pts));
-
-        match extractor.extract_cues(&packet) {
-            Ok(c) => cues.extend(c),
-            Err(e) => tracing::debug!(
-                track_index,
-                start_sequence,
-                end_sequence,
-                "subtitle cue extraction error (skipping): {}",
-                e
-            ),
+        if pts < window.abs_end {
+            window.remaining.remove(&pts);
+        } else {
+            window.remaining.clear();
         }
 
-        if remaining.is_empty() {
-            break;
+        if pts < window.abs_end && !(pts < window.abs_start && spanning == SpanningCues::Start) {
+            let video_st_in_sub_tb = crate::ffmpeg_utils::utils::rescale_ts(
+                video_st,
+                index.video_timebase,
+                window.stream_timebase,
+            );
+            let sub_playtime = pts.saturating_sub(window.sub_start_time);
+            let aligned_pts = sub_playtime + video_st_in_sub_tb;
+            packet.set_pts(Some(aligned_pts));
+
+            match window.extractor.extract_cues(&packet) {
+                Ok(c) => window.cues.extend(c),
+                Err(e) => tracing::debug!(
+                    track_index = window.track_index,
+                    pts,
+                    "subtitle cue extraction error (skipping): {}",
+                    e
+                ),
+            }
         }
-    }
 
-    // Clamp cue timestamps to segment bounds
-    for cue in &mut cues {
-        if cue.start_ms < seg_start_ms {
-            cue.start_ms = seg_start_ms;
-        }
-        if cue.end_ms > seg_end_ms {
-            cue.end_ms = seg_end_ms;
+        if window.remaining.is_empty() {
+            pending -= 1;
+            if pending == 0 {
+                break;
+            }
         }
     }
-    cues.retain(|cue| cue.start_ms < cue.end_ms);
-
-    let config = WebVttConfig {
-        include_header_comment: false,
-    };
-    let mut writer = WebVttWriter::with_config(config);
-    let bytes = writer.write(&cues);
-
-    tracing::debug!(
-        track_index,
-        start_sequence,
-        end_sequence,
-        cues = cues.len(),
-        "generate_subtitle_segment: done"
-    );
+    Ok(())
+}
 
-    Ok(bytes)
+#[cfg(not(feature = "subtitles"))]
+pub(crate) fn generate_subtitle_segment(
+    _index: &StreamIndex,
+    _track_index: usize,
+    _start_sequence: usize,
+    _end_sequence: usize,
+    _source_path: &Path,
+    _timestamps: crate::params::SubtitleTimestamps,
+) -> Result<Bytes> {
+    Err(HlsError::FeatureDisabled("subtitles"))
 }
 
 /// A demuxed packet held in memory while the full segment is being collected.
@@ -538,15 +959,28 @@ pub(crate) struct BufferedPacket {
 /// Iterates the demuxer until both video (stopped at the next keyframe boundary)
 /// and audio (stopped at `segment.end_pts`) are fully consumed.  Returns packets
 /// in demux order, each tagged with their stream metadata for later rescaling.
+///
+/// `pending` packets (left over from the previous segment, see `DemuxCursor`)
+/// are processed before reading from `input`. Packets of the requested streams
+/// that were read but belong to the next segment are returned as the second
+/// element, so a cursor can hand them to the next segment.
+///
+/// The packets of `mix_track_index`, the audio track of an audio mix, are
+/// read along with the audio track, up to the same end.
 fn buffer_media_packets(
     input: &mut ffmpeg::format::context::Input,
+    pending: Vec<BufferedPacket>,
     segment: &SegmentInfo,
     segment_type: &str,
     video_timebase: ffmpeg::Rational,
     stream_indices: &[usize],
     audio_track_index: Option<usize>,
-) -> Vec<BufferedPacket> {
+    mix_track_index: Option<usize>,
+    audio_range: Option<&std::ops::Range<i64>>,
+    mix_range: Option<&std::ops::Range<i64>>,
+) -> (Vec<BufferedPacket>, Vec<BufferedPacket>) {
     let mut buffered_packets = Vec::new();
+    let mut leftover_packets = Vec::new();
     let is_interleaved = segment_type == "av";
 
     let end_pts_90k = crate::ffmpeg_utils::utils::rescale_ts(
@@ -555,66 +989,165 @@ fn buffer_media_packets(
         ffmpeg::Rational(1, 90000),
     );
 
-    let mut packet_count = 0;
-
     let mut video_done = !is_interleaved && segment_type == "audio";
     let mut audio_done = !is_interleaved && segment_type == "video";
+    let mut mix_done = mix_track_index.is_none();
 
-    for (stream, packet) in input.packets() {
+    let demuxed = input.packets().filter_map(|(stream, packet)| {
         let stream_id = stream.index();
-        let is_video_stream = crate::ffmpeg_utils::utils::is_video_codec(stream.parameters().id());
-
         if is_interleaved
             && !stream_indices.contains(&stream_id)
             && audio_track_index != Some(stream_id)
         {
-            continue;
+            return None;
         }
-        if !is_interleaved && stream_id != stream_indices[0] {
-            continue;
+        if !is_interleaved && stream_id != stream_indices[0] && mix_track_index != Some(stream_id) {
+            return None;
         }
+        Some(BufferedPacket {
+            stream_id,
+            packet,
+            timebase: stream.time_base(),
+            is_video_stream: crate::ffmpeg_utils::utils::is_video_codec(stream.parameters().id()),
+        })
+    });
 
+    for buffered in pending.into_iter().chain(demuxed) {
+        let packet = &buffered.packet;
         let pts_90k = crate::ffmpeg_utils::utils::rescale_ts(
             packet.pts().or(packet.dts()).unwrap_or(0),
-            stream.time_base(),
+            buffered.timebase,
             ffmpeg::Rational(1, 90000),
         );
 
-        if is_video_stream {
-            if packet.is_key() && pts_90k >= end_pts_90k {
+        if buffered.is_video_stream {
+            // Segments that don't start with a keyframe (see
+            // `scanner::build_fixed_duration_segments`) are cut at any frame.
+            if (packet.is_key() || !segment.is_keyframe) && pts_90k >= end_pts_90k {
                 video_done = true;
             }
             if video_done {
-                if audio_done {
+                leftover_packets.push(buffered);
+                if audio_done && mix_done {
                     break;
                 }
                 continue;
             }
         } else {
-            if pts_90k >= end_pts_90k {
-                if is_interleaved || packet_count > 0 {
-                    audio_done = true;
-                }
+            // Audio that starts at or after the end belongs to the next segment.
+            let is_mix = mix_track_index == Some(buffered.stream_id);
+            let range = if is_mix { mix_range } else { audio_range };
+            let past_end = match range {
+                Some(range) => packet.pts().or(packet.dts()).unwrap_or(0) >= range.end,
+                None => pts_90k >= end_pts_90k,
+            };
+            if past_end && is_mix {
+                mix_done = true;
+            } else if past_end {
+                audio_done = true;
             }
 
-            if audio_done {
-                if video_done {
+            if (is_mix && mix_done) || (!is_mix && audio_done) {
+                leftover_packets.push(buffered);
+                if video_done && audio_done && mix_done {
                     break;
                 }
                 continue;
             }
         }
 
-        buffered_packets.push(BufferedPacket {
-            stream_id,
-            packet,
-            timebase: stream.time_base().clone(),
-            is_video_stream,
-        });
-        packet_count += 1;
+        buffered_packets.push(buffered);
+    }
+
+    (buffered_packets, leftover_packets)
+}
+
+/// The audio track mixed into the transcoded one, see `Attempt::mix_track`.
+#[cfg_attr(not(feature = "transcode"), allow(dead_code))]
+struct MixSource {
+    stream_index: usize,
+    params: ffmpeg::codec::Parameters,
+    timebase: ffmpeg::Rational,
+    /// Packets from before the segment start, like the transcoded track's
+    preroll: Vec<ffmpeg::Packet>,
+}
+
+/// The packets of audio track `audio_idx` in `buffered_packets`, after its
+/// pre-roll packets, in decode order.
+///
+/// The pre-roll seek may return packets that also appear after the main
+/// byte-aligned seek (interleaving overlap), so buffered packets whose DTS
+/// already appears in the pre-roll are left out.
+#[cfg(feature = "transcode")]
+fn merge_preroll(
+    audio_idx: usize,
+    buffered_packets: &[BufferedPacket],
+    preroll: Vec<ffmpeg::Packet>,
+) -> Vec<ffmpeg::Packet> {
+    let preroll_dts: std::collections::HashSet<i64> = preroll
+        .iter()
+        .map(|p| p.dts().or(p.pts()).unwrap_or(i64::MIN))
+        .collect();
+    let mut all_audio_packets = preroll;
+    for pkt in buffered_packets
+        .iter()
+        .filter(|p| p.stream_id == audio_idx)
+        .map(|p| p.packet.clone())
+    {
+        let dts = pkt.dts().or(pkt.pts()).unwrap_or(i64::MIN);
+        if !preroll_dts.contains(&dts) {
+            all_audio_packets.push(pkt);
+        }
     }
+    all_audio_packets.sort_by_key(|p| p.dts().or(p.pts()).unwrap_or(0));
+    all_audio_packets
+}
 
-    buffered_packets
+/// Put the passthrough audio packets of the pre-roll that belong to the
+/// segment in front of `buffered_packets`. These are the packets that are
+/// stored before the video keyframe the demuxer seeked to. Packets that were
+/// read twice are kept once.
+fn prepend_preroll(
+    buffered_packets: &mut Vec<BufferedPacket>,
+    audio_idx: usize,
+    timebase: ffmpeg::Rational,
+    range: &std::ops::Range<i64>,
+    preroll: Vec<ffmpeg::Packet>,
+) {
+    let buffered_dts: std::collections::HashSet<i64> = buffered_packets
+        .iter()
+        .filter(|p| p.stream_id == audio_idx)
+        .map(|p| p.packet.dts().or(p.packet.pts()).unwrap_or(i64::MIN))
+        .collect();
+    let preroll: Vec<BufferedPacket> = preroll
+        .into_iter()
+        .filter(|p| range.contains(&p.pts().or(p.dts()).unwrap_or(0)))
+        .filter(|p| !buffered_dts.contains(&p.dts().or(p.pts()).unwrap_or(i64::MIN)))
+        .map(|packet| BufferedPacket {
+            stream_id: audio_idx,
+            packet,
+            timebase,
+            is_video_stream: false,
+        })
+        .collect();
+    buffered_packets.splice(0..0, preroll);
+}
+
+/// The PTS range, in `timebase`, of the audio packets that are transcoded for
+/// `segment`: those of `StreamIndex::audio_packet_range`, and the ones just
+/// after it that the last AAC frame of the segment overlaps.
+fn transcode_packet_range(
+    index: &StreamIndex,
+    segment: &SegmentInfo,
+    timebase: ffmpeg::Rational,
+) -> std::ops::Range<i64> {
+    let range = index.audio_packet_range(segment, timebase);
+    let lookahead = crate::ffmpeg_utils::utils::rescale_ts(
+        TRANSCODE_LOOKAHEAD_US,
+        ffmpeg::Rational(1, 1_000_000),
+        timebase,
+    );
+    range.start..range.end.saturating_add(lookahead)
 }
 
 /// Transcode buffered audio packets to AAC if requested, otherwise no-op.
@@ -622,18 +1155,25 @@ fn buffer_media_packets(
 /// When `transcode_audio_to_aac` is true, extracts the raw audio packets from
 /// `buffered_packets`, runs them through the decode → resample → encode pipeline,
 /// and returns the resulting AAC packets along with their output timebase.
-/// When false, returns empty vecs immediately.
+/// When false, returns empty vecs immediately. `aac_bitrate` overrides the
+/// configured AAC bitrate. With `mix`, the mix of both tracks is transcoded.
+#[cfg(feature = "transcode")]
 fn transcode_audio_if_needed(
     index: &StreamIndex,
     audio_track_index: Option<usize>,
     audio_params: Option<ffmpeg::codec::Parameters>,
     audio_timebase: Option<ffmpeg::Rational>,
     transcode_audio_to_aac: bool,
+    aac_bitrate: Option<u64>,
     buffered_packets: &[BufferedPacket],
     segment: &SegmentInfo,
     video_timebase: ffmpeg::Rational,
     audio_preroll: Vec<ffmpeg::Packet>,
+    mix: Option<MixSource>,
 ) -> Result<(Vec<ffmpeg::Packet>, Option<ffmpeg::Rational>)> {
+    use crate::transcode::decoder::AudioDecoder;
+    use crate::transcode::pipeline::MixInput;
+
     let mut transcoded_audio_packets = Vec::new();
     let mut audio_output_tb = None;
 
@@ -641,32 +1181,33 @@ fn transcode_audio_if_needed(
         if let (Some(audio_idx), Some(params), Some(audio_tb)) =
             (audio_track_index, audio_params, audio_timebase)
         {
-            let decoder = crate::transcode::decoder::AudioDecoder::open(params, audio_idx)?;
+            let decoder = AudioDecoder::open(params, audio_idx)?;
             let audio_info = index.get_audio_stream(audio_idx)?;
-
-            // Collect audio packets from the main buffered set
-            let main_audio_packets: Vec<_> = buffered_packets
-                .iter()
-                .filter(|p| p.stream_id == audio_idx)
-                .map(|p| p.packet.clone())
-                .collect();
-
-            // Merge pre-roll with main packets, deduplicating by DTS.
-            // The pre-roll seek may return packets that also appear after the
-            // main byte-aligned seek (interleaving overlap), so we skip any
-            // main packet whose DTS already appears in the pre-roll.
-            let preroll_dts: std::collections::HashSet<i64> = audio_preroll
-                .iter()
-                .map(|p| p.dts().or(p.pts()).unwrap_or(i64::MIN))
-                .collect();
-            let mut all_audio_packets = audio_preroll;
-            for pkt in main_audio_packets {
-                let dts = pkt.dts().or(pkt.pts()).unwrap_or(i64::MIN);
-                if !preroll_dts.contains(&dts) {
-                    all_audio_packets.push(pkt);
-                }
+            let all_audio_packets = merge_preroll(audio_idx, buffered_packets, audio_preroll);
+
+            if let Some(mix) = mix {
+                let (aac_packets, output_tb) =
+                    crate::transcode::pipeline::transcode_mixed_audio_segment(
+                        MixInput {
+                            decoder,
+                            packets: all_audio_packets,
+                            timebase: audio_tb,
+                            info: audio_info,
+                        },
+                        MixInput {
+                            decoder: AudioDecoder::open(mix.params, mix.stream_index)?,
+                            packets: merge_preroll(mix.stream_index, buffered_packets, mix.preroll),
+                            timebase: mix.timebase,
+                            info: index.get_audio_stream(mix.stream_index)?,
+                        },
+                        segment,
+                        video_timebase,
+                        false,
+                        &index.config,
+                        aac_bitrate,
+                    )?;
+                return Ok((aac_packets, Some(output_tb)));
             }
-            all_audio_packets.sort_by_key(|p| p.dts().or(p.pts()).unwrap_or(0));
 
             let (aac_packets, output_tb) = crate::transcode::pipeline::transcode_audio_segment(
                 decoder,
@@ -676,6 +1217,8 @@ fn transcode_audio_if_needed(
                 segment,
                 video_timebase,
                 false,
+                &index.config,
+                aac_bitrate,
             )?;
             transcoded_audio_packets = aac_packets;
             audio_output_tb = Some(output_tb);
@@ -685,6 +1228,105 @@ fn transcode_audio_if_needed(
     Ok((transcoded_audio_packets, audio_output_tb))
 }
 
+#[cfg(not(feature = "transcode"))]
+fn transcode_audio_if_needed(
+    _index: &StreamIndex,
+    _audio_track_index: Option<usize>,
+    _audio_params: Option<ffmpeg::codec::Parameters>,
+    _audio_timebase: Option<ffmpeg::Rational>,
+    transcode_audio_to_aac: bool,
+    _aac_bitrate: Option<u64>,
+    _buffered_packets: &[BufferedPacket],
+    _segment: &SegmentInfo,
+    _video_timebase: ffmpeg::Rational,
+    _audio_preroll: Vec<ffmpeg::Packet>,
+    _mix: Option<MixSource>,
+) -> Result<(Vec<ffmpeg::Packet>, Option<ffmpeg::Rational>)> {
+    if transcode_audio_to_aac {
+        return Err(HlsError::FeatureDisabled("transcode"));
+    }
+    Ok((Vec::new(), None))
+}
+
+/// Packets of AAC silence for the passthrough audio track `audio_idx`,
+/// covering `segment`, timestamped in the track's `timebase`.
+///
+/// Only silence for AAC-LC mono or stereo tracks can be generated, for other
+/// tracks the result is empty.
+#[cfg(feature = "transcode")]
+fn silent_audio_packets(
+    index: &StreamIndex,
+    audio_idx: usize,
+    timebase: ffmpeg::Rational,
+    segment: &SegmentInfo,
+) -> Result<Vec<BufferedPacket>> {
+    let audio_info = index.get_audio_stream(audio_idx)?;
+    let is_aac_lc = audio_info.codec_id == ffmpeg::codec::Id::AAC
+        && audio_info
+            .codec_string
+            .as_deref()
+            .is_none_or(|c| c == "mp4a.40.2");
+    if !is_aac_lc || audio_info.channels > 2 || audio_info.sample_rate == 0 {
+        return Ok(Vec::new());
+    }
+
+    let sample_tb = ffmpeg::Rational(1, audio_info.sample_rate as i32);
+    let convert = |pts| crate::ffmpeg_utils::utils::rescale_ts(pts, index.video_timebase, timebase);
+    let start = convert(segment.start_pts);
+    let end = convert(segment.end_pts);
+    let samples = crate::ffmpeg_utils::utils::rescale_ts(end - start, timebase, sample_tb);
+    if samples <= 0 {
+        return Ok(Vec::new());
+    }
+
+    let packets = crate::transcode::encoder::encode_silence(
+        audio_info.sample_rate,
+        audio_info.channels,
+        aac_bitrate(audio_info.channels, &index.config.aac_bitrates),
+        samples as usize,
+    )?;
+    tracing::debug!(
+        seq = segment.sequence,
+        stream_index = audio_idx,
+        packets = packets.len(),
+        "no audio packets in segment, sending silence"
+    );
+    Ok(packets
+        .into_iter()
+        .map(|mut packet| {
+            let pts = start
+                + crate::ffmpeg_utils::utils::rescale_ts(
+                    packet.pts().unwrap_or(0),
+                    sample_tb,
+                    timebase,
+                );
+            packet.set_pts(Some(pts));
+            packet.set_dts(Some(pts));
+            packet.set_duration(crate::ffmpeg_utils::utils::rescale_ts(
+                packet.duration(),
+                sample_tb,
+                timebase,
+            ));
+            BufferedPacket {
+                stream_id: audio_idx,
+                packet,
+                timebase,
+                is_video_stream: false,
+            }
+        })
+        .collect())
+}
+
+#[cfg(not(feature = "transcode"))]
+fn silent_audio_packets(
+    _index: &StreamIndex,
+    _audio_idx: usize,
+    _timebase: ffmpeg::Rational,
+    _segment: &SegmentInfo,
+) -> Result<Vec<BufferedPacket>> {
+    Ok(Vec::new())
+}
+
 /// Write buffered packets into `muxer`, interleaving transcoded audio as needed.
 ///
 /// Filters out packets that precede the segment's nominal start time, rescales
@@ -692,6 +1334,9 @@ fn transcode_audio_if_needed(
 /// packets with the video stream in decode order.  Returns the muxer (ready for
 /// `finalize_segment`) plus the DTS of the first video packet, the first audio
 /// packet, and the first packet of either kind — used to set TFDT values.
+///
+/// When `streamer` is active, completed fragments are sent out as soon as the
+/// first DTS values needed by `make_patcher` are known.
 fn mux_media_segment(
     _segment_type: &str,
     is_interleaved: bool,
@@ -701,8 +1346,16 @@ fn mux_media_segment(
     mut muxer: Fmp4Muxer,
     buffered_packets: Vec<BufferedPacket>,
     audio_track_index: Option<usize>,
+    audio_range: Option<&std::ops::Range<i64>>,
     transcoded_audio_packets: Vec<ffmpeg::Packet>,
     audio_output_tb: Option<ffmpeg::Rational>,
+    progress: Option<&dyn ProgressObserver>,
+    streamer: &mut SegmentStreamer,
+    make_patcher: &dyn Fn(
+        Option<i64>,
+        Option<i64>,
+        Option<i64>,
+    ) -> crate::segment::isobmff::TfdtPatcher,
 ) -> Result<(Fmp4Muxer, Option<i64>, Option<i64>, Option<i64>)> {
     let start_pts_90k = crate::ffmpeg_utils::utils::rescale_ts(
         segment.start_pts,
@@ -738,6 +1391,7 @@ fn mux_media_segment(
             target_dts_90k: i64,
             first_packet_dts: &mut Option<i64>,
             first_audio_dts: &mut Option<i64>,
+            progress: Option<&dyn ProgressObserver>,
         ) -> Result<()> {
             if self.packets.is_empty() || self.idx >= self.packets.len() {
                 return Ok(());
@@ -764,6 +1418,7 @@ fn mux_media_segment(
                     // uses it for the last sample's trun entry.
                     pkt.set_duration(1024);
                     muxer.write_packet(pkt)?;
+                    muxer.notify_progress(progress);
                     self.idx += 1;
                 } else {
                     break;
@@ -807,7 +1462,14 @@ fn mux_media_segment(
         } else {
             start_pts_90k
         };
-        if pts_90k < stream_threshold {
+        // Passthrough audio is cut in its own timebase, see `audio_packet_range`.
+        let before_start = match audio_range {
+            Some(range) if !is_video_stream => {
+                packet.pts().or(packet.dts()).unwrap_or(0) < range.start
+            }
+            _ => pts_90k < stream_threshold,
+        };
+        if before_start {
             continue;
         }
 
@@ -854,20 +1516,30 @@ fn mux_media_segment(
             }
         }
 
-        if pts_90k < stream_threshold {
-            continue;
-        }
-
         if transcode_audio_to_aac {
             interleaver.write_upto(
                 &mut muxer,
                 dts_90k,
                 &mut first_packet_dts,
                 &mut first_audio_dts,
+                progress,
             )?;
         }
 
         muxer.write_packet(&mut packet)?;
+        muxer.notify_progress(progress);
+
+        // Only stream once the TFDT base of every track is known.
+        let dts_known = if is_interleaved {
+            first_video_dts.is_some() && first_audio_dts.is_some()
+        } else {
+            first_packet_dts.is_some()
+        };
+        if streamer.is_active() && dts_known {
+            streamer.send_fragments(&mut muxer, || {
+                make_patcher(first_video_dts, first_audio_dts, first_packet_dts)
+            });
+        }
     }
 
     if transcode_audio_to_aac {
@@ -876,20 +1548,98 @@ fn mux_media_segment(
             i64::MAX,
             &mut first_packet_dts,
             &mut first_audio_dts,
+            progress,
         )?;
     }
 
     Ok((muxer, first_video_dts, first_audio_dts, first_packet_dts))
 }
 
-/// Flush `muxer`, strip the init segment prefix, correct TFDT values in every
-/// `moof` fragment, prepend a `styp` box, and return the final `.m4s` bytes.
+/// Apply the compatibility profile to (patched) media segment data: the
+/// `tfdt` version, and the `styp` box if `styp` is set.
+fn apply_profile(data: Vec<u8>, styp: bool) -> Vec<u8> {
+    let profile = crate::segment::profile::compatibility_profile();
+    let mut data = if profile.tfdt_version() == 0 {
+        crate::segment::isobmff::downgrade_tfdts(&data)
+    } else {
+        data
+    };
+    if styp {
+        data.splice(0..0, profile.styp());
+    }
+    data
+}
+
+/// How far before the segment start audio is read: audio-only segments seek
+/// there, transcoded and interleaved segments collect a pre-roll from there.
+const AUDIO_SEEK_PREROLL_US: i64 = 1_000_000;
+
+/// How much audio after the segment end is transcoded, so that the last AAC
+/// frame of a segment is encoded from the source instead of from padding.
+const TRANSCODE_LOOKAHEAD_US: i64 = 100_000;
+
+/// Fragment duration used when the segment is streamed while it is generated.
+const STREAMING_FRAGMENT_DURATION_US: i64 = 1_000_000;
+
+/// Forwards finished fragments to a data-consuming `ProgressObserver`.
 ///
-/// For interleaved segments the video and audio TFDTs are patched independently
-/// via their track IDs.  For single-track segments a single delta is applied.
+/// Fragments are patched with the same `TfdtPatcher` that would otherwise be
+/// applied to the complete segment, and the first chunk gets the `styp` box, so
+/// the concatenation of all chunks is byte-identical to the non-streamed segment.
+struct SegmentStreamer<'a> {
+    observer: Option<&'a dyn ProgressObserver>,
+    patcher: Option<crate::segment::isobmff::TfdtPatcher>,
+    data: Vec<u8>,
+    /// Prepend the `styp` box (see `MuxOptions`)
+    styp: bool,
+}
+
+impl<'a> SegmentStreamer<'a> {
+    fn new(progress: Option<&'a dyn ProgressObserver>) -> Self {
+        Self {
+            observer: progress.filter(|p| p.wants_data()),
+            patcher: None,
+            data: Vec::new(),
+            styp: true,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.observer.is_some()
+    }
+
+    /// Send whatever complete fragments the muxer has produced.
+    fn send_fragments<F>(&mut self, muxer: &mut Fmp4Muxer, patcher: F)
+    where
+        F: FnOnce() -> crate::segment::isobmff::TfdtPatcher,
+    {
+        let Some(observer) = self.observer else {
+            return;
+        };
+        let Some(mut chunk) = muxer.take_fragments() else {
+            return;
+        };
+        self.patcher.get_or_insert_with(patcher).patch(&mut chunk);
+        let chunk = apply_profile(chunk, self.data.is_empty() && self.styp);
+        self.data.extend_from_slice(&chunk);
+        observer.on_data(Bytes::from(chunk));
+    }
+}
+
+/// Build the patcher that corrects TFDT values (and `mfhd` sequence numbers)
+/// in every `moof` fragment of a media segment.
+///
+/// For interleaved segments the video and audio TFDTs are patched independently
+/// via their track IDs.  For single-track segments a single delta is applied.
 /// The `first_*_dts` values returned by `mux_media_segment` are used as the
 /// base for the delta so that the TFDT matches the actual first decoded frame.
-fn finalize_segment(
+/// Nothing here depends on other segments having been generated first.
+///
+/// The audio TFDT is the same for an interleaved segment and for the audio
+/// segment of the same track, whether the segment was demuxed after a seek
+/// or after the previous segment: a player that switches audio tracks (or
+/// from interleaved to separate audio) lands on the same timeline.
+fn segment_tfdt_patcher(
     segment_type: &str,
     is_interleaved: bool,
     transcode_audio_to_aac: bool,
@@ -897,116 +1647,158 @@ fn finalize_segment(
     segment: &SegmentInfo,
     index: &StreamIndex,
     audio_track_index: Option<usize>,
-    mut muxer: Fmp4Muxer,
     first_video_dts: Option<i64>,
     first_audio_dts: Option<i64>,
     first_packet_dts: Option<i64>,
-) -> Result<Bytes> {
-    let full_data = muxer.finalize()?;
-
-    let media_offset =
-        crate::segment::muxer::find_media_segment_offset(&full_data).ok_or_else(|| {
-            HlsError::Muxing("No media segment data found (moof/styp missing)".to_string())
-        })?;
-    let mut media_data = full_data[media_offset..].to_vec();
-
-    let (audio_tb, encoder_delay): (ffmpeg::Rational, i64) = if let Some(target) = audio_track_index {
-        if let Ok(info) = index.get_audio_stream(target) {
-            let delay = if transcode_audio_to_aac {
-                1024 // AAC encoder delay
-            } else {
-                info.encoder_delay
-            };
-            (ffmpeg::Rational::new(1, info.sample_rate as i32), delay)
-        } else {
-            (ffmpeg::Rational::new(1, 48000), 0)
-        }
-    } else {
-        (ffmpeg::Rational::new(1, 48000), 0)
-    };
+) -> crate::segment::isobmff::TfdtPatcher {
+    use crate::segment::isobmff::TfdtPatcher;
 
+    let video_shift = timeline_shift(index, ffmpeg::Rational(1, 90000));
     let video_target_tfdt = crate::ffmpeg_utils::utils::rescale_ts(
         segment.start_pts,
         video_timebase,
         ffmpeg::Rational(1, 90000),
-    )
-    .max(0) as u64;
-
-    let audio_target_tfdt = crate::ffmpeg_utils::utils::rescale_ts(
-        segment.start_pts,
-        video_timebase,
-        audio_tb,
-    )
-    .max(0) as i64
-        - encoder_delay;
-    let audio_target_tfdt = audio_target_tfdt.max(0) as u64;
+    ) + video_shift;
+    let video_tfdt = |first_dts: Option<i64>| match first_dts {
+        Some(dts) => (dts + video_shift).max(0) as u64,
+        None => video_target_tfdt.max(0) as u64,
+    };
 
-    let start_frag_seq = segment.sequence as u32 + 1;
+    let start_frag_seq = segment.sequence as u32 * index.config.fragment_sequence_multiplier + 1;
 
     if is_interleaved {
         let v_track: u32 = 1;
         let a_track: u32 = 2;
-
-        let audio_tfdt_for_patch = if let Some(dts) = first_audio_dts {
-            // first_audio_dts is the DTS of the first packet we wrote (the priming packet).
-            // By setting tfdt to (dts - delay), the player's decoder (which
-            // also has a delay) will output the sample at dts.
-            (dts as i64 - encoder_delay).max(0) as u64
-        } else {
-            audio_target_tfdt
-        };
-
-        let video_tfdt_for_patch = if let Some(dts) = first_video_dts {
-            dts.max(0) as u64
-        } else {
-            video_target_tfdt
-        };
-
-        crate::segment::isobmff::patch_tfdts_per_track(
-            &mut media_data,
+        TfdtPatcher::per_track(
             start_frag_seq,
             v_track,
             a_track,
-            video_tfdt_for_patch,
-            audio_tfdt_for_patch,
-        );
+            video_tfdt(first_video_dts),
+            audio_tfdt(
+                index,
+                audio_track_index,
+                transcode_audio_to_aac,
+                video_timebase,
+                segment,
+                first_audio_dts,
+            ),
+        )
     } else {
         let single_track_tfdt = if segment_type == "video" {
-            if let Some(dts) = first_packet_dts {
-                dts.max(0) as u64
-            } else {
-                video_target_tfdt
-            }
+            video_tfdt(first_packet_dts)
         } else {
-            if let Some(dts) = first_packet_dts {
-                // For audio only, first_packet_dts is in audio_tb.
-                // We must shift by encoder_delay to align with presentation.
-                (dts as i64 - encoder_delay).max(0) as u64
-            } else {
-                let a_idx = audio_track_index.unwrap_or(0);
-                if let Ok(audio_info) = index.get_audio_stream(a_idx) {
-                    let audio_tb = ffmpeg::Rational::new(1, audio_info.sample_rate as i32);
-                    crate::ffmpeg_utils::utils::rescale_ts(
-                        segment.start_pts,
-                        video_timebase,
-                        audio_tb,
-                    )
-                    .max(0) as u64
-                } else {
-                    0
-                }
-            }
+            audio_tfdt(
+                index,
+                audio_track_index,
+                transcode_audio_to_aac,
+                video_timebase,
+                segment,
+                first_packet_dts,
+            )
         };
-        crate::segment::isobmff::patch_tfdts(&mut media_data, single_track_tfdt, start_frag_seq);
+        TfdtPatcher::new(single_track_tfdt, start_frag_seq)
     }
+}
 
-    let styp_box: [u8; 24] = [
-        0x00, 0x00, 0x00, 24, b's', b't', b'y', b'p', b'i', b's', b'o', b'8', 0x00, 0x00, 0x02,
-        0x00, b'i', b's', b'o', b'8', b'c', b'm', b'f', b'c',
-    ];
-    media_data.splice(0..0, styp_box);
+/// The TFDT of audio track `audio_track_index` in a segment whose first
+/// audio packet has DTS `first_dts`, in the sample rate of the track.
+///
+/// `first_dts` is the DTS of the first packet written, the priming packet
+/// of transcoded audio. Shifting it by the encoder delay makes the player's
+/// decoder (which also has a delay) output that sample at `first_dts`.
+/// Without audio packets the segment start is used, shifted the same way.
+fn audio_tfdt(
+    index: &StreamIndex,
+    audio_track_index: Option<usize>,
+    transcode_audio_to_aac: bool,
+    video_timebase: ffmpeg::Rational,
+    segment: &SegmentInfo,
+    first_dts: Option<i64>,
+) -> u64 {
+    let audio_info = audio_track_index.and_then(|track| index.get_audio_stream(track).ok());
+    let (audio_tb, encoder_delay) = match audio_info {
+        Some(info) => {
+            let delay = if transcode_audio_to_aac {
+                1024 // AAC encoder delay
+            } else {
+                info.encoder_delay
+            };
+            (ffmpeg::Rational::new(1, info.sample_rate as i32), delay)
+        }
+        None => (ffmpeg::Rational::new(1, 48000), 0),
+    };
+    let first_dts = first_dts.unwrap_or_else(|| {
+        crate::ffmpeg_utils::utils::rescale_ts(segment.start_pts, video_timebase, audio_tb).max(0)
+    });
+    (first_dts + timeline_shift(index, audio_tb) - encoder_delay).max(0) as u64
+}
 
-    Ok(Bytes::from(media_data))
+/// How much the negative timestamp policy shifts the timeline of all tracks
+/// of the title, in `timebase`. See `NegativeTsPolicy`.
+fn timeline_shift(index: &StreamIndex, timebase: ffmpeg::Rational) -> i64 {
+    let shift = crate::segment::profile::negative_ts_policy().shift(index.first_dts);
+    crate::ffmpeg_utils::utils::rescale_ts(shift, index.video_timebase, timebase)
+}
+
+/// Flush `muxer`, strip the init segment prefix, correct TFDT values in every
+/// `moof` fragment, prepend a `styp` box, and return the final `.m4s` bytes.
+///
+/// When streaming, the remaining fragments are sent to the observer and the
+/// return value is everything that was streamed.
+fn finalize_segment(
+    segment_type: &str,
+    is_interleaved: bool,
+    transcode_audio_to_aac: bool,
+    video_timebase: ffmpeg::Rational,
+    segment: &SegmentInfo,
+    index: &StreamIndex,
+    audio_track_index: Option<usize>,
+    mut muxer: Fmp4Muxer,
+    first_video_dts: Option<i64>,
+    first_audio_dts: Option<i64>,
+    first_packet_dts: Option<i64>,
+    progress: Option<&dyn ProgressObserver>,
+    streamer: &mut SegmentStreamer,
+) -> Result<Bytes> {
+    muxer.write_trailer();
+    muxer.notify_progress(progress);
+
+    let patcher = || {
+        segment_tfdt_patcher(
+            segment_type,
+            is_interleaved,
+            transcode_audio_to_aac,
+            video_timebase,
+            segment,
+            index,
+            audio_track_index,
+            first_video_dts,
+            first_audio_dts,
+            first_packet_dts,
+        )
+    };
+
+    if streamer.is_active() {
+        streamer.send_fragments(&mut muxer, patcher);
+        if streamer.data.is_empty() {
+            return Err(HlsError::Muxing(
+                "No media segment data found (moof/styp missing)".to_string(),
+            ));
+        }
+        return Ok(Bytes::from(std::mem::take(&mut streamer.data)));
+    }
+
+    let full_data = muxer.finalize()?;
+
+    let media_offset =
+        crate::segment::muxer::find_media_segment_offset(&full_data).ok_or_else(|| {
+            HlsError::Muxing("No media segment data found (moof/styp missing)".to_string())
+        })?;
+    let mut media_data = full_data[media_offset..].to_vec();
+
+    patcher().patch(&mut media_data);
+
+    Ok(Bytes::from(apply_profile(media_data, streamer.styp)))
 }
 
 /// Core FFmpeg-based segment generator shared by all media segment types.
@@ -1016,22 +1808,47 @@ fn finalize_segment(
 /// requested streams with the muxer, buffers packets until the segment boundary,
 /// optionally transcodes audio to AAC, muxes everything, and delegates final
 /// TFDT patching and `styp` insertion to `finalize_segment`.
-fn generate_media_segment_ffmpeg(
+pub(crate) fn generate_media_segment_ffmpeg(
     segment: &SegmentInfo,
     segment_type: &str,
     video_track_index: Option<usize>,
     audio_track_index: Option<usize>,
     index: &StreamIndex,
-    transcode_audio_to_aac: bool,
+    attempt: Attempt,
+    progress: Option<&dyn ProgressObserver>,
 ) -> Result<Bytes> {
+    let transcode_audio_to_aac = attempt.transcode_audio;
     let is_interleaved = segment_type == "av";
     let video_timebase = index.video_timebase;
+    // Only transcoded audio tracks of their own can be a mix.
+    let mix_track_index = attempt
+        .mix_track
+        .filter(|_| transcode_audio_to_aac && segment_type == "audio");
 
     let target_start_sec = segment.start_pts as f64 * video_timebase.numerator() as f64
         / video_timebase.denominator() as f64;
     let seek_ts = (target_start_sec * 1_000_000.0) as i64;
 
-    let mut input = index.get_context()?;
+    // Sequential requests for the same tracks continue reading from where the
+    // previous segment stopped. Transcoded audio needs a pre-roll from before
+    // the segment start, so it always seeks. A retry opens the file again, as
+    // the shared input may be stuck in an error state.
+    let tracks = (video_track_index, audio_track_index);
+    let demux_start = if attempt.fresh_input {
+        DemuxStart::Open
+    } else if transcode_audio_to_aac {
+        DemuxStart::Seek
+    } else {
+        index.demux_start(tracks, segment.sequence)
+    };
+    let (mut input, pending_packets, needs_seek) = match demux_start {
+        DemuxStart::Continue(cursor) => (ContextGuard::Owned(cursor.input), cursor.pending, false),
+        DemuxStart::Open => {
+            let input = crate::source::open_input(&index.source_path)?;
+            (ContextGuard::Owned(input), Vec::new(), true)
+        }
+        DemuxStart::Seek => (index.get_context()?, Vec::new(), true),
+    };
     // avformat_seek_file (mov demuxer) compares the target `ts` against PTS, not
     // DTS. For B-frame video the target IDR has PTS > DTS by one CTO (~83ms for
     // typical 24-30fps content with a 2-frame reorder window). seek_ts is derived
@@ -1042,8 +1859,8 @@ fn generate_media_segment_ffmpeg(
     // PTS(target IDR) <= ts while still being well below the next segment's IDR.
     let seek_ts_with_slack = seek_ts + 500_000; // +500ms to clear B-frame CTO
 
-    // For transcoded audio in interleaved segments, collect a pre-roll window
-    // of audio packets before the main seek position.  In MP4 files the
+    // For transcoded audio, and for interleaved segments, collect a pre-roll
+    // window of audio packets before the main seek position.  In MP4 files the
     // demuxer's timestamp-based seek lands at the video IDR's byte offset;
     // audio packets interleaved just before that offset are skipped, creating
     // an 85 ms+ gap at certain segment boundaries.  By collecting those packets
@@ -1051,13 +1868,18 @@ fn generate_media_segment_ffmpeg(
     // demuxer) and prepending them to the transcoder's input, the
     // target_grid_start_48k filter still discards out-of-range output — so
     // this pre-roll has no effect on segment boundaries, only on coverage.
-    let audio_preroll_packets: Vec<ffmpeg::Packet> = if transcode_audio_to_aac {
+    // Passthrough audio is cut from the pre-roll by `audio_packet_range`.
+    // The track of an audio mix gets a pre-roll of its own.
+    let mut mix_preroll_packets = Vec::new();
+    let needs_preroll = transcode_audio_to_aac || (is_interleaved && needs_seek);
+    let mut audio_preroll_packets: Vec<ffmpeg::Packet> = if needs_preroll {
         if let Some(audio_idx) = audio_track_index {
-            let preroll_seek_us = (seek_ts - 1_000_000).max(0);
+            let preroll_seek_us = (seek_ts - AUDIO_SEEK_PREROLL_US).max(0);
             let mut preroll = Vec::new();
             let _ = input.seek(preroll_seek_us, ..seek_ts_with_slack);
             for (stream, packet) in input.packets() {
-                if stream.index() != audio_idx {
+                let is_mix = mix_track_index == Some(stream.index());
+                if stream.index() != audio_idx && !is_mix {
                     continue;
                 }
                 let pkt_pts = packet.pts().or(packet.dts()).unwrap_or(0);
@@ -1070,7 +1892,11 @@ fn generate_media_segment_ffmpeg(
                 if pkt_us >= seek_ts_with_slack {
                     break;
                 }
-                preroll.push(packet);
+                if is_mix {
+                    mix_preroll_packets.push(packet);
+                } else {
+                    preroll.push(packet);
+                }
             }
             preroll
         } else {
@@ -1080,12 +1906,30 @@ fn generate_media_segment_ffmpeg(
         vec![]
     };
 
-    input
-        .seek(seek_ts_with_slack, ..(seek_ts + 2_000_000))
-        .map_err(|e| HlsError::Ffmpeg(crate::error::FfmpegError::ReadFrame(e.to_string())))?;
+    if needs_seek && segment_type == "audio" && !transcode_audio_to_aac {
+        // Audio packets just after the segment start may be stored before the
+        // video keyframe, so start reading early. The packets before the start
+        // are dropped by `audio_packet_range`, which decides which segment
+        // every packet belongs to.
+        input
+            .seek((seek_ts - AUDIO_SEEK_PREROLL_US).max(0), ..seek_ts)
+            .map_err(|e| HlsError::Ffmpeg(crate::error::FfmpegError::ReadFrame(e.to_string())))?;
+    } else if needs_seek {
+        input
+            .seek(seek_ts_with_slack, ..(seek_ts + 2_000_000))
+            .map_err(|e| HlsError::Ffmpeg(crate::error::FfmpegError::ReadFrame(e.to_string())))?;
+    }
 
     let mut muxer = Fmp4Muxer::new()?;
     let mut stream_indices = Vec::new();
+    let nal_format = |idx: usize| {
+        index
+            .video_streams
+            .iter()
+            .find(|v| v.stream_index == idx)
+            .map(|v| v.nal_format)
+            .unwrap_or_default()
+    };
 
     for stream in input.streams() {
         let params = stream.parameters();
@@ -1095,7 +1939,7 @@ fn generate_media_segment_ffmpeg(
         if is_interleaved {
             if let Some(video_idx) = video_track_index {
                 if idx == video_idx && crate::ffmpeg_utils::utils::is_video_codec(codec_id) {
-                    muxer.add_video_stream(&params, idx)?;
+                    muxer.add_passthrough_video_stream(&params, idx, nal_format(idx))?;
                     stream_indices.push(idx);
                 }
             }
@@ -1103,16 +1947,14 @@ fn generate_media_segment_ffmpeg(
                 if idx == audio_idx && crate::ffmpeg_utils::utils::is_audio_codec(codec_id) {
                     let audio_info = index.get_audio_stream(audio_idx)?;
                     if transcode_audio_to_aac {
-                        let bitrate =
-                            crate::transcode::encoder::get_recommended_bitrate(audio_info.channels);
-                        let encoder = crate::transcode::encoder::AacEncoder::open(
-                            crate::transcode::pipeline::HLS_SAMPLE_RATE,
-                            2,
-                            bitrate,
+                        let params = aac_codec_parameters(
+                            audio_info.channels,
+                            &index.config,
+                            attempt.aac_bitrate,
                         )?;
-                        muxer.add_audio_stream(&encoder.codec_parameters(), idx)?;
-                    } else {
                         muxer.add_audio_stream(&params, idx)?;
+                    } else {
+                        muxer.add_passthrough_audio_stream(&params, idx)?;
                     }
                     stream_indices.push(idx);
                 }
@@ -1130,15 +1972,18 @@ fn generate_media_segment_ffmpeg(
                     }
                 }
                 if is_video {
-                    muxer.add_video_stream(&params, idx)?;
+                    muxer.add_passthrough_video_stream(&params, idx, nal_format(idx))?;
                 } else {
                     if transcode_audio_to_aac {
                         let audio_info = index.get_audio_stream(idx)?;
-                        let bitrate = get_recommended_bitrate(audio_info.channels);
-                        let encoder = AacEncoder::open(HLS_SAMPLE_RATE, 2, bitrate)?;
-                        muxer.add_audio_stream(&encoder.codec_parameters(), idx)?;
-                    } else {
+                        let params = aac_codec_parameters(
+                            audio_info.channels,
+                            &index.config,
+                            attempt.aac_bitrate,
+                        )?;
                         muxer.add_audio_stream(&params, idx)?;
+                    } else {
+                        muxer.add_passthrough_audio_stream(&params, idx)?;
                     }
                 }
                 stream_indices.push(idx);
@@ -1163,6 +2008,20 @@ fn generate_media_segment_ffmpeg(
             audio_timebase = Some(s.time_base());
         }
     }
+    let mix = match mix_track_index {
+        Some(mix_idx) => {
+            let s = input.stream(mix_idx).ok_or_else(|| {
+                HlsError::StreamNotFound(format!("No audio stream {} to mix", mix_idx))
+            })?;
+            Some(MixSource {
+                stream_index: mix_idx,
+                params: s.parameters(),
+                timebase: s.time_base(),
+                preroll: mix_preroll_packets,
+            })
+        }
+        None => None,
+    };
 
     // delay_moov is required when:
     //   1. Pure audio segments: no video keyframes to drive fragmentation.
@@ -1177,22 +2036,85 @@ fn generate_media_segment_ffmpeg(
     //
     // Since we enabled CTTS v1 (negative_cts_offsets) in muxer.rs, delay_moov
     // no longer causes the CTTS/tfdt corruption for B-frame video.
-    let needs_delay_moov = segment_type == "audio" || segment_type == "av";
+    let needs_delay_moov = attempt
+        .mux
+        .delay_moov
+        .unwrap_or(segment_type == "audio" || segment_type == "av");
+    let mut streamer = SegmentStreamer::new(progress);
+    streamer.styp = attempt.mux.styp;
+    if streamer.is_active() {
+        muxer.set_fragment_duration(STREAMING_FRAGMENT_DURATION_US);
+    }
     muxer.write_header(needs_delay_moov)?;
 
-    let buffered_packets = buffer_media_packets(
+    // Audio is cut at exact packet boundaries in its own timebase. Transcoded
+    // audio is read a little further, and the encoder output is cut at the
+    // same boundaries on the AAC frame grid.
+    let audio_range = audio_timebase.map(|tb| {
+        if transcode_audio_to_aac {
+            transcode_packet_range(index, segment, tb)
+        } else {
+            index.audio_packet_range(segment, tb)
+        }
+    });
+    let mix_range = mix
+        .as_ref()
+        .map(|mix| transcode_packet_range(index, segment, mix.timebase));
+
+    let (mut buffered_packets, leftover_packets) = buffer_media_packets(
         &mut input,
+        pending_packets,
         segment,
         segment_type,
         video_timebase,
         &stream_indices,
         audio_track_index,
+        mix_track_index,
+        audio_range.as_ref(),
+        mix_range.as_ref(),
     );
+    if let (Some(audio_idx), Some(audio_tb), Some(range)) =
+        (audio_track_index, audio_timebase, &audio_range)
+    {
+        if !transcode_audio_to_aac {
+            let preroll = std::mem::take(&mut audio_preroll_packets);
+            prepend_preroll(&mut buffered_packets, audio_idx, audio_tb, range, preroll);
+        }
+    }
 
-    // Drop the context lock as soon as all raw packets are read.
+    // Keep a context of our own as the cursor for the next segment. Dropping
+    // a shared context releases its lock as soon as all raw packets are read.
     // This allows other threads (look-ahead workers) to start reading the
     // next segments while this thread performs the heavy transcoding/muxing.
-    std::mem::drop(input);
+    // `segment` may span several segments (long audio segments), so the next
+    // one is the first that ends after it.
+    let next_sequence = index
+        .segments
+        .iter()
+        .find(|s| s.sequence > segment.sequence && s.end_pts > segment.end_pts)
+        .map_or(segment.sequence + 1, |s| s.sequence);
+    let cursor = match input {
+        ContextGuard::Owned(input) if !transcode_audio_to_aac => Some(DemuxCursor {
+            input,
+            next_sequence,
+            pending: leftover_packets,
+        }),
+        _ => None,
+    };
+    index.store_demux_cursor(tracks, next_sequence - 1, cursor);
+
+    // The audio track ended before this segment (audio shorter than video):
+    // send silence instead of an empty segment, so players don't stall.
+    if let (Some(audio_idx), Some(audio_tb)) = (audio_track_index, audio_timebase) {
+        if segment_type == "audio"
+            && !transcode_audio_to_aac
+            && !buffered_packets.iter().any(|p| p.stream_id == audio_idx)
+        {
+            buffered_packets = silent_audio_packets(index, audio_idx, audio_tb, segment)?;
+        }
+    }
+    let _in_flight =
+        crate::memory::InFlight::new(buffered_packets.iter().map(|p| p.packet.size()).sum());
 
     let (transcoded_audio_packets, audio_output_tb) = transcode_audio_if_needed(
         index,
@@ -1200,11 +2122,32 @@ fn generate_media_segment_ffmpeg(
         audio_params,
         audio_timebase,
         transcode_audio_to_aac,
+        attempt.aac_bitrate,
         &buffered_packets,
         segment,
         video_timebase,
         audio_preroll_packets,
+        mix,
     )?;
+    // The track of an audio mix was transcoded with the audio track.
+    if let Some(mix_idx) = mix_track_index {
+        buffered_packets.retain(|p| p.stream_id != mix_idx);
+    }
+
+    let make_patcher = |first_video_dts, first_audio_dts, first_packet_dts| {
+        segment_tfdt_patcher(
+            segment_type,
+            is_interleaved,
+            transcode_audio_to_aac,
+            video_timebase,
+            segment,
+            index,
+            audio_track_index,
+            first_video_dts,
+            first_audio_dts,
+            first_packet_dts,
+        )
+    };
 
     let (muxer, _v_dts, _a_dts, _p_dts) = mux_media_segment(
         segment_type,
@@ -1215,8 +2158,12 @@ fn generate_media_segment_ffmpeg(
         muxer,
         buffered_packets,
         audio_track_index,
+        audio_range.as_ref(),
         transcoded_audio_packets,
         audio_output_tb,
+        progress,
+        &mut streamer,
+        &make_patcher,
     )?;
 
     finalize_segment(
@@ -1231,6 +2178,8 @@ fn generate_media_segment_ffmpeg(
         _v_dts,
         _a_dts,
         _p_dts,
+        progress,
+        &mut streamer,
     )
 }
 #[cfg(test)]
@@ -1269,7 +2218,7 @@ mod tests {
         // Call generate_video_segment
         // Note: The third argument source_path in generate_video_segment is seemingly unused in the function body
         // (it uses index.source_path), but we pass it anyway.
-        let result = generate_video_segment(&index, 0, 0, &path);
+        let result = generate_video_segment(&index, 0, 0, &path, None);
 
         match result {
             Ok(bytes) => {
@@ -1285,6 +2234,227 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_generate_video_segment_progress() {
+        let _ = ffmpeg::init();
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("testvideos");
+        path.push("bun33s.mp4");
+
+        if !path.exists() {
+            eprintln!("Test video not found at {:?}, skipping test", path);
+            return;
+        }
+
+        let mut index = StreamIndex::new(path.clone());
+        index.segments.push(crate::media::SegmentInfo {
+            sequence: 0,
+            start_pts: 0,
+            end_pts: 360000,
+            duration_secs: 4.0,
+            is_keyframe: true,
+            video_byte_offset: 0,
+        });
+
+        let last = std::sync::Mutex::new(crate::hlsvideo::SegmentProgress::default());
+        let observer = |p: &crate::hlsvideo::SegmentProgress| {
+            *last.lock().unwrap() = *p;
+        };
+        let bytes = generate_video_segment(&index, 0, 0, &path, Some(&observer))
+            .expect("Failed to generate video segment");
+
+        let last = *last.lock().unwrap();
+        assert!(last.fragments >= 1, "observer was never called");
+        assert!(last.packets_written > 0);
+        assert!(last.bytes_written > 0 && last.bytes_written <= bytes.len());
+    }
+
+    #[test]
+    fn test_generate_video_segment_streaming() {
+        let _ = ffmpeg::init();
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("testvideos");
+        path.push("bun33s.mp4");
+
+        if !path.exists() {
+            eprintln!("Test video not found at {:?}, skipping test", path);
+            return;
+        }
+
+        let mut index = StreamIndex::new(path.clone());
+        index.segments.push(crate::media::SegmentInfo {
+            sequence: 0,
+            start_pts: 0,
+            end_pts: 360000,
+            duration_secs: 4.0,
+            is_keyframe: true,
+            video_byte_offset: 0,
+        });
+
+        struct Collector(std::sync::Mutex<Vec<Bytes>>);
+        impl ProgressObserver for Collector {
+            fn on_fragment(&self, _: &crate::hlsvideo::SegmentProgress) {}
+            fn wants_data(&self) -> bool {
+                true
+            }
+            fn on_data(&self, chunk: Bytes) {
+                self.0.lock().unwrap().push(chunk);
+            }
+        }
+
+        let collector = Collector(std::sync::Mutex::new(Vec::new()));
+        let bytes = generate_video_segment(&index, 0, 0, &path, Some(&collector))
+            .expect("Failed to generate video segment");
+
+        let chunks = collector.0.into_inner().unwrap();
+        assert!(
+            chunks.len() > 1,
+            "segment was not streamed in multiple chunks"
+        );
+        assert_eq!(&chunks[0][4..8], b"styp");
+        assert_eq!(chunks.concat(), bytes.to_vec());
+    }
+
+    #[test]
+    fn test_fragment_progress() {
+        use crate::tests::fixtures::generate::{generate, FixtureSpec};
+
+        let Some(path) = generate(&FixtureSpec::h264_aac()) else {
+            return;
+        };
+        let index = StreamIndex::open(&path, None).unwrap();
+        let video = index.video_streams[0].stream_index;
+
+        // Streamed, so that the segment has a fragment per second.
+        struct Collector(std::sync::Mutex<Vec<crate::hlsvideo::SegmentProgress>>);
+        impl ProgressObserver for Collector {
+            fn on_fragment(&self, progress: &crate::hlsvideo::SegmentProgress) {
+                self.0.lock().unwrap().push(*progress);
+            }
+            fn wants_data(&self) -> bool {
+                true
+            }
+        }
+        let collector = Collector(std::sync::Mutex::new(Vec::new()));
+        let bytes = generate_video_segment(&index, video, 0, &path, Some(&collector)).unwrap();
+
+        // One report per fragment, as each one is written.
+        let reports = collector.0.into_inner().unwrap();
+        let moofs = crate::segment::compare::analyze(&bytes)
+            .boxes
+            .iter()
+            .filter(|b| b.path == "moof")
+            .count();
+        assert!(moofs > 1, "{} fragments", moofs);
+        assert_eq!(reports.len(), moofs);
+        for (n, pair) in reports.windows(2).enumerate() {
+            assert_eq!(pair[1].fragments, n + 2);
+            assert!(pair[1].bytes_written > pair[0].bytes_written);
+            assert!(pair[1].packets_written >= pair[0].packets_written);
+        }
+    }
+
+    #[test]
+    fn test_demux_cursor_equivalence() {
+        use crate::tests::fixtures::generate::{generate, FixtureSpec};
+
+        let Some(path) = generate(&FixtureSpec::h264_aac()) else {
+            return;
+        };
+        let index = StreamIndex::open(&path, None).unwrap();
+        let video = index.video_streams[0].stream_index;
+        let audio = index.audio_streams[0].stream_index;
+        let count = index.segments.len();
+        assert!(count >= 3, "{} segments", count);
+        let generate_both = |sequence| {
+            let v = generate_video_segment(&index, video, sequence, &path, None).unwrap();
+            let a = generate_audio_segment(&index, audio, sequence, &path, None, None).unwrap();
+            (v, a)
+        };
+        let cursor_at = |tracks| {
+            let cursors = index.demux_cursors.lock().unwrap();
+            cursors
+                .get(&tracks)
+                .and_then(|(_, c)| c.as_ref().map(|c| c.next_sequence))
+        };
+
+        // Backwards, every segment is generated after a seek.
+        let seeked: Vec<_> = (0..count).rev().map(generate_both).collect();
+        // Forwards, segment 1 opens an input of its own, and the segments
+        // after it continue reading from where the previous one stopped.
+        for (sequence, expected) in (0..count).zip(seeked.into_iter().rev()) {
+            if sequence >= 2 {
+                assert_eq!(cursor_at((Some(video), None)), Some(sequence));
+                assert_eq!(cursor_at((None, Some(audio))), Some(sequence));
+            }
+            let (v, a) = generate_both(sequence);
+            assert!(v == expected.0, "video segment {} differs", sequence);
+            assert!(a == expected.1, "audio segment {} differs", sequence);
+        }
+    }
+
+    #[cfg(feature = "transcode")]
+    #[test]
+    fn test_generate_interleaved_segment_parallel() {
+        use crate::tests::fixtures::generate::{generate, FixtureSpec};
+
+        let Some(path) = generate(&FixtureSpec::h264_aac()) else {
+            return;
+        };
+        let index = StreamIndex::open(&path, None).unwrap();
+        let video = index.video_streams[0].stream_index;
+        let audio = index.audio_streams[0].stream_index;
+
+        struct Collector(std::sync::Mutex<Vec<Bytes>>);
+        impl ProgressObserver for Collector {
+            fn on_fragment(&self, _: &crate::hlsvideo::SegmentProgress) {}
+            fn wants_data(&self) -> bool {
+                true
+            }
+            fn on_data(&self, chunk: Bytes) {
+                self.0.lock().unwrap().push(chunk);
+            }
+        }
+
+        // Transcoded: the halves are generated in parallel and merged, and
+        // the merged segment is streamed at once.
+        let collector = Collector(std::sync::Mutex::new(Vec::new()));
+        let segment = &index.segments[0];
+        let bytes = generate_interleaved_segment(
+            &index,
+            video,
+            audio,
+            segment,
+            &path,
+            Some("aac"),
+            Some(&collector),
+        )
+        .unwrap();
+        assert_eq!(collector.0.into_inner().unwrap(), [bytes.clone()]);
+
+        let init = generate_interleaved_init_segment(&index, video, audio, Some("aac")).unwrap();
+        let mut init_ids: Vec<u32> = crate::segment::isobmff::track_handlers(&init)
+            .iter()
+            .map(|(track_id, _)| *track_id)
+            .collect();
+        let mut segment_ids: Vec<u32> = crate::segment::compare::analyze(&bytes)
+            .tracks
+            .iter()
+            .map(|t| t.track_id)
+            .collect();
+        init_ids.sort();
+        segment_ids.sort();
+        assert_eq!(segment_ids, init_ids);
+        let audio_track_id = crate::segment::isobmff::track_handlers(&init)
+            .into_iter()
+            .find(|(_, handler)| handler == b"soun")
+            .map(|(track_id, _)| track_id);
+        assert_eq!(
+            audio_track_id,
+            Some(interleaved_audio_track_id(video, audio))
+        );
+    }
+
     #[test]
     fn test_generate_video_segment_advancement() {
         let _ = ffmpeg::init();
@@ -1309,7 +2479,7 @@ mod tests {
         // Simplest way to have sequence 1 at index 1
         index.segments.push(segment);
 
-        let result = generate_video_segment(&index, 0, 1, &path);
+        let result = generate_video_segment(&index, 0, 1, &path, None);
 
         match result {
             Ok(bytes) => {
@@ -1356,13 +2526,15 @@ mod tests {
                 language: None,
                 profile: None,
                 level: None,
+                codec_string: None,
+                nal_format: Default::default(),
+                stats: None,
             }],
             audio_streams: vec![],
             subtitle_streams: vec![],
             segments: vec![],
             indexed_at: std::time::SystemTime::now(),
             last_accessed: std::sync::atomic::AtomicU64::new(0),
-            segment_first_pts: std::sync::Arc::new(Vec::new()),
             cached_context: None,
             cache_enabled: true,
             last_requested_segment: std::sync::atomic::AtomicI64::new(-1),
@@ -1440,6 +2612,8 @@ mod tests {
             bitrate: 128000,
             language: Some("en".to_string()),
             transcode_to: None,
+            mix_with: None,
+            codec_string: None,
             encoder_delay: 0,
         });
 
@@ -1455,7 +2629,7 @@ mod tests {
         index.segments.push(segment);
 
         // Call generate_audio_segment
-        let result = generate_audio_segment(&index, 1, 0, &source_path, None);
+        let result = generate_audio_segment(&index, 1, 0, &source_path, None, None);
 
         match result {
             Ok(bytes) => {
@@ -1498,6 +2672,8 @@ mod tests {
             bitrate: 128000,
             language: Some("en".to_string()),
             transcode_to: None,
+            mix_with: None,
+            codec_string: None,
             encoder_delay: 0,
         });
 
@@ -1539,6 +2715,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(feature = "transcode")]
     fn test_generate_audio_segment_transcode() {
         let _ = ffmpeg::init();
         let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
@@ -1560,6 +2737,8 @@ mod tests {
             bitrate: 128000,
             language: Some("en".to_string()),
             transcode_to: Some(ffmpeg::codec::Id::AAC),
+            mix_with: None,
+            codec_string: None,
             encoder_delay: 0,
         });
 
@@ -1573,7 +2752,7 @@ mod tests {
         };
         index.segments.push(segment);
 
-        let result = generate_audio_segment(&index, 1, 0, &source_path, Some("aac"));
+        let result = generate_audio_segment(&index, 1, 0, &source_path, Some("aac"), None);
 
         match result {
             Ok(bytes) => {
@@ -1585,4 +2764,142 @@ mod tests {
             Err(e) => panic!("Failed to transcode audio segment: {:?}", e),
         }
     }
+
+    #[cfg(feature = "subtitles")]
+    #[test]
+    fn test_negative_dts_shift() {
+        use crate::params::SubtitleTimestamps;
+        use crate::segment::drift::track_timing;
+        use crate::segment::profile::{set_thread_negative_ts_policy, NegativeTsPolicy};
+        use crate::tests::fixtures::generate::{generate, FixtureSpec};
+
+        let Some(path) = generate(&FixtureSpec::b_frames()) else {
+            return;
+        };
+        let index = StreamIndex::open(&path, None).unwrap();
+        // B-frames: the first decode timestamp is below the first keyframe.
+        assert!(index.first_dts < index.segments[0].start_pts, "{:?}", index);
+        let video = index.video_streams[0].stream_index;
+        let audio = index.audio_streams[0].stream_index;
+        let subtitle = index.subtitle_streams[0].stream_index;
+        let source = &index.source_path;
+
+        // Start of the video, the audio and the subtitles of a segment, in
+        // seconds. Not the first one, where unshifted times are clamped to 0.
+        let starts = |policy, sequence| {
+            set_thread_negative_ts_policy(Some(policy));
+            let video_init = generate_video_init_segment(&index).unwrap();
+            let video_data = generate_video_segment(&index, video, sequence, source, None).unwrap();
+            let audio_init = generate_audio_init_segment(&index, audio, None).unwrap();
+            let audio_data =
+                generate_audio_segment(&index, audio, sequence, source, None, None).unwrap();
+            let vtt = generate_subtitle_segment(
+                &index,
+                subtitle,
+                sequence,
+                sequence,
+                source,
+                SubtitleTimestamps::Mpegts,
+            )
+            .unwrap();
+            set_thread_negative_ts_policy(None);
+
+            let vtt = String::from_utf8(vtt.to_vec()).unwrap();
+            let mpegts: u64 = vtt
+                .lines()
+                .find_map(|l| l.strip_prefix("X-TIMESTAMP-MAP=MPEGTS:"))
+                .and_then(|l| l.split(',').next())
+                .unwrap()
+                .parse()
+                .unwrap();
+            [
+                track_timing(&video_init, &video_data).unwrap().0,
+                track_timing(&audio_init, &audio_data).unwrap().0,
+                mpegts as f64 / 90000.0,
+            ]
+        };
+
+        let shift = -index.first_dts as f64 * f64::from(index.video_timebase);
+        let disabled = starts(NegativeTsPolicy::Disabled, 1);
+        let shifted = starts(NegativeTsPolicy::MakeNonNegative, 1);
+        for (track, (a, b)) in disabled.iter().zip(shifted).enumerate() {
+            let moved = b - a;
+            assert!((moved - shift).abs() < 0.001, "track {}: {}", track, moved);
+        }
+
+        // The first video sample now decodes at 0, instead of being clamped.
+        let first = starts(NegativeTsPolicy::MakeNonNegative, 0);
+        assert!(first[0].abs() < 0.001, "{:?}", first);
+    }
+
+    #[test]
+    fn test_legacy_profile() {
+        use crate::segment::drift::track_timing;
+        use crate::segment::isobmff::find_box;
+        use crate::segment::profile::{
+            set_thread_compatibility_profile, set_thread_negative_ts_policy, CompatibilityProfile,
+            NegativeTsPolicy,
+        };
+        use crate::tests::fixtures::generate::{generate, FixtureSpec};
+
+        let Some(path) = generate(&FixtureSpec::b_frames()) else {
+            return;
+        };
+        let index = StreamIndex::open(&path, None).unwrap();
+        let video = index.video_streams[0].stream_index;
+        let audio = index.audio_streams[0].stream_index;
+        let source = &index.source_path;
+
+        // The init segments and segment 1 of the video and the audio.
+        let segments = |profile| {
+            set_thread_compatibility_profile(Some(profile));
+            let segments = [
+                generate_video_init_segment(&index).unwrap(),
+                generate_video_segment(&index, video, 1, source, None).unwrap(),
+                generate_audio_init_segment(&index, audio, None).unwrap(),
+                generate_audio_segment(&index, audio, 1, source, None, None).unwrap(),
+            ];
+            set_thread_compatibility_profile(None);
+            segments
+        };
+        // Version of box `path` of a media segment.
+        let version = |segment: &[u8], path: &[&[u8; 4]]| {
+            let payload = path
+                .iter()
+                .try_fold(segment, |data, btype| find_box(data, btype))
+                .unwrap();
+            payload[0]
+        };
+
+        let legacy = segments(CompatibilityProfile::Legacy);
+        for init in [&legacy[0], &legacy[2]] {
+            let ftyp = find_box(init, b"ftyp").unwrap();
+            assert_eq!(&ftyp[..4], b"iso5");
+            assert!(!ftyp.windows(4).any(|w| w == b"iso6"));
+        }
+        for segment in [&legacy[1], &legacy[3]] {
+            assert_eq!(&find_box(segment, b"styp").unwrap()[..4], b"msdh");
+            assert_eq!(version(segment, &[b"moof", b"traf", b"tfdt"]), 0);
+            assert_eq!(version(segment, &[b"moof", b"traf", b"trun"]), 0);
+        }
+
+        // Only the boxes differ: the timing is that of the default profile
+        // with the same negative timestamp policy.
+        set_thread_negative_ts_policy(Some(NegativeTsPolicy::MakeNonNegative));
+        let apple = segments(CompatibilityProfile::Apple);
+        set_thread_negative_ts_policy(None);
+        assert_eq!(version(&apple[1], &[b"moof", b"traf", b"tfdt"]), 1);
+        for (init, segment) in [(0, 1), (2, 3)] {
+            let (start, duration) = track_timing(&legacy[init], &legacy[segment]).unwrap();
+            let (apple_start, apple_duration) =
+                track_timing(&apple[init], &apple[segment]).unwrap();
+            assert!(
+                (start - apple_start).abs() < 0.001,
+                "{} {}",
+                start,
+                apple_start
+            );
+            assert!((duration - apple_duration).abs() < 0.001);
+        }
+    }
 }
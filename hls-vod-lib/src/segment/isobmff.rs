@@ -1,30 +1,81 @@
 //! ISOBMFF (MP4) box parsing and manipulation utilities.
 //! Centralizes boilerplate for traversing MP4 structures in memory.
+//!
+//! Everything in here runs on muxer output and must not panic on malformed
+//! input: truncated or bogus boxes end the walk at that point.
+
+/// Maximum nesting depth of container boxes that is traversed.
+const MAX_DEPTH: usize = 16;
+
+/// Parse the header of the box at `pos`.
+///
+/// Returns `(header_len, size, box_type)`. Handles `size == 1` (64-bit
+/// largesize follows the type) and `size == 0` (box extends to the end of
+/// `data`). Returns `None` if the header is truncated, the size is smaller
+/// than the header, or the box extends beyond `data`.
+pub fn box_header(data: &[u8], pos: usize) -> Option<(usize, usize, [u8; 4])> {
+    let header = data.get(pos..pos.checked_add(8)?)?;
+    let btype: [u8; 4] = header[4..8].try_into().unwrap();
+    let (header_len, size) = match u32::from_be_bytes(header[0..4].try_into().unwrap()) {
+        0 => (8, data.len() - pos),
+        1 => {
+            let largesize = data.get(pos + 8..pos + 16)?;
+            let size = u64::from_be_bytes(largesize.try_into().unwrap());
+            (16, usize::try_from(size).ok()?)
+        }
+        size => (8, size as usize),
+    };
+    if size < header_len || size > data.len() - pos {
+        return None;
+    }
+    Some((header_len, size, btype))
+}
+
+/// Length of the header of a box with `payload_len` bytes of payload: 8, or
+/// 16 if the size does not fit in 32 bits and a largesize is needed.
+pub fn box_header_len(payload_len: usize) -> usize {
+    if payload_len as u64 + 8 > u32::MAX as u64 {
+        16
+    } else {
+        8
+    }
+}
+
+/// Append a box header for a box with `payload_len` bytes of payload,
+/// using a 64-bit largesize if needed (see `box_header_len`).
+pub fn write_box_header(out: &mut Vec<u8>, btype: &[u8; 4], payload_len: usize) {
+    let header_len = box_header_len(payload_len);
+    let size = (payload_len + header_len) as u64;
+    if header_len == 16 {
+        out.extend_from_slice(&1u32.to_be_bytes());
+        out.extend_from_slice(btype);
+        out.extend_from_slice(&size.to_be_bytes());
+    } else {
+        out.extend_from_slice(&(size as u32).to_be_bytes());
+        out.extend_from_slice(btype);
+    }
+}
 
 /// Walk all top-level boxes in a buffer, and recursively traverse specified container boxes.
-/// `callback` is invoked for EVERY box in pre-order traversal.
-/// The callback signature is `|box_type: &[u8; 4], payload: &[u8]|`.
-/// Mutable version of `walk_boxes`.
 /// `callback` is invoked for EVERY box in pre-order traversal, with a mutable payload slice.
 pub fn walk_boxes_mut<F>(data: &mut [u8], containers: &[&[u8; 4]], callback: &mut F)
 where
     F: FnMut(&[u8; 4], &mut [u8]),
 {
-    let mut pos = 0;
-    let len = data.len();
-    while pos + 8 <= len {
-        let size =
-            u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
-        if size < 8 || pos + size > len {
-            break;
-        }
-        let btype: [u8; 4] = data[pos + 4..pos + 8].try_into().unwrap();
+    walk_boxes_depth(data, containers, callback, 0);
+}
 
-        let payload = &mut data[pos + 8..pos + size];
+fn walk_boxes_depth<F>(data: &mut [u8], containers: &[&[u8; 4]], callback: &mut F, depth: usize)
+where
+    F: FnMut(&[u8; 4], &mut [u8]),
+{
+    let mut pos = 0;
+    while let Some((header_len, size, btype)) = box_header(data, pos) {
+        let payload = &mut data[pos + header_len..pos + size];
         callback(&btype, payload);
 
-        if containers.contains(&&btype) {
-            walk_boxes_mut(payload, containers, callback);
+        if containers.contains(&&btype) && depth < MAX_DEPTH {
+            walk_boxes_depth(payload, containers, callback, depth + 1);
         }
 
         pos += size;
@@ -73,47 +124,7 @@ pub fn fix_trex_durations_per_track(
 /// multi-fragment segments). Also patches mfhd sequence numbers starting from
 /// `start_frag_seq`.
 pub fn patch_tfdts(media_data: &mut Vec<u8>, target_time: u64, start_frag_seq: u32) {
-    let mut tfdt_delta: Option<i64> = None;
-    let mut frag_count = 0;
-
-    walk_boxes_mut(media_data, &[b"moof", b"traf"], &mut |btype, payload| {
-        if btype == b"moof" {
-            // moof is a container, nothing to mutate directly here.
-        } else if btype == b"mfhd" {
-            let current_frag_seq = start_frag_seq.wrapping_add(frag_count);
-            frag_count += 1;
-            if payload.len() >= 8 {
-                payload[4..8].copy_from_slice(&current_frag_seq.to_be_bytes());
-            }
-        } else if btype == b"tfdt" {
-            if payload.is_empty() {
-                return;
-            }
-            let version = payload[0];
-            let (current_tfdt, value_offset) = if version == 1 && payload.len() >= 12 {
-                (u64::from_be_bytes(payload[4..12].try_into().unwrap()), 4)
-            } else if payload.len() >= 8 {
-                (
-                    u32::from_be_bytes(payload[4..8].try_into().unwrap()) as u64,
-                    4,
-                )
-            } else {
-                (0, 0)
-            };
-
-            if value_offset > 0 {
-                if tfdt_delta.is_none() {
-                    tfdt_delta = Some(target_time as i64 - current_tfdt as i64);
-                }
-                let new_tfdt = (current_tfdt as i64 + tfdt_delta.unwrap()) as u64;
-                if version == 1 {
-                    payload[4..12].copy_from_slice(&new_tfdt.to_be_bytes());
-                } else {
-                    payload[4..8].copy_from_slice(&(new_tfdt as u32).to_be_bytes());
-                }
-            }
-        }
-    });
+    TfdtPatcher::new(target_time, start_frag_seq).patch(media_data);
 }
 
 /// Patch `mfhd` sequence numbers AND each track's `tfdt` independently.
@@ -132,57 +143,740 @@ pub fn patch_tfdts_per_track(
     video_target_tfdt: u64,
     audio_target_tfdt: u64,
 ) {
-    let mut frag_count = 0u32;
-    // Track the last track_id we read from tfhd
-    let mut current_track_id: u32 = 0;
-
-    let mut video_tfdt_delta: Option<i64> = None;
-    let mut audio_tfdt_delta: Option<i64> = None;
-
-    walk_boxes_mut(media_data, &[b"moof", b"traf"], &mut |btype, payload| {
-        if btype == b"mfhd" && payload.len() >= 8 {
-            let seq = start_frag_seq.wrapping_add(frag_count);
-            frag_count += 1;
-            payload[4..8].copy_from_slice(&seq.to_be_bytes());
-        } else if btype == b"tfhd" && payload.len() >= 8 {
-            // tfhd layout: version(1) + flags(3) + track_id(4)
-            current_track_id = u32::from_be_bytes(payload[4..8].try_into().unwrap_or([0; 4]));
-        } else if btype == b"tfdt" && !payload.is_empty() {
-            let version = payload[0];
-            let (current_tfdt, value_offset) = if version == 1 && payload.len() >= 12 {
-                (u64::from_be_bytes(payload[4..12].try_into().unwrap()), 4)
-            } else if payload.len() >= 8 {
-                (
-                    u32::from_be_bytes(payload[4..8].try_into().unwrap()) as u64,
-                    4,
-                )
-            } else {
-                (0, 0)
-            };
-
-            if value_offset > 0 {
-                if current_track_id == video_track_id {
-                    if video_tfdt_delta.is_none() {
-                        video_tfdt_delta = Some(video_target_tfdt as i64 - current_tfdt as i64);
-                    }
-                    let new_tfdt = (current_tfdt as i64 + video_tfdt_delta.unwrap()) as u64;
-                    if version == 1 {
-                        payload[4..12].copy_from_slice(&new_tfdt.to_be_bytes());
-                    } else {
-                        payload[4..8].copy_from_slice(&(new_tfdt as u32).to_be_bytes());
-                    }
-                } else if current_track_id == audio_track_id {
-                    if audio_tfdt_delta.is_none() {
-                        audio_tfdt_delta = Some(audio_target_tfdt as i64 - current_tfdt as i64);
-                    }
-                    let new_tfdt = (current_tfdt as i64 + audio_tfdt_delta.unwrap()) as u64;
-                    if version == 1 {
-                        payload[4..12].copy_from_slice(&new_tfdt.to_be_bytes());
-                    } else {
-                        payload[4..8].copy_from_slice(&(new_tfdt as u32).to_be_bytes());
-                    }
+    TfdtPatcher::per_track(
+        start_frag_seq,
+        video_track_id,
+        audio_track_id,
+        video_target_tfdt,
+        audio_target_tfdt,
+    )
+    .patch(media_data);
+}
+
+/// Incremental `tfdt`/`mfhd` patcher.
+///
+/// Keeps the per-track deltas and the fragment count between calls, so a
+/// segment can be patched one fragment at a time (e.g. when streaming) with
+/// the same result as patching the complete segment at once.
+pub struct TfdtPatcher {
+    start_frag_seq: u32,
+    frag_count: u32,
+    current_track_id: u32,
+    /// (track_id, target tfdt, delta). A track_id of 0 matches every track.
+    tracks: Vec<(u32, u64, Option<i64>)>,
+}
+
+impl TfdtPatcher {
+    /// One delta for all tracks, see `patch_tfdts`.
+    pub fn new(target_time: u64, start_frag_seq: u32) -> Self {
+        Self {
+            start_frag_seq,
+            frag_count: 0,
+            current_track_id: 0,
+            tracks: vec![(0, target_time, None)],
+        }
+    }
+
+    /// Separate deltas for the video and audio track, see `patch_tfdts_per_track`.
+    pub fn per_track(
+        start_frag_seq: u32,
+        video_track_id: u32,
+        audio_track_id: u32,
+        video_target_tfdt: u64,
+        audio_target_tfdt: u64,
+    ) -> Self {
+        Self {
+            start_frag_seq,
+            frag_count: 0,
+            current_track_id: 0,
+            tracks: vec![
+                (video_track_id, video_target_tfdt, None),
+                (audio_track_id, audio_target_tfdt, None),
+            ],
+        }
+    }
+
+    /// Patch all `moof` boxes in `media_data`.
+    pub fn patch(&mut self, media_data: &mut [u8]) {
+        walk_boxes_mut(media_data, &[b"moof", b"traf"], &mut |btype, payload| {
+            if btype == b"mfhd" && payload.len() >= 8 {
+                let seq = self.start_frag_seq.wrapping_add(self.frag_count);
+                self.frag_count = self.frag_count.wrapping_add(1);
+                payload[4..8].copy_from_slice(&seq.to_be_bytes());
+            } else if btype == b"tfhd" && payload.len() >= 8 {
+                // tfhd layout: version(1) + flags(3) + track_id(4)
+                self.current_track_id =
+                    u32::from_be_bytes(payload[4..8].try_into().unwrap_or([0; 4]));
+            } else if btype == b"tfdt" {
+                let Some(current_tfdt) = tfdt_time(payload) else {
+                    return;
+                };
+                let version = payload[0];
+
+                let track_id = self.current_track_id;
+                let Some((_, target, delta)) = self
+                    .tracks
+                    .iter_mut()
+                    .find(|(id, _, _)| *id == 0 || *id == track_id)
+                else {
+                    return;
+                };
+                let delta =
+                    *delta.get_or_insert((*target as i64).wrapping_sub(current_tfdt as i64));
+                let new_tfdt = (current_tfdt as i64).wrapping_add(delta) as u64;
+                if version == 1 {
+                    payload[4..12].copy_from_slice(&new_tfdt.to_be_bytes());
+                } else {
+                    payload[4..8].copy_from_slice(&(new_tfdt as u32).to_be_bytes());
                 }
             }
+        });
+    }
+}
+
+/// Position, size and type of every top-level box in `data`.
+fn top_level_boxes(data: &[u8]) -> Vec<(usize, usize, [u8; 4])> {
+    let mut boxes = Vec::new();
+    let mut pos = 0;
+    while let Some((_, size, btype)) = box_header(data, pos) {
+        boxes.push((pos, size, btype));
+        pos += size;
+    }
+    boxes
+}
+
+/// The payload of a box returned by `top_level_boxes`.
+fn box_payload(data: &[u8], pos: usize) -> &[u8] {
+    let (header_len, size, _) = box_header(data, pos).unwrap();
+    &data[pos + header_len..pos + size]
+}
+
+/// Type and payload of every top-level box in `data`.
+pub fn child_boxes(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    top_level_boxes(data)
+        .into_iter()
+        .map(|(pos, _, btype)| (btype, box_payload(data, pos)))
+        .collect()
+}
+
+/// The payload of the first top-level box of type `btype` in `data`.
+pub fn find_box<'a>(data: &'a [u8], btype: &[u8; 4]) -> Option<&'a [u8]> {
+    child_boxes(data)
+        .into_iter()
+        .find(|(t, _)| t == btype)
+        .map(|(_, payload)| payload)
+}
+
+/// The track ID and the handler type (`vide`, `soun`, ...) of every `trak`
+/// of the `moov` box of an init segment, in order.
+pub fn track_handlers(init: &[u8]) -> Vec<(u32, [u8; 4])> {
+    let Some(moov) = find_box(init, b"moov") else {
+        return Vec::new();
+    };
+    child_boxes(moov)
+        .into_iter()
+        .filter(|(btype, _)| btype == b"trak")
+        .filter_map(|(_, trak)| {
+            // The track ID follows the creation and modification times.
+            let tkhd = find_box(trak, b"tkhd")?;
+            let pos = if tkhd.first()? == &1 { 20 } else { 12 };
+            let track_id = u32::from_be_bytes(tkhd.get(pos..pos + 4)?.try_into().ok()?);
+            let hdlr = find_box(find_box(trak, b"mdia")?, b"hdlr")?;
+            Some((track_id, hdlr.get(8..12)?.try_into().ok()?))
+        })
+        .collect()
+}
+
+/// The base media decode time in the payload of a `tfdt` box (version 0 or 1).
+pub fn tfdt_time(payload: &[u8]) -> Option<u64> {
+    match *payload.first()? {
+        1 => Some(u64::from_be_bytes(payload.get(4..12)?.try_into().ok()?)),
+        _ => Some(u32::from_be_bytes(payload.get(4..8)?.try_into().ok()?) as u64),
+    }
+}
+
+/// A `moof` box and the payload of the `mdat` that follows it.
+struct Fragment<'a> {
+    moof: &'a [u8],
+    /// Offset of the `mdat` payload relative to the start of the `moof`.
+    payload_offset: usize,
+    payload: &'a [u8],
+}
+
+fn split_fragments(data: &[u8]) -> Vec<Fragment<'_>> {
+    let boxes = top_level_boxes(data);
+    boxes
+        .windows(2)
+        .filter(|w| &w[0].2 == b"moof" && &w[1].2 == b"mdat")
+        .map(|w| {
+            let payload = box_payload(data, w[1].0);
+            Fragment {
+                moof: &data[w[0].0..w[0].0 + w[0].1],
+                payload_offset: w[1].0 + w[1].1 - payload.len() - w[0].0,
+                payload,
+            }
+        })
+        .collect()
+}
+
+/// Merge a video-only and an audio-only media segment into one `moof` + `mdat`.
+///
+/// The result has the layout the muxer produces for an interleaved segment:
+/// the `mfhd` of the first video fragment, all video `traf`s, then all audio
+/// `traf`s (with their `tfhd` track ID set to `audio_track_id`), followed by a
+/// single `mdat`. `trun` data offsets are rewritten for the new layout; `tfdt`s
+/// are copied as-is, so both inputs must already be patched.
+///
+/// Returns `None` if the inputs don't use `default-base-is-moof` style
+/// offsets (a `tfhd` base data offset, or a `trun` without data offset).
+pub fn merge_track_fragments(video: &[u8], audio: &[u8], audio_track_id: u32) -> Option<Vec<u8>> {
+    let video = split_fragments(video);
+    let audio = split_fragments(audio);
+    let moof = box_payload(video.first()?.moof, 0);
+    let mfhd = top_level_boxes(moof)
+        .into_iter()
+        .find(|b| &b.2 == b"mfhd")
+        .map(|(pos, size, _)| &moof[pos..pos + size])?;
+    if audio.is_empty() {
+        return None;
+    }
+
+    // (traf, index of the fragment it came from)
+    let mut trafs: Vec<(Vec<u8>, usize)> = Vec::new();
+    let fragments: Vec<&Fragment> = video.iter().chain(audio.iter()).collect();
+    for (i, frag) in fragments.iter().enumerate() {
+        let is_audio = i >= video.len();
+        let moof = box_payload(frag.moof, 0);
+        for (pos, size, btype) in top_level_boxes(moof) {
+            if &btype == b"traf" {
+                let mut traf = moof[pos..pos + size].to_vec();
+                let header_len = traf.len() - box_payload(&traf, 0).len();
+                if is_audio {
+                    walk_boxes_mut(&mut traf[header_len..], &[], &mut |btype, payload| {
+                        if btype == b"tfhd" && payload.len() >= 8 {
+                            payload[4..8].copy_from_slice(&audio_track_id.to_be_bytes());
+                        }
+                    });
+                }
+                trafs.push((traf, i));
+            }
+        }
+    }
+
+    let moof_size = 8 + mfhd.len() + trafs.iter().map(|(t, _)| t.len()).sum::<usize>();
+    if moof_size > u32::MAX as usize {
+        return None;
+    }
+    let payload_len: usize = fragments.iter().map(|f| f.payload.len()).sum();
+    let mdat_header_len = box_header_len(payload_len);
+    let mut mdat_positions = Vec::with_capacity(fragments.len());
+    let mut mdat_pos = mdat_header_len;
+    for frag in &fragments {
+        mdat_positions.push(mdat_pos);
+        mdat_pos += frag.payload.len();
+    }
+
+    let mut ok = true;
+    for (traf, i) in trafs.iter_mut() {
+        let frag = fragments[*i];
+        let shift = (moof_size + mdat_positions[*i]) as i64 - frag.payload_offset as i64;
+        let header_len = traf.len() - box_payload(traf, 0).len();
+        walk_boxes_mut(&mut traf[header_len..], &[], &mut |btype, payload| {
+            if payload.len() < 4 {
+                return;
+            }
+            let flags = u32::from_be_bytes([0, payload[1], payload[2], payload[3]]);
+            if btype == b"tfhd" && flags & 0x1 != 0 {
+                ok = false;
+            } else if btype == b"trun" {
+                if flags & 0x1 == 0 || payload.len() < 12 {
+                    ok = false;
+                    return;
+                }
+                let offset = i32::from_be_bytes(payload[8..12].try_into().unwrap()) as i64;
+                // trun data offsets are 32 bit, so samples must start in the first 2 GB.
+                let Ok(offset) = i32::try_from(offset + shift) else {
+                    ok = false;
+                    return;
+                };
+                payload[8..12].copy_from_slice(&offset.to_be_bytes());
+            }
+        });
+    }
+    if !ok {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(moof_size + mdat_header_len + payload_len);
+    write_box_header(&mut out, b"moof", moof_size - 8);
+    out.extend_from_slice(mfhd);
+    for (traf, _) in &trafs {
+        out.extend_from_slice(traf);
+    }
+    write_box_header(&mut out, b"mdat", payload_len);
+    for frag in &fragments {
+        out.extend_from_slice(frag.payload);
+    }
+    Some(out)
+}
+
+/// A `ftyp` or `styp` box with the given brands.
+pub fn brand_box(btype: &[u8; 4], major: &[u8; 4], minor: u32, compatible: &[&[u8; 4]]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(16 + 4 * compatible.len());
+    write_box_header(&mut out, btype, 8 + 4 * compatible.len());
+    out.extend_from_slice(major);
+    out.extend_from_slice(&minor.to_be_bytes());
+    for brand in compatible {
+        out.extend_from_slice(*brand);
+    }
+    out
+}
+
+/// Replace the `ftyp` box at the start of an init segment with `ftyp`.
+/// Does nothing if `data` does not start with a `ftyp` box.
+pub fn replace_ftyp(data: &mut Vec<u8>, ftyp: &[u8]) {
+    if let Some((_, size, btype)) = box_header(data, 0) {
+        if &btype == b"ftyp" {
+            data.splice(0..size, ftyp.iter().copied());
+        }
+    }
+}
+
+/// Rewrite version 1 `tfdt` boxes to version 0 (32-bit decode time) if the
+/// decode time fits, for players that only understand version 0.
+///
+/// The `moof`s get smaller, so `trun` data offsets are adjusted. Fragments
+/// with a `tfhd` base data offset are copied as-is.
+pub fn downgrade_tfdts(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut end = 0;
+    for (pos, size, btype) in top_level_boxes(data) {
+        let b = &data[pos..pos + size];
+        match downgrade_moof_tfdts(&btype, b) {
+            Some(moof) => out.extend_from_slice(&moof),
+            None => out.extend_from_slice(b),
+        }
+        end = pos + size;
+    }
+    out.extend_from_slice(&data[end..]);
+    out
+}
+
+// The `moof` box `moof` with version 0 `tfdt`s, or `None` if nothing changed.
+fn downgrade_moof_tfdts(btype: &[u8; 4], moof: &[u8]) -> Option<Vec<u8>> {
+    if btype != b"moof" {
+        return None;
+    }
+    let payload = box_payload(moof, 0);
+    let mut children = Vec::new();
+    for (pos, size, btype) in top_level_boxes(payload) {
+        let child = &payload[pos..pos + size];
+        if &btype != b"traf" {
+            children.push(child.to_vec());
+            continue;
+        }
+        let traf_payload = box_payload(child, 0);
+        let mut traf_children = Vec::new();
+        for (pos, size, btype) in top_level_boxes(traf_payload) {
+            let b = &traf_payload[pos..pos + size];
+            let p = box_payload(b, 0);
+            if &btype == b"tfhd" && p.len() >= 4 && p[3] & 0x1 != 0 {
+                return None;
+            }
+            if &btype == b"tfdt" && p.len() >= 12 && p[0] == 1 {
+                let time = u64::from_be_bytes(p[4..12].try_into().unwrap());
+                if let Ok(time) = u32::try_from(time) {
+                    let mut tfdt = Vec::with_capacity(16);
+                    write_box_header(&mut tfdt, b"tfdt", 8);
+                    tfdt.extend_from_slice(&[0, p[1], p[2], p[3]]);
+                    tfdt.extend_from_slice(&time.to_be_bytes());
+                    traf_children.push(tfdt);
+                    continue;
+                }
+            }
+            traf_children.push(b.to_vec());
+        }
+        let mut traf = Vec::new();
+        write_box_header(&mut traf, b"traf", traf_children.iter().map(Vec::len).sum());
+        for b in traf_children {
+            traf.extend_from_slice(&b);
+        }
+        children.push(traf);
+    }
+
+    let payload_len: usize = children.iter().map(Vec::len).sum();
+    let mut out = Vec::with_capacity(payload_len + 8);
+    write_box_header(&mut out, b"moof", payload_len);
+    let header_len = out.len();
+    for b in children {
+        out.extend_from_slice(&b);
+    }
+    let shrink = moof.len().checked_sub(out.len())?;
+    if shrink == 0 {
+        return None;
+    }
+
+    // Data offsets are relative to the start of the moof, and the samples
+    // in the mdat after it moved `shrink` bytes closer.
+    walk_boxes_mut(&mut out[header_len..], &[b"traf"], &mut |btype, payload| {
+        if btype == b"trun" && payload.len() >= 12 && payload[3] & 0x1 != 0 {
+            let offset = i32::from_be_bytes(payload[8..12].try_into().unwrap());
+            let offset = offset.wrapping_sub(shrink as i32);
+            payload[8..12].copy_from_slice(&offset.to_be_bytes());
         }
     });
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mp4_box(btype: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut b = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        b.extend_from_slice(btype);
+        b.extend_from_slice(payload);
+        b
+    }
+
+    /// A single-track segment: moof(mfhd, traf(tfhd, tfdt, trun)) + mdat.
+    fn fragment(seq: u32, track_id: u32, tfdt: u32, data: &[u8]) -> Vec<u8> {
+        let tfdt = mp4_box(b"tfdt", &[&[0u8; 4][..], &tfdt.to_be_bytes()].concat());
+        fragment_with_tfdt(seq, track_id, tfdt, data)
+    }
+
+    /// Like `fragment`, with a version 1 `tfdt`.
+    fn fragment_v1(seq: u32, track_id: u32, tfdt: u64, data: &[u8]) -> Vec<u8> {
+        let tfdt = mp4_box(
+            b"tfdt",
+            &[&[1u8, 0, 0, 0][..], &tfdt.to_be_bytes()].concat(),
+        );
+        fragment_with_tfdt(seq, track_id, tfdt, data)
+    }
+
+    fn fragment_with_tfdt(seq: u32, track_id: u32, tfdt: Vec<u8>, data: &[u8]) -> Vec<u8> {
+        let mfhd = mp4_box(b"mfhd", &[&[0u8; 4][..], &seq.to_be_bytes()].concat());
+        let tfhd = mp4_box(
+            b"tfhd",
+            &[&[0, 0x02, 0, 0][..], &track_id.to_be_bytes()].concat(),
+        );
+        // trun with data-offset-present, one sample, offset patched below.
+        let trun_payload = [&[0, 0, 0, 1][..], &1u32.to_be_bytes(), &0i32.to_be_bytes()].concat();
+        let traf_len = 8 + tfhd.len() + tfdt.len() + trun_payload.len() + 8;
+        let moof_len = 8 + mfhd.len() + traf_len;
+        let trun_payload = [
+            &[0, 0, 0, 1][..],
+            &1u32.to_be_bytes(),
+            &((moof_len + 8) as i32).to_be_bytes(),
+        ]
+        .concat();
+        let traf = mp4_box(
+            b"traf",
+            &[tfhd, tfdt, mp4_box(b"trun", &trun_payload)].concat(),
+        );
+        let mut out = mp4_box(b"styp", b"iso8");
+        out.extend(mp4_box(b"moof", &[mfhd, traf].concat()));
+        out.extend(mp4_box(b"mdat", data));
+        out
+    }
+
+    /// (track_id, tfdt, sample data) for every traf in the first moof.
+    fn read_trafs(data: &[u8]) -> Vec<(u32, u32, Vec<u8>)> {
+        let moof_pos = top_level_boxes(data)
+            .into_iter()
+            .find(|b| &b.2 == b"moof")
+            .unwrap()
+            .0;
+        let mut trafs = Vec::new();
+        let (mut track_id, mut tfdt) = (0, 0);
+        let mut moof = data[moof_pos..].to_vec();
+        walk_boxes_mut(
+            &mut moof[8..],
+            &[b"traf"],
+            &mut |btype, payload| match btype {
+                b"tfhd" => track_id = u32::from_be_bytes(payload[4..8].try_into().unwrap()),
+                b"tfdt" => tfdt = u32::from_be_bytes(payload[4..8].try_into().unwrap()),
+                b"trun" => {
+                    let offset = i32::from_be_bytes(payload[8..12].try_into().unwrap()) as usize;
+                    let start = moof_pos + offset;
+                    trafs.push((track_id, tfdt, data[start..start + 3].to_vec()));
+                }
+                _ => {}
+            },
+        );
+        trafs
+    }
+
+    /// xorshift64, so the property tests are reproducible without extra dependencies.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    /// Serialize a box with a 32-bit size, a 64-bit largesize, or (if it is
+    /// the last box of its parent) size 0.
+    fn encode_box(rng: &mut Rng, btype: &[u8; 4], payload: &[u8], last: bool) -> Vec<u8> {
+        match rng.below(if last { 3 } else { 2 }) {
+            0 => mp4_box(btype, payload),
+            1 => {
+                let mut b = 1u32.to_be_bytes().to_vec();
+                b.extend_from_slice(btype);
+                b.extend_from_slice(&((payload.len() + 16) as u64).to_be_bytes());
+                b.extend_from_slice(payload);
+                b
+            }
+            _ => [&[0u8; 4][..], btype, payload].concat(),
+        }
+    }
+
+    /// A random box tree. Appends (type, payload length) of every box to
+    /// `visits` in pre-order.
+    fn random_boxes(rng: &mut Rng, depth: usize, visits: &mut Vec<([u8; 4], usize)>) -> Vec<u8> {
+        let mut out = Vec::new();
+        let count = rng.below(4);
+        for i in 0..count {
+            let last = i + 1 == count;
+            if depth < 4 && rng.below(2) == 0 {
+                let btype = if rng.below(2) == 0 {
+                    *b"moof"
+                } else {
+                    *b"traf"
+                };
+                let idx = visits.len();
+                visits.push((btype, 0));
+                let payload = random_boxes(rng, depth + 1, visits);
+                visits[idx].1 = payload.len();
+                out.extend(encode_box(rng, &btype, &payload, last));
+            } else {
+                let btype = [*b"mfhd", *b"tfhd", *b"tfdt", *b"trun"][rng.below(4)];
+                let payload: Vec<u8> = (0..rng.below(24)).map(|_| rng.next() as u8).collect();
+                visits.push((btype, payload.len()));
+                out.extend(encode_box(rng, &btype, &payload, last));
+            }
+        }
+        out
+    }
+
+    fn walk_visits(data: &mut [u8]) -> Vec<([u8; 4], usize)> {
+        let mut visits = Vec::new();
+        walk_boxes_mut(data, &[b"moof", b"traf"], &mut |btype, payload| {
+            visits.push((*btype, payload.len()))
+        });
+        visits
+    }
+
+    #[test]
+    fn test_walk_random_box_trees() {
+        for seed in 1..500 {
+            let mut rng = Rng(seed);
+            let mut expected = Vec::new();
+            let mut data = random_boxes(&mut rng, 0, &mut expected);
+            assert_eq!(walk_visits(&mut data), expected, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn test_malformed_input_does_not_panic() {
+        for seed in 1..2000 {
+            let mut rng = Rng(seed);
+            let mut data = random_boxes(&mut rng, 0, &mut Vec::new());
+            data.truncate(rng.below(data.len() + 1));
+            for _ in 0..rng.below(4) {
+                if !data.is_empty() {
+                    let pos = rng.below(data.len());
+                    data[pos] = rng.next() as u8;
+                }
+            }
+            walk_visits(&mut data);
+            patch_tfdts(&mut data.clone(), rng.next(), rng.next() as u32);
+            patch_tfdts_per_track(&mut data.clone(), u32::MAX, 1, 2, u64::MAX, 0);
+            merge_track_fragments(&data, &data, 2);
+            child_boxes(&data);
+        }
+    }
+
+    #[test]
+    fn test_box_header() {
+        // Regular, largesize and size-0 boxes.
+        assert_eq!(
+            box_header(&mp4_box(b"free", b"ab"), 0),
+            Some((8, 10, *b"free"))
+        );
+        let mut large = 1u32.to_be_bytes().to_vec();
+        large.extend_from_slice(b"mdat");
+        large.extend_from_slice(&18u64.to_be_bytes());
+        large.extend_from_slice(b"ab");
+        assert_eq!(box_header(&large, 0), Some((16, 18, *b"mdat")));
+        let to_end = [&[0u8; 4][..], b"mdat", b"abc"].concat();
+        assert_eq!(box_header(&to_end, 0), Some((8, 11, *b"mdat")));
+
+        // Truncated header, size smaller than the header, size past the end.
+        assert_eq!(box_header(&large[..12], 0), None);
+        assert_eq!(box_header(&to_end, 4), None);
+        assert_eq!(box_header(&[0, 0, 0, 4, b'f', b'r', b'e', b'e'], 0), None);
+        large[8..16].copy_from_slice(&8u64.to_be_bytes());
+        assert_eq!(box_header(&large, 0), None);
+        large[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(box_header(&large, 0), None);
+        assert_eq!(box_header(&mp4_box(b"free", b"ab"), usize::MAX), None);
+    }
+
+    #[test]
+    fn test_child_boxes() {
+        let tfdt0 = [&[0u8; 4][..], &100u32.to_be_bytes()].concat();
+        let tfdt1 = [&[1u8, 0, 0, 0][..], &(1u64 << 40).to_be_bytes()].concat();
+        let data = [mp4_box(b"tfdt", &tfdt0), mp4_box(b"tfdt", &tfdt1)].concat();
+        let boxes = child_boxes(&data);
+        assert_eq!(boxes.len(), 2);
+        assert_eq!(tfdt_time(boxes[0].1), Some(100));
+        assert_eq!(tfdt_time(boxes[1].1), Some(1 << 40));
+        assert_eq!(find_box(&data, b"tfdt"), Some(&tfdt0[..]));
+        assert_eq!(find_box(&data, b"trun"), None);
+        assert_eq!(tfdt_time(&tfdt1[..8]), None);
+    }
+
+    #[test]
+    fn test_patch_tfdts_largesize() {
+        let tfdt = mp4_box(b"tfdt", &[&[0u8; 4][..], &100u32.to_be_bytes()].concat());
+        let mut traf = 1u32.to_be_bytes().to_vec();
+        traf.extend_from_slice(b"traf");
+        traf.extend_from_slice(&((tfdt.len() + 16) as u64).to_be_bytes());
+        traf.extend_from_slice(&tfdt);
+        let mut data = [&[0u8; 4][..], b"moof", &traf].concat();
+
+        patch_tfdts(&mut data, 5000, 1);
+        let pos = data.windows(4).position(|w| w == b"tfdt").unwrap();
+        let patched = u32::from_be_bytes(data[pos + 8..pos + 12].try_into().unwrap());
+        assert_eq!(patched, 5000);
+    }
+
+    #[test]
+    fn test_walk_depth_limit() {
+        // Every box is a moof that extends to the end of its parent.
+        let mut data = b"\0\0\0\0moof".repeat(10_000);
+        assert_eq!(walk_visits(&mut data).len(), MAX_DEPTH + 1);
+    }
+
+    #[test]
+    fn test_merge_track_fragments() {
+        let video = fragment(7, 1, 90_000, b"vvv");
+        let audio = fragment(7, 1, 48_000, b"aaa");
+        let merged = merge_track_fragments(&video, &audio, 2).unwrap();
+
+        let boxes = top_level_boxes(&merged);
+        let types: Vec<_> = boxes.iter().map(|b| &b.2).collect();
+        assert_eq!(types, [b"moof", b"mdat"]);
+        assert_eq!(boxes[1].0 + boxes[1].1, merged.len());
+        assert_eq!(
+            read_trafs(&merged),
+            vec![(1, 90_000, b"vvv".to_vec()), (2, 48_000, b"aaa".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_track_handlers() {
+        let trak = |version: u8, track_id: u32, handler: &[u8; 4]| {
+            let mut tkhd = vec![version, 0, 0, 0];
+            tkhd.resize(if version == 1 { 20 } else { 12 }, 0);
+            tkhd.extend_from_slice(&track_id.to_be_bytes());
+            tkhd.resize(tkhd.len() + 8, 0);
+            let hdlr = [&[0u8; 8][..], handler, &[0u8; 12]].concat();
+            let mdia = mp4_box(b"mdia", &mp4_box(b"hdlr", &hdlr));
+            mp4_box(b"trak", &[mp4_box(b"tkhd", &tkhd), mdia].concat())
+        };
+        let moov = [
+            mp4_box(b"mvhd", &[0; 100]),
+            trak(0, 1, b"vide"),
+            trak(1, 3, b"soun"),
+        ]
+        .concat();
+        let init = [mp4_box(b"ftyp", b"iso5"), mp4_box(b"moov", &moov)].concat();
+        assert_eq!(track_handlers(&init), [(1, *b"vide"), (3, *b"soun")]);
+        assert!(track_handlers(b"").is_empty());
+    }
+
+    #[test]
+    fn test_write_box_header() {
+        let mut out = Vec::new();
+        write_box_header(&mut out, b"mdat", 3);
+        assert_eq!(out, [0, 0, 0, 11, b'm', b'd', b'a', b't']);
+
+        let mut out = Vec::new();
+        write_box_header(&mut out, b"mdat", u32::MAX as usize);
+        assert_eq!(&out[..8], &[0, 0, 0, 1, b'm', b'd', b'a', b't']);
+        assert_eq!(out[8..16], (u32::MAX as u64 + 16).to_be_bytes());
+        assert_eq!(box_header_len(u32::MAX as usize - 8), 8);
+        assert_eq!(box_header_len(u32::MAX as usize - 7), 16);
+    }
+
+    #[test]
+    fn test_merge_track_fragments_largesize_mdat() {
+        let video = fragment(1, 1, 0, b"vvv");
+        // Audio fragment with a 64-bit largesize mdat header.
+        let audio = fragment(1, 1, 0, b"aaa");
+        let mdat_pos = audio.len() - 11;
+        let mut large = audio[..mdat_pos].to_vec();
+        large.extend_from_slice(&[0, 0, 0, 1, b'm', b'd', b'a', b't']);
+        large.extend_from_slice(&19u64.to_be_bytes());
+        large.extend_from_slice(b"aaa");
+        // The samples moved 8 bytes further away from the moof.
+        let pos = large.windows(4).position(|w| w == b"trun").unwrap() + 12;
+        let offset = i32::from_be_bytes(large[pos..pos + 4].try_into().unwrap());
+        large[pos..pos + 4].copy_from_slice(&(offset + 8).to_be_bytes());
+
+        let merged = merge_track_fragments(&video, &large, 2).unwrap();
+        assert_eq!(
+            read_trafs(&merged),
+            vec![(1, 0, b"vvv".to_vec()), (2, 0, b"aaa".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_merge_track_fragments_requires_data_offsets() {
+        let video = fragment(1, 1, 0, b"vvv");
+        let mut audio = fragment(1, 1, 0, b"aaa");
+        // Clear the data-offset-present flag of the audio trun.
+        let pos = audio.windows(4).position(|w| w == b"trun").unwrap();
+        audio[pos + 7] = 0;
+        assert!(merge_track_fragments(&video, &audio, 2).is_none());
+        assert!(merge_track_fragments(&video, b"", 2).is_none());
+    }
+
+    #[test]
+    fn test_downgrade_tfdts() {
+        let data = fragment_v1(1, 1, 90_000, b"vvv");
+        let downgraded = downgrade_tfdts(&data);
+        assert_eq!(downgraded.len(), data.len() - 4);
+        assert_eq!(read_trafs(&downgraded), vec![(1, 90_000, b"vvv".to_vec())]);
+        let pos = downgraded.windows(4).position(|w| w == b"tfdt").unwrap();
+        assert_eq!(downgraded[pos + 4], 0);
+
+        // Decode times that don't fit in 32 bits, and version 0, stay as they are.
+        let data = fragment_v1(1, 1, u32::MAX as u64 + 1, b"vvv");
+        assert_eq!(downgrade_tfdts(&data), data);
+        let data = fragment(1, 1, 90_000, b"vvv");
+        assert_eq!(downgrade_tfdts(&data), data);
+    }
+
+    #[test]
+    fn test_replace_ftyp() {
+        let mut data = mp4_box(b"ftyp", b"iso5\0\0\x02\0iso5iso6mp41");
+        data.extend(mp4_box(b"moov", b""));
+        let ftyp = brand_box(b"ftyp", b"cmfc", 0, &[b"iso6", b"cmfc"]);
+        assert_eq!(ftyp, mp4_box(b"ftyp", b"cmfc\0\0\0\0iso6cmfc"));
+        replace_ftyp(&mut data, &ftyp);
+        let types: Vec<_> = top_level_boxes(&data).into_iter().map(|b| b.2).collect();
+        assert_eq!(types, [*b"ftyp", *b"moov"]);
+        assert_eq!(&data[..ftyp.len()], &ftyp[..]);
+    }
 }
@@ -2,6 +2,12 @@
 //!
 //! This module handles fMP4/CMAF segment generation using FFmpeg CLI.
 
+pub mod compare;
+pub mod consistency;
+pub mod drift;
 pub mod generator;
 pub mod isobmff;
 pub mod muxer;
+pub mod nal;
+pub mod profile;
+pub mod retry;
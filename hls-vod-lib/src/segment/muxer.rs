@@ -1,16 +1,89 @@
 //! fMP4 validation and muxing utilities
 
 use crate::error::{FfmpegError, Result};
+use crate::ffmpeg_utils::bsf::BitstreamFilter;
 use crate::ffmpeg_utils::io::{create_memory_io, MemoryWriter};
+use crate::hlsvideo::{ProgressObserver, SegmentProgress};
+use crate::segment::isobmff::box_header;
 use ffmpeg_next as ffmpeg;
 use std::collections::HashMap;
 
+/// Muxer settings for media segments that are normally chosen automatically.
+///
+/// Only used to compare muxer configurations, see
+/// `hlsvideo::PlaylistOrSegment::compare_muxers`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct MuxOptions {
+    /// Set the `delay_moov` movflag. `None` = decided per segment type.
+    pub delay_moov: Option<bool>,
+    /// Prepend a `styp` box to the media segment
+    pub styp: bool,
+}
+
+impl Default for MuxOptions {
+    fn default() -> Self {
+        Self {
+            delay_moov: None,
+            styp: true,
+        }
+    }
+}
+
+impl std::str::FromStr for MuxOptions {
+    type Err = String;
+
+    /// Parse a comma separated list of `delay_moov`, `no_delay_moov`,
+    /// `styp`, `no_styp`. An empty string gives the defaults.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut opts = MuxOptions::default();
+        for flag in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            match flag {
+                "delay_moov" => opts.delay_moov = Some(true),
+                "no_delay_moov" => opts.delay_moov = Some(false),
+                "styp" => opts.styp = true,
+                "no_styp" => opts.styp = false,
+                _ => return Err(format!("unknown muxer option: {}", flag)),
+            }
+        }
+        Ok(opts)
+    }
+}
+
+/// The `movflags` of the mp4 muxer. Negative composition offsets (`trun`
+/// version 1) depend on the compatibility profile.
+fn movflags(delay_moov: bool) -> String {
+    let mut flags = String::from("empty_moov+default_base_moof");
+    if delay_moov {
+        flags.push_str("+delay_moov");
+    }
+    if crate::segment::profile::compatibility_profile().negative_cts_offsets() {
+        flags.push_str("+negative_cts_offsets");
+    }
+    flags
+}
+
 /// Muxer for creating fMP4/CMAF segments in memory
 pub struct Fmp4Muxer {
     output: ffmpeg::format::context::Output,
     writer: Box<MemoryWriter>,
     /// Map from input stream index to output stream index
     stream_map: HashMap<usize, usize>,
+    /// Bitstream filters per output stream index
+    filters: HashMap<usize, BitstreamFilter>,
+    /// NAL length size per output stream index, for Annex B packets that
+    /// must be converted to match an `avcC`/`hvcC` record
+    annexb_length_size: HashMap<usize, usize>,
+    /// Size of the header (init segment) in the output buffer
+    header_len: usize,
+    /// Offset in the output buffer up to which fragments have been reported
+    reported_len: usize,
+    /// Packets written since the header
+    packets_written: usize,
+    /// Fragments flushed so far
+    fragments: usize,
+    trailer_written: bool,
+    /// Maximum fragment duration in microseconds
+    frag_duration: i64,
 }
 
 impl Fmp4Muxer {
@@ -22,6 +95,14 @@ impl Fmp4Muxer {
             output,
             writer,
             stream_map: HashMap::new(),
+            filters: HashMap::new(),
+            annexb_length_size: HashMap::new(),
+            header_len: 0,
+            reported_len: 0,
+            packets_written: 0,
+            fragments: 0,
+            trailer_written: false,
+            frag_duration: 60_000_000,
         })
     }
 
@@ -54,6 +135,32 @@ impl Fmp4Muxer {
         Ok(out_index)
     }
 
+    /// Add a passthrough video stream.
+    ///
+    /// Like `add_video_stream`, but Annex B packets are converted to
+    /// length-prefixed NAL units if the codec parameters carry an `avcC` or
+    /// `hvcC` record. With Annex B extradata the mp4 muxer converts them itself.
+    pub fn add_passthrough_video_stream(
+        &mut self,
+        params: &ffmpeg::codec::parameters::Parameters,
+        input_index: usize,
+        nal_format: crate::media::NalFormat,
+    ) -> Result<usize> {
+        let out_index = self.add_video_stream(params, input_index)?;
+        if nal_format == crate::media::NalFormat::AnnexB {
+            let extradata = crate::ffmpeg_utils::helpers::codec_params_extradata(params);
+            if let Some(size) = super::nal::nal_length_size(params.id(), &extradata) {
+                tracing::debug!(
+                    "Converting Annex B packets of stream {} to {}-byte NAL lengths",
+                    input_index,
+                    size
+                );
+                self.annexb_length_size.insert(out_index, size);
+            }
+        }
+        Ok(out_index)
+    }
+
     /// Add an audio stream
     pub fn add_audio_stream(
         &mut self,
@@ -88,19 +195,73 @@ impl Fmp4Muxer {
         Ok(out_index)
     }
 
+    /// Add a passthrough audio stream.
+    ///
+    /// Like `add_audio_stream`, but packets go through the bitstream filter
+    /// configured for the codec (see `ffmpeg_utils::bsf`), if any.
+    pub fn add_passthrough_audio_stream(
+        &mut self,
+        params: &ffmpeg::codec::parameters::Parameters,
+        input_index: usize,
+    ) -> Result<usize> {
+        let out_index = self.add_audio_stream(params, input_index)?;
+        // Packets are rescaled to the output time base before they are written.
+        let Some(time_base) = self.output.stream(out_index).map(|s| s.time_base()) else {
+            return Ok(out_index);
+        };
+        if let Some(filter) = BitstreamFilter::for_audio(params, time_base)? {
+            self.set_stream_parameters(out_index, &filter.output_parameters());
+            self.filters.insert(out_index, filter);
+        }
+        Ok(out_index)
+    }
+
+    /// Replace the codec parameters of an output stream (before the header is written).
+    fn set_stream_parameters(&mut self, out_index: usize, params: &ffmpeg::codec::Parameters) {
+        if let Some(mut out_stream) = self.output.stream_mut(out_index) {
+            out_stream.set_parameters(params.clone());
+            crate::ffmpeg_utils::helpers::stream_reset_codec_tag(&mut out_stream);
+        }
+    }
+
+    /// Run a copy of `packet` through the bitstream filter of its stream and
+    /// take over the output parameters, which may only be known now.
+    fn prime_filter(&mut self, packet: &ffmpeg::Packet) -> Result<()> {
+        let Some(&out_index) = self.stream_map.get(&packet.stream()) else {
+            return Ok(());
+        };
+        let Some(filter) = self.filters.get_mut(&out_index) else {
+            return Ok(());
+        };
+        let mut packet = packet.clone();
+        filter.filter(&mut packet)?;
+        let params = filter.output_parameters();
+        self.set_stream_parameters(out_index, &params);
+        Ok(())
+    }
+
+    /// Set the maximum fragment duration in microseconds.
+    ///
+    /// The default (60s) produces one fragment per segment. A shorter duration
+    /// makes the muxer flush fragments while the segment is still being
+    /// generated, which is what streaming output needs. Must be called before
+    /// `write_header`.
+    pub fn set_fragment_duration(&mut self, micros: i64) {
+        self.frag_duration = micros;
+    }
+
     /// Write output header (generates init.mp4)
     pub fn write_header(&mut self, delay_moov: bool) -> Result<Vec<u8>> {
         let mut opts = ffmpeg::Dictionary::new();
-        if delay_moov {
-            opts.set("movflags", "empty_moov+default_base_moof+delay_moov+negative_cts_offsets");
-        } else {
-            opts.set("movflags", "empty_moov+default_base_moof+negative_cts_offsets");
-        }
-        opts.set("avoid_negative_ts", "0");
+        opts.set("movflags", &movflags(delay_moov));
+        opts.set(
+            "avoid_negative_ts",
+            crate::segment::profile::negative_ts_policy().ffmpeg_option(),
+        );
         // Prevent the mp4 muxer from implicitly adding frag_keyframe (which
         // splits each segment into multiple moof/mdat fragments at every video
         // keyframe).  A large frag_duration ensures one fragment per segment.
-        opts.set("frag_duration", "60000000");
+        opts.set("frag_duration", &self.frag_duration.to_string());
 
         self.output
             .write_header_with(opts)
@@ -115,6 +276,8 @@ impl Fmp4Muxer {
         // with writer.position. If we clear here, FFmpeg's seeks to patch moof size
         // fields land at wrong offsets (init_size bytes past the actual target),
         // producing size=0 moof boxes that Chrome's MSE rejects.
+        self.header_len = self.writer.len();
+        self.reported_len = self.header_len;
         Ok(self.writer.data())
     }
 
@@ -129,15 +292,24 @@ impl Fmp4Muxer {
     where
         I: IntoIterator<Item = &'a mut ffmpeg::Packet>,
     {
-        let mut opts = ffmpeg::Dictionary::new();
-        if delay_moov {
-            opts.set("movflags", "empty_moov+default_base_moof+delay_moov+negative_cts_offsets");
-        } else {
-            // Even if caller said false, we might want it for consistency.
-            // But let's respect the flag for now but add CTTS v1.
-            opts.set("movflags", "empty_moov+default_base_moof+negative_cts_offsets");
+        // Filters like aac_adtstoasc only know the codec config after the
+        // first packet, and the moov needs it.
+        let packets: Vec<&mut ffmpeg::Packet> = packets.into_iter().collect();
+        let mut unprimed: Vec<usize> = self.filters.keys().copied().collect();
+        for packet in &packets {
+            let out_index = self.stream_map.get(&packet.stream()).copied();
+            if let Some(pos) = unprimed.iter().position(|&i| Some(i) == out_index) {
+                unprimed.remove(pos);
+                self.prime_filter(packet)?;
+            }
         }
-        opts.set("avoid_negative_ts", "0");
+
+        let mut opts = ffmpeg::Dictionary::new();
+        opts.set("movflags", &movflags(delay_moov));
+        opts.set(
+            "avoid_negative_ts",
+            crate::segment::profile::negative_ts_policy().ffmpeg_option(),
+        );
 
         self.output
             .write_header_with(opts)
@@ -163,6 +335,29 @@ impl Fmp4Muxer {
         let stream_index = packet.stream();
 
         if let Some(&out_index) = self.stream_map.get(&stream_index) {
+            if let Some(filter) = self.filters.get_mut(&out_index) {
+                for mut filtered in filter.filter(packet)? {
+                    filtered.set_stream(out_index);
+                    filtered.set_position(-1);
+                    self.write_interleaved(&mut filtered)?;
+                }
+                return Ok(());
+            }
+            if let Some(&size) = self.annexb_length_size.get(&out_index) {
+                let converted = packet
+                    .data()
+                    .and_then(|data| super::nal::annexb_to_length_prefixed(data, size));
+                if let Some(data) = converted {
+                    let mut converted = ffmpeg::Packet::copy(&data);
+                    converted.set_pts(packet.pts());
+                    converted.set_dts(packet.dts());
+                    converted.set_duration(packet.duration());
+                    converted.set_flags(packet.flags());
+                    converted.set_stream(stream_index);
+                    *packet = converted;
+                }
+            }
+
             packet.set_stream(out_index);
             packet.set_position(-1); // Unset byte position
 
@@ -177,18 +372,71 @@ impl Fmp4Muxer {
             // But we can't easily access output tb before header is written?
             // Actually 'mp4' usually sets tb based on stream.
 
-            packet
-                .write_interleaved(&mut self.output)
-                .map_err(|e| FfmpegError::WriteError(format!("Failed to write packet: {}", e)))?;
+            self.write_interleaved(packet)?;
         }
 
         Ok(())
     }
 
-    /// Flush and get the accumulated segment data
+    fn write_interleaved(&mut self, packet: &mut ffmpeg::Packet) -> Result<()> {
+        packet
+            .write_interleaved(&mut self.output)
+            .map_err(|e| FfmpegError::WriteError(format!("Failed to write packet: {}", e)))?;
+        self.packets_written += 1;
+        Ok(())
+    }
+
+    /// Report progress to `observer` for every fragment (`moof` and `mdat`)
+    /// the muxer has written completely since the last call.
+    pub fn notify_progress(&mut self, observer: Option<&dyn ProgressObserver>) {
+        let Some(observer) = observer else {
+            return;
+        };
+        // The muxer writes a fragment into the AVIO buffer, not to the output.
+        crate::ffmpeg_utils::helpers::flush_avio(&mut self.output);
+        while let Some(end) = self.writer.fragment_end(self.reported_len) {
+            self.reported_len = end;
+            self.fragments += 1;
+            observer.on_fragment(&SegmentProgress {
+                fragments: self.fragments,
+                packets_written: self.packets_written,
+                bytes_written: end.saturating_sub(self.header_len),
+            });
+        }
+    }
+
+    /// Return the media fragments that were completely written since the last call.
     ///
-    /// Should be called after writing all packets for a segment.
-    pub fn finalize(&mut self) -> Result<Vec<u8>> {
+    /// See `MemoryWriter::take_complete_boxes`.
+    pub fn take_fragments(&mut self) -> Option<Vec<u8>> {
+        crate::ffmpeg_utils::helpers::flush_avio(&mut self.output);
+        self.writer.take_complete_boxes()
+    }
+
+    /// Write the trailer, flushing the last fragment to the output buffer.
+    ///
+    /// Called by `finalize` if it wasn't called before.
+    pub fn write_trailer(&mut self) {
+        if self.trailer_written {
+            return;
+        }
+        self.trailer_written = true;
+
+        // Drain the bitstream filters.
+        let out_indexes: Vec<usize> = self.filters.keys().copied().collect();
+        for out_index in out_indexes {
+            let packets = match self.filters.get_mut(&out_index).map(|f| f.flush()) {
+                Some(Ok(packets)) => packets,
+                _ => continue,
+            };
+            for mut packet in packets {
+                packet.set_stream(out_index);
+                if let Err(e) = self.write_interleaved(&mut packet) {
+                    tracing::debug!("Failed to write filtered packet: {}", e);
+                }
+            }
+        }
+
         // Write trailer is NOT correct for fMP4 usually if we want just fragments?
         // But we need to flush any buffered data.
         // write_trailer() writes the index if not empty_moov, but with empty_moov it might just flush.
@@ -203,6 +451,13 @@ impl Fmp4Muxer {
                 e
             );
         }
+    }
+
+    /// Flush and get the accumulated segment data
+    ///
+    /// Should be called after writing all packets for a segment.
+    pub fn finalize(&mut self) -> Result<Vec<u8>> {
+        self.write_trailer();
 
         let data = self.writer.data();
         self.writer.clear();
@@ -239,15 +494,9 @@ pub fn parse_elst_media_time(data: &[u8]) -> Option<i64> {
 #[allow(dead_code)] // we need this for testing and development
 fn parse_elst_in_boxes(data: &[u8]) -> Option<i64> {
     let mut pos = 0;
-    while pos + 8 <= data.len() {
-        let size = u32::from_be_bytes(data[pos..pos + 4].try_into().ok()?) as usize;
-        let box_type = &data[pos + 4..pos + 8];
-        let size = if size == 0 { data.len() - pos } else { size };
-        if size < 8 || pos + size > data.len() {
-            break;
-        }
-        let content = &data[pos + 8..pos + size];
-        match box_type {
+    while let Some((header_len, size, box_type)) = box_header(data, pos) {
+        let content = &data[pos + header_len..pos + size];
+        match &box_type {
             b"moov" | b"trak" | b"edts" | b"mdia" | b"minf" | b"stbl" => {
                 if let Some(v) = parse_elst_in_boxes(content) {
                     return Some(v);
@@ -309,22 +558,12 @@ pub fn validate_fmp4(data: &[u8]) -> bool {
 pub fn find_box(data: &[u8], box_type: &[u8]) -> Option<usize> {
     let mut pos = 0;
     while pos + 8 <= data.len() {
-        let size =
-            u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
-
-        if size < 8 {
-            return None;
-        }
-
-        if pos + 8 > data.len() {
-            return None;
-        }
-
         let current_type = &data[pos + 4..pos + 8];
         if current_type == box_type {
             return Some(pos);
         }
 
+        let (_, size, _) = box_header(data, pos)?;
         pos += size;
     }
     None
@@ -334,8 +573,6 @@ pub fn find_box(data: &[u8], box_type: &[u8]) -> Option<usize> {
 pub fn find_media_segment_offset(data: &[u8]) -> Option<usize> {
     let mut pos = 0;
     while pos + 8 <= data.len() {
-        let size =
-            u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
         let type_bytes = &data[pos + 4..pos + 8];
 
         // If we encounter a box that signals start of media segment
@@ -343,18 +580,11 @@ pub fn find_media_segment_offset(data: &[u8]) -> Option<usize> {
             return Some(pos);
         }
 
-        // Safety check for size
-        if size < 8 {
-            // Invalid box size, stop here? Or return partial?
-            // Assuming init segment boxes are valid.
-            break;
-        }
-
-        let next_pos = pos + size;
-        if next_pos > data.len() {
+        // Invalid or truncated box (the init segment boxes are assumed to be valid).
+        let Some((_, size, _)) = box_header(data, pos) else {
             break;
-        }
-        pos = next_pos;
+        };
+        pos += size;
     }
     // If we didn't find specific media boxes, maybe we just return None (meaning no media segment found)
     None
@@ -365,6 +595,15 @@ mod tests {
     use super::*;
     use std::path::PathBuf;
 
+    #[test]
+    fn test_mux_options_from_str() {
+        assert_eq!("".parse::<MuxOptions>().unwrap(), MuxOptions::default());
+        let opts: MuxOptions = "no_delay_moov, no_styp".parse().unwrap();
+        assert_eq!(opts.delay_moov, Some(false));
+        assert!(!opts.styp);
+        assert!("fast".parse::<MuxOptions>().is_err());
+    }
+
     #[test]
     fn test_muxer_integration() {
         println!("Starting test_muxer_integration");
@@ -513,6 +752,26 @@ mod tests {
         assert!(validate_fmp4(&data));
     }
 
+    #[test]
+    fn test_find_box_largesize() {
+        // ftyp, a moov with a 64-bit largesize, then a moof.
+        let mut data = vec![0, 0, 0, 16, b'f', b't', b'y', b'p'];
+        data.extend_from_slice(b"iso6\0\0\0\0");
+        data.extend_from_slice(&[0, 0, 0, 1, b'm', b'o', b'o', b'v']);
+        data.extend_from_slice(&24u64.to_be_bytes());
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&[0, 0, 0, 8, b'm', b'o', b'o', b'f']);
+
+        assert_eq!(find_box(&data, b"moov"), Some(16));
+        assert_eq!(find_box(&data, b"moof"), Some(40));
+        assert_eq!(find_media_segment_offset(&data), Some(40));
+        assert_eq!(find_box(&data, b"mdat"), None);
+
+        // A largesize that points past the end stops the walk.
+        data[27] = 255;
+        assert_eq!(find_box(&data, b"moof"), None);
+    }
+
     #[test]
     fn test_mux_ac3_header() {
         ffmpeg::init().unwrap();
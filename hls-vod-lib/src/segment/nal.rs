@@ -0,0 +1,233 @@
+//! H.264/H.265 NAL unit framing
+//!
+//! MP4 (and most MKV) files store NAL units with a big-endian length prefix,
+//! whose size is declared in the `avcC`/`hvcC` decoder configuration.
+//! MPEG-TS and some MKV muxers deliver Annex B instead, where NAL units are
+//! separated by `00 00 01` or `00 00 00 01` start codes.
+//!
+//! fMP4 samples must be length-prefixed. When the extradata is Annex B as
+//! well, the FFmpeg mp4 muxer converts both itself. When the extradata is an
+//! `avcC`/`hvcC` record but the packets carry start codes, FFmpeg copies them
+//! as-is, and players fail to decode the track. Those packets are converted
+//! here.
+
+use ffmpeg_next as ffmpeg;
+
+use crate::media::NalFormat;
+
+/// Whether `data` starts with an Annex B start code.
+pub fn is_annexb(data: &[u8]) -> bool {
+    data.starts_with(&[0, 0, 1]) || data.starts_with(&[0, 0, 0, 1])
+}
+
+/// The NAL length prefix size declared in an `avcC` or `hvcC` record.
+///
+/// Returns `None` for Annex B or missing extradata.
+pub fn nal_length_size(codec_id: ffmpeg::codec::Id, extradata: &[u8]) -> Option<usize> {
+    if extradata.first() != Some(&1) {
+        return None;
+    }
+    // lengthSizeMinusOne is in the low two bits of byte 4 (avcC) or 21 (hvcC).
+    let offset = match codec_id {
+        ffmpeg::codec::Id::H264 => 4,
+        ffmpeg::codec::Id::HEVC => 21,
+        _ => return None,
+    };
+    let size = (extradata.get(offset)? & 0x03) as usize + 1;
+    // A length size of 3 is reserved.
+    (size != 3).then_some(size)
+}
+
+/// Split Annex B data into NAL units, without start codes.
+fn split_annexb(data: &[u8]) -> Vec<&[u8]> {
+    let mut nals = Vec::new();
+    let mut start = None;
+    let mut i = 0;
+    while i + 2 < data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            if let Some(s) = start {
+                nals.push(trim_trailing_zeros(&data[s..i]));
+            }
+            i += 3;
+            start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+    if let Some(s) = start {
+        nals.push(trim_trailing_zeros(&data[s..]));
+    }
+    nals.retain(|nal| !nal.is_empty());
+    nals
+}
+
+/// Strip the zero bytes that belong to the next (4-byte) start code, or
+/// that are `trailing_zero_8bits`. A NAL unit never ends in a zero byte.
+fn trim_trailing_zeros(nal: &[u8]) -> &[u8] {
+    let end = nal.iter().rposition(|&b| b != 0).map_or(0, |p| p + 1);
+    &nal[..end]
+}
+
+/// Convert an Annex B packet to length-prefixed NAL units.
+///
+/// The packet is not checked for a leading start code: the framing of a
+/// stream is detected once, at scan time (`VideoStreamInfo::nal_format`).
+/// Returns `None` if the data has no start code at all, or if a NAL unit
+/// does not fit in a `length_size` byte prefix.
+pub fn annexb_to_length_prefixed(data: &[u8], length_size: usize) -> Option<Vec<u8>> {
+    if !(1..=4).contains(&length_size) {
+        return None;
+    }
+    let nals = split_annexb(data);
+    if nals.is_empty() {
+        return None;
+    }
+    let mut out = Vec::with_capacity(data.len() + nals.len());
+    for nal in nals {
+        let len = nal.len() as u64;
+        if len >> (8 * length_size) != 0 {
+            return None;
+        }
+        out.extend_from_slice(&len.to_be_bytes()[8 - length_size..]);
+        out.extend_from_slice(nal);
+    }
+    Some(out)
+}
+
+/// Split length-prefixed data into NAL units.
+///
+/// Stops at a NAL unit that runs past the end of `data`.
+fn split_length_prefixed(data: &[u8], length_size: usize) -> Vec<&[u8]> {
+    let mut nals = Vec::new();
+    let mut rest = data;
+    while rest.len() >= length_size {
+        let len = rest[..length_size]
+            .iter()
+            .fold(0usize, |len, &b| len << 8 | b as usize);
+        let Some(nal) = rest.get(length_size..length_size + len) else {
+            break;
+        };
+        if !nal.is_empty() {
+            nals.push(nal);
+        }
+        rest = &rest[length_size + len..];
+    }
+    nals
+}
+
+/// Whether the packet `data` holds an IDR picture (H.264) or an IRAP
+/// picture (HEVC), which decodes without earlier pictures.
+///
+/// `nal_format` is the framing of the stream; `length_size` is the NAL
+/// length prefix size of length-prefixed streams, see `nal_length_size`.
+/// Returns `None` for other codecs.
+pub fn is_random_access(
+    codec_id: ffmpeg::codec::Id,
+    data: &[u8],
+    nal_format: NalFormat,
+    length_size: Option<usize>,
+) -> Option<bool> {
+    let nals = match nal_format {
+        NalFormat::AnnexB => split_annexb(data),
+        NalFormat::LengthPrefixed => split_length_prefixed(data, length_size.unwrap_or(4)),
+    };
+    let random_access = match codec_id {
+        // nal_unit_type 5: coded slice of an IDR picture.
+        ffmpeg::codec::Id::H264 => nals.iter().any(|nal| nal[0] & 0x1f == 5),
+        // nal_unit_type 16..=21: BLA, IDR and CRA pictures.
+        ffmpeg::codec::Id::HEVC => nals
+            .iter()
+            .any(|nal| (16..=21).contains(&((nal[0] >> 1) & 0x3f))),
+        _ => return None,
+    };
+    Some(random_access)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_annexb() {
+        assert!(is_annexb(&[0, 0, 1, 0x65]));
+        assert!(is_annexb(&[0, 0, 0, 1, 0x65]));
+        assert!(!is_annexb(&[0, 0, 0, 2, 0x65, 0x88]));
+        assert!(!is_annexb(&[]));
+    }
+
+    #[test]
+    fn test_nal_length_size() {
+        let avcc = [1, 0x64, 0x00, 0x28, 0xff, 0xe1];
+        assert_eq!(nal_length_size(ffmpeg::codec::Id::H264, &avcc), Some(4));
+        let avcc = [1, 0x64, 0x00, 0x28, 0xfd, 0xe1];
+        assert_eq!(nal_length_size(ffmpeg::codec::Id::H264, &avcc), Some(2));
+        let mut hvcc = [0u8; 23];
+        hvcc[0] = 1;
+        hvcc[21] = 0x0f;
+        assert_eq!(nal_length_size(ffmpeg::codec::Id::HEVC, &hvcc), Some(4));
+        assert_eq!(
+            nal_length_size(ffmpeg::codec::Id::H264, &[0, 0, 0, 1, 0x67]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_annexb_to_length_prefixed() {
+        // AUD with a 4-byte start code, then an IDR slice with a 3-byte one.
+        let data = [0, 0, 0, 1, 0x09, 0xf0, 0, 0, 1, 0x65, 0x88, 0x84, 0x00];
+        assert_eq!(
+            annexb_to_length_prefixed(&data, 4).unwrap(),
+            vec![0, 0, 0, 2, 0x09, 0xf0, 0, 0, 0, 3, 0x65, 0x88, 0x84]
+        );
+        assert_eq!(
+            annexb_to_length_prefixed(&data, 2).unwrap(),
+            vec![0, 2, 0x09, 0xf0, 0, 3, 0x65, 0x88, 0x84]
+        );
+        // No start code.
+        assert_eq!(
+            annexb_to_length_prefixed(&[0, 0, 0, 2, 0x09, 0xf0], 4),
+            None
+        );
+        // A 300 byte NAL unit does not fit a one byte length.
+        let mut big = vec![0, 0, 1, 0x65];
+        big.resize(304, 0x55);
+        assert_eq!(annexb_to_length_prefixed(&big, 1), None);
+    }
+
+    #[test]
+    fn test_is_random_access() {
+        use ffmpeg::codec::Id;
+        use NalFormat::{AnnexB, LengthPrefixed};
+
+        // SEI, then an IDR slice.
+        let idr = [0, 0, 0, 2, 0x06, 0x05, 0, 0, 0, 2, 0x65, 0x88];
+        assert_eq!(
+            is_random_access(Id::H264, &idr, LengthPrefixed, Some(4)),
+            Some(true)
+        );
+        // A non-IDR slice, with 2-byte length prefixes.
+        let slice = [0, 2, 0x41, 0x9a];
+        assert_eq!(
+            is_random_access(Id::H264, &slice, LengthPrefixed, Some(2)),
+            Some(false)
+        );
+        // Annex B.
+        let idr = [0, 0, 0, 1, 0x09, 0xf0, 0, 0, 1, 0x65, 0x88, 0x84];
+        assert_eq!(is_random_access(Id::H264, &idr, AnnexB, None), Some(true));
+        // HEVC IDR_W_RADL and TRAIL_R.
+        assert_eq!(
+            is_random_access(Id::HEVC, &[0, 0, 0, 2, 0x26, 0x01], LengthPrefixed, None),
+            Some(true)
+        );
+        assert_eq!(
+            is_random_access(Id::HEVC, &[0, 0, 0, 2, 0x02, 0x01], LengthPrefixed, None),
+            Some(false)
+        );
+        // A truncated NAL unit.
+        assert_eq!(
+            is_random_access(Id::H264, &[0, 0, 0, 9, 0x65], LengthPrefixed, None),
+            Some(false)
+        );
+        assert_eq!(is_random_access(Id::MPEG4, &idr, AnnexB, None), None);
+    }
+}
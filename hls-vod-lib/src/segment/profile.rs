@@ -0,0 +1,257 @@
+//! Compatibility profiles
+//!
+//! Players differ in which brands and box versions they accept. A profile
+//! selects the `ftyp` brands of init segments, the `styp` brands of media
+//! segments, the `tfdt` and `trun` versions, the handling of negative
+//! timestamps, and the `EXT-X-VERSION` of the playlists. It is set once at
+//! startup with `set_compatibility_profile()`.
+//!
+//! The negative timestamp policy of the profile can be overridden with
+//! `set_negative_ts_policy()`.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::segment::isobmff::brand_box;
+
+/// Set of brands and box versions that segments and playlists are written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CompatibilityProfile {
+    /// What Apple devices and hls.js expect: the `ftyp` written by FFmpeg,
+    /// `styp` `iso8`/`cmfc`, 64-bit `tfdt`s, negative composition offsets
+    /// (`trun` version 1), and `EXT-X-VERSION:7`.
+    #[default]
+    Apple,
+    /// Strict CMAF: `ftyp` `cmfc`, `styp` `cmfs`, otherwise like `Apple`.
+    CmafStrict,
+    /// Older smart TVs and set-top boxes: `ftyp` `iso5` without `iso6`,
+    /// `styp` `msdh`, 32-bit `tfdt`s where the time fits, only positive
+    /// composition offsets (`trun` version 0), and `EXT-X-VERSION:6`.
+    Legacy,
+}
+
+/// What is done with a title whose decode timestamps start below zero, like
+/// FFmpeg's `avoid_negative_ts` muxer option.
+///
+/// Segments are muxed one at a time, so the shift FFmpeg applies to a segment
+/// is undone by the `tfdt` patching; the shift of the whole title is taken
+/// from the lowest decode timestamp of its video, recorded when it is indexed,
+/// and applied to the `tfdt` of every track (and the `X-TIMESTAMP-MAP` of
+/// subtitles) the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NegativeTsPolicy {
+    /// Keep the timestamps; negative decode times are written as 0.
+    Disabled,
+    /// Shift the title so that it starts at 0 if it starts below 0.
+    MakeNonNegative,
+    /// Shift the title so that it starts at 0.
+    MakeZero,
+}
+
+impl NegativeTsPolicy {
+    /// The value of the `avoid_negative_ts` muxer option.
+    pub(crate) fn ffmpeg_option(self) -> &'static str {
+        match self {
+            NegativeTsPolicy::Disabled => "disabled",
+            NegativeTsPolicy::MakeNonNegative => "make_non_negative",
+            NegativeTsPolicy::MakeZero => "make_zero",
+        }
+    }
+
+    /// How much the timeline of a title whose first decode timestamp is
+    /// `first_dts` is shifted, in the same timebase.
+    pub(crate) fn shift(self, first_dts: i64) -> i64 {
+        match self {
+            NegativeTsPolicy::Disabled => 0,
+            NegativeTsPolicy::MakeNonNegative => (-first_dts).max(0),
+            NegativeTsPolicy::MakeZero => -first_dts,
+        }
+    }
+}
+
+static PROFILE: AtomicU8 = AtomicU8::new(CompatibilityProfile::Apple as u8);
+
+// 0 is the policy of the profile, otherwise `NegativeTsPolicy` plus 1.
+static NEGATIVE_TS: AtomicU8 = AtomicU8::new(0);
+
+// Tests that need another profile or policy set it for their own thread
+// only, so the tests running next to them keep the default.
+#[cfg(test)]
+thread_local! {
+    static THREAD_PROFILE: std::cell::Cell<Option<CompatibilityProfile>> =
+        const { std::cell::Cell::new(None) };
+    static THREAD_NEGATIVE_TS: std::cell::Cell<Option<NegativeTsPolicy>> =
+        const { std::cell::Cell::new(None) };
+}
+
+/// Override the compatibility profile on the current thread.
+#[cfg(test)]
+pub(crate) fn set_thread_compatibility_profile(profile: Option<CompatibilityProfile>) {
+    THREAD_PROFILE.with(|p| p.set(profile));
+}
+
+/// Override the negative timestamp policy on the current thread.
+#[cfg(test)]
+pub(crate) fn set_thread_negative_ts_policy(policy: Option<NegativeTsPolicy>) {
+    THREAD_NEGATIVE_TS.with(|p| p.set(policy));
+}
+
+/// Set the compatibility profile of all segments and playlists generated
+/// after this call. The default is `CompatibilityProfile::Apple`.
+pub fn set_compatibility_profile(profile: CompatibilityProfile) {
+    PROFILE.store(profile as u8, Ordering::Relaxed);
+}
+
+/// The compatibility profile.
+pub fn compatibility_profile() -> CompatibilityProfile {
+    #[cfg(test)]
+    if let Some(profile) = THREAD_PROFILE.with(|p| p.get()) {
+        return profile;
+    }
+    match PROFILE.load(Ordering::Relaxed) {
+        1 => CompatibilityProfile::CmafStrict,
+        2 => CompatibilityProfile::Legacy,
+        _ => CompatibilityProfile::Apple,
+    }
+}
+
+/// Override the negative timestamp policy of the compatibility profile,
+/// or go back to it with `None`.
+pub fn set_negative_ts_policy(policy: Option<NegativeTsPolicy>) {
+    NEGATIVE_TS.store(policy.map_or(0, |p| p as u8 + 1), Ordering::Relaxed);
+}
+
+/// The negative timestamp policy: the one set with `set_negative_ts_policy()`,
+/// or the one of the compatibility profile.
+pub fn negative_ts_policy() -> NegativeTsPolicy {
+    #[cfg(test)]
+    if let Some(policy) = THREAD_NEGATIVE_TS.with(|p| p.get()) {
+        return policy;
+    }
+    match NEGATIVE_TS.load(Ordering::Relaxed) {
+        1 => NegativeTsPolicy::Disabled,
+        2 => NegativeTsPolicy::MakeNonNegative,
+        3 => NegativeTsPolicy::MakeZero,
+        _ => compatibility_profile().negative_ts(),
+    }
+}
+
+impl CompatibilityProfile {
+    /// The `ftyp` box of init segments, or `None` to keep the one FFmpeg writes.
+    pub fn ftyp(self) -> Option<Vec<u8>> {
+        match self {
+            CompatibilityProfile::Apple => None,
+            CompatibilityProfile::CmafStrict => {
+                Some(brand_box(b"ftyp", b"cmfc", 0, &[b"iso6", b"cmfc"]))
+            }
+            CompatibilityProfile::Legacy => Some(brand_box(
+                b"ftyp",
+                b"iso5",
+                512,
+                &[b"iso5", b"dash", b"mp41"],
+            )),
+        }
+    }
+
+    /// The `styp` box prepended to media segments.
+    pub fn styp(self) -> Vec<u8> {
+        match self {
+            CompatibilityProfile::Apple => brand_box(b"styp", b"iso8", 512, &[b"iso8", b"cmfc"]),
+            CompatibilityProfile::CmafStrict => {
+                brand_box(b"styp", b"cmfs", 0, &[b"cmfs", b"msdh", b"msix"])
+            }
+            CompatibilityProfile::Legacy => brand_box(b"styp", b"msdh", 0, &[b"msdh", b"msix"]),
+        }
+    }
+
+    /// Version of the `tfdt` boxes: 1 is 64-bit, 0 is 32-bit where the
+    /// decode time fits.
+    pub fn tfdt_version(self) -> u8 {
+        match self {
+            CompatibilityProfile::Legacy => 0,
+            _ => 1,
+        }
+    }
+
+    /// Whether `trun`s may have negative composition offsets (version 1).
+    /// Without them, FFmpeg shifts the composition times to be positive.
+    pub fn negative_cts_offsets(self) -> bool {
+        self != CompatibilityProfile::Legacy
+    }
+
+    /// What is done with negative decode timestamps. Older devices reject
+    /// segments of titles that start below zero, which are written with a
+    /// `tfdt` of 0 and overlap the next segment.
+    pub fn negative_ts(self) -> NegativeTsPolicy {
+        match self {
+            CompatibilityProfile::Legacy => NegativeTsPolicy::MakeNonNegative,
+            _ => NegativeTsPolicy::Disabled,
+        }
+    }
+
+    /// The `EXT-X-VERSION` of the playlists. Media playlists with tags of a
+    /// later version (`EXT-X-GAP`, `EXT-X-SKIP`) get that version instead.
+    pub fn hls_version(self) -> u32 {
+        match self {
+            CompatibilityProfile::Legacy => 6,
+            _ => 7,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_serde() {
+        let profile: CompatibilityProfile = serde_json::from_str(r#""cmaf-strict""#).unwrap();
+        assert_eq!(profile, CompatibilityProfile::CmafStrict);
+        assert_eq!(
+            serde_json::to_string(&CompatibilityProfile::Legacy).unwrap(),
+            r#""legacy""#
+        );
+    }
+
+    #[test]
+    fn test_apple_styp() {
+        // The styp that was always written before profiles existed.
+        let styp = CompatibilityProfile::Apple.styp();
+        assert_eq!(&styp[..8], &[0, 0, 0, 24, b's', b't', b'y', b'p']);
+        assert_eq!(&styp[8..], b"iso8\0\0\x02\0iso8cmfc");
+        assert!(CompatibilityProfile::Apple.ftyp().is_none());
+    }
+
+    #[test]
+    fn test_profile_settings() {
+        let legacy = CompatibilityProfile::Legacy;
+        assert_eq!(legacy.tfdt_version(), 0);
+        assert!(!legacy.negative_cts_offsets());
+        assert_eq!(legacy.hls_version(), 6);
+        let ftyp = legacy.ftyp().unwrap();
+        assert_eq!(&ftyp[4..8], b"ftyp");
+        assert!(!ftyp.windows(4).any(|w| w == b"iso6"));
+
+        let cmaf = CompatibilityProfile::CmafStrict;
+        assert_eq!(cmaf.hls_version(), 7);
+        assert_eq!(&cmaf.styp()[8..12], b"cmfs");
+    }
+
+    #[test]
+    fn test_negative_ts_policy() {
+        assert_eq!(NegativeTsPolicy::Disabled.shift(-2002), 0);
+        assert_eq!(NegativeTsPolicy::MakeNonNegative.shift(-2002), 2002);
+        assert_eq!(NegativeTsPolicy::MakeNonNegative.shift(1001), 0);
+        assert_eq!(NegativeTsPolicy::MakeZero.shift(1001), -1001);
+        assert_eq!(
+            CompatibilityProfile::Legacy.negative_ts(),
+            NegativeTsPolicy::MakeNonNegative
+        );
+
+        let policy: NegativeTsPolicy = serde_json::from_str(r#""make_zero""#).unwrap();
+        assert_eq!(policy.ffmpeg_option(), "make_zero");
+    }
+}
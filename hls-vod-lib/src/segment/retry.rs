@@ -0,0 +1,354 @@
+//! Retry and fallback for media segment generation
+//!
+//! A failed segment normally ends up as an HTTP 500 and a stalled player.
+//! Many failures are transient though (short reads on network storage, an
+//! NFS hiccup), and some are specific to audio passthrough. Errors are
+//! classified first, so that deterministic failures are not retried:
+//!
+//! 1. transient errors are retried with a freshly opened input,
+//! 2. muxing errors with passthrough audio fall back to transcoding to AAC
+//!    (if enabled, see [`RetryPolicy::transcode_fallback`]),
+//! 3. segments that still fail are marked as gaps, and later playlists
+//!    carry `EXT-X-GAP` for them so players skip instead of stalling.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{FfmpegError, HlsError, Result};
+use crate::hlsvideo::{ProgressObserver, SegmentProgress};
+use crate::media::StreamIndex;
+
+/// Retry policy for media segment generation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Number of retries after a transient error (default 2)
+    pub max_retries: u32,
+    /// Delay before each retry, in milliseconds (default 200)
+    pub retry_delay_ms: u64,
+    /// Transcode passthrough audio to AAC if muxing it fails (default off).
+    ///
+    /// The segment then no longer matches the init segment, so this only
+    /// helps players that re-read the codec configuration, like hls.js.
+    pub transcode_fallback: bool,
+    /// Mark segments that keep failing as `EXT-X-GAP` in playlists generated
+    /// afterwards (default on)
+    pub gap_fallback: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 2,
+            retry_delay_ms: 200,
+            transcode_fallback: false,
+            gap_fallback: true,
+        }
+    }
+}
+
+static RETRY_POLICY: RwLock<Option<RetryPolicy>> = RwLock::new(None);
+
+/// Set the retry policy for media segment generation.
+pub fn set_retry_policy(policy: RetryPolicy) {
+    *RETRY_POLICY.write().unwrap_or_else(|e| e.into_inner()) = Some(policy);
+}
+
+fn retry_policy() -> RetryPolicy {
+    RETRY_POLICY
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .unwrap_or_default()
+}
+
+/// How a segment generation error should be handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorClass {
+    /// Reading the source failed; a retry may succeed
+    Transient,
+    /// The muxer rejected the packets; transcoding may help
+    Mux,
+    /// A retry gives the same result
+    Permanent,
+}
+
+pub(crate) fn classify(err: &HlsError) -> ErrorClass {
+    use std::io::ErrorKind;
+    match err {
+        HlsError::Io(e) => match e.kind() {
+            ErrorKind::NotFound
+            | ErrorKind::PermissionDenied
+            | ErrorKind::InvalidInput
+            | ErrorKind::InvalidData
+            | ErrorKind::Unsupported => ErrorClass::Permanent,
+            _ => ErrorClass::Transient,
+        },
+        HlsError::Ffmpeg(e) | HlsError::FfmpegContext { source: e, .. } => match e {
+            FfmpegError::OpenInput(_) | FfmpegError::ReadFrame(_) => ErrorClass::Transient,
+            FfmpegError::MuxerCreate(_)
+            | FfmpegError::StreamConfig(_)
+            | FfmpegError::WriteHeader(_)
+            | FfmpegError::WritePacket(_)
+            | FfmpegError::WriteTrailer(_)
+            | FfmpegError::WriteError(_) => ErrorClass::Mux,
+            _ => ErrorClass::Permanent,
+        },
+        HlsError::Muxing(_) => ErrorClass::Mux,
+        _ => ErrorClass::Permanent,
+    }
+}
+
+/// How to generate a media segment on this attempt.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Attempt {
+    /// Open the source again instead of using a cached or shared input
+    pub fresh_input: bool,
+    /// Transcode the audio track to AAC
+    pub transcode_audio: bool,
+    /// Bitrate of the transcoded audio, `None` for the configured one
+    pub aac_bitrate: Option<u64>,
+    /// Audio track mixed into the transcoded one, for an audio mix
+    pub mix_track: Option<usize>,
+    /// Muxer settings, normally the defaults
+    pub mux: crate::segment::muxer::MuxOptions,
+}
+
+/// Forwards to the caller's observer, and records whether any segment data
+/// has been sent to it.
+pub(crate) struct SentTracker<'a> {
+    inner: &'a dyn ProgressObserver,
+    sent_any: AtomicBool,
+}
+
+impl<'a> SentTracker<'a> {
+    pub(crate) fn new(inner: &'a dyn ProgressObserver) -> Self {
+        Self {
+            inner,
+            sent_any: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether `on_data` was called.
+    pub(crate) fn sent_any(&self) -> bool {
+        self.sent_any.load(Ordering::Relaxed)
+    }
+}
+
+impl ProgressObserver for SentTracker<'_> {
+    fn on_fragment(&self, progress: &SegmentProgress) {
+        self.inner.on_fragment(progress);
+    }
+
+    fn wants_data(&self) -> bool {
+        self.inner.wants_data()
+    }
+
+    fn on_data(&self, chunk: Bytes) {
+        self.sent_any.store(true, Ordering::Relaxed);
+        self.inner.on_data(chunk);
+    }
+}
+
+/// Generate media segment `sequence` with `generate`, applying the policy.
+///
+/// `attempt` is the first attempt. `has_audio` says whether the segment
+/// has an audio track, which could be transcoded as a fallback. `generate`
+/// gets the observer to report `progress` to. Once data has been streamed
+/// to it, the response is under way, so nothing is retried.
+pub(crate) fn generate_with_retry<F>(
+    index: &StreamIndex,
+    sequence: usize,
+    mut attempt: Attempt,
+    has_audio: bool,
+    progress: Option<&dyn ProgressObserver>,
+    mut generate: F,
+) -> Result<Bytes>
+where
+    F: FnMut(Attempt, Option<&dyn ProgressObserver>) -> Result<Bytes>,
+{
+    let policy = retry_policy();
+    let tracker = progress.map(SentTracker::new);
+    let observer = tracker.as_ref().map(|t| t as &dyn ProgressObserver);
+    let sent_any = || tracker.as_ref().is_some_and(|t| t.sent_any());
+    let mut retries = 0;
+    loop {
+        let err = match generate(attempt, observer) {
+            Ok(data) => return Ok(data),
+            Err(err) => err,
+        };
+        let class = classify(&err);
+        if sent_any() || class == ErrorClass::Permanent {
+            return Err(err);
+        }
+        if class == ErrorClass::Transient && retries < policy.max_retries {
+            retries += 1;
+            tracing::warn!(
+                "segment {}: {}, retrying ({}/{})",
+                sequence,
+                err,
+                retries,
+                policy.max_retries
+            );
+            std::thread::sleep(Duration::from_millis(policy.retry_delay_ms));
+            attempt.fresh_input = true;
+            continue;
+        }
+        if policy.transcode_fallback
+            && cfg!(feature = "transcode")
+            && has_audio
+            && !attempt.transcode_audio
+        {
+            tracing::warn!(
+                "segment {}: {}, falling back to transcoding the audio",
+                sequence,
+                err
+            );
+            attempt.transcode_audio = true;
+            attempt.fresh_input = true;
+            continue;
+        }
+        if policy.gap_fallback {
+            tracing::warn!("segment {}: {}, marking it as a gap", sequence, err);
+            index.mark_gap(sequence);
+        }
+        return Err(err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let io = |kind| HlsError::Io(std::io::Error::from(kind));
+        assert_eq!(
+            classify(&io(std::io::ErrorKind::UnexpectedEof)),
+            ErrorClass::Transient
+        );
+        assert_eq!(
+            classify(&io(std::io::ErrorKind::NotFound)),
+            ErrorClass::Permanent
+        );
+        assert_eq!(
+            classify(&FfmpegError::ReadFrame("EIO".to_string()).into()),
+            ErrorClass::Transient
+        );
+        assert_eq!(
+            classify(&FfmpegError::WriteError("EINVAL".to_string()).into()),
+            ErrorClass::Mux
+        );
+        assert_eq!(
+            classify(&HlsError::InvalidCodec("dts".to_string())),
+            ErrorClass::Permanent
+        );
+    }
+
+    #[test]
+    fn test_retry_transient() {
+        let index = StreamIndex::new("/test/retry.mp4".into());
+        let mut attempts = Vec::new();
+        let result = generate_with_retry(&index, 3, Attempt::default(), false, None, |a, _| {
+            attempts.push(a.fresh_input);
+            if attempts.len() < 2 {
+                Err(FfmpegError::ReadFrame("short read".to_string()).into())
+            } else {
+                Ok(Bytes::from_static(b"moof"))
+            }
+        });
+        assert_eq!(result.unwrap(), Bytes::from_static(b"moof"));
+        assert_eq!(attempts, vec![false, true]);
+        assert!(!index.is_gap(3));
+    }
+
+    #[test]
+    fn test_no_retry_permanent() {
+        let index = StreamIndex::new("/test/retry.mp4".into());
+        let mut calls = 0;
+        let result = generate_with_retry(&index, 3, Attempt::default(), true, None, |_, _| {
+            calls += 1;
+            Err(HlsError::NoSupportedAudio)
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+        assert!(!index.is_gap(3));
+    }
+
+    #[test]
+    fn test_gap_after_mux_error() {
+        let index = StreamIndex::new("/test/retry.mp4".into());
+        let mut calls = 0;
+        let result = generate_with_retry(&index, 5, Attempt::default(), false, None, |_, _| {
+            calls += 1;
+            Err(HlsError::Muxing("bad packet".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+        assert!(index.is_gap(5));
+        assert!(!index.is_gap(4));
+    }
+
+    struct Streamer(std::sync::Mutex<Vec<Bytes>>);
+
+    impl ProgressObserver for Streamer {
+        fn on_fragment(&self, _: &SegmentProgress) {}
+        fn wants_data(&self) -> bool {
+            true
+        }
+        fn on_data(&self, chunk: Bytes) {
+            self.0.lock().unwrap().push(chunk);
+        }
+    }
+
+    #[test]
+    fn test_retry_streaming() {
+        // Nothing was sent yet, so a streamed segment is retried.
+        let index = StreamIndex::new("/test/retry.mp4".into());
+        let streamer = Streamer(std::sync::Mutex::new(Vec::new()));
+        let mut calls = 0;
+        let result = generate_with_retry(
+            &index,
+            3,
+            Attempt::default(),
+            false,
+            Some(&streamer),
+            |_, progress| {
+                calls += 1;
+                if calls < 2 {
+                    return Err(FfmpegError::ReadFrame("short read".to_string()).into());
+                }
+                let progress = progress.unwrap();
+                assert!(progress.wants_data());
+                progress.on_data(Bytes::from_static(b"moof"));
+                Ok(Bytes::from_static(b"moof"))
+            },
+        );
+        assert_eq!(result.unwrap(), Bytes::from_static(b"moof"));
+        assert_eq!(calls, 2);
+        assert_eq!(streamer.0.lock().unwrap().len(), 1);
+
+        // Once a chunk went out, the error is returned as is.
+        let mut calls = 0;
+        let result = generate_with_retry(
+            &index,
+            4,
+            Attempt::default(),
+            false,
+            Some(&streamer),
+            |_, progress| {
+                calls += 1;
+                progress.unwrap().on_data(Bytes::from_static(b"moof"));
+                Err(FfmpegError::ReadFrame("short read".to_string()).into())
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+        assert!(!index.is_gap(4));
+    }
+}
@@ -0,0 +1,120 @@
+//! Media files in memory.
+//!
+//! Media is read from files, unless a file was added in memory with
+//! `add_memory_source()`, for example one fetched from a blob store. It is
+//! then read through a custom `AVIOContext`, by the scanner as well as by
+//! the segment generator, and addressed by its path as if it were a file.
+//! Tests and fuzzers can run the whole pipeline on synthesized media this
+//! way, without temporary files.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use dashmap::DashMap;
+use ffmpeg_next as ffmpeg;
+
+use crate::error::FfmpegError;
+use crate::ffmpeg_utils::io::{open_memory_input, InputContext};
+
+struct MemorySource {
+    data: Arc<[u8]>,
+    /// Changes when other data is added at the same path
+    generation: u64,
+}
+
+static SOURCES: OnceLock<DashMap<PathBuf, MemorySource>> = OnceLock::new();
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+fn sources() -> &'static DashMap<PathBuf, MemorySource> {
+    SOURCES.get_or_init(DashMap::new)
+}
+
+/// Add a media file held in memory at `path`, which does not have to exist
+/// on disk. Replaces an earlier file at the same path, and removes its
+/// streams.
+pub fn add_memory_source(path: impl Into<PathBuf>, data: impl Into<Arc<[u8]>>) {
+    let path = path.into();
+    let source = MemorySource {
+        data: data.into(),
+        generation: GENERATION.fetch_add(1, Ordering::Relaxed) + 1,
+    };
+    if sources().insert(path.clone(), source).is_some() {
+        crate::cache::remove_streams_by_path(&path);
+    }
+}
+
+/// Remove the media file in memory at `path`, and its streams.
+/// Returns `false` if there is none.
+pub fn remove_memory_source(path: &Path) -> bool {
+    if sources().remove(path).is_none() {
+        return false;
+    }
+    crate::cache::remove_streams_by_path(path);
+    true
+}
+
+/// Whether `path` is a media file in memory.
+pub fn is_memory_source(path: &Path) -> bool {
+    sources().contains_key(path)
+}
+
+/// The size and generation of the media file in memory at `path`.
+pub(crate) fn memory_source_version(path: &Path) -> Option<(usize, u64)> {
+    sources().get(path).map(|s| (s.data.len(), s.generation))
+}
+
+/// Open `path` for reading: the media file in memory, or else the file.
+pub(crate) fn open_input(path: &Path) -> Result<InputContext, FfmpegError> {
+    let data = sources().get(path).map(|s| s.data.clone());
+    match data {
+        Some(data) => open_memory_input(data, &path.to_string_lossy()),
+        None => ffmpeg::format::input(&path)
+            .map(InputContext::from)
+            .map_err(|e| FfmpegError::OpenInput(format!("Failed to open {:?}: {}", path, e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hlsvideo::HlsVideo;
+    use crate::params::HlsParams;
+    use crate::tests::fixtures::generate::{generate, FixtureSpec};
+
+    // The first video segment of a new stream of `path`.
+    fn first_video_segment(path: &Path) -> Vec<u8> {
+        let main = HlsParams::main_playlist(path.to_string_lossy());
+        let HlsVideo::MainPlaylist(p) = HlsVideo::open(path, main.clone()).unwrap() else {
+            panic!("not a main playlist");
+        };
+        let track_id = p.index.primary_video().unwrap().stream_index;
+        let params = main.session(p.index.stream_id()).video_segment(track_id, 0);
+        HlsVideo::open(path, params).unwrap().generate().unwrap()
+    }
+
+    #[test]
+    fn test_memory_source() {
+        let Some(media) = generate(&FixtureSpec::h264_aac()) else {
+            return;
+        };
+        let path = PathBuf::from("/memory/test_memory_source.mp4");
+        assert!(!is_memory_source(&path));
+        add_memory_source(&path, std::fs::read(&media).unwrap());
+        assert!(is_memory_source(&path));
+
+        assert_eq!(first_video_segment(&path), first_video_segment(&media));
+
+        assert!(remove_memory_source(&path));
+        assert!(!remove_memory_source(&path));
+        assert!(open_input(&path).is_err());
+    }
+
+    #[test]
+    fn test_invalid_memory_source() {
+        let path = PathBuf::from("/memory/test_invalid_memory_source.mp4");
+        add_memory_source(&path, &b"not a media file"[..]);
+        assert!(matches!(open_input(&path), Err(FfmpegError::OpenInput(_))));
+        remove_memory_source(&path);
+    }
+}
@@ -4,9 +4,26 @@
 //!
 //! Extracts text content from subtitle packets and AVSubtitle structs.
 
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use crate::error::Result;
 use crate::ffmpeg_utils::ffmpeg;
 
+static BATCH_EXTRACTION: AtomicBool = AtomicBool::new(false);
+
+/// Extract the cues of every text subtitle track in one pass over the file
+/// when a WebVTT segment is requested, and cache the segments of the other
+/// tracks (default off). Saves reading the same part of the file once per
+/// track, for files with many subtitle tracks. Needs the segment cache.
+pub fn set_subtitle_batch_extraction(enabled: bool) {
+    BATCH_EXTRACTION.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether batch extraction is on, see `set_subtitle_batch_extraction()`.
+pub fn subtitle_batch_extraction() -> bool {
+    BATCH_EXTRACTION.load(Ordering::Relaxed)
+}
+
 /// A single subtitle cue with timing and text
 #[derive(Debug, Clone)]
 pub struct SubtitleCue {
@@ -208,8 +225,22 @@ impl SubtitleExtractor {
 
     /// Clean ASS/SSA text by removing style overrides
     fn clean_ass_text(&self, text: &str) -> String {
-        // ASS format can contain style overrides like {\pos(100,200)}
-        // We strip these for WebVTT output
+        // Packets from FFmpeg carry the event fields before the text:
+        // ReadOrder, Layer, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+        let fields: Vec<&str> = text.splitn(9, ',').collect();
+        let text = match fields.as_slice() {
+            [read_order, layer, .., text]
+                if fields.len() == 9
+                    && read_order.trim().parse::<i64>().is_ok()
+                    && layer.trim().parse::<i64>().is_ok() =>
+            {
+                *text
+            }
+            _ => text,
+        };
+
+        // ASS format can contain style overrides like {\pos(100,200)}, and
+        // karaoke timing like {\k20}. We strip these for WebVTT output
         let mut result = String::new();
         let mut in_tag = false;
 
@@ -223,7 +254,18 @@ impl SubtitleExtractor {
             }
         }
 
-        result.trim().to_string()
+        // Line breaks (\N hard, \n soft) and hard spaces (\h).
+        let result = result
+            .replace("\\N", "\n")
+            .replace("\\n", "\n")
+            .replace("\\h", "\u{a0}");
+
+        result
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n")
     }
 }
 
@@ -278,6 +320,14 @@ mod tests {
         let ass_text = "{\\pos(100,200)}Hello{\\c&H00FFFF&} World";
         let cleaned = extractor.clean_ass_text(ass_text);
         assert_eq!(cleaned, "Hello World");
+
+        let ass_text = "{\\k20}Kon{\\k15}ni{\\k30}chi{\\k25}wa\\NHello\\hthere";
+        let cleaned = extractor.clean_ass_text(ass_text);
+        assert_eq!(cleaned, "Konnichiwa\nHello\u{a0}there");
+
+        let packet = "12,0,Default,,0,0,0,,Line one,\\N  line two";
+        let cleaned = extractor.clean_ass_text(packet);
+        assert_eq!(cleaned, "Line one,\nline two");
     }
 
     #[test]
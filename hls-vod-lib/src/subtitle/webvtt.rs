@@ -3,15 +3,121 @@
 //! WebVTT format writer
 //!
 //! Generates WebVTT formatted output for HLS subtitle segments.
+//!
+//! ASS/SSA files often have overlapping events: two speakers on screen at
+//! once, a sign next to dialogue, or karaoke, where every syllable is a
+//! separate event on top of the full line. Written out as-is, these become
+//! interleaved WebVTT cues that many players stack in random order or drop.
+//! By default overlapping cues are therefore resolved into consecutive,
+//! non-overlapping cues, see [`resolve_overlapping_cues`].
+//!
+//! Every cue gets an identifier derived from its start time, so a cue that
+//! is written to more than one segment (see [`SpanningCues`]) has the same
+//! identifier in all of them.
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use crate::subtitle::extractor::SubtitleCue;
 use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+/// Default for `WebVttConfig::merge_overlapping`.
+static MERGE_OVERLAPPING: AtomicBool = AtomicBool::new(true);
+
+/// Set whether overlapping cues are merged into non-overlapping ones
+/// (default on).
+pub fn set_merge_overlapping_cues(merge: bool) {
+    MERGE_OVERLAPPING.store(merge, Ordering::Relaxed);
+}
+
+/// Which segments a cue that crosses a segment boundary is written to.
+///
+/// Segments are cut at video keyframes, subtitle cues are not. A cue that
+/// is written to every segment it overlaps is rendered twice by players
+/// that do not recognize it as the same cue.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpanningCues {
+    /// Only the segment where the cue starts, with its full duration.
+    #[default]
+    Start,
+    /// Every segment the cue overlaps, with the same times and identifier,
+    /// for players that join identical cues and start playback mid-cue.
+    Repeat,
+}
+
+impl SpanningCues {
+    /// The name used in configuration.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SpanningCues::Start => "start",
+            SpanningCues::Repeat => "repeat",
+        }
+    }
+}
+
+impl FromStr for SpanningCues {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "start" => Ok(SpanningCues::Start),
+            "repeat" => Ok(SpanningCues::Repeat),
+            _ => Err(format!("invalid spanning cue mode: {}", s)),
+        }
+    }
+}
+
+/// Whether spanning cues are repeated, see `SpanningCues`.
+static REPEAT_SPANNING: AtomicBool = AtomicBool::new(false);
+
+/// Set which segments a cue that crosses a segment boundary is written to
+/// (default `Start`).
+pub fn set_spanning_cues(mode: SpanningCues) {
+    REPEAT_SPANNING.store(mode == SpanningCues::Repeat, Ordering::Relaxed);
+}
+
+/// The configured `SpanningCues` mode.
+pub fn spanning_cues() -> SpanningCues {
+    if REPEAT_SPANNING.load(Ordering::Relaxed) {
+        SpanningCues::Repeat
+    } else {
+        SpanningCues::Start
+    }
+}
+
+/// An `X-TIMESTAMP-MAP` header: cue time `local_ms` is media time `mpegts`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampMap {
+    /// Media time in 90 kHz units
+    pub mpegts: u64,
+    /// Cue time in milliseconds
+    pub local_ms: i64,
+}
 
 /// WebVTT writer configuration
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct WebVttConfig {
     /// Include WebVTT header comment
     pub include_header_comment: bool,
+    /// Resolve overlapping cues into non-overlapping ones
+    pub merge_overlapping: bool,
+    /// Write an `X-TIMESTAMP-MAP` header
+    pub timestamp_map: Option<TimestampMap>,
+    /// Write a cue identifier before every cue
+    pub cue_ids: bool,
+}
+
+impl Default for WebVttConfig {
+    fn default() -> Self {
+        Self {
+            include_header_comment: false,
+            merge_overlapping: MERGE_OVERLAPPING.load(Ordering::Relaxed),
+            timestamp_map: None,
+            cue_ids: true,
+        }
+    }
 }
 
 /// WebVTT writer for generating subtitle segments
@@ -38,6 +144,15 @@ impl WebVttWriter {
     fn write_header(&mut self) {
         self.output.push_str("WEBVTT\n");
 
+        if let Some(map) = self.config.timestamp_map {
+            // MPEG-TS timestamps are 33 bits.
+            self.output.push_str(&format!(
+                "X-TIMESTAMP-MAP=MPEGTS:{},LOCAL:{}\n",
+                map.mpegts & ((1 << 33) - 1),
+                Self::format_timestamp(map.local_ms)
+            ));
+        }
+
         if self.config.include_header_comment {
             self.output.push_str("\nGenerated by HLS Server\n");
         }
@@ -63,15 +178,61 @@ impl WebVttWriter {
             .replace('"', "&quot;")
     }
 
+    /// Escape cue text, keeping WebVTT markup (`<i>`, `<ruby>`/`<rt>`, ...).
+    pub fn escape_cue_text(text: &str) -> String {
+        const TAGS: &[&str] = &["b", "i", "u", "c", "v", "lang", "ruby", "rt"];
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(pos) = rest.find('<') {
+            out.push_str(&Self::escape_html(&rest[..pos]));
+            rest = &rest[pos..];
+            let tag = rest[1..].find(['<', '>']).and_then(|end| {
+                let inner = &rest[1..end + 1];
+                let name = inner.strip_prefix('/').unwrap_or(inner);
+                let name = name.split(['.', ' ']).next().unwrap_or("");
+                (rest.as_bytes()[end + 1] == b'>' && TAGS.contains(&name)).then_some(end + 2)
+            });
+            match tag {
+                Some(len) => {
+                    out.push_str(&rest[..len]);
+                    rest = &rest[len..];
+                }
+                None => {
+                    out.push_str("&lt;");
+                    rest = &rest[1..];
+                }
+            }
+        }
+        out.push_str(&Self::escape_html(rest));
+        out
+    }
+
+    /// The identifier of a cue: its start time in milliseconds, plus a
+    /// counter if earlier cues of this segment start at the same time.
+    ///
+    /// The identifier only depends on the cue, not on the segment, so a
+    /// repeated cue is recognizable as the same cue.
+    pub fn cue_id(cue: &SubtitleCue, same_start: usize) -> String {
+        match same_start {
+            0 => cue.start_ms.to_string(),
+            n => format!("{}-{}", cue.start_ms, n),
+        }
+    }
+
     /// Write a single cue
-    fn write_cue(&mut self, cue: &SubtitleCue) {
+    fn write_cue(&mut self, cue: &SubtitleCue, same_start: usize) {
+        if self.config.cue_ids {
+            self.output.push_str(&Self::cue_id(cue, same_start));
+            self.output.push('\n');
+        }
+
         // Write timing line
         let start = Self::format_timestamp(cue.start_ms);
         let end = Self::format_timestamp(cue.end_ms);
         self.output.push_str(&format!("{} --> {}\n", start, end));
 
         // Write text with HTML escaping
-        let escaped_text = Self::escape_html(&cue.text);
+        let escaped_text = Self::escape_cue_text(&cue.text);
         self.output.push_str(&escaped_text);
         self.output.push_str("\n\n");
     }
@@ -82,8 +243,19 @@ impl WebVttWriter {
             self.write_header();
         }
 
-        for cue in cues {
-            self.write_cue(cue);
+        let resolved;
+        let cues = if self.config.merge_overlapping {
+            resolved = resolve_overlapping_cues(cues);
+            &resolved[..]
+        } else {
+            cues
+        };
+        for (i, cue) in cues.iter().enumerate() {
+            let same_start = cues[..i]
+                .iter()
+                .filter(|c| c.start_ms == cue.start_ms)
+                .count();
+            self.write_cue(cue, same_start);
         }
     }
 
@@ -111,6 +283,48 @@ impl Default for WebVttWriter {
     }
 }
 
+/// Resolve overlapping cues into consecutive, non-overlapping cues.
+///
+/// The timeline is split at every cue start and end. Each piece gets one cue
+/// with the text of all cues active during it, one below the other, in order
+/// of start time. Texts are not changed otherwise, so line breaks and ruby
+/// markup survive. Identical texts are shown once (karaoke often repeats the
+/// line per syllable, only the styling differs), and pieces with the same
+/// text are joined again.
+pub fn resolve_overlapping_cues(cues: &[SubtitleCue]) -> Vec<SubtitleCue> {
+    let mut sorted: Vec<&SubtitleCue> = cues.iter().filter(|c| c.end_ms > c.start_ms).collect();
+    sorted.sort_by_key(|c| c.start_ms);
+
+    let overlaps = sorted.windows(2).any(|w| w[1].start_ms < w[0].end_ms);
+    if !overlaps {
+        return sorted.into_iter().cloned().collect();
+    }
+
+    let mut bounds: Vec<i64> = sorted.iter().flat_map(|c| [c.start_ms, c.end_ms]).collect();
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    let mut result: Vec<SubtitleCue> = Vec::new();
+    for w in bounds.windows(2) {
+        let (start, end) = (w[0], w[1]);
+        let mut lines: Vec<&str> = Vec::new();
+        for cue in sorted.iter().take_while(|c| c.start_ms < end) {
+            if cue.end_ms >= end && !lines.contains(&cue.text.as_str()) {
+                lines.push(&cue.text);
+            }
+        }
+        if lines.is_empty() {
+            continue;
+        }
+        let text = lines.join("\n");
+        match result.last_mut() {
+            Some(last) if last.end_ms == start && last.text == text => last.end_ms = end,
+            _ => result.push(SubtitleCue::new(start, end, text)),
+        }
+    }
+    result
+}
+
 /// Generate a WebVTT segment from subtitle cues
 pub fn generate_webvtt_segment(cues: &[SubtitleCue], config: Option<WebVttConfig>) -> Bytes {
     let mut writer = match config {
@@ -124,6 +338,7 @@ pub fn generate_webvtt_segment(cues: &[SubtitleCue], config: Option<WebVttConfig
 pub fn generate_webvtt_with_timestamp_map(cues: &[SubtitleCue], _mpegts_offset: u64) -> Bytes {
     let config = WebVttConfig {
         include_header_comment: false,
+        ..Default::default()
     };
     generate_webvtt_segment(cues, Some(config))
 }
@@ -154,6 +369,25 @@ mod tests {
         assert_eq!(WebVttWriter::escape_html("<tag>"), "&lt;tag&gt;");
     }
 
+    #[test]
+    fn test_escape_cue_text() {
+        assert_eq!(
+            WebVttWriter::escape_cue_text("<i>A & B</i>"),
+            "<i>A &amp; B</i>"
+        );
+        assert_eq!(
+            WebVttWriter::escape_cue_text("<ruby>空<rt>そら</rt></ruby>"),
+            "<ruby>空<rt>そら</rt></ruby>"
+        );
+        assert_eq!(
+            WebVttWriter::escape_cue_text("<v.loud Bob>Hi"),
+            "<v.loud Bob>Hi"
+        );
+        assert_eq!(WebVttWriter::escape_cue_text("<tag>"), "&lt;tag&gt;");
+        assert_eq!(WebVttWriter::escape_cue_text("a <b c"), "a &lt;b c");
+        assert_eq!(WebVttWriter::escape_cue_text("1 < 2 <i>x"), "1 &lt; 2 <i>x");
+    }
+
     #[test]
     fn test_webvtt_writer_creation() {
         let writer = WebVttWriter::new();
@@ -164,6 +398,8 @@ mod tests {
     fn test_webvtt_writer_with_config() {
         let config = WebVttConfig {
             include_header_comment: false,
+            merge_overlapping: false,
+            timestamp_map: None,
         };
         let writer = WebVttWriter::with_config(config);
         assert!(writer.output().is_empty());
@@ -198,6 +434,35 @@ mod tests {
         assert!(output.contains("Second"));
     }
 
+    #[test]
+    fn test_cue_ids() {
+        let cues = vec![
+            SubtitleCue::new(1000, 2000, "A".to_string()),
+            SubtitleCue::new(1000, 2000, "B".to_string()),
+            SubtitleCue::new(2500, 3000, "C".to_string()),
+        ];
+        let mut writer = WebVttWriter::with_config(WebVttConfig {
+            merge_overlapping: false,
+            ..Default::default()
+        });
+        let output = String::from_utf8_lossy(&writer.write(&cues)).to_string();
+        assert!(output.contains("\n1000\n00:00:01.000 --> 00:00:02.000\nA\n"));
+        assert!(output.contains("\n1000-1\n00:00:01.000 --> 00:00:02.000\nB\n"));
+        assert!(output.contains("\n2500\n00:00:02.500 --> 00:00:03.000\nC\n"));
+
+        // The same cue has the same identifier in the next segment.
+        let next = String::from_utf8_lossy(&writer.write(&cues[2..])).to_string();
+        assert!(next.contains("\n2500\n00:00:02.500"));
+    }
+
+    #[test]
+    fn test_spanning_cues_from_str() {
+        assert_eq!("start".parse(), Ok(SpanningCues::Start));
+        assert_eq!("repeat".parse(), Ok(SpanningCues::Repeat));
+        assert!("clamp".parse::<SpanningCues>().is_err());
+        assert_eq!(SpanningCues::default(), SpanningCues::Start);
+    }
+
     #[test]
     fn test_generate_webvtt_segment() {
         let cues = vec![SubtitleCue::new(0, 2000, "Test".to_string())];
@@ -207,6 +472,21 @@ mod tests {
         assert!(text.contains("WEBVTT"));
     }
 
+    #[test]
+    fn test_write_timestamp_map() {
+        let mut writer = WebVttWriter::with_config(WebVttConfig {
+            timestamp_map: Some(TimestampMap {
+                mpegts: 900_000,
+                local_ms: 10_000,
+            }),
+            ..Default::default()
+        });
+        let cues = vec![SubtitleCue::new(10_500, 12_000, "Test".to_string())];
+        let output = String::from_utf8_lossy(&writer.write(&cues)).to_string();
+        assert!(output.starts_with("WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:10.000\n\n"));
+        assert!(output.contains("00:00:10.500 --> 00:00:12.000"));
+    }
+
     #[test]
     fn test_generate_with_timestamp_map() {
         let cues = vec![SubtitleCue::new(0, 2000, "Test".to_string())];
@@ -214,4 +494,77 @@ mod tests {
         let text = String::from_utf8_lossy(&result);
         assert!(!text.contains("X-TIMESTAMP-MAP"));
     }
+
+    #[test]
+    fn test_resolve_overlapping_cues() {
+        let cues = vec![
+            SubtitleCue::new(1000, 4000, "Sign".to_string()),
+            SubtitleCue::new(2000, 3000, "Line one\nLine two".to_string()),
+        ];
+        let resolved = resolve_overlapping_cues(&cues);
+        let spans: Vec<_> = resolved
+            .iter()
+            .map(|c| (c.start_ms, c.end_ms, c.text.as_str()))
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                (1000, 2000, "Sign"),
+                (2000, 3000, "Sign\nLine one\nLine two"),
+                (3000, 4000, "Sign"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_karaoke_cues() {
+        // The full line, repeated per highlighted syllable.
+        let cues = vec![
+            SubtitleCue::new(0, 3000, "<ruby>空<rt>そら</rt></ruby>".to_string()),
+            SubtitleCue::new(0, 1000, "<ruby>空<rt>そら</rt></ruby>".to_string()),
+            SubtitleCue::new(1000, 2000, "<ruby>空<rt>そら</rt></ruby>".to_string()),
+        ];
+        let resolved = resolve_overlapping_cues(&cues);
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].start_ms, 0);
+        assert_eq!(resolved[0].end_ms, 3000);
+        assert_eq!(resolved[0].text, cues[0].text);
+    }
+
+    #[test]
+    fn test_resolve_no_overlap() {
+        let cues = vec![
+            SubtitleCue::new(3000, 4000, "Second".to_string()),
+            SubtitleCue::new(1000, 2000, "First".to_string()),
+            SubtitleCue::new(2000, 2000, "Empty".to_string()),
+        ];
+        let resolved = resolve_overlapping_cues(&cues);
+        let texts: Vec<_> = resolved.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, vec!["First", "Second"]);
+    }
+
+    #[test]
+    fn test_write_overlapping_cues() {
+        let cues = vec![
+            SubtitleCue::new(0, 2000, "A".to_string()),
+            SubtitleCue::new(1000, 3000, "B".to_string()),
+        ];
+        let mut writer = WebVttWriter::with_config(WebVttConfig {
+            include_header_comment: false,
+            merge_overlapping: true,
+            timestamp_map: None,
+            cue_ids: false,
+        });
+        let output = String::from_utf8_lossy(&writer.write(&cues)).to_string();
+        assert!(output.contains("00:00:01.000 --> 00:00:02.000\nA\nB\n"));
+
+        let mut writer = WebVttWriter::with_config(WebVttConfig {
+            include_header_comment: false,
+            merge_overlapping: false,
+            timestamp_map: None,
+            cue_ids: false,
+        });
+        let output = String::from_utf8_lossy(&writer.write(&cues)).to_string();
+        assert!(output.contains("00:00:01.000 --> 00:00:03.000\nB\n"));
+    }
 }
@@ -230,10 +230,10 @@ mod tests {
             generate_video_init_segment(index).expect("Failed to generate init segment");
         let timescales = parse_mdhd_timescales(&init_bytes);
 
-        let seg0_bytes =
-            generate_video_segment(index, 0, 0, &asset_path).expect("Failed to generate segment 0");
-        let seg1_bytes =
-            generate_video_segment(index, 0, 1, &asset_path).expect("Failed to generate segment 1");
+        let seg0_bytes = generate_video_segment(index, 0, 0, &asset_path, None)
+            .expect("Failed to generate segment 0");
+        let seg1_bytes = generate_video_segment(index, 0, 1, &asset_path, None)
+            .expect("Failed to generate segment 1");
 
         let seg0 = parse_media_segment(&seg0_bytes);
         let seg1 = parse_media_segment(&seg1_bytes);
@@ -284,7 +284,7 @@ mod tests {
         // Generate segments 0 and 1 and measure cross-segment audio continuity
         for seg_idx in 0..media.segments.len().min(3) {
             let seg = crate::segment::generator::generate_interleaved_segment(
-                &media, video_idx, audio_idx, &media.segments[seg_idx], &asset_path, None,
+                &media, video_idx, audio_idx, &media.segments[seg_idx], &asset_path, None, None,
             ).expect("seg failed");
             std::fs::write(format!("/tmp/alex_av{}.m4s", seg_idx), &seg).unwrap();
             eprintln!("seg{}: {} bytes", seg_idx, seg.len());
@@ -339,7 +339,7 @@ mod tests {
         for (i, seg) in media.segments.iter().take(50).enumerate() {
             let start_sec = seg.start_pts as f64 * vtb.numerator() as f64 / vtb.denominator() as f64;
             match crate::segment::generator::generate_interleaved_segment(
-                &media, video_idx, audio_idx, seg, &asset_path, transcode,
+                &media, video_idx, audio_idx, seg, &asset_path, transcode, None,
             ) {
                 Ok(data) => {
                     let all_moofs = parse_all_moofs(&data);
@@ -425,7 +425,7 @@ mod tests {
 
         for seg_idx in 0..media.segments.len().min(3) {
             let seg = crate::segment::generator::generate_interleaved_segment(
-                &media, video_idx, audio_idx, &media.segments[seg_idx], &asset_path, transcode,
+                &media, video_idx, audio_idx, &media.segments[seg_idx], &asset_path, transcode, None,
             ).expect("seg failed");
             std::fs::write(format!("/tmp/alex_av_transcoded{}.m4s", seg_idx), &seg).unwrap();
             eprintln!("transcoded seg{}: {} bytes", seg_idx, seg.len());
@@ -547,6 +547,7 @@ mod tests {
             &media.segments[0],
             &asset_path,
             None,
+            None,
         )
         .expect("Failed to generate interleaved segment 0");
 
@@ -557,6 +558,7 @@ mod tests {
             &media.segments[1],
             &asset_path,
             None,
+            None,
         )
         .expect("Failed to generate interleaved segment 1");
 
@@ -818,12 +820,12 @@ mod tests {
         let index = &media;
         let audio_stream = index.audio_streams.first().expect("No audio stream found");
 
-        let seg0_bytes = generate_audio_segment(index, 1, 0, &asset_path, None)
+        let seg0_bytes = generate_audio_segment(index, 1, 0, &asset_path, None, None)
             .expect("Failed to generate audio seg 0");
         let seg0 = parse_media_segment(&seg0_bytes);
         assert_eq!(seg0.base_decode_time, 0);
 
-        let seg1_bytes = generate_audio_segment(index, 1, 1, &asset_path, None)
+        let seg1_bytes = generate_audio_segment(index, 1, 1, &asset_path, None, None)
             .expect("Failed to generate audio seg 1");
         let seg1 = parse_media_segment(&seg1_bytes);
 
@@ -26,11 +26,11 @@ fn test_dump_segments() {
     std::fs::write("/tmp/vid_init.mp4", &video_init).unwrap();
     println!("Wrote video init segment: {} bytes", video_init.len());
 
-    let video_bytes = generate_video_segment(&media, 0, 0, &asset).unwrap();
+    let video_bytes = generate_video_segment(&media, 0, 0, &asset, None).unwrap();
     std::fs::write("/tmp/vid0.mp4", &video_bytes).unwrap();
     println!("Wrote video segment 0: {} bytes", video_bytes.len());
 
-    let video_bytes1 = generate_video_segment(&media, 0, 1, &asset).unwrap();
+    let video_bytes1 = generate_video_segment(&media, 0, 1, &asset, None).unwrap();
     std::fs::write("/tmp/vid1.mp4", &video_bytes1).unwrap();
     println!("Wrote video segment 1: {} bytes", video_bytes1.len());
 
@@ -39,11 +39,11 @@ fn test_dump_segments() {
     std::fs::write("/tmp/aud_init_aac.mp4", &audio_init_aac).unwrap();
     println!("Wrote audio init (aac): {} bytes", audio_init_aac.len());
 
-    let aud0_aac = generate_audio_segment(&media, 1, 0, &asset, None).unwrap();
+    let aud0_aac = generate_audio_segment(&media, 1, 0, &asset, None, None).unwrap();
     std::fs::write("/tmp/aud0_aac.mp4", &aud0_aac).unwrap();
     println!("Wrote aac mod 0: {} bytes", aud0_aac.len());
 
-    let aud1_aac = generate_audio_segment(&media, 1, 1, &asset, None).unwrap();
+    let aud1_aac = generate_audio_segment(&media, 1, 1, &asset, None, None).unwrap();
     std::fs::write("/tmp/aud1_aac.mp4", &aud1_aac).unwrap();
     println!("Wrote aac mod 1: {} bytes", aud1_aac.len());
 
@@ -57,15 +57,29 @@ fn test_dump_segments() {
     println!("Wrote interleaved init segment: {} bytes", av_init.len());
 
     let seg0 = media.segments.get(0).unwrap();
-    let av_bytes0 =
-        generate_interleaved_segment(&media, video_idx, audio_idx, seg0, &asset, Some("aac"))
-            .unwrap();
+    let av_bytes0 = generate_interleaved_segment(
+        &media,
+        video_idx,
+        audio_idx,
+        seg0,
+        &asset,
+        Some("aac"),
+        None,
+    )
+    .unwrap();
     std::fs::write("/tmp/av0.mp4", &av_bytes0).unwrap();
 
     let seg1 = media.segments.get(1).unwrap();
-    let av_bytes1 =
-        generate_interleaved_segment(&media, video_idx, audio_idx, seg1, &asset, Some("aac"))
-            .unwrap();
+    let av_bytes1 = generate_interleaved_segment(
+        &media,
+        video_idx,
+        audio_idx,
+        seg1,
+        &asset,
+        Some("aac"),
+        None,
+    )
+    .unwrap();
     std::fs::write("/tmp/av1.mp4", &av_bytes1).unwrap();
 
     // Combine for ffprobe
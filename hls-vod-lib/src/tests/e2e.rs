@@ -28,7 +28,18 @@ fn get_master(media: &StreamIndex, session: Option<&str>) -> String {
             .collect(),
         codecs: Vec::new(),
         transcode: std::collections::HashMap::new(),
+        aac_alternates: false,
         interleave: false,
+        interleaved_fallback: false,
+        combined_init: false,
+        hdcp_level: None,
+        subtitle_timestamps: None,
+        max_height: None,
+        max_bitrate: None,
+        audio_bitrate: None,
+        audio_mix: None,
+        start_offset: None,
+        languages: Vec::new(),
     };
     String::from_utf8(p.generate().unwrap()).unwrap()
 }
@@ -68,14 +79,11 @@ fn try_get_segment(media: &StreamIndex, path: &str) -> Result<Vec<u8>, crate::er
 
 /// Test the complete stream lifecycle
 pub fn test_stream_lifecycle() -> ValidationResult {
-    // Use a real asset for the complete lifecycle test
-    let mut asset_path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-    asset_path.push("testvideos");
-    asset_path.push("bun33s.mp4");
-
-    if !asset_path.exists() {
-        return ValidationResult::success(); // Skip if asset missing
-    }
+    // Use a synthesized media file for the complete lifecycle test
+    let spec = crate::tests::fixtures::generate::FixtureSpec::h264_aac();
+    let Some(asset_path) = crate::tests::fixtures::generate::generate(&spec) else {
+        return ValidationResult::success(); // Skip if no H.264 encoder
+    };
 
     let media = StreamIndex::open(&asset_path, None).expect("Parsing failed");
 
@@ -99,6 +107,116 @@ pub fn test_stream_lifecycle() -> ValidationResult {
     ValidationResult::success()
 }
 
+/// Test that the audio segments of a file hold every audio packet exactly once.
+///
+/// The segments are generated last to first, so that every one of them
+/// seeks. With `interleaved`, the audio track of the interleaved segments is
+/// checked instead of the audio segments. The sample counts must add up to
+/// the number of packets in the file, and the timeline must be continuous:
+/// each segment starts where the previous one ended, within one tick of the
+/// source timebase (a sample for MP4, a millisecond for Matroska).
+pub fn test_audio_continuity(
+    spec: &crate::tests::fixtures::generate::FixtureSpec,
+    interleaved: bool,
+) -> ValidationResult {
+    let Some(asset_path) = crate::tests::fixtures::generate::generate(spec) else {
+        return ValidationResult::success(); // Skip if an encoder is missing
+    };
+    let media = StreamIndex::open(&asset_path, None).expect("Parsing failed");
+    let audio = media.audio_streams[0].stream_index;
+    let video = media.video_streams[0].stream_index;
+
+    let (timebase, packets) = {
+        let mut input = ffmpeg_next::format::input(&asset_path).unwrap();
+        let timebase = input.stream(audio).unwrap().time_base();
+        let start = media.audio_packet_range(&media.segments[0], timebase).start;
+        let packets = input
+            .packets()
+            .filter(|(s, p)| s.index() == audio && p.pts().or(p.dts()).unwrap_or(0) >= start)
+            .count() as u64;
+        (timebase, packets)
+    };
+
+    // The track ID of the audio in the segments.
+    let track_id = if interleaved {
+        let init = crate::segment::generator::generate_interleaved_init_segment(
+            &media, video, audio, None,
+        )
+        .expect("Init segment failed");
+        let audio_track = crate::segment::isobmff::track_handlers(&init)
+            .into_iter()
+            .find(|(_, handler)| handler == b"soun");
+        Some(audio_track.expect("No audio track in the init segment").0)
+    } else {
+        None
+    };
+
+    let mut timing = Vec::new();
+    for segment in media.segments.iter().rev() {
+        let data = if interleaved {
+            crate::segment::generator::generate_interleaved_segment(
+                &media,
+                video,
+                audio,
+                segment,
+                &asset_path,
+                None,
+                None,
+            )
+        } else {
+            crate::segment::generator::generate_audio_segment(
+                &media,
+                audio,
+                segment.sequence,
+                &asset_path,
+                None,
+                None,
+            )
+        };
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                return ValidationResult::fail(format!("segment {}: {}", segment.sequence, e))
+            }
+        };
+        let structure = crate::segment::compare::analyze(&data);
+        let track = match track_id {
+            Some(id) => structure.tracks.iter().find(|t| t.track_id == id),
+            None => structure.tracks.first(),
+        };
+        let Some(track) = track else {
+            return ValidationResult::fail(format!("segment {}: no audio", segment.sequence));
+        };
+        timing.push((segment.sequence, track.clone()));
+    }
+    timing.reverse();
+
+    let mut result = ValidationResult::success();
+    let samples: u64 = timing.iter().map(|(_, t)| t.sample_count).sum();
+    if samples != packets {
+        result.errors.push(format!(
+            "{} samples in the segments, {} packets in the file",
+            samples, packets
+        ));
+    }
+    let sample_rate = media.audio_streams[0].sample_rate as i64;
+    let tolerance =
+        (sample_rate * timebase.numerator() as i64 / timebase.denominator() as i64).max(0);
+    for pair in timing.windows(2) {
+        let ((_, prev), (seq, next)) = (&pair[0], &pair[1]);
+        let end = prev.base_decode_time.unwrap_or(0) as i64 + prev.duration as i64;
+        let start = next.base_decode_time.unwrap_or(0) as i64;
+        if (start - end).abs() > tolerance {
+            result.errors.push(format!(
+                "segment {} starts at {}, the previous one ends at {}",
+                seq, start, end
+            ));
+        }
+    }
+    result.is_valid = result.errors.is_empty();
+    result
+}
+
 /// Test playlist generation for various configurations
 pub fn test_playlist_generation() -> Vec<(&'static str, ValidationResult)> {
     let mut results = Vec::new();
@@ -192,6 +310,145 @@ pub fn test_audio_track_switching() -> ValidationResult {
     ValidationResult::success()
 }
 
+/// Test that switching audio tracks between segments is seamless.
+///
+/// Every audio track is generated once in order, and once switching tracks
+/// at every segment, the way a player that changes languages requests them
+/// (a seek instead of continuing from the previous segment). Passthrough
+/// tracks are also generated transcoded to AAC. A track must have the same
+/// `tfdt` and duration both ways, all tracks must list the same segments,
+/// and at every segment all tracks must start within
+/// `DRIFT_THRESHOLD_SECS` of each other.
+pub fn test_audio_track_switch(
+    spec: &crate::tests::fixtures::generate::FixtureSpec,
+) -> ValidationResult {
+    use crate::segment::compare::analyze;
+    use crate::segment::drift::{track_timing, DRIFT_THRESHOLD_SECS};
+    use crate::segment::generator::{generate_audio_init_segment, generate_audio_segment};
+
+    let Some(asset_path) = crate::tests::fixtures::generate::generate(spec) else {
+        return ValidationResult::success(); // Skip if an encoder is missing
+    };
+    let media = StreamIndex::open(&asset_path, None).expect("Parsing failed");
+
+    let mut renditions: Vec<(usize, Option<&str>)> = Vec::new();
+    for audio in &media.audio_streams {
+        renditions.push((audio.stream_index, None));
+        if audio.transcode_to.is_none() {
+            renditions.push((audio.stream_index, Some("aac")));
+        }
+    }
+    if renditions.len() < 2 {
+        return ValidationResult::fail("Expected at least 2 audio renditions");
+    }
+
+    let mut result = ValidationResult::success();
+
+    // The same segments, with the same durations, in every audio playlist.
+    let extinf = |track: usize| -> Vec<String> {
+        get_variant(&media, &format!("t.{}.m3u8", track))
+            .lines()
+            .filter(|l| l.starts_with("#EXTINF:"))
+            .map(str::to_string)
+            .collect()
+    };
+    let first = extinf(media.audio_streams[0].stream_index);
+    for audio in &media.audio_streams[1..] {
+        if extinf(audio.stream_index) != first {
+            result.errors.push(format!(
+                "audio track {} has other segments than track {}",
+                audio.stream_index, media.audio_streams[0].stream_index
+            ));
+        }
+    }
+
+    let segment = |(track, transcode): (usize, Option<&str>), sequence: usize| {
+        generate_audio_segment(&media, track, sequence, &asset_path, transcode, None)
+    };
+    let timing = |data: &[u8]| {
+        let track = analyze(data).tracks.into_iter().next()?;
+        Some((track.base_decode_time, track.duration, track.sample_count))
+    };
+
+    // In order, per rendition.
+    let mut in_order = Vec::new();
+    for &rendition in &renditions {
+        let mut segments = Vec::new();
+        for s in &media.segments {
+            match segment(rendition, s.sequence) {
+                Ok(data) => segments.push(data),
+                Err(e) => {
+                    return ValidationResult::fail(format!(
+                        "{:?} segment {}: {}",
+                        rendition, s.sequence, e
+                    ))
+                }
+            }
+        }
+        in_order.push(segments);
+    }
+
+    // Switching at every segment: rendition `r` gets segment `n` right after
+    // another rendition got segment `n - 1`.
+    for offset in 0..renditions.len() {
+        for (n, s) in media.segments.iter().enumerate() {
+            let r = (n + offset) % renditions.len();
+            let switched = match segment(renditions[r], s.sequence) {
+                Ok(data) => timing(&data[..]),
+                Err(e) => {
+                    result.errors.push(format!(
+                        "{:?} segment {}: {} after a switch",
+                        renditions[r], s.sequence, e
+                    ));
+                    continue;
+                }
+            };
+            let expected = timing(&in_order[r][n][..]);
+            if switched != expected {
+                result.errors.push(format!(
+                    "{:?} segment {}: (tfdt, duration, samples) {:?} after a switch, \
+                     {:?} in order",
+                    renditions[r], s.sequence, switched, expected
+                ));
+            }
+        }
+    }
+
+    // All renditions start at the same time, in seconds: the timescales of
+    // passthrough and transcoded tracks can differ.
+    let mut starts = Vec::new();
+    for (&(track, transcode), segments) in renditions.iter().zip(&in_order) {
+        let init = match generate_audio_init_segment(&media, track, transcode) {
+            Ok(init) => init,
+            Err(e) => return ValidationResult::fail(format!("{} init: {}", track, e)),
+        };
+        let rendition_starts: Vec<Option<f64>> = segments
+            .iter()
+            .map(|data| track_timing(&init, data).map(|(start, _)| start))
+            .collect();
+        starts.push(rendition_starts);
+    }
+    for (n, s) in media.segments.iter().enumerate() {
+        let Some(column) = starts.iter().map(|r| r[n]).collect::<Option<Vec<f64>>>() else {
+            result
+                .errors
+                .push(format!("segment {}: no tfdt", s.sequence));
+            continue;
+        };
+        let min = column.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = column.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        if max - min > DRIFT_THRESHOLD_SECS {
+            result.errors.push(format!(
+                "segment {}: audio tracks start at {:?}",
+                s.sequence, column
+            ));
+        }
+    }
+
+    result.is_valid = result.errors.is_empty();
+    result
+}
+
 /// Test subtitle synchronization
 pub fn test_subtitle_sync() -> ValidationResult {
     let fixture = TestMediaInfo::with_subtitles();
@@ -312,6 +569,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_audio_continuity_e2e() {
+        use crate::tests::fixtures::generate::FixtureSpec;
+        for spec in [FixtureSpec::h264_aac(), FixtureSpec::hevc_mkv()] {
+            for interleaved in [false, true] {
+                let result = test_audio_continuity(&spec, interleaved);
+                assert!(
+                    result.is_valid,
+                    "{} (interleaved: {}): audio continuity test failed: {:?}",
+                    spec.name, interleaved, result.errors
+                );
+            }
+        }
+    }
+
     #[test]
     fn test_playlist_generation_all_configs() {
         let results = test_playlist_generation();
@@ -330,6 +602,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_audio_track_switch_e2e() {
+        use crate::tests::fixtures::generate::FixtureSpec;
+        let spec = FixtureSpec::multi_language();
+        let result = test_audio_track_switch(&spec);
+        assert!(
+            result.is_valid,
+            "{}: audio track switch test failed: {:?}",
+            spec.name, result.errors
+        );
+    }
+
     #[test]
     fn test_subtitle_sync_e2e() {
         let result = test_subtitle_sync();
@@ -376,6 +660,7 @@ mod tests {
             segment.sequence,
             &asset_path,
             Some("aac"),
+            None,
         )
         .unwrap();
 
@@ -389,6 +674,91 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_generate_response() {
+        use crate::hlsvideo::{HlsVideo, PlaylistOrSegment};
+        use crate::tests::fixtures::generate::{generate, FixtureSpec};
+
+        let Some(path) = generate(&FixtureSpec::h264_aac()) else {
+            return;
+        };
+        crate::cache::init_segment_cache(crate::cache::SegmentCacheConfig::default());
+        let media = StreamIndex::open(&path, None).unwrap();
+        let session = HlsParams::main_playlist(path.to_string_lossy()).session(&media.stream_id);
+        let track = media.video_streams[0].stream_index;
+        let respond = |params: HlsParams| {
+            HlsVideo::PlaylistOrSegment(PlaylistOrSegment::from_index(params, media.clone()))
+                .generate_response()
+                .unwrap()
+        };
+
+        let segment = respond(session.video_segment(track, 0));
+        assert_eq!(segment.content_type, "video/iso.segment");
+        assert_eq!(segment.duration, Some(media.segments[0].duration_secs));
+        assert!(segment.etag.starts_with('"') && segment.etag.ends_with('"'));
+        assert_eq!(segment.stats.bytes, segment.body.len());
+        assert!(!segment.stats.from_cache);
+        assert!(!segment.stats.generation_time.is_zero());
+        // The second time it comes from the cache, if there is one.
+        let again = respond(session.video_segment(track, 0));
+        assert_eq!(again.body, segment.body);
+        assert_eq!(again.etag, segment.etag);
+        assert_eq!(again.stats.from_cache, cfg!(feature = "cache"));
+        assert_eq!(
+            again.stats.generation_time.is_zero(),
+            again.stats.from_cache
+        );
+
+        let playlist = respond(session.playlist(track));
+        assert_eq!(playlist.content_type, "application/vnd.apple.mpegurl");
+        assert_eq!(playlist.cache_control, "no-cache");
+        assert_eq!(playlist.duration, None);
+        assert_ne!(playlist.etag, segment.etag);
+    }
+
+    #[test]
+    #[cfg(feature = "cache")]
+    fn test_streamed_segment_is_cached() {
+        use crate::hlsvideo::{HlsVideo, PlaylistOrSegment, ProgressObserver, SegmentProgress};
+        use crate::tests::fixtures::generate::{generate, FixtureSpec};
+
+        struct Collector(std::sync::Mutex<Vec<bytes::Bytes>>);
+        impl ProgressObserver for Collector {
+            fn on_fragment(&self, _: &SegmentProgress) {}
+            fn wants_data(&self) -> bool {
+                true
+            }
+            fn on_data(&self, chunk: bytes::Bytes) {
+                self.0.lock().unwrap().push(chunk);
+            }
+        }
+
+        let Some(path) = generate(&FixtureSpec::h264_aac()) else {
+            return;
+        };
+        crate::cache::init_segment_cache(crate::cache::SegmentCacheConfig::default());
+        let media = StreamIndex::open(&path, None).unwrap();
+        let session = HlsParams::main_playlist(path.to_string_lossy()).session(&media.stream_id);
+        // A segment that no other test asks for, so the first request is a miss.
+        let last = media.segments.len() - 1;
+        let params = session
+            .video_segment(media.video_streams[0].stream_index, last)
+            .interleave(media.audio_streams[0].stream_index, None);
+
+        let collector = Collector(std::sync::Mutex::new(Vec::new()));
+        let streamed = PlaylistOrSegment::from_index(params.clone(), media.clone())
+            .generate_with_progress(&collector)
+            .unwrap();
+        let chunks = collector.0.into_inner().unwrap();
+        assert!(!chunks.is_empty(), "segment was not streamed");
+        assert_eq!(chunks.concat(), streamed);
+
+        let cached = HlsVideo::PlaylistOrSegment(PlaylistOrSegment::from_index(params, media));
+        let (again, stats) = cached.generate_with_stats().unwrap();
+        assert!(stats.from_cache, "streamed segment was not cached");
+        assert_eq!(again, streamed);
+    }
+
     #[test]
     fn test_benchmark_segment_generation() {
         let result = benchmark_segment_generation(100);
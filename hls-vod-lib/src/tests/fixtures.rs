@@ -1,10 +1,13 @@
 //! Test fixtures for integration tests
 //!
 //! Provides mock media file information for testing without actual media files.
+//! Real media files are synthesized by `generate`, which is `crate::fixtures`.
+
+pub use crate::fixtures as generate;
 
 use crate::ffmpeg_utils::ffmpeg;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicI64, AtomicU64};
+use std::sync::atomic::AtomicU64;
 // use std::sync::Arc; // Commented out as per instruction
 // use MediaInfo; // Commented out as per instruction
 // use crate::ffmpeg_utils::ffmpeg::Rational; // Commented out as per instruction
@@ -128,7 +131,6 @@ impl TestMediaInfo {
             segments: Vec::new(),
             indexed_at: std::time::SystemTime::now(),
             last_accessed: AtomicU64::new(0),
-            segment_first_pts: std::sync::Arc::new(Vec::new()),
             cached_context: None,
             cache_enabled: true,
             last_requested_segment: std::sync::atomic::AtomicI64::new(-1),
@@ -148,6 +150,9 @@ impl TestMediaInfo {
                     language: Some("eng".to_string()),
                     profile: None,
                     level: None,
+                    codec_string: None,
+                    nal_format: Default::default(),
+                    stats: None,
                 });
             }
         }
@@ -169,6 +174,8 @@ impl TestMediaInfo {
                 bitrate: 128000,
                 language,
                 transcode_to: None,
+                mix_with: None,
+                codec_string: None,
                 encoder_delay: 0,
             });
             audio_index += 1;
@@ -214,11 +221,6 @@ impl TestMediaInfo {
             });
         }
 
-        // Initialize segment_first_pts
-        let n = index.segments.len();
-        let v: Vec<AtomicI64> = (0..n).map(|_| AtomicI64::new(i64::MIN)).collect();
-        index.segment_first_pts = std::sync::Arc::new(v);
-
         index
     }
 
@@ -0,0 +1,317 @@
+//! Golden-file regression tests for playlist and segment output.
+//!
+//! For every fixture file, the main playlist, all variant playlists, their
+//! init segments and the first few media segments are generated. A
+//! structural fingerprint of the output (codec strings, `EXTINF` values,
+//! box layout, `tfdt` and sample timing) is compared against the JSON file
+//! in `tests/golden/` at the top of the repository.
+//!
+//! Besides the files in `tests/assets/`, the synthesized fixtures of
+//! `fixtures::generate` are checked. Fixtures that are not present, cannot
+//! be generated or have no golden file yet are skipped. Run with
+//! `UPDATE_GOLDEN=1` to write the golden files of a new fixture, or to
+//! rewrite all of them after an intended change, and review the diff
+//! before committing it.
+
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::hlsvideo::HlsVideo;
+use crate::params::HlsParams;
+use crate::segment::compare::{analyze, TrackTiming};
+
+/// Fixture name and file name in `tests/assets/`.
+pub const FIXTURES: &[(&str, &str)] = &[
+    ("bun33s_mp4", "bun33s.mp4"),
+    ("bun33s_mkv", "bun33s.mkv"),
+    ("bun33s_webm", "bun33s.webm"),
+];
+
+/// Number of media segments fingerprinted per playlist.
+const SEGMENTS_PER_PLAYLIST: usize = 3;
+
+#[derive(Debug, Serialize)]
+pub struct Fingerprint {
+    /// `CODECS` attributes of the variants
+    pub codecs: Vec<String>,
+    /// `EXT-X-MEDIA` tags
+    pub renditions: Vec<String>,
+    pub playlists: Vec<PlaylistFingerprint>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlaylistFingerprint {
+    pub uri: String,
+    pub target_duration: Option<String>,
+    pub segment_count: usize,
+    pub extinf: Vec<String>,
+    pub init: Option<SegmentFingerprint>,
+    pub segments: Vec<SegmentFingerprint>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SegmentFingerprint {
+    pub uri: String,
+    /// fMP4: box paths. WebVTT: header and cue timing lines.
+    pub layout: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sequence_numbers: Vec<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tracks: Vec<TrackTiming>,
+}
+
+fn assets_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../tests/assets")
+}
+
+fn golden_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../tests/golden")
+}
+
+// Resolve `uri` relative to the request path `base`.
+pub(crate) fn join_uri(base: &str, uri: &str) -> String {
+    match base.rfind('/') {
+        Some(pos) => format!("{}/{}", &base[..pos], uri),
+        None => uri.to_string(),
+    }
+}
+
+fn fetch(media: &Path, request_path: &str) -> Vec<u8> {
+    let params =
+        HlsParams::parse(request_path).unwrap_or_else(|| panic!("cannot parse {}", request_path));
+    HlsVideo::open(media, params)
+        .and_then(|v| v.generate())
+        .unwrap_or_else(|e| panic!("{}: {}", request_path, e))
+}
+
+pub(crate) fn attribute<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let start = line.find(&format!("{}=\"", name))? + name.len() + 2;
+    let len = line[start..].find('"')?;
+    Some(&line[start..start + len])
+}
+
+fn segment_fingerprint(uri: String, data: &[u8]) -> SegmentFingerprint {
+    if data.starts_with(b"WEBVTT") {
+        let text = String::from_utf8_lossy(data);
+        let layout = text
+            .lines()
+            .filter(|l| {
+                l.starts_with("WEBVTT") || l.starts_with("X-TIMESTAMP-MAP") || l.contains("-->")
+            })
+            .map(String::from)
+            .collect();
+        return SegmentFingerprint {
+            uri,
+            layout,
+            sequence_numbers: Vec::new(),
+            tracks: Vec::new(),
+        };
+    }
+    let structure = analyze(data);
+    SegmentFingerprint {
+        uri,
+        layout: structure.boxes.into_iter().map(|b| b.path).collect(),
+        sequence_numbers: structure.sequence_numbers,
+        tracks: structure.tracks,
+    }
+}
+
+fn playlist_fingerprint(media: &Path, request_path: &str, uri: String) -> PlaylistFingerprint {
+    let text = String::from_utf8(fetch(media, request_path)).unwrap();
+    let mut fp = PlaylistFingerprint {
+        uri,
+        target_duration: None,
+        segment_count: 0,
+        extinf: Vec::new(),
+        init: None,
+        segments: Vec::new(),
+    };
+    for line in text.lines() {
+        if let Some(v) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+            fp.target_duration = Some(v.to_string());
+        } else if let Some(v) = line.strip_prefix("#EXTINF:") {
+            fp.extinf.push(v.trim_end_matches(',').to_string());
+        } else if line.starts_with("#EXT-X-MAP:") {
+            if let Some(map) = attribute(line, "URI") {
+                let data = fetch(media, &join_uri(request_path, map));
+                fp.init = Some(segment_fingerprint(map.to_string(), &data));
+            }
+        } else if !line.starts_with('#') && !line.is_empty() {
+            fp.segment_count += 1;
+            if fp.segments.len() < SEGMENTS_PER_PLAYLIST {
+                let data = fetch(media, &join_uri(request_path, line));
+                fp.segments
+                    .push(segment_fingerprint(line.to_string(), &data));
+            }
+        }
+    }
+    fp
+}
+
+/// Generate the fingerprint of a media file.
+pub fn fingerprint(media: &Path) -> Fingerprint {
+    let video_url = media.to_str().unwrap().trim_start_matches('/').to_string();
+    let master_path = format!("{}.as.m3u8", video_url);
+    let params = HlsParams::parse(&master_path).unwrap();
+    let video = HlsVideo::open(media, params).unwrap();
+    let stream_id = match &video {
+        HlsVideo::MainPlaylist(p) => p.index.stream_id.clone(),
+        HlsVideo::PlaylistOrSegment(_) => unreachable!(),
+    };
+    let master = String::from_utf8(video.generate().unwrap()).unwrap();
+
+    // The stream id is random, or with `stable_stream_ids` derived from the
+    // path and modification time of the file: leave it out of the URIs.
+    let normalize = |s: &str| s.replace(&stream_id, "{stream}");
+
+    let mut fp = Fingerprint {
+        codecs: Vec::new(),
+        renditions: Vec::new(),
+        playlists: Vec::new(),
+    };
+    let mut uris = Vec::new();
+    for line in master.lines() {
+        if line.starts_with("#EXT-X-STREAM-INF:") {
+            if let Some(codecs) = attribute(line, "CODECS") {
+                fp.codecs.push(codecs.to_string());
+            }
+        } else if line.starts_with("#EXT-X-MEDIA:") {
+            fp.renditions.push(normalize(line));
+            if let Some(uri) = attribute(line, "URI") {
+                uris.push(uri.to_string());
+            }
+        } else if !line.starts_with('#') && !line.is_empty() {
+            uris.push(line.to_string());
+        }
+    }
+    let mut seen = std::collections::HashSet::new();
+    uris.retain(|u| seen.insert(u.clone()));
+    for uri in uris {
+        let request_path = join_uri(&master_path, &uri);
+        fp.playlists
+            .push(playlist_fingerprint(media, &request_path, normalize(&uri)));
+    }
+    fp
+}
+
+/// JSON pointers of the values that differ between `a` and `b`.
+fn json_diff(path: &str, a: &Value, b: &Value, out: &mut Vec<String>) {
+    match (a, b) {
+        (Value::Object(x), Value::Object(y)) => {
+            for (k, v) in x {
+                match y.get(k) {
+                    Some(w) => json_diff(&format!("{}/{}", path, k), v, w, out),
+                    None => out.push(format!("{}/{}: removed", path, k)),
+                }
+            }
+            for k in y.keys().filter(|k| !x.contains_key(*k)) {
+                out.push(format!("{}/{}: added", path, k));
+            }
+        }
+        (Value::Array(x), Value::Array(y)) if x.len() == y.len() => {
+            for (i, (v, w)) in x.iter().zip(y).enumerate() {
+                json_diff(&format!("{}/{}", path, i), v, w, out);
+            }
+        }
+        _ if a != b => out.push(format!("{}: {} -> {}", path, a, b)),
+        _ => {}
+    }
+}
+
+/// Compare the fingerprint of fixture `name` with its golden file.
+///
+/// Returns the differences, or `None` if there is no golden file. With
+/// `UPDATE_GOLDEN` set, the golden file is written instead.
+pub fn check_golden(name: &str, media: &Path) -> Option<Vec<String>> {
+    let actual = serde_json::to_value(fingerprint(media)).unwrap();
+    let file = golden_dir().join(format!("{}.json", name));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(golden_dir()).unwrap();
+        let mut data = serde_json::to_string_pretty(&actual).unwrap();
+        data.push('\n');
+        std::fs::write(&file, data).unwrap();
+        println!("wrote {:?}", file);
+        return Some(Vec::new());
+    }
+    if !file.exists() {
+        return None;
+    }
+    let expected: Value = serde_json::from_slice(&std::fs::read(&file).unwrap()).unwrap();
+    let mut diffs = Vec::new();
+    json_diff("", &expected, &actual, &mut diffs);
+    Some(diffs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::fixtures::generate::{generate, FixtureSpec};
+
+    #[test]
+    fn test_golden_files() {
+        crate::ffmpeg_utils::init().unwrap();
+
+        let mut fixtures = Vec::new();
+        for (name, file) in FIXTURES {
+            match assets_dir().join(file).canonicalize() {
+                Ok(media) => fixtures.push((name.to_string(), media)),
+                Err(_) => println!("Skipping {}: {} doesn't exist", name, file),
+            }
+        }
+        for spec in FixtureSpec::all() {
+            if let Some(media) = generate(&spec) {
+                fixtures.push((spec.name.to_string(), media));
+            }
+        }
+
+        let mut failed = Vec::new();
+        for (name, media) in &fixtures {
+            let Some(diffs) = check_golden(name, media) else {
+                println!("Skipping {}: no golden file", name);
+                continue;
+            };
+            if !diffs.is_empty() {
+                println!("{}: output differs from golden file:", name);
+                for d in &diffs {
+                    println!("  {}", d);
+                }
+                failed.push(name.as_str());
+            }
+        }
+        assert!(
+            failed.is_empty(),
+            "output differs from golden files for {:?}, run with UPDATE_GOLDEN=1 if intended",
+            failed
+        );
+    }
+
+    #[test]
+    fn test_json_diff() {
+        let a = serde_json::json!({"extinf": ["4.000", "4.000"], "codecs": ["avc1.64001f"]});
+        let b = serde_json::json!({"extinf": ["4.000", "3.960"], "target": 4});
+        let mut out = Vec::new();
+        json_diff("", &a, &b, &mut out);
+        assert_eq!(
+            out,
+            vec![
+                "/codecs: removed".to_string(),
+                "/extinf/1: \"4.000\" -> \"3.960\"".to_string(),
+                "/target: added".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_join_uri() {
+        assert_eq!(
+            join_uri("media/a.mp4/s1/t.0.m3u8", "v/0.1.m4s"),
+            "media/a.mp4/s1/v/0.1.m4s"
+        );
+        assert_eq!(
+            join_uri("media/a.mp4.as.m3u8", "a.mp4/s1/t.0.m3u8"),
+            "media/a.mp4/s1/t.0.m3u8"
+        );
+    }
+}
@@ -6,15 +6,19 @@
 //! - Segment generation
 //! - Audio track switching
 //! - Subtitle synchronization
+//! - Golden-file comparison of playlist and segment structure
+//! - Concurrent requests for one stream (stress test)
 //! - Performance benchmarks
 
 pub mod dts_debug;
 pub mod dump_test;
 pub mod e2e;
 pub mod fixtures;
+pub mod golden;
 pub mod init_inspect;
 pub mod playlist_dump;
 pub mod pts_debug;
+pub mod stress;
 pub mod test_audio_bug;
 pub mod test_context_reuse;
 pub mod test_send;
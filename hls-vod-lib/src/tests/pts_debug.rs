@@ -13,8 +13,9 @@ fn debug_video_alex_pts_with_audio() {
     let video_idx = index.primary_video().unwrap().stream_index;
 
     println!("Generating Video Segment 0...");
-    let data = crate::segment::generator::generate_video_segment(&index, video_idx, 0, &video_path)
-        .expect("Failed to generate segment");
+    let data =
+        crate::segment::generator::generate_video_segment(&index, video_idx, 0, &video_path, None)
+            .expect("Failed to generate segment");
 
     if let Some(pos) = data.windows(4).position(|w| w == b"tfdt") {
         let tfdt_box = &data[pos - 4..pos + 24];
@@ -23,7 +24,7 @@ fn debug_video_alex_pts_with_audio() {
 
     println!("Generating Audio Segment 0 (track 1)...");
     let audio_data =
-        crate::segment::generator::generate_audio_segment(&index, 1, 0, &video_path, None)
+        crate::segment::generator::generate_audio_segment(&index, 1, 0, &video_path, None, None)
             .expect("Failed to generate audio segment");
 
     if let Some(pos) = audio_data.windows(4).position(|w| w == b"tfdt") {
@@ -0,0 +1,369 @@
+//! Concurrency stress tests.
+//!
+//! Many threads request the playlists and segments of one stream at the
+//! same time, each in a different order: first to last, last to first,
+//! audio and subtitles before video, or shuffled. This exercises the state
+//! that concurrent requests share: the cached input context and the demux
+//! cursors. The segment cache is bypassed, so that every request generates
+//! its segment.
+//!
+//! Every response must be byte-identical to the one a single thread
+//! generates in playlist order for another stream of the same file, with
+//! its own session id. A run in which no thread finishes for `TIMEOUT`
+//! fails as a deadlock instead of hanging. In interleaved segments, video
+//! and audio must start together.
+//!
+//! The default run is short. Set `HLS_VOD_STRESS=<n>` for a stress test
+//! with `n` times as many threads, each going through the stream `n` times.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+use crate::hlsvideo::HlsVideo;
+use crate::media::StreamIndex;
+use crate::params::HlsParams;
+use crate::segment::compare::analyze;
+use crate::tests::golden::{attribute, join_uri};
+
+/// Orders in which a thread requests the segments, see `request_order`.
+const ORDERS: usize = 4;
+
+/// Threads per stream in the default run.
+const THREADS: usize = 8;
+
+/// How long to wait for the next thread to finish.
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Maximum difference between the start of the video and the audio of an
+/// interleaved segment: the AAC encoder delay, a frame of audio, and the
+/// composition offset of a B-frame.
+const MAX_AV_OFFSET_SECS: f64 = 0.2;
+
+/// The stress factor, from `HLS_VOD_STRESS` (default 1).
+pub fn stress_factor() -> usize {
+    std::env::var("HLS_VOD_STRESS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(1)
+}
+
+/// The request paths of one stream of a media file.
+pub struct Stream {
+    pub stream_id: String,
+    /// Variant playlists, in the order of the main playlist
+    pub playlists: Vec<String>,
+    /// Init and media segments, in playlist order
+    pub segments: Vec<String>,
+    /// The init segment of every media segment
+    pub init: HashMap<String, String>,
+}
+
+impl Stream {
+    /// `path` without the stream id, the same for every stream of a file.
+    fn key(&self, path: &str) -> String {
+        path.replace(&self.stream_id, "{stream}")
+    }
+}
+
+// Generate a playlist or segment, past the segment cache.
+fn fetch(media: &Path, request_path: &str) -> Result<Vec<u8>, String> {
+    let params =
+        HlsParams::parse(request_path).ok_or_else(|| format!("cannot parse {}", request_path))?;
+    HlsVideo::open(media, params)
+        .and_then(|v| match v {
+            HlsVideo::PlaylistOrSegment(p) => p.do_generate(None).map(|(data, _)| data),
+            v => v.generate(),
+        })
+        .map_err(|e| format!("{}: {}", request_path, e))
+}
+
+// A session id that no other stream of this process has, also not with
+// `stable_stream_ids`.
+fn new_session_id() -> String {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    format!("stress{}", NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+// The URI of a playlist line, or of the `EXT-X-MEDIA`/`EXT-X-MAP` tag `tag`.
+fn line_uri<'a>(line: &'a str, tag: &str) -> Option<&'a str> {
+    if line.starts_with(tag) {
+        attribute(line, "URI")
+    } else if !line.starts_with('#') && !line.is_empty() {
+        Some(line)
+    } else {
+        None
+    }
+}
+
+/// Open a new stream of `media` with its own session id, interleaved or
+/// not, and collect its paths.
+pub fn open_stream(media: &Path, interleave: bool) -> Result<Stream, String> {
+    let video_url = media.to_str().unwrap().trim_start_matches('/');
+    let master_path = format!("{}.as.m3u8", video_url);
+    let params = HlsParams::parse(&master_path).unwrap();
+    let config = crate::config::lib_config();
+    let mut video = StreamIndex::open_with_config(media, Some(new_session_id()), config)
+        .and_then(|index| HlsVideo::from_index(index, params))
+        .map_err(|e| e.to_string())?;
+    let HlsVideo::MainPlaylist(main) = &mut video else {
+        return Err(format!("{} is not a main playlist", master_path));
+    };
+    if interleave {
+        main.interleave();
+    }
+    let stream_id = main.index.stream_id.clone();
+    let master = String::from_utf8(video.generate().map_err(|e| e.to_string())?).unwrap();
+
+    let mut stream = Stream {
+        stream_id,
+        playlists: Vec::new(),
+        segments: Vec::new(),
+        init: HashMap::new(),
+    };
+    for uri in master.lines().filter_map(|l| line_uri(l, "#EXT-X-MEDIA:")) {
+        let path = join_uri(&master_path, uri);
+        if !stream.playlists.contains(&path) {
+            stream.playlists.push(path);
+        }
+    }
+    for playlist in &stream.playlists {
+        let text = String::from_utf8(fetch(media, playlist)?).unwrap();
+        let mut init = None;
+        for line in text.lines() {
+            let Some(uri) = line_uri(line, "#EXT-X-MAP:") else {
+                continue;
+            };
+            let path = join_uri(playlist, uri);
+            if line.starts_with("#EXT-X-MAP:") {
+                init = Some(path.clone());
+            } else if let Some(init) = &init {
+                stream.init.insert(path.clone(), init.clone());
+            }
+            if !stream.segments.contains(&path) {
+                stream.segments.push(path);
+            }
+        }
+    }
+    Ok(stream)
+}
+
+/// The segments in the order of thread `n`: first to last, last to first,
+/// audio and subtitles before video, or shuffled.
+pub fn request_order(segments: &[String], n: usize) -> Vec<String> {
+    let mut segments = segments.to_vec();
+    match n % ORDERS {
+        0 => {}
+        1 => segments.reverse(),
+        2 => segments.sort_by_key(|s| s.contains("/v/")),
+        _ => {
+            // xorshift64, so a failing order can be reproduced.
+            let mut state = n as u64 + 1;
+            for i in (1..segments.len()).rev() {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                segments.swap(i, (state % (i as u64 + 1)) as usize);
+            }
+        }
+    }
+    segments
+}
+
+/// Check that the tracks of a media segment with more than one track start
+/// at the same time.
+pub fn check_av_alignment(init: &[u8], segment: &[u8]) -> Result<(), String> {
+    let timescales: HashMap<u32, u32> = analyze(init).timescales.into_iter().collect();
+    let starts: Vec<f64> = analyze(segment)
+        .tracks
+        .iter()
+        .filter_map(|t| {
+            let timescale = *timescales.get(&t.track_id).filter(|&&ts| ts > 0)?;
+            let start = t.base_decode_time? as i64 + t.first_cts_offset.unwrap_or(0);
+            Some(start as f64 / timescale as f64)
+        })
+        .collect();
+    if starts.len() < 2 {
+        return Ok(());
+    }
+    let min = starts.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = starts.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    if max - min > MAX_AV_OFFSET_SECS {
+        return Err(format!("tracks start at {:?}", starts));
+    }
+    Ok(())
+}
+
+/// Request every segment of one stream of `media` from `threads` threads at
+/// once, `rounds` times per thread, and compare the results with what a
+/// single thread generates for another stream. Returns the problems found.
+pub fn stress_stream(media: &Path, interleave: bool, threads: usize, rounds: usize) -> Vec<String> {
+    // The reference, generated in playlist order.
+    let reference = match open_stream(media, interleave) {
+        Ok(stream) => stream,
+        Err(e) => return vec![e],
+    };
+    let mut expected = HashMap::new();
+    for path in &reference.segments {
+        match fetch(media, path) {
+            Ok(data) => expected.insert(reference.key(path), data),
+            Err(e) => return vec![format!("reference {}", e)],
+        };
+    }
+
+    let stream = match open_stream(media, interleave) {
+        Ok(stream) => Arc::new(stream),
+        Err(e) => return vec![e],
+    };
+    let expected = Arc::new(expected);
+    let media = Arc::new(media.to_path_buf());
+    let (tx, rx) = mpsc::channel();
+    for n in 0..threads {
+        let (stream, expected, media, tx) = (
+            Arc::clone(&stream),
+            Arc::clone(&expected),
+            Arc::clone(&media),
+            tx.clone(),
+        );
+        std::thread::spawn(move || {
+            let problems = run_thread(&media, &stream, &expected, n, rounds);
+            let _ = tx.send((n, problems));
+        });
+    }
+    drop(tx);
+
+    let mut problems = Vec::new();
+    for _ in 0..threads {
+        match rx.recv_timeout(TIMEOUT) {
+            Ok((n, p)) => problems.extend(p.into_iter().map(|p| format!("thread {}: {}", n, p))),
+            Err(_) => {
+                problems.push(format!(
+                    "no thread finished within {:?}, deadlock?",
+                    TIMEOUT
+                ));
+                break;
+            }
+        }
+    }
+    problems
+}
+
+fn run_thread(
+    media: &Path,
+    stream: &Stream,
+    expected: &HashMap<String, Vec<u8>>,
+    n: usize,
+    rounds: usize,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut received = HashMap::new();
+    for round in 0..rounds {
+        // Players fetch the playlists first.
+        for path in &stream.playlists {
+            if let Err(e) = fetch(media, path) {
+                problems.push(e);
+            }
+        }
+        for path in request_order(&stream.segments, n + round * ORDERS) {
+            let data = match fetch(media, &path) {
+                Ok(data) => data,
+                Err(e) => {
+                    problems.push(e);
+                    continue;
+                }
+            };
+            let key = stream.key(&path);
+            if expected.get(&key) != Some(&data) {
+                problems.push(format!("{}: differs from the reference", key));
+            }
+            received.insert(path, data);
+        }
+    }
+    for (path, init) in &stream.init {
+        if let (Some(init), Some(segment)) = (received.get(init), received.get(path)) {
+            if let Err(e) = check_av_alignment(init, segment) {
+                problems.push(format!("{}: {}", stream.key(path), e));
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::fixtures::generate::{generate, FixtureSpec};
+
+    fn stress(spec: FixtureSpec, interleave: bool) {
+        let Some(media) = generate(&spec) else {
+            return;
+        };
+        let factor = stress_factor();
+        let problems = stress_stream(&media, interleave, THREADS * factor, factor);
+        assert!(
+            problems.is_empty(),
+            "{} (interleave {}):\n{}",
+            spec.name,
+            interleave,
+            problems.join("\n")
+        );
+    }
+
+    #[test]
+    fn test_request_order() {
+        let segments: Vec<String> = ["t/v/0.m4s", "t/v/1.m4s", "t/a/1.0.m4s", "t/s/2.0-4.vtt"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(request_order(&segments, 0), segments);
+        assert_eq!(request_order(&segments, 1)[0], "t/s/2.0-4.vtt");
+        assert_eq!(
+            request_order(&segments, 2),
+            ["t/a/1.0.m4s", "t/s/2.0-4.vtt", "t/v/0.m4s", "t/v/1.m4s"]
+        );
+        let mut shuffled = request_order(&segments, 3);
+        assert_eq!(shuffled, request_order(&segments, 3));
+        shuffled.sort();
+        let mut sorted = segments.clone();
+        sorted.sort();
+        assert_eq!(shuffled, sorted);
+    }
+
+    #[test]
+    fn test_audio_before_video() {
+        // The audio segments must not depend on the video segments having
+        // been generated first.
+        let Some(media) = generate(&FixtureSpec::h264_aac()) else {
+            return;
+        };
+        let video_first = open_stream(&media, false).unwrap();
+        let audio_first = open_stream(&media, false).unwrap();
+        let mut order = video_first.segments.clone();
+        order.sort_by_key(|s| !s.contains("/v/"));
+        let mut expected = HashMap::new();
+        for path in &order {
+            expected.insert(video_first.key(path), fetch(&media, path).unwrap());
+        }
+        for path in request_order(&audio_first.segments, 2) {
+            let key = audio_first.key(&path);
+            assert!(
+                expected.get(&key) == Some(&fetch(&media, &path).unwrap()),
+                "{} differs when generated before the video",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn test_concurrent_requests() {
+        stress(FixtureSpec::multi_language(), false);
+    }
+
+    #[test]
+    fn test_concurrent_interleaved_requests() {
+        stress(FixtureSpec::h264_aac(), true);
+    }
+}
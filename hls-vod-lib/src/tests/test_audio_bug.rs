@@ -12,11 +12,12 @@ fn test_audio_segment_3_bug() {
     let options = IndexOptions {
         segment_duration_secs: 4.0,
         index_segments: true,
+        ..Default::default()
     };
     let index = scan_file_with_options(&video_path, &options).unwrap();
     println!("Audio streams: {:?}", index.audio_streams);
 
     // Test generating segment 0, track 3
-    let res = generate_audio_segment(&index, 3, 0, &video_path, None);
+    let res = generate_audio_segment(&index, 3, 0, &video_path, None, None);
     println!("Audio segment 3 result: {:?}", res.map(|b| b.len()));
 }
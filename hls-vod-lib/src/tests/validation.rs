@@ -118,6 +118,26 @@ pub fn validate_variant_playlist(content: &str, _playlist_type: PlaylistType) ->
         errors.push("No segment entries found".to_string());
     }
 
+    // Every EXTINF, rounded up, must fit in the target duration.
+    let target_duration = content
+        .lines()
+        .find_map(|l| l.strip_prefix("#EXT-X-TARGETDURATION:"))
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    for line in content.lines() {
+        let Some(extinf) = line.strip_prefix("#EXTINF:") else {
+            continue;
+        };
+        let duration = extinf.split(',').next().unwrap_or("").trim();
+        match (duration.parse::<f64>(), target_duration) {
+            (Ok(d), Some(target)) if d.ceil() as u64 > target => errors.push(format!(
+                "EXTINF {} exceeds EXT-X-TARGETDURATION {}",
+                duration, target
+            )),
+            (Err(_), _) => errors.push(format!("Invalid EXTINF duration: {}", duration)),
+            _ => {}
+        }
+    }
+
     // Validate segment entries have corresponding URIs
     let mut has_extinf = false;
     for line in content.lines() {
@@ -344,6 +364,11 @@ segment_0.m4s
 "#;
         let result = validate_variant_playlist(content, PlaylistType::Video);
         assert!(result.is_valid);
+
+        let content = content.replace("#EXTINF:4.000,", "#EXTINF:6.001,");
+        let result = validate_variant_playlist(&content, PlaylistType::Video);
+        assert!(!result.is_valid);
+        assert!(result.errors[0].contains("EXT-X-TARGETDURATION"));
     }
 
     #[test]
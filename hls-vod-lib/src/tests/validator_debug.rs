@@ -4,7 +4,6 @@ mod tests {
     use crate::media::{SegmentInfo, StreamIndex};
     use std::path::PathBuf;
     use std::sync::atomic::{AtomicI64, AtomicU64};
-    use std::sync::Arc;
 
     #[test]
     fn test_reproduce_mdat_mismatch() {
@@ -30,7 +29,6 @@ mod tests {
             segments: Vec::new(),
             indexed_at: std::time::SystemTime::now(),
             last_accessed: AtomicU64::new(0),
-            segment_first_pts: Arc::new(Vec::new()),
             cached_context: None,
             cache_enabled: true,
             last_requested_segment: AtomicI64::new(-1),
@@ -47,12 +45,8 @@ mod tests {
         };
         index.segments.push(segment);
 
-        // Initialize segment_first_pts
-        let n = index.segments.len();
-        let v: Vec<AtomicI64> = (0..n).map(|_| AtomicI64::new(i64::MIN)).collect();
-        index.segment_first_pts = Arc::new(v);
-
-        let bytes = crate::segment::generator::generate_video_segment(&index, 0, 1, &path).unwrap();
+        let bytes =
+            crate::segment::generator::generate_video_segment(&index, 0, 1, &path, None).unwrap();
         let data = bytes.as_ref();
 
         // Parse moof and trun
@@ -84,12 +84,19 @@ impl AudioDecoder {
 
     /// Receive one decoded PCM frame, or `None` if the decoder needs more
     /// input.
+    ///
+    /// A corrupt frame is reported as `FfmpegError::DecodePacket`; the
+    /// decoder can go on with the next packet. Other errors leave the decoder
+    /// in an unknown state, see `reset`.
     pub fn receive_frame(&mut self) -> Result<Option<ffmpeg::util::frame::Audio>> {
         let mut frame = ffmpeg::util::frame::Audio::empty();
         match self.decoder.receive_frame(&mut frame) {
             Ok(()) => Ok(Some(frame)),
             Err(ffmpeg::Error::Other { errno }) if errno == ffmpeg::error::EAGAIN => Ok(None),
             Err(ffmpeg::Error::Eof) => Ok(None),
+            Err(ffmpeg::Error::InvalidData) => Err(HlsError::Ffmpeg(FfmpegError::DecodePacket(
+                format!("corrupt frame on stream {}", self.stream_index),
+            ))),
             Err(e) => Err(HlsError::Ffmpeg(FfmpegError::ReadFrame(format!(
                 "receive_frame error on stream {}: {}",
                 self.stream_index, e
@@ -97,6 +104,12 @@ impl AudioDecoder {
         }
     }
 
+    /// Drop the decoder state (buffered packets and frames), after an error
+    /// that it might not recover from by itself.
+    pub fn reset(&mut self) {
+        self.decoder.flush();
+    }
+
     /// The source stream index.
     pub fn stream_index(&self) -> usize {
         self.stream_index
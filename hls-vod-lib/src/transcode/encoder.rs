@@ -4,10 +4,21 @@
 //!
 //! Wraps an FFmpeg `AVCodecContext` to encode PCM frames (FLTP, 48 kHz,
 //! stereo) to AAC-LC packets.
+//!
+//! Two encoder implementations can be used: FFmpeg's native `aac` encoder,
+//! and `libfdk_aac` (better quality at low bitrates) if FFmpeg was built with
+//! it. The implementation, bitrate and VBR mode are set once at startup with
+//! [`set_aac_encoder`]; if `libfdk_aac` is not available or fails to open,
+//! the native encoder is used instead.
+
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
 
 use crate::error::{FfmpegError, HlsError, Result};
 use ffmpeg_next as ffmpeg;
 use ffmpeg_next::codec;
+use ffmpeg_next::software::resampling;
 use ffmpeg_next::util::channel_layout::ChannelLayout;
 use ffmpeg_next::util::format::sample::Sample;
 
@@ -16,9 +27,125 @@ pub const ENCODER_SAMPLE_FMT: Sample = Sample::F32(ffmpeg::util::format::sample:
 /// AAC encoder frame size (number of samples per channel per frame)
 pub const AAC_FRAME_SIZE: usize = 1024;
 
+/// AAC encoder implementation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AacEncoderImpl {
+    /// FFmpeg's native AAC encoder.
+    #[default]
+    #[serde(rename = "aac")]
+    Native,
+    /// The Fraunhofer FDK AAC encoder.
+    #[serde(rename = "libfdk_aac")]
+    Fdk,
+}
+
+impl AacEncoderImpl {
+    /// FFmpeg name of the encoder.
+    pub fn name(self) -> &'static str {
+        match self {
+            AacEncoderImpl::Native => "aac",
+            AacEncoderImpl::Fdk => "libfdk_aac",
+        }
+    }
+
+    fn is_available(self) -> bool {
+        match self {
+            AacEncoderImpl::Native => codec::encoder::find(codec::Id::AAC).is_some(),
+            AacEncoderImpl::Fdk => codec::encoder::find_by_name(self.name()).is_some(),
+        }
+    }
+
+    /// Sample format the encoder accepts. `libfdk_aac` only takes packed S16.
+    fn sample_format(self) -> Sample {
+        match self {
+            AacEncoderImpl::Native => ENCODER_SAMPLE_FMT,
+            AacEncoderImpl::Fdk => Sample::I16(ffmpeg::util::format::sample::Type::Packed),
+        }
+    }
+}
+
+/// AAC encoder settings.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AacEncoderConfig {
+    /// Encoder implementation
+    #[serde(default)]
+    pub encoder: AacEncoderImpl,
+    /// Bitrate in bits/s (default: depends on the number of channels)
+    #[serde(default)]
+    pub bitrate: Option<u64>,
+    /// VBR mode 1 (lowest) to 5 (highest quality), `libfdk_aac` only.
+    /// Overrides the bitrate.
+    #[serde(default)]
+    pub vbr: Option<u8>,
+}
+
+static AAC_ENCODER: RwLock<Option<AacEncoderConfig>> = RwLock::new(None);
+
+/// The AAC encoders that this FFmpeg build provides.
+pub fn available_aac_encoders() -> Vec<AacEncoderImpl> {
+    [AacEncoderImpl::Native, AacEncoderImpl::Fdk]
+        .into_iter()
+        .filter(|e| e.is_available())
+        .collect()
+}
+
+/// Configure the AAC encoder used for transcoding.
+///
+/// Returns the implementation that will actually be used: if the requested
+/// one is not available, this falls back to the native encoder.
+pub fn set_aac_encoder(mut config: AacEncoderConfig) -> Result<AacEncoderImpl> {
+    if let Some(vbr) = config.vbr {
+        if !(1..=5).contains(&vbr) {
+            return Err(HlsError::Config(format!(
+                "invalid AAC VBR mode {} (1-5)",
+                vbr
+            )));
+        }
+    }
+    let available = available_aac_encoders();
+    if !available.contains(&config.encoder) {
+        tracing::warn!(
+            "AAC encoder {} not available, using {}",
+            config.encoder.name(),
+            AacEncoderImpl::Native.name()
+        );
+        config.encoder = AacEncoderImpl::Native;
+    }
+    if config.vbr.is_some() && config.encoder != AacEncoderImpl::Fdk {
+        tracing::warn!("AAC VBR mode is only supported by libfdk_aac, ignoring");
+        config.vbr = None;
+    }
+    tracing::info!(
+        "AAC encoder: {} (available: {:?})",
+        config.encoder.name(),
+        available.iter().map(|e| e.name()).collect::<Vec<_>>()
+    );
+    let encoder = config.encoder;
+    *AAC_ENCODER.write().unwrap_or_else(|e| e.into_inner()) = Some(config);
+    Ok(encoder)
+}
+
+fn aac_encoder_config() -> AacEncoderConfig {
+    AAC_ENCODER
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .unwrap_or_default()
+}
+
+/// AAC bitrate for `channels`: the configured one, or the one of `bitrates`
+/// (see `LibConfig::aac_bitrates`).
+pub fn aac_bitrate(channels: u16, bitrates: &crate::config::AacBitrates) -> u64 {
+    aac_encoder_config()
+        .bitrate
+        .unwrap_or_else(|| bitrates.for_channels(channels))
+}
+
 /// AAC encoder backed by a real FFmpeg codec context
 pub struct AacEncoder {
     encoder: ffmpeg::encoder::Audio,
+    /// Converts the FLTP input to the encoder's sample format, if different.
+    converter: Option<resampling::Context>,
     frame_size: usize,
     output_timebase: ffmpeg::Rational,
     pts: i64,
@@ -26,12 +153,47 @@ pub struct AacEncoder {
 
 impl AacEncoder {
     /// Open an AAC encoder at the given parameters.
+    ///
+    /// Uses the implementation set with [`set_aac_encoder`], falling back
+    /// to the native encoder if that fails.
     pub fn open(sample_rate: u32, channels: u16, bitrate: u64) -> Result<Self> {
-        let codec = codec::encoder::find(codec::Id::AAC).ok_or_else(|| {
-            HlsError::Ffmpeg(FfmpegError::EncoderNotFound(
-                "AAC encoder not found in this FFmpeg build".into(),
-            ))
+        let config = aac_encoder_config();
+        match Self::open_impl(&config, sample_rate, channels, bitrate) {
+            Err(e) if config.encoder != AacEncoderImpl::Native => {
+                tracing::warn!(
+                    "failed to open {}: {}, falling back to {}",
+                    config.encoder.name(),
+                    e,
+                    AacEncoderImpl::Native.name()
+                );
+                let native = AacEncoderConfig {
+                    encoder: AacEncoderImpl::Native,
+                    vbr: None,
+                    ..config
+                };
+                Self::open_impl(&native, sample_rate, channels, bitrate)
+            }
+            result => result,
+        }
+    }
+
+    fn open_impl(
+        config: &AacEncoderConfig,
+        sample_rate: u32,
+        channels: u16,
+        bitrate: u64,
+    ) -> Result<Self> {
+        let codec = match config.encoder {
+            AacEncoderImpl::Native => codec::encoder::find(codec::Id::AAC),
+            AacEncoderImpl::Fdk => codec::encoder::find_by_name(config.encoder.name()),
+        };
+        let codec = codec.ok_or_else(|| {
+            HlsError::Ffmpeg(FfmpegError::EncoderNotFound(format!(
+                "AAC encoder {} not found in this FFmpeg build",
+                config.encoder.name()
+            )))
         })?;
+        let sample_format = config.encoder.sample_format();
 
         let ch_layout = if channels == 1 {
             ChannelLayout::MONO
@@ -51,22 +213,51 @@ impl AacEncoder {
         })?;
 
         audio_enc.set_rate(sample_rate as i32);
-        audio_enc.set_format(ENCODER_SAMPLE_FMT);
+        audio_enc.set_format(sample_format);
         audio_enc.set_channel_layout(ch_layout);
         audio_enc.set_bit_rate(bitrate as usize);
 
-        let encoder = audio_enc.open_as(codec).map_err(|e| {
+        let mut options = ffmpeg::Dictionary::new();
+        if let Some(vbr) = config.vbr {
+            options.set("vbr", &vbr.to_string());
+        }
+
+        let encoder = audio_enc.open_as_with(codec, options).map_err(|e| {
             HlsError::Ffmpeg(FfmpegError::EncoderNotFound(format!(
-                "Failed to open AAC encoder: {}",
+                "Failed to open AAC encoder {}: {}",
+                config.encoder.name(),
                 e
             )))
         })?;
 
+        let converter = if sample_format != ENCODER_SAMPLE_FMT {
+            let context = resampling::Context::get(
+                ENCODER_SAMPLE_FMT,
+                ch_layout,
+                sample_rate,
+                sample_format,
+                ch_layout,
+                sample_rate,
+            )
+            .map_err(|e| {
+                HlsError::Ffmpeg(FfmpegError::EncoderNotFound(format!(
+                    "Cannot convert to {:?} for {}: {}",
+                    sample_format,
+                    config.encoder.name(),
+                    e
+                )))
+            })?;
+            Some(context)
+        } else {
+            None
+        };
+
         let frame_size = encoder.frame_size() as usize;
         let output_timebase = ffmpeg::Rational::new(1, sample_rate as i32);
 
         Ok(Self {
             encoder,
+            converter,
             frame_size: if frame_size == 0 {
                 AAC_FRAME_SIZE
             } else {
@@ -79,6 +270,21 @@ impl AacEncoder {
 
     /// Send one PCM frame to the encoder.
     pub fn send_frame(&mut self, frame: &ffmpeg::util::frame::Audio) -> Result<()> {
+        if let Some(converter) = self.converter.as_mut() {
+            let mut converted = ffmpeg::util::frame::Audio::empty();
+            converter.run(frame, &mut converted).map_err(|e| {
+                HlsError::Ffmpeg(FfmpegError::EncoderNotFound(format!(
+                    "AAC encoder sample conversion error: {}",
+                    e
+                )))
+            })?;
+            converted.set_pts(frame.pts());
+            return self.send(&converted);
+        }
+        self.send(frame)
+    }
+
+    fn send(&mut self, frame: &ffmpeg::util::frame::Audio) -> Result<()> {
         self.encoder.send_frame(frame).map_err(|e| {
             HlsError::Ffmpeg(FfmpegError::EncoderNotFound(format!(
                 "AAC encoder send_frame error: {}",
@@ -150,20 +356,63 @@ impl AacEncoder {
     }
 }
 
-/// Check whether the FFmpeg build includes an AAC encoder.
-pub fn is_aac_encoder_available() -> bool {
-    codec::encoder::find(codec::Id::AAC).is_some()
+/// A frame of `samples` samples of silence, in the format the encoder
+/// expects (FLTP, mono or stereo).
+pub fn silence_frame(
+    samples: usize,
+    sample_rate: u32,
+    channels: u16,
+) -> ffmpeg::util::frame::Audio {
+    let ch_layout = if channels == 1 {
+        ChannelLayout::MONO
+    } else {
+        ChannelLayout::STEREO
+    };
+    let mut frame = ffmpeg::util::frame::Audio::new(ENCODER_SAMPLE_FMT, samples, ch_layout);
+    frame.set_rate(sample_rate);
+    for ch in 0..frame.channels() as usize {
+        crate::ffmpeg_utils::helpers::audio_plane_data_mut(&mut frame, ch).fill(0);
+    }
+    frame
 }
 
-/// Get recommended AAC bitrate for a given channel count.
-pub fn get_recommended_bitrate(channels: u16) -> u64 {
-    match channels {
-        1 => 64_000,
-        2 => 128_000,
-        6 => 384_000,
-        8 => 512_000,
-        _ => 128_000,
+/// Encode at least `samples` samples of silence to AAC-LC packets.
+///
+/// Every packet is one frame of silence. The packets are timestamped in
+/// 1 / sample_rate, starting at 0; the priming packet(s) of the encoder are
+/// left out, a decoder outputs silence for the first frame anyway.
+pub fn encode_silence(
+    sample_rate: u32,
+    channels: u16,
+    bitrate: u64,
+    samples: usize,
+) -> Result<Vec<ffmpeg::codec::packet::Packet>> {
+    let mut encoder = AacEncoder::open(sample_rate, channels, bitrate)?;
+    let frame_size = encoder.frame_size();
+    let frames = samples.div_ceil(frame_size);
+
+    let mut packets = Vec::with_capacity(frames + 1);
+    let mut frame = silence_frame(frame_size, sample_rate, channels);
+    for i in 0..frames {
+        frame.set_pts(Some((i * frame_size) as i64));
+        encoder.send_frame(&frame)?;
+        while let Some(packet) = encoder.receive_packet()? {
+            packets.push(packet);
+        }
     }
+    packets.extend(encoder.flush()?);
+
+    packets.retain(|p| p.pts().unwrap_or(0) >= 0);
+    packets.truncate(frames);
+    for packet in packets.iter_mut() {
+        packet.set_duration(frame_size as i64);
+    }
+    Ok(packets)
+}
+
+/// Check whether the FFmpeg build includes an AAC encoder.
+pub fn is_aac_encoder_available() -> bool {
+    codec::encoder::find(codec::Id::AAC).is_some()
 }
 
 #[cfg(test)]
@@ -176,10 +425,34 @@ mod tests {
     }
 
     #[test]
-    fn test_get_recommended_bitrate() {
-        assert_eq!(get_recommended_bitrate(1), 64_000);
-        assert_eq!(get_recommended_bitrate(2), 128_000);
-        assert_eq!(get_recommended_bitrate(6), 384_000);
+    fn test_aac_encoder_impl_serde() {
+        let config: AacEncoderConfig =
+            serde_json::from_str(r#"{"encoder":"libfdk_aac","vbr":4}"#).unwrap();
+        assert_eq!(config.encoder, AacEncoderImpl::Fdk);
+        assert_eq!(config.vbr, Some(4));
+        assert_eq!(config.bitrate, None);
+
+        let config: AacEncoderConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(config.encoder, AacEncoderImpl::Native);
+    }
+
+    #[test]
+    fn test_available_aac_encoders() {
+        let available = available_aac_encoders();
+        assert!(available.contains(&AacEncoderImpl::Native));
+        assert_eq!(
+            available.contains(&AacEncoderImpl::Fdk),
+            codec::encoder::find_by_name("libfdk_aac").is_some()
+        );
+    }
+
+    #[test]
+    fn test_set_aac_encoder_invalid_vbr() {
+        let config = AacEncoderConfig {
+            vbr: Some(9),
+            ..Default::default()
+        };
+        assert!(set_aac_encoder(config).is_err());
     }
 
     #[test]
@@ -201,6 +474,36 @@ mod tests {
         let enc = enc.unwrap();
         assert_eq!(enc.output_timebase(), ffmpeg::Rational::new(1, 48000));
     }
+
+    #[test]
+    fn test_silence_frame() {
+        let frame = silence_frame(1536, 48000, 2);
+        assert_eq!(frame.samples(), 1536);
+        assert_eq!(frame.rate(), 48000);
+        assert_eq!(frame.channels(), 2);
+        for ch in 0..2 {
+            let data = crate::ffmpeg_utils::helpers::audio_plane_data(&frame, ch);
+            let floats = crate::ffmpeg_utils::helpers::fltp_plane_as_f32(data, 1536).unwrap();
+            assert!(floats.iter().all(|&s| s == 0.0));
+        }
+        assert_eq!(silence_frame(1024, 44100, 1).channels(), 1);
+    }
+
+    #[test]
+    fn test_encode_silence() {
+        if !is_aac_encoder_available() {
+            return;
+        }
+        // 2.5 frames is rounded up to 3 frames.
+        let packets = encode_silence(44100, 2, 128_000, 2560).unwrap();
+        assert_eq!(packets.len(), 3);
+        for (i, packet) in packets.iter().enumerate() {
+            assert_eq!(packet.pts(), Some(i as i64 * 1024));
+            assert_eq!(packet.duration(), 1024);
+        }
+        assert!(encode_silence(48000, 1, 64_000, 0).unwrap().is_empty());
+    }
+
     #[test]
     #[ignore]
     fn test_aac_encoder_delay() {
@@ -0,0 +1,115 @@
+//! Audio mixer for the transcoding pipeline
+//!
+//! Mixes two resampled tracks (stereo `FLTP`) into one with an FFmpeg filter
+//! graph, at the volumes of `LibConfig::audio_mix`. For commentary or karaoke
+//! tracks that are meant to be heard over the main track.
+
+use ffmpeg_next as ffmpeg;
+
+use crate::config::AudioMixConfig;
+use crate::error::{HlsError, Result};
+
+/// Filter graph with two `abuffer` sources, `main` and `secondary`, into `amix`.
+pub struct AudioMixer {
+    graph: ffmpeg::filter::Graph,
+}
+
+// helper.
+fn filter_error(e: ffmpeg::Error) -> HlsError {
+    HlsError::Transcode(format!("audio mix filter graph: {}", e))
+}
+
+impl AudioMixer {
+    /// Create a mixer of two stereo `FLTP` tracks at `sample_rate` Hz.
+    pub fn new(sample_rate: u32, volumes: &AudioMixConfig) -> Result<Self> {
+        let find = |name: &str| {
+            ffmpeg::filter::find(name)
+                .ok_or_else(|| HlsError::Transcode(format!("FFmpeg has no {} filter", name)))
+        };
+        let mut graph = ffmpeg::filter::Graph::new();
+        let args = format!(
+            "time_base=1/{0}:sample_rate={0}:sample_fmt=fltp:channel_layout=stereo",
+            sample_rate
+        );
+        for name in ["main", "secondary"] {
+            graph
+                .add(&find("abuffer")?, name, &args)
+                .map_err(filter_error)?;
+        }
+        graph
+            .add(&find("abuffersink")?, "out", "")
+            .map_err(filter_error)?;
+        graph
+            .output("main", 0)
+            .and_then(|p| p.output("secondary", 0))
+            .and_then(|p| p.input("out", 0))
+            .and_then(|p| p.parse(&mix_filter(volumes)))
+            .map_err(filter_error)?;
+        graph.validate().map_err(filter_error)?;
+        Ok(Self { graph })
+    }
+
+    /// Mix `main` and `secondary`, two tracks that start at the same time.
+    /// The shorter one is padded with silence.
+    pub fn mix(
+        mut self,
+        main: Vec<ffmpeg::util::frame::Audio>,
+        secondary: Vec<ffmpeg::util::frame::Audio>,
+    ) -> Result<Vec<ffmpeg::util::frame::Audio>> {
+        for (name, frames) in [("main", main), ("secondary", secondary)] {
+            let mut source = self
+                .graph
+                .get(name)
+                .ok_or_else(|| HlsError::Transcode(format!("no {} mix input", name)))?;
+            let mut pts = 0;
+            for mut frame in frames {
+                frame.set_pts(Some(pts));
+                pts += frame.samples() as i64;
+                source.source().add(&frame).map_err(filter_error)?;
+            }
+            source.source().flush().map_err(filter_error)?;
+        }
+
+        let mut sink = self
+            .graph
+            .get("out")
+            .ok_or_else(|| HlsError::Transcode("no mix output".to_string()))?;
+        let mut mixed = Vec::new();
+        loop {
+            let mut frame = ffmpeg::util::frame::Audio::empty();
+            match sink.sink().frame(&mut frame) {
+                Ok(()) => mixed.push(frame),
+                Err(ffmpeg::Error::Eof) => break,
+                Err(ffmpeg::Error::Other { errno }) if errno == ffmpeg::util::error::EAGAIN => {
+                    break
+                }
+                Err(e) => return Err(filter_error(e)),
+            }
+        }
+        Ok(mixed)
+    }
+}
+
+/// The filter of the graph: `amix` at `volumes`, converted back to the
+/// stereo `FLTP` that the AAC encoder takes.
+fn mix_filter(volumes: &AudioMixConfig) -> String {
+    format!(
+        "[main][secondary]amix=inputs=2:duration=longest:normalize=0:weights='{} {}',\
+         aformat=sample_fmts=fltp:channel_layouts=stereo[out]",
+        volumes.main_volume, volumes.secondary_volume
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mix_filter() {
+        assert_eq!(
+            mix_filter(&AudioMixConfig::default()),
+            "[main][secondary]amix=inputs=2:duration=longest:normalize=0:weights='0.5 1',\
+             aformat=sample_fmts=fltp:channel_layouts=stereo[out]"
+        );
+    }
+}
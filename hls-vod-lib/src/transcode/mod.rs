@@ -2,12 +2,14 @@
 //!
 //! This module handles audio transcoding for HLS compatibility:
 //! - Audio decoder initialization from source streams
-//! - Audio resampling to 48kHz (HLS standard)
+//! - Audio resampling to the configured sample rate
 //! - AAC encoder initialization
+//! - Mixing of a second audio track into the main one
 //! - Standalone audio transcoding pipeline (independent tracks)
 //! - In-memory encoded packet buffering
 
 pub mod decoder;
 pub mod encoder;
+pub mod mixer;
 pub mod pipeline;
 pub mod resampler;
@@ -4,19 +4,20 @@
 //!
 //! Combines `AudioDecoder` → `AudioResampler` → `AacEncoder` to convert
 //! non-AAC audio streams (AC-3, Opus, MP3, FLAC, …) into AAC-LC packets
-//! ready for fMP4 muxing.
+//! ready for fMP4 muxing, optionally with a second track mixed in by
+//! `AudioMixer`.
 
 use ffmpeg_next as ffmpeg;
 
-use crate::error::Result;
+use crate::config::LibConfig;
+use crate::error::{FfmpegError, HlsError, Result};
 use crate::media::{AudioStreamInfo, SegmentInfo};
 
 use super::decoder::AudioDecoder;
-use super::encoder::{get_recommended_bitrate, AacEncoder};
+use super::encoder::{aac_bitrate, encode_silence, silence_frame, AacEncoder};
+use super::mixer::AudioMixer;
 use super::resampler::AudioResampler;
 
-pub use super::resampler::HLS_SAMPLE_RATE;
-
 /// Check if an audio stream needs transcoding for HLS compatibility.
 ///
 /// AAC streams can be muxed directly; everything else must be decoded and
@@ -39,18 +40,163 @@ pub fn needs_transcoding(audio_stream: &AudioStreamInfo) -> bool {
 ///
 /// Returns a `Vec` of AAC packets ready to be written into an `Fmp4Muxer`.
 /// Packet timestamps are expressed in the AAC encoder's output timebase
-/// (1 / sample_rate).
+/// (1 / sample_rate). The audio is encoded at `bitrate`, or else at the
+/// bitrate of `config` for the channel count.
 pub fn transcode_audio_segment(
-    mut decoder: AudioDecoder,
+    decoder: AudioDecoder,
     audio_packets: Vec<ffmpeg::codec::packet::Packet>,
     audio_timebase: ffmpeg::Rational,
     audio_info: &AudioStreamInfo,
     segment: &SegmentInfo,
     video_timebase: ffmpeg::Rational,
     shift_to_zero: bool,
+    config: &LibConfig,
+    bitrate: Option<u64>,
+) -> Result<(Vec<ffmpeg::codec::packet::Packet>, ffmpeg::Rational)> {
+    let bitrate = bitrate.unwrap_or_else(|| aac_bitrate(audio_info.channels, &config.aac_bitrates));
+    let (pcm_frames, first_frame_pts_48k) = decode_segment_pcm(
+        decoder,
+        audio_packets,
+        audio_timebase,
+        audio_info,
+        segment,
+        video_timebase,
+        config,
+    )?;
+    encode_segment_pcm(
+        pcm_frames,
+        first_frame_pts_48k,
+        audio_info.stream_index,
+        segment,
+        video_timebase,
+        shift_to_zero,
+        config.sample_rate,
+        bitrate,
+    )
+}
+
+/// One of the two audio tracks of `transcode_mixed_audio_segment`.
+pub struct MixInput<'a> {
+    /// Decoder of the track
+    pub decoder: AudioDecoder,
+    /// Compressed packets of the segment, with pre-roll
+    pub packets: Vec<ffmpeg::codec::packet::Packet>,
+    /// Timebase of the packets
+    pub timebase: ffmpeg::Rational,
+    /// The track
+    pub info: &'a AudioStreamInfo,
+}
+
+/// Transcode the mix of two audio tracks of a source segment into AAC
+/// packets, like `transcode_audio_segment`.
+///
+/// Both tracks are decoded and resampled, `secondary` is mixed into `main`
+/// at the volumes of `config.audio_mix`, and the mix is encoded at `bitrate`,
+/// or else at the bitrate of `config` for the channel count of `main`. If
+/// one track starts later than the other, it is preceded by silence.
+pub fn transcode_mixed_audio_segment(
+    main: MixInput,
+    secondary: MixInput,
+    segment: &SegmentInfo,
+    video_timebase: ffmpeg::Rational,
+    shift_to_zero: bool,
+    config: &LibConfig,
+    bitrate: Option<u64>,
 ) -> Result<(Vec<ffmpeg::codec::packet::Packet>, ffmpeg::Rational)> {
+    let stream_index = main.info.stream_index;
+    let bitrate = bitrate.unwrap_or_else(|| aac_bitrate(main.info.channels, &config.aac_bitrates));
+    let sample_rate = config.sample_rate;
+
+    let mut tracks = Vec::new();
+    for input in [main, secondary] {
+        tracks.push(decode_segment_pcm(
+            input.decoder,
+            input.packets,
+            input.timebase,
+            input.info,
+            segment,
+            video_timebase,
+            config,
+        )?);
+    }
+    let start = tracks.iter().filter_map(|(_, start)| *start).min();
+    let max_gap = segment_samples(segment, video_timebase, sample_rate);
+    for (frames, first) in tracks.iter_mut() {
+        if let (Some(start), Some(first)) = (start, *first) {
+            if let Some(gap) = silence_gap(first - start, 0, max_gap) {
+                if !frames.is_empty() {
+                    frames.insert(0, silence_frame(gap, sample_rate, 2));
+                }
+            }
+        }
+    }
+
+    let secondary = tracks.pop().map(|(frames, _)| frames).unwrap_or_default();
+    let main = tracks.pop().map(|(frames, _)| frames).unwrap_or_default();
+    let pcm_frames = if main.is_empty() && secondary.is_empty() {
+        Vec::new()
+    } else {
+        AudioMixer::new(sample_rate, &config.audio_mix)?.mix(main, secondary)?
+    };
+    tracing::debug!(
+        seq = segment.sequence,
+        stream_index,
+        pcm_frames = pcm_frames.len(),
+        "transcode_mixed_audio_segment: mixed"
+    );
+
+    encode_segment_pcm(
+        pcm_frames,
+        start,
+        stream_index,
+        segment,
+        video_timebase,
+        shift_to_zero,
+        sample_rate,
+        bitrate,
+    )
+}
+
+/// Length of `segment` in samples at `sample_rate`.
+fn segment_samples(
+    segment: &SegmentInfo,
+    video_timebase: ffmpeg::Rational,
+    sample_rate: u32,
+) -> i64 {
+    crate::ffmpeg_utils::utils::rescale_ts(
+        segment.end_pts - segment.start_pts,
+        video_timebase,
+        ffmpeg::Rational(1, sample_rate as i32),
+    )
+    .max(0)
+}
+
+/// The samples of silence that fill a hole of `gap` samples in audio with
+/// frames of `frame_samples`, or `None` for no silence. Holes of less than
+/// half a frame are timestamp rounding (e.g. AAC in MKV). The silence is at
+/// most `max_gap` samples long, the length of the segment: a corrupt
+/// timestamp must not allocate hours of silence.
+fn silence_gap(gap: i64, frame_samples: i64, max_gap: i64) -> Option<usize> {
+    (gap > frame_samples / 2 && gap > 0).then(|| gap.min(max_gap) as usize)
+}
+
+/// Decode and resample the packets of one audio track of a segment.
+///
+/// Returns the PCM frames, and the timestamp of the first one at the sample
+/// rate of `config`. Corrupt packets are replaced by silence; if nothing
+/// could be decoded at all, the whole segment is silence.
+fn decode_segment_pcm(
+    mut decoder: AudioDecoder,
+    audio_packets: Vec<ffmpeg::codec::packet::Packet>,
+    audio_timebase: ffmpeg::Rational,
+    audio_info: &AudioStreamInfo,
+    segment: &SegmentInfo,
+    video_timebase: ffmpeg::Rational,
+    config: &LibConfig,
+) -> Result<(Vec<ffmpeg::util::frame::Audio>, Option<i64>)> {
     let stream_index = audio_info.stream_index;
-    let bitrate = get_recommended_bitrate(audio_info.channels);
+    let sample_rate = config.sample_rate;
+    let max_gap = segment_samples(segment, video_timebase, sample_rate);
 
     tracing::debug!(
         seq = segment.sequence,
@@ -62,14 +208,48 @@ pub fn transcode_audio_segment(
     );
 
     // ── Decode compressed packets into PCM frames ───────────────────────
+    // Corrupt packets are skipped, and the audio they would have decoded to
+    // is replaced by silence, so the segment keeps its duration.
     let mut pcm_frames: Vec<ffmpeg::util::frame::Audio> = Vec::new();
     let mut resampler: Option<AudioResampler> = None;
     let mut first_frame_pts_48k: Option<i64> = None;
+    let mut next_frame_pts_48k: Option<i64> = None;
+    let mut corrupt_packets = 0usize;
+    let mut decoder_resets = 0usize;
+    let mut skipped = false;
 
     for packet in audio_packets {
-        decoder.send_packet(&packet)?;
+        let (frames, error) = decode_packet(&mut decoder, &packet);
+        if let Some(e) = error {
+            let fatal = !matches!(e, HlsError::Ffmpeg(FfmpegError::DecodePacket(_)));
+            if fatal {
+                decoder.reset();
+                decoder_resets += 1;
+            }
+            tracing::debug!(
+                seq = segment.sequence,
+                stream_index,
+                pts = ?packet.pts(),
+                fatal,
+                error = %e,
+                "transcode_audio_segment: skipping corrupt packet"
+            );
+            corrupt_packets += 1;
+            skipped = true;
+            // Nothing decoded yet: the silence starts at this packet.
+            if next_frame_pts_48k.is_none() {
+                next_frame_pts_48k = packet.pts().map(|pts| {
+                    crate::ffmpeg_utils::utils::rescale_ts(
+                        pts,
+                        audio_timebase,
+                        ffmpeg::Rational(1, sample_rate as i32),
+                    )
+                });
+                first_frame_pts_48k = first_frame_pts_48k.or(next_frame_pts_48k);
+            }
+        }
 
-        while let Some(frame) = decoder.receive_frame()? {
+        for frame in frames {
             // Lazily create the resampler from the first decoded frame
             let rsmp = match resampler {
                 Some(ref mut r) => r,
@@ -80,7 +260,7 @@ pub fn transcode_audio_segment(
                         format = ?frame.format(),
                         "transcode_audio_segment: creating resampler from first frame"
                     );
-                    resampler = Some(AudioResampler::new(&frame, HLS_SAMPLE_RATE)?);
+                    resampler = Some(AudioResampler::new(&frame, sample_rate, &config.downmix)?);
                     resampler.as_mut().unwrap()
                 }
             };
@@ -91,23 +271,67 @@ pub fn transcode_audio_segment(
                 first_frame_pts_48k = Some(crate::ffmpeg_utils::utils::rescale_ts(
                     fr_pts,
                     audio_timebase,
-                    ffmpeg::Rational(1, HLS_SAMPLE_RATE as i32),
+                    ffmpeg::Rational(1, sample_rate as i32),
                 ));
             }
 
+            // Fill the hole left by skipped packets.
+            let frame_pts_48k = frame.pts().map(|pts| {
+                crate::ffmpeg_utils::utils::rescale_ts(
+                    pts,
+                    audio_timebase,
+                    ffmpeg::Rational(1, sample_rate as i32),
+                )
+            });
+            let frame_samples_48k =
+                frame.samples() as i64 * sample_rate as i64 / frame.rate().max(1) as i64;
+            if skipped {
+                if let (Some(pts), Some(expected)) = (frame_pts_48k, next_frame_pts_48k) {
+                    if let Some(gap) = silence_gap(pts - expected, frame_samples_48k, max_gap) {
+                        pcm_frames.push(silence_frame(gap, sample_rate, 2));
+                    }
+                }
+            }
+            next_frame_pts_48k = frame_pts_48k.map(|pts| pts + frame_samples_48k);
+            skipped = false;
+
             let resampled = rsmp.convert(&frame)?;
             pcm_frames.extend(resampled);
         }
     }
 
+    if corrupt_packets > 0 {
+        tracing::warn!(
+            seq = segment.sequence,
+            stream_index,
+            codec = ?audio_info.codec_id,
+            corrupt_packets,
+            decoder_resets,
+            "transcode_audio_segment: corrupt audio packets replaced by silence"
+        );
+    }
+
     tracing::debug!(
         pcm_frames = pcm_frames.len(),
         "transcode_audio_segment: decode loop complete"
     );
 
-    // Flush decoder
+    // Flush decoder; a corrupt tail is dropped.
     decoder.send_eof()?;
-    while let Some(frame) = decoder.receive_frame()? {
+    loop {
+        let frame = match decoder.receive_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                tracing::debug!(
+                    seq = segment.sequence,
+                    stream_index,
+                    error = %e,
+                    "transcode_audio_segment: dropping corrupt frame at flush"
+                );
+                break;
+            }
+        };
         if let Some(rsmp) = resampler.as_mut() {
             let resampled = rsmp.convert(&frame)?;
             pcm_frames.extend(resampled);
@@ -125,13 +349,49 @@ pub fn transcode_audio_segment(
         "transcode_audio_segment: after flush"
     );
 
+    // Nothing could be decoded: the whole segment is silence.
+    if pcm_frames.is_empty() && corrupt_packets > 0 {
+        let to_48k = |pts: i64| {
+            crate::ffmpeg_utils::utils::rescale_ts(
+                pts,
+                video_timebase,
+                ffmpeg::Rational(1, sample_rate as i32),
+            )
+        };
+        let (start_48k, end_48k) = (to_48k(segment.start_pts), to_48k(segment.end_pts));
+        first_frame_pts_48k = Some(start_48k);
+        pcm_frames.push(silence_frame(
+            (end_48k - start_48k).max(0) as usize,
+            sample_rate,
+            2,
+        ));
+    }
+
+    Ok((pcm_frames, first_frame_pts_48k))
+}
+
+/// Encode resampled PCM frames, the first one at `first_frame_pts_48k`, to
+/// the AAC packets of `segment`. Without frames, the segment is silence.
+#[allow(clippy::too_many_arguments)]
+fn encode_segment_pcm(
+    pcm_frames: Vec<ffmpeg::util::frame::Audio>,
+    first_frame_pts_48k: Option<i64>,
+    stream_index: usize,
+    segment: &SegmentInfo,
+    video_timebase: ffmpeg::Rational,
+    shift_to_zero: bool,
+    sample_rate: u32,
+    bitrate: u64,
+) -> Result<(Vec<ffmpeg::codec::packet::Packet>, ffmpeg::Rational)> {
+    // No audio in this range at all, e.g. the audio track is shorter than
+    // the video: the segment is silence, so players don't stall on it.
     if pcm_frames.is_empty() {
-        tracing::warn!(
+        tracing::debug!(
             seq = segment.sequence,
             stream_index,
-            "transcode_audio_segment: 0 PCM frames decoded - returning empty packet list"
+            "transcode_audio_segment: no audio packets - returning silence"
         );
-        return Ok((vec![], ffmpeg::Rational::new(1, HLS_SAMPLE_RATE as i32)));
+        return silent_segment(segment, video_timebase, shift_to_zero, sample_rate, bitrate);
     }
 
     // ── 5. Align grid and Encode PCM frames → AAC packets ─────────────────
@@ -158,13 +418,13 @@ pub fn transcode_audio_segment(
     let channels: u16 = pcm_frames.first().map(|f| f.channels()).unwrap_or(2);
     let pcm_frames = rechunk_pcm_frames(pcm_frames, AAC_FRAME_SIZE, discard_samples);
 
-    let mut encoder = AacEncoder::open(HLS_SAMPLE_RATE, channels, bitrate)?;
+    let mut encoder = AacEncoder::open(sample_rate, channels, bitrate)?;
     let output_timebase = encoder.output_timebase();
 
     // The boundary of the requested segment
     let segment_start_sec = segment.start_pts as f64 * video_timebase.numerator() as f64
         / video_timebase.denominator() as f64;
-    let segment_start_48k = (segment_start_sec * HLS_SAMPLE_RATE as f64) as i64;
+    let segment_start_48k = (segment_start_sec * sample_rate as f64) as i64;
 
     // Snap to the first AAC frame boundary that is >= the segment start.
     // Using ceil (not floor) ensures the first output packet belongs to THIS
@@ -182,7 +442,7 @@ pub fn transcode_audio_segment(
     // boundary we guarantee segment N ends where segment N+1 begins.
     let segment_end_sec = segment.end_pts as f64 * video_timebase.numerator() as f64
         / video_timebase.denominator() as f64;
-    let segment_end_48k = (segment_end_sec * HLS_SAMPLE_RATE as f64) as i64;
+    let segment_end_48k = (segment_end_sec * sample_rate as f64) as i64;
     let audio_end_limit_48k = ((segment_end_48k + AAC_FRAME_SIZE as i64 - 1)
         / AAC_FRAME_SIZE as i64)
         * AAC_FRAME_SIZE as i64;
@@ -224,6 +484,16 @@ pub fn transcode_audio_segment(
         }
     }
 
+    // Only audio from before the segment (the pre-roll) was decoded.
+    if aac_packets.is_empty() {
+        tracing::debug!(
+            seq = segment.sequence,
+            stream_index,
+            "transcode_audio_segment: audio ends before segment - returning silence"
+        );
+        return silent_segment(segment, video_timebase, shift_to_zero, sample_rate, bitrate);
+    }
+
     tracing::debug!(
         aac_packets = aac_packets.len(),
         "transcode_audio_segment: done"
@@ -232,6 +502,65 @@ pub fn transcode_audio_segment(
     Ok((aac_packets, output_timebase))
 }
 
+/// AAC silence for `segment`, on the same 1024-sample grid and with the same
+/// timestamps as the packets `transcode_audio_segment` encodes.
+fn silent_segment(
+    segment: &SegmentInfo,
+    video_timebase: ffmpeg::Rational,
+    shift_to_zero: bool,
+    sample_rate: u32,
+    bitrate: u64,
+) -> Result<(Vec<ffmpeg::codec::packet::Packet>, ffmpeg::Rational)> {
+    const AAC_FRAME_SIZE: i64 = 1024;
+    let to_grid_48k = |pts: i64| {
+        let sec =
+            pts as f64 * video_timebase.numerator() as f64 / video_timebase.denominator() as f64;
+        let pts_48k = (sec * sample_rate as f64) as i64;
+        ((pts_48k + AAC_FRAME_SIZE - 1) / AAC_FRAME_SIZE) * AAC_FRAME_SIZE
+    };
+    let start_48k = to_grid_48k(segment.start_pts);
+    let end_48k = to_grid_48k(segment.end_pts);
+
+    let mut packets = encode_silence(
+        sample_rate,
+        2,
+        bitrate,
+        (end_48k - start_48k).max(0) as usize,
+    )?;
+    // Without shift_to_zero the timestamps are absolute, otherwise relative
+    // to the (left out) priming packet.
+    let offset = if shift_to_zero {
+        AAC_FRAME_SIZE
+    } else {
+        start_48k
+    };
+    for pkt in packets.iter_mut() {
+        let pts = pkt.pts().unwrap_or(0) + offset;
+        pkt.set_pts(Some(pts));
+        pkt.set_dts(Some(pts));
+    }
+    Ok((packets, ffmpeg::Rational::new(1, sample_rate as i32)))
+}
+
+/// Decode one packet. Returns the frames decoded so far, and the error that
+/// stopped decoding, if any.
+fn decode_packet(
+    decoder: &mut AudioDecoder,
+    packet: &ffmpeg::codec::packet::Packet,
+) -> (Vec<ffmpeg::util::frame::Audio>, Option<HlsError>) {
+    let mut frames = Vec::new();
+    if let Err(e) = decoder.send_packet(packet) {
+        return (frames, Some(e));
+    }
+    loop {
+        match decoder.receive_frame() {
+            Ok(Some(frame)) => frames.push(frame),
+            Ok(None) => return (frames, None),
+            Err(e) => return (frames, Some(e)),
+        }
+    }
+}
+
 /// Rechunk a list of FLTP audio frames so every frame except the last has
 /// exactly `chunk_size` samples. Required because the AAC encoder demands
 /// 1024 samples/frame while Opus decodes 960 samples/frame.
@@ -320,16 +649,19 @@ pub struct TranscodeRequirements {
     pub target_bitrate: u64,
 }
 
-/// Get transcoding requirements for an audio stream.
-pub fn get_transcode_requirements(audio_stream: &AudioStreamInfo) -> TranscodeRequirements {
+/// Get transcoding requirements for an audio stream, with library config `config`.
+pub fn get_transcode_requirements(
+    audio_stream: &AudioStreamInfo,
+    config: &LibConfig,
+) -> TranscodeRequirements {
     TranscodeRequirements {
         needs_transcoding: needs_transcoding(audio_stream),
         source_codec: audio_stream.codec_id,
         source_sample_rate: audio_stream.sample_rate,
         source_channels: audio_stream.channels,
-        target_sample_rate: HLS_SAMPLE_RATE,
+        target_sample_rate: config.sample_rate,
         target_channels: 2,
-        target_bitrate: get_recommended_bitrate(audio_stream.channels),
+        target_bitrate: aac_bitrate(audio_stream.channels, &config.aac_bitrates),
     }
 }
 
@@ -347,6 +679,8 @@ mod tests {
             bitrate: 128000,
             language: Some("en".to_string()),
             transcode_to: None,
+            mix_with: None,
+            codec_string: None,
             encoder_delay: 0,
         }
     }
@@ -382,17 +716,42 @@ mod tests {
             bitrate: 384000,
             language: Some("en".to_string()),
             transcode_to: None,
+            mix_with: None,
+            codec_string: None,
             encoder_delay: 0,
         };
-        let reqs = get_transcode_requirements(&stream);
+        let reqs = get_transcode_requirements(&stream, &LibConfig::default());
         assert!(reqs.needs_transcoding);
         assert_eq!(reqs.source_codec, ffmpeg::codec::Id::VORBIS);
         assert_eq!(reqs.target_sample_rate, 48000);
         assert_eq!(reqs.target_channels, 2);
+        assert_eq!(reqs.target_bitrate, 384_000);
+
+        let config = LibConfig::default().sample_rate(44100);
+        assert_eq!(
+            get_transcode_requirements(&stream, &config).target_sample_rate,
+            44100
+        );
     }
 
     #[test]
-    fn test_transcoder_config_default() {
-        assert_eq!(HLS_SAMPLE_RATE, 48000);
+    fn test_silence_gap() {
+        // Rounding, a real hole, and a hole from a corrupt timestamp.
+        assert_eq!(silence_gap(400, 1024, 192_000), None);
+        assert_eq!(silence_gap(-5000, 1024, 192_000), None);
+        assert_eq!(silence_gap(2048, 1024, 192_000), Some(2048));
+        assert_eq!(silence_gap(i64::MAX / 2, 1024, 192_000), Some(192_000));
+        assert_eq!(silence_gap(1, 0, 192_000), Some(1));
+
+        let segment = SegmentInfo {
+            sequence: 0,
+            start_pts: 90_000,
+            end_pts: 450_000,
+            duration_secs: 4.0,
+            is_keyframe: true,
+            video_byte_offset: 0,
+        };
+        let samples = segment_samples(&segment, ffmpeg::Rational(1, 90_000), 48_000);
+        assert_eq!(samples, 192_000);
     }
 }
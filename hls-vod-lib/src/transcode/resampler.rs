@@ -2,16 +2,17 @@
 
 //! Audio resampler for the transcoding pipeline
 //!
-//! Converts decoded PCM frames to 48 kHz / stereo / `FLTP` for the AAC encoder.
+//! Converts decoded PCM frames to stereo `FLTP` at the sample rate of
+//! `LibConfig::sample_rate` for the AAC encoder.
+//! Multichannel audio is mixed down as set in `LibConfig::downmix`.
 
+use crate::config::DownmixConfig;
 use crate::error::{HlsError, Result};
 use ffmpeg_next as ffmpeg;
 use ffmpeg_next::software::resampling;
 use ffmpeg_next::util::channel_layout::ChannelLayout;
 use ffmpeg_next::util::format::sample::Sample;
 
-/// Target sample rate for HLS-compatible AAC audio
-pub const HLS_SAMPLE_RATE: u32 = 48000;
 /// Target channel layout for HLS (stereo)
 pub const HLS_CHANNEL_LAYOUT: ChannelLayout = ChannelLayout::STEREO;
 /// Target sample format required by the AAC encoder
@@ -25,8 +26,13 @@ pub struct AudioResampler {
 
 impl AudioResampler {
     /// Create a resampler that converts the format described by `src_frame` to
-    /// the standard HLS output format (48 kHz, stereo, FLTP).
-    pub fn new(src_frame: &ffmpeg::util::frame::Audio, target_rate: u32) -> Result<Self> {
+    /// the standard HLS output format (stereo, FLTP) at `target_rate`. More
+    /// than two channels are mixed down with `downmix`.
+    pub fn new(
+        src_frame: &ffmpeg::util::frame::Audio,
+        target_rate: u32,
+        downmix: &DownmixConfig,
+    ) -> Result<Self> {
         let src_layout = if src_frame.channel_layout().bits() == 0 {
             // No channel layout set; fall back based on channel count
             match src_frame.channels() {
@@ -37,13 +43,19 @@ impl AudioResampler {
             src_frame.channel_layout()
         };
 
-        let context = resampling::Context::get(
+        let options = if src_frame.channels() > 2 {
+            downmix_options(downmix)
+        } else {
+            ffmpeg::Dictionary::new()
+        };
+        let context = resampling::Context::get_with(
             src_frame.format(),
             src_layout,
             src_frame.rate(),
             HLS_SAMPLE_FORMAT,
             HLS_CHANNEL_LAYOUT,
             target_rate,
+            options,
         )
         .map_err(|e| {
             HlsError::Ffmpeg(crate::error::FfmpegError::ReadFrame(format!(
@@ -116,22 +128,17 @@ impl AudioResampler {
     }
 }
 
-/// Get recommended AAC bitrate for a given channel count.
-pub fn get_recommended_bitrate(channels: u16) -> u64 {
-    match channels {
-        1 => 64_000,
-        2 => 128_000,
-        6 => 384_000,
-        8 => 512_000,
-        _ => 128_000,
-    }
-}
-
-/// Determine whether resampling is needed given the source frame parameters.
-pub fn needs_resampling(frame: &ffmpeg::util::frame::Audio) -> bool {
-    frame.rate() != HLS_SAMPLE_RATE
-        || frame.format() != HLS_SAMPLE_FORMAT
-        || frame.channel_layout() != HLS_CHANNEL_LAYOUT
+/// The `SwrContext` options of `downmix`.
+fn downmix_options(downmix: &DownmixConfig) -> ffmpeg::Dictionary<'static> {
+    let mut options = ffmpeg::Dictionary::new();
+    options.set("matrix_encoding", downmix.mode.matrix_encoding());
+    options.set("center_mix_level", &downmix.center_mix_level.to_string());
+    options.set(
+        "surround_mix_level",
+        &downmix.surround_mix_level.to_string(),
+    );
+    options.set("lfe_mix_level", &downmix.lfe_mix_level.to_string());
+    options
 }
 
 #[cfg(test)]
@@ -139,40 +146,15 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_to_hls_sample_rate() {
-        assert_eq!(HLS_SAMPLE_RATE, 48000);
-    }
-
-    #[test]
-    fn test_get_channel_count_mono() {
-        assert_eq!(get_recommended_bitrate(1), 64_000);
-    }
-
-    #[test]
-    fn test_get_channel_count_stereo() {
-        assert_eq!(get_recommended_bitrate(2), 128_000);
-    }
-
-    #[test]
-    fn test_get_channel_count_5_1() {
-        assert_eq!(get_recommended_bitrate(6), 384_000);
-    }
-
-    #[test]
-    fn test_get_channel_count_default() {
-        assert_eq!(get_recommended_bitrate(0), 128_000);
-        assert_eq!(get_recommended_bitrate(4), 128_000);
-    }
-
-    #[test]
-    fn test_resampler_config_default() {
-        assert_eq!(HLS_SAMPLE_RATE, 48000);
-    }
-
-    #[test]
-    fn test_needs_resampling() {
-        // We cannot construct real AVFrame easily, but we can at least test
-        // the constants are sane
-        assert_eq!(HLS_CHANNEL_LAYOUT, ChannelLayout::STEREO);
+    fn test_downmix_options() {
+        let downmix = DownmixConfig {
+            mode: crate::config::DownmixMode::Dplii,
+            center_mix_level: 1.0,
+            ..Default::default()
+        };
+        let options = downmix_options(&downmix);
+        assert_eq!(options.get("matrix_encoding"), Some("dplii"));
+        assert_eq!(options.get("center_mix_level"), Some("1"));
+        assert_eq!(options.get("lfe_mix_level"), Some("0"));
     }
 }
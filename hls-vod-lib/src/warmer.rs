@@ -0,0 +1,532 @@
+//! Background warming of popular titles.
+//!
+//! The look-ahead workers generate the next few segments of a stream that is
+//! playing. The warmer goes further: while no player is waiting for a
+//! segment, it generates every segment of the most watched open streams into
+//! the segment cache, so that later playback of those titles doesn't have to
+//! wait for FFmpeg.
+//!
+//! Popularity comes from the segment cache: the accesses of the cached
+//! segments of every stream. Segments of the warmer are cached without an
+//! access, so a stream doesn't keep itself popular by being warmed. Warmed
+//! segments that got evicted are not warmed again: the cache had a better
+//! use for the memory, and warming them would only evict something else.
+//! The renditions to warm are the ones that have
+//! segments in the cache, e.g. `v/0+1-aac.*.m4s` of an interleaved stream.
+//! Jobs are taken from a priority queue, most accessed stream first and
+//! then in playback order, and the round stops as soon as a player request
+//! comes in, or when the warmed segments fill the configured share of the
+//! segment cache.
+//!
+//! Streams need stable ids (`SegmentCacheConfig::stable_stream_ids`) for
+//! warmed segments to be found again by new sessions of the same title.
+//!
+//! Across restarts, the hot set of the cache can be exported with
+//! `export_hot_set()`: the streams and the keys of their cached media
+//! segments, not the segments themselves. `rewarm()` opens those streams
+//! again under the same ids, so the sessions of players keep working, and
+//! regenerates the segments in the background, most accessed stream first.
+
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BinaryHeap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::{get_stream_by_id, segment_cache, SegmentCache};
+use crate::media::StreamIndex;
+use crate::params::{DefaultUrlCodec, HlsParams, UrlCodec, UrlType};
+
+/// Unix time of the last player request for a media segment.
+static LAST_REQUEST: AtomicU64 = AtomicU64::new(0);
+static STARTED: AtomicBool = AtomicBool::new(false);
+/// Cache keys (`SegmentCache::make_key`) of the segments the warmer
+/// generated, of the streams that are still open.
+static WARMED: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// Warmer configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WarmerConfig {
+    /// Run the warmer
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Only warm after no player has requested a segment for this many seconds
+    #[serde(default = "default_idle_secs")]
+    pub idle_secs: u64,
+
+    /// Seconds between warming rounds
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+
+    /// Number of most accessed streams to warm
+    #[serde(default = "default_max_streams")]
+    pub max_streams: usize,
+
+    /// Share of the segment cache memory that one round may fill, in percent
+    #[serde(default = "default_cache_percent")]
+    pub cache_percent: usize,
+}
+
+fn default_idle_secs() -> u64 {
+    30
+}
+
+fn default_interval_secs() -> u64 {
+    60
+}
+
+fn default_max_streams() -> usize {
+    3
+}
+
+fn default_cache_percent() -> usize {
+    50
+}
+
+impl Default for WarmerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            idle_secs: default_idle_secs(),
+            interval_secs: default_interval_secs(),
+            max_streams: default_max_streams(),
+            cache_percent: default_cache_percent(),
+        }
+    }
+}
+
+/// Start the warmer thread.
+///
+/// Call once at startup, after `init_segment_cache()`. Does nothing if the
+/// warmer is not enabled or already running.
+pub fn start_warmer(config: WarmerConfig) {
+    if !config.enabled || STARTED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    tracing::info!(
+        "Warming the {} most accessed streams after {}s idle",
+        config.max_streams,
+        config.idle_secs
+    );
+    std::thread::Builder::new()
+        .name("hls-warmer".to_string())
+        .spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(config.interval_secs.max(1)));
+            if is_idle(config.idle_secs) {
+                let (segments, bytes) = run_round(&config);
+                if segments > 0 {
+                    tracing::info!("warmer: generated {} segments, {} bytes", segments, bytes);
+                }
+            }
+        })
+        .expect("Failed to spawn warmer thread");
+}
+
+/// Note that a player requested a media segment; the warmer backs off.
+pub(crate) fn note_request() {
+    LAST_REQUEST.store(unix_time(), Ordering::Relaxed);
+}
+
+fn is_idle(idle_secs: u64) -> bool {
+    unix_time().saturating_sub(LAST_REQUEST.load(Ordering::Relaxed)) >= idle_secs
+}
+
+// helper.
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// A segment to warm.
+struct Job {
+    /// Accesses of the stream in the segment cache
+    priority: usize,
+    sequence: usize,
+    stream: Arc<StreamIndex>,
+    params: HlsParams,
+}
+
+impl PartialEq for Job {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Job {}
+
+impl PartialOrd for Job {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Job {
+    // Most accessed stream first, then the earliest segment.
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// Media segment `sequence` of the rendition of `params`, which must be a
+/// single media segment.
+fn segment_params(params: &HlsParams, sequence: usize) -> Option<HlsParams> {
+    let url_type = match &params.url_type {
+        UrlType::VideoSegment(v) if v.segment_id.is_some() && v.end_segment_id.is_none() => {
+            UrlType::VideoSegment(crate::params::VideoSegment {
+                segment_id: Some(sequence),
+                with_init: false,
+                ..v.clone()
+            })
+        }
+        UrlType::AudioSegment(a) if a.segment_id.is_some() && a.end_segment_id.is_none() => {
+            UrlType::AudioSegment(crate::params::AudioSegment {
+                segment_id: Some(sequence),
+                with_init: false,
+                ..a.clone()
+            })
+        }
+        _ => return None,
+    };
+    Some(HlsParams {
+        url_type,
+        session_id: params.session_id.clone(),
+        video_url: params.video_url.clone(),
+    })
+}
+
+/// The renditions of `stream` that have media segments in the cache, as the
+/// parameters of their first segment.
+fn cached_renditions(stream: &StreamIndex, keys: &[String]) -> Vec<HlsParams> {
+    let mut seen = HashSet::new();
+    let mut renditions = Vec::new();
+    for key in keys {
+        // Cache keys are URLs of the default scheme, relative to the
+        // session; the video name only has to look like one.
+        let url = format!("title.mp4/{}/{}", stream.stream_id, key);
+        let Some(params) = DefaultUrlCodec.parse(&url) else {
+            continue;
+        };
+        let Some(mut first) = segment_params(&params, 0) else {
+            continue;
+        };
+        if seen.insert(first.to_string()) {
+            first.video_url = stream.source_path.to_string_lossy().to_string();
+            renditions.push(first);
+        }
+    }
+    renditions
+}
+
+/// The segments of the renditions of `stream` that have cached segments
+/// (`keys`), that are neither cached nor `warmed` before.
+fn stream_jobs(
+    stream: &Arc<StreamIndex>,
+    accesses: usize,
+    keys: &[String],
+    warmed: &HashSet<String>,
+) -> Vec<Job> {
+    let mut jobs = Vec::new();
+    for rendition in cached_renditions(stream, keys) {
+        for sequence in 0..stream.segment_count() {
+            let Some(params) = segment_params(&rendition, sequence) else {
+                continue;
+            };
+            let key = params.to_string();
+            if keys.contains(&key) {
+                continue;
+            }
+            // Warmed, and evicted since.
+            if warmed.contains(&SegmentCache::make_key(&stream.stream_id, &key)) {
+                continue;
+            }
+            jobs.push(Job {
+                priority: accesses,
+                sequence,
+                stream: stream.clone(),
+                params,
+            });
+        }
+    }
+    jobs
+}
+
+/// The segments of the `max_streams` most accessed streams that are not in
+/// the cache yet.
+fn plan(max_streams: usize) -> BinaryHeap<Job> {
+    let mut jobs = BinaryHeap::new();
+    let Some(cache) = segment_cache() else {
+        return jobs;
+    };
+    let mut warmed = WARMED.lock().unwrap_or_else(|e| e.into_inner());
+    let warmed = warmed.get_or_insert_with(HashSet::new);
+    warmed.retain(|key| {
+        key.split_once(':')
+            .is_some_and(|(stream_id, _)| get_stream_by_id(stream_id).is_some())
+    });
+
+    let popular = cache
+        .stream_accesses()
+        .into_iter()
+        .filter_map(|(stream_id, accesses)| Some((get_stream_by_id(&stream_id)?, accesses)))
+        .take(max_streams);
+    for (stream, accesses) in popular {
+        let keys = cache.stream_keys(&stream.stream_id);
+        jobs.extend(stream_jobs(&stream, accesses, &keys, warmed));
+    }
+    jobs
+}
+
+/// Warm segments until a player request comes in or the budget is used up.
+/// Returns the number of segments and bytes generated.
+fn run_round(config: &WarmerConfig) -> (usize, usize) {
+    let idle = || is_idle(config.idle_secs);
+    run_jobs(
+        plan(config.max_streams),
+        config.cache_percent,
+        "warmer",
+        idle,
+    )
+}
+
+/// Generate the jobs in priority order while `keep_going` returns true, and
+/// until they fill `cache_percent` of the segment cache memory. Returns the
+/// number of segments and bytes generated.
+fn run_jobs(
+    mut jobs: BinaryHeap<Job>,
+    cache_percent: usize,
+    what: &str,
+    keep_going: impl Fn() -> bool,
+) -> (usize, usize) {
+    let Some(cache) = segment_cache() else {
+        return (0, 0);
+    };
+    let budget = cache.stats().memory_limit_bytes / 100 * cache_percent.min(100);
+    let (mut segments, mut bytes) = (0, 0);
+    while let Some(job) = jobs.pop() {
+        if bytes >= budget || !keep_going() {
+            break;
+        }
+        let key = SegmentCache::make_key(&job.stream.stream_id, &job.params.to_string());
+        if let Some(size) = crate::lookahead::pregenerate(&job.stream, job.params, what, true) {
+            segments += 1;
+            bytes += size;
+            let mut warmed = WARMED.lock().unwrap_or_else(|e| e.into_inner());
+            warmed.get_or_insert_with(HashSet::new).insert(key);
+        }
+    }
+    (segments, bytes)
+}
+
+/// A stream of the hot set of the segment cache, see `export_hot_set()`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HotStream {
+    pub stream_id: String,
+    /// Path of the source file
+    pub path: PathBuf,
+    /// Version of the source file that was indexed. Streams of a file that
+    /// changed since are not warmed again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_version: Option<String>,
+    /// Accesses of the cached segments of the stream
+    pub accesses: usize,
+    /// Cache keys of the cached media segments, relative to the session,
+    /// e.g. `v/0+1-aac.3.m4s`
+    pub segments: Vec<String>,
+}
+
+/// The media segment `key` of `stream` (a cache key, see `HotStream`), and
+/// its sequence number. `None` for other keys, such as playlists.
+fn media_segment(stream: &StreamIndex, key: &str) -> Option<(HlsParams, usize)> {
+    // As in `cached_renditions`, the video name only has to look like one.
+    let url = format!("title.mp4/{}/{}", stream.stream_id, key);
+    let mut params = DefaultUrlCodec.parse(&url)?;
+    let sequence = match &params.url_type {
+        UrlType::VideoSegment(v) if v.end_segment_id.is_none() => v.segment_id?,
+        UrlType::AudioSegment(a) if a.end_segment_id.is_none() => a.segment_id?,
+        _ => return None,
+    };
+    segment_params(&params, sequence)?;
+    params.video_url = stream.source_path.to_string_lossy().to_string();
+    Some((params, sequence))
+}
+
+/// The hot set of the segment cache: the `max_streams` most accessed open
+/// streams, with the keys of their cached media segments.
+pub fn export_hot_set(max_streams: usize) -> Vec<HotStream> {
+    let Some(cache) = segment_cache() else {
+        return Vec::new();
+    };
+    cache
+        .stream_accesses()
+        .into_iter()
+        .filter_map(|(stream_id, accesses)| Some((get_stream_by_id(&stream_id)?, accesses)))
+        .take(max_streams)
+        .map(|(stream, accesses)| {
+            let mut segments: Vec<(usize, String)> = cache
+                .stream_keys(&stream.stream_id)
+                .into_iter()
+                .filter_map(|key| Some((media_segment(&stream, &key)?.1, key)))
+                .collect();
+            segments.sort();
+            HotStream {
+                stream_id: stream.stream_id.clone(),
+                path: stream.source_path.clone(),
+                source_version: stream.source_version.clone(),
+                accesses,
+                segments: segments.into_iter().map(|(_, key)| key).collect(),
+            }
+        })
+        .collect()
+}
+
+/// Open the streams of a hot set exported with `export_hot_set()` again,
+/// and regenerate their segments into the segment cache in a background
+/// thread, until they fill `cache_percent` of it.
+///
+/// Streams whose source file changed or is gone are skipped. Returns the
+/// number of segments queued.
+pub fn rewarm(streams: Vec<HotStream>, cache_percent: usize) -> usize {
+    if segment_cache().is_none() {
+        return 0;
+    }
+    let queued = streams.iter().map(|s| s.segments.len()).sum();
+    let spawned = std::thread::Builder::new()
+        .name("hls-rewarm".to_string())
+        .spawn(move || {
+            let mut jobs = BinaryHeap::new();
+            for hot in streams {
+                if let Some(indexed) = &hot.source_version {
+                    let current = crate::media::source_version(&hot.path).ok();
+                    if current.map(|(_, version)| version).as_ref() != Some(indexed) {
+                        tracing::info!("rewarm: {:?} changed, skipped", hot.path);
+                        continue;
+                    }
+                }
+                let stream = match StreamIndex::open(&hot.path, Some(hot.stream_id.clone())) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        tracing::warn!("rewarm: {:?}: {}", hot.path, e);
+                        continue;
+                    }
+                };
+                for key in &hot.segments {
+                    if let Some((params, sequence)) = media_segment(&stream, key) {
+                        jobs.push(Job {
+                            priority: hot.accesses,
+                            sequence,
+                            stream: stream.clone(),
+                            params,
+                        });
+                    }
+                }
+            }
+            let (segments, bytes) = run_jobs(jobs, cache_percent, "rewarm", || true);
+            tracing::info!("rewarm: generated {} segments, {} bytes", segments, bytes);
+        });
+    match spawned {
+        Ok(_) => queued,
+        Err(e) => {
+            tracing::warn!("rewarm: failed to spawn thread: {}", e);
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_order() {
+        let stream = Arc::new(StreamIndex::new("/test/video.mp4".into()));
+        let params = DefaultUrlCodec.parse("video.mp4/abc/v/0.0.m4s").unwrap();
+        let job = |priority, sequence| Job {
+            priority,
+            sequence,
+            stream: stream.clone(),
+            params: params.clone(),
+        };
+        let mut jobs = BinaryHeap::from(vec![job(1, 0), job(5, 3), job(5, 1), job(2, 0)]);
+        let order: Vec<_> = std::iter::from_fn(|| jobs.pop())
+            .map(|j| (j.priority, j.sequence))
+            .collect();
+        assert_eq!(order, [(5, 1), (5, 3), (2, 0), (1, 0)]);
+    }
+
+    #[test]
+    fn test_media_segment() {
+        let stream = StreamIndex::new("/test/video.mp4".into());
+        let (params, sequence) = media_segment(&stream, "v/0+1-aac.3.m4s").unwrap();
+        assert_eq!(sequence, 3);
+        assert_eq!(params.to_string(), "v/0+1-aac.3.m4s");
+        assert_eq!(params.video_url, "/test/video.mp4");
+        assert_eq!(
+            params.session_id.as_deref(),
+            Some(stream.stream_id.as_str())
+        );
+        assert_eq!(media_segment(&stream, "a/2.5.m4s").unwrap().1, 5);
+        assert!(media_segment(&stream, "a/2.6-8.m4s").is_none());
+        assert!(media_segment(&stream, "v/0.init.mp4").is_none());
+        assert!(media_segment(&stream, "t.0+1-aac.m3u8").is_none());
+    }
+
+    #[test]
+    fn test_cached_renditions() {
+        let stream = StreamIndex::new("/test/video.mp4".into());
+        let keys: Vec<String> = [
+            "v/0+1-aac.3.m4s",
+            "v/0+1-aac.4.m4s",
+            "v/0+1-aac.0.hdr.m4s",
+            "v/0.init.mp4",
+            "a/2.5.m4s",
+            "a/2.6-8.m4s",
+            "t.0+1-aac.m3u8",
+        ]
+        .iter()
+        .map(|k| k.to_string())
+        .collect();
+        let mut renditions: Vec<_> = cached_renditions(&stream, &keys)
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        renditions.sort();
+        assert_eq!(renditions, ["a/2.0.m4s", "v/0+1-aac.0.m4s"]);
+    }
+
+    #[test]
+    fn test_stream_jobs() {
+        let mut stream = StreamIndex::new("/test/video.mp4".into());
+        for sequence in 0..4 {
+            stream.segments.push(crate::media::SegmentInfo {
+                sequence,
+                start_pts: sequence as i64 * 4000,
+                end_pts: (sequence as i64 + 1) * 4000,
+                duration_secs: 4.0,
+                is_keyframe: true,
+                video_byte_offset: 0,
+            });
+        }
+        let stream = Arc::new(stream);
+        let keys = vec!["v/0.1.m4s".to_string()];
+        // Segment 2 was warmed in an earlier round, and evicted since.
+        let warmed = HashSet::from([SegmentCache::make_key(&stream.stream_id, "v/0.2.m4s")]);
+
+        let jobs = stream_jobs(&stream, 7, &keys, &warmed);
+        let jobs: Vec<_> = jobs
+            .iter()
+            .map(|j| (j.priority, j.params.to_string()))
+            .collect();
+        assert_eq!(
+            jobs,
+            [(7, "v/0.0.m4s".to_string()), (7, "v/0.3.m4s".to_string())]
+        );
+    }
+}
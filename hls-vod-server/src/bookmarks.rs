@@ -0,0 +1,251 @@
+//! Resume positions
+//!
+//! Players report how far they got in a title with `POST /progress`, per
+//! user. The next main playlist of that title for the same user (`?user=`)
+//! starts there, with `EXT-X-START`.
+//!
+//! A user is not just a name the client picks: every request carries a user
+//! token, an HMAC of the name with the `[auth]` secret (see `user_token`).
+//! The admin API hands them out with `GET /admin/user-token?user=`.
+//!
+//! Positions are kept in a `BookmarkStore`: in memory, or in a JSON file
+//! when `[bookmarks] path` is set, so they survive a restart. The file is
+//! written at most once a minute and on shutdown, see `BookmarkStore::flush`.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::config::BookmarksConfig;
+
+/// Storage of resume positions, in seconds, per user and title.
+pub trait BookmarkStore: Send + Sync {
+    /// The position `user` reported last for `title`.
+    fn get(&self, user: &str, title: &str) -> Option<f64>;
+
+    /// Store the position of `user` in `title`.
+    fn set(&self, user: &str, title: &str, position: f64) -> std::io::Result<()>;
+
+    /// Forget the position of `user` in `title`, e.g. when it was watched to the end.
+    fn remove(&self, user: &str, title: &str) -> std::io::Result<()>;
+
+    /// The number of titles `user` has a position in.
+    fn titles(&self, user: &str) -> usize;
+
+    /// Save the changes since the last flush, for stores that persist them.
+    fn flush(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Positions per user, per title.
+type Positions = HashMap<String, HashMap<String, f64>>;
+
+/// Resume positions that are lost on a restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    positions: Mutex<Positions>,
+}
+
+impl BookmarkStore for MemoryStore {
+    fn get(&self, user: &str, title: &str) -> Option<f64> {
+        self.positions.lock().get(user)?.get(title).copied()
+    }
+
+    fn set(&self, user: &str, title: &str, position: f64) -> std::io::Result<()> {
+        let mut positions = self.positions.lock();
+        positions
+            .entry(user.to_string())
+            .or_default()
+            .insert(title.to_string(), position);
+        Ok(())
+    }
+
+    fn remove(&self, user: &str, title: &str) -> std::io::Result<()> {
+        let mut positions = self.positions.lock();
+        if let Some(titles) = positions.get_mut(user) {
+            titles.remove(title);
+            if titles.is_empty() {
+                positions.remove(user);
+            }
+        }
+        Ok(())
+    }
+
+    fn titles(&self, user: &str) -> usize {
+        self.positions.lock().get(user).map_or(0, |t| t.len())
+    }
+}
+
+/// Resume positions saved in a JSON file.
+///
+/// Players report their position every few seconds, so changes are only
+/// written by `flush`. The file is written to a temporary file next to it
+/// and renamed, so a crash halfway never leaves a truncated file.
+pub struct FileStore {
+    path: PathBuf,
+    memory: MemoryStore,
+    // Changed since the last flush.
+    dirty: AtomicBool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BookmarkFile {
+    positions: Positions,
+}
+
+impl FileStore {
+    /// Open the store in `path`. A file that does not exist yet is empty.
+    pub fn open(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let path = path.into();
+        let positions = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice::<BookmarkFile>(&data)?.positions,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Positions::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            memory: MemoryStore {
+                positions: Mutex::new(positions),
+            },
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    fn save(&self, positions: Positions) -> std::io::Result<()> {
+        let file = BookmarkFile { positions };
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&file)?)?;
+        std::fs::rename(&tmp, &self.path)
+    }
+}
+
+impl BookmarkStore for FileStore {
+    fn get(&self, user: &str, title: &str) -> Option<f64> {
+        self.memory.get(user, title)
+    }
+
+    fn set(&self, user: &str, title: &str, position: f64) -> std::io::Result<()> {
+        self.memory.set(user, title, position)?;
+        self.dirty.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn remove(&self, user: &str, title: &str) -> std::io::Result<()> {
+        self.memory.remove(user, title)?;
+        self.dirty.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn titles(&self, user: &str) -> usize {
+        self.memory.titles(user)
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let positions = self.memory.positions.lock().clone();
+        self.save(positions).inspect_err(|_| {
+            self.dirty.store(true, Ordering::Relaxed);
+        })
+    }
+}
+
+/// Create the bookmark store of the configuration, if bookmarks are enabled.
+///
+/// If the file can't be read the positions are kept in memory, rather than
+/// overwriting the file with only the new ones.
+pub fn create_store(config: &BookmarksConfig) -> Option<Arc<dyn BookmarkStore>> {
+    if !config.enabled {
+        return None;
+    }
+    let Some(path) = &config.path else {
+        return Some(Arc::new(MemoryStore::default()));
+    };
+    match FileStore::open(path) {
+        Ok(store) => Some(Arc::new(store)),
+        Err(e) => {
+            tracing::error!(
+                "Failed to read bookmarks from {}: {}. Keeping them in memory.",
+                path,
+                e
+            );
+            Some(Arc::new(MemoryStore::default()))
+        }
+    }
+}
+
+/// The user token of `user`: the hex HMAC-SHA256 of its name with `secret`.
+pub fn user_token(secret: &str, user: &str) -> String {
+    crate::auth::sign(secret.as_bytes(), &format!("user/{}", user), 0)
+}
+
+/// Whether `token` is the user token of `user`.
+pub fn user_token_ok(secret: &str, user: &str, token: &str) -> bool {
+    crate::auth::constant_time_eq(&user_token(secret, user), token)
+}
+
+/// The title of a media URL path, the key of its positions.
+pub fn title(video_url: &str) -> &str {
+    video_url.trim_start_matches('/')
+}
+
+/// Where to start playing a title of `duration` seconds, given the stored
+/// `position`.
+///
+/// Positions close to the start are not worth resuming, and positions
+/// close to the end mean the title was watched: it starts from the top.
+pub fn resume_position(config: &BookmarksConfig, position: f64, duration: f64) -> Option<f64> {
+    (position >= config.min_position_secs && position < duration - config.end_margin_secs)
+        .then_some(position)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bookmarks.json");
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.get("alice", "movies/film.mkv"), None);
+        store.set("alice", "movies/film.mkv", 754.5).unwrap();
+        store.set("bob", "movies/film.mkv", 12.0).unwrap();
+        store.remove("bob", "movies/film.mkv").unwrap();
+        assert_eq!(store.titles("alice"), 1);
+        assert_eq!(store.titles("bob"), 0);
+
+        // Nothing is written until the store is flushed.
+        assert!(!path.exists());
+        store.flush().unwrap();
+
+        // Reopened, as after a restart.
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.get("alice", "movies/film.mkv"), Some(754.5));
+        assert_eq!(store.get("bob", "movies/film.mkv"), None);
+    }
+
+    #[test]
+    fn test_user_token() {
+        let token = user_token("secret", "alice");
+        assert!(user_token_ok("secret", "alice", &token));
+        assert!(!user_token_ok("secret", "bob", &token));
+        assert!(!user_token_ok("other", "alice", &token));
+        assert!(!user_token_ok("secret", "alice", ""));
+    }
+
+    #[test]
+    fn test_resume_position() {
+        let config = BookmarksConfig::default();
+        assert_eq!(resume_position(&config, 5.0, 3600.0), None);
+        assert_eq!(resume_position(&config, 754.5, 3600.0), Some(754.5));
+        assert_eq!(resume_position(&config, 3590.0, 3600.0), None);
+    }
+}
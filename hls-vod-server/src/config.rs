@@ -1,6 +1,8 @@
 //! Server configuration
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
 
 pub use hls_vod_lib::cache::SegmentCacheConfig;
 
@@ -15,6 +17,50 @@ pub struct SegmentConfig {
 
     /// Maximum segment duration (tolerance)
     pub max_duration_secs: f64,
+
+    /// Target duration of segments in audio-only main playlists, 0 = same as video
+    #[serde(default)]
+    pub audio_segment_duration_secs: f64,
+
+    /// Compute keyframe statistics of video tracks and align segments to whole GOPs
+    #[serde(default)]
+    pub video_stats: bool,
+
+    /// Brands and box versions of segments, and the playlist version
+    #[serde(default)]
+    pub compatibility_profile: hls_vod_lib::CompatibilityProfile,
+
+    /// Retry and fallback policy for failed segments
+    #[serde(default)]
+    pub retry: hls_vod_lib::RetryPolicy,
+
+    /// Serve the main playlist of a new session before the segment index is built
+    #[serde(default)]
+    pub fast_start: bool,
+
+    /// Files with more segments than this get longer ones, 0 = no limit
+    #[serde(default = "default_max_segments")]
+    pub max_segments: usize,
+
+    /// Playlist delta updates (`EXT-X-SKIP`) for players that ask with `_HLS_skip`
+    #[serde(default)]
+    pub delta_updates: bool,
+
+    /// Maximum size of a variant playlist in bytes, 0 = no limit
+    #[serde(default)]
+    pub playlist_byte_budget: usize,
+
+    /// Negative timestamp policy, `None` = that of the compatibility profile
+    #[serde(default)]
+    pub negative_ts: Option<hls_vod_lib::NegativeTsPolicy>,
+}
+
+fn default_max_segments() -> usize {
+    hls_vod_lib::DEFAULT_MAX_SEGMENTS
+}
+
+fn default_worker_timeout_secs() -> u64 {
+    120
 }
 
 impl Default for SegmentConfig {
@@ -23,6 +69,15 @@ impl Default for SegmentConfig {
             target_duration_secs: 4.0,
             min_duration_secs: 3.0,
             max_duration_secs: 6.0,
+            audio_segment_duration_secs: 0.0,
+            video_stats: false,
+            compatibility_profile: hls_vod_lib::CompatibilityProfile::default(),
+            retry: hls_vod_lib::RetryPolicy::default(),
+            fast_start: false,
+            max_segments: default_max_segments(),
+            delta_updates: false,
+            playlist_byte_budget: 0,
+            negative_ts: None,
         }
     }
 }
@@ -33,11 +88,35 @@ pub struct AudioConfig {
     /// Target sample rate for AAC output
     pub target_sample_rate: u32,
 
-    /// AAC bitrate in bps
+    /// AAC bitrate of stereo audio in bps (other channel counts: library defaults)
     pub aac_bitrate: u64,
 
     /// Enable audio transcoding
     pub enable_transcoding: bool,
+
+    /// AAC encoder implementation (`aac` or `libfdk_aac`)
+    #[serde(default)]
+    pub aac_encoder: hls_vod_lib::AacEncoderImpl,
+
+    /// VBR mode 1-5 (`libfdk_aac` only)
+    #[serde(default)]
+    pub aac_vbr: Option<u8>,
+
+    /// Bitstream filters for passthrough audio, per codec (library defaults if unset)
+    #[serde(default)]
+    pub bitstream_filters: Option<HashMap<String, String>>,
+
+    /// TrueHD/DTS tracks: transcode to AAC (`aac`) or leave out (`exclude`)
+    #[serde(default)]
+    pub hd_audio: hls_vod_lib::HdAudio,
+
+    /// Stereo downmix of multichannel audio (ITU or Dolby Pro Logic II)
+    #[serde(default)]
+    pub downmix: hls_vod_lib::DownmixConfig,
+
+    /// Volumes of the tracks of an audio mix (`?audio_mix=`)
+    #[serde(default)]
+    pub mix: hls_vod_lib::AudioMixConfig,
 }
 
 impl Default for AudioConfig {
@@ -46,12 +125,130 @@ impl Default for AudioConfig {
             target_sample_rate: 48000,
             aac_bitrate: 128000,
             enable_transcoding: true,
+            aac_encoder: hls_vod_lib::AacEncoderImpl::Native,
+            aac_vbr: None,
+            bitstream_filters: None,
+            hd_audio: hls_vod_lib::HdAudio::default(),
+            downmix: hls_vod_lib::DownmixConfig::default(),
+            mix: hls_vod_lib::AudioMixConfig::default(),
         }
     }
 }
 
-/// Server configuration
+/// Subtitle configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubtitleConfig {
+    /// Merge overlapping cues (common in ASS/SSA) into non-overlapping WebVTT cues
+    pub merge_overlapping_cues: bool,
+
+    /// Default timestamp mode of WebVTT segments (`zero` or `mpegts`)
+    #[serde(default)]
+    pub timestamps: hls_vod_lib::SubtitleTimestamps,
+
+    /// Segments a cue crossing a segment boundary is written to (`start` or `repeat`)
+    #[serde(default)]
+    pub spanning_cues: hls_vod_lib::SpanningCues,
+
+    /// Extract the cues of all text subtitle tracks in one pass over the file
+    #[serde(default)]
+    pub batch_extraction: bool,
+}
+
+impl Default for SubtitleConfig {
+    fn default() -> Self {
+        Self {
+            merge_overlapping_cues: true,
+            timestamps: hls_vod_lib::SubtitleTimestamps::Zero,
+            spanning_cues: hls_vod_lib::SpanningCues::Start,
+            batch_extraction: false,
+        }
+    }
+}
+
+/// Response compression configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompressionConfig {
+    /// Compress playlists and WebVTT segments (gzip or brotli, per Accept-Encoding)
+    pub enabled: bool,
+
+    /// Don't compress responses smaller than this many bytes
+    pub min_size: usize,
+
+    /// Number of compressed responses to keep, so hot playlists are compressed once
+    pub cache_entries: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_size: 1024,
+            cache_entries: 256,
+        }
+    }
+}
+
+/// URL signing configuration
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AuthConfig {
+    /// HMAC-SHA256 key used to sign playlist and segment URLs
+    pub secret: String,
+
+    /// How long a signed URL stays valid, in seconds
+    pub url_ttl_secs: u64,
+}
+
+/// Resume positions configuration, see `crate::bookmarks`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BookmarksConfig {
+    /// Store the positions players report to `/progress`, and resume there
+    pub enabled: bool,
+
+    /// JSON file the positions are saved in. If not set, they are kept in memory.
+    pub path: Option<String>,
+
+    /// Start from the top if the position is less than this many seconds in
+    pub min_position_secs: f64,
+
+    /// Start from the top if the position is this close to the end, in seconds
+    pub end_margin_secs: f64,
+
+    /// Most titles a user can have a position in; reports of more are refused
+    pub max_titles_per_user: usize,
+}
+
+impl Default for BookmarksConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: None,
+            min_position_secs: 30.0,
+            end_margin_secs: 60.0,
+            max_titles_per_user: 1000,
+        }
+    }
+}
+
+/// A named media root
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MediaRoot {
+    /// Name of the root, the first component of the URL path
+    pub name: String,
+
+    /// Directory on disk the rest of the URL path is relative to
+    pub path: String,
+
+    /// If not empty, requests need one of these tokens (`?token=` or a bearer token)
+    #[serde(default)]
+    pub readonly_tokens: Vec<String>,
+}
+
+/// Server configuration
+///
+/// The `Debug` output, which is logged at startup, leaves out the secrets:
+/// the signing key, the admin token and the media root tokens.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     /// Host address to bind to
     pub host: String,
@@ -62,23 +259,91 @@ pub struct ServerConfig {
     /// Cache configuration
     pub cache: SegmentCacheConfig,
 
+    /// Background warming of popular titles
+    #[serde(default)]
+    pub warmer: hls_vod_lib::warmer::WarmerConfig,
+
     /// Segment configuration
     pub segment: SegmentConfig,
 
     /// Audio configuration
     pub audio: AudioConfig,
 
+    /// Subtitle configuration
+    #[serde(default)]
+    pub subtitles: SubtitleConfig,
+
+    /// Response compression configuration
+    #[serde(default)]
+    pub compression: CompressionConfig,
+
     /// Enable CORS
     pub cors_enabled: bool,
 
     /// Log level (trace, debug, info, warn, error)
     pub log_level: String,
 
+    /// FFmpeg log capture: suppressed messages and rate limit
+    #[serde(default)]
+    pub ffmpeg_log: hls_vod_lib::FfmpegLogConfig,
+
     /// Maximum concurrent streams
     pub max_concurrent_streams: Option<usize>,
 
-    /// Rate limit requests per second
+    /// Rate limit requests per second, per client IP
     pub rate_limit_rps: Option<u32>,
+
+    /// Maximum request body size in MB
+    #[serde(default)]
+    pub max_request_size_mb: Option<usize>,
+
+    /// Maximum length of the request path and query
+    #[serde(default)]
+    pub max_url_length: Option<usize>,
+
+    /// HDCP-LEVEL attribute for video variants (TYPE-0, TYPE-1 or NONE)
+    pub hdcp_level: Option<String>,
+
+    /// Answer main playlist requests with `202 Accepted` and `Retry-After` if
+    /// indexing the file takes longer than this many seconds, 0 = wait for it.
+    /// Also the longest `Retry-After`.
+    #[serde(default)]
+    pub open_wait_secs: u64,
+
+    /// Signed URLs. If set, every request except the main playlist needs a valid signature.
+    pub auth: Option<AuthConfig>,
+
+    /// Generate segments in this many worker processes (crash isolation), 0 = in-process
+    #[serde(default)]
+    pub workers: usize,
+
+    /// Kill a worker process that takes longer than this to generate a segment
+    #[serde(default = "default_worker_timeout_secs")]
+    pub worker_timeout_secs: u64,
+
+    /// Send a `Repr-Digest` (SHA-256) header with playlists and segments
+    #[serde(default)]
+    pub repr_digest: bool,
+
+    /// Order and select the default audio and subtitle tracks by `Accept-Language`
+    #[serde(default)]
+    pub accept_language: bool,
+
+    /// Bearer token for the admin API. If not set, the admin API is disabled.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+
+    /// Named media roots. If empty, URL paths are filesystem paths.
+    #[serde(default)]
+    pub media_roots: Vec<MediaRoot>,
+
+    /// Extensions of the source files URLs can address (library defaults if unset)
+    #[serde(default)]
+    pub source_extensions: Option<Vec<String>>,
+
+    /// Resume positions reported by players
+    #[serde(default)]
+    pub bookmarks: BookmarksConfig,
 }
 
 impl Default for ServerConfig {
@@ -87,16 +352,90 @@ impl Default for ServerConfig {
             host: "0.0.0.0".to_string(),
             port: 3000,
             cache: SegmentCacheConfig::default(),
+            warmer: hls_vod_lib::warmer::WarmerConfig::default(),
             segment: SegmentConfig::default(),
             audio: AudioConfig::default(),
+            subtitles: SubtitleConfig::default(),
+            compression: CompressionConfig::default(),
             cors_enabled: true,
             log_level: "info".to_string(),
+            ffmpeg_log: hls_vod_lib::FfmpegLogConfig::default(),
             max_concurrent_streams: Some(100),
             rate_limit_rps: Some(100),
+            max_request_size_mb: Some(10),
+            max_url_length: Some(8192),
+            hdcp_level: None,
+            open_wait_secs: 0,
+            auth: None,
+            workers: 0,
+            worker_timeout_secs: default_worker_timeout_secs(),
+            repr_digest: false,
+            accept_language: false,
+            admin_token: None,
+            media_roots: Vec::new(),
+            source_extensions: None,
+            bookmarks: BookmarksConfig::default(),
         }
     }
 }
 
+/// Stands in for a secret in `Debug` output.
+fn redact(secret: &Option<String>) -> Option<&'static str> {
+    secret.as_ref().map(|_| "***")
+}
+
+impl fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field("secret", &"***")
+            .field("url_ttl_secs", &self.url_ttl_secs)
+            .finish()
+    }
+}
+
+impl fmt::Debug for MediaRoot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MediaRoot")
+            .field("name", &self.name)
+            .field("path", &self.path)
+            .field("readonly_tokens", &vec!["***"; self.readonly_tokens.len()])
+            .finish()
+    }
+}
+
+impl fmt::Debug for ServerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("cache", &self.cache)
+            .field("warmer", &self.warmer)
+            .field("segment", &self.segment)
+            .field("audio", &self.audio)
+            .field("subtitles", &self.subtitles)
+            .field("compression", &self.compression)
+            .field("cors_enabled", &self.cors_enabled)
+            .field("log_level", &self.log_level)
+            .field("ffmpeg_log", &self.ffmpeg_log)
+            .field("max_concurrent_streams", &self.max_concurrent_streams)
+            .field("rate_limit_rps", &self.rate_limit_rps)
+            .field("max_request_size_mb", &self.max_request_size_mb)
+            .field("max_url_length", &self.max_url_length)
+            .field("hdcp_level", &self.hdcp_level)
+            .field("open_wait_secs", &self.open_wait_secs)
+            .field("auth", &self.auth)
+            .field("workers", &self.workers)
+            .field("worker_timeout_secs", &self.worker_timeout_secs)
+            .field("repr_digest", &self.repr_digest)
+            .field("accept_language", &self.accept_language)
+            .field("admin_token", &redact(&self.admin_token))
+            .field("media_roots", &self.media_roots)
+            .field("source_extensions", &self.source_extensions)
+            .field("bookmarks", &self.bookmarks)
+            .finish()
+    }
+}
+
 impl ServerConfig {
     /// Get the socket address string
     pub fn socket_addr(&self) -> String {
@@ -149,4 +488,27 @@ mod tests {
         };
         assert_eq!(config.socket_addr(), "127.0.0.1:8080");
     }
+
+    #[test]
+    fn test_debug_hides_secrets() {
+        let config = ServerConfig {
+            auth: Some(AuthConfig {
+                secret: "hmac-key".to_string(),
+                url_ttl_secs: 3600,
+            }),
+            admin_token: Some("admin-token".to_string()),
+            media_roots: vec![MediaRoot {
+                name: "movies".to_string(),
+                path: "/srv/movies".to_string(),
+                readonly_tokens: vec!["root-token".to_string()],
+            }],
+            ..Default::default()
+        };
+        let debug = format!("{:?}", config);
+        assert!(debug.contains("url_ttl_secs: 3600"));
+        assert!(debug.contains("/srv/movies"));
+        for secret in ["hmac-key", "admin-token", "root-token"] {
+            assert!(!debug.contains(secret), "{} in {}", secret, debug);
+        }
+    }
 }
@@ -3,6 +3,7 @@
 //! Loads server configuration from TOML files.
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::Path;
 
 use crate::config::ServerConfig;
@@ -18,10 +19,20 @@ pub struct ConfigFile {
     pub segment: SegmentSettings,
     /// Audio settings
     pub audio: AudioSettings,
+    /// Subtitle settings
+    pub subtitles: Option<SubtitleSettings>,
+    /// Compression settings
+    pub compression: Option<CompressionSettings>,
     /// Logging settings
     pub logging: Option<LoggingSettings>,
     /// Limits settings
     pub limits: Option<LimitsSettings>,
+    /// URL signing settings
+    pub auth: Option<crate::config::AuthConfig>,
+    /// Named media roots (`[[media_roots]]`)
+    pub media_roots: Option<Vec<crate::config::MediaRoot>>,
+    /// Resume positions (`[bookmarks]`)
+    pub bookmarks: Option<crate::config::BookmarksConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -32,6 +43,22 @@ pub struct ServerSettings {
     pub port: u16,
     /// Enable CORS
     pub cors_enabled: Option<bool>,
+    /// HDCP-LEVEL attribute for video variants (TYPE-0, TYPE-1 or NONE)
+    pub hdcp_level: Option<String>,
+    /// Number of worker processes for segment generation (crash isolation)
+    pub workers: Option<usize>,
+    /// Kill a worker that takes longer than this for a segment (default 120)
+    pub worker_timeout_secs: Option<u64>,
+    /// Answer 202 if indexing the file of a main playlist takes longer (0 = wait)
+    pub open_wait_secs: Option<u64>,
+    /// Send a `Repr-Digest` (SHA-256) header with playlists and segments
+    pub repr_digest: Option<bool>,
+    /// Order and select the default audio and subtitle tracks by `Accept-Language`
+    pub accept_language: Option<bool>,
+    /// Bearer token for the admin API (disabled if not set)
+    pub admin_token: Option<String>,
+    /// Extensions of the source files URLs can address (library defaults if unset)
+    pub source_extensions: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -44,6 +71,18 @@ pub struct CacheSettings {
     pub ttl_secs: u64,
     /// Number of segments to read ahead
     pub lookahead: usize,
+    /// Memory budget in MB for the cache, stream indexes and in-flight segments together
+    pub memory_budget_mb: Option<usize>,
+    /// Drop streams that have not been accessed for this many seconds
+    pub stream_timeout_secs: Option<u64>,
+    /// Keep streams of paused players for this many seconds after the last keep-alive
+    pub paused_timeout_secs: Option<u64>,
+    /// Derive stream ids from the file, so reopening a file reuses its id
+    pub stable_stream_ids: Option<bool>,
+    /// Which entries to evict when the cache is full: "lru" or "gdsf"
+    pub eviction_policy: Option<hls_vod_lib::cache::EvictionPolicy>,
+    /// Background warming of popular titles (`[cache.warmer]`)
+    pub warmer: Option<hls_vod_lib::warmer::WarmerConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -54,16 +93,68 @@ pub struct SegmentSettings {
     pub min_duration_secs: Option<f64>,
     /// Maximum segment duration
     pub max_duration_secs: Option<f64>,
+    /// Target duration of segments in audio-only playlists (0 = same as video)
+    pub audio_segment_duration_secs: Option<f64>,
+    /// Compute keyframe statistics of video tracks and align segments to whole GOPs
+    pub video_stats: Option<bool>,
+    /// Brands and box versions of segments, and the playlist version
+    pub compatibility_profile: Option<hls_vod_lib::CompatibilityProfile>,
+    /// Retry and fallback policy for failed segments
+    pub retry: Option<hls_vod_lib::RetryPolicy>,
+    /// Serve the main playlist of a new session before the segment index is built
+    pub fast_start: Option<bool>,
+    /// Files with more segments than this get longer ones (0 = no limit)
+    pub max_segments: Option<usize>,
+    /// Playlist delta updates (`EXT-X-SKIP`) for players that ask with `_HLS_skip`
+    pub delta_updates: Option<bool>,
+    /// Maximum size of a variant playlist in bytes (0 = no limit)
+    pub playlist_byte_budget: Option<usize>,
+    /// Negative timestamp policy (default: that of the compatibility profile)
+    pub negative_ts: Option<hls_vod_lib::NegativeTsPolicy>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AudioSettings {
     /// Target sample rate for AAC output
     pub target_sample_rate: u32,
-    /// AAC bitrate in bps
+    /// AAC bitrate of stereo audio in bps (other channel counts: library defaults)
     pub aac_bitrate: u64,
     /// Enable audio transcoding
     pub enable_transcoding: Option<bool>,
+    /// AAC encoder implementation (`aac` or `libfdk_aac`)
+    pub aac_encoder: Option<hls_vod_lib::AacEncoderImpl>,
+    /// VBR mode 1-5 (`libfdk_aac` only)
+    pub aac_vbr: Option<u8>,
+    /// Bitstream filters for passthrough audio, codec name -> filter chain
+    pub bitstream_filters: Option<HashMap<String, String>>,
+    /// TrueHD/DTS tracks: transcode to AAC (`aac`) or leave out (`exclude`)
+    pub hd_audio: Option<hls_vod_lib::HdAudio>,
+    /// Stereo downmix of multichannel audio (`mode` = `itu` or `dplii`, mix levels)
+    pub downmix: Option<hls_vod_lib::DownmixConfig>,
+    /// Volumes of the tracks of an audio mix (`main_volume`, `secondary_volume`)
+    pub mix: Option<hls_vod_lib::AudioMixConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubtitleSettings {
+    /// Merge overlapping cues into non-overlapping WebVTT cues
+    pub merge_overlapping_cues: Option<bool>,
+    /// Default timestamp mode of WebVTT segments (`zero` or `mpegts`)
+    pub timestamps: Option<hls_vod_lib::SubtitleTimestamps>,
+    /// Segments a cue crossing a segment boundary is written to
+    pub spanning_cues: Option<hls_vod_lib::SpanningCues>,
+    /// Extract the cues of all text subtitle tracks in one pass
+    pub batch_extraction: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompressionSettings {
+    /// Compress playlists and WebVTT segments
+    pub enabled: Option<bool>,
+    /// Minimum response size in bytes to compress
+    pub min_size: Option<usize>,
+    /// Number of compressed responses to cache
+    pub cache_entries: Option<usize>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -72,16 +163,20 @@ pub struct LoggingSettings {
     pub level: String,
     /// Output format (json, pretty)
     pub format: Option<String>,
+    /// FFmpeg log capture (`[logging.ffmpeg]`)
+    pub ffmpeg: Option<hls_vod_lib::FfmpegLogConfig>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LimitsSettings {
     /// Maximum concurrent streams
     pub max_concurrent_streams: Option<usize>,
-    /// Rate limit requests per second
+    /// Rate limit requests per second per client IP
     pub rate_limit_rps: Option<u32>,
     /// Maximum request body size in MB
     pub max_request_size_mb: Option<usize>,
+    /// Maximum length of the request path and query
+    pub max_url_length: Option<usize>,
 }
 
 impl ConfigFile {
@@ -106,32 +201,68 @@ impl ConfigFile {
                 host: "0.0.0.0".to_string(),
                 port: 3000,
                 cors_enabled: Some(true),
+                hdcp_level: None,
+                workers: None,
+                worker_timeout_secs: None,
+                open_wait_secs: None,
+                repr_digest: None,
+                accept_language: None,
+                admin_token: None,
+                source_extensions: None,
             },
             cache: CacheSettings {
                 max_memory_mb: 512,
                 max_segments: 100,
                 ttl_secs: 300,
                 lookahead: 2,
+                memory_budget_mb: None,
+                stream_timeout_secs: Some(600),
+                paused_timeout_secs: Some(3600),
+                stable_stream_ids: None,
+                eviction_policy: None,
+                warmer: None,
             },
             segment: SegmentSettings {
                 target_duration_secs: 4.0,
                 min_duration_secs: Some(3.0),
                 max_duration_secs: Some(6.0),
+                audio_segment_duration_secs: None,
+                video_stats: None,
+                compatibility_profile: None,
+                retry: None,
+                fast_start: None,
+                max_segments: None,
+                delta_updates: None,
+                playlist_byte_budget: None,
+                negative_ts: None,
             },
             audio: AudioSettings {
                 target_sample_rate: 48000,
                 aac_bitrate: 128000,
                 enable_transcoding: Some(true),
+                aac_encoder: None,
+                aac_vbr: None,
+                bitstream_filters: None,
+                hd_audio: None,
+                downmix: None,
+                mix: None,
             },
+            subtitles: None,
+            compression: None,
             logging: Some(LoggingSettings {
                 level: "info".to_string(),
                 format: Some("pretty".to_string()),
+                ffmpeg: None,
             }),
             limits: Some(LimitsSettings {
                 max_concurrent_streams: Some(100),
                 rate_limit_rps: Some(100),
                 max_request_size_mb: Some(10),
+                max_url_length: Some(8192),
             }),
+            auth: None,
+            media_roots: None,
+            bookmarks: None,
         }
     }
 
@@ -145,24 +276,101 @@ impl ConfigFile {
                 max_segments: self.cache.max_segments,
                 ttl_secs: self.cache.ttl_secs,
                 lookahead: self.cache.lookahead,
+                memory_budget_mb: self.cache.memory_budget_mb.unwrap_or(0),
+                stream_timeout_secs: self.cache.stream_timeout_secs.unwrap_or(600),
+                paused_timeout_secs: self.cache.paused_timeout_secs.unwrap_or(3600),
+                stable_stream_ids: self.cache.stable_stream_ids.unwrap_or(false),
+                eviction_policy: self.cache.eviction_policy.unwrap_or_default(),
             },
+            warmer: self.cache.warmer.unwrap_or_default(),
             segment: crate::config::SegmentConfig {
                 target_duration_secs: self.segment.target_duration_secs,
                 min_duration_secs: self.segment.min_duration_secs.unwrap_or(3.0),
                 max_duration_secs: self.segment.max_duration_secs.unwrap_or(6.0),
+                audio_segment_duration_secs: self
+                    .segment
+                    .audio_segment_duration_secs
+                    .unwrap_or(0.0),
+                video_stats: self.segment.video_stats.unwrap_or(false),
+                compatibility_profile: self.segment.compatibility_profile.unwrap_or_default(),
+                retry: self.segment.retry.unwrap_or_default(),
+                fast_start: self.segment.fast_start.unwrap_or(false),
+                max_segments: self
+                    .segment
+                    .max_segments
+                    .unwrap_or(hls_vod_lib::DEFAULT_MAX_SEGMENTS),
+                delta_updates: self.segment.delta_updates.unwrap_or(false),
+                playlist_byte_budget: self.segment.playlist_byte_budget.unwrap_or(0),
+                negative_ts: self.segment.negative_ts,
             },
             audio: crate::config::AudioConfig {
                 target_sample_rate: self.audio.target_sample_rate,
                 aac_bitrate: self.audio.aac_bitrate,
                 enable_transcoding: self.audio.enable_transcoding.unwrap_or(true),
+                aac_encoder: self.audio.aac_encoder.unwrap_or_default(),
+                aac_vbr: self.audio.aac_vbr,
+                bitstream_filters: self.audio.bitstream_filters,
+                hd_audio: self.audio.hd_audio.unwrap_or_default(),
+                downmix: self.audio.downmix.unwrap_or_default(),
+                mix: self.audio.mix.unwrap_or_default(),
+            },
+            subtitles: crate::config::SubtitleConfig {
+                merge_overlapping_cues: self
+                    .subtitles
+                    .as_ref()
+                    .and_then(|s| s.merge_overlapping_cues)
+                    .unwrap_or(true),
+                timestamps: self
+                    .subtitles
+                    .as_ref()
+                    .and_then(|s| s.timestamps)
+                    .unwrap_or_default(),
+                spanning_cues: self
+                    .subtitles
+                    .as_ref()
+                    .and_then(|s| s.spanning_cues)
+                    .unwrap_or_default(),
+                batch_extraction: self
+                    .subtitles
+                    .and_then(|s| s.batch_extraction)
+                    .unwrap_or(false),
+            },
+            compression: {
+                let default = crate::config::CompressionConfig::default();
+                let c = self.compression.as_ref();
+                crate::config::CompressionConfig {
+                    enabled: c.and_then(|c| c.enabled).unwrap_or(default.enabled),
+                    min_size: c.and_then(|c| c.min_size).unwrap_or(default.min_size),
+                    cache_entries: c
+                        .and_then(|c| c.cache_entries)
+                        .unwrap_or(default.cache_entries),
+                }
             },
             cors_enabled: self.server.cors_enabled.unwrap_or(true),
             log_level: self
                 .logging
-                .map(|l| l.level)
+                .as_ref()
+                .map(|l| l.level.clone())
                 .unwrap_or_else(|| "info".to_string()),
+            ffmpeg_log: self
+                .logging
+                .and_then(|l| l.ffmpeg)
+                .unwrap_or_default(),
             max_concurrent_streams: self.limits.as_ref().and_then(|l| l.max_concurrent_streams),
             rate_limit_rps: self.limits.as_ref().and_then(|l| l.rate_limit_rps),
+            max_request_size_mb: self.limits.as_ref().and_then(|l| l.max_request_size_mb),
+            max_url_length: self.limits.as_ref().and_then(|l| l.max_url_length),
+            hdcp_level: self.server.hdcp_level,
+            open_wait_secs: self.server.open_wait_secs.unwrap_or(0),
+            auth: self.auth,
+            workers: self.server.workers.unwrap_or(0),
+            worker_timeout_secs: self.server.worker_timeout_secs.unwrap_or(120),
+            repr_digest: self.server.repr_digest.unwrap_or(false),
+            accept_language: self.server.accept_language.unwrap_or(false),
+            admin_token: self.server.admin_token,
+            media_roots: self.media_roots.unwrap_or_default(),
+            source_extensions: self.server.source_extensions,
+            bookmarks: self.bookmarks.unwrap_or_default(),
         }
     }
 }
@@ -211,6 +419,33 @@ mod tests {
         assert_eq!(server_config.segment.target_duration_secs, 4.0);
     }
 
+    #[test]
+    fn test_media_roots() {
+        let mut temp_file = NamedTempFile::new().unwrap();
+        let mut content = toml::to_string_pretty(&ConfigFile::default_config()).unwrap();
+        content.push_str(
+            r#"
+[[media_roots]]
+name = "movies"
+path = "/srv/movies"
+
+[[media_roots]]
+name = "kids"
+path = "/srv/kids"
+readonly_tokens = ["abc"]
+"#,
+        );
+        temp_file.write_all(content.as_bytes()).unwrap();
+
+        let config = ConfigFile::from_file(temp_file.path())
+            .unwrap()
+            .into_server_config();
+        assert_eq!(config.media_roots.len(), 2);
+        assert_eq!(config.media_roots[0].name, "movies");
+        assert!(config.media_roots[0].readonly_tokens.is_empty());
+        assert_eq!(config.media_roots[1].readonly_tokens, vec!["abc"]);
+    }
+
     #[test]
     fn test_generate_default_config() {
         let temp_file = NamedTempFile::new().unwrap();
@@ -1,5 +1,7 @@
 //! Server-specific error types
 
+use axum::response::{IntoResponse, Response};
+use hls_vod_http::HttpError;
 use hls_vod_lib::HlsError;
 use thiserror::Error;
 
@@ -20,22 +22,18 @@ pub enum ServerError {
     Io(#[from] std::io::Error),
 }
 
-impl axum::response::IntoResponse for ServerError {
-    fn into_response(self) -> axum::response::Response {
-        let (status, body) = match self {
-            ServerError::Library(HlsError::StreamNotFound(id)) => (
-                axum::http::StatusCode::NOT_FOUND,
-                format!("Stream not found: {}", id),
-            ),
-            ServerError::Library(HlsError::SegmentNotFound { .. }) => {
-                (axum::http::StatusCode::NOT_FOUND, self.to_string())
-            }
-            _ => (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                self.to_string(),
-            ),
-        };
-
-        (status, body).into_response()
+impl From<ServerError> for HttpError {
+    fn from(err: ServerError) -> Self {
+        match err {
+            ServerError::Library(e) => e.into(),
+            ServerError::Io(e) => HlsError::Io(e).into(),
+            other => HttpError::InternalError(other.to_string()),
+        }
+    }
+}
+
+impl IntoResponse for ServerError {
+    fn into_response(self) -> Response {
+        HttpError::from(self).into_response()
     }
 }
@@ -1,20 +1,28 @@
 use std::sync::Arc;
 
 use super::handlers::HttpError;
+use super::middleware::{request_id_of, spawn_blocking};
 use crate::state::AppState;
-use axum::http::{header, HeaderMap, HeaderValue};
+use crate::worker::WorkerPool;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
 use axum::response::IntoResponse;
+use bytes::Bytes;
+use hls_vod_lib::hlsvideo::{ProgressObserver, SegmentProgress};
+use hls_vod_lib::media::StreamIndex;
 use hls_vod_lib::HlsVideo;
 
 /// Dynamic request handler mapped to `/*path`
 pub async fn handle_dynamic_request(
     axum::extract::State(state): axum::extract::State<Arc<AppState>>,
-    axum::extract::Path(path): axum::extract::Path<String>,
+    uri: axum::http::Uri,
     axum::extract::Query(query_params): axum::extract::Query<
         std::collections::HashMap<String, String>,
     >,
+    request_headers: HeaderMap,
 ) -> Result<axum::response::Response, HttpError> {
-    // Decode the URL.
+    // Decode the URL. HlsParams::parse does the percent-decoding, so it
+    // gets the path as it was requested.
+    let path = uri.path().trim_start_matches('/').to_string();
     tracing::info!("Raw URL path: {}", path);
     let hls_url = hls_vod_lib::HlsParams::parse(&path).ok_or_else(|| {
         HttpError::SegmentNotFound(format!(
@@ -25,43 +33,85 @@ pub async fn handle_dynamic_request(
     tracing::info!("Parsed HLS URL: {:?}", hls_url);
     tracing::info!("Parsed video_url: {}", hls_url.video_url);
 
-    // We simply take the url path as the path to the video.
-    let mut media_path = std::path::PathBuf::from(&hls_url.video_url);
-    tracing::info!(
-        "Initial check existence ({}): {}",
-        hls_url.video_url,
-        media_path.exists()
-    );
-
-    if !media_path.exists() {
-        if !hls_url.video_url.starts_with('/') {
-            let prefixed = format!("/{}", hls_url.video_url);
-            media_path = std::path::PathBuf::from(&prefixed);
-            tracing::info!(
-                "Prefixed check existence ({}): {}",
-                prefixed,
-                media_path.exists()
-            );
+    if let Some(codec) = &state.url_codec {
+        if !crate::auth::verify(codec, &hls_url, &query_params) {
+            return Err(HttpError::Forbidden(format!(
+                "Missing, invalid or expired signature: {}",
+                path
+            )));
         }
     }
 
-    if !media_path.exists() && !media_path.is_absolute() {
-        if let Ok(cwd) = std::env::current_dir() {
-            let joined = cwd.join(&hls_url.video_url);
-            tracing::info!(
-                "CWD joined check ({}): {}",
-                joined.display(),
-                joined.exists()
-            );
-            if joined.exists() {
-                media_path = joined;
-            }
-        }
+    // With signed URLs, only the main playlist needs the token of the root:
+    // the signatures of the URLs in it prove the rest was authorized. A
+    // signed main playlist URL (from the season manifest) is authorized too.
+    // The token is checked before the path is resolved, see `check_root_token`.
+    let signed = state.url_codec.as_ref().is_some_and(|codec| {
+        !hls_url.is_main_playlist() || crate::auth::is_signed(codec, &hls_url, &query_params)
+    });
+    if !signed {
+        check_root_token(&state, &hls_url.video_url, &query_params, &request_headers)?;
     }
+
+    let media_path = resolve_in_roots(&state, &hls_url.video_url)?;
     tracing::info!("FINAL Resolved media path: {:?}", media_path);
 
+    // A byte range of a segment, e.g. the `EXT-X-MAP` byterange of a
+    // combined init playlist. Playlists are always sent whole.
+    let range = request_headers
+        .get(header::RANGE)
+        .filter(|_| !hls_url.is_playlist())
+        .cloned();
+
+    // With crash isolation, segments are generated in a worker process.
+    if let Some(workers) = &state.workers {
+        if !hls_url.is_playlist() {
+            let request_id = request_id_of(&request_headers);
+            return generate_in_worker(
+                Arc::clone(workers),
+                media_path,
+                path,
+                &hls_url,
+                request_id,
+                range,
+            )
+            .await;
+        }
+    }
+
+    // A delta update of a variant playlist, see `hls_vod_lib::playlist_delta_update()`.
+    let delta_update = hls_url.is_playlist()
+        && !hls_url.is_main_playlist()
+        && matches!(
+            query_params.get("_HLS_skip").map(|v| v.as_str()),
+            Some("YES") | Some("v2")
+        );
+
+    let hdcp_level = state.config.hdcp_level.clone();
+    let open_wait_secs = state.config.open_wait_secs;
+    // Resume where the user (`?user=`) left off, if the user token is
+    // valid, see `crate::bookmarks`.
+    let user_ok = |user: &str| match (&state.config.auth, query_params.get("user_token")) {
+        (Some(auth), Some(token)) => crate::bookmarks::user_token_ok(&auth.secret, user, token),
+        _ => false,
+    };
+    let bookmark = match (&state.bookmarks, query_params.get("user")) {
+        (Some(store), Some(user)) if hls_url.is_main_playlist() && user_ok(user) => {
+            store.get(user, crate::bookmarks::title(&hls_url.video_url))
+        }
+        _ => None,
+    };
+    let bookmarks_config = state.config.bookmarks.clone();
+    // Audio and subtitles in the client's languages first.
+    let accept_language = state.config.accept_language && hls_url.is_main_playlist();
+    let languages = if accept_language {
+        accept_languages(&request_headers)
+    } else {
+        Vec::new()
+    };
+
     // All code is sync, so spawn it in a separate thread.
-    tokio::task::spawn_blocking(move || {
+    let (hls_video, headers) = spawn_blocking(move || {
         if !media_path.exists() {
             return Err(HttpError::StreamNotFound(format!(
                 "Media file not found: {}",
@@ -74,8 +124,22 @@ pub async fn handle_dynamic_request(
             media_path,
             hls_url.session_id
         );
-        let mut hls_video = HlsVideo::open(&media_path, hls_url)
-            .map_err(|e| HttpError::InternalError(format!("Failed to open media: {}", e)))?;
+        // A new session of a large file: answer 202 while it is indexed.
+        let new_session = hls_url.is_main_playlist() && hls_url.session_id.is_none();
+        let mut hls_video = if open_wait_secs > 0 && new_session {
+            let handle = StreamIndex::open_async(&media_path, None, hls_vod_lib::lib_config());
+            match handle.wait(std::time::Duration::from_secs(open_wait_secs)) {
+                Some(index) => index.and_then(|index| HlsVideo::from_index(index, hls_url))?,
+                None => {
+                    return Err(HttpError::Indexing {
+                        status: handle.status(),
+                        max_retry_secs: open_wait_secs,
+                    })
+                }
+            }
+        } else {
+            HlsVideo::open(&media_path, hls_url)?
+        };
 
         if let HlsVideo::MainPlaylist(p) = &mut hls_video {
             let tracks: Vec<usize> = query_params
@@ -96,12 +160,82 @@ pub async fn handle_dynamic_request(
                 .unwrap_or_default();
             p.filter_codecs(&codecs);
 
+            // interleave=fallback lists the muxed variants after the
+            // separate audio renditions, for TVs that can't play those.
+            match query_params.get("interleave").map(|v| v.as_str()) {
+                Some("true" | "1") => p.interleave(),
+                Some("fallback") => p.interleaved_fallback(),
+                _ => {}
+            }
+
+            if query_params
+                .get("aac_alternates")
+                .map(|v| v == "true" || v == "1")
+                .unwrap_or(false)
+            {
+                p.aac_alternates();
+            }
+
             if query_params
-                .get("interleave")
+                .get("combined_init")
                 .map(|v| v == "true" || v == "1")
                 .unwrap_or(false)
             {
-                p.interleave();
+                p.combined_init();
+            }
+
+            // Caps for constrained links, e.g. no 4K for remote players.
+            if let Some(height) = query_params.get("maxheight") {
+                let height = height.parse().map_err(|_| {
+                    HttpError::InvalidFormat(format!("invalid maxheight: {}", height))
+                })?;
+                p.max_height(height);
+            }
+            if let Some(bitrate) = query_params.get("maxbitrate") {
+                let bitrate = bitrate.parse().map_err(|_| {
+                    HttpError::InvalidFormat(format!("invalid maxbitrate: {}", bitrate))
+                })?;
+                p.max_bitrate(bitrate);
+            }
+            // Transcoded audio at a lower or higher bitrate, in kbit/s.
+            if let Some(kbps) = query_params.get("audio_bitrate") {
+                let kbps = kbps.parse().map_err(|_| {
+                    HttpError::InvalidFormat(format!("invalid audio_bitrate: {}", kbps))
+                })?;
+                p.audio_bitrate(kbps)
+                    .map_err(|e| HttpError::InvalidFormat(e.to_string()))?;
+            }
+            // Two audio tracks mixed into one, `<main>,<secondary>`.
+            if let Some(mix) = query_params.get("audio_mix") {
+                let (main, secondary) = mix
+                    .split_once(',')
+                    .and_then(|(m, s)| Some((m.parse().ok()?, s.parse().ok()?)))
+                    .ok_or_else(|| {
+                        HttpError::InvalidFormat(format!("invalid audio_mix: {}", mix))
+                    })?;
+                p.audio_mix(main, secondary)
+                    .map_err(|e| HttpError::InvalidFormat(e.to_string()))?;
+            }
+
+            if let Some(mode) = query_params.get("subtitle_timestamps") {
+                let mode = mode.parse().map_err(HttpError::InvalidFormat)?;
+                p.subtitle_timestamps(mode);
+            }
+
+            if let Some(level) = &hdcp_level {
+                p.hdcp_level(level)?;
+            }
+
+            if !languages.is_empty() {
+                p.preferred_languages(&languages);
+            }
+
+            let duration = p.index.duration_secs;
+            let resume = bookmark.and_then(|position| {
+                crate::bookmarks::resume_position(&bookmarks_config, position, duration)
+            });
+            if let Some(position) = resume {
+                p.start_offset(position)?;
             }
         }
 
@@ -115,13 +249,361 @@ pub async fn handle_dynamic_request(
             header::CACHE_CONTROL,
             HeaderValue::from_static(hls_video.cache_control()),
         );
+        if accept_language {
+            headers.insert(header::VARY, HeaderValue::from_static("accept-language"));
+        }
 
-        let bytes = hls_video
-            .generate()
-            .map_err(|e| HttpError::InternalError(e.to_string()))?;
-
-        Ok((headers, bytes).into_response())
+        Ok((hls_video, headers))
     })
     .await
-    .map_err(|e| HttpError::InternalError(e.to_string()))?
+    .map_err(|e| HttpError::InternalError(e.to_string()))??;
+
+    // The length of the segment is needed for the `Content-Range`, so a
+    // range request waits for the complete segment instead of streaming it.
+    if let Some(range) = range {
+        let bytes = spawn_blocking(move || hls_video.generate().map_err(HttpError::from))
+            .await
+            .map_err(|e| HttpError::InternalError(e.to_string()))??;
+        return Ok(ranged_response(headers, bytes.into(), Some(&range)));
+    }
+
+    // Generate in a separate thread. Media segments that are not in the cache
+    // are sent to us fragment by fragment while they are being muxed. The
+    // channel is unbounded so that a slow client doesn't hold up FFmpeg; it
+    // holds at most one segment, which the generator keeps anyway.
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let task = spawn_blocking(move || {
+        hls_video
+            .generate_with_progress(&ChannelSink(tx))
+            .map_err(HttpError::from)
+    });
+
+    let first = match rx.recv().await {
+        Some(chunk) => chunk,
+        None => {
+            // Nothing was streamed, so the complete output is the return value.
+            let bytes = task
+                .await
+                .map_err(|e| HttpError::InternalError(e.to_string()))??;
+            if delta_update {
+                let playlist = String::from_utf8_lossy(&bytes);
+                let playlist = hls_vod_lib::playlist_delta_update(&playlist);
+                return Ok((headers, playlist).into_response());
+            }
+            return Ok((headers, bytes).into_response());
+        }
+    };
+
+    // Once the first chunk is out the status is fixed, so a failure halfway
+    // can only be signalled by aborting the body.
+    let stream = futures_util::stream::unfold(
+        (Some(first), rx, Some(task)),
+        |(first, mut rx, task)| async move {
+            if let Some(chunk) = first {
+                return Some((Ok(chunk), (None, rx, task)));
+            }
+            if let Some(chunk) = rx.recv().await {
+                return Some((Ok(chunk), (None, rx, task)));
+            }
+            let result = match task?.await {
+                Ok(Ok(_)) => return None,
+                Ok(Err(e)) => format!("{:?}", e),
+                Err(e) => e.to_string(),
+            };
+            tracing::error!("segment generation failed while streaming: {}", result);
+            let err = std::io::Error::other(result);
+            Some((Err(err), (None, rx, None)))
+        },
+    );
+
+    Ok((headers, axum::body::Body::from_stream(stream)).into_response())
+}
+
+/// The languages of an `Accept-Language` header, most preferred first.
+///
+/// Languages with `q=0` and the `*` wildcard are left out.
+fn accept_languages(headers: &HeaderMap) -> Vec<String> {
+    let Some(accept) = headers
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return Vec::new();
+    };
+    let mut languages: Vec<(String, f32)> = accept
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let language = parts.next()?.trim();
+            let q = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map(|q| q.trim().parse::<f32>().unwrap_or(0.0))
+                .unwrap_or(1.0);
+            (!language.is_empty() && language != "*" && q > 0.0).then(|| (language.to_string(), q))
+        })
+        .collect();
+    // Stable, so equal weights keep the order of the header.
+    languages.sort_by(|a, b| b.1.total_cmp(&a.1));
+    languages.into_iter().map(|(l, _)| l).collect()
+}
+
+/// Generate a segment in a worker process; see `crate::worker`.
+async fn generate_in_worker(
+    workers: Arc<WorkerPool>,
+    media_path: std::path::PathBuf,
+    path: String,
+    hls_url: &hls_vod_lib::HlsParams,
+    request_id: Option<String>,
+    range: Option<HeaderValue>,
+) -> Result<axum::response::Response, HttpError> {
+    if !media_path.exists() {
+        return Err(HttpError::StreamNotFound(format!(
+            "Media file not found: {}",
+            hls_url.video_url,
+        )));
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(hls_url.mime_type()),
+    );
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(hls_url.cache_control()),
+    );
+
+    let data = spawn_blocking(move || workers.generate(media_path, path, request_id))
+        .await
+        .map_err(|e| HttpError::InternalError(e.to_string()))??;
+    Ok(ranged_response(headers, data.into(), range.as_ref()))
+}
+
+/// The response with segment `data`, or with the part of it that the
+/// `Range` header `range` asks for (`206 Partial Content`).
+///
+/// Only a single byte range is supported. Other ranges are ignored and the
+/// whole segment is sent, as RFC 9110 allows.
+fn ranged_response(
+    mut headers: HeaderMap,
+    data: Bytes,
+    range: Option<&HeaderValue>,
+) -> axum::response::Response {
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    let range = range
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| byte_range(v, data.len()));
+    match range {
+        None => (headers, data).into_response(),
+        Some(Ok(range)) => {
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, data.len());
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&content_range).unwrap(),
+            );
+            (StatusCode::PARTIAL_CONTENT, headers, data.slice(range)).into_response()
+        }
+        Some(Err(())) => {
+            let content_range = format!("bytes */{}", data.len());
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&content_range).unwrap(),
+            );
+            (StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response()
+        }
+    }
+}
+
+/// The bytes of a body of `len` bytes that `Range` header `value` selects.
+///
+/// `None` if the header is not a single byte range, so it is ignored.
+/// `Some(Err(()))` if the range is past the end of the body.
+fn byte_range(value: &str, len: usize) -> Option<Result<std::ops::Range<usize>, ()>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    if first.is_empty() {
+        // The last `last` bytes.
+        let suffix: usize = last.parse().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok(len.saturating_sub(suffix)..len));
+    }
+    let first: usize = first.parse().ok()?;
+    let end = match last {
+        "" => len,
+        last => {
+            let last: usize = last.parse().ok()?;
+            if last < first {
+                return None;
+            }
+            last.saturating_add(1).min(len)
+        }
+    };
+    if first >= len {
+        return Some(Err(()));
+    }
+    Some(Ok(first..end))
+}
+
+/// Check the token of the media root that `video_url` is in, if any.
+///
+/// Call this before `resolve_in_roots`: a caller without a token must not be
+/// able to tell from a 404 or a 403 whether a file exists.
+pub(crate) fn check_root_token(
+    state: &AppState,
+    video_url: &str,
+    query: &std::collections::HashMap<String, String>,
+    headers: &HeaderMap,
+) -> Result<(), HttpError> {
+    match crate::roots::find_root(&state.config.media_roots, video_url) {
+        Some(root) if !crate::roots::token_ok(root, query, headers) => Err(HttpError::Forbidden(
+            format!("Missing or invalid token for media root {}", root.name),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Check the token of the media root of the file of stream `stream_id`,
+/// for requests by stream id. An unknown stream passes; the caller 404s.
+pub(crate) fn check_stream_token(
+    state: &AppState,
+    stream_id: &str,
+    query: &std::collections::HashMap<String, String>,
+    headers: &HeaderMap,
+) -> Result<(), HttpError> {
+    let Some(path) = hls_vod_lib::cache::stream_source_path(stream_id) else {
+        return Ok(());
+    };
+    match crate::roots::root_of_path(&state.config.media_roots, &path) {
+        Some(root) if !crate::roots::token_ok(root, query, headers) => Err(HttpError::Forbidden(
+            format!("Missing or invalid token for media root {}", root.name),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Map the video part of a request URL to a path on disk, see `crate::roots`.
+pub(crate) fn resolve_in_roots(
+    state: &AppState,
+    video_url: &str,
+) -> Result<std::path::PathBuf, HttpError> {
+    crate::roots::resolve(&state.config.media_roots, video_url)
+        .map(|(path, _)| path)
+        .map_err(|e| match e {
+            crate::roots::ResolveError::NotFound => {
+                HttpError::StreamNotFound(format!("Media file not found: {}", video_url))
+            }
+            crate::roots::ResolveError::Forbidden => {
+                HttpError::Forbidden(format!("Access denied: {}", video_url))
+            }
+        })
+}
+
+/// Map the video part of a request URL to a path on disk, without media roots.
+pub(crate) fn resolve_media_path(video_url: &str) -> std::path::PathBuf {
+    // We simply take the url path as the path to the video.
+    let mut media_path = std::path::PathBuf::from(video_url);
+    tracing::info!(
+        "Initial check existence ({}): {}",
+        video_url,
+        media_path.exists()
+    );
+
+    if !media_path.exists() {
+        if !video_url.starts_with('/') {
+            let prefixed = format!("/{}", video_url);
+            media_path = std::path::PathBuf::from(&prefixed);
+            tracing::info!(
+                "Prefixed check existence ({}): {}",
+                prefixed,
+                media_path.exists()
+            );
+        }
+    }
+
+    if !media_path.exists() && !media_path.is_absolute() {
+        if let Ok(cwd) = std::env::current_dir() {
+            let joined = cwd.join(video_url);
+            tracing::info!(
+                "CWD joined check ({}): {}",
+                joined.display(),
+                joined.exists()
+            );
+            if joined.exists() {
+                media_path = joined;
+            }
+        }
+    }
+    media_path
+}
+
+/// Forwards segment data from the generator thread to the response body.
+struct ChannelSink(tokio::sync::mpsc::UnboundedSender<Bytes>);
+
+impl ProgressObserver for ChannelSink {
+    fn on_fragment(&self, _progress: &SegmentProgress) {}
+
+    fn wants_data(&self) -> bool {
+        true
+    }
+
+    fn on_data(&self, chunk: Bytes) {
+        // If the client went away the receiver is gone; keep generating
+        // anyway so the segment still ends up in the cache.
+        let _ = self.0.send(chunk);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_languages() {
+        let mut headers = HeaderMap::new();
+        assert!(accept_languages(&headers).is_empty());
+        headers.insert(
+            header::ACCEPT_LANGUAGE,
+            HeaderValue::from_static("en;q=0.5, nl-NL, de;q=0.8, *;q=0.1, fr;q=0"),
+        );
+        assert_eq!(accept_languages(&headers), ["nl-NL", "de", "en"]);
+    }
+
+    #[test]
+    fn test_byte_range() {
+        assert_eq!(byte_range("bytes=0-99", 1000), Some(Ok(0..100)));
+        assert_eq!(byte_range("bytes=900-", 1000), Some(Ok(900..1000)));
+        assert_eq!(byte_range("bytes=-100", 1000), Some(Ok(900..1000)));
+        assert_eq!(byte_range("bytes=990-2000", 1000), Some(Ok(990..1000)));
+        assert_eq!(byte_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(byte_range("bytes=0-1,5-9", 1000), None);
+        assert_eq!(byte_range("bytes=9-5", 1000), None);
+        assert_eq!(byte_range("items=0-1", 1000), None);
+    }
+
+    #[tokio::test]
+    async fn test_ranged_response() {
+        let data = Bytes::from_static(b"0123456789");
+        let range = HeaderValue::from_static("bytes=2-4");
+        let response = ranged_response(HeaderMap::new(), data.clone(), Some(&range));
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 2-4/10");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"234");
+
+        let response = ranged_response(HeaderMap::new(), data.clone(), None);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], "bytes");
+
+        let range = HeaderValue::from_static("bytes=10-");
+        let response = ranged_response(HeaderMap::new(), data, Some(&range));
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */10");
+    }
 }
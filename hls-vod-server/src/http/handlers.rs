@@ -1,63 +1,101 @@
+use super::middleware::spawn_blocking;
 use crate::state::AppState;
 use axum::{
-    extract::State,
-    http::StatusCode,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
-use hls_vod_lib::HlsError;
+use hls_vod_lib::HlsVideo;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
-/// Custom error response for HLS operations
-#[derive(Debug)]
-pub enum HttpError {
-    StreamNotFound(String),
-    SegmentNotFound(String),
-    InvalidFormat(String),
-    InternalError(String),
-}
-
-impl IntoResponse for HttpError {
-    fn into_response(self) -> Response {
-        let (status, message) = match self {
-            HttpError::StreamNotFound(m) => (StatusCode::NOT_FOUND, m),
-            HttpError::SegmentNotFound(m) => (StatusCode::NOT_FOUND, m),
-            HttpError::InvalidFormat(m) => (StatusCode::BAD_REQUEST, m),
-            HttpError::InternalError(m) => (StatusCode::INTERNAL_SERVER_ERROR, m),
-        };
-
-        (status, message).into_response()
-    }
-}
-
-impl From<HlsError> for HttpError {
-    fn from(err: HlsError) -> Self {
-        match err {
-            HlsError::StreamNotFound(m) => HttpError::StreamNotFound(m),
-            HlsError::SegmentNotFound { .. } => HttpError::SegmentNotFound(err.to_string()),
-            HlsError::Muxing(m) => HttpError::InternalError(m),
-            HlsError::Transcode(m) => HttpError::InternalError(m),
-            HlsError::Ffmpeg(e) => HttpError::InternalError(e.to_string()),
-            HlsError::Io(e) => HttpError::InternalError(e.to_string()),
-            _ => HttpError::InternalError(err.to_string()),
-        }
-    }
-}
+pub use hls_vod_http::HttpError;
 
 /// Health check endpoint
 pub async fn health_check() -> (StatusCode, &'static str) {
     (StatusCode::OK, "OK")
 }
 
-/// Version information endpoint
+/// Version information endpoint, with the capabilities of the linked FFmpeg
 pub async fn version_check() -> Json<serde_json::Value> {
     Json(serde_json::json!({
         "status": "online",
         "version": env!("CARGO_PKG_VERSION"),
-        "ffmpeg": hls_vod_lib::ffmpeg_version_info()
+        "ffmpeg": hls_vod_lib::ffmpeg_version_info(),
+        "ffmpeg_capabilities": hls_vod_lib::ffmpeg_capabilities(),
     }))
 }
 
+/// Keep-alive endpoint: heartbeat of a (paused) player.
+///
+/// Keeps the stream open so that the player can resume without the file
+/// being indexed again.
+pub async fn keepalive(Path(stream_id): Path<String>) -> Result<StatusCode, HttpError> {
+    if hls_vod_lib::cache::keepalive_stream(&stream_id) {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(HttpError::StreamNotFound(format!(
+            "Stream not found: {}",
+            stream_id
+        )))
+    }
+}
+
+/// Font attached to the file of a stream, for client-side ASS rendering.
+pub async fn attachment(
+    State(state): State<Arc<AppState>>,
+    Path((stream_id, name)): Path<(String, String)>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, HttpError> {
+    super::dynamic::check_stream_token(&state, &stream_id, &query, &headers)?;
+    let attachment = hls_vod_lib::cache::stream_attachment(&stream_id, &name).ok_or_else(|| {
+        HttpError::StreamNotFound(format!("Attachment not found: {}/{}", stream_id, name))
+    })?;
+    let content_type = HeaderValue::from_str(&attachment.mime_type)
+        .unwrap_or(HeaderValue::from_static("application/octet-stream"));
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (
+                header::CACHE_CONTROL,
+                HeaderValue::from_static("max-age=3600"),
+            ),
+        ],
+        attachment.data,
+    )
+        .into_response())
+}
+
+/// Cover art of the file of a stream, by track (stream index).
+pub async fn artwork(
+    State(state): State<Arc<AppState>>,
+    Path((stream_id, track)): Path<(String, usize)>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response, HttpError> {
+    super::dynamic::check_stream_token(&state, &stream_id, &query, &headers)?;
+    let artwork = hls_vod_lib::cache::stream_artwork(&stream_id, track).ok_or_else(|| {
+        HttpError::StreamNotFound(format!("Artwork not found: {}/{}", stream_id, track))
+    })?;
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(artwork.mime_type),
+            ),
+            (
+                header::CACHE_CONTROL,
+                HeaderValue::from_static("max-age=3600"),
+            ),
+        ],
+        artwork.data,
+    )
+        .into_response())
+}
+
 /// Debug endpoint: cache statistics
 pub async fn cache_stats(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
     let stats = state.cache_stats();
@@ -65,9 +103,17 @@ pub async fn cache_stats(State(state): State<Arc<AppState>>) -> Json<serde_json:
         "size": stats.entry_count,
         "memory_usage": stats.total_size_bytes,
         "capacity": stats.memory_limit_bytes,
+        "eviction_policy": stats.eviction_policy,
+        "saved_generation_secs": stats.saved_generation_secs,
+        "evicted_generation_secs": stats.evicted_generation_secs,
     }))
 }
 
+/// Debug endpoint: memory usage per subsystem
+pub async fn memory_stats() -> Json<hls_vod_lib::memory::MemoryStats> {
+    Json(hls_vod_lib::memory::memory_stats())
+}
+
 /// Debug endpoint: active streams
 pub async fn active_streams(
     State(_state): State<Arc<AppState>>,
@@ -75,3 +121,596 @@ pub async fn active_streams(
     let streams = hls_vod_lib::cache::active_streams();
     Json(streams)
 }
+
+/// Debug endpoint: the last failed requests of every active stream
+///
+/// Segments generated in worker processes are not included. Needs the
+/// admin token.
+pub async fn stream_errors(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<hls_vod_lib::cache::StreamErrors>>, HttpError> {
+    check_admin(&state, &headers)?;
+    Ok(Json(hls_vod_lib::cache::stream_errors()))
+}
+
+/// Debug endpoint: generate a media segment with two muxer configurations
+/// and compare the results.
+///
+/// The path is a segment URL, `?a=` and `?b=` are comma separated muxer
+/// options (`delay_moov`, `no_delay_moov`, `styp`, `no_styp`). Needs the
+/// admin token.
+pub async fn compare_muxers(
+    State(state): State<Arc<AppState>>,
+    uri: axum::http::Uri,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Json<hls_vod_lib::SegmentComparison>, HttpError> {
+    check_admin(&state, &headers)?;
+    let option = |name: &str| {
+        query
+            .get(name)
+            .map(|s| s.as_str())
+            .unwrap_or("")
+            .parse::<hls_vod_lib::MuxOptions>()
+            .map_err(|e| HttpError::InvalidFormat(format!("{}: {}", name, e)))
+    };
+    let (a, b) = (option("a")?, option("b")?);
+
+    // HlsParams::parse decodes the path itself.
+    let path = uri
+        .path()
+        .strip_prefix("/debug/compare/")
+        .unwrap_or_default();
+    let params = hls_vod_lib::HlsParams::parse(path)
+        .ok_or_else(|| HttpError::SegmentNotFound(format!("Invalid segment URL: {}", path)))?;
+    super::dynamic::check_root_token(&state, &params.video_url, &query, &headers)?;
+    let media_path = super::dynamic::resolve_in_roots(&state, &params.video_url)?;
+    let comparison = spawn_blocking(move || {
+        let video = HlsVideo::open(&media_path, params)?;
+        match video {
+            HlsVideo::PlaylistOrSegment(s) => Ok(s.compare_muxers(a, b)?),
+            HlsVideo::MainPlaylist(_) => Err(HttpError::InvalidFormat(
+                "not a media segment URL".to_string(),
+            )),
+        }
+    })
+    .await
+    .map_err(|e| HttpError::InternalError(e.to_string()))??;
+
+    Ok(Json(comparison))
+}
+
+/// Debug endpoint: check the init segment of every playlist of a media file
+/// against one of its media segments.
+///
+/// The path is the media file, `?segment=` the sequence number of the media
+/// segment to check (default 1). Needs the admin token.
+pub async fn check_consistency(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<hls_vod_lib::ConsistencyCheck>>, HttpError> {
+    check_admin(&state, &headers)?;
+    let sequence = match query.get("segment") {
+        Some(s) => s
+            .parse::<usize>()
+            .map_err(|_| HttpError::InvalidFormat(format!("segment: invalid number {}", s)))?,
+        None => 1,
+    };
+    super::dynamic::check_root_token(&state, &path, &query, &headers)?;
+    let media_path = super::dynamic::resolve_in_roots(&state, &path)?;
+    let checks = spawn_blocking(move || {
+        if !media_path.exists() {
+            return Err(HttpError::StreamNotFound(format!(
+                "Media file not found: {}",
+                path
+            )));
+        }
+        let params = hls_vod_lib::HlsParams {
+            video_url: path,
+            session_id: None,
+            url_type: hls_vod_lib::params::UrlType::MainPlaylist,
+        };
+        match HlsVideo::open(&media_path, params)? {
+            HlsVideo::MainPlaylist(p) => Ok(p.check_consistency(sequence)?),
+            _ => Err(HttpError::InternalError("unexpected HLS video type".into())),
+        }
+    })
+    .await
+    .map_err(|e| HttpError::InternalError(e.to_string()))??;
+
+    Ok(Json(checks))
+}
+
+/// Debug endpoint: follow the audio against the video over every segment
+/// of a media file.
+///
+/// The path is the media file, `?audio=` the audio track (default the first
+/// one) and `?threshold=` the drift in seconds above which a segment is
+/// flagged (default 0.1). Generates the whole title, so it takes a while:
+/// needs the admin token, runs one at a time, and stops when the client
+/// goes away.
+pub async fn check_drift(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Json<hls_vod_lib::DriftReport>, HttpError> {
+    check_admin(&state, &headers)?;
+    let audio_track = match query.get("audio") {
+        Some(s) => Some(
+            s.parse::<usize>()
+                .map_err(|_| HttpError::InvalidFormat(format!("audio: invalid track {}", s)))?,
+        ),
+        None => None,
+    };
+    let threshold = match query.get("threshold") {
+        Some(s) => s
+            .parse::<f64>()
+            .ok()
+            .filter(|t| t.is_finite() && *t >= 0.0)
+            .ok_or_else(|| HttpError::InvalidFormat(format!("threshold: invalid value {}", s)))?,
+        None => hls_vod_lib::DRIFT_THRESHOLD_SECS,
+    };
+    super::dynamic::check_root_token(&state, &path, &query, &headers)?;
+    let media_path = super::dynamic::resolve_in_roots(&state, &path)?;
+    if state.drift_check.swap(true, Ordering::AcqRel) {
+        return Err(HttpError::Unavailable(
+            "A drift check is already running".into(),
+        ));
+    }
+    let running = DriftCheckRunning(state.clone());
+    // Dropped with this future if the client disconnects.
+    let cancel = CancelOnDrop(Arc::new(AtomicBool::new(false)));
+    let cancelled = cancel.0.clone();
+    let report = spawn_blocking(move || {
+        let _running = running;
+        if !media_path.exists() {
+            return Err(HttpError::StreamNotFound(format!(
+                "Media file not found: {}",
+                path
+            )));
+        }
+        let params = hls_vod_lib::HlsParams {
+            video_url: path,
+            session_id: None,
+            url_type: hls_vod_lib::params::UrlType::MainPlaylist,
+        };
+        match HlsVideo::open(&media_path, params)? {
+            HlsVideo::MainPlaylist(p) => Ok(p.check_drift(audio_track, threshold, &cancelled)?),
+            _ => Err(HttpError::InternalError("unexpected HLS video type".into())),
+        }
+    })
+    .await
+    .map_err(|e| HttpError::InternalError(e.to_string()))??;
+
+    Ok(Json(report))
+}
+
+/// Clears `AppState::drift_check` when the drift check is done.
+struct DriftCheckRunning(Arc<AppState>);
+
+impl Drop for DriftCheckRunning {
+    fn drop(&mut self) {
+        self.0.drift_check.store(false, Ordering::Release);
+    }
+}
+
+/// Sets the flag when dropped.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Debug endpoint: probe a media file.
+///
+/// Lists the tracks we serve, and the tracks that are left out of the
+/// playlists together with the reason why. Needs the admin token.
+pub async fn probe(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, HttpError> {
+    check_admin(&state, &headers)?;
+    super::dynamic::check_root_token(&state, &path, &query, &headers)?;
+    let media_path = super::dynamic::resolve_in_roots(&state, &path)?;
+    let index = spawn_blocking(move || {
+        if !media_path.exists() {
+            return Err(HttpError::StreamNotFound(format!(
+                "Media file not found: {}",
+                path
+            )));
+        }
+        let params = hls_vod_lib::HlsParams {
+            video_url: path,
+            session_id: None,
+            url_type: hls_vod_lib::params::UrlType::MainPlaylist,
+        };
+        match HlsVideo::open(&media_path, params)? {
+            HlsVideo::MainPlaylist(p) => Ok(p.index),
+            _ => Err(HttpError::InternalError("unexpected HLS video type".into())),
+        }
+    })
+    .await
+    .map_err(|e| HttpError::InternalError(e.to_string()))??;
+
+    Ok(Json(serde_json::json!({
+        "stream_id": index.stream_id(),
+        "duration": index.duration_secs,
+        "segments": index.segment_count(),
+        "segment_escalation": index.segment_escalation.map(|e| serde_json::json!({
+            "max_segments": e.max_segments,
+            "original_segments": e.original_segments,
+            "original_duration": e.original_duration_secs,
+            "duration": e.duration_secs,
+        })),
+        "video": index.video_streams.iter().map(|v| serde_json::json!({
+            "track": v.stream_index,
+            "codec": v.codec_id.name(),
+            "width": v.width,
+            "height": v.height,
+            "stats": v.stats.map(|s| serde_json::json!({
+                "min_gop_secs": s.min_gop_secs,
+                "avg_gop_secs": s.avg_gop_secs,
+                "max_gop_secs": s.max_gop_secs,
+                "avg_gop_frames": s.avg_gop_frames,
+                "max_gop_frames": s.max_gop_frames,
+                "b_frames": s.has_b_frames,
+                "bitrate_variation": s.bitrate_variation,
+            })),
+        })).collect::<Vec<_>>(),
+        "audio": index.audio_streams.iter().map(|a| serde_json::json!({
+            "track": a.stream_index,
+            "codec": a.codec_id.name(),
+            "language": a.language,
+            "channels": a.channels,
+            "transcode_to": a.transcode_to.map(|c| c.name()),
+        })).collect::<Vec<_>>(),
+        "subtitles": index.subtitle_streams.iter().map(|s| serde_json::json!({
+            "track": s.stream_index,
+            "codec": s.codec_id.name(),
+            "language": s.language,
+        })).collect::<Vec<_>>(),
+        "attachments": index.attachments.iter().map(|a| serde_json::json!({
+            "name": a.filename,
+            "mime_type": a.mime_type,
+            "size": a.data.len(),
+            "url": format!(
+                "/streams/{}/attachments/{}",
+                index.stream_id(),
+                encode_path_segment(&a.filename)
+            ),
+        })).collect::<Vec<_>>(),
+        "artwork": index.artwork.iter().map(|a| serde_json::json!({
+            "track": a.stream_index,
+            "mime_type": a.mime_type,
+            "size": a.data.len(),
+            "url": format!("/streams/{}/artwork/{}", index.stream_id(), a.stream_index),
+        })).collect::<Vec<_>>(),
+        "excluded": index.excluded_tracks.iter().map(|t| serde_json::json!({
+            "track": t.stream_index,
+            "codec": t.codec_id.name(),
+            "language": t.language,
+            "reason": t.reason,
+        })).collect::<Vec<_>>(),
+    })))
+}
+
+/// Durations, tracks and main playlist URLs of all episodes in a folder
+///
+/// Only folders in media roots can be listed. With signed URLs, the main
+/// playlist URLs are signed, and play without the token of the root.
+pub async fn season_manifest(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Json<hls_vod_lib::season::SeasonManifest>, HttpError> {
+    if state.config.media_roots.is_empty() {
+        return Err(HttpError::Forbidden(
+            "Season manifests need media roots".into(),
+        ));
+    }
+    super::dynamic::check_root_token(&state, &path, &query, &headers)?;
+    let dir = super::dynamic::resolve_in_roots(&state, &path)?;
+    let mut manifest = spawn_blocking(move || {
+        if !dir.is_dir() {
+            return Err(HttpError::StreamNotFound(format!(
+                "Folder not found: {}",
+                path
+            )));
+        }
+        Ok(hls_vod_lib::season::season_manifest(&dir, &path)?)
+    })
+    .await
+    .map_err(|e| HttpError::InternalError(e.to_string()))??;
+
+    for episode in &mut manifest.episodes {
+        let url = episode.url.trim_start_matches('/');
+        episode.url = match (&state.url_codec, hls_vod_lib::HlsParams::parse(url)) {
+            (Some(codec), Some(params)) => format!("/{}", codec.signed_request_path(&params)),
+            _ => format!("/{}", url),
+        };
+    }
+    Ok(Json(manifest))
+}
+
+/// Body of a progress report.
+#[derive(Debug, serde::Deserialize)]
+pub struct ProgressReport {
+    pub user: String,
+    /// See `crate::bookmarks::user_token`
+    pub user_token: String,
+    /// The media URL path, as in the main playlist URL
+    pub title: String,
+    /// Position in seconds, `null` to forget it (watched to the end)
+    pub position: Option<f64>,
+}
+
+/// The bookmark store, see `crate::bookmarks`, if `token` is the user
+/// token of `user`.
+fn bookmark_store(
+    state: &AppState,
+    user: &str,
+    token: Option<&str>,
+) -> Result<Arc<dyn crate::bookmarks::BookmarkStore>, HttpError> {
+    let Some(store) = state.bookmarks.clone() else {
+        return Err(HttpError::Forbidden("Bookmarks are disabled".into()));
+    };
+    let Some(auth) = &state.config.auth else {
+        return Err(HttpError::Forbidden(
+            "Bookmarks need [auth] for user tokens".into(),
+        ));
+    };
+    match token {
+        Some(token) if crate::bookmarks::user_token_ok(&auth.secret, user, token) => Ok(store),
+        _ => Err(HttpError::Forbidden("Missing or invalid user token".into())),
+    }
+}
+
+/// Player progress report: the position of a user in a title. The next
+/// main playlist of the title with `?user=` starts there.
+pub async fn report_progress(
+    State(state): State<Arc<AppState>>,
+    Json(report): Json<ProgressReport>,
+) -> Result<StatusCode, HttpError> {
+    if report.user.is_empty() {
+        return Err(HttpError::InvalidFormat("empty user".into()));
+    }
+    let store = bookmark_store(&state, &report.user, Some(&report.user_token))?;
+    if let Some(position) = report.position {
+        if !position.is_finite() || position < 0.0 {
+            return Err(HttpError::InvalidFormat(format!(
+                "invalid position: {}",
+                position
+            )));
+        }
+    }
+    let title = crate::bookmarks::title(&report.title);
+    if report.position.is_some()
+        && store.get(&report.user, title).is_none()
+        && store.titles(&report.user) >= state.config.bookmarks.max_titles_per_user
+    {
+        return Err(HttpError::Forbidden(format!(
+            "At most {} titles per user, forget one first",
+            state.config.bookmarks.max_titles_per_user
+        )));
+    }
+    spawn_blocking(move || {
+        let title = crate::bookmarks::title(&report.title);
+        match report.position {
+            Some(position) => store.set(&report.user, title, position),
+            None => store.remove(&report.user, title),
+        }
+    })
+    .await
+    .map_err(|e| HttpError::InternalError(e.to_string()))?
+    .map_err(|e| HttpError::InternalError(format!("Failed to save bookmark: {}", e)))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The stored position of a user in a title (`?user=&user_token=&title=`).
+pub async fn get_progress(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Result<Json<serde_json::Value>, HttpError> {
+    let (Some(user), Some(title)) = (query.get("user"), query.get("title")) else {
+        return Err(HttpError::InvalidFormat(
+            "user and title are required".into(),
+        ));
+    };
+    let store = bookmark_store(&state, user, query.get("user_token").map(|t| t.as_str()))?;
+    let title = crate::bookmarks::title(title);
+    match store.get(user, title) {
+        Some(position) => Ok(Json(serde_json::json!({
+            "user": user,
+            "title": title,
+            "position": position,
+        }))),
+        None => Err(HttpError::StreamNotFound(format!(
+            "No position of {} in {}",
+            user, title
+        ))),
+    }
+}
+
+/// Check the bearer token of an admin API request.
+fn check_admin(state: &AppState, headers: &HeaderMap) -> Result<(), HttpError> {
+    let Some(admin_token) = &state.config.admin_token else {
+        return Err(HttpError::Forbidden("Admin API is disabled".into()));
+    };
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if token != Some(admin_token.as_str()) {
+        return Err(HttpError::Forbidden(
+            "Missing or invalid admin token".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Resolve the media file of an admin API request.
+fn admin_media_path(state: &AppState, path: &str) -> Result<std::path::PathBuf, HttpError> {
+    let media_path = super::dynamic::resolve_in_roots(state, path)?;
+    if !media_path.exists() {
+        return Err(HttpError::StreamNotFound(format!(
+            "Media file not found: {}",
+            path
+        )));
+    }
+    Ok(media_path)
+}
+
+/// Admin endpoint: list the disabled tracks of a media file.
+pub async fn list_disabled_tracks(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<hls_vod_lib::DisabledTrack>>, HttpError> {
+    check_admin(&state, &headers)?;
+    let media_path = admin_media_path(&state, &path)?;
+    Ok(Json(hls_vod_lib::disabled_tracks(&media_path)))
+}
+
+/// Body of a disable track request.
+#[derive(Debug, serde::Deserialize)]
+pub struct DisableTrackRequest {
+    pub track: usize,
+    #[serde(default)]
+    pub reason: String,
+}
+
+/// Admin endpoint: disable a track of a media file.
+///
+/// The track is left out of the playlists from the next request on.
+pub async fn disable_track(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<String>,
+    headers: HeaderMap,
+    Json(request): Json<DisableTrackRequest>,
+) -> Result<StatusCode, HttpError> {
+    check_admin(&state, &headers)?;
+    let media_path = admin_media_path(&state, &path)?;
+    tracing::info!(
+        "Disabling track {} of {:?}: {}",
+        request.track,
+        media_path,
+        request.reason
+    );
+    spawn_blocking(move || hls_vod_lib::disable_track(&media_path, request.track, &request.reason))
+        .await
+        .map_err(|e| HttpError::InternalError(e.to_string()))??;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Admin endpoint: enable a disabled track again (`?track=N`).
+pub async fn enable_track(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<StatusCode, HttpError> {
+    check_admin(&state, &headers)?;
+    let media_path = admin_media_path(&state, &path)?;
+    let track: usize = query
+        .get("track")
+        .and_then(|t| t.parse().ok())
+        .ok_or_else(|| HttpError::InvalidFormat("missing or invalid track".into()))?;
+    tracing::info!("Enabling track {} of {:?}", track, media_path);
+    let enabled = spawn_blocking(move || hls_vod_lib::enable_track(&media_path, track))
+        .await
+        .map_err(|e| HttpError::InternalError(e.to_string()))??;
+    if enabled {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(HttpError::SegmentNotFound(format!(
+            "Track {} is not disabled",
+            track
+        )))
+    }
+}
+
+/// Number of streams exported by `export_hot_set` without `?streams=N`.
+const HOT_SET_STREAMS: usize = 20;
+
+/// The segment cache is in the worker processes, if there are any.
+fn check_local_cache(state: &AppState) -> Result<(), HttpError> {
+    if state.config.workers > 0 {
+        return Err(HttpError::InvalidFormat(
+            "Segments are cached in the worker processes".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Admin endpoint: the user token of `?user=`, for `/progress` and the
+/// `?user=` of main playlists, see `crate::bookmarks::user_token`.
+pub async fn user_token(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, HttpError> {
+    check_admin(&state, &headers)?;
+    let Some(auth) = &state.config.auth else {
+        return Err(HttpError::Forbidden(
+            "Bookmarks need [auth] for user tokens".into(),
+        ));
+    };
+    let user = match query.get("user") {
+        Some(user) if !user.is_empty() => user,
+        _ => return Err(HttpError::InvalidFormat("user is required".into())),
+    };
+    Ok(Json(serde_json::json!({
+        "user": user,
+        "user_token": crate::bookmarks::user_token(&auth.secret, user),
+    })))
+}
+
+/// Admin endpoint: export the hot set of the segment cache (`?streams=N`),
+/// to warm it again after a restart with `import_hot_set`.
+pub async fn export_hot_set(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<hls_vod_lib::warmer::HotStream>>, HttpError> {
+    check_admin(&state, &headers)?;
+    check_local_cache(&state)?;
+    let streams = match query.get("streams") {
+        Some(n) => n
+            .parse()
+            .map_err(|_| HttpError::InvalidFormat(format!("invalid streams: {}", n)))?,
+        None => HOT_SET_STREAMS,
+    };
+    Ok(Json(hls_vod_lib::warmer::export_hot_set(streams)))
+}
+
+/// Admin endpoint: open the streams of an exported hot set again and
+/// regenerate their segments in the background.
+pub async fn import_hot_set(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(streams): Json<Vec<hls_vod_lib::warmer::HotStream>>,
+) -> Result<(StatusCode, Json<serde_json::Value>), HttpError> {
+    check_admin(&state, &headers)?;
+    check_local_cache(&state)?;
+    tracing::info!("Warming {} streams of an imported hot set", streams.len());
+    let queued = hls_vod_lib::warmer::rewarm(streams, state.config.warmer.cache_percent);
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({ "queued_segments": queued })),
+    ))
+}
+
+/// Percent-encode `s` as one segment of a URL path: like a path, but with
+/// `/` encoded too.
+fn encode_path_segment(s: &str) -> String {
+    hls_vod_lib::percent_encode_path(s).replace('/', "%2F")
+}
@@ -2,9 +2,26 @@
 //!
 //! Additional middleware for the HTTP server.
 
-use axum::{body::Body, http::Request, middleware::Next, response::Response};
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, HeaderMap, HeaderName, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use base64::Engine;
+use bytes::Bytes;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
 use std::time::Instant;
-use tracing::{info, warn};
+use tracing::{info, warn, Instrument};
+
+use crate::config::CompressionConfig;
+use crate::state::AppState;
 
 /// Request logging middleware
 pub async fn request_logger(request: Request<Body>, next: Next) -> Response {
@@ -28,15 +45,62 @@ pub async fn request_logger(request: Request<Body>, next: Next) -> Response {
     response
 }
 
-/// Rate limiting middleware (placeholder)
+/// Header with the correlation id of a request.
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Request id middleware
 ///
-/// TODO: Implement proper rate limiting with:
-/// - Token bucket algorithm
-/// - Per-IP limits
-/// - Configurable limits
-pub async fn rate_limiter(request: Request<Body>, next: Next) -> Response {
-    // For now, pass through all requests
-    next.run(request).await
+/// Takes the id from an `X-Request-Id` header set by a proxy in front of us,
+/// or makes one up. The rest of the request runs in a `request` span with
+/// the id, so every log line of the request carries it; the id is also sent
+/// back in the response. Use `spawn_blocking()` below to keep the span in
+/// blocking tasks.
+pub async fn request_id(mut request: Request<Body>, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&REQUEST_ID)
+        .filter(|v| valid_request_id(v.as_bytes()))
+        .cloned()
+        .unwrap_or_else(|| {
+            let id = uuid::Uuid::new_v4().simple().to_string();
+            HeaderValue::from_str(&id).unwrap()
+        });
+    request.headers_mut().insert(REQUEST_ID, id.clone());
+
+    let span = tracing::info_span!("request", id = %id.to_str().unwrap_or_default());
+    let mut response = next.run(request).instrument(span).await;
+    response.headers_mut().insert(REQUEST_ID, id);
+    response
+}
+
+/// An id from a client that we can safely put in our logs.
+fn valid_request_id(id: &[u8]) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id
+            .iter()
+            .all(|&c| c.is_ascii_alphanumeric() || b"-_.:".contains(&c))
+}
+
+/// The id of a request, see `request_id()`.
+pub fn request_id_of(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(&REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+/// `tokio::task::spawn_blocking`, in the span of the request.
+///
+/// The log lines of the blocking task, including the FFmpeg messages of the
+/// library, carry the request id.
+pub fn spawn_blocking<F, R>(f: F) -> tokio::task::JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || span.in_scope(f))
 }
 
 /// Connection limit middleware (placeholder)
@@ -49,3 +113,360 @@ pub async fn connection_limiter(request: Request<Body>, next: Next) -> Response
     // For now, pass through all requests
     next.run(request).await
 }
+
+/// Content encodings we can compress responses with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// Pick the encoding for a request from its `Accept-Encoding` header.
+    ///
+    /// Brotli is preferred over gzip. Encodings with `q=0` are refused.
+    pub fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+        let accept = headers.get(header::ACCEPT_ENCODING)?.to_str().ok()?;
+        let mut brotli = false;
+        let mut gzip = false;
+        for item in accept.split(',') {
+            let mut parts = item.split(';');
+            let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let refused = parts.any(|p| {
+                p.trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .map(|q| q <= 0.0)
+                    .unwrap_or(false)
+            });
+            if refused {
+                continue;
+            }
+            match name.as_str() {
+                "br" => brotli = true,
+                "gzip" | "x-gzip" | "*" => gzip = true,
+                _ => {}
+            }
+        }
+        if brotli {
+            Some(Encoding::Brotli)
+        } else if gzip {
+            Some(Encoding::Gzip)
+        } else {
+            None
+        }
+    }
+
+    /// Value of the `Content-Encoding` header.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Compress `data`.
+    pub fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut out = Vec::with_capacity(data.len() / 4);
+                // Quality 5 compresses playlists about as well as 11, at a fraction of the CPU.
+                let mut w = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                w.write_all(data)?;
+                drop(w);
+                Ok(out)
+            }
+            Encoding::Gzip => {
+                let mut w = flate2::write::GzEncoder::new(
+                    Vec::with_capacity(data.len() / 4),
+                    flate2::Compression::default(),
+                );
+                w.write_all(data)?;
+                w.finish()
+            }
+        }
+    }
+}
+
+/// Compressed responses, keyed by encoding and a hash of the uncompressed body.
+///
+/// Players poll the same playlists over and over. The playlists themselves
+/// come from the segment cache, this makes sure we don't compress them
+/// again on every request either.
+pub struct CompressionCache {
+    entries: Mutex<lru::LruCache<(Encoding, u64, usize), Bytes>>,
+}
+
+impl CompressionCache {
+    pub fn new(config: &CompressionConfig) -> Self {
+        let size = NonZeroUsize::new(config.cache_entries).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            entries: Mutex::new(lru::LruCache::new(size)),
+        }
+    }
+
+    /// Get the compressed version of `data`, compressing it if it's not in the cache.
+    pub fn get_or_compress(&self, encoding: Encoding, data: &[u8]) -> std::io::Result<Bytes> {
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        let key = (encoding, hasher.finish(), data.len());
+
+        if let Some(b) = self.entries.lock().get(&key) {
+            return Ok(b.clone());
+        }
+        let compressed = Bytes::from(encoding.compress(data)?);
+        self.entries.lock().put(key, compressed.clone());
+        Ok(compressed)
+    }
+
+    /// Number of cached compressed responses.
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Is this a response we compress: playlists and WebVTT.
+fn is_compressible(headers: &HeaderMap) -> bool {
+    let Some(ct) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    let ct = ct.split(';').next().unwrap_or("").trim();
+    matches!(
+        ct,
+        "application/vnd.apple.mpegurl" | "application/x-mpegurl" | "text/vtt"
+    )
+}
+
+/// Compression middleware for playlists and WebVTT segments.
+///
+/// The encoding is negotiated via `Accept-Encoding`. Media segments are
+/// left alone, they don't compress and are streamed while being generated.
+pub async fn compress_playlists(
+    State(state): State<Arc<AppState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let config = &state.config.compression;
+    let encoding = if config.enabled {
+        Encoding::negotiate(request.headers())
+    } else {
+        None
+    };
+
+    let response = next.run(request).await;
+    if !config.enabled
+        || !response.status().is_success()
+        || !is_compressible(response.headers())
+        || response.headers().contains_key(header::CONTENT_ENCODING)
+    {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    let Some(encoding) = encoding else {
+        return Response::from_parts(parts, body);
+    };
+
+    let data = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("compress_playlists: reading body: {}", e);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    if data.len() < config.min_size {
+        return Response::from_parts(parts, Body::from(data));
+    }
+
+    let state2 = Arc::clone(&state);
+    let input = data.clone();
+    let compressed =
+        spawn_blocking(move || state2.compressed.get_or_compress(encoding, &input)).await;
+    match compressed {
+        Ok(Ok(compressed)) => {
+            parts.headers.insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.as_str()),
+            );
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(compressed))
+        }
+        Ok(Err(e)) => {
+            warn!("compress_playlists: {}: {}", encoding.as_str(), e);
+            Response::from_parts(parts, Body::from(data))
+        }
+        Err(e) => {
+            warn!("compress_playlists: {}", e);
+            Response::from_parts(parts, Body::from(data))
+        }
+    }
+}
+
+/// Is this a playlist or a segment.
+fn is_hls_response(headers: &HeaderMap) -> bool {
+    let Some(ct) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    let ct = ct.split(';').next().unwrap_or("").trim();
+    matches!(
+        ct,
+        "application/vnd.apple.mpegurl"
+            | "video/mp4"
+            | "video/iso.segment"
+            | "audio/mp4"
+            | "text/vtt"
+    )
+}
+
+/// Value of the `Repr-Digest` header (RFC 9530): the SHA-256 of `data`.
+pub fn repr_digest_value(data: &[u8]) -> String {
+    let hash = Sha256::digest(data);
+    format!(
+        "sha-256=:{}:",
+        base64::engine::general_purpose::STANDARD.encode(hash)
+    )
+}
+
+/// `Repr-Digest` middleware for playlists and segments.
+///
+/// Lets caches and debugging tools downstream detect responses that were
+/// truncated or corrupted by a proxy. It runs after compression, so the
+/// digest is over the bytes that are sent. The digest needs the whole
+/// body, so segments are no longer streamed while they are being muxed.
+pub async fn repr_digest(
+    State(state): State<Arc<AppState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let response = next.run(request).await;
+    // The digest of a 206 would have to be over the whole segment.
+    if !state.config.repr_digest
+        || response.status() != StatusCode::OK
+        || !is_hls_response(response.headers())
+    {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let data = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(data) => data,
+        Err(e) => {
+            // The segment failed halfway, pass that on.
+            warn!("repr_digest: reading body: {}", e);
+            let err = std::io::Error::other(e.to_string());
+            let body = Body::from_stream(futures_util::stream::iter([Err::<Bytes, _>(err)]));
+            return Response::from_parts(parts, body);
+        }
+    };
+    if let Ok(value) = HeaderValue::from_str(&repr_digest_value(&data)) {
+        parts
+            .headers
+            .insert(HeaderName::from_static("repr-digest"), value);
+    }
+    Response::from_parts(parts, Body::from(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_str(value).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Encoding::negotiate(&HeaderMap::new()), None);
+        assert_eq!(Encoding::negotiate(&accept("identity")), None);
+        assert_eq!(Encoding::negotiate(&accept("gzip")), Some(Encoding::Gzip));
+        assert_eq!(
+            Encoding::negotiate(&accept("gzip, deflate, br")),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(
+            Encoding::negotiate(&accept("br;q=0, gzip;q=0.5")),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(Encoding::negotiate(&accept("gzip;q=0")), None);
+    }
+
+    #[test]
+    fn test_compress_roundtrip() {
+        let data = "#EXTINF:4.000000,\nv/segment.mp4\n".repeat(500);
+
+        let gz = Encoding::Gzip.compress(data.as_bytes()).unwrap();
+        let mut out = String::new();
+        flate2::read::GzDecoder::new(&gz[..])
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!(out, data);
+
+        let br = Encoding::Brotli.compress(data.as_bytes()).unwrap();
+        let mut out = String::new();
+        brotli::Decompressor::new(&br[..], 4096)
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!(out, data);
+        assert!(br.len() < data.len() / 10);
+    }
+
+    #[test]
+    fn test_compression_cache() {
+        let cache = CompressionCache::new(&CompressionConfig {
+            cache_entries: 2,
+            ..Default::default()
+        });
+        let a = cache.get_or_compress(Encoding::Gzip, b"aaaa").unwrap();
+        let b = cache.get_or_compress(Encoding::Gzip, b"aaaa").unwrap();
+        assert_eq!(a, b);
+        assert_eq!(cache.len(), 1);
+        cache.get_or_compress(Encoding::Brotli, b"aaaa").unwrap();
+        cache.get_or_compress(Encoding::Gzip, b"bbbb").unwrap();
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_valid_request_id() {
+        assert!(valid_request_id(b"3f2a9c1e-7b44-4d0a-9e8f-0c1d2e3f4a5b"));
+        assert!(valid_request_id(b"edge-lb:12345"));
+        assert!(!valid_request_id(b""));
+        assert!(!valid_request_id(b"id with spaces"));
+        assert!(!valid_request_id(&[b'a'; 200]));
+    }
+
+    #[test]
+    fn test_repr_digest_value() {
+        // RFC 9530, the SHA-256 of `{"hello": "world"}`.
+        assert_eq!(
+            repr_digest_value(b"{\"hello\": \"world\"}"),
+            "sha-256=:X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=:"
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("audio/mp4"));
+        assert!(is_hls_response(&headers));
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        assert!(!is_hls_response(&headers));
+    }
+}
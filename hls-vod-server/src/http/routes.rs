@@ -1,19 +1,28 @@
 //! Axum router configuration
 
 use axum::{
+    extract::DefaultBodyLimit,
     http::{header, Method},
-    routing::{any, get},
+    routing::{any, get, post},
     Router,
 };
 use std::sync::Arc;
 use std::time::Duration;
 use tower_http::cors::{Any, CorsLayer};
+use tower_http::limit::RequestBodyLimitLayer;
 use tower_http::trace::TraceLayer;
 
+use crate::limits::{rate_limit_middleware, url_length_middleware};
 use crate::state::AppState;
 
 use super::dynamic::handle_dynamic_request;
-use super::handlers::{active_streams, cache_stats, health_check, version_check};
+use super::handlers::{
+    active_streams, artwork, attachment, cache_stats, check_consistency, check_drift,
+    compare_muxers, disable_track, enable_track, export_hot_set, get_progress, health_check,
+    import_hot_set, keepalive, list_disabled_tracks, memory_stats, probe, report_progress,
+    season_manifest, stream_errors, user_token, version_check,
+};
+use super::middleware::{compress_playlists, repr_digest, request_id, REQUEST_ID};
 
 /// Create the Axum router with all routes
 pub fn create_router(state: Arc<AppState>) -> Router {
@@ -30,22 +39,76 @@ pub fn create_router(state: Arc<AppState>) -> Router {
             header::ORIGIN,
         ])
         .allow_private_network(true)
+        .expose_headers([REQUEST_ID, header::CONTENT_RANGE])
         .max_age(Duration::from_secs(3600));
 
+    let max_body = state.config.max_request_size_mb.unwrap_or(10) * 1024 * 1024;
+    let max_url_length = state.config.max_url_length.unwrap_or(8192);
+
     // Build router
-    Router::new()
+    let mut router = Router::new()
         // Health and version endpoints
         .route("/health", get(health_check))
         .route("/version", get(version_check))
+        // Player heartbeat
+        .route("/streams/{id}/keepalive", post(keepalive).get(keepalive))
+        // Fonts attached to the file (client-side ASS rendering)
+        .route("/streams/{id}/attachments/{name}", get(attachment))
+        // Cover art (attached pictures)
+        .route("/streams/{id}/artwork/{track}", get(artwork))
+        // Episodes of a folder, for series front-ends
+        .route("/season/{*path}", get(season_manifest))
+        // Resume positions
+        .route("/progress", post(report_progress).get(get_progress))
         // Debug endpoints
         .route("/debug/cache", get(cache_stats))
+        .route("/debug/memory", get(memory_stats))
         .route("/debug/streams", get(active_streams))
+        .route("/debug/errors", get(stream_errors))
+        .route("/debug/probe/{*path}", get(probe))
+        .route("/debug/compare/{*path}", get(compare_muxers))
+        .route("/debug/consistency/{*path}", get(check_consistency))
+        .route("/debug/drift/{*path}", get(check_drift))
+        // Admin endpoints
+        .route(
+            "/admin/tracks/{*path}",
+            get(list_disabled_tracks)
+                .post(disable_track)
+                .delete(enable_track),
+        )
+        .route(
+            "/admin/cache/hot-set",
+            get(export_hot_set).post(import_hot_set),
+        )
+        .route("/admin/user-token", get(user_token))
         // Media wildcard
         // Using `any` ensures that `OPTIONS` requests to media paths
         // are handled correctly by the handler or CORS layer.
         .route("/{*path}", any(handle_dynamic_request))
         // Middleware
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            compress_playlists,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            repr_digest,
+        ))
+        .layer(DefaultBodyLimit::max(max_body))
+        .layer(RequestBodyLimitLayer::new(max_body))
+        .layer(axum::middleware::from_fn_with_state(
+            max_url_length,
+            url_length_middleware,
+        ));
+    if state.config.rate_limit_rps.unwrap_or(100) > 0 {
+        router = router.layer(axum::middleware::from_fn_with_state(
+            state.rate_limiter.clone(),
+            rate_limit_middleware,
+        ));
+    }
+    router
         .layer(TraceLayer::new_for_http())
+        .layer(axum::middleware::from_fn(request_id))
         .layer(cors)
         // State
         .with_state(state)
@@ -100,4 +163,416 @@ mod tests {
             .unwrap()
             .contains("GET"));
     }
+
+    #[tokio::test]
+    async fn test_url_too_long() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::util::ServiceExt;
+
+        let state = Arc::new(AppState::new(ServerConfig {
+            max_url_length: Some(64),
+            ..Default::default()
+        }));
+        let app = create_router(state);
+
+        let request = Request::builder()
+            .uri(format!("/{}.mp4.as.m3u8", "a".repeat(100)))
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::URI_TOO_LONG);
+    }
+
+    #[tokio::test]
+    async fn test_parent_dir_forbidden() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::util::ServiceExt;
+
+        let state = Arc::new(AppState::new(ServerConfig::default()));
+        let app = create_router(state);
+
+        let request = Request::builder()
+            .uri("/media/../etc/test.mp4.as.m3u8")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_admin_disabled() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::util::ServiceExt;
+
+        let state = Arc::new(AppState::new(ServerConfig::default()));
+        let app = create_router(state);
+
+        let request = Request::builder()
+            .uri("/admin/tracks/media/test.mp4")
+            .header(header::AUTHORIZATION, "Bearer anything")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_errors_admin_only() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::util::ServiceExt;
+
+        let state = Arc::new(AppState::new(ServerConfig {
+            admin_token: Some("secret".to_string()),
+            ..Default::default()
+        }));
+        let app = create_router(state);
+
+        let request = |auth: &str| {
+            Request::builder()
+                .uri("/debug/errors")
+                .header(header::AUTHORIZATION, auth)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request("Bearer wrong")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = app.oneshot(request("Bearer secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_compare_invalid_options() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::util::ServiceExt;
+
+        let state = Arc::new(AppState::new(ServerConfig {
+            admin_token: Some("secret".to_string()),
+            ..Default::default()
+        }));
+        let app = create_router(state);
+
+        let request = |auth: &str| {
+            Request::builder()
+                .uri("/debug/compare/media/test.mp4/v/0.1.m4s?a=no_styp&b=fast")
+                .header(header::AUTHORIZATION, auth)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request("Bearer wrong")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = app.oneshot(request("Bearer secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_consistency_invalid_segment() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::util::ServiceExt;
+
+        let state = Arc::new(AppState::new(ServerConfig {
+            admin_token: Some("secret".to_string()),
+            ..Default::default()
+        }));
+        let app = create_router(state);
+
+        let request = |auth: &str| {
+            Request::builder()
+                .uri("/debug/consistency/media/test.mp4?segment=first")
+                .header(header::AUTHORIZATION, auth)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request("Bearer wrong")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = app.oneshot(request("Bearer secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_unknown_stream() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::util::ServiceExt;
+
+        let state = Arc::new(AppState::new(ServerConfig::default()));
+        let app = create_router(state);
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/streams/no-such-stream/keepalive")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_attachment_unknown_stream() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::util::ServiceExt;
+
+        let state = Arc::new(AppState::new(ServerConfig::default()));
+        let app = create_router(state);
+
+        let request = Request::builder()
+            .uri("/streams/no-such-stream/attachments/Arial.ttf")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_progress() {
+        use crate::config::AuthConfig;
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::util::ServiceExt;
+
+        let mut config = ServerConfig::default();
+        config.bookmarks.enabled = true;
+        config.bookmarks.max_titles_per_user = 1;
+        config.admin_token = Some("admin".to_string());
+        // Not through `AppState::new`, which would install the signing URL codec.
+        let mut state = AppState::new(config);
+        state.config.auth = Some(AuthConfig {
+            secret: "secret".to_string(),
+            url_ttl_secs: 3600,
+        });
+        let app = create_router(Arc::new(state));
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/admin/user-token?user=alice")
+                    .header(header::AUTHORIZATION, "Bearer admin")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let token = body["user_token"].as_str().unwrap().to_string();
+        assert_eq!(token, crate::bookmarks::user_token("secret", "alice"));
+
+        let report = |token: &str, title: &str, position: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/progress")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(format!(
+                    r#"{{"user":"alice","user_token":"{}","title":"{}","position":{}}}"#,
+                    token, title, position
+                )))
+                .unwrap()
+        };
+        let get = |token: &str| {
+            Request::builder()
+                .uri(format!(
+                    "/progress?user=alice&user_token={}&title=movies/film.mkv",
+                    token
+                ))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(report(&token, "/movies/film.mkv", "-1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = app
+            .clone()
+            .oneshot(report("bad", "/movies/film.mkv", "754.5"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = app
+            .clone()
+            .oneshot(report(&token, "/movies/film.mkv", "754.5"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = app.clone().oneshot(get("bad")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = app.clone().oneshot(get(&token)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // One title per user: another one is refused, the same one is not.
+        let response = app
+            .clone()
+            .oneshot(report(&token, "/movies/other.mkv", "60"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = app
+            .clone()
+            .oneshot(report(&token, "/movies/film.mkv", "800"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = app
+            .clone()
+            .oneshot(report(&token, "/movies/film.mkv", "null"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = app.oneshot(get(&token)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_season_needs_media_roots() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::util::ServiceExt;
+
+        let state = Arc::new(AppState::new(ServerConfig::default()));
+        let app = create_router(state);
+
+        let request = Request::builder()
+            .uri("/season/etc")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_root_token_before_path() {
+        use crate::config::MediaRoot;
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::util::ServiceExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.mp4"), b"").unwrap();
+        let state = Arc::new(AppState::new(ServerConfig {
+            media_roots: vec![MediaRoot {
+                name: "kids".to_string(),
+                path: dir.path().display().to_string(),
+                readonly_tokens: vec!["secret".to_string()],
+            }],
+            ..Default::default()
+        }));
+        let app = create_router(state);
+        let status = |uri: &str| {
+            let app = app.clone();
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+
+        // Without a token, a file that exists and one that doesn't look the same.
+        assert_eq!(status("/kids/a.mp4.as.m3u8").await, StatusCode::FORBIDDEN);
+        assert_eq!(
+            status("/kids/missing.mp4.as.m3u8").await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status("/kids/missing.mp4.as.m3u8?token=secret").await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_artwork_unknown_stream() {
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use tower::util::ServiceExt;
+
+        let state = Arc::new(AppState::new(ServerConfig::default()));
+        let app = create_router(state);
+
+        let request = Request::builder()
+            .uri("/streams/no-such-stream/artwork/1")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_playlist_delta_update() {
+        use crate::config::MediaRoot;
+        use axum::body::Body;
+        use axum::http::{Request, StatusCode};
+        use hls_vod_lib::bench::{generate, FixtureSpec};
+        use tower::util::ServiceExt;
+
+        // Long enough to have segments older than CAN-SKIP-UNTIL.
+        let spec = FixtureSpec {
+            name: "h264_aac_60s",
+            duration_secs: 60,
+            ..FixtureSpec::h264_aac()
+        };
+        let Some(path) = generate(&spec) else {
+            return;
+        };
+        hls_vod_lib::set_playlist_delta_updates(true);
+        let state = Arc::new(AppState::new(ServerConfig {
+            media_roots: vec![MediaRoot {
+                name: "media".to_string(),
+                path: path.parent().unwrap().display().to_string(),
+                readonly_tokens: Vec::new(),
+            }],
+            ..Default::default()
+        }));
+        let app = create_router(state);
+
+        let get = |uri: String| {
+            let app = app.clone();
+            async move {
+                let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        let file = path.file_name().unwrap().to_str().unwrap();
+        let main = get(format!("/media/{}.as.m3u8", file)).await;
+        let variant = main
+            .lines()
+            .find(|l| !l.is_empty() && !l.starts_with('#'))
+            .unwrap();
+
+        // A complete playlist: delta updates are not advertised.
+        let full = get(format!("/media/{}", variant)).await;
+        assert!(full.ends_with("#EXT-X-ENDLIST\n"));
+        assert!(!full.contains("#EXT-X-SERVER-CONTROL"));
+        assert!(!full.contains("#EXT-X-SKIP"));
+
+        let delta = get(format!("/media/{}?_HLS_skip=YES", variant)).await;
+        assert!(delta.contains("#EXT-X-SKIP:SKIPPED-SEGMENTS="), "{}", delta);
+        assert!(delta.contains("#EXT-X-VERSION:9\n"));
+        assert!(delta.len() < full.len());
+        assert!(delta.ends_with("#EXT-X-ENDLIST\n"));
+    }
 }
@@ -2,14 +2,14 @@
 
 use axum::{
     body::Body,
-    extract::State,
+    extract::{ConnectInfo, State},
     http::{Request, StatusCode},
     middleware::Next,
     response::Response,
 };
 use parking_lot::RwLock;
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -82,7 +82,7 @@ impl TokenBucket {
 #[derive(Debug)]
 pub struct RateLimiter {
     /// Per-IP rate limiters
-    limiters: RwLock<HashMap<SocketAddr, TokenBucket>>,
+    limiters: RwLock<HashMap<IpAddr, TokenBucket>>,
     /// Configuration
     config: RateLimitConfig,
 }
@@ -96,7 +96,7 @@ impl RateLimiter {
     }
 
     /// Check if request is allowed
-    pub fn is_allowed(&self, ip: SocketAddr) -> bool {
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
         let mut limiters = self.limiters.write();
 
         let limiter = limiters.entry(ip).or_insert_with(|| {
@@ -176,18 +176,22 @@ impl ConnectionLimiter {
     }
 }
 
+/// IP address of the client, if the server was started with connect info.
+fn client_ip(request: &Request<Body>) -> IpAddr {
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())
+        .unwrap_or(IpAddr::from([0, 0, 0, 0]))
+}
+
 /// Rate limiting middleware
 pub async fn rate_limit_middleware(
     State(limiter): State<Arc<RateLimiter>>,
     request: Request<Body>,
     next: Next,
 ) -> Result<Response, (StatusCode, &'static str)> {
-    // Get client IP
-    let ip = request
-        .extensions()
-        .get::<SocketAddr>()
-        .copied()
-        .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+    let ip = client_ip(&request);
 
     if !limiter.is_allowed(ip) {
         return Err((StatusCode::TOO_MANY_REQUESTS, "Rate limit exceeded"));
@@ -221,6 +225,26 @@ pub async fn connection_limit_middleware(
     Ok(response)
 }
 
+/// URL length limiting middleware
+///
+/// We map URLs to filesystem paths, there is no reason to accept huge ones.
+pub async fn url_length_middleware(
+    State(max_length): State<usize>,
+    request: Request<Body>,
+    next: Next,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let length = request
+        .uri()
+        .path_and_query()
+        .map(|pq| pq.as_str().len())
+        .unwrap_or(0);
+    if length > max_length {
+        return Err((StatusCode::URI_TOO_LONG, "URL too long"));
+    }
+
+    Ok(next.run(request).await)
+}
+
 /// Create rate limiter from config
 pub fn create_rate_limiter(config: &crate::config::ServerConfig) -> Arc<RateLimiter> {
     // Default rate limits
@@ -228,7 +252,7 @@ pub fn create_rate_limiter(config: &crate::config::ServerConfig) -> Arc<RateLimi
 
     Arc::new(RateLimiter::new(RateLimitConfig {
         requests_per_second: rate_limit,
-        burst_size: rate_limit / 2,
+        burst_size: (rate_limit / 2).max(1),
     }))
 }
 
@@ -266,7 +290,7 @@ mod tests {
             burst_size: 5,
         });
 
-        let ip = SocketAddr::from(([127, 0, 0, 1], 8080));
+        let ip = IpAddr::from([127, 0, 0, 1]);
 
         // Should allow burst
         for _ in 0..5 {
@@ -275,6 +299,9 @@ mod tests {
 
         // Should be rate limited
         assert!(!limiter.is_allowed(ip));
+
+        // Other clients are not
+        assert!(limiter.is_allowed(IpAddr::from([127, 0, 0, 2])));
     }
 
     #[test]
@@ -6,13 +6,19 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
+mod auth;
+mod bookmarks;
 mod config;
 mod config_file;
 mod error;
 mod http;
 mod limits;
 mod metrics;
+mod roots;
+#[cfg(test)]
+mod simulator;
 mod state;
+mod worker;
 
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -31,8 +37,11 @@ const APP_NAME: &str = "hls-vod-server";
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    // Started by the server as a segment worker, see `worker`.
+    let worker_mode = std::env::args().any(|arg| arg == worker::WORKER_ARG);
+
     // Initialize logging
-    init_logging();
+    init_logging(worker_mode);
 
     tracing::info!("{} v{} starting", APP_NAME, VERSION);
     tracing::info!("FFmpeg version: {}", hls_vod_lib::ffmpeg_version_info());
@@ -62,11 +71,81 @@ async fn main() -> Result<()> {
         ServerConfig::default()
     };
     tracing::info!("Configuration loaded: {:?}", config);
+    crate::roots::validate(&config.media_roots, config.auth.is_some())
+        .map_err(crate::error::ServerError::Config)?;
+    if let Some(level) = &config.hdcp_level {
+        if !hls_vod_lib::HDCP_LEVELS.contains(&level.as_str()) {
+            return Err(crate::error::ServerError::Config(format!(
+                "invalid hdcp_level {}, expected one of {:?}",
+                level,
+                hls_vod_lib::HDCP_LEVELS
+            )));
+        }
+    }
+    if config.bookmarks.enabled && config.auth.is_none() {
+        tracing::warn!("[bookmarks] needs [auth]: user tokens are signed with its secret");
+    }
+
+    // Select the AAC encoder for audio transcoding.
+    if config.audio.enable_transcoding {
+        hls_vod_lib::set_aac_encoder(hls_vod_lib::AacEncoderConfig {
+            encoder: config.audio.aac_encoder,
+            bitrate: None,
+            vbr: config.audio.aac_vbr,
+        })
+        .map_err(|e| crate::error::ServerError::Internal(e.to_string()))?;
+    }
+    hls_vod_lib::set_lib_config(
+        hls_vod_lib::LibConfig::default()
+            .segment_duration(config.segment.target_duration_secs)
+            .aac_bitrates(hls_vod_lib::AacBitrates {
+                stereo: config.audio.aac_bitrate,
+                ..Default::default()
+            })
+            .sample_rate(config.audio.target_sample_rate)
+            .downmix(config.audio.downmix)
+            .audio_mix(config.audio.mix)
+            .fast_start(config.segment.fast_start)
+            .max_segments(config.segment.max_segments),
+    )
+    .map_err(|e| crate::error::ServerError::Config(e.to_string()))?;
+    hls_vod_lib::set_hd_audio(config.audio.hd_audio);
+    hls_vod_lib::set_retry_policy(config.segment.retry.clone());
+    hls_vod_lib::set_audio_segment_duration(config.segment.audio_segment_duration_secs);
+    hls_vod_lib::set_playlist_byte_budget(config.segment.playlist_byte_budget);
+    hls_vod_lib::set_video_stats(config.segment.video_stats);
+    hls_vod_lib::set_compatibility_profile(config.segment.compatibility_profile);
+    hls_vod_lib::set_negative_ts_policy(config.segment.negative_ts);
+    hls_vod_lib::set_playlist_delta_updates(config.segment.delta_updates);
+    hls_vod_lib::set_merge_overlapping_cues(config.subtitles.merge_overlapping_cues);
+    hls_vod_lib::set_subtitle_timestamps(config.subtitles.timestamps);
+    hls_vod_lib::set_spanning_cues(config.subtitles.spanning_cues);
+    hls_vod_lib::set_subtitle_batch_extraction(config.subtitles.batch_extraction);
+    hls_vod_lib::set_ffmpeg_log_config(config.ffmpeg_log.clone());
+    if let Some(extensions) = &config.source_extensions {
+        hls_vod_lib::set_source_extensions(extensions.clone());
+    }
+    if let Some(filters) = &config.audio.bitstream_filters {
+        hls_vod_lib::set_audio_bitstream_filters(filters.clone())
+            .map_err(|e| crate::error::ServerError::Internal(e.to_string()))?;
+    }
+
+    if worker_mode {
+        hls_vod_lib::cache::init_segment_cache(config.cache.clone());
+        return worker::run();
+    }
 
     // Create application state
-    let state = Arc::new(AppState::new(config.clone()));
+    let state = Arc::new(AppState::new(config.clone()).with_workers(&config_path)?);
+    if config.workers > 0 {
+        tracing::info!("Generating segments in worker processes");
+    } else {
+        // Workers have their own caches, the warmer can't fill them.
+        hls_vod_lib::warmer::start_warmer(config.warmer.clone());
+    }
 
-    // Background task: evict expired streams every 60 seconds.
+    // Background task: evict expired streams and save the resume positions
+    // every 60 seconds.
     {
         let state_bg = Arc::clone(&state);
         tokio::spawn(async move {
@@ -78,6 +157,9 @@ async fn main() -> Result<()> {
                 if removed > 0 {
                     tracing::info!("Evicted {} expired stream(s)", removed);
                 }
+                state_bg.cleanup_rate_limiter();
+                let state_bg = Arc::clone(&state_bg);
+                let _ = tokio::task::spawn_blocking(move || state_bg.flush_bookmarks()).await;
             }
         });
     }
@@ -90,20 +172,59 @@ async fn main() -> Result<()> {
     tracing::info!("Starting HTTP server on {}", addr);
 
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    // Connect info gives the rate limiter the client IP.
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap();
+
+    // The background task only saves the resume positions once a minute.
+    tracing::info!("Shutting down");
+    let _ = tokio::task::spawn_blocking(move || state.flush_bookmarks()).await;
 
     Ok(())
 }
 
+/// Wait for Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
 /// Initialize logging with tracing
-fn init_logging() {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "hls_vod_server=debug,tower_http=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+///
+/// Workers log to stderr, their stdout carries the responses.
+fn init_logging(worker_mode: bool) {
+    let registry = tracing_subscriber::registry().with(
+        tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| "hls_vod_server=debug,tower_http=debug,ffmpeg=warn".into()),
+    );
+    if worker_mode {
+        registry
+            .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+            .init();
+    } else {
+        registry.with(tracing_subscriber::fmt::layer()).init();
+    }
 }
 
 #[cfg(test)]
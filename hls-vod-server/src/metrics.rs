@@ -173,6 +173,20 @@ impl Metrics {
         output.push_str("# TYPE hls_segment_cache_hit_ratio gauge\n");
         output.push_str(&format!("hls_segment_cache_hit_ratio {:.4}\n", hit_ratio));
 
+        let cache = hls_vod_lib::cache::segment_cache_stats();
+        output.push_str("\n# HELP hls_segment_cache_saved_generation_seconds_total Generation time of the segments served from the cache\n");
+        output.push_str("# TYPE hls_segment_cache_saved_generation_seconds_total counter\n");
+        output.push_str(&format!(
+            "hls_segment_cache_saved_generation_seconds_total {:.3}\n",
+            cache.saved_generation_secs
+        ));
+        output.push_str("\n# HELP hls_segment_cache_evicted_generation_seconds_total Generation time of the segments evicted from the cache\n");
+        output.push_str("# TYPE hls_segment_cache_evicted_generation_seconds_total counter\n");
+        output.push_str(&format!(
+            "hls_segment_cache_evicted_generation_seconds_total {:.3}\n",
+            cache.evicted_generation_secs
+        ));
+
         // Stream metrics
         output.push_str("\n# HELP hls_active_streams Number of active streams\n");
         output.push_str("# TYPE hls_active_streams gauge\n");
@@ -181,6 +195,37 @@ impl Metrics {
             *self.active_streams.read()
         ));
 
+        // Memory metrics
+        let memory = hls_vod_lib::memory::memory_stats();
+        output.push_str("\n# HELP hls_memory_bytes Approximate memory usage by subsystem\n");
+        output.push_str("# TYPE hls_memory_bytes gauge\n");
+        for (subsystem, bytes) in [
+            ("segment_cache", memory.segment_cache_bytes),
+            ("stream_index", memory.stream_index_bytes),
+            ("input_context", memory.input_context_bytes),
+            ("demux_cursor", memory.demux_cursor_bytes),
+            ("in_flight", memory.in_flight_bytes),
+        ] {
+            output.push_str(&format!(
+                "hls_memory_bytes{{subsystem=\"{}\"}} {}\n",
+                subsystem, bytes
+            ));
+        }
+        output.push_str(
+            "\n# HELP hls_memory_budget_bytes Configured memory budget (0 = unlimited)\n",
+        );
+        output.push_str("# TYPE hls_memory_budget_bytes gauge\n");
+        output.push_str(&format!(
+            "hls_memory_budget_bytes {}\n",
+            memory.budget_bytes
+        ));
+        output.push_str("\n# HELP hls_memory_evictions_total Times memory was released to stay within the budget\n");
+        output.push_str("# TYPE hls_memory_evictions_total counter\n");
+        output.push_str(&format!(
+            "hls_memory_evictions_total {}\n",
+            memory.evictions
+        ));
+
         // Transcoding metrics
         output.push_str("\n# HELP hls_transcode_operations_total Total transcoding operations\n");
         output.push_str("# TYPE hls_transcode_operations_total counter\n");
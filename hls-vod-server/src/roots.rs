@@ -0,0 +1,236 @@
+//! Named media roots
+//!
+//! With `[[media_roots]]` configured, the first component of the URL path
+//! is the name of a root, and the rest of the path is relative to the
+//! directory of that root. Every root can have its own set of tokens.
+//!
+//! Without media roots, the URL path is the filesystem path.
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use axum::http::{header, HeaderMap};
+
+use crate::config::MediaRoot;
+
+/// Why a URL could not be mapped to a file.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResolveError {
+    /// No such root, or no such file.
+    NotFound,
+    /// The path contains `..`, or leaves the root via a symlink.
+    Forbidden,
+}
+
+/// Check the media roots in the configuration.
+///
+/// Roots with tokens need `signed_urls` (`[auth]`): the URLs in a playlist
+/// don't carry the token, only a signature.
+pub fn validate(roots: &[MediaRoot], signed_urls: bool) -> Result<(), String> {
+    for (i, root) in roots.iter().enumerate() {
+        if root.name.is_empty() || root.name.contains('/') || root.name == ".." {
+            return Err(format!("media root: invalid name {:?}", root.name));
+        }
+        if roots[..i].iter().any(|r| r.name == root.name) {
+            return Err(format!("media root: duplicate name {:?}", root.name));
+        }
+        if !root.readonly_tokens.is_empty() && !signed_urls {
+            return Err(format!(
+                "media root {}: readonly_tokens needs [auth], playlist URLs don't carry the token",
+                root.name
+            ));
+        }
+        if !Path::new(&root.path).is_dir() {
+            return Err(format!(
+                "media root {}: {} is not a directory",
+                root.name, root.path
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Map the video part of a request URL to a path on disk.
+///
+/// Returns the path and the media root it is in, if media roots are configured.
+pub fn resolve<'a>(
+    roots: &'a [MediaRoot],
+    video_url: &str,
+) -> Result<(PathBuf, Option<&'a MediaRoot>), ResolveError> {
+    if Path::new(video_url)
+        .components()
+        .any(|c| matches!(c, Component::ParentDir))
+    {
+        return Err(ResolveError::Forbidden);
+    }
+
+    if roots.is_empty() {
+        return Ok((crate::http::dynamic::resolve_media_path(video_url), None));
+    }
+
+    let video_url = video_url.trim_start_matches('/');
+    let (name, rest) = video_url.split_once('/').ok_or(ResolveError::NotFound)?;
+    let root = roots
+        .iter()
+        .find(|r| r.name == name)
+        .ok_or(ResolveError::NotFound)?;
+
+    // Symlinks inside the root are fine, as long as they don't point outside of it.
+    let dir = Path::new(&root.path)
+        .canonicalize()
+        .map_err(|_| ResolveError::NotFound)?;
+    let path = dir
+        .join(rest)
+        .canonicalize()
+        .map_err(|_| ResolveError::NotFound)?;
+    if !path.starts_with(&dir) {
+        tracing::warn!("{}: {:?} is outside of the media root", video_url, path);
+        return Err(ResolveError::Forbidden);
+    }
+    Ok((path, Some(root)))
+}
+
+/// The media root of `video_url`, going by the name in the URL only.
+///
+/// Unlike `resolve`, this doesn't look at the filesystem, so the token of the
+/// root can be checked before a caller learns whether a file exists. `None`
+/// without media roots, or if there is no root of that name.
+pub fn find_root<'a>(roots: &'a [MediaRoot], video_url: &str) -> Option<&'a MediaRoot> {
+    let video_url = video_url.trim_start_matches('/');
+    let (name, _) = video_url.split_once('/')?;
+    roots.iter().find(|r| r.name == name)
+}
+
+/// The media root that file `path` (a canonical path) is in, if any.
+pub fn root_of_path<'a>(roots: &'a [MediaRoot], path: &Path) -> Option<&'a MediaRoot> {
+    roots.iter().find(|r| {
+        Path::new(&r.path)
+            .canonicalize()
+            .is_ok_and(|dir| path.starts_with(dir))
+    })
+}
+
+/// Check the token of a request for a media root.
+///
+/// The token is either the `token` query parameter or a bearer token.
+/// A root without tokens is open to everyone.
+pub fn token_ok(root: &MediaRoot, query: &HashMap<String, String>, headers: &HeaderMap) -> bool {
+    if root.readonly_tokens.is_empty() {
+        return true;
+    }
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let token = query.get("token").map(|t| t.as_str()).or(bearer);
+    match token {
+        Some(token) => root
+            .readonly_tokens
+            .iter()
+            .any(|t| crate::auth::constant_time_eq(t, token)),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roots(dir: &Path) -> Vec<MediaRoot> {
+        vec![
+            MediaRoot {
+                name: "movies".to_string(),
+                path: dir.join("movies").to_str().unwrap().to_string(),
+                readonly_tokens: Vec::new(),
+            },
+            MediaRoot {
+                name: "kids".to_string(),
+                path: dir.join("kids").to_str().unwrap().to_string(),
+                readonly_tokens: vec!["secret".to_string()],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("movies")).unwrap();
+        std::fs::create_dir(dir.path().join("kids")).unwrap();
+        std::fs::write(dir.path().join("movies/a.mp4"), b"").unwrap();
+        std::fs::write(dir.path().join("kids/b.mp4"), b"").unwrap();
+        let roots = roots(dir.path());
+        assert!(validate(&roots, true).is_ok());
+
+        let (path, root) = resolve(&roots, "movies/a.mp4").unwrap();
+        assert!(path.ends_with("movies/a.mp4"));
+        assert_eq!(root.unwrap().name, "movies");
+
+        assert_eq!(
+            resolve(&roots, "movies/b.mp4").unwrap_err(),
+            ResolveError::NotFound
+        );
+        assert_eq!(
+            resolve(&roots, "other/a.mp4").unwrap_err(),
+            ResolveError::NotFound
+        );
+        assert_eq!(
+            resolve(&roots, "movies/../kids/b.mp4").unwrap_err(),
+            ResolveError::Forbidden
+        );
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.path().join("kids"), dir.path().join("movies/k"))
+                .unwrap();
+            assert_eq!(
+                resolve(&roots, "movies/k/b.mp4").unwrap_err(),
+                ResolveError::Forbidden
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("movies")).unwrap();
+        let mut roots = roots(dir.path());
+        // "kids" does not exist.
+        assert!(validate(&roots, true).is_err());
+        roots[1].path = roots[0].path.clone();
+        assert!(validate(&roots, true).is_ok());
+        // "kids" has tokens, which need signed URLs.
+        assert!(validate(&roots, false).is_err());
+        roots[1].name = "movies".to_string();
+        assert!(validate(&roots, true).is_err());
+    }
+
+    #[test]
+    fn test_root_of_path() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("movies")).unwrap();
+        std::fs::write(dir.path().join("movies/a.mp4"), b"").unwrap();
+        let roots = roots(dir.path());
+        let (path, _) = resolve(&roots, "movies/a.mp4").unwrap();
+        assert_eq!(root_of_path(&roots, &path).unwrap().name, "movies");
+        assert!(root_of_path(&roots, Path::new("/elsewhere/a.mp4")).is_none());
+    }
+
+    #[test]
+    fn test_token_ok() {
+        let roots = roots(Path::new("/nonexistent"));
+        let mut query = HashMap::new();
+        let mut headers = HeaderMap::new();
+
+        assert!(token_ok(&roots[0], &query, &headers));
+        assert!(!token_ok(&roots[1], &query, &headers));
+
+        query.insert("token".to_string(), "wrong".to_string());
+        assert!(!token_ok(&roots[1], &query, &headers));
+        query.insert("token".to_string(), "secret".to_string());
+        assert!(token_ok(&roots[1], &query, &headers));
+
+        query.clear();
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert!(token_ok(&roots[1], &query, &headers));
+    }
+}
@@ -0,0 +1,416 @@
+//! HLS playback simulator for end-to-end tests
+//!
+//! Acts like a player against a server on a real TCP socket: fetches the
+//! main playlist, picks a variant and an audio rendition, fetches the init
+//! segments and then the media segments in order, and checks the fMP4
+//! timing of what it receives:
+//!
+//! - the `tfdt` of every segment continues where the previous one ended,
+//! - `mfhd` sequence numbers increase,
+//! - audio and video segments start at the same time,
+//! - after switching to another audio rendition, audio continues at the
+//!   same position.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hls_vod_lib::{analyze_segment, SegmentStructure};
+
+use crate::config::ServerConfig;
+use crate::http::create_router;
+use crate::state::AppState;
+
+/// Maximum start time difference between audio and video, in seconds.
+const AV_TOLERANCE: f64 = 0.1;
+
+/// Start a server on a random local port.
+pub async fn start_server(config: ServerConfig) -> SocketAddr {
+    let state = Arc::new(AppState::new(config));
+    let app = create_router(state);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+    addr
+}
+
+/// A variant stream or rendition from the main playlist.
+#[derive(Debug, Clone)]
+pub struct Rendition {
+    pub uri: String,
+    pub attributes: HashMap<String, String>,
+}
+
+/// A parsed media playlist.
+#[derive(Debug, Clone)]
+pub struct MediaPlaylist {
+    pub url: String,
+    pub init: Option<String>,
+    pub segments: Vec<(f64, String)>,
+    /// Timescale per track id, from the init segment
+    pub timescales: HashMap<u32, u32>,
+}
+
+/// The timing of one received media segment, in seconds.
+#[derive(Debug, Clone)]
+pub struct SegmentTiming {
+    pub url: String,
+    pub sequence_numbers: Vec<u32>,
+    /// Per track: start and end time
+    pub tracks: Vec<(u32, f64, f64)>,
+}
+
+impl SegmentTiming {
+    pub fn start(&self) -> f64 {
+        self.tracks
+            .iter()
+            .map(|t| t.1)
+            .fold(f64::INFINITY, f64::min)
+    }
+}
+
+fn parse_attributes(line: &str) -> HashMap<String, String> {
+    let mut attrs = HashMap::new();
+    let mut rest = line.split_once(':').map(|(_, r)| r).unwrap_or("");
+    while !rest.is_empty() {
+        let Some((key, value)) = rest.split_once('=') else {
+            break;
+        };
+        let (value, next) = match value.strip_prefix('"') {
+            Some(v) => {
+                let end = v.find('"').unwrap_or(v.len());
+                (&v[..end], v[end..].trim_start_matches('"'))
+            }
+            None => match value.find(',') {
+                Some(end) => (&value[..end], &value[end..]),
+                None => (value, ""),
+            },
+        };
+        attrs.insert(key.trim().to_string(), value.to_string());
+        rest = next.trim_start_matches(',');
+    }
+    attrs
+}
+
+// Resolve `uri` relative to `base`.
+fn resolve(base: &str, uri: &str) -> String {
+    if uri.starts_with("http://") || uri.starts_with("https://") {
+        return uri.to_string();
+    }
+    let base = base.split('?').next().unwrap();
+    match base.rfind('/') {
+        Some(pos) => format!("{}/{}", &base[..pos], uri),
+        None => uri.to_string(),
+    }
+}
+
+/// A simulated player.
+pub struct Player {
+    client: reqwest::Client,
+}
+
+impl Player {
+    pub fn new() -> Self {
+        Player {
+            client: reqwest::Client::new(),
+        }
+    }
+
+    async fn get(&self, url: &str) -> Vec<u8> {
+        let response = self.client.get(url).send().await.unwrap();
+        assert!(
+            response.status().is_success(),
+            "GET {}: {}",
+            url,
+            response.status()
+        );
+        response.bytes().await.unwrap().to_vec()
+    }
+
+    async fn get_text(&self, url: &str) -> String {
+        String::from_utf8(self.get(url).await).unwrap()
+    }
+
+    /// Fetch the main playlist: the variants and the audio renditions.
+    pub async fn main_playlist(&self, url: &str) -> (Vec<Rendition>, Vec<Rendition>) {
+        let text = self.get_text(url).await;
+        assert!(text.starts_with("#EXTM3U"), "{}: not a playlist", url);
+        let mut variants = Vec::new();
+        let mut audio = Vec::new();
+        let mut stream_inf = None;
+        for line in text.lines() {
+            if line.starts_with("#EXT-X-STREAM-INF:") {
+                stream_inf = Some(parse_attributes(line));
+            } else if line.starts_with("#EXT-X-MEDIA:") {
+                let attributes = parse_attributes(line);
+                if attributes.get("TYPE").map(|t| t.as_str()) == Some("AUDIO") {
+                    if let Some(uri) = attributes.get("URI") {
+                        audio.push(Rendition {
+                            uri: resolve(url, uri),
+                            attributes,
+                        });
+                    }
+                }
+            } else if !line.starts_with('#') && !line.is_empty() {
+                if let Some(attributes) = stream_inf.take() {
+                    variants.push(Rendition {
+                        uri: resolve(url, line),
+                        attributes,
+                    });
+                }
+            }
+        }
+        (variants, audio)
+    }
+
+    /// Fetch a media playlist.
+    pub async fn media_playlist(&self, url: &str) -> MediaPlaylist {
+        let text = self.get_text(url).await;
+        let mut playlist = MediaPlaylist {
+            url: url.to_string(),
+            init: None,
+            segments: Vec::new(),
+            timescales: HashMap::new(),
+        };
+        let mut duration = None;
+        for line in text.lines() {
+            if line.starts_with("#EXT-X-MAP:") {
+                playlist.init = parse_attributes(line).get("URI").map(|u| resolve(url, u));
+            } else if let Some(d) = line.strip_prefix("#EXTINF:") {
+                duration = d.trim_end_matches(',').parse::<f64>().ok();
+            } else if !line.starts_with('#') && !line.is_empty() {
+                let d = duration.take().expect("segment without EXTINF");
+                playlist.segments.push((d, resolve(url, line)));
+            }
+        }
+        assert!(!playlist.segments.is_empty(), "{}: no segments", url);
+        playlist
+    }
+
+    /// Fetch the init segment of a playlist and remember the timescales.
+    ///
+    /// Track ids are only unique within a playlist, so the timescales are
+    /// kept per playlist.
+    pub async fn load_init(&self, playlist: &mut MediaPlaylist) {
+        let Some(init) = &playlist.init else {
+            panic!("{}: no EXT-X-MAP", playlist.url);
+        };
+        let structure = analyze_segment(&self.get(init).await);
+        assert!(
+            !structure.timescales.is_empty(),
+            "{}: no tracks in init segment",
+            init
+        );
+        for (track, timescale) in structure.timescales {
+            assert!(timescale > 0, "{}: track {} has no timescale", init, track);
+            playlist.timescales.insert(track, timescale);
+        }
+    }
+
+    fn timing(playlist: &MediaPlaylist, url: &str, structure: SegmentStructure) -> SegmentTiming {
+        let tracks = structure
+            .tracks
+            .iter()
+            .map(|t| {
+                let timescale = *playlist
+                    .timescales
+                    .get(&t.track_id)
+                    .unwrap_or_else(|| panic!("{}: unknown track {}", url, t.track_id))
+                    as f64;
+                let start =
+                    t.base_decode_time
+                        .unwrap_or_else(|| panic!("{}: no tfdt", url)) as f64
+                        / timescale;
+                (t.track_id, start, start + t.duration as f64 / timescale)
+            })
+            .collect();
+        SegmentTiming {
+            url: url.to_string(),
+            sequence_numbers: structure.sequence_numbers,
+            tracks,
+        }
+    }
+
+    /// Fetch media segment `n` of a playlist.
+    pub async fn segment(&self, playlist: &MediaPlaylist, n: usize) -> SegmentTiming {
+        let url = &playlist.segments[n].1;
+        let data = self.get(url).await;
+        let structure = analyze_segment(&data);
+        assert!(!structure.tracks.is_empty(), "{}: no fragments", url);
+        Self::timing(playlist, url, structure)
+    }
+}
+
+/// Check that `next` continues where `prev` ended.
+///
+/// `tolerance` is in seconds; audio frames don't line up exactly with
+/// segment boundaries.
+pub fn assert_continuous(prev: &SegmentTiming, next: &SegmentTiming, tolerance: f64) {
+    for &(track, _, end) in &prev.tracks {
+        if let Some(&(_, start, _)) = next.tracks.iter().find(|t| t.0 == track) {
+            assert!(
+                (start - end).abs() <= tolerance,
+                "track {}: {} ends at {:.3}, {} starts at {:.3}",
+                track,
+                prev.url,
+                end,
+                next.url,
+                start
+            );
+        }
+    }
+    let last = prev.sequence_numbers.iter().max();
+    let first = next.sequence_numbers.iter().min();
+    if let (Some(last), Some(first)) = (last, first) {
+        assert!(
+            first > last,
+            "{}: sequence number {} after {}",
+            next.url,
+            first,
+            last
+        );
+    }
+}
+
+/// Check that audio and video segments start at the same time.
+pub fn assert_aligned(video: &SegmentTiming, audio: &SegmentTiming) {
+    let (v, a) = (video.start(), audio.start());
+    assert!(
+        (v - a).abs() <= AV_TOLERANCE,
+        "{} starts at {:.3}, {} at {:.3}",
+        video.url,
+        v,
+        audio.url,
+        a
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_asset() -> Option<std::path::PathBuf> {
+        let path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../tests/assets/bun33s.mp4")
+            .canonicalize()
+            .ok();
+        if path.is_none() {
+            println!("Skipping, tests/assets/bun33s.mp4 doesn't exist");
+        }
+        path
+    }
+
+    #[test]
+    fn test_parse_attributes() {
+        let attrs = parse_attributes(
+            "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aac\",NAME=\"English, stereo\",DEFAULT=YES,URI=\"a/1.m3u8\"",
+        );
+        assert_eq!(attrs["TYPE"], "AUDIO");
+        assert_eq!(attrs["NAME"], "English, stereo");
+        assert_eq!(attrs["DEFAULT"], "YES");
+        assert_eq!(attrs["URI"], "a/1.m3u8");
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(
+            resolve("http://h/m/a.mp4/s/t.0.m3u8", "v/0.1.m4s"),
+            "http://h/m/a.mp4/s/v/0.1.m4s"
+        );
+        assert_eq!(
+            resolve("http://h/m/a.mp4.as.m3u8?x=1", "a.mp4/s/t.0.m3u8"),
+            "http://h/m/a.mp4/s/t.0.m3u8"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_playback() {
+        let Some(media) = test_asset() else {
+            return;
+        };
+        hls_vod_lib::ffmpeg_init().unwrap();
+        let addr = start_server(ServerConfig::default()).await;
+        let player = Player::new();
+
+        let url = format!("http://{}{}.as.m3u8", addr, media.display());
+        let (variants, audio) = player.main_playlist(&url).await;
+        assert!(!variants.is_empty(), "no variants");
+
+        let mut video = player.media_playlist(&variants[0].uri).await;
+        player.load_init(&mut video).await;
+        let mut audio = match audio.first() {
+            Some(a) => Some(player.media_playlist(&a.uri).await),
+            None => None,
+        };
+        if let Some(a) = &mut audio {
+            player.load_init(a).await;
+        }
+
+        let count = video.segments.len().min(4);
+        let mut prev: Option<(SegmentTiming, Option<SegmentTiming>)> = None;
+        for n in 0..count {
+            let v = player.segment(&video, n).await;
+            let a = match &audio {
+                Some(a) if n < a.segments.len() => Some(player.segment(a, n).await),
+                _ => None,
+            };
+            if let Some(a) = &a {
+                assert_aligned(&v, a);
+            }
+            if let Some((pv, pa)) = &prev {
+                assert_continuous(pv, &v, 0.001);
+                if let (Some(pa), Some(a)) = (pa, &a) {
+                    assert_continuous(pa, a, AV_TOLERANCE);
+                }
+            }
+            prev = Some((v, a));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_audio_switch() {
+        use hls_vod_lib::bench::{generate, FixtureSpec};
+
+        // Two audio tracks, in two languages.
+        let Some(media) = generate(&FixtureSpec::multi_language()) else {
+            return;
+        };
+        hls_vod_lib::ffmpeg_init().unwrap();
+        let addr = start_server(ServerConfig::default()).await;
+        let player = Player::new();
+
+        let url = format!("http://{}{}.as.m3u8", addr, media.display());
+        let (_, audio) = player.main_playlist(&url).await;
+        assert!(
+            audio.len() >= 2,
+            "{} has less than two audio renditions",
+            url
+        );
+
+        let mut first = player.media_playlist(&audio[0].uri).await;
+        let mut second = player.media_playlist(&audio[1].uri).await;
+        player.load_init(&mut first).await;
+        let before = player.segment(&first, 0).await;
+
+        // Switch: the next segment comes from the other rendition. Track
+        // ids may differ between renditions, so compare the times only.
+        player.load_init(&mut second).await;
+        let after = player.segment(&second, 1).await;
+        let end = before.tracks.iter().map(|t| t.2).fold(0.0, f64::max);
+        assert!(
+            (after.start() - end).abs() <= AV_TOLERANCE,
+            "{} ends at {:.3}, {} starts at {:.3}",
+            before.url,
+            end,
+            after.url,
+            after.start()
+        );
+    }
+}
@@ -8,8 +8,15 @@
 //! - Server configuration
 
 use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
+use hls_vod_lib::params::SignedUrlCodec;
+
+use crate::bookmarks::BookmarkStore;
 use crate::config::ServerConfig;
+use crate::http::middleware::CompressionCache;
+use crate::limits::RateLimiter;
+use crate::worker::WorkerPool;
 
 /// Application state shared across all handlers
 pub struct AppState {
@@ -18,18 +25,56 @@ pub struct AppState {
 
     /// Server configuration
     pub config: ServerConfig,
+
+    /// URL signing codec, if signed URLs are enabled
+    pub url_codec: Option<Arc<SignedUrlCodec>>,
+
+    /// Worker processes for segment generation, if crash isolation is enabled
+    pub workers: Option<Arc<WorkerPool>>,
+
+    /// Compressed playlists and WebVTT segments
+    pub compressed: CompressionCache,
+
+    /// Per client IP rate limiter
+    pub rate_limiter: Arc<RateLimiter>,
+
+    /// Resume positions, if bookmarks are enabled
+    pub bookmarks: Option<Arc<dyn BookmarkStore>>,
+
+    /// A `/debug/drift` check is running; only one runs at a time
+    pub drift_check: AtomicBool,
 }
 
 impl AppState {
     pub fn new(config: ServerConfig) -> Self {
         hls_vod_lib::cache::init_segment_cache(config.cache.clone());
+        let url_codec = config.auth.as_ref().map(crate::auth::install);
+        let compressed = CompressionCache::new(&config.compression);
+        let rate_limiter = crate::limits::create_rate_limiter(&config);
+        let bookmarks = crate::bookmarks::create_store(&config.bookmarks);
 
         Self {
             shutdown: AtomicBool::new(false),
             config,
+            url_codec,
+            workers: None,
+            compressed,
+            rate_limiter,
+            bookmarks,
+            drift_check: AtomicBool::new(false),
         }
     }
 
+    /// Generate segments in worker processes started with `config_path`.
+    pub fn with_workers(mut self, config_path: &str) -> std::io::Result<Self> {
+        if self.config.workers > 0 {
+            let timeout = std::time::Duration::from_secs(self.config.worker_timeout_secs);
+            let pool = WorkerPool::new(config_path, self.config.workers, timeout)?;
+            self.workers = Some(Arc::new(pool));
+        }
+        Ok(self)
+    }
+
     /// Create AppState with default configuration
     pub fn with_defaults() -> Self {
         Self::new(ServerConfig::default())
@@ -55,6 +100,21 @@ impl AppState {
     pub fn cleanup_expired_streams(&self) -> usize {
         hls_vod_lib::cache::cleanup_expired_streams()
     }
+
+    /// Forget rate limiter state of clients that went away
+    pub fn cleanup_rate_limiter(&self) {
+        self.rate_limiter
+            .cleanup(std::time::Duration::from_secs(60));
+    }
+
+    /// Save the resume positions reported since the last call
+    pub fn flush_bookmarks(&self) {
+        if let Some(store) = &self.bookmarks {
+            if let Err(e) = store.flush() {
+                tracing::error!("Failed to save bookmarks: {}", e);
+            }
+        }
+    }
 }
 
 impl Default for AppState {
@@ -0,0 +1,391 @@
+//! Crash isolation: segment generation in worker processes
+//!
+//! A crash in FFmpeg (a segfault on a corrupt file, say) takes the whole
+//! server down. With `workers` set in the config, segment requests are
+//! handed to helper processes instead: the server binary started again with
+//! the `--worker` argument. A crashed worker only fails the request it was
+//! working on; the next request gets a fresh worker.
+//!
+//! Requests and responses are bincode-encoded and framed with a 32-bit
+//! big-endian length, over the worker's stdin and stdout. Workers log to
+//! stderr, in a span with the id of the HTTP request.
+//!
+//! Playlists are still generated in the server process, as they need its
+//! stream index anyway. A worker indexes a file the first time it sees it.
+//!
+//! This costs memory: every worker keeps its own stream indexes and its own
+//! segment cache (`cache.memory_limit_mb` each), on top of those of the
+//! server, so with `workers = N` the memory use can grow to about N + 1
+//! times that of a server without workers. There are never more than N
+//! workers; requests beyond that wait for one. A worker that takes longer
+//! than `worker_timeout_secs` for a request is killed.
+
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Result, ServerError};
+
+/// Command line argument that starts the binary as a worker.
+pub const WORKER_ARG: &str = "--worker";
+
+/// Largest frame accepted from a pipe.
+const MAX_FRAME_LEN: usize = 512 * 1024 * 1024;
+
+/// A segment request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkerRequest {
+    /// The media file
+    pub media_path: PathBuf,
+    /// The request path, as parsed by `HlsParams::parse`
+    pub url: String,
+    /// The id of the HTTP request, for the logs
+    pub request_id: Option<String>,
+}
+
+/// The answer to a `WorkerRequest`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WorkerResponse {
+    Data(Vec<u8>),
+    Error(String),
+}
+
+/// Write `msg` as one frame.
+fn write_frame<W: Write, T: Serialize>(w: &mut W, msg: &T) -> io::Result<()> {
+    let data =
+        bincode::serialize(msg).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(&data)?;
+    w.flush()
+}
+
+/// Read one frame. Returns `None` at a clean end of stream.
+fn read_frame<R: Read, T: DeserializeOwned>(r: &mut R) -> io::Result<Option<T>> {
+    let mut len = [0u8; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame too large: {} bytes", len),
+        ));
+    }
+    let mut data = vec![0u8; len];
+    r.read_exact(&mut data)?;
+    bincode::deserialize(&data)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Worker main loop: answer requests from stdin until it is closed.
+pub fn run() -> Result<()> {
+    let mut input = BufReader::new(io::stdin().lock());
+    let mut output = BufWriter::new(io::stdout().lock());
+    tracing::info!("worker {} started", std::process::id());
+
+    while let Some(request) = read_frame::<_, WorkerRequest>(&mut input)? {
+        let id = request.request_id.as_deref().unwrap_or("-");
+        let _span = tracing::info_span!("request", id).entered();
+        let response = match generate(&request) {
+            Ok(data) => WorkerResponse::Data(data),
+            Err(e) => WorkerResponse::Error(e),
+        };
+        write_frame(&mut output, &response)?;
+        hls_vod_lib::cache::cleanup_expired_streams();
+    }
+    Ok(())
+}
+
+fn generate(request: &WorkerRequest) -> std::result::Result<Vec<u8>, String> {
+    let params = hls_vod_lib::HlsParams::parse(&request.url)
+        .ok_or_else(|| format!("invalid request path: {}", request.url))?;
+    hls_vod_lib::HlsVideo::open(&request.media_path, params)
+        .and_then(|video| video.generate())
+        .map_err(|e| e.to_string())
+}
+
+/// A running worker process.
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    /// Frames read from the worker's stdout by a reader thread, so that
+    /// waiting for one can time out.
+    responses: Receiver<io::Result<Option<WorkerResponse>>>,
+}
+
+impl Worker {
+    fn request(
+        &mut self,
+        request: &WorkerRequest,
+        timeout: Duration,
+    ) -> io::Result<WorkerResponse> {
+        write_frame(&mut self.stdin, request)?;
+        let response = match self.responses.recv_timeout(timeout) {
+            Ok(response) => response?,
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no response after {}s", timeout.as_secs()),
+                ))
+            }
+            // The reader thread ends after a clean end of stream.
+            Err(RecvTimeoutError::Disconnected) => None,
+        };
+        response.ok_or_else(|| {
+            let status = self.child.try_wait().ok().flatten();
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                match status {
+                    Some(status) => format!("worker exited: {}", status),
+                    None => "worker closed its output".to_string(),
+                },
+            )
+        })
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Pool of worker processes.
+///
+/// Workers are started on demand, one per concurrent request, up to
+/// `max_workers`; a request waits until one is free. The workers that are
+/// not busy are kept around for the next request.
+pub struct WorkerPool {
+    program: PathBuf,
+    args: Vec<String>,
+    max_workers: usize,
+    timeout: Duration,
+    workers: Mutex<Workers>,
+    /// Signalled when a worker is returned or has exited
+    freed: Condvar,
+}
+
+struct Workers {
+    idle: Vec<Worker>,
+    /// Busy and idle workers
+    running: usize,
+}
+
+impl WorkerPool {
+    /// A pool that runs this binary with `config_path` as worker.
+    pub fn new(config_path: &str, max_workers: usize, timeout: Duration) -> io::Result<Self> {
+        let program = std::env::current_exe()?;
+        let args = vec![config_path.to_string(), WORKER_ARG.to_string()];
+        Ok(Self::with_command(program, args, max_workers, timeout))
+    }
+
+    /// A pool that runs `program` with `args` as worker.
+    pub fn with_command(
+        program: PathBuf,
+        args: Vec<String>,
+        max_workers: usize,
+        timeout: Duration,
+    ) -> Self {
+        WorkerPool {
+            program,
+            args,
+            max_workers: max_workers.max(1),
+            timeout,
+            workers: Mutex::new(Workers {
+                idle: Vec::new(),
+                running: 0,
+            }),
+            freed: Condvar::new(),
+        }
+    }
+
+    fn spawn(&self) -> io::Result<Worker> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+        let stdin = child.stdin.take().ok_or(io::ErrorKind::BrokenPipe)?;
+        let stdout = child.stdout.take().ok_or(io::ErrorKind::BrokenPipe)?;
+        let (tx, responses) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("hls-worker-read".to_string())
+            .spawn(move || {
+                let mut stdout = BufReader::new(stdout);
+                loop {
+                    let frame = read_frame(&mut stdout);
+                    let last = !matches!(frame, Ok(Some(_)));
+                    if tx.send(frame).is_err() || last {
+                        break;
+                    }
+                }
+            })?;
+        Ok(Worker {
+            child,
+            stdin,
+            responses,
+        })
+    }
+
+    /// An idle worker, a new one if there are fewer than `max_workers`, or
+    /// the first one that becomes idle.
+    fn take(&self) -> Result<Worker> {
+        let mut workers = self.workers.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(worker) = workers.idle.pop() {
+                return Ok(worker);
+            }
+            if workers.running < self.max_workers {
+                break;
+            }
+            workers = self.freed.wait(workers).unwrap_or_else(|e| e.into_inner());
+        }
+        workers.running += 1;
+        drop(workers);
+        self.spawn().map_err(|e| {
+            self.release(None);
+            e.into()
+        })
+    }
+
+    /// Return a worker to the pool, or `None` for one that is gone.
+    fn release(&self, worker: Option<Worker>) {
+        let mut workers = self.workers.lock().unwrap_or_else(|e| e.into_inner());
+        match worker {
+            Some(worker) => workers.idle.push(worker),
+            None => workers.running -= 1,
+        }
+        self.freed.notify_one();
+    }
+
+    /// Generate a segment in a worker. Blocks.
+    pub fn generate(
+        &self,
+        media_path: PathBuf,
+        url: String,
+        request_id: Option<String>,
+    ) -> Result<Vec<u8>> {
+        let mut worker = self.take()?;
+
+        let request = WorkerRequest {
+            media_path,
+            url,
+            request_id,
+        };
+        let response = match worker.request(&request, self.timeout) {
+            Ok(response) => response,
+            Err(e) => {
+                // Crashed, hung up or hangs; dropping it kills and reaps it.
+                tracing::error!("worker {} failed: {}", worker.child.id(), e);
+                drop(worker);
+                self.release(None);
+                return Err(ServerError::Internal(format!("worker failed: {}", e)));
+            }
+        };
+        self.release(Some(worker));
+
+        match response {
+            WorkerResponse::Data(data) => Ok(data),
+            WorkerResponse::Error(e) => Err(ServerError::Internal(e)),
+        }
+    }
+
+    /// Number of idle workers.
+    pub fn idle_count(&self) -> usize {
+        self.workers.lock().map(|w| w.idle.len()).unwrap_or(0)
+    }
+
+    /// Number of worker processes, busy or idle.
+    pub fn running_count(&self) -> usize {
+        self.workers.lock().map(|w| w.running).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let request = WorkerRequest {
+            media_path: PathBuf::from("/media/movie.mkv"),
+            url: "/media/movie.mkv/abc/v/media-0.3.m4s".to_string(),
+            request_id: Some("3f2a9c1e".to_string()),
+        };
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &request).unwrap();
+        write_frame(&mut buf, &WorkerResponse::Data(vec![1, 2, 3])).unwrap();
+
+        let mut r = &buf[..];
+        let decoded: WorkerRequest = read_frame(&mut r).unwrap().unwrap();
+        assert_eq!(decoded.media_path, request.media_path);
+        assert_eq!(decoded.url, request.url);
+        assert_eq!(decoded.request_id, request.request_id);
+        let response: WorkerResponse = read_frame(&mut r).unwrap().unwrap();
+        assert!(matches!(response, WorkerResponse::Data(d) if d == [1, 2, 3]));
+        assert!(read_frame::<_, WorkerResponse>(&mut r).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &WorkerResponse::Error("oops".to_string())).unwrap();
+        buf.truncate(buf.len() - 1);
+        assert!(read_frame::<_, WorkerResponse>(&mut &buf[..]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_crashed_worker() {
+        // A "worker" that dies from a segfault as soon as it starts.
+        let pool = WorkerPool::with_command(
+            PathBuf::from("/bin/sh"),
+            vec!["-c".to_string(), "kill -SEGV $$".to_string()],
+            2,
+            Duration::from_secs(10),
+        );
+        let result = pool.generate(PathBuf::from("/media/movie.mkv"), "x".to_string(), None);
+        assert!(result.is_err());
+        assert_eq!(pool.idle_count(), 0);
+        assert_eq!(pool.running_count(), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hanging_worker() {
+        // A "worker" that never answers. Both requests time out: the second
+        // one waits for the only worker slot, and gets a new worker.
+        let pool = std::sync::Arc::new(WorkerPool::with_command(
+            PathBuf::from("/bin/sh"),
+            vec!["-c".to_string(), "sleep 60".to_string()],
+            1,
+            Duration::from_millis(200),
+        ));
+        let started = std::time::Instant::now();
+        let requests: Vec<_> = (0..2)
+            .map(|_| {
+                let pool = std::sync::Arc::clone(&pool);
+                std::thread::spawn(move || {
+                    pool.generate(PathBuf::from("/media/movie.mkv"), "x".to_string(), None)
+                })
+            })
+            .collect();
+        for request in requests {
+            assert!(request.join().unwrap().is_err());
+        }
+        assert!(started.elapsed() >= Duration::from_millis(400));
+        assert_eq!(pool.running_count(), 0);
+    }
+}
@@ -13,6 +13,10 @@ pub struct Config {
     pub safari: SafariConfig,
     #[serde(default)]
     pub cache: hls_vod_lib::cache::SegmentCacheConfig,
+    #[serde(default)]
+    pub aac: hls_vod_lib::AacEncoderConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
 }
 
 #[derive(Debug, Deserialize, Clone, Default)]
@@ -37,6 +41,31 @@ pub struct SafariConfig {
     pub force_transcoding: bool,
 }
 
+/// Request limits.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Maximum request body size in bytes.
+    pub max_body_bytes: usize,
+    /// Maximum length of the request path and query.
+    pub max_url_length: usize,
+    /// Requests per second per client IP, 0 = unlimited.
+    pub rate_limit_rps: u32,
+    /// Number of requests a client can burst above the rate.
+    pub rate_limit_burst: u32,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 10 * 1024 * 1024,
+            max_url_length: 8192,
+            rate_limit_rps: 0,
+            rate_limit_burst: 50,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone, Default)]
 pub struct JellyfinConfig {
     pub jellyfin: String,
@@ -97,6 +126,8 @@ impl Config {
                 ..Default::default()
             },
             cache: Default::default(),
+            aac: Default::default(),
+            limits: Default::default(),
         }
     }
 }
@@ -1,15 +1,23 @@
-use axum::{body::Body, extract::State, http::StatusCode, response::Response};
+use axum::{body::Body, extract::State, response::Response};
+use hls_vod_http::HttpError;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 
 use crate::AppState;
 
 pub async fn proxymedia_handler(
     State(state): State<Arc<AppState>>,
-    axum::extract::Path(path): axum::extract::Path<String>,
+    uri: axum::http::Uri,
     axum::extract::Query(query_params): axum::extract::Query<
         std::collections::HashMap<String, String>,
     >,
-) -> Result<Response, StatusCode> {
+) -> Result<Response, HttpError> {
+    // Not the decoded path parameter, HlsParams::parse does the decoding.
+    let path = uri
+        .path()
+        .strip_prefix("/proxymedia/")
+        .unwrap_or_default()
+        .to_string();
     tracing::info!("Proxymedia request for path: {}", path);
     // Path comes in like Users/mikevs/Devel/...
     let mut clean_path = path.clone();
@@ -22,7 +30,7 @@ pub async fn proxymedia_handler(
         Some(params) => params,
         None => hls_vod_lib::HlsParams::parse(&path).ok_or_else(|| {
             tracing::error!("Invalid HLS request: {}", path);
-            StatusCode::BAD_REQUEST
+            HttpError::InvalidFormat(format!("Invalid HLS request: {}", path))
         })?,
     };
 
@@ -32,29 +40,12 @@ pub async fn proxymedia_handler(
 
     tracing::info!("Parsed HLS URL: {:?}", hls_url);
 
-    let mut media_path = std::path::PathBuf::from(&hls_url.video_url);
-
-    // If media_root is set, prepend it to the path
-    if !state.media_root.is_empty() {
-        let root = std::path::Path::new(&state.media_root);
-        // We want to join them. If hls_url.video_url starts with /, we might need to be careful
-        // depending on if we want it to be relative to root.
-        // Usually joining an absolute path with another path makes it absolute.
-        // Let's trim leading slash if we have a root.
-        let video_url = hls_url.video_url.trim_start_matches('/');
-        media_path = root.join(video_url);
-        tracing::info!("Prepended media_root: {:?}", media_path);
-    }
-
-    if !media_path.exists() {
-        tracing::error!("Media file not found: {:?}", media_path);
-        return Err(StatusCode::NOT_FOUND);
-    }
+    let media_path = resolve_media_path(&state.media_root, &hls_url.video_url)?;
 
     tokio::task::spawn_blocking(move || {
         let mut hls_video = hls_vod_lib::HlsVideo::open(&media_path, hls_url).map_err(|e| {
             tracing::error!("Failed to open media: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+            HttpError::from(e)
         })?;
 
         if let hls_vod_lib::HlsVideo::MainPlaylist(p) = &mut hls_video {
@@ -98,7 +89,7 @@ pub async fn proxymedia_handler(
 
         let bytes = hls_video.generate().map_err(|e| {
             tracing::error!("Failed to generate HLS data: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
+            HttpError::from(e)
         })?;
 
         let mut response = Response::new(Body::from(bytes));
@@ -106,5 +97,96 @@ pub async fn proxymedia_handler(
         Ok(response)
     })
     .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|e| HttpError::InternalError(e.to_string()))?
+}
+
+/// Map the video part of the URL to a path on disk.
+///
+/// Paths with `..` components are refused. If a media root is set, the file
+/// must also really be inside it, so a symlink can't point outside of it.
+fn resolve_media_path(media_root: &str, video_url: &str) -> Result<PathBuf, HttpError> {
+    let video_path = Path::new(video_url);
+    if video_path
+        .components()
+        .any(|c| matches!(c, Component::ParentDir))
+    {
+        tracing::warn!("Refusing path with '..': {}", video_url);
+        return Err(HttpError::Forbidden(format!(
+            "Path with '..': {}",
+            video_url
+        )));
+    }
+
+    if media_root.is_empty() {
+        let media_path = video_path.to_path_buf();
+        if !media_path.exists() {
+            tracing::error!("Media file not found: {:?}", media_path);
+            return Err(HttpError::StreamNotFound(format!(
+                "Media file not found: {}",
+                video_url
+            )));
+        }
+        return Ok(media_path);
+    }
+
+    // The URL path is relative to the media root, even if it starts with a '/'.
+    let root = Path::new(media_root);
+    let media_path = root.join(video_url.trim_start_matches('/'));
+    tracing::info!("Prepended media_root: {:?}", media_path);
+
+    let (Ok(root), Ok(media_path)) = (root.canonicalize(), media_path.canonicalize()) else {
+        tracing::error!("Media file not found: {:?}", media_path);
+        return Err(HttpError::StreamNotFound(format!(
+            "Media file not found: {}",
+            video_url
+        )));
+    };
+    if !media_path.starts_with(&root) {
+        tracing::warn!("Refusing path outside of media root: {:?}", media_path);
+        return Err(HttpError::Forbidden(format!(
+            "Path outside of media root: {}",
+            video_url
+        )));
+    }
+    Ok(media_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+
+    fn status(result: Result<PathBuf, HttpError>) -> StatusCode {
+        result.map_or_else(|e| e.status(), |_| StatusCode::OK)
+    }
+
+    #[test]
+    fn test_resolve_media_path() {
+        let root = std::env::temp_dir().join(format!("proxymedia-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("movies")).unwrap();
+        std::fs::write(root.join("movies/a.mp4"), b"").unwrap();
+        let root_str = root.to_str().unwrap();
+
+        assert!(resolve_media_path(root_str, "/movies/a.mp4").is_ok());
+        assert!(resolve_media_path(root_str, "movies/a.mp4").is_ok());
+        assert_eq!(
+            status(resolve_media_path(root_str, "movies/../movies/a.mp4")),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(resolve_media_path(root_str, "movies/b.mp4")),
+            StatusCode::NOT_FOUND
+        );
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("/etc", root.join("etc")).unwrap();
+            assert_eq!(
+                status(resolve_media_path(root_str, "etc/hostname")),
+                StatusCode::FORBIDDEN
+            );
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 }
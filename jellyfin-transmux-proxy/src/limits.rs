@@ -0,0 +1,128 @@
+//! Request limits: URL length and per client IP rate limiting.
+//!
+//! The request body size is limited by a `RequestBodyLimitLayer`,
+//! see `main`.
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::LimitsConfig;
+use crate::AppState;
+
+// Token bucket of one client.
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Per client IP rate limiter.
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    rate: f64,
+    burst: f64,
+}
+
+impl RateLimiter {
+    pub fn new(config: &LimitsConfig) -> RateLimiter {
+        RateLimiter {
+            buckets: Mutex::new(HashMap::new()),
+            rate: config.rate_limit_rps as f64,
+            burst: config.rate_limit_burst.max(1) as f64,
+        }
+    }
+
+    /// Check if a request from `ip` is allowed.
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            last_refill: now,
+        });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last_refill = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forget clients that have not been seen for `max_age`.
+    pub fn cleanup(&self, max_age: Duration) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|_, b| now.duration_since(b.last_refill) < max_age);
+    }
+}
+
+/// Middleware that rejects overly long URLs and rate limits clients.
+pub async fn limit_requests(
+    State(state): State<Arc<AppState>>,
+    request: Request<Body>,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let url_len = request
+        .uri()
+        .path_and_query()
+        .map(|pq| pq.as_str().len())
+        .unwrap_or(0);
+    if url_len > state.limits.max_url_length {
+        tracing::warn!("URL too long ({} bytes)", url_len);
+        return Err(StatusCode::URI_TOO_LONG);
+    }
+
+    if let Some(ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        if !state.rate_limiter.is_allowed(addr.ip()) {
+            tracing::warn!("Rate limit exceeded for {}", addr.ip());
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+    }
+
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(&LimitsConfig {
+            rate_limit_rps: 10,
+            rate_limit_burst: 5,
+            ..Default::default()
+        });
+        let ip1: IpAddr = "127.0.0.1".parse().unwrap();
+        let ip2: IpAddr = "127.0.0.2".parse().unwrap();
+
+        for _ in 0..5 {
+            assert!(limiter.is_allowed(ip1));
+        }
+        assert!(!limiter.is_allowed(ip1));
+        assert!(limiter.is_allowed(ip2));
+    }
+
+    #[test]
+    fn test_rate_limiter_unlimited() {
+        let limiter = RateLimiter::new(&LimitsConfig::default());
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        for _ in 0..1000 {
+            assert!(limiter.is_allowed(ip));
+        }
+    }
+}
@@ -7,11 +7,13 @@ use clap::Parser;
 use reqwest::Client;
 use socket2::{Domain, Protocol, Socket, Type};
 use tower_http::cors::CorsLayer;
+use tower_http::limit::RequestBodyLimitLayer;
 use tower_http::trace::{DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse, TraceLayer};
 use tracing::Level;
 
 pub mod config;
 pub mod hls;
+pub mod limits;
 pub mod playbackinfo;
 pub mod proxy;
 pub mod types;
@@ -46,6 +48,8 @@ pub struct AppState {
     pub media_root: String,
     pub http_client: Client,
     pub safari_force_transcoding: bool,
+    pub limits: config::LimitsConfig,
+    pub rate_limiter: limits::RateLimiter,
 }
 
 // Helper to create a listener.
@@ -103,8 +107,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     tracing_subscriber::fmt()
         .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "jellyfin_transmux_proxy=info,tower_http=info".into()),
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+                "jellyfin_transmux_proxy=info,tower_http=info,ffmpeg=warn".into()
+            }),
         )
         .init();
 
@@ -143,14 +148,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     if config.cache.lookahead > 0 {
         tracing::info!("Segment look-ahead: {} segments", config.cache.lookahead);
     }
+    hls_vod_lib::set_aac_encoder(config.aac.clone())?;
 
     let state = Arc::new(AppState {
         jellyfin_url: config.jellyfin.jellyfin.clone(),
         media_root: config.jellyfin.mediaroot.clone().unwrap_or_default(),
         http_client,
         safari_force_transcoding: config.safari.force_transcoding,
+        rate_limiter: limits::RateLimiter::new(&config.limits),
+        limits: config.limits.clone(),
     });
 
+    // Forget idle clients of the rate limiter every minute.
+    if config.limits.rate_limit_rps > 0 {
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+                state
+                    .rate_limiter
+                    .cleanup(std::time::Duration::from_secs(60));
+            }
+        });
+    }
+
     let app = Router::new()
         .route(
             "/Items/{item_id}/PlaybackInfo",
@@ -162,6 +183,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         )
         .route("/socket", axum::routing::get(websocket_handler))
         .fallback(any(proxy_handler))
+        .layer(RequestBodyLimitLayer::new(config.limits.max_body_bytes))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            limits::limit_requests,
+        ))
         .layer(CorsLayer::permissive())
         .layer(
             TraceLayer::new_for_http()
@@ -181,7 +207,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let listener = tcp_listener(*addr)?;
             listeners.push(tokio::spawn(async move {
                 axum_server::from_tcp(listener)?
-                    .serve(app_clone.into_make_service())
+                    .serve(app_clone.into_make_service_with_connect_info::<std::net::SocketAddr>())
                     .await
             }));
         }
@@ -209,7 +235,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let listener = tcp_listener(*addr)?;
             listeners.push(tokio::spawn(async move {
                 axum_server::from_tcp_rustls(listener, rustls_config_clone)?
-                    .serve(app_clone.into_make_service())
+                    .serve(app_clone.into_make_service_with_connect_info::<std::net::SocketAddr>())
                     .await
             }));
         }